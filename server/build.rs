@@ -0,0 +1,49 @@
+// build.rs
+//! Incrusta en el binario la información de compilación que expone `build_info` y, con la
+//! feature `grpc`, genera el servidor de `proto/lmserver.proto` (y el cliente, que usan las pruebas).
+use std::env;
+use std::process::Command;
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let text = String::from_utf8(output.stdout).ok()?.trim().to_string();
+    (!text.is_empty()).then_some(text)
+}
+
+/// Usa el `protoc` de `protoc-bin-vendored` para no exigirlo instalado en la máquina.
+#[cfg(feature = "grpc")]
+fn compile_protos() {
+    println!("cargo:rerun-if-changed=proto/lmserver.proto");
+    let protoc = protoc_bin_vendored::protoc_bin_path().expect("No hay protoc incluido para esta plataforma");
+    env::set_var("PROTOC", protoc);
+    tonic_build::configure()
+        .compile_protos(&["proto/lmserver.proto"], &["proto"])
+        .expect("No se pudo compilar proto/lmserver.proto");
+}
+
+fn main() {
+    #[cfg(feature = "grpc")]
+    compile_protos();
+
+    let git_hash = git(&["rev-parse", "--short=12", "HEAD"]).unwrap_or_else(|| "unknown".to_string());
+    // Recompilar cuando cambia el commit actual (HEAD o la rama a la que apunta).
+    if let Some(git_dir) = git(&["rev-parse", "--absolute-git-dir"]) {
+        println!("cargo:rerun-if-changed={}/HEAD", git_dir);
+        if let Some(head_ref) = git(&["symbolic-ref", "-q", "HEAD"]) {
+            println!("cargo:rerun-if-changed={}/{}", git_dir, head_ref);
+        }
+    }
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| key.strip_prefix("CARGO_FEATURE_").map(|f| f.to_lowercase().replace('_', "-")))
+        .collect();
+    features.sort();
+
+    println!("cargo:rustc-env=LMSERVER_GIT_HASH={}", git_hash);
+    println!("cargo:rustc-env=LMSERVER_BUILD_TIMESTAMP={}", chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ"));
+    println!("cargo:rustc-env=LMSERVER_FEATURES={}", if features.is_empty() { "none".to_string() } else { features.join(",") });
+}
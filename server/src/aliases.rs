@@ -0,0 +1,62 @@
+// src/aliases.rs
+//! Alias de pools para migraciones: un nombre antiguo que se resuelve a otra pool.
+//!
+//! Se aplican en cada petición (`/lmstudio`, `/ollama`, `/pool/{nombre}`) y a los anuncios
+//! de descubrimiento, de modo que clientes y nodos sin actualizar acaban en la pool nueva.
+//! Un alias puede tapar una pool existente (p.ej. `lmstudio=ollama` para consolidar ambas);
+//! esa pool deja de recibir peticiones y nodos.
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::ids::PoolName;
+
+#[derive(Debug)]
+pub struct AliasConfigError(String);
+
+impl fmt::Display for AliasConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for AliasConfigError {}
+
+#[derive(Default)]
+pub struct PoolAliases {
+    aliases: BTreeMap<PoolName, &'static str>,
+}
+
+impl PoolAliases {
+    /// Interpreta entradas `alias=pool`. El destino debe ser una pool real, no otro alias.
+    pub fn new(pools: &[&'static str], entries: &[String]) -> Result<Self, AliasConfigError> {
+        let mut aliases = BTreeMap::new();
+        for entry in entries {
+            let Some((alias, target)) = entry.split_once('=').map(|(a, t)| (a.trim(), t.trim())).filter(|(a, t)| !a.is_empty() && !t.is_empty()) else {
+                return Err(AliasConfigError(format!("Alias de pool inválido '{}': se esperaba <alias>=<pool>", entry)));
+            };
+            let Some(target) = pools.iter().copied().find(|pool| *pool == target) else {
+                return Err(AliasConfigError(format!("El alias '{}' apunta a una pool desconocida '{}'", alias, target)));
+            };
+            let alias = PoolName::new(alias).map_err(|e| AliasConfigError(e.to_string()))?;
+            if alias == *target {
+                return Err(AliasConfigError(format!("El alias '{}' apunta a sí mismo", alias)));
+            }
+            if aliases.insert(alias.clone(), target).is_some() {
+                return Err(AliasConfigError(format!("Alias de pool '{}' repetido", alias)));
+            }
+        }
+        if let Some((alias, target)) = aliases.iter().find(|(_, target)| aliases.contains_key(**target)) {
+            return Err(AliasConfigError(format!("El alias '{}' apunta a '{}', que también es un alias", alias, target)));
+        }
+        Ok(Self { aliases })
+    }
+
+    /// Pool a la que apunta `name`, si es un alias.
+    pub fn resolve(&self, name: &str) -> Option<&'static str> {
+        self.aliases.get(name).copied()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &'static str)> {
+        self.aliases.iter().map(|(alias, target)| (alias.as_str(), *target))
+    }
+}
@@ -0,0 +1,312 @@
+// src/api_types.rs
+//! Contrato de la API de estado y administración: sobre versionado y tipos compartidos.
+//!
+//! Toda ruta que no es de inferencia se sirve también bajo `/v1` (`/v1/nodes`, `/v1/stats/summary`,
+//! ...). Ahí las respuestas JSON van dentro de un sobre `{"api_version": 1, "data": ...}`, también
+//! las de error, cuyo estado HTTP no cambia. Las rutas sin prefijo siguen respondiendo como antes
+//! durante una versión más, con `Deprecation: true` y un `Link` a su sucesora. Quedan fuera la
+//! inferencia, las rutas compatibles con Ollama (`/api/*`), la página `/` y `/openapi.json`.
+//!
+//! Los tipos de este módulo son el esquema de las respuestas: el servidor los serializa y
+//! `openapi::check` lee con ellos los ejemplos del documento, rechazando campos desconocidos,
+//! así que un campo renombrado en un lado y no en el otro hace fallar la comprobación de CI.
+use actix_web::body::{self, BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::Uri;
+use actix_web::middleware::Next;
+use actix_web::HttpResponse;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+use crate::auth::{self, Access};
+use crate::platform::Platform;
+
+/// Versión de la API que anuncia el sobre.
+pub const API_VERSION: u32 = 1;
+
+/// Prefijo de las rutas versionadas.
+pub const PREFIX: &str = "/v1";
+
+/// Rutas que no son de inferencia pero se quedan sin versionar.
+const UNVERSIONED: &[&str] = &["/", "/openapi.json", "/api/version"];
+
+/// Sobre de toda respuesta JSON bajo `/v1`.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Envelope<T> {
+    pub api_version: u32,
+    pub data: T,
+}
+
+impl<T> Envelope<T> {
+    pub fn new(data: T) -> Self {
+        Self { api_version: API_VERSION, data }
+    }
+}
+
+/// Ritmo de despacho de un nodo con `--dispatch-rate`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DispatchRateStatus {
+    pub per_second: f64,
+    pub burst: u32,
+    /// Fichas disponibles ahora.
+    pub tokens: f64,
+}
+
+/// Tope de peticiones por minuto de un nodo (`max_rpm`).
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RpmStatus {
+    pub max_rpm: u32,
+    /// Peticiones despachadas en el último minuto.
+    pub used: u32,
+}
+
+/// Fase canaria de un nodo recién anunciado (`canary`).
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CanaryStatus {
+    pub elapsed_secs: u64,
+    pub requests: u32,
+    pub errors: u32,
+}
+
+/// Un nodo en `/nodes` y `/nodes/watch`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct NodeSummary {
+    pub node_id: String,
+    pub service: String,
+    pub service_url: String,
+    pub state: String,
+    pub source: String,
+    pub version: Option<String>,
+    pub low_disk: bool,
+    pub dispatch_rate: Option<DispatchRateStatus>,
+    /// Tope de peticiones por minuto y su uso; `null` si no tiene.
+    #[serde(default)]
+    pub rpm: Option<RpmStatus>,
+    /// Fase canaria en curso; `null` si el nodo ya recibe tráfico completo.
+    #[serde(default)]
+    pub canary: Option<CanaryStatus>,
+    pub capabilities: Vec<String>,
+    pub backend_version: Option<String>,
+    pub in_flight: u32,
+    pub capabilities_stale: bool,
+    pub weight: u32,
+    pub platform: Platform,
+    /// Dominio de fallo, anunciado o fijado con `--node-domain`.
+    pub domain: Option<String>,
+    /// IP a la que está fijado su ID (`--node-pinning`).
+    #[serde(default)]
+    pub pinned_ip: Option<String>,
+    /// Etiquetas `clave=valor` anunciadas con `--tag`.
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    /// Último `model` que atendió (`warm`).
+    #[serde(default)]
+    pub last_model: Option<String>,
+    /// Si su IP está en un `--local-prefix`.
+    #[serde(default)]
+    pub local: bool,
+    pub latency_ms: Option<u64>,
+    /// Segundos hasta que sale del registro, si se está drenando.
+    pub drain_secs: Option<u64>,
+}
+
+/// Respuesta de `GET /nodes`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct NodeList {
+    pub balancer_version: String,
+    pub revision: u64,
+    pub nodes: Vec<NodeSummary>,
+    pub discovery_listeners: Vec<Value>,
+    /// Lápidas, con `?include=removed`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub removed: Option<Vec<Value>>,
+}
+
+/// Si la ruta (por patrón) se sirve también bajo `/v1`, con sobre.
+pub fn is_versioned(pattern: &str) -> bool {
+    matches!(auth::route_access(pattern), Some(Access::Public | Access::Read(_) | Access::Protected(_))) && !UNVERSIONED.contains(&pattern)
+}
+
+/// Lee un ejemplo de la documentación con el tipo compartido de su esquema, dentro del sobre.
+/// `None` si el esquema aún no tiene tipo compartido.
+pub fn check_example(schema: &str, example: &Value) -> Option<Result<(), String>> {
+    fn read<T: DeserializeOwned>(example: &Value) -> Result<(), String> {
+        serde_json::from_value::<Envelope<T>>(json!(Envelope::new(example))).map(drop).map_err(|e| e.to_string())
+    }
+    match schema {
+        "NodeList" => Some(read::<NodeList>(example)),
+        _ => None,
+    }
+}
+
+/// Sirve las rutas `/v1/...` con las mismas rutas sin prefijo y envuelve su JSON; marca las rutas
+/// sin prefijo como obsoletas. Va por fuera de `auth::authorize`, que ve la ruta ya sin prefijo.
+pub async fn versioning(mut req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let Some(unprefixed) = req.path().strip_prefix(PREFIX).filter(|rest| rest.starts_with('/')).map(str::to_string) else {
+        let successor = req.match_pattern().filter(|pattern| is_versioned(pattern)).map(|_| format!("{}{}", PREFIX, req.path()));
+        let mut res = next.call(req).await?.map_into_boxed_body();
+        if let Some(successor) = successor {
+            let headers = res.headers_mut();
+            headers.insert(header::HeaderName::from_static("deprecation"), HeaderValue::from_static("true"));
+            if let Ok(link) = HeaderValue::from_str(&format!("<{}>; rel=\"successor-version\"", successor)) {
+                headers.insert(header::LINK, link);
+            }
+        }
+        return Ok(res);
+    };
+
+    let mut path_and_query = unprefixed;
+    if let Some(query) = req.uri().query() {
+        path_and_query = format!("{}?{}", path_and_query, query);
+    }
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = path_and_query.parse().ok();
+    if let Ok(uri) = Uri::from_parts(parts) {
+        req.match_info_mut().get_mut().update(&uri);
+        req.head_mut().uri = uri;
+    }
+    if !req.match_pattern().is_some_and(|pattern| is_versioned(&pattern)) {
+        return Ok(req.into_response(HttpResponse::NotFound().finish()));
+    }
+    envelope(next.call(req).await?).await
+}
+
+async fn envelope(res: ServiceResponse<impl MessageBody + 'static>) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let is_json = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+    if !is_json {
+        return Ok(res.map_into_boxed_body());
+    }
+    let (req, res) = res.into_parts();
+    let (mut head, res_body) = res.into_parts();
+    let bytes = body::to_bytes(res_body).await.map_err(|e| actix_web::error::ErrorInternalServerError(e.into().to_string()))?;
+    let wrapped = match serde_json::from_slice::<Value>(&bytes) {
+        Ok(data) => serde_json::to_vec(&Envelope::new(data)).map_err(actix_web::error::ErrorInternalServerError)?,
+        Err(_) => bytes.to_vec(),
+    };
+    head.headers_mut().remove(header::CONTENT_LENGTH);
+    Ok(ServiceResponse::new(req, head.set_body(BoxBody::new(wrapped))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body, read_body_json, TestRequest};
+    use actix_web::web;
+    use std::time::Duration;
+
+    use crate::balancer::{self, OLLAMA};
+    use crate::testing;
+
+    const ADMIN: (&str, &str) = ("Authorization", "Bearer secreto");
+
+    fn state() -> web::Data<crate::balancer::AppState> {
+        let state = testing::state(&["--admin-token", "secreto", "--dispatch-rate", "lmstudio=10:2"]);
+        testing::announce(&state, "lmstudio", "box1", &testing::chat_node(Duration::ZERO));
+        testing::announce(&state, "ollama", "box2", "http://10.0.0.2:11434/");
+        state.deregister_node(&OLLAMA, &testing::node_id("box2"));
+        state
+    }
+
+    fn content_type<B>(res: &ServiceResponse<B>) -> String {
+        res.headers().get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or_default().to_string()
+    }
+
+    /// `/v1/nodes` se lee entero con los tipos compartidos, sin campos de más en ningún nivel.
+    #[actix_web::test]
+    async fn node_list_deserializes_into_the_shared_types() {
+        let state = state();
+        let app = init_service(balancer::app(state.clone())).await;
+        let req = TestRequest::get().uri("/v1/nodes?include=removed").insert_header(ADMIN).to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), 200);
+        assert!(res.headers().get("deprecation").is_none());
+        let bytes = read_body(res).await;
+        let envelope: Envelope<NodeList> = serde_json::from_slice(&bytes).unwrap_or_else(|e| panic!("{}: {}", e, String::from_utf8_lossy(&bytes)));
+        assert_eq!(envelope.api_version, API_VERSION);
+        let list = envelope.data;
+        assert_eq!(list.nodes.len(), 1);
+        assert_eq!((list.nodes[0].node_id.as_str(), list.nodes[0].service.as_str()), ("box1", "lmstudio"));
+        assert_eq!(list.nodes[0].dispatch_rate.as_ref().map(|rate| (rate.per_second, rate.burst)), Some((10.0, 2)));
+        assert_eq!(list.removed.unwrap()[0]["node_id"], "box2");
+
+        // Un campo que el servidor añadiera sin tocar el tipo haría fallar la lectura.
+        let mut drifted: Value = serde_json::from_slice(&bytes).unwrap();
+        drifted["data"]["nodes"][0]["nuevo_campo"] = json!(1);
+        assert!(serde_json::from_value::<Envelope<NodeList>>(drifted.clone()).is_err());
+        assert!(check_example("NodeList", &drifted["data"]).unwrap().is_err());
+        drifted["data"]["nodes"][0].as_object_mut().unwrap().remove("nuevo_campo");
+        assert_eq!(check_example("NodeList", &drifted["data"]), Some(Ok(())));
+        assert_eq!(check_example("SinTipo", &drifted["data"]), None);
+    }
+
+    /// Cada ruta versionada responde igual con y sin prefijo; con prefijo, su JSON va en el sobre
+    /// y sin él lleva los avisos de obsolescencia.
+    #[actix_web::test]
+    async fn every_versioned_route_is_enveloped_under_v1_and_deprecated_without_it() {
+        let state = state();
+        let app = init_service(balancer::app(state.clone())).await;
+        let versioned: Vec<&str> = auth::ROUTES.iter().map(|(pattern, _)| *pattern).filter(|pattern| is_versioned(pattern)).collect();
+        assert!(versioned.contains(&"/nodes") && versioned.contains(&"/stats/summary"));
+        for pattern in versioned {
+            let path = pattern.replace("{id}", "box1");
+            let plain = call_service(&app, TestRequest::get().uri(&path).insert_header(ADMIN).to_request()).await;
+            let prefixed = call_service(&app, TestRequest::get().uri(&format!("/v1{}", path)).insert_header(ADMIN).to_request()).await;
+            assert_eq!(prefixed.status(), plain.status(), "{}", path);
+            assert_eq!(plain.headers().get("deprecation").map(|value| value.to_str().unwrap()), Some("true"), "{}", path);
+            // El `Link` sólo se pone si la ruta casó con su patrón.
+            let link = plain.headers().get(header::LINK).unwrap().to_str().unwrap().to_string();
+            assert_eq!(link, format!("</v1{}>; rel=\"successor-version\"", path));
+            if !content_type(&plain).starts_with("application/json") {
+                // Texto, SSE o NDJSON pasan sin sobre.
+                assert_eq!(content_type(&prefixed), content_type(&plain), "{}", path);
+                continue;
+            }
+            let plain: Value = read_body_json(plain).await;
+            let prefixed: Value = read_body_json(prefixed).await;
+            let keys: Vec<&String> = prefixed.as_object().unwrap().keys().collect();
+            assert_eq!(keys, ["api_version", "data"], "{}", path);
+            assert_eq!(prefixed["api_version"], API_VERSION, "{}", path);
+            assert_eq!(std::mem::discriminant(&prefixed["data"]), std::mem::discriminant(&plain), "{}", path);
+        }
+    }
+
+    #[actix_web::test]
+    async fn errors_are_enveloped_and_unversioned_routes_stay_out_of_v1() {
+        let state = state();
+        let app = init_service(balancer::app(state.clone())).await;
+
+        let req = TestRequest::post().uri("/v1/nodes/nadie/remove").insert_header(ADMIN).to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), 404);
+        let body: Value = read_body_json(res).await;
+        assert_eq!(body["api_version"], API_VERSION);
+        assert!(body["data"]["error"].as_str().unwrap().contains("nadie"), "{}", body);
+        // La autenticación ve la ruta sin prefijo.
+        let res = call_service(&app, TestRequest::post().uri("/v1/stats/reset").to_request()).await;
+        assert_eq!(res.status(), 401);
+
+        for (method, path) in [("POST", "/v1/lmstudio"), ("POST", "/v1/ollama"), ("GET", "/v1/"), ("GET", "/v1/openapi.json"), ("GET", "/v1/api/version"), ("GET", "/v1/no-existe")] {
+            let req = TestRequest::default().method(method.parse().unwrap()).uri(path).insert_header(ADMIN).to_request();
+            assert_eq!(call_service(&app, req).await.status(), 404, "{}", path);
+        }
+        // Las que no se versionan tampoco se marcan obsoletas.
+        let res = call_service(&app, TestRequest::get().uri("/openapi.json").to_request()).await;
+        assert!(res.headers().get("deprecation").is_none());
+        let res = call_service(&app, TestRequest::post().uri("/lmstudio").set_json(json!({ "messages": [{ "role": "user", "content": "hola" }] })).to_request()).await;
+        assert_eq!(res.status(), 200);
+        assert!(res.headers().get("deprecation").is_none());
+    }
+}
@@ -0,0 +1,61 @@
+// src/audit.rs
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Serialize;
+
+use crate::labels::RequestLabels;
+
+/// Versión del esquema de los registros exportados. Sólo se incrementa ante cambios incompatibles.
+pub const AUDIT_SCHEMA_VERSION: u32 = 1;
+
+/// Registro de auditoría de una petición. Nunca contiene el contenido de la petición ni de la respuesta.
+#[derive(Debug, Serialize)]
+pub struct AuditRecord {
+    pub schema_version: u32,
+    pub timestamp: DateTime<Utc>,
+    pub api_key: Option<String>,
+    pub service: String,
+    pub model: Option<String>,
+    pub prompt_tokens: Option<u64>,
+    pub completion_tokens: Option<u64>,
+    pub total_tokens: Option<u64>,
+    /// Los tokens son una estimación del balanceador: el nodo no mandó recuento (`usage`).
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub estimated: bool,
+    pub node_id: String,
+    /// Post-procesados aplicados a la respuesta (`strip-tokens`, `stop`, `trim`).
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub postprocess: Vec<String>,
+    /// Pool más barata de la que se desbordó la petición hasta `service`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spilled_from: Option<String>,
+    /// Motivo por el que el balanceador cortó la respuesta (`drain_timeout`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub terminated: Option<&'static str>,
+    /// Etiquetas de analítica de `X-LMServer-Labels`, con las forzadas por la key.
+    #[serde(skip_serializing_if = "RequestLabels::is_empty")]
+    pub labels: RequestLabels,
+}
+
+/// Acepta RFC 3339 (`2025-04-01T00:00:00Z`) o una fecha (`2025-04-01`, interpretada como medianoche UTC).
+pub fn parse_bound(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|dt| dt.with_timezone(&Utc))
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+                .map(|dt| dt.and_utc())
+        })
+}
+
+/// Forma enmascarada de la API key: identifica al cliente sin guardar el secreto.
+pub fn mask_api_key(key: &str) -> String {
+    let chars: Vec<char> = key.chars().collect();
+    if chars.len() <= 8 {
+        return "****".to_string();
+    }
+    let prefix: String = chars[..3].iter().collect();
+    let suffix: String = chars[chars.len() - 4..].iter().collect();
+    format!("{}...{}", prefix, suffix)
+}
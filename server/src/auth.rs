@@ -0,0 +1,413 @@
+// src/auth.rs
+//! Autorización por scopes de las rutas del balanceador.
+//!
+//! Cada ruta registrada tiene una entrada en `ROUTES` con el acceso que exige; el middleware
+//! `authorize` la busca por el patrón de la ruta y rechaza con 403 cualquier ruta sin entrada,
+//! así que una ruta nueva no queda abierta por olvido; `openapi::check` exige además que esté
+//! documentada. Quién llama se deduce del token Bearer:
+//!
+//! - `--admin-token`: todos los scopes salvo `usage:read`, que sigue siendo exclusivo de
+//!   `--audit-token` (o de una key con ese scope).
+//! - Una API key con `scopes = [...]` en `--api-keys-file`: sólo esos scopes.
+//! - Una API key sin `scopes`: sólo `inference`, como antes de existir los scopes.
+//!
+//! Las rutas de lectura (`nodes:read`, `stats:read`, `config:read`) siguen abiertas salvo con
+//! `--protect-read-endpoints`. La inferencia sólo se restringe a las keys con `scopes` que no
+//! incluyen `inference`; sin key, o con una key sin scopes, pasa como siempre.
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, HttpRequest, HttpResponse};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fmt;
+use subtle::ConstantTimeEq;
+
+use crate::balancer::{bearer_token, AppState};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Scope {
+    #[serde(rename = "inference")]
+    Inference,
+    #[serde(rename = "nodes:read")]
+    NodesRead,
+    /// Endpoints que modifican o cargan nodos (`PATCH /nodes/{id}/dispatch-rate`, `POST /nodes/{id}/diagnose`, `POST /nodes/{id}/reprobe`,
+    /// `PATCH /nodes/{id}/benchmark`, `POST /nodes/{id}/repin`, `POST /nodes/{id}/remove`).
+    #[serde(rename = "nodes:write")]
+    NodesWrite,
+    #[serde(rename = "stats:read")]
+    StatsRead,
+    #[serde(rename = "stats:write")]
+    StatsWrite,
+    #[serde(rename = "config:read")]
+    ConfigRead,
+    #[serde(rename = "config:write")]
+    ConfigWrite,
+    /// Endpoints `/debug/*`, que pueden mostrar prompts.
+    #[serde(rename = "debug")]
+    Debug,
+    /// Exportación del registro de auditoría y del uso diario.
+    #[serde(rename = "usage:read")]
+    UsageRead,
+}
+
+impl Scope {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Scope::Inference => "inference",
+            Scope::NodesRead => "nodes:read",
+            Scope::NodesWrite => "nodes:write",
+            Scope::StatsRead => "stats:read",
+            Scope::StatsWrite => "stats:write",
+            Scope::ConfigRead => "config:read",
+            Scope::ConfigWrite => "config:write",
+            Scope::Debug => "debug",
+            Scope::UsageRead => "usage:read",
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.label())
+    }
+}
+
+/// Acceso que exige una ruta.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    /// Abierta a todos (página de estado, versión).
+    Public,
+    /// Inferencia: las keys con scopes necesitan `inference`; el resto pasa.
+    Inference,
+    /// Lectura: abierta salvo con `--protect-read-endpoints`.
+    Read(Scope),
+    /// Exige siempre el scope.
+    Protected(Scope),
+}
+
+/// Acceso de cada ruta, por patrón. Toda ruta registrada en la `App` debe aparecer aquí.
+pub const ROUTES: &[(&str, Access)] = &[
+    ("/", Access::Public),
+    ("/version", Access::Public),
+    ("/api/version", Access::Public),
+    ("/readyz", Access::Public),
+    ("/status/public", Access::Public),
+    ("/openapi.json", Access::Public),
+    ("/lmstudio", Access::Inference),
+    ("/ollama", Access::Inference),
+    ("/pool/{name}", Access::Inference),
+    ("/api/tags", Access::Inference),
+    ("/nodes", Access::Read(Scope::NodesRead)),
+    ("/nodes/watch", Access::Read(Scope::NodesRead)),
+    ("/nodes/{id}", Access::Read(Scope::NodesRead)),
+    ("/nodes/{id}/history", Access::Read(Scope::NodesRead)),
+    ("/nodes/{id}/dispatch-rate", Access::Protected(Scope::NodesWrite)),
+    ("/nodes/{id}/diagnose", Access::Protected(Scope::NodesWrite)),
+    ("/nodes/{id}/reprobe", Access::Protected(Scope::NodesWrite)),
+    ("/nodes/{id}/benchmarks", Access::Read(Scope::NodesRead)),
+    ("/nodes/{id}/benchmark", Access::Protected(Scope::NodesWrite)),
+    ("/nodes/{id}/repin", Access::Protected(Scope::NodesWrite)),
+    ("/nodes/{id}/remove", Access::Protected(Scope::NodesWrite)),
+    ("/events", Access::Read(Scope::NodesRead)),
+    ("/metrics", Access::Read(Scope::StatsRead)),
+    ("/stats/summary", Access::Read(Scope::StatsRead)),
+    ("/stats/fairness", Access::Read(Scope::StatsRead)),
+    ("/stats/reset", Access::Protected(Scope::StatsWrite)),
+    ("/config", Access::Read(Scope::ConfigRead)),
+    ("/admin/profile", Access::Protected(Scope::ConfigWrite)),
+    ("/admin/reload", Access::Protected(Scope::ConfigWrite)),
+    ("/admin/config/rollback", Access::Protected(Scope::ConfigWrite)),
+    ("/admin/support-bundle", Access::Protected(Scope::ConfigRead)),
+    ("/debug/preview", Access::Protected(Scope::Debug)),
+    ("/debug/route", Access::Protected(Scope::Debug)),
+    ("/debug/memory", Access::Protected(Scope::Debug)),
+    ("/audit/export", Access::Protected(Scope::UsageRead)),
+    ("/usage/daily", Access::Protected(Scope::UsageRead)),
+];
+
+pub fn route_access(pattern: &str) -> Option<Access> {
+    ROUTES.iter().find(|(route, _)| *route == pattern).map(|(_, access)| *access)
+}
+
+/// Quién hace la petición, según su token Bearer.
+enum Caller<'a> {
+    Anonymous,
+    /// Token que no es de administración, de auditoría ni una API key conocida.
+    Unknown,
+    Admin,
+    Auditor,
+    Key { name: &'a str, scopes: Option<&'a [Scope]> },
+}
+
+impl<'a> Caller<'a> {
+    fn identify(state: &'a AppState, req: &HttpRequest) -> Self {
+        let Some(token) = bearer_token(req) else {
+            return Caller::Anonymous;
+        };
+        if token_matches(state.admin_token.as_deref(), token) {
+            return Caller::Admin;
+        }
+        if token_matches(state.audit_token.as_deref(), token) {
+            return Caller::Auditor;
+        }
+        match state.key_policies.lookup(token) {
+            Some((name, policy)) => Caller::Key { name, scopes: policy.scopes.as_deref() },
+            None => Caller::Unknown,
+        }
+    }
+
+    fn has(&self, scope: Scope) -> bool {
+        match self {
+            Caller::Anonymous | Caller::Unknown => false,
+            Caller::Admin => scope != Scope::UsageRead,
+            Caller::Auditor => scope == Scope::UsageRead,
+            Caller::Key { scopes: Some(scopes), .. } => scopes.contains(&scope),
+            Caller::Key { scopes: None, .. } => scope == Scope::Inference,
+        }
+    }
+}
+
+/// Compara el token con el configurado en tiempo constante, para no filtrar por el tiempo de
+/// respuesta cuántos caracteres del principio acierta.
+fn token_matches(expected: Option<&str>, token: &str) -> bool {
+    expected.is_some_and(|expected| bool::from(expected.as_bytes().ct_eq(token.as_bytes())))
+}
+
+/// Si alguna credencial configurada puede obtener el scope.
+fn grantable(state: &AppState, scope: Scope) -> bool {
+    match scope {
+        Scope::UsageRead => state.audit_token.is_some() || state.key_policies.grants(scope),
+        _ => state.admin_token.is_some() || state.key_policies.grants(scope),
+    }
+}
+
+/// Comprueba el acceso a una ruta. Devuelve la respuesta de rechazo si no se permite.
+pub fn check(state: &AppState, req: &HttpRequest, access: Access) -> Option<HttpResponse> {
+    let caller = Caller::identify(state, req);
+    let scope = match access {
+        Access::Public => return None,
+        Access::Inference => {
+            return match caller {
+                Caller::Key { name, scopes: Some(scopes) } if !scopes.contains(&Scope::Inference) => {
+                    Some(missing_scope(name, Scope::Inference))
+                }
+                _ => None,
+            };
+        }
+        Access::Read(_) if !state.protect_read_endpoints => return None,
+        Access::Read(scope) | Access::Protected(scope) => scope,
+    };
+    if caller.has(scope) {
+        return None;
+    }
+    match caller {
+        Caller::Key { name, .. } => Some(missing_scope(name, scope)),
+        Caller::Admin | Caller::Auditor => Some(HttpResponse::Forbidden().json(json!({
+            "error": format!("Este token no da acceso a '{}'.", scope),
+            "missing_scope": scope,
+        }))),
+        Caller::Anonymous | Caller::Unknown if !grantable(state, scope) => Some(HttpResponse::Forbidden().json(json!({
+            "error": format!("Ninguna credencial configurada tiene el scope '{}' (--admin-token, --audit-token o scopes en --api-keys-file).", scope),
+            "missing_scope": scope,
+        }))),
+        Caller::Anonymous | Caller::Unknown => Some(HttpResponse::Unauthorized().json(json!({
+            "error": format!("Falta un token Bearer válido con el scope '{}'.", scope),
+            "missing_scope": scope,
+        }))),
+    }
+}
+
+fn missing_scope(key_name: &str, scope: Scope) -> HttpResponse {
+    warn!("Auth: La API key '{}' no tiene el scope '{}'.", key_name, scope);
+    HttpResponse::Forbidden().json(json!({
+        "error": format!("La API key '{}' no tiene el scope '{}'.", key_name, scope),
+        "missing_scope": scope,
+    }))
+}
+
+/// Middleware de la `App`: aplica a cada petición el acceso de su ruta en `ROUTES`.
+pub async fn authorize(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    // Sin patrón no hay ruta: que responda el 404 de actix.
+    let Some(pattern) = req.match_pattern() else {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    };
+    let denied = match (route_access(&pattern), req.app_data::<web::Data<AppState>>()) {
+        (Some(access), Some(state)) => check(state, req.request(), access),
+        (None, _) => {
+            error!("Auth: La ruta {} no tiene acceso asignado en auth::ROUTES; se rechaza.", pattern);
+            Some(HttpResponse::Forbidden().json(json!({ "error": "Ruta sin scope asignado." })))
+        }
+        (Some(_), None) => Some(HttpResponse::InternalServerError().finish()),
+    };
+    match denied {
+        Some(response) => Ok(req.into_response(response).map_into_right_body()),
+        None => next.call(req).await.map(ServiceResponse::map_into_left_body),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::{web, App};
+    use serde_json::{json, Value};
+    use std::time::Duration;
+
+    use crate::balancer;
+    use crate::testing;
+
+    /// Una petición de cada grupo de rutas, con el scope que la protege.
+    const GROUPS: [(Scope, &str, &str); 9] = [
+        (Scope::Inference, "POST", "/lmstudio"),
+        (Scope::NodesRead, "GET", "/nodes"),
+        (Scope::NodesWrite, "POST", "/nodes/box1/remove"),
+        (Scope::StatsRead, "GET", "/stats/summary"),
+        (Scope::StatsWrite, "POST", "/stats/reset"),
+        (Scope::ConfigRead, "GET", "/config"),
+        (Scope::ConfigWrite, "POST", "/admin/reload"),
+        (Scope::Debug, "GET", "/debug/memory"),
+        (Scope::UsageRead, "GET", "/usage/daily"),
+    ];
+
+    /// Una key por scope (`sk-<scope>`, sección `<scope>` con `:` cambiado por `-`) y una sin scopes (`sk-plain`).
+    fn keys_file() -> std::path::PathBuf {
+        let mut keys = String::from("[keys.plain]\nkey = \"sk-plain\"\n");
+        for (scope, _, _) in GROUPS {
+            keys += &format!("\n[keys.{}]\nkey = \"sk-{}\"\nscopes = [\"{}\"]\n", scope.label().replace(':', "-"), scope, scope);
+        }
+        testing::temp_file("keys.toml", &keys)
+    }
+
+    /// Estado con un nodo falso `box0` que contesta enseguida, para que las inferencias permitidas
+    /// no esperen en la cola (`/nodes/box1/remove` apunta a otro nodo y no lo quita).
+    fn state_with_node(args: &[&str]) -> web::Data<AppState> {
+        let state = testing::state(args);
+        testing::announce(&state, "lmstudio", "box0", &testing::chat_node(Duration::ZERO));
+        state
+    }
+
+    fn request(method: &str, uri: &str, token: Option<&str>) -> TestRequest {
+        let req = match method {
+            "POST" => TestRequest::post().set_json(json!({ "model": "m", "messages": [{ "role": "user", "content": "hola" }] })),
+            _ => TestRequest::get(),
+        };
+        let req = req.uri(uri);
+        match token {
+            Some(token) => req.insert_header(("Authorization", format!("Bearer {}", token))),
+            None => req,
+        }
+    }
+
+    /// Lo que se espera de cada llamador en cada grupo.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum Expect {
+        Allowed,
+        /// 403 nombrando el scope que falta.
+        Forbidden,
+        /// 401: hace falta un token.
+        Unauthorized,
+    }
+
+    #[actix_web::test]
+    async fn each_route_group_allows_denies_and_challenges_by_scope() {
+        let keys = keys_file();
+        let state = state_with_node(&[
+            "--api-keys-file", keys.to_str().unwrap(),
+            "--admin-token", "admin",
+            "--audit-token", "audit",
+            "--protect-read-endpoints",
+        ]);
+        let app = init_service(balancer::app(state)).await;
+
+        for (scope, method, uri) in GROUPS {
+            let mut callers: Vec<(String, Option<String>, Expect)> = vec![
+                ("anónimo".into(), None, if scope == Scope::Inference { Expect::Allowed } else { Expect::Unauthorized }),
+                ("token desconocido".into(), Some("sk-nadie".into()), if scope == Scope::Inference { Expect::Allowed } else { Expect::Unauthorized }),
+                ("admin".into(), Some("admin".into()), if scope == Scope::UsageRead { Expect::Forbidden } else { Expect::Allowed }),
+                ("auditor".into(), Some("audit".into()), if matches!(scope, Scope::UsageRead | Scope::Inference) { Expect::Allowed } else { Expect::Forbidden }),
+                ("key sin scopes".into(), Some("sk-plain".into()), if scope == Scope::Inference { Expect::Allowed } else { Expect::Forbidden }),
+            ];
+            for (key_scope, _, _) in GROUPS {
+                let expect = if key_scope == scope { Expect::Allowed } else { Expect::Forbidden };
+                callers.push((format!("key {}", key_scope), Some(format!("sk-{}", key_scope)), expect));
+            }
+
+            for (caller, token, expect) in callers {
+                let resp = call_service(&app, request(method, uri, token.as_deref()).to_request()).await;
+                let status = resp.status().as_u16();
+                let what = format!("{} {} como {}: {}", method, uri, caller, status);
+                match expect {
+                    Expect::Allowed => assert!(status != 401 && status != 403, "{}", what),
+                    Expect::Forbidden | Expect::Unauthorized => {
+                        assert_eq!(status, if expect == Expect::Forbidden { 403 } else { 401 }, "{}", what);
+                        let body: Value = read_body_json(resp).await;
+                        assert_eq!(body["missing_scope"], scope.label(), "{}", what);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sin `--protect-read-endpoints` las lecturas siguen abiertas; las escrituras, no.
+    #[actix_web::test]
+    async fn read_routes_stay_open_unless_protected() {
+        let state = state_with_node(&["--admin-token", "admin"]);
+        let app = init_service(balancer::app(state)).await;
+
+        for (scope, method, uri) in GROUPS {
+            let status = call_service(&app, request(method, uri, None).to_request()).await.status().as_u16();
+            let open = matches!(scope, Scope::Inference | Scope::NodesRead | Scope::StatsRead | Scope::ConfigRead);
+            assert_eq!(status != 401 && status != 403, open, "{} {}: {}", method, uri, status);
+        }
+    }
+
+    /// Un scope que ninguna credencial puede tener se rechaza con 403, no pidiendo un token.
+    #[actix_web::test]
+    async fn scopes_nobody_holds_are_forbidden() {
+        let state = testing::state(&[]);
+        let app = init_service(balancer::app(state)).await;
+
+        let resp = call_service(&app, request("POST", "/stats/reset", None).to_request()).await;
+        assert_eq!(resp.status(), 403);
+        let body: Value = read_body_json(resp).await;
+        assert_eq!(body["missing_scope"], "stats:write");
+        assert!(body["error"].as_str().unwrap().contains("Ninguna credencial"), "{}", body);
+    }
+
+    /// Una ruta registrada sin entrada en `ROUTES` se rechaza para todos, también el admin.
+    #[actix_web::test]
+    async fn routes_without_a_table_entry_are_rejected() {
+        let state = testing::state(&["--admin-token", "admin"]);
+        let app = init_service(
+            App::new()
+                .app_data(state)
+                .wrap(from_fn(authorize))
+                .route("/nueva", web::get().to(|| async { HttpResponse::Ok().finish() }))
+                .route("/version", web::get().to(|| async { HttpResponse::Ok().finish() })),
+        )
+        .await;
+
+        for token in [None, Some("admin")] {
+            let resp = call_service(&app, request("GET", "/nueva", token).to_request()).await;
+            assert_eq!(resp.status(), 403);
+        }
+        assert_eq!(call_service(&app, request("GET", "/version", None).to_request()).await.status(), 200);
+        assert_eq!(call_service(&app, request("GET", "/no-existe", None).to_request()).await.status(), 404);
+    }
+
+    #[test]
+    fn route_table_has_one_entry_per_pattern_and_labels_match_serde() {
+        for (i, (route, _)) in ROUTES.iter().enumerate() {
+            assert!(ROUTES[..i].iter().all(|(other, _)| other != route), "{} aparece dos veces", route);
+        }
+        for (scope, _, _) in GROUPS {
+            assert_eq!(serde_json::to_value(scope).unwrap(), scope.label());
+        }
+    }
+}
@@ -1,263 +1,3107 @@
 // src/balancer.rs
-use actix_web::{post, web, App, HttpResponse, HttpServer, Responder};
-use std::collections::HashMap;
-use std::io::{self, Write};
-use std::sync::{Arc, RwLock};
+use actix_web::http::{header, StatusCode};
+use actix_web::middleware::from_fn;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::{get, post, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, Instant};
 use tokio::net::UdpSocket;
-use tokio::time::sleep;
+use tokio::time::{sleep, sleep_until};
+use tokio_util::sync::CancellationToken;
 use log::{info, warn, error, debug, trace};
-use url::Url;
+
+use crate::aliases::PoolAliases;
+use crate::api_types::{self, NodeList, NodeSummary};
+use crate::audit::{self, AuditRecord, AUDIT_SCHEMA_VERSION};
+use crate::auth;
+use crate::benchmark::{self, Benchmarks};
+use crate::build_info;
+use crate::canary::{self, CanaryPolicy, CanaryState, CanaryVerdict};
+use crate::cancel;
+use crate::capacity::{self, CapacityHints};
+use crate::clock;
+use crate::config::BalancerConfig;
+use crate::diagnose::{self, Diagnostics};
+use crate::discovery::{self, ModelsReassembler};
+use crate::dispatch_rate::{self, DispatchLimits, TokenBucket};
+use crate::dns::CachingResolver;
+use crate::domains::FailureDomains;
+use crate::drain::{self, DrainDeadlines};
+use crate::errors::{self, ErrorCategory, ErrorLog};
+use crate::events::{self, BalancerEvent, EventHub};
+use crate::fairness::{self, FairnessTracker};
+use crate::context::{self, ContextLimits, ContextRejection};
+use crate::reload::{self, ConfigHistory};
+use crate::retry::{self, RetryPolicies, RetrySite};
+use crate::tags::{self, NodeTags, TagRequirement};
+use crate::target;
+use crate::headers::{self, HeaderLimits, HeaderWhitelist, LimitedHeaders};
+use crate::reconcile;
+use crate::rpm::{RpmLimits, RpmWindow};
+use crate::rules::{self, RouteRequest, RuleAction, RuleSet};
+use crate::storage::{self, StorageReport};
+use crate::tasks::{BackgroundTasks, TASK_SHUTDOWN_TIMEOUT};
+use crate::tiers::NodeTiers;
+use crate::warm::WarmAffinity;
+use crate::index;
+use crate::interleave::{Interleaver, RequestLength};
+use crate::latency::{LatencyEwma, LatencyPolicy};
+use crate::keys::{self, KeyPolicies};
+use crate::labels;
+use crate::client_limit::{self, ClientLimiter};
+use crate::direct::DirectPools;
+use crate::limits::{self, BoundedStores, Limits};
+use crate::locality::Locality;
+use crate::listeners::{self, DiscoveryListener};
+use crate::history::{NodeHistory, TransitionCause};
+use crate::ids::{NodeId, PoolName, ServiceUrl};
+use crate::idempotency::{self, Claim, IdempotencyCache};
+use crate::loader::{self, ModelLoader};
+use crate::metrics::Metrics;
+use crate::models;
+use crate::ollama::{self, OllamaCache};
+use crate::openapi;
+use crate::pipeline::{self, PipelineReservations, PipelineToken};
+use crate::platform::{Platform, PlatformMatch};
+use crate::postprocess::{self, Postprocessors, ResponsePlan};
+use crate::persistence::{self, PersistenceBackend, Store};
+use crate::pins::{self, NodePins};
+use crate::preview;
+use crate::spill::{self, SpillPolicy};
+use crate::stats;
+use crate::status::{self, PublicField, RequestWindow};
+use crate::tombstones::{self, NodeStats, RemovalReason, Tombstones};
+use crate::tools::{self, CapabilityOverrides, InvalidToolCalls};
+use crate::ui;
+use crate::usage::{self, TokenCounts, UsageEstimator};
+use crate::profiles::{self, ProfileManager, RuntimeSettings};
+use crate::queues::{ModelQueues, WaitShape};
+use crate::user_affinity::UserAffinity;
+use crate::reprobe::{self, CapabilityProbe, Reprober};
+use crate::revisions::{self, RegistryRevisions};
+use crate::selection::{self, NodeSelector, SelectionContext};
+use crate::sessions::{self, Session, SessionAffinity};
+use crate::slow_lane::SlowLane;
+use crate::streaming::{self, NodeLease, StreamFraming, StreamLimiter};
+use crate::strictness::{self, PoolStrictness, Strictness};
+use crate::support::{self, RecentEvent, RecentRequest, Ring};
+use crate::validation;
+use crate::warmup::{self, WarmUp};
+use crate::workload::{self, WorkloadClass, WorkloadPolicy};
 
 #[derive(Clone, Debug)]
 pub enum NodeHealth {
     Available,
     Busy,
+    /// Reservado por el coordinador de carga de modelos mientras descarga/carga modelos.
+    Loading,
     Failed(Instant),
+    /// Anunció que se apagará (`DRAINING`): no recibe peticiones nuevas y sale del registro en
+    /// el instante indicado, salvo que vuelva a anunciarse antes.
+    Draining(Instant),
+}
+
+impl NodeHealth {
+    pub const FAILED_LABEL: &'static str = "failed";
+    pub const ABSENT_LABEL: &'static str = "absent";
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            NodeHealth::Available => "available",
+            NodeHealth::Busy => "busy",
+            NodeHealth::Loading => "loading",
+            NodeHealth::Failed(_) => Self::FAILED_LABEL,
+            NodeHealth::Draining(_) => "draining",
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct NodeInfo {
-    state: NodeHealth,
-    service_url: String,
-    last_seen: Instant,
+    pub(crate) state: NodeHealth,
+    pub(crate) service_url: ServiceUrl,
+    pub(crate) last_seen: Instant,
+    pub(crate) source: NodeSource,
+    pub(crate) models: Vec<String>,
+    /// Ventana de contexto por modelo anunciada por el nodo (`CONTEXT`).
+    pub(crate) context_windows: BTreeMap<String, u64>,
+    /// Ventana de contexto de todo el nodo (`MAX_CONTEXT`), para los modelos sin ventana propia.
+    pub(crate) max_context: Option<u64>,
+    /// Último informe de espacio del volumen de modelos (`STORAGE`).
+    pub(crate) storage: Option<StorageReport>,
+    /// Último error al reenviar al nodo, con su categoría (`connect_refused: ...`).
+    pub(crate) last_error: Option<String>,
+    /// Versión del binario anunciada por el nodo (`VERSION`); `None` en nodos antiguos o estáticos.
+    pub(crate) version: Option<String>,
+    /// Fichas de despacho si el nodo tiene un ritmo máximo (`--dispatch-rate`).
+    pub(crate) dispatch_bucket: Option<TokenBucket>,
+    /// Capacidades anunciadas por el nodo (`CAPS`) o halladas por `reprobe`; `--node-capability`
+    /// manda sobre ellas.
+    pub(crate) capabilities: BTreeSet<String>,
+    /// Versión que informa el backend del nodo (`BACKEND`).
+    pub(crate) backend_version: Option<String>,
+    pub(crate) capability_probe: CapabilityProbe,
+    /// Clase de la petición que lo ocupa; sólo vale mientras está Busy.
+    pub(crate) workload: Option<WorkloadClass>,
+    /// Longitud esperada de la petición que lo ocupa (`interleave`); sólo vale mientras está Busy.
+    pub(crate) length: Option<RequestLength>,
+    /// Peticiones despachadas al nodo que aún no han terminado (`InFlight`). Se comparte entre
+    /// las copias del registro para que una guarda siga descontando aunque el nodo se reanuncie.
+    pub(crate) in_flight: Arc<AtomicU32>,
+    /// Acumulados desde que se registró; pasan a la lápida al salir.
+    pub(crate) stats: NodeStats,
+    /// Peso anunciado (`WEIGHT`) para `--node-selection weighted`; 1 si no anuncia ninguno.
+    pub(crate) weight: u32,
+    /// Tope de peticiones por minuto anunciado (`MAX_RPM`); `--node-max-rpm` puede bajarlo (`rpm`).
+    pub(crate) max_rpm: Option<u32>,
+    /// Despachos del último minuto, si el nodo tiene tope.
+    pub(crate) rpm_window: RpmWindow,
+    /// Fase canaria en curso (`canary`); `None` cuando recibe tráfico completo.
+    pub(crate) canary: Option<CanaryState>,
+    /// Plataforma anunciada (`PLATFORM`); todo `unknown` si no anuncia ninguna.
+    pub(crate) platform: Platform,
+    /// Dominio de fallo anunciado (`DOMAIN`); `--node-domain` manda sobre él (`domains`).
+    pub(crate) domain: Option<String>,
+    /// Etiquetas `clave=valor` anunciadas (`TAGS`), para `X-Require-Tags`.
+    pub(crate) tags: NodeTags,
+    /// Desde cuándo está Busy o Loading; sólo vale mientras lo está (`reconcile`).
+    pub(crate) busy_since: Option<Instant>,
+    /// `X-Request-Id` de la última petición que se le reenvió, para los avisos de `reconcile`.
+    pub(crate) last_request_id: Option<String>,
+    /// Último `model` que atendió y cuándo se le despachó (`warm`).
+    pub(crate) last_model: Option<(String, Instant)>,
+    /// IP de origen de su último anuncio (UDP o latido gRPC), para `--local-prefix`.
+    pub(crate) source_ip: Option<IpAddr>,
+    /// Si la petición que lo ocupa es del carril lento (`slow_lane`); sólo vale mientras está Busy.
+    pub(crate) slow: bool,
+    /// Media móvil de lo que tardan sus respuestas (`latency`).
+    pub(crate) latency: LatencyEwma,
+    /// Último `SPOOLED` recibido: caída y peticiones acumuladas en ella.
+    pub(crate) spooled: Option<(u64, u64)>,
+}
+
+impl NodeInfo {
+    /// Nodo recién registrado, disponible y sin nada anunciado aún.
+    fn new(service_url: ServiceUrl, source: NodeSource) -> Self {
+        NodeInfo {
+            state: NodeHealth::Available,
+            service_url,
+            last_seen: Instant::now(),
+            source,
+            models: Vec::new(),
+            context_windows: BTreeMap::new(),
+            max_context: None,
+            storage: None,
+            last_error: None,
+            version: None,
+            dispatch_bucket: None,
+            capabilities: BTreeSet::new(),
+            backend_version: None,
+            capability_probe: CapabilityProbe::default(),
+            workload: None,
+            length: None,
+            in_flight: Arc::default(),
+            stats: NodeStats::default(),
+            weight: 1,
+            max_rpm: None,
+            rpm_window: RpmWindow::default(),
+            canary: None,
+            platform: Platform::default(),
+            domain: None,
+            tags: NodeTags::new(),
+            source_ip: None,
+            last_model: None,
+            busy_since: None,
+            last_request_id: None,
+            slow: false,
+            latency: LatencyEwma::default(),
+            spooled: None,
+        }
+    }
+
+    pub(crate) fn in_flight(&self) -> u32 {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+}
+
+/// Origen del registro de un nodo.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NodeSource {
+    /// Anunciado por un proceso nodo (UDP o mDNS); caduca si deja de anunciarse.
+    Announced,
+    /// Backend local configurado con `--local-node`; no caduca y se comprueba con health checks.
+    Local,
+    /// Backend local detectado al arrancar con `--auto-local`; igual que `Local`.
+    LocalAuto,
+}
+
+impl NodeSource {
+    pub fn label(&self) -> &'static str {
+        match self {
+            NodeSource::Announced => "announced",
+            NodeSource::Local => "local",
+            NodeSource::LocalAuto => "local-auto",
+        }
+    }
+}
+
+pub type NodeMap = Arc<RwLock<HashMap<NodeId, NodeInfo>>>;
+
+/// Motivo por el que no se registró un nodo.
+#[derive(Debug)]
+pub(crate) enum RegisterError {
+    UnknownService,
+    /// La pool está en su tope y no tiene nodos caídos que sustituir.
+    PoolFull(usize),
+}
+
+impl fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegisterError::UnknownService => f.write_str("servicio desconocido"),
+            RegisterError::PoolFull(cap) => write!(f, "la pool está llena ({} nodos) y no tiene nodos caídos que sustituir", cap),
+        }
+    }
 }
 
+impl std::error::Error for RegisterError {}
+
+/// Pools integradas; el resto de nombres que se aceptan son alias suyos (`--pool-alias`).
+pub(crate) static LMSTUDIO: PoolName = PoolName::from_static("lmstudio");
+pub(crate) static OLLAMA: PoolName = PoolName::from_static("ollama");
+
 pub struct AppState {
-    lm_studio_nodes: Arc<RwLock<HashMap<String, NodeInfo>>>,
-    ollama_nodes: Arc<RwLock<HashMap<String, NodeInfo>>>,
-    client: reqwest::Client,
-    listen_addr: String,
-    queue_timeout: Duration,
-    queue_poll_interval: Duration,
+    pub(crate) started_at: Instant,
+    pub(crate) lm_studio_nodes: NodeMap,
+    pub(crate) ollama_nodes: NodeMap,
+    pub(crate) node_history: Arc<RwLock<NodeHistory>>,
+    pub(crate) upstream_errors: RwLock<ErrorLog>,
+    pub(crate) client: reqwest::Client,
+    pub(crate) listen_addr: String,
+    pub(crate) queue_poll_interval: Duration,
+    /// Auditoría, uso diario e instantánea del registro (`--persistence`).
+    pub(crate) persistence: Option<Store>,
+    /// Estimación de tokens de los streams sin `usage` (`--usage-chars-per-token`).
+    pub(crate) usage_estimator: UsageEstimator,
+    pub(crate) audit_token: Option<String>,
+    pub(crate) admin_token: Option<String>,
+    /// Exige scope también en las rutas de lectura (`--protect-read-endpoints`).
+    pub(crate) protect_read_endpoints: bool,
+    pub(crate) metrics: Metrics,
+    pub(crate) header_whitelists: HashMap<String, HeaderWhitelist>,
+    pub(crate) header_limits: HeaderLimits,
+    pub(crate) postprocessors: Postprocessors,
+    pub(crate) context_limits: ContextLimits,
+    /// Política de reintentos de cada punto (`--retry-policy`).
+    pub(crate) retry: RetryPolicies,
+    pub(crate) pool_aliases: PoolAliases,
+    pub(crate) routing_rules: RwLock<RuleSet>,
+    /// Configuraciones aplicadas, para el diff y la vuelta atrás (`reload`).
+    pub(crate) config_history: ConfigHistory,
+    pub(crate) idempotency: IdempotencyCache,
+    pub(crate) fairness: FairnessTracker,
+    /// Criterio de `--node-selection`.
+    pub(crate) selector: Box<dyn NodeSelector>,
+    /// Niveles de `--node-tier`: los nodos de un nivel se prueban antes que los del siguiente.
+    pub(crate) tiers: NodeTiers,
+    pub(crate) capacity: CapacityHints,
+    pub(crate) revisions: RegistryRevisions,
+    /// Espacio libre mínimo en el volumen de modelos antes de marcar un nodo.
+    pub(crate) min_free_disk_bytes: u64,
+    pub(crate) events: EventHub,
+    /// Pools sin nodos disponibles, según el consumidor de alertas; la UI las resalta.
+    pub(crate) empty_pools: Mutex<BTreeSet<String>>,
+    pub(crate) dns_resolver: CachingResolver,
+    pub(crate) ollama_cache: OllamaCache,
+    pub(crate) profiles: ProfileManager,
+    pub(crate) stream_buffer_bytes: usize,
+    /// Silencio tras el que se envía un keep-alive en SSE; `None` lo desactiva.
+    pub(crate) sse_keepalive: Option<Duration>,
+    pub(crate) stream_limiter: StreamLimiter,
+    pub(crate) pipeline: PipelineReservations,
+    pub(crate) sessions: SessionAffinity,
+    /// Pares (nodo, versión) distintos de la del balanceador de los que ya se avisó.
+    pub(crate) key_policies: KeyPolicies,
+    pub(crate) model_loader: ModelLoader,
+    pub(crate) version_warnings: Mutex<HashSet<(String, String)>>,
+    pub(crate) bounded: BoundedStores,
+    pub(crate) discovery_listeners: Vec<Arc<DiscoveryListener>>,
+    pub(crate) dispatch_limits: DispatchLimits,
+    pub(crate) spill: SpillPolicy,
+    pub(crate) capability_overrides: CapabilityOverrides,
+    /// Dominios de fallo de los nodos (`--node-domain` y `DOMAIN`).
+    pub(crate) domains: FailureDomains,
+    pub(crate) rpm_limits: RpmLimits,
+    pub(crate) canary: CanaryPolicy,
+    pub(crate) locality: Locality,
+    pub(crate) direct: DirectPools,
+    pub(crate) client_limiter: ClientLimiter,
+    pub(crate) warm: WarmAffinity,
+    /// Margen de `reconcile` para un nodo Busy sin petición; `None` lo desactiva.
+    pub(crate) stale_busy_grace: Option<Duration>,
+    /// Peticiones en curso en nodos cuyo drenaje vence (`--drain-policy`).
+    pub(crate) drain: DrainDeadlines,
+    /// Repite una vez en otro nodo las respuestas con `tool_calls` mal formados.
+    pub(crate) retry_invalid_tool_calls: bool,
+    pub(crate) warmup: WarmUp,
+    pub(crate) diagnostics: Diagnostics,
+    pub(crate) reprober: Reprober,
+    /// Duraciones recientes para la vista pública.
+    pub(crate) request_window: RequestWindow,
+    pub(crate) public_status_fields: Vec<PublicField>,
+    /// La UI de terminal pinta la vista pública (`--ui-public`).
+    pub(crate) ui_public: bool,
+    /// Clases de carga y nodos reservados para interactivo.
+    pub(crate) workload: WorkloadPolicy,
+    pub(crate) interleave: Interleaver,
+    pub(crate) slow_lane: SlowLane,
+    pub(crate) model_queues: ModelQueues,
+    pub(crate) user_affinity: UserAffinity,
+    pub(crate) benchmarks: Benchmarks,
+    /// IP fijada a cada ID de nodo (`--node-pinning`).
+    pub(crate) pins: NodePins,
+    pub(crate) tombstones: Tombstones,
+    pub(crate) latency: Arc<LatencyPolicy>,
+    /// Últimas peticiones y eventos para el paquete de soporte.
+    pub(crate) recent_requests: Ring<RecentRequest>,
+    pub(crate) recent_events: Ring<RecentEvent>,
+}
+
+/// Lo que una petición exige del nodo que la atienda.
+#[derive(Clone, Copy, Debug)]
+struct NodeDemand<'a> {
+    capability: Option<&'a str>,
+    class: WorkloadClass,
+    length: RequestLength,
+    /// Plataforma preferida por una regla (`prefer`): esos nodos se prueban antes que el resto.
+    prefer: Option<&'a PlatformMatch>,
+    /// Campo `model` de la petición: en una pool que anuncia modelos, sólo los nodos que lo tienen.
+    model: Option<&'a str>,
+    /// Nodo al que está fijada la sesión de la petición (`sessions`): sólo se prueba ese.
+    only: Option<&'a str>,
+    /// Dominio de fallo del nodo que acaba de fallar al repetir la petición: sus nodos se prueban
+    /// los últimos.
+    avoid_domain: Option<&'a str>,
+    /// Prompt estimado más `max_tokens` (`context::check`): sólo nodos en cuya ventana cabe.
+    context_tokens: Option<u64>,
+    /// Etiquetas de `X-Require-Tags`: sólo nodos que las tienen todas.
+    tags: Option<&'a TagRequirement>,
+    /// Petición del carril lento (`slow_lane`): sólo nodos del carril y, sin carril, hasta el tope.
+    slow: bool,
 }
 
 impl AppState {
-    fn find_and_occupy_node(
-        nodes_lock: &Arc<RwLock<HashMap<String, NodeInfo>>>,
-    ) -> Option<(String, String)> {
+    /// Ocupa el siguiente nodo libre de la pool; con `capability`, sólo entre los que la tienen.
+    /// Una petición batch no pasa de los nodos que le deja la reserva interactiva.
+    fn find_and_occupy_node(&self, service: &PoolName, nodes_lock: &NodeMap, demand: NodeDemand) -> Option<(NodeId, ServiceUrl)> {
+        let NodeDemand { capability, class, length, prefer, model, only, avoid_domain, context_tokens, tags, slow } = demand;
         debug!(" -> Entrando a find_and_occupy_node...");
         let mut nodes = nodes_lock.write().unwrap();
+        let now = Instant::now();
+        if !self.workload.admits(class, service, &nodes) {
+            trace!("    -> Las peticiones batch ya ocupan todos los nodos no reservados de '{}'.", service);
+            return None;
+        }
+        if !self.interleave.admits(service, length) {
+            trace!("    -> Las peticiones largas de '{}' ceden el turno a las cortas en espera.", service);
+            return None;
+        }
+        if slow && !self.slow_lane.admits(&nodes) {
+            trace!("    -> Las peticiones del carril lento ya ocupan todos los nodos que pueden en '{}'.", service);
+            return None;
+        }
 
-        let found_node = nodes
-            .iter_mut()
-            .find(|(_id, info)| {
-                 trace!("    -> Verificando nodo ID: {} (URL: {}) - Estado: {:?}", _id, info.service_url, info.state);
-                 matches!(info.state, NodeHealth::Available)
-            });
+        // El criterio ordena; aquí se decide si cada nodo puede atender la petición. Sólo gasta
+        // ficha el nodo elegido: `find_map` para en el primero que la tiene.
+        let mut order = self.selector.order(&SelectionContext { service, nodes: &nodes });
+        if let Some(only) = only {
+            order.retain(|unique_id| unique_id.as_str() == only);
+        }
+        if self.locality.is_enabled() {
+            // Orden estable y el primero: los niveles y las reglas mandan sobre la red local.
+            order.sort_by_key(|unique_id| !nodes.get(unique_id).is_some_and(|info| self.locality.is_local(info)));
+        }
+        if self.warm.is_enabled() {
+            // Orden estable: un nodo con el modelo caliente gana a uno local; los niveles y las
+            // reglas mandan sobre los dos.
+            order.sort_by_key(|unique_id| !nodes.get(unique_id).is_some_and(|info| self.warm.is_warm(info, model, now)));
+        }
+        if self.tiers.is_enabled() {
+            // Orden estable: dentro de cada nivel sigue mandando el criterio de selección.
+            order.sort_by_key(|unique_id| nodes.get(unique_id).map_or(usize::MAX, |info| self.tiers.tier_of(&info.platform)));
+        }
+        if let Some(prefer) = prefer {
+            // Orden estable: dentro de cada grupo sigue mandando el criterio de selección (y el nivel).
+            order.sort_by_key(|unique_id| !nodes.get(unique_id).is_some_and(|info| prefer.matches(&info.platform)));
+        }
+        if let Some(avoid) = avoid_domain {
+            // Orden estable y el último: uno del mismo dominio sólo si no queda ninguno de otro.
+            order.sort_by_key(|unique_id| nodes.get(unique_id).and_then(|info| self.domains.domain_of(unique_id, info)) == Some(avoid));
+        }
+        let advertised = models::advertised(&nodes);
+        // Los canarios sólo se reparten si otro nodo de la pool puede atender la petición.
+        let throttle_canaries = self.canary.is_enabled()
+            && only.is_none()
+            && nodes.iter().any(|(unique_id, info)| info.canary.is_none() && self.node_usable(advertised, unique_id, info, demand));
+        let found_node = order.into_iter().find_map(|unique_id| {
+            let info = nodes.get_mut(&unique_id)?;
+            trace!("    -> Verificando nodo ID: {} (URL: {}) - Estado: {:?}", unique_id, info.service_url, info.state);
+            let eligible = matches!(info.state, NodeHealth::Available)
+                && models::eligible(advertised, info, model)
+                && capability.is_none_or(|capability| self.capability_overrides.has(&unique_id, &info.capabilities, capability))
+                && context_tokens.is_none_or(|needed| self.context_limits.node_fits(info, model, needed))
+                && tags.is_none_or(|tags| tags.matches(&info.tags))
+                && (!slow || self.slow_lane.node_admits(info))
+                && self.rpm_limits.admits(&unique_id, info, now)
+                && (!throttle_canaries || info.canary.as_mut().is_none_or(|canary| self.canary.admits(canary)))
+                && self.dispatch_limits.try_take(service, &unique_id, &mut info.dispatch_bucket, now);
+            let service_url = info.service_url.clone();
+            eligible.then_some((unique_id, service_url))
+        });
 
-        if let Some((unique_id, node_info)) = found_node {
-            debug!("    -> Nodo disponible encontrado ID: {}. Marcando como Busy.", unique_id);
-            node_info.state = NodeHealth::Busy;
-            Some((unique_id.clone(), node_info.service_url.clone()))
+        if let Some(found) = found_node {
+            debug!("    -> Nodo disponible encontrado ID: {}. Marcando como Busy.", found.0);
+            if let Some(tier) = nodes.get(&found.0).map(|info| self.tiers.tier_of(&info.platform)).filter(|tier| *tier > 0) {
+                debug!("    -> El nodo es del nivel {}: ninguno de un nivel anterior puede atender la petición.", tier);
+            }
+            if let Some(avoid) = avoid_domain.filter(|avoid| nodes.get(&found.0).and_then(|info| self.domains.domain_of(&found.0, info)) == Some(avoid)) {
+                debug!("    -> El nodo es del dominio '{}' del que falló: no queda otro que pueda atender la petición.", avoid);
+            }
+            if nodes.get(&found.0).is_some_and(|info| self.warm.is_warm(info, model, now)) {
+                debug!("    -> El nodo tiene caliente el modelo {:?}.", model);
+                self.metrics.warm_model_hits.fetch_add(1, Ordering::Relaxed);
+            }
+            self.selector.chosen(&SelectionContext { service, nodes: &nodes }, &found.0);
+            // La elegibilidad se toma con el lock aún tomado, antes de ocupar el nodo.
+            self.fairness.record(service, &nodes, &found.0, self.selector.weighted(), &self.tiers, &self.domains);
+            if let Some(node_info) = nodes.get_mut(&found.0) {
+                node_info.stats.dispatched += 1;
+                node_info.state = NodeHealth::Busy;
+                node_info.workload = Some(class);
+                node_info.length = Some(length);
+                node_info.slow = slow;
+                node_info.busy_since = Some(now);
+                self.rpm_limits.record(&found.0, node_info, now);
+                if throttle_canaries {
+                    if let Some(canary) = node_info.canary.as_mut() {
+                        self.canary.dispatched(canary);
+                    }
+                }
+                if let Some(model) = model {
+                    node_info.last_model = Some((model.to_string(), now));
+                }
+            }
+            self.interleave.dispatched(service, length);
+            self.capacity.record(service, &nodes);
+            self.revisions.bump(service, &found.0);
+            // Una prueba de rendimiento en curso en el nodo cede ante el tráfico real.
+            self.benchmarks.yield_node(&found.0);
+            Some(found)
         } else {
             debug!("    -> No se encontró ningún nodo disponible.");
             None
         }
     }
 
-    fn update_node_state(
-        nodes_lock: &Arc<RwLock<HashMap<String, NodeInfo>>>,
-        unique_node_id: &str,
+    pub(crate) fn update_node_state(
+        &self,
+        service: &PoolName,
+        unique_node_id: &NodeId,
         new_health: NodeHealth,
+        cause: TransitionCause,
     ) {
+        let Some(nodes_lock) = self.pool(service) else {
+            return;
+        };
         let mut nodes = nodes_lock.write().unwrap();
+        self.set_node_state(service, &mut nodes, unique_node_id, new_health, cause);
+    }
+
+    /// `update_node_state` con el lock de escritura de la pool ya tomado, para quien tiene que
+    /// comprobar el nodo y cambiarlo sin soltarlo entre medias (`reconcile`).
+    pub(crate) fn set_node_state(
+        &self,
+        service: &PoolName,
+        nodes: &mut HashMap<NodeId, NodeInfo>,
+        unique_node_id: &NodeId,
+        new_health: NodeHealth,
+        cause: TransitionCause,
+    ) {
         if let Some(node_info) = nodes.get_mut(unique_node_id) {
-             debug!("  -> Actualizando estado del nodo ID {} (URL: {}) a: {:?}", unique_node_id, node_info.service_url, new_health);
+             debug!("  -> Actualizando estado del nodo ID {} (URL: {}) a: {:?} (causa: {:?})", unique_node_id, node_info.service_url, new_health, cause);
+            // Un nodo drenándose sigue así hasta salir o volver a anunciarse: las peticiones en
+            // curso terminan sin devolverlo a la pool.
+            let new_health = match (&node_info.state, new_health) {
+                (NodeHealth::Draining(deadline), new_health) if !matches!(new_health, NodeHealth::Draining(_)) => NodeHealth::Draining(*deadline),
+                (_, new_health) => new_health,
+            };
+            // Al terminar una petición (o caer el nodo) se juzga su fase canaria.
+            let verdict = node_info
+                .canary
+                .as_ref()
+                .filter(|_| matches!(new_health, NodeHealth::Available | NodeHealth::Failed(_)))
+                .and_then(|canary| self.canary.verdict(canary, Instant::now()));
+            if let Some(verdict) = verdict {
+                self.end_canary(service, unique_node_id, node_info, verdict);
+            }
+            let (new_health, cause) = match verdict {
+                Some(CanaryVerdict::Failed) => (NodeHealth::Failed(Instant::now()), TransitionCause::Canary),
+                _ => (new_health, cause),
+            };
+            let from = node_info.state.label();
+            let to = new_health.label();
+            if matches!(new_health, NodeHealth::Busy | NodeHealth::Loading) && from != to {
+                node_info.busy_since = Some(Instant::now());
+            }
             node_info.state = new_health;
+            if to == NodeHealth::FAILED_LABEL && from != to {
+                node_info.stats.failures += 1;
+            }
+            if !matches!(node_info.state, NodeHealth::Busy) {
+                node_info.workload = None;
+                node_info.length = None;
+                node_info.slow = false;
+            }
+            if !matches!(node_info.state, NodeHealth::Busy | NodeHealth::Loading) {
+                node_info.busy_since = None;
+            }
+            // Las ocupaciones Available <-> Busy de cada petición no son transiciones de ciclo de
+            // vida; liberar un nodo por una fuga de estado, sí.
+            if from != to && (cause == TransitionCause::Reconcile || !(from == "busy" && to == "available")) {
+                self.record_transition(unique_node_id, service, from, to, cause);
+            }
+            self.capacity.record(service, nodes);
+            self.revisions.bump(service, unique_node_id);
         } else {
              warn!("  -> Intento de actualizar estado de nodo ID {} fallido (nodo no encontrado).", unique_node_id);
         }
     }
+
+    /// Punto único por el que pasa toda transición de ciclo de vida salvo las salidas del registro,
+    /// que pasan por `record_removal`. El historial y el resto de consumidores la reciben por el
+    /// bus de eventos.
+    pub(crate) fn record_transition(
+        &self,
+        unique_node_id: &NodeId,
+        service: &PoolName,
+        from: &'static str,
+        to: &'static str,
+        cause: TransitionCause,
+    ) {
+        let (node_id, service, timestamp) = (unique_node_id.to_string(), service.to_string(), chrono::Local::now().to_rfc3339());
+        let event = if from == NodeHealth::ABSENT_LABEL {
+            BalancerEvent::NodeRegistered { node_id, service, state: to, cause, timestamp }
+        } else {
+            BalancerEvent::StateChanged { node_id, service, from, to, cause, timestamp }
+        };
+        self.events.publish(event);
+    }
+
+    /// Anota la salida de un nodo del registro: deja su lápida y publica `node_removed` con su ID.
+    pub(crate) fn record_removal(&self, service: &PoolName, unique_node_id: &NodeId, info: &NodeInfo, reason: RemovalReason) {
+        let tombstone_id = self.tombstones.bury(service, unique_node_id, info, reason);
+        self.drain.node_removed(service, unique_node_id, reason == RemovalReason::Drained);
+        self.events.publish(BalancerEvent::NodeRemoved {
+            node_id: unique_node_id.to_string(),
+            service: service.to_string(),
+            from: info.state.label(),
+            cause: reason.cause(),
+            reason,
+            tombstone_id,
+            timestamp: chrono::Local::now().to_rfc3339(),
+        });
+    }
+
+    /// Nodo nuevo en la pool. Si tenía lápida se borra y, con `--restore-node-stats`, recupera
+    /// sus acumulados.
+    fn revived_node(&self, service: &PoolName, unique_node_id: &NodeId, service_url: ServiceUrl, source: NodeSource) -> NodeInfo {
+        let mut info = NodeInfo::new(service_url, source);
+        if let Some(stats) = self.tombstones.revive(service, unique_node_id) {
+            info.stats = stats;
+        }
+        info
+    }
+
+    /// Cierra la fase canaria del nodo: la quita o, si no la superó, empieza otra.
+    fn end_canary(&self, service: &PoolName, unique_node_id: &NodeId, info: &mut NodeInfo, verdict: CanaryVerdict) {
+        let Some(ended) = info.canary.take() else {
+            return;
+        };
+        let (requests, errors) = canary::counts(&ended);
+        let error_rate = canary::error_rate(&ended);
+        match verdict {
+            CanaryVerdict::Promoted => {
+                self.metrics.canary_promotions.fetch_add(1, Ordering::Relaxed);
+                info!("Canario: El nodo ID {} ({}) supera su fase canaria ({} errores en {} peticiones); pasa a tráfico completo.", unique_node_id, service, errors, requests);
+            }
+            CanaryVerdict::Failed => {
+                self.metrics.canary_failures.fetch_add(1, Ordering::Relaxed);
+                info.canary = self.canary.start();
+            }
+        }
+        self.events.publish(BalancerEvent::NodeCanaryEnded {
+            service: service.to_string(),
+            node_id: unique_node_id.to_string(),
+            outcome: verdict.label(),
+            requests,
+            errors,
+            error_rate,
+        });
+    }
+
+    /// Anota en la fase canaria del nodo, si está en ella, cómo terminó una petición.
+    fn record_canary(&self, nodes_lock: &NodeMap, unique_node_id: &NodeId, ok: bool) {
+        if let Some(canary) = nodes_lock.write().unwrap().get_mut(unique_node_id).and_then(|info| info.canary.as_mut()) {
+            self.canary.record(canary, ok);
+        }
+    }
+}
+
+/// Petición esperando nodo en la cola; se descuenta al salir de ella, termine como termine.
+struct QueuedRequest<'a> {
+    state: &'a AppState,
+    service: &'a str,
+}
+
+impl<'a> QueuedRequest<'a> {
+    fn enter(state: &'a AppState, service: &'a str) -> Self {
+        state.metrics.queued_requests.fetch_add(1, Ordering::Relaxed);
+        if let Some(pool) = state.capacity.pool(service) {
+            pool.queued.fetch_add(1, Ordering::Relaxed);
+        }
+        Self { state, service }
+    }
+}
+
+impl Drop for QueuedRequest<'_> {
+    fn drop(&mut self) {
+        self.state.metrics.queued_requests.fetch_sub(1, Ordering::Relaxed);
+        if let Some(pool) = self.state.capacity.pool(self.service) {
+            pool.queued.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Petición despachada a un nodo; cuenta en su `in_flight` hasta que se suelta, termine como termine.
+pub(crate) struct InFlight(Option<Arc<AtomicU32>>);
+
+impl InFlight {
+    fn start(nodes_lock: &NodeMap, unique_node_id: &str, request_id: &str) -> Self {
+        let counter = nodes_lock.write().unwrap().get_mut(unique_node_id).map(|info| {
+            info.last_request_id = Some(request_id.to_string());
+            info.in_flight.clone()
+        });
+        if let Some(counter) = &counter {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        Self(counter)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if let Some(counter) = &self.0 {
+            counter.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Cabeceras de la petición del cliente que la whitelist de la pool permite reenviar.
+fn forwarded_headers(req: &HttpRequest, whitelist: Option<&HeaderWhitelist>) -> Vec<(String, Vec<u8>)> {
+    let Some(whitelist) = whitelist else {
+        return Vec::new();
+    };
+    req.headers()
+        .iter()
+        .filter(|(name, _)| whitelist.allows(name.as_str()))
+        .map(|(name, value)| (name.as_str().to_string(), value.as_bytes().to_vec()))
+        .collect()
+}
+
+/// Reenvía la petición al nodo. Si no se puede conectar, la repite según `--retry-policy
+/// forward`, sin esperar más de lo que la petición esperaría en la cola. `timeout` sustituye al
+/// tiempo de respuesta del cliente HTTP (carril lento).
+async fn forward_request(
+    state: &AppState,
+    node_service_url: &ServiceUrl,
+    headers: Vec<(String, Vec<u8>)>,
+    req_body: web::Bytes,
+    timeout: Option<Duration>,
+) -> Result<reqwest::Response, reqwest::Error> {
+     debug!("  -> forward_request: Enviando POST a {} con body size: {} y {} cabeceras reenviadas", node_service_url, req_body.len(), headers.len());
+     let deadline = Instant::now() + state.profiles.settings().queue_timeout();
+     retry::execute(&state.retry, RetrySite::Forward, &state.metrics, deadline, reqwest::Error::is_connect, || {
+         let mut request = state.client.post(node_service_url.as_str())
+             .header(reqwest::header::CONTENT_TYPE, "application/json");
+         for (name, value) in &headers {
+             request = request.header(name.as_str(), value.as_slice());
+         }
+         if let Some(timeout) = timeout {
+             request = request.timeout(timeout);
+         }
+         let sent = request.body(req_body.clone()).send();
+         async move {
+             sent.await.inspect_err(|e| {
+                 if e.is_connect() {
+                     // El nodo puede haber cambiado de IP (DHCP): se re-resuelve antes del siguiente intento.
+                     if let Some(url::Host::Domain(host)) = node_service_url.host() {
+                         state.dns_resolver.invalidate(host);
+                     }
+                 }
+             })
+         }
+     })
+     .await
+}
+
+/// Repite una vez, en otro nodo libre de la pool, una petición cuya respuesta traía `tool_calls`
+/// mal formados en `failed`; mejor en uno de otro dominio de fallo. Devuelve el nodo, aún
+/// ocupado, y su respuesta si esta vez es válida.
+async fn retry_tool_calls(
+    state: &AppState,
+    service: &PoolName,
+    nodes_lock: &NodeMap,
+    demand: NodeDemand<'_>,
+    failed: &NodeId,
+    headers: Vec<(String, Vec<u8>)>,
+    req_body: web::Bytes,
+) -> Option<(NodeId, ServiceUrl, web::Bytes)> {
+    let failed_domain = nodes_lock.read().unwrap().get(failed).and_then(|info| state.domains.domain_of(failed, info).map(str::to_string));
+    let demand = NodeDemand { avoid_domain: failed_domain.as_deref(), ..demand };
+    let Some((unique_node_id, node_service_url)) = state.find_and_occupy_node(service, nodes_lock, demand) else {
+        debug!("  -> No hay otro nodo libre en '{}' para repetir la llamada a herramientas.", service);
+        return None;
+    };
+    let request_id = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(cancel::REQUEST_ID_HEADER))
+        .map(|(_, value)| String::from_utf8_lossy(value).into_owned())
+        .unwrap_or_default();
+    let _in_flight = InFlight::start(nodes_lock, &unique_node_id, &request_id);
+    state.metrics.tool_call_retries.fetch_add(1, Ordering::Relaxed);
+    info!("  -> Repitiendo la petición con tools en el nodo ID {}.", unique_node_id);
+    let forwarded_at = Instant::now();
+    let timeout = state.slow_lane.request_timeout().filter(|_| demand.slow);
+    let forwarded = forward_request(state, &node_service_url, headers, req_body, timeout).await;
+    let succeeded = forwarded.as_ref().is_ok_and(|response| response.status().is_success());
+    state.record_latency(nodes_lock, &unique_node_id, succeeded.then(|| forwarded_at.elapsed()));
+    state.record_canary(nodes_lock, &unique_node_id, forwarded.as_ref().is_ok_and(|response| !response.status().is_server_error()));
+    let response = match forwarded {
+        Ok(response) => response,
+        Err(e) => {
+            let category = state.record_upstream_error(service, &unique_node_id, &e);
+            error!("  -> Error al repetir la petición en el nodo ID {} [{}]: {}", unique_node_id, category.label(), e);
+            state.update_node_state(service, &unique_node_id, NodeHealth::Failed(Instant::now()), TransitionCause::RequestFailure);
+            return None;
+        }
+    };
+    let status = response.status();
+    let body = match response.bytes().await {
+        Ok(body) if status.is_success() => body,
+        Ok(_) => {
+            warn!("  -> El nodo ID {} respondió al reintento con estado no exitoso: {}", unique_node_id, status);
+            state.update_node_state(service, &unique_node_id, NodeHealth::Failed(Instant::now()), TransitionCause::RequestFailure);
+            return None;
+        }
+        Err(e) => {
+            let category = state.record_upstream_error(service, &unique_node_id, &e);
+            error!("  -> Error al leer el reintento del nodo ID {} [{}]: {}", unique_node_id, category.label(), e);
+            state.update_node_state(service, &unique_node_id, NodeHealth::Failed(Instant::now()), TransitionCause::RequestFailure);
+            return None;
+        }
+    };
+    match tools::validate_tool_calls(&body) {
+        Ok(()) => Some((unique_node_id, node_service_url, body)),
+        Err(invalid) => {
+            state.record_invalid_tool_calls(service, &unique_node_id, &invalid);
+            state.update_node_state(service, &unique_node_id, NodeHealth::Available, TransitionCause::RequestCompleted);
+            None
+        }
+    }
+}
+
+/// Espera hasta `deadline` un nodo libre de la pool para reenviarle una petición que ya había
+/// salido hacia otro.
+async fn wait_for_node(state: &AppState, service: &PoolName, nodes_lock: &NodeMap, demand: NodeDemand<'_>, deadline: Instant) -> Option<(NodeId, ServiceUrl)> {
+    loop {
+        if let Some(found) = state.find_and_occupy_node(service, nodes_lock, demand) {
+            return Some(found);
+        }
+        if Instant::now() >= deadline {
+            return None;
+        }
+        sleep(state.queue_poll_interval).await;
+    }
+}
+
+/// Respuesta de error con el formato de la API de OpenAI.
+fn openai_error(status: StatusCode, error_type: &str, param: Option<&str>, message: &str) -> HttpResponse {
+    HttpResponse::build(status).json(serde_json::json!({
+        "error": {
+            "message": message,
+            "type": error_type,
+            "param": param,
+        }
+    }))
+}
+
+/// Intenta explicar por qué llegó un cuerpo vacío a partir de las cabeceras de la petición.
+fn empty_body_hint(req: &HttpRequest) -> &'static str {
+    let headers = req.headers();
+    let content_length = headers.get(header::CONTENT_LENGTH).and_then(|v| v.to_str().ok());
+    if content_length.is_none() && !headers.contains_key(header::TRANSFER_ENCODING) {
+        "La petición no declara Content-Length ni Transfer-Encoding: probablemente es un GET reconvertido en POST o un proxy descartó el cuerpo."
+    } else if !headers.contains_key(header::CONTENT_TYPE) {
+        "Falta la cabecera Content-Type: algunos clientes no envían el cuerpo sin 'Content-Type: application/json'."
+    } else if content_length == Some("0") {
+        "El cliente envió 'Content-Length: 0'; revisa que serialice el cuerpo antes de enviarlo."
+    } else {
+        "Revisa que el cliente o algún proxy intermedio no esté descartando el cuerpo."
+    }
+}
+
+pub(crate) fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+/// Cliente de una petición para los topes y la idempotencia: su API key o, sin ella, la IP de la
+/// conexión.
+fn client_identity(req: &HttpRequest) -> String {
+    match bearer_token(req) {
+        Some(key) => format!("key:{}", key),
+        None => format!("ip:{}", req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default()),
+    }
+}
+
+/// Extrae el recuento de tokens de una respuesta OpenAI (`usage`) u Ollama nativa (`prompt_eval_count`/`eval_count`).
+fn response_usage(body: &[u8]) -> TokenCounts {
+    serde_json::from_slice::<serde_json::Value>(body).map_or((None, None, None), |json| usage::json_usage(&json))
+}
+
+async fn handle_service_request(
+    service_name: &str,
+    service: &PoolName,
+    nodes_lock: NodeMap,
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    req_body: web::Bytes,
+    request_id: String,
+) -> HttpResponse {
+    let settings = state.profiles.settings();
+    let queue_timeout = settings.queue_timeout();
+    let queue_poll_interval = state.queue_poll_interval;
+    info!("Balancer handle_service_request para '{}' RECIBIDO [{}].", service_name, request_id);
+    debug!("  -> Tamaño del body recibido: {} bytes", req_body.len());
+
+    if !req_body.is_empty() && req_body.len() < 1024 {
+         match std::str::from_utf8(&req_body) {
+             Ok(body_str) => trace!("  -> Contenido del body recibido: {}", body_str),
+             Err(_) => trace!("  -> Contenido del body recibido: (No es UTF-8 válido o muy largo)"),
+         }
+    } else if req_body.is_empty() {
+         debug!("  -> Contenido del body recibido: ¡¡¡VACÍO!!!");
+         state.metrics.rejected_empty_bodies.fetch_add(1, Ordering::Relaxed);
+         let hint = empty_body_hint(&req);
+         warn!("  -> Rechazando petición '{}' con cuerpo vacío: {}", service_name, hint);
+         return openai_error(
+             StatusCode::BAD_REQUEST,
+             "invalid_request_error",
+             None,
+             &format!("El cuerpo de la petición está vacío; se esperaba un JSON. {}", hint),
+         );
+    }
+
+    // Una petición mal formada se rechaza aquí, sin ocupar ningún nodo.
+    let chat_request = match validation::validate_chat_request(&req_body) {
+        Ok(chat_request) => chat_request,
+        Err(e) => {
+            state.metrics.rejected_invalid_requests.fetch_add(1, Ordering::Relaxed);
+            warn!("  -> Rechazando petición '{}' inválida: {}", service_name, e);
+            return openai_error(StatusCode::BAD_REQUEST, "invalid_request_error", e.param.as_deref(), &e.to_string());
+        }
+    };
+    let request_strictness = settings.strictness.of(service);
+    if request_strictness != Strictness::Off {
+        let unknown = strictness::unknown_fields(service, &chat_request.extra);
+        if let Some(first) = unknown.first() {
+            state.metrics.strict_request_violations.fetch_add(1, Ordering::Relaxed);
+            let message = format!("Campos de primer nivel desconocidos: {}.", unknown.join(", "));
+            if request_strictness == Strictness::Enforce {
+                state.metrics.rejected_invalid_requests.fetch_add(1, Ordering::Relaxed);
+                warn!("  -> Rechazando petición '{}' (strictness enforce): {}", service_name, message);
+                return openai_error(StatusCode::BAD_REQUEST, "invalid_request_error", Some(first), &message);
+            }
+            warn!("  -> Petición '{}' con campos desconocidos (strictness warn): {}", service_name, message);
+        }
+    }
+    let (mut model, wants_stream, max_tokens, wants_tools, user_field) = (
+        chat_request.model,
+        chat_request.stream == Some(true),
+        chat_request.max_tokens,
+        tools::requests_tools(&chat_request.extra),
+        chat_request.extra.get("user").and_then(serde_json::Value::as_str).map(str::to_string),
+    );
+
+    let required_tags = match TagRequirement::from_headers(req.headers()) {
+        Ok(tags) => tags,
+        Err(e) => {
+            warn!("  -> Rechazando petición '{}': {} inválida: {}.", service_name, tags::REQUIRE_TAGS_HEADER, e);
+            return openai_error(StatusCode::BAD_REQUEST, "invalid_request_error", None, &format!("{} inválida: {}.", tags::REQUIRE_TAGS_HEADER, e));
+        }
+    };
+
+    // Etiquetas de analítica: se validan aquí, con las forzadas o prohibidas por la key, y nunca
+    // salen hacia el nodo.
+    let request_labels = labels::from_headers(req.headers()).and_then(|mut request_labels| {
+        if let Some((_, policy)) = bearer_token(&req).and_then(|key| state.key_policies.lookup(key)) {
+            policy.labels.apply(&mut request_labels)?;
+        }
+        Ok(request_labels)
+    });
+    let request_labels = match request_labels {
+        Ok(request_labels) => request_labels,
+        Err(e) => {
+            warn!("  -> Rechazando petición '{}': {} inválida: {}.", service_name, labels::LABELS_HEADER, e);
+            return openai_error(StatusCode::BAD_REQUEST, "invalid_request_error", None, &format!("{} inválida: {}.", labels::LABELS_HEADER, e));
+        }
+    };
+
+    let target_node = match target::from_headers(req.headers()) {
+        Ok(target_node) => target_node,
+        Err(e) => {
+            warn!("  -> Rechazando petición '{}': {} inválida: {}.", service_name, target::TARGET_NODE_HEADER, e);
+            return openai_error(StatusCode::BAD_REQUEST, "invalid_request_error", None, &format!("{} inválida: {}.", target::TARGET_NODE_HEADER, e));
+        }
+    };
+
+    let header_class = match WorkloadClass::from_headers(req.headers()) {
+        Ok(class) => class,
+        Err(value) => {
+            warn!("  -> Rechazando petición '{}': clase de carga desconocida '{}'.", service_name, value);
+            return openai_error(
+                StatusCode::BAD_REQUEST,
+                "invalid_request_error",
+                None,
+                &format!("{} inválida '{}': se esperaba 'interactive' o 'batch'.", workload::WORKLOAD_CLASS_HEADER, value),
+            );
+        }
+    };
+
+    // Política de la API key: se resuelve una vez y rellena/fuerza parámetros antes de elegir nodo.
+    let mut overridden: Vec<&'static str> = Vec::new();
+    let mut pinned_pool = None;
+    let mut key_class = None;
+    let mut key_max_in_flight = None;
+    let req_body = match bearer_token(&req).and_then(|key| state.key_policies.lookup(key)) {
+        Some((policy_name, policy)) => match policy.apply(&req_body) {
+            Ok(applied) => {
+                debug!("  -> Aplicada la política de API key '{}'.", policy_name);
+                model = applied.model;
+                overridden = applied.overridden;
+                pinned_pool = policy.pool.as_deref();
+                key_class = policy.class;
+                key_max_in_flight = policy.max_in_flight;
+                applied.body.unwrap_or(req_body)
+            }
+            Err(denied) => {
+                warn!("  -> La API key '{}' no puede usar el modelo {:?}.", policy_name, denied.model);
+                return openai_error(
+                    StatusCode::FORBIDDEN,
+                    "model_not_allowed",
+                    Some("model"),
+                    &format!(
+                        "Esta API key no puede usar el modelo {}. Modelos permitidos: {}.",
+                        denied.model.as_deref().unwrap_or("(ninguno)"),
+                        denied.allowed.join(", ")
+                    ),
+                );
+            }
+        },
+        None => req_body,
+    };
+
+    // Una key con `pool` manda sus peticiones a esa pool; una regla que coincida manda sobre ella.
+    let pinned = pinned_pool.and_then(|pool| state.resolve_pool(pool));
+    let (service_name, service, nodes_lock) = match pinned.clone() {
+        Some(resolved) => {
+            debug!("  -> La API key fija la pool '{}'.", resolved.1);
+            resolved
+        }
+        None => (service_name, service, nodes_lock),
+    };
+
+    // Reglas de enrutado por contenido: la primera que coincide decide la pool o rechaza.
+    let (service_name, service, nodes_lock, routed_by_rule, rule_class, rule_prefer) = {
+        let rules = state.routing_rules.read().unwrap();
+        let matched = if rules.len() == 0 {
+            None
+        } else {
+            let body: serde_json::Value = serde_json::from_slice(&req_body).unwrap_or_default();
+            let api_key_name = bearer_token(&req).and_then(|key| state.key_policies.lookup(key)).map(|(name, _)| name);
+            rules
+                .evaluate(&RouteRequest { api_key_name, headers: req.headers(), body: &body })
+                .map(|rule| (rule.name.clone(), rule.action.clone()))
+        };
+        drop(rules);
+        match matched {
+            Some((rule, RuleAction::Reject(message))) => {
+                warn!("  -> Petición '{}' rechazada por la regla '{}'.", service_name, rule);
+                return openai_error(StatusCode::FORBIDDEN, "rejected_by_rule", None, &message);
+            }
+            Some((rule, RuleAction::Route(target))) => match state.resolve_pool(&target) {
+                Some((service_name, service, nodes_lock)) => {
+                    debug!("  -> La regla '{}' enruta la petición a la pool '{}'.", rule, service);
+                    (service_name, service, nodes_lock, true, None, None)
+                }
+                None => (service_name, service, nodes_lock, false, None, None),
+            },
+            Some((rule, RuleAction::Classify(class))) => {
+                debug!("  -> La regla '{}' clasifica la petición como {}.", rule, class.label());
+                (service_name, service, nodes_lock, false, Some(class), None)
+            }
+            Some((rule, RuleAction::Prefer(platform))) => {
+                debug!("  -> La regla '{}' prefiere los nodos con plataforma {:?}.", rule, platform);
+                (service_name, service, nodes_lock, false, None, Some(platform))
+            }
+            None => (service_name, service, nodes_lock, false, None, None),
+        }
+    };
+    let class = state.workload.classify(rule_class, key_class, header_class);
+    let length = state.interleave.classify(wants_stream, max_tokens);
+
+    // Una petición enorme va al carril lento; sin pool fijada por key ni regla, a la del carril.
+    let slow_reason = state.slow_lane.classify(&req_body);
+    let slow = slow_reason.is_some();
+    let lane_pool = state.slow_lane.pool().filter(|_| slow && pinned.is_none() && !routed_by_rule).and_then(|pool| state.resolve_pool(pool));
+    if let Some(reason) = &slow_reason {
+        state.metrics.slow_lane_requests.fetch_add(1, Ordering::Relaxed);
+        info!("  -> Carril lento ({}): {}.", reason, state.slow_lane.lane_label());
+    }
+    let queue_timeout = if slow { state.slow_lane.queue_timeout(queue_timeout) } else { queue_timeout };
+    let request_timeout = state.slow_lane.request_timeout().filter(|_| slow);
+
+    // Sin pool fijada por key, regla ni carril lento, una pool con coste reparte por su cadena
+    // desde la más barata, y una con `--pool-spillover` puede acabar en su pool de desborde.
+    let spillover = state.spill.spillover_for(service).filter(|_| pinned.is_none() && !routed_by_rule && lane_pool.is_none());
+    let mut candidates: Vec<(&str, &PoolName, NodeMap)> = match lane_pool {
+        Some(lane_pool) => vec![lane_pool],
+        None => match state.spill.chain_for(service).filter(|_| pinned.is_none() && !routed_by_rule) {
+            Some(chain) => chain.filter_map(|pool| state.resolve_pool(pool)).collect(),
+            None => std::iter::once((service_name, service, nodes_lock)).chain(spillover.and_then(|to| state.resolve_pool(to))).collect(),
+        },
+    };
+    let (service_name, service, nodes_lock) = candidates[0].clone();
+
+    // Las cabeceras a reenviar se recortan a los límites antes de ocupar un nodo, con la whitelist
+    // de cada pool a la que puede ir la petición. No se desborda a una pool que las rechazaría.
+    let mut limited_headers = Vec::with_capacity(candidates.len());
+    for (index, (_, candidate, _)) in candidates.iter().enumerate() {
+        match state.header_limits.apply(forwarded_headers(&req, state.header_whitelists.get(candidate.as_str()))) {
+            Ok(limited) => limited_headers.push(Some(limited)),
+            Err(e) if index == 0 => {
+                state.metrics.rejected_oversized_authorization.fetch_add(1, Ordering::Relaxed);
+                warn!("  -> Rechazando petición '{}': {}", service_name, e);
+                return openai_error(StatusCode::BAD_REQUEST, "invalid_request_error", None, &e.to_string());
+            }
+            Err(e) => {
+                warn!("  -> La petición no podrá desbordar a '{}': {}", candidate, e);
+                limited_headers.push(None);
+            }
+        }
+    }
+
+    // Con X-Target-Node la petición sólo puede ir a la pool que tiene ese nodo.
+    let (service_name, service, nodes_lock) = match &target_node {
+        Some(target_node) => {
+            let index = candidates
+                .iter()
+                .zip(&limited_headers)
+                .position(|((_, _, lock), headers)| headers.is_some() && lock.read().unwrap().contains_key(target_node.as_str()));
+            let Some(index) = index else {
+                let known: BTreeSet<String> = candidates.iter().flat_map(|(_, _, lock)| lock.read().unwrap().keys().map(ToString::to_string).collect::<Vec<_>>()).collect();
+                let listed = if known.is_empty() { "(ninguno)".to_string() } else { known.iter().cloned().collect::<Vec<_>>().join(", ") };
+                let message = format!("Ningún nodo de {} tiene el ID '{}'. Nodos conocidos: {}.", service_name, target_node, listed);
+                warn!("  -> Rechazando petición '{}': {}", service_name, message);
+                return HttpResponse::NotFound().json(serde_json::json!({
+                    "error": {
+                        "message": message,
+                        "type": "node_not_found",
+                        "param": null,
+                        "known_node_ids": known,
+                    }
+                }));
+            };
+            candidates.swap(0, index);
+            candidates.truncate(1);
+            limited_headers.swap(0, index);
+            limited_headers.truncate(1);
+            info!("  -> {}: la petición espera al nodo ID {} de '{}'.", target::TARGET_NODE_HEADER, target_node, candidates[0].1);
+            candidates[0].clone()
+        }
+        None => (service_name, service, nodes_lock),
+    };
+
+    // Una petición con `tools` sólo va a nodos que saben llamar a herramientas.
+    let capability = wants_tools.then_some(tools::TOOL_CALLING);
+    if let Some(capability) = capability {
+        let capable = candidates
+            .iter()
+            .zip(&limited_headers)
+            .any(|((_, _, lock), headers)| headers.is_some() && state.pool_has_capability(lock, capability));
+        if !capable {
+            state.metrics.rejected_missing_capability.fetch_add(1, Ordering::Relaxed);
+            warn!("  -> Rechazando petición '{}': ningún nodo tiene la capacidad '{}'.", service_name, capability);
+            return openai_error(
+                StatusCode::NOT_IMPLEMENTED,
+                "capability_unavailable",
+                Some("tools"),
+                &format!("Ningún nodo de {} tiene la capacidad '{}' que exige una petición con tools.", service_name, capability),
+            );
+        }
+    }
+
+    // En las pools que anuncian modelos, la petición sólo va a nodos con su `model`.
+    let routable = candidates
+        .iter()
+        .zip(&limited_headers)
+        .any(|((_, _, lock), headers)| headers.is_some() && models::routable(&lock.read().unwrap(), model.as_deref()));
+    if !routable {
+        state.metrics.rejected_unknown_model.fetch_add(1, Ordering::Relaxed);
+        let mut available = BTreeSet::new();
+        for (_, _, lock) in &candidates {
+            models::available(&lock.read().unwrap(), &mut available);
+        }
+        let available: Vec<String> = available.into_iter().collect();
+        let message = match model.as_deref() {
+            Some(model) => format!("Ningún nodo de {} tiene el modelo '{}'. Modelos disponibles: {}.", service_name, model, available.join(", ")),
+            None => format!("La petición no indica 'model'. Modelos disponibles en {}: {}.", service_name, available.join(", ")),
+        };
+        warn!("  -> Rechazando petición '{}': {}", service_name, message);
+        return HttpResponse::NotFound().json(serde_json::json!({
+            "error": {
+                "message": message,
+                "type": "model_not_found",
+                "param": "model",
+                "available_models": available,
+            }
+        }));
+    }
+
+    // Con X-Require-Tags, si ningún nodo que puede llegar a atenderla (libre u ocupado) tiene
+    // todas las etiquetas, se responde ya en lugar de esperar en la cola.
+    if let Some(required) = &required_tags {
+        let pools: Vec<_> = candidates.iter().zip(&limited_headers).filter(|(_, headers)| headers.is_some()).map(|((_, _, lock), _)| lock.read().unwrap()).collect();
+        let live = || pools.iter().flat_map(|nodes| nodes.values()).filter(|info| !matches!(info.state, NodeHealth::Failed(_) | NodeHealth::Draining(_)));
+        if !live().any(|info| required.matches(&info.tags)) {
+            let unsatisfied = required.unsatisfied(live().map(|info| &info.tags));
+            drop(pools);
+            state.metrics.rejected_unsatisfied_tags.fetch_add(1, Ordering::Relaxed);
+            let message = if unsatisfied.is_empty() {
+                format!("Ningún nodo de {} tiene a la vez las etiquetas {}.", service_name, required.label())
+            } else {
+                format!("Ningún nodo de {} tiene las etiquetas: {}.", service_name, unsatisfied.join(", "))
+            };
+            warn!("  -> Rechazando petición '{}': {}", service_name, message);
+            return openai_error(StatusCode::SERVICE_UNAVAILABLE, "tags_unavailable", None, &message);
+        }
+    }
+
+    // Un cliente con demasiadas peticiones en curso no entra en la cola.
+    let client_permit = match key_max_in_flight.or(state.client_limiter.default_max()) {
+        Some(max) => {
+            let client = client_identity(&req);
+            let label = match bearer_token(&req) {
+                Some(key) => format!("la API key {}", audit::mask_api_key(key)),
+                None => format!("la IP {}", req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default()),
+            };
+            match client_limit::try_acquire(state.clone(), &client, max) {
+                Some(permit) => Some(permit),
+                None => {
+                    state.metrics.rejected_client_in_flight.fetch_add(1, Ordering::Relaxed);
+                    warn!("  -> Rechazando petición '{}': {} ya tiene {} peticiones en curso.", service_name, label, max);
+                    let mut response = openai_error(
+                        StatusCode::TOO_MANY_REQUESTS,
+                        "client_in_flight_limit_exceeded",
+                        None,
+                        &format!("Demasiadas peticiones en curso para este cliente (límite {}). Espera a que terminen las anteriores.", max),
+                    );
+                    if let Ok(retry_after) = header::HeaderValue::from_str(&client_limit::RETRY_AFTER.as_secs().to_string()) {
+                        response.headers_mut().insert(header::RETRY_AFTER, retry_after);
+                    }
+                    return response;
+                }
+            }
+        }
+        None => None,
+    };
+
+    // Los streams tienen su propio tope; las peticiones sin stream no se ven afectadas.
+    let stream_permit = if wants_stream {
+        match streaming::try_acquire(state.clone(), service, bearer_token(&req)) {
+            Ok(permit) => Some(permit),
+            Err(limit) => {
+                warn!("  -> Rechazando stream '{}': alcanzado el tope de streams simultáneos ({}).", service_name, limit.label());
+                return openai_error(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "streaming_limit_exceeded",
+                    Some("stream"),
+                    &format!("Demasiados streams simultáneos (límite {}). Reintenta más tarde o usa stream=false.", limit.label()),
+                );
+            }
+        }
+    } else {
+        None
+    };
+
+    let start_time = Instant::now();
+    let req_body = match settings.max_tokens.and_then(|ceiling| profiles::apply_max_tokens_ceiling(&req_body, ceiling)) {
+        Some(clamped) => {
+            debug!("  -> max_tokens recortado al techo del perfil '{}'.", state.profiles.active_name());
+            clamped
+        }
+        None => req_body,
+    };
+
+    // Un prompt que no cabe en la ventana del modelo, o en la de ningún nodo, se rechaza antes
+    // de ocupar uno.
+    let context_tokens = match context::check(&state, service, &req_body) {
+        Ok(needed) => needed,
+        Err(rejection) => {
+            state.metrics.rejected_over_context.fetch_add(1, Ordering::Relaxed);
+            warn!("  -> Rechazando petición '{}': {}", service_name, rejection);
+            let status = match rejection {
+                ContextRejection::Model(_) => StatusCode::BAD_REQUEST,
+                ContextRejection::Nodes(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            };
+            return openai_error(status, "context_length_exceeded", Some("messages"), &rejection.to_string());
+        }
+    };
+
+    let pipeline_token = PipelineToken::from_request(&req, service, bearer_token(&req));
+    let claimed = pipeline_token.as_ref().filter(|_| target_node.is_none()).and_then(|token| state.pipeline.claim(token, &nodes_lock));
+    if let Some((unique_node_id, _)) = &claimed {
+        debug!("  -> Pipeline: reutilizando el nodo reservado ID {} sin pasar por la cola.", unique_node_id);
+    }
+    let claimed = match claimed {
+        Some(found) => Some(found),
+        // El cargador ocupa un nodo igual que la cola: batch no puede usarlo para saltarse la reserva.
+        None if target_node.is_none() && state.model_loader.is_enabled() && state.workload.admits(class, service, &nodes_lock.read().unwrap()) => match loader::ensure_model(&state, service, model.as_deref()).await {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                return openai_error(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "model_load_failed",
+                    Some("model"),
+                    &format!("No se pudo preparar un nodo con el modelo pedido: {}", e),
+                );
+            }
+        },
+        None => None,
+    };
+
+    // Tampoco uno sin las etiquetas de X-Require-Tags.
+    let claimed = match (claimed, &required_tags) {
+        (Some((unique_node_id, node_service_url)), Some(tags)) if !state.node_has_tags(&nodes_lock, &unique_node_id, tags) => {
+            debug!("  -> El nodo ID {} no tiene las etiquetas {}; se busca otro.", unique_node_id, tags.label());
+            pipeline::release_node(&state, service, &unique_node_id, &node_service_url, None);
+            None
+        }
+        (claimed, _) => claimed,
+    };
+    // Un nodo reservado o preparado que no sabe llamar a herramientas vuelve a la pool.
+    let claimed = match (claimed, capability) {
+        (Some((unique_node_id, node_service_url)), Some(capability)) if !state.node_has_capability(&nodes_lock, &unique_node_id, capability) => {
+            debug!("  -> El nodo ID {} no tiene la capacidad '{}'; se busca otro.", unique_node_id, capability);
+            pipeline::release_node(&state, service, &unique_node_id, &node_service_url, None);
+            None
+        }
+        (claimed, _) => claimed,
+    };
+    // Tampoco sirve uno reservado por el pipeline que no tiene el modelo de esta petición.
+    let claimed = match claimed {
+        Some((unique_node_id, node_service_url)) if !state.node_serves_model(&nodes_lock, &unique_node_id, model.as_deref()) => {
+            debug!("  -> El nodo ID {} no anuncia el modelo {:?}; se busca otro.", unique_node_id, model);
+            pipeline::release_node(&state, service, &unique_node_id, &node_service_url, None);
+            None
+        }
+        claimed => claimed,
+    };
+    // Ni uno que ya recibió su tope de peticiones en el último minuto.
+    let claimed = match claimed {
+        Some((unique_node_id, node_service_url)) if !state.node_under_rpm_cap(&nodes_lock, &unique_node_id) => {
+            debug!("  -> El nodo ID {} está en su tope de peticiones por minuto; se busca otro.", unique_node_id);
+            pipeline::release_node(&state, service, &unique_node_id, &node_service_url, None);
+            None
+        }
+        claimed => claimed,
+    };
+
+    // Los nodos reservados o preparados por el cargador también cuentan para el reparto.
+    if let Some((unique_node_id, _)) = &claimed {
+        let mut nodes = nodes_lock.write().unwrap();
+        if let Some(info) = nodes.get_mut(unique_node_id) {
+            info.stats.dispatched += 1;
+            info.workload = Some(class);
+            info.length = Some(length);
+            info.busy_since = Some(Instant::now());
+            state.rpm_limits.record(unique_node_id, info, Instant::now());
+            if let Some(model) = &model {
+                info.last_model = Some((model.clone(), Instant::now()));
+            }
+        }
+        state.fairness.record(service, &nodes, unique_node_id, state.selector.weighted(), &state.tiers, &state.domains);
+    }
+    // Recién arrancado, el exceso de carga se devuelve al cliente en lugar de cargarlo en el primer nodo.
+    let pool_queued = state.capacity.pool(service).map_or(0, |pool| pool.queued.load(Ordering::Relaxed));
+    if claimed.is_none() && state.warmup.rejects(pool_queued) {
+        state.metrics.rejected_warmup.fetch_add(1, Ordering::Relaxed);
+        warn!("  -> Rechazando petición '{}': calentamiento en curso con {} peticiones en cola.", service_name, pool_queued);
+        let mut response = openai_error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "warming_up",
+            None,
+            "El balanceador acaba de arrancar y los nodos se están registrando. Reintenta en unos segundos.",
+        );
+        if let Ok(retry_after) = header::HeaderValue::from_str(&state.warmup.retry_after.as_secs().to_string()) {
+            response.headers_mut().insert(header::RETRY_AFTER, retry_after);
+        }
+        return response;
+    }
+    let demand = NodeDemand { capability, class, length, prefer: rule_prefer.as_ref(), model: model.as_deref(), only: target_node.as_deref(), avoid_domain: None, context_tokens, tags: required_tags.as_ref(), slow };
+    // Una pool directa con un solo nodo reenvía ya, sin cola ni ocupar el nodo.
+    let direct_node = if claimed.is_none() && candidates.len() == 1 && target_node.is_none() && !slow {
+        state.direct_node(service, &nodes_lock, demand)
+    } else {
+        None
+    };
+    // Una sesión fijada espera a su nodo en la pool donde lo dejó, mientras éste pueda atenderla.
+    let session = Session::from_request(&req, bearer_token(&req)).filter(|_| state.sessions.is_enabled() && target_node.is_none());
+    let mut sticky = session.as_ref().and_then(|session| state.sessions.pinned(session)).and_then(|(pool, unique_node_id)| {
+        let index = candidates.iter().zip(&limited_headers).position(|((_, candidate, _), headers)| candidate.as_str() == pool && headers.is_some())?;
+        Some((index, unique_node_id))
+    });
+    // Sin sesión fijada, la petición de un usuario espera al nodo que le toca en la primera pool.
+    let mut user = state.user_affinity.user_of(&req, user_field.as_deref()).filter(|_| sticky.is_none() && target_node.is_none());
+    let queued = (claimed.is_none() && direct_node.is_none()).then(|| (QueuedRequest::enter(&state, service), state.interleave.wait(service, length)));
+    // La cola del modelo; una sesión fijada no entra mientras espere a su nodo.
+    let shape = WaitShape { class, length, capability: capability.map(str::to_string) };
+    let mut turn = None;
+    // Pools de la cadena en juego: las más baratas se siguen probando tras pasar a una más cara.
+    let mut tier = 0;
+    let mut tier_since = Instant::now();
+    let mut spill_reason = None;
+    let mut direct = direct_node.is_some();
+    let (chosen, (unique_node_id, node_service_url)) = if let Some(found) = claimed {
+        (0, found)
+    } else if let Some(found) = direct_node {
+        state.metrics.direct_requests.fetch_add(1, Ordering::Relaxed);
+        debug!("  -> Pool directa '{}': reenviando al nodo ID {} sin cola.", service, found.0);
+        (0, found)
+    } else { loop {
+        // El nodo del usuario se recalcula en cada vuelta: si sale de la pool, le toca otro.
+        if let Some(user) = &user {
+            sticky = state.user_node(&candidates[0].2, user, demand).map(|unique_node_id| (0, unique_node_id));
+        }
+        if let Some((index, unique_node_id)) = sticky.take() {
+            if state.session_node_usable(&candidates[index].2, &unique_node_id, demand) {
+                sticky = Some((index, unique_node_id));
+            } else {
+                debug!("  -> El nodo ID {} de la sesión ya no puede atenderla; se enruta de nuevo.", unique_node_id);
+            }
+        }
+        let found = match &sticky {
+            Some((index, unique_node_id)) => {
+                let (_, candidate, lock) = &candidates[*index];
+                let demand = NodeDemand { only: Some(unique_node_id.as_str()), ..demand };
+                state.find_and_occupy_node(candidate, lock, demand).map(|found| (*index, found))
+            }
+            // Una lenta no guarda turno: el tráfico normal no espera detrás de ella.
+            None if !slow && !turn.get_or_insert_with(|| state.model_queues.enter(service, model.as_deref(), shape.clone())).is_next() => None,
+            None => candidates
+                .iter()
+                .enumerate()
+                .take(tier + 1)
+                .filter(|(index, _)| limited_headers[*index].is_some())
+                .find_map(|(index, (_, candidate, lock))| state.find_and_occupy_node(candidate, lock, demand).map(|found| (index, found))),
+        };
+        if let Some((index, found)) = found {
+            debug!("  -> Nodo encontrado y ocupado: ID {}, URL {}", found.0, found.1);
+            break (index, found);
+        }
+        if let (Some(_), Some((_, unique_node_id))) = (&user, &sticky) {
+            if state.user_affinity.fallback {
+                debug!("  -> El nodo ID {} del usuario está ocupado; se enruta a otro.", unique_node_id);
+                user = None;
+                sticky = None;
+                continue;
+            }
+        }
+
+        if sticky.is_none() && tier + 1 < candidates.len() {
+            let depth = state.capacity.pool(candidates[tier].1).map_or(0, |pool| pool.queued.load(Ordering::Relaxed));
+            let reason = match spillover {
+                Some(_) => state.spill.should_spill_over(tier_since.elapsed()),
+                None => state.spill.should_spill(depth, tier_since.elapsed()),
+            };
+            if let Some(reason) = reason {
+                debug!("  -> Sin nodo en '{}' ({}); se prueba también '{}'.", candidates[tier].1, reason.label(), candidates[tier + 1].1);
+                tier += 1;
+                tier_since = Instant::now();
+                spill_reason = Some(reason);
+                continue;
+            }
+        }
+
+        if start_time.elapsed() > queue_timeout {
+            error!("  -> ERROR: No se encontraron nodos disponibles para '{}' dentro del tiempo de espera ({}s).", service_name, queue_timeout.as_secs());
+            return HttpResponse::ServiceUnavailable().body(format!(
+                "No hay nodos {} disponibles (timeout {}s)",
+                service_name,
+                queue_timeout.as_secs()
+            ));
+        }
+
+        if cancel::client_gone(&req) {
+            state.metrics.cancelled_requests.fetch_add(1, Ordering::Relaxed);
+            info!("Cancelación: [{}] El cliente se fue mientras esperaba nodo de '{}'.", request_id, service_name);
+            return cancel::client_closed();
+        }
+        trace!("  -> No hay nodos {} disponibles. Esperando {}ms...", service_name, queue_poll_interval.as_millis());
+        match &turn {
+            Some(turn) => turn.wait(queue_poll_interval).await,
+            None => sleep(queue_poll_interval).await,
+        }
+    } };
+
+    drop(turn);
+    drop(queued);
+    let spilled_from = (chosen > 0).then(|| candidates[0].1);
+    if candidates.len() > 1 {
+        state.spill.record(spilled_from.map(|from| (from.as_str(), candidates[chosen].1.as_str())));
+    }
+    if let Some(from) = spilled_from {
+        let reason = spill_reason.map_or("", |reason| reason.label());
+        info!("  -> Petición desbordada de '{}' a '{}' ({}).", from, candidates[chosen].1, reason);
+        state.events.publish(BalancerEvent::PoolSpill {
+            from: from.to_string(),
+            to: candidates[chosen].1.to_string(),
+            reason,
+            waited_ms: start_time.elapsed().as_millis() as u64,
+        });
+    }
+    let (service_name, service, nodes_lock) = candidates.swap_remove(chosen);
+    if let Some(session) = &session {
+        debug!("  -> Sesión '{}' fijada al nodo ID {} de '{}'.", session.id(), unique_node_id, service);
+        state.sessions.pin(session, service, &unique_node_id);
+    }
+    let LimitedHeaders { forwarded: mut outbound_headers, dropped: dropped_headers } = limited_headers.swap_remove(chosen).unwrap_or_default();
+    for (name, reason) in &dropped_headers {
+        state.metrics.dropped_headers[reason.index()].fetch_add(1, Ordering::Relaxed);
+        warn!("  -> La cabecera '{}' no se reenvía a '{}' ({}).", name, service_name, reason.label());
+    }
+    let dropped_headers = dropped_headers.into_iter().map(|(name, _)| name).collect::<Vec<_>>().join(",");
+    // Desbordada con `--pool-spillover`, va a la ruta OpenAI del nodo aunque anunciara la nativa de Ollama.
+    let upstream_of = |node_service_url: &ServiceUrl| match spilled_from.filter(|_| spillover.is_some()) {
+        Some(_) => spill::spillover_url(node_service_url),
+        None => node_service_url.clone(),
+    };
+    let mut upstream_url = upstream_of(&node_service_url);
+    if upstream_url.as_str() != node_service_url.as_str() {
+        debug!("  -> Petición desbordada reenviada a {} en lugar de {}.", upstream_url, node_service_url);
+    }
+    let (mut unique_node_id, mut node_service_url) = (unique_node_id, node_service_url);
+    let mut occupied_at = Instant::now();
+    let mut in_flight = InFlight::start(&nodes_lock, &unique_node_id, &request_id);
+    if request_labels.is_empty() {
+        info!("  -> Intentando reenviar petición [{}] a ID: {}, URL: {}", request_id, unique_node_id, node_service_url);
+    } else {
+        info!(
+            "  -> Intentando reenviar petición [{}] a ID: {}, URL: {} (etiquetas {})",
+            request_id,
+            unique_node_id,
+            node_service_url,
+            labels::label(&request_labels)
+        );
+    }
+    let postprocess = state.postprocessors.plan(service, &req_body);
+    let audit_record = |node_id: &str, (prompt_tokens, completion_tokens, total_tokens), postprocess: Vec<String>| AuditRecord {
+        schema_version: AUDIT_SCHEMA_VERSION,
+        timestamp: chrono::Utc::now(),
+        api_key: bearer_token(&req).map(audit::mask_api_key),
+        service: service.to_string(),
+        model: model.clone(),
+        prompt_tokens,
+        completion_tokens,
+        total_tokens,
+        estimated: false,
+        node_id: node_id.to_string(),
+        postprocess,
+        spilled_from: spilled_from.map(PoolName::to_string),
+        terminated: None,
+        labels: request_labels.clone(),
+    };
+
+    outbound_headers.retain(|(name, _)| !name.eq_ignore_ascii_case(cancel::REQUEST_ID_HEADER));
+    outbound_headers.push((cancel::REQUEST_ID_HEADER.to_string(), request_id.clone().into_bytes()));
+    // En modo directo el nodo no está ocupado: el reintento podría volver a elegirlo.
+    let retry_request = (state.retry_invalid_tool_calls && !direct).then(|| (outbound_headers.clone(), req_body.clone()));
+    // Soltar el futuro cierra la conexión con el nodo: el backend ve el corte y deja de generar.
+    let abandon = |unique_node_id: &NodeId, node_service_url: &ServiceUrl, occupied_at: Instant, direct: bool| {
+        state.metrics.cancelled_requests.fetch_add(1, Ordering::Relaxed);
+        info!(
+            "Cancelación: [{}] El cliente se fue; se abandona la petición al nodo ID {} tras {}ms.",
+            request_id,
+            unique_node_id,
+            occupied_at.elapsed().as_millis()
+        );
+        if !direct {
+            pipeline::release_node(&state, service, unique_node_id, node_service_url, None);
+        }
+        state.events.publish(BalancerEvent::RequestCompleted {
+            service: service.to_string(),
+            node_id: unique_node_id.to_string(),
+            status: cancel::CLIENT_CLOSED_REQUEST,
+            duration_ms: occupied_at.elapsed().as_millis() as u64,
+            streamed: false,
+        });
+        cancel::client_closed()
+    };
+    let forwarded = loop {
+        let drained = state.drain.watch(service, &unique_node_id);
+        tokio::select! {
+            forwarded = forward_request(&state, &upstream_url, outbound_headers.clone(), req_body.clone(), request_timeout) => break forwarded,
+            _ = cancel::disconnected(&req) => return abandon(&unique_node_id, &node_service_url, occupied_at, direct),
+            _ = drain::expired(drained.as_ref()) => {}
+        }
+        // Venció el drenaje del nodo sin respuesta: ya no está en el registro y la petición va a otro.
+        let found = wait_for_node(&state, service, &nodes_lock, demand, Instant::now() + queue_timeout).await;
+        state.events.publish(BalancerEvent::RequestTerminated {
+            service: service.to_string(),
+            node_id: unique_node_id.to_string(),
+            request_id: request_id.clone(),
+            reason: drain::DRAIN_TIMEOUT,
+            action: if found.is_some() { "redispatched" } else { "failed" },
+            streamed: false,
+        });
+        let Some((next_node_id, next_service_url)) = found else {
+            warn!("  -> Venció el drenaje del nodo ID {} y no hay otro nodo en '{}' para la petición [{}].", unique_node_id, service, request_id);
+            return openai_error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "node_drained",
+                None,
+                &format!("El nodo de {} que atendía la petición se apagó y no hay otro disponible.", service_name),
+            );
+        };
+        state.metrics.drain_redispatched_requests.fetch_add(1, Ordering::Relaxed);
+        info!("  -> Venció el drenaje del nodo ID {}; petición [{}] reenviada al nodo ID {}.", unique_node_id, request_id, next_node_id);
+        if let Some(session) = &session {
+            state.sessions.pin(session, service, &next_node_id);
+        }
+        (unique_node_id, node_service_url) = (next_node_id, next_service_url);
+        upstream_url = upstream_of(&node_service_url);
+        occupied_at = Instant::now();
+        in_flight = InFlight::start(&nodes_lock, &unique_node_id, &request_id);
+        direct = false;
+    };
+    let succeeded = forwarded.as_ref().is_ok_and(|response| response.status().is_success());
+    state.record_latency(&nodes_lock, &unique_node_id, succeeded.then(|| occupied_at.elapsed()));
+    state.record_canary(&nodes_lock, &unique_node_id, forwarded.as_ref().is_ok_and(|response| !response.status().is_server_error()));
+    let http_response = match forwarded {
+        Ok(response) => {
+            let status = response.status();
+            info!("  -> Respuesta recibida del nodo ID {} (URL {}) con estado: {}", unique_node_id, node_service_url, status);
+            if let Some(framing) = StreamFraming::of(&response).filter(|_| status.is_success()) {
+                debug!("  -> Respuesta en streaming ({:?}) del nodo ID {}; reenviando con buffer.", framing, unique_node_id);
+                let content_type = response.headers().get(reqwest::header::CONTENT_TYPE).cloned();
+                // El post-procesado sólo sabe reescribir SSE.
+                let postprocess = postprocess.filter(|_| framing == StreamFraming::Sse);
+                let applied = postprocess.as_ref().map(ResponsePlan::applied).unwrap_or_default();
+                // Los tokens se cuentan al terminar el stream (`usage`).
+                let record = state.persistence.is_some().then(|| {
+                    let request: serde_json::Value = serde_json::from_slice(&req_body).unwrap_or_default();
+                    let usage = state.usage_estimator.stream(context::estimate_prompt_tokens(&request));
+                    (audit_record(&unique_node_id, (None, None, None), applied.clone()), usage)
+                });
+                let mut builder = HttpResponse::build(status);
+                builder.insert_header((sessions::NODE_ID_HEADER, unique_node_id.as_str()));
+                builder.insert_header((spill::SERVED_POOL_HEADER, service.as_str()));
+                if !overridden.is_empty() {
+                    builder.insert_header((keys::OVERRIDE_HEADER, overridden.join(",")));
+                }
+                if !dropped_headers.is_empty() {
+                    builder.insert_header((headers::DROPPED_HEADERS_HEADER, dropped_headers.as_str()));
+                }
+                if !applied.is_empty() {
+                    info!("  -> Post-procesado del stream del nodo ID {}: {}", unique_node_id, applied.join(","));
+                    builder.insert_header((postprocess::POSTPROCESS_HEADER, applied.join(",")));
+                }
+                state.capacity.insert_headers(service, &mut builder);
+                if let Some(content_type) = content_type {
+                    builder.insert_header((header::CONTENT_TYPE, content_type.as_bytes()));
+                }
+                for proxy_header in streaming::proxy_headers() {
+                    builder.insert_header(proxy_header);
+                }
+                let lease = NodeLease {
+                    service: service.clone(),
+                    unique_node_id: unique_node_id.clone(),
+                    service_url: node_service_url.clone(),
+                    occupied_at,
+                    pipeline_token,
+                    request_id: request_id.clone(),
+                    in_flight,
+                    drain: state.drain.watch(service, &unique_node_id),
+                    direct,
+                    client_permit,
+                    strictness: settings.strictness.of(service),
+                };
+                let body = streaming::relay(state.clone(), lease, response, framing, record, stream_permit, postprocess);
+                return builder.streaming(body);
+            }
+            let body_bytes = tokio::select! {
+                body_bytes = response.bytes() => body_bytes,
+                _ = cancel::disconnected(&req) => return abandon(&unique_node_id, &node_service_url, occupied_at, direct),
+            };
+            let response_strictness = settings.strictness.of(service);
+            let invalid_response = body_bytes
+                .as_ref()
+                .ok()
+                .filter(|_| status.is_success() && response_strictness != Strictness::Off)
+                .and_then(|body_bytes| strictness::check_response(body_bytes, upstream_url.path().starts_with("/api/")).err());
+            if let Some(violation) = &invalid_response {
+                state.record_invalid_response(service, &unique_node_id, response_strictness, violation);
+            }
+            let body = match body_bytes {
+                // Con `--strictness enforce`, la respuesta sin el mensaje esperado no llega al cliente.
+                Ok(_) if invalid_response.is_some() && response_strictness == Strictness::Enforce => {
+                    state.update_node_state(service, &unique_node_id, NodeHealth::Failed(Instant::now()), TransitionCause::RequestFailure);
+                    debug!("  -> Marcando nodo ID {} como Failed.", unique_node_id);
+                    Ok(Err(openai_error(
+                        StatusCode::BAD_GATEWAY,
+                        "invalid_response",
+                        None,
+                        &format!("El nodo de {} devolvió una respuesta que no sigue el formato esperado: {}.", service_name, invalid_response.unwrap_or_default()),
+                    )))
+                }
+                // Una respuesta que dice llamar a herramientas tiene que traer `tool_calls` bien formados.
+                Ok(body_bytes) => match tools::validate_tool_calls(&body_bytes).err().filter(|_| status.is_success()) {
+                    None => Ok(Ok((unique_node_id.clone(), node_service_url.clone(), body_bytes))),
+                    Some(invalid) => {
+                        state.record_invalid_tool_calls(service, &unique_node_id, &invalid);
+                        // El nodo sigue ocupado durante el reintento para que no se vuelva a elegir.
+                        let retried = match retry_request {
+                            Some((headers, body)) => retry_tool_calls(&state, service, &nodes_lock, demand, &unique_node_id, headers, body).await,
+                            None => None,
+                        };
+                        if !direct {
+                            pipeline::release_node(&state, service, &unique_node_id, &node_service_url, None);
+                        }
+                        Ok(retried.ok_or_else(|| {
+                            openai_error(
+                                StatusCode::BAD_GATEWAY,
+                                "invalid_tool_calls",
+                                None,
+                                &format!("El nodo de {} devolvió tool_calls mal formados: {}.", service_name, invalid),
+                            )
+                        }))
+                    }
+                },
+                Err(e) => Err(e),
+            };
+            match body {
+                Ok(Err(rejected)) => rejected,
+                Ok(Ok((served_by, served_by_url, body_bytes))) => {
+                    let mut applied = Vec::new();
+                    if status.is_success() {
+                        if !direct {
+                            pipeline::release_node(&state, service, &served_by, &served_by_url, pipeline_token.as_ref());
+                        }
+                        if let Some(plan) = &postprocess {
+                            applied = plan.applied();
+                            info!("  -> Post-procesado de la respuesta del nodo ID {}: {}", served_by, applied.join(","));
+                        }
+                    } else {
+                         warn!("  -> Nodo ID {} respondió con estado no exitoso: {}", unique_node_id, status);
+                         state.update_node_state(service, &unique_node_id, NodeHealth::Failed(Instant::now()), TransitionCause::RequestFailure);
+                         debug!("  -> Marcando nodo ID {} como Failed.", unique_node_id);
+                    }
+                    let body_bytes = match postprocess.as_ref().filter(|_| !applied.is_empty()) {
+                        Some(plan) => plan.apply_body(&body_bytes).unwrap_or(body_bytes),
+                        None => body_bytes,
+                    };
+                    if let Some(store) = &state.persistence {
+                        store.record(&audit_record(&served_by, response_usage(&body_bytes), applied.clone()));
+                    }
+                    let mut builder = HttpResponse::build(status);
+                    builder.insert_header((sessions::NODE_ID_HEADER, served_by.as_str()));
+                    builder.insert_header((spill::SERVED_POOL_HEADER, service.as_str()));
+                    if !overridden.is_empty() {
+                        builder.insert_header((keys::OVERRIDE_HEADER, overridden.join(",")));
+                    }
+                    if !dropped_headers.is_empty() {
+                        builder.insert_header((headers::DROPPED_HEADERS_HEADER, dropped_headers.as_str()));
+                    }
+                    if !applied.is_empty() {
+                        builder.insert_header((postprocess::POSTPROCESS_HEADER, applied.join(",")));
+                    }
+                    if status.is_success() {
+                        state.capacity.insert_headers(service, &mut builder);
+                    }
+                    builder.body(body_bytes)
+                }
+                Err(e) => {
+                     let category = state.record_upstream_error(service, &unique_node_id, &e);
+                     error!("  -> Error al leer la respuesta del nodo ID {} [{}]: {}", unique_node_id, category.label(), e);
+                     state.update_node_state(service, &unique_node_id, NodeHealth::Failed(Instant::now()), TransitionCause::RequestFailure);
+                     debug!("  -> Marcando nodo ID {} como Failed.", unique_node_id);
+                     HttpResponse::InternalServerError().body(format!("Error leyendo respuesta de {}", service_name))
+                }
+            }
+        }
+        Err(e) => {
+             let category = state.record_upstream_error(service, &unique_node_id, &e);
+             error!("  -> Error al reenviar la solicitud al nodo ID {} [{}]: {}", unique_node_id, category.label(), e);
+             state.update_node_state(service, &unique_node_id, NodeHealth::Failed(Instant::now()), TransitionCause::RequestFailure);
+             debug!("  -> Marcando nodo ID {} como Failed.", unique_node_id);
+            HttpResponse::InternalServerError()
+                .body(format!("Error reenviando a {}: {}", service_name, e))
+        }
+    };
+    state.events.publish(BalancerEvent::RequestCompleted {
+        service: service.to_string(),
+        node_id: unique_node_id.into(),
+        status: http_response.status().as_u16(),
+        duration_ms: occupied_at.elapsed().as_millis() as u64,
+        streamed: false,
+    });
+    http_response
+}
+
+/// Atiende una petición de inferencia y la anota en las últimas peticiones.
+async fn serve_pool(name: &str, state: web::Data<AppState>, req: HttpRequest, req_body: web::Bytes) -> HttpResponse {
+    let request_id = cancel::request_id(&req);
+    let recent = RecentRequest::start(&req, &request_id, name, &req_body);
+    let recent_requests = state.clone();
+    let response = dispatch_pool(name, state, req, req_body, request_id).await;
+    recent_requests.recent_requests.record(recent.finish(&response));
+    response
+}
+
+/// Atiende una petición de inferencia en la pool `name`, resolviendo antes su alias.
+async fn dispatch_pool(name: &str, state: web::Data<AppState>, req: HttpRequest, req_body: web::Bytes, request_id: String) -> HttpResponse {
+    let Some((service_name, service, nodes_lock)) = state.resolve_pool(name) else {
+        return openai_error(StatusCode::NOT_FOUND, "invalid_request_error", None, &format!("Pool desconocida '{}'.", name));
+    };
+    if *service != *name {
+        debug!("  -> La pool '{}' es un alias de '{}'.", name, service);
+    }
+    let idempotency_key = req.headers().get(idempotency::IDEMPOTENCY_HEADER).and_then(|v| v.to_str().ok()).map(str::to_string);
+    let Some(idempotency_key) = idempotency_key.filter(|_| state.idempotency.is_enabled()) else {
+        return handle_service_request(service_name, service, nodes_lock, state, req, req_body, request_id).await;
+    };
+    if serde_json::from_slice::<serde_json::Value>(&req_body).ok().and_then(|body| body.get("stream")?.as_bool()) == Some(true) {
+        return openai_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            Some("stream"),
+            "Idempotency-Key no se admite con stream=true: una respuesta en streaming no puede repetirse. Quita la cabecera o usa stream=false.",
+        );
+    }
+
+    // Cada cliente tiene su propio espacio de claves en cada pool.
+    let client = client_identity(&req);
+    let cache = state.clone();
+    loop {
+        match cache.idempotency.claim(&client, service, &idempotency_key, &req_body) {
+            Claim::Owner(in_flight) => {
+                let response = handle_service_request(service_name, service, nodes_lock, state, req, req_body, request_id).await;
+                return in_flight.finish(response).await;
+            }
+            Claim::Replay(stored) => {
+                debug!("  -> Idempotency-Key '{}' ya atendida; se repite la respuesta guardada.", idempotency_key);
+                state.metrics.idempotent_replays.fetch_add(1, Ordering::Relaxed);
+                return stored.to_response();
+            }
+            Claim::TooLarge => {
+                return openai_error(
+                    StatusCode::CONFLICT,
+                    "idempotency_replay_unavailable",
+                    None,
+                    &format!(
+                        "La petición con Idempotency-Key '{}' ya se atendió, pero su respuesta superaba el tope de {} bytes y no se guardó para repetirla.",
+                        idempotency_key, state.idempotency.max_body_bytes
+                    ),
+                );
+            }
+            Claim::Wait(mut outcome) => {
+                debug!("  -> Idempotency-Key '{}' en curso; esperando a la petición original.", idempotency_key);
+                // Al terminar la original se vuelve a reclamar: o hay respuesta guardada o se reintenta.
+                let _ = outcome.wait_for(|outcome| !matches!(outcome, idempotency::Outcome::Pending)).await;
+            }
+            Claim::Mismatch => {
+                warn!("  -> Rechazando petición '{}': Idempotency-Key '{}' reutilizada con otro cuerpo.", service_name, idempotency_key);
+                return openai_error(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "idempotency_key_reused",
+                    None,
+                    &format!("La Idempotency-Key '{}' ya se usó con otro cuerpo de petición. Usa una clave nueva para una petición distinta.", idempotency_key),
+                );
+            }
+            Claim::Full => {
+                warn!("  -> Caché de idempotencia llena de peticiones en curso; '{}' se atiende sin protección.", idempotency_key);
+                return handle_service_request(service_name, service, nodes_lock, state, req, req_body, request_id).await;
+            }
+        }
+    }
+}
+
+#[post("/lmstudio")]
+async fn lm_studio_handler(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    req_body: web::Bytes,
+) -> impl Responder {
+     info!("Balancer /lmstudio handler RECIBIDO request. Body size: {}", req_body.len());
+     serve_pool("lmstudio", state, req, req_body).await
+}
+
+#[post("/ollama")]
+async fn ollama_handler(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    req_body: web::Bytes,
+) -> impl Responder {
+     info!("Balancer /ollama handler RECIBIDO request. Body size: {}", req_body.len());
+     serve_pool("ollama", state, req, req_body).await
+}
+
+/// Ruta genérica por nombre de pool o de alias.
+#[post("/pool/{name}")]
+async fn pool_handler(
+    state: web::Data<AppState>,
+    path: web::Path<PoolName>,
+    req: HttpRequest,
+    req_body: web::Bytes,
+) -> impl Responder {
+    let name = path.into_inner();
+    info!("Balancer /pool/{} handler RECIBIDO request. Body size: {}", name, req_body.len());
+    serve_pool(&name, state, req, req_body).await
+}
+
+#[get("/version")]
+async fn version_handler() -> impl Responder {
+    HttpResponse::Ok().json(build_info::to_json())
+}
+
+/// Resumen de un nodo tal como aparece en `/nodes` y `/nodes/watch`.
+pub(crate) fn node_summary(state: &AppState, service: &str, unique_node_id: &str, info: &NodeInfo) -> NodeSummary {
+    NodeSummary {
+        node_id: unique_node_id.to_string(),
+        service: service.to_string(),
+        service_url: info.service_url.to_string(),
+        state: info.state.label().to_string(),
+        source: info.source.label().to_string(),
+        version: info.version.clone(),
+        low_disk: state.is_low_on_disk(info),
+        dispatch_rate: state.dispatch_limits.describe(service, unique_node_id, info.dispatch_bucket.as_ref()),
+        rpm: state.rpm_limits.status(unique_node_id, info),
+        canary: info.canary.as_ref().map(|canary| state.canary.status(canary)),
+        capabilities: state.capability_overrides.effective(unique_node_id, &info.capabilities).into_iter().map(str::to_string).collect(),
+        backend_version: info.backend_version.clone(),
+        in_flight: info.in_flight(),
+        capabilities_stale: info.capability_probe.stale,
+        weight: info.weight,
+        platform: info.platform.clone(),
+        domain: state.domains.domain_of(unique_node_id, info).map(str::to_string),
+        pinned_ip: state.pins.pinned_ip(unique_node_id).map(|ip| ip.to_string()),
+        tags: info.tags.clone(),
+        last_model: info.last_model.as_ref().map(|(model, _)| model.clone()),
+        local: state.locality.is_local(info),
+        latency_ms: info.latency.ms().map(|ms| ms.round() as u64),
+        drain_secs: match info.state {
+            NodeHealth::Draining(deadline) => Some(deadline.saturating_duration_since(Instant::now()).as_secs()),
+            _ => None,
+        },
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct NodesQuery {
+    include: Option<String>,
+}
+
+/// Registro de `/nodes` y del paquete de soporte; con `include_removed`, también las lápidas.
+pub(crate) fn node_list(state: &AppState, include_removed: bool) -> NodeList {
+    let revision = state.revisions.current();
+    let mut nodes = Vec::new();
+    for (_, service, lock) in state.pools() {
+        let mut pool: Vec<_> = lock
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(id, info)| node_summary(state, service, id, info))
+            .collect();
+        pool.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+        nodes.extend(pool);
+    }
+    let removed = include_removed.then(|| state.tombstones.list().iter().map(|tombstone| serde_json::json!(tombstone)).collect());
+    NodeList {
+        balancer_version: build_info::summary(),
+        revision,
+        nodes,
+        discovery_listeners: state.discovery_listeners.iter().map(|listener| listener.summary()).collect(),
+        removed,
+    }
+}
+
+/// Resumen de todos los nodos registrados, con la versión del binario que anuncian. `revision`
+/// sirve de punto de partida para `/nodes/watch`. Con `?include=removed`, también las lápidas de
+/// los nodos que salieron.
+#[get("/nodes")]
+async fn nodes_handler(state: web::Data<AppState>, query: web::Query<NodesQuery>) -> impl Responder {
+    let include_removed = query.include.as_deref().is_some_and(|include| include.split(',').any(|part| part.trim() == "removed"));
+    HttpResponse::Ok().json(node_list(&state, include_removed))
+}
+
+/// Detalle de un nodo en todas las pools donde está registrado, con el resumen de errores de reenvío.
+#[get("/nodes/{id}")]
+async fn node_detail_handler(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let unique_node_id = path.into_inner();
+    let services: Vec<serde_json::Value> = state
+        .pools()
+        .into_iter()
+        .filter_map(|(_, service, lock)| {
+            let nodes = lock.read().unwrap();
+            nodes.get(unique_node_id.as_str()).map(|info| serde_json::json!({
+                "service": service,
+                "service_url": info.service_url,
+                "state": info.state.label(),
+                "source": info.source.label(),
+                "last_seen_secs": info.last_seen.elapsed().as_secs(),
+                "models": info.models,
+                "context_windows": info.context_windows,
+                "max_context": info.max_context,
+                "storage": info.storage,
+                "low_disk": state.is_low_on_disk(info),
+                "last_error": info.last_error,
+                "version": info.version,
+                "backend_version": info.backend_version,
+                "capability_probe": info.capability_probe,
+                "spooled_requests": info.stats.spooled,
+            }))
+        })
+        .collect();
+    let upstream_errors = state.upstream_errors.read().unwrap();
+    let recent = upstream_errors.recent(&unique_node_id);
+    if services.is_empty() && recent.is_empty() {
+        return HttpResponse::NotFound().json(serde_json::json!({
+            "error": format!("Nodo {} desconocido", unique_node_id),
+        }));
+    }
+    HttpResponse::Ok().json(serde_json::json!({
+        "node_id": unique_node_id,
+        "stats": stats::epoch_json(&state),
+        "services": services,
+        "errors": {
+            "total": state.metrics.upstream_error_totals(&unique_node_id),
+            "last_hour": upstream_errors.counts_within(&unique_node_id, Duration::from_secs(3600)),
+            "last_24h": upstream_errors.counts_within(&unique_node_id, Duration::from_secs(24 * 3600)),
+            "recent": recent,
+        },
+    }))
+}
+
+#[get("/nodes/{id}/history")]
+async fn node_history_handler(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let unique_node_id = path.into_inner();
+    let history = state.node_history.read().unwrap();
+    match history.get(&unique_node_id) {
+        Some(transitions) => HttpResponse::Ok().json(serde_json::json!({
+            "node_id": unique_node_id,
+            "transitions": transitions,
+        })),
+        None => HttpResponse::NotFound().json(serde_json::json!({
+            "error": format!("No hay historial para el nodo {}", unique_node_id),
+        })),
+    }
+}
+
+/// Sustituye `localhost`/`127.0.0.1` en la URL anunciada por la IP desde la que llegó el anuncio.
+pub(crate) fn effective_service_url(announced: ServiceUrl, source_ip: IpAddr, unique_node_id: &str) -> ServiceUrl {
+    if !matches!(announced.host(), Some(url::Host::Domain("localhost")) | Some(url::Host::Ipv4(std::net::Ipv4Addr::LOCALHOST))) {
+        return announced;
+    }
+    match announced.with_host(&source_ip.to_string()) {
+        Ok(effective) => {
+            debug!("Discovery: Reemplazado host 'localhost'/'127.0.0.1' con '{}' para nodo {}", source_ip, unique_node_id);
+            effective
+        }
+        Err(e) => {
+            warn!("Discovery: {} (nodo {}). Usando URL original.", e, unique_node_id);
+            announced
+        }
+    }
+}
+
+impl AppState {
+    /// Todas las pools como `(nombre visible, clave de servicio, mapa de nodos)`.
+    pub(crate) fn pools(&self) -> [(&'static str, &'static PoolName, &NodeMap); 2] {
+        [
+            ("LM Studio", &LMSTUDIO, &self.lm_studio_nodes),
+            ("Ollama", &OLLAMA, &self.ollama_nodes),
+        ]
+    }
+
+    /// Nombre real de la pool: el destino si `name` es un alias, o `name` tal cual.
+    pub(crate) fn canonical_service<'a>(&self, name: &'a str) -> &'a str {
+        self.pool_aliases.resolve(name).unwrap_or(name)
+    }
+
+    /// Pool que atiende `name` (nombre o alias): nombre para mostrar, nombre real y nodos.
+    fn resolve_pool(&self, name: &str) -> Option<(&'static str, &'static PoolName, NodeMap)> {
+        let service = self.canonical_service(name);
+        self.pools()
+            .into_iter()
+            .find(|(_, pool, _)| **pool == *service)
+            .map(|(service_name, pool, lock)| (service_name, pool, lock.clone()))
+    }
+
+    /// Nombre real de la pool que atiende `name` (nombre o alias), si es una de las integradas.
+    pub(crate) fn pool_name(&self, name: &str) -> Option<&'static PoolName> {
+        self.resolve_pool(name).map(|(_, pool, _)| pool)
+    }
+
+    pub(crate) fn pool(&self, service_type: &str) -> Option<&NodeMap> {
+        match service_type {
+            "lmstudio" => Some(&self.lm_studio_nodes),
+            "ollama" => Some(&self.ollama_nodes),
+            _ => None,
+        }
+    }
+
+    /// ID del nodo de la pool que ya está registrado con esa URL, prefiriendo los de formato nuevo.
+    fn node_id_by_url(&self, service_type: &PoolName, service_url: &ServiceUrl) -> Option<NodeId> {
+        let nodes = self.pool(service_type)?.read().unwrap();
+        let mut matching: Vec<&NodeId> = nodes.iter().filter(|(_, info)| info.service_url == *service_url).map(|(id, _)| id).collect();
+        matching.sort_by_key(|id| id.as_str().starts_with(discovery::LEGACY_ID_PREFIX));
+        matching.first().map(|id| (*id).clone())
+    }
+
+    /// Retira el nodo de formato antiguo que apunta al mismo backend que un nodo recién anunciado
+    /// con el formato nuevo. Si está ocupado se deja para la limpieza por inactividad.
+    fn retire_legacy_duplicate(&self, service_type: &PoolName, unique_node_id: &NodeId, service_url: &ServiceUrl) {
+        let Some(lock) = self.pool(service_type) else {
+            return;
+        };
+        let mut nodes = lock.write().unwrap();
+        let duplicate = nodes
+            .iter()
+            .find(|(id, info)| {
+                *id != unique_node_id
+                    && id.as_str().starts_with(discovery::LEGACY_ID_PREFIX)
+                    && info.service_url == *service_url
+                    && !matches!(info.state, NodeHealth::Busy)
+            })
+            .map(|(id, _)| id.clone());
+        let Some(duplicate) = duplicate else {
+            return;
+        };
+        if let Some(node_info) = nodes.remove(&duplicate) {
+            self.revisions.bump(service_type, &duplicate);
+            drop(nodes);
+            info!("Discovery: Nodo ID {} sustituido por ID {}, que anuncia el mismo backend con el formato nuevo.", duplicate, unique_node_id);
+            self.record_removal(service_type, &duplicate, &node_info, RemovalReason::Replaced);
+        }
+    }
+
+    /// Hace sitio para un nodo nuevo en una pool llena sacando el nodo caído visto hace más
+    /// tiempo. Devuelve el nodo sacado para anotar su salida ya sin el lock.
+    fn make_room(
+        &self,
+        service_type: &PoolName,
+        nodes: &mut HashMap<NodeId, NodeInfo>,
+        unique_node_id: &NodeId,
+    ) -> Result<Option<(NodeId, NodeInfo)>, RegisterError> {
+        let cap = self.bounded.limits.nodes_per_pool;
+        if nodes.len() < cap || nodes.contains_key(unique_node_id) {
+            return Ok(None);
+        }
+        let oldest_failed = nodes
+            .iter()
+            .filter(|(_, info)| matches!(info.state, NodeHealth::Failed(_)))
+            .min_by_key(|(_, info)| info.last_seen)
+            .map(|(id, _)| id.clone())
+            .ok_or(RegisterError::PoolFull(cap))?;
+        let evicted = nodes.remove_entry(&oldest_failed);
+        self.revisions.bump(service_type, &oldest_failed);
+        Ok(evicted)
+    }
+
+    fn record_eviction(&self, service_type: &PoolName, unique_node_id: &NodeId, evicted: Option<(NodeId, NodeInfo)>) {
+        if let Some((evicted_id, evicted_info)) = evicted {
+            warn!(
+                "Límites: La pool {} está en su tope ({} nodos); el nodo caído {} deja sitio a {}.",
+                service_type, self.bounded.limits.nodes_per_pool, evicted_id, unique_node_id
+            );
+            self.record_removal(service_type, &evicted_id, &evicted_info, RemovalReason::Evicted);
+        }
+    }
+
+    /// Registra o refresca un nodo. Es el punto de entrada común para todos los mecanismos de descubrimiento.
+    /// Falla si el tipo de servicio no es conocido o si la pool está llena sin ningún nodo caído que sacar.
+    pub(crate) fn register_node(&self, service_type: &PoolName, unique_node_id: &NodeId, service_url: ServiceUrl) -> Result<(), RegisterError> {
+        let Some(lock) = self.pool(service_type) else {
+            return Err(RegisterError::UnknownService);
+        };
+        let mut nodes = lock.write().unwrap();
+        let evicted = self.make_room(service_type, &mut nodes, unique_node_id)?;
+        debug!("Discovery: Añadiendo/Actualizando nodo ID {} para servicio {}.", unique_node_id, service_type);
+        let previous = nodes.get(unique_node_id).cloned();
+        // Un anuncio no debe liberar un nodo que está atendiendo una petición. Sí cancela un drenaje.
+        let state = match previous.as_ref().map(|info| (&info.state, info.in_flight())) {
+            Some((NodeHealth::Busy, _)) => NodeHealth::Busy,
+            Some((NodeHealth::Loading, _)) => NodeHealth::Loading,
+            Some((NodeHealth::Draining(_), in_flight)) if in_flight > 0 => NodeHealth::Busy,
+            _ => NodeHealth::Available,
+        };
+        let drain_cancelled = match previous.as_ref().map(|info| &info.state) {
+            Some(NodeHealth::Draining(deadline)) => Some(deadline.saturating_duration_since(Instant::now())),
+            _ => None,
+        };
+        let from = previous.as_ref().map_or(NodeHealth::ABSENT_LABEL, |info| info.state.label());
+        let to = state.label();
+        let info = match previous {
+            Some(previous) => {
+                let busy_since = matches!(state, NodeHealth::Busy | NodeHealth::Loading).then(|| previous.busy_since.unwrap_or_else(Instant::now));
+                NodeInfo { state, service_url, last_seen: Instant::now(), source: NodeSource::Announced, busy_since, ..previous }
+            }
+            None => {
+                let mut info = self.revived_node(service_type, unique_node_id, service_url, NodeSource::Announced);
+                info.canary = self.canary.start();
+                info
+            }
+        };
+        nodes.insert(unique_node_id.clone(), info);
+        self.revisions.bump(service_type, unique_node_id);
+        drop(nodes);
+        self.record_eviction(service_type, unique_node_id, evicted);
+        if from != to {
+            self.record_transition(unique_node_id, service_type, from, to, TransitionCause::Heartbeat);
+        }
+        if let Some(remaining) = drain_cancelled {
+            info!("Discovery: Nodo ID {} ({}) vuelve a anunciarse {}s antes de su apagado; se cancela el drenaje.", unique_node_id, service_type, remaining.as_secs());
+            self.events.publish(BalancerEvent::NodeDrainCancelled {
+                service: service_type.to_string(),
+                node_id: unique_node_id.to_string(),
+                remaining_secs: remaining.as_secs(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Clasifica un error al hablar con el nodo y lo anota en el nodo, el registro de errores y las métricas.
+    pub(crate) fn record_upstream_error(&self, service_type: &PoolName, unique_node_id: &NodeId, error: &reqwest::Error) -> ErrorCategory {
+        let category = errors::classify(error);
+        self.record_node_error(service_type, unique_node_id, category, error.to_string());
+        category
+    }
+
+    fn record_node_error(&self, service_type: &PoolName, unique_node_id: &NodeId, category: ErrorCategory, message: String) {
+        if let Some(lock) = self.pool(service_type) {
+            let mut nodes = lock.write().unwrap();
+            if let Some(info) = nodes.get_mut(unique_node_id) {
+                info.last_error = Some(format!("{}: {}", category.label(), message));
+                self.revisions.bump(service_type, unique_node_id);
+            }
+        }
+        self.upstream_errors.write().unwrap().record(unique_node_id, service_type, category, message);
+        self.metrics.record_upstream_error(unique_node_id, category.label());
+    }
+
+    /// Registra un nodo estático que no depende de anuncios. Se mantiene hasta que el proceso termina.
+    pub(crate) fn register_static_node(
+        &self,
+        service_type: &PoolName,
+        unique_node_id: &NodeId,
+        service_url: ServiceUrl,
+        source: NodeSource,
+    ) -> Result<(), RegisterError> {
+        let Some(lock) = self.pool(service_type) else {
+            return Err(RegisterError::UnknownService);
+        };
+        let mut nodes = lock.write().unwrap();
+        let evicted = self.make_room(service_type, &mut nodes, unique_node_id)?;
+        info!("Registrando nodo estático {} ({}) para {}: {}", unique_node_id, source.label(), service_type, service_url);
+        let info = self.revived_node(service_type, unique_node_id, service_url, source);
+        nodes.insert(unique_node_id.clone(), info);
+        self.revisions.bump(service_type, unique_node_id);
+        drop(nodes);
+        self.record_eviction(service_type, unique_node_id, evicted);
+        self.record_transition(unique_node_id, service_type, NodeHealth::ABSENT_LABEL, NodeHealth::Available.label(), TransitionCause::Admin);
+        Ok(())
+    }
+
+    /// Aplica el resultado de un health check. Los nodos Busy no se tocan: la petición en curso decide su estado.
+    pub(crate) fn apply_health_check(&self, service_type: &PoolName, unique_node_id: &NodeId, healthy: bool) {
+        let Some(lock) = self.pool(service_type) else {
+            return;
+        };
+        let new_health = {
+            let mut nodes = lock.write().unwrap();
+            let Some(node_info) = nodes.get_mut(unique_node_id) else {
+                return;
+            };
+            if healthy {
+                node_info.last_seen = Instant::now();
+                self.revisions.bump(service_type, unique_node_id);
+            }
+            match (&node_info.state, healthy) {
+                (NodeHealth::Failed(_), true) => NodeHealth::Available,
+                (NodeHealth::Available, false) => NodeHealth::Failed(Instant::now()),
+                _ => return,
+            }
+        };
+        self.update_node_state(service_type, unique_node_id, new_health, TransitionCause::HealthCheck);
+    }
+
+    /// Sustituye la lista de modelos anunciada por un nodo ya registrado.
+    pub(crate) fn set_node_models(&self, service_type: &PoolName, unique_node_id: &NodeId, models: Vec<String>) {
+        let Some(lock) = self.pool(service_type) else {
+            return;
+        };
+        let mut nodes = lock.write().unwrap();
+        if let Some(node_info) = nodes.get_mut(unique_node_id) {
+            debug!("Discovery: Nodo ID {} ({}) anuncia {} modelos.", unique_node_id, service_type, models.len());
+            node_info.models = models;
+            self.revisions.bump(service_type, unique_node_id);
+        }
+    }
+
+    /// Añade o actualiza las ventanas de contexto que anuncia un nodo ya registrado.
+    pub(crate) fn set_node_context_windows(&self, service_type: &PoolName, unique_node_id: &NodeId, windows: Vec<(String, u64)>) {
+        let Some(lock) = self.pool(service_type) else {
+            return;
+        };
+        let mut nodes = lock.write().unwrap();
+        if let Some(node_info) = nodes.get_mut(unique_node_id) {
+            trace!("Discovery: Nodo ID {} ({}) anuncia la ventana de contexto de {} modelos.", unique_node_id, service_type, windows.len());
+            node_info.context_windows.extend(windows);
+            self.revisions.bump(service_type, unique_node_id);
+        }
+    }
+
+    /// Guarda la ventana de contexto de todo el nodo (`MAX_CONTEXT`).
+    pub(crate) fn set_node_max_context(&self, service_type: &PoolName, unique_node_id: &NodeId, tokens: u64) {
+        let Some(lock) = self.pool(service_type) else {
+            return;
+        };
+        if let Some(node_info) = lock.write().unwrap().get_mut(unique_node_id).filter(|info| info.max_context != Some(tokens)) {
+            debug!("Discovery: El nodo ID {} ({}) anuncia una ventana de contexto de {} tokens.", unique_node_id, service_type, tokens);
+            node_info.max_context = Some(tokens);
+            self.revisions.bump(service_type, unique_node_id);
+        }
+    }
+
+    /// Guarda el informe de espacio de un nodo y avisa cuando cruza el mínimo de espacio libre.
+    pub(crate) fn set_node_storage(&self, service_type: &PoolName, unique_node_id: &NodeId, report: StorageReport) {
+        let Some(lock) = self.pool(service_type) else {
+            return;
+        };
+        let (was_low, is_low, free_bytes) = {
+            let mut nodes = lock.write().unwrap();
+            let Some(node_info) = nodes.get_mut(unique_node_id) else {
+                return;
+            };
+            let was_low = node_info.storage.as_ref().is_some_and(|r| r.is_low(self.min_free_disk_bytes));
+            let is_low = report.is_low(self.min_free_disk_bytes);
+            let free_bytes = report.free_bytes;
+            node_info.storage = Some(report);
+            self.revisions.bump(service_type, unique_node_id);
+            (was_low, is_low, free_bytes)
+        };
+        if is_low && !was_low {
+            warn!(
+                "Discovery: Nodo ID {} ({}) con poco disco para modelos: {} libres (mínimo {}).",
+                unique_node_id, service_type, storage::human_bytes(free_bytes), storage::human_bytes(self.min_free_disk_bytes)
+            );
+            self.events.publish(BalancerEvent::DiskSpaceLow {
+                service: service_type.to_string(),
+                node_id: unique_node_id.to_string(),
+                free_bytes,
+                min_free_bytes: self.min_free_disk_bytes,
+            });
+        } else if was_low && !is_low {
+            info!("Discovery: Nodo ID {} ({}) vuelve a tener {} libres para modelos.", unique_node_id, service_type, storage::human_bytes(free_bytes));
+            self.events.publish(BalancerEvent::DiskSpaceRecovered {
+                service: service_type.to_string(),
+                node_id: unique_node_id.to_string(),
+                free_bytes,
+            });
+        }
+    }
+
+    /// Indica si el nodo anunció menos espacio libre del mínimo configurado.
+    pub(crate) fn is_low_on_disk(&self, info: &NodeInfo) -> bool {
+        info.storage.as_ref().is_some_and(|report| report.is_low(self.min_free_disk_bytes))
+    }
+
+    /// Guarda la versión del binario que anuncia el nodo. Avisa una sola vez por nodo y versión
+    /// si no coincide con la del balanceador.
+    pub(crate) fn set_node_version(&self, service_type: &PoolName, unique_node_id: &NodeId, version: &str) {
+        let Some(lock) = self.pool(service_type) else {
+            return;
+        };
+        if let Some(node_info) = lock.write().unwrap().get_mut(unique_node_id) {
+            node_info.version = Some(version.to_string());
+            self.revisions.bump(service_type, unique_node_id);
+        }
+        let own_version = build_info::summary();
+        if version == own_version {
+            return;
+        }
+        let mut warned = self.version_warnings.lock().unwrap();
+        // Al llenarse se olvida todo: como mucho se repite algún aviso.
+        if warned.len() >= self.bounded.limits.version_warnings {
+            warned.clear();
+        }
+        if warned.insert((unique_node_id.to_string(), version.to_string())) {
+            warn!("Discovery: El nodo ID {} ejecuta lmServer {} y el balanceador {}.", unique_node_id, version, own_version);
+        }
+    }
+
+    /// Mezcla en la media de latencia del nodo un reenvío que tardó `elapsed`, o un fallo si es `None`.
+    fn record_latency(&self, nodes_lock: &NodeMap, unique_node_id: &NodeId, elapsed: Option<Duration>) {
+        if let Some(info) = nodes_lock.write().unwrap().get_mut(unique_node_id) {
+            match elapsed {
+                Some(elapsed) => self.latency.observe(&mut info.latency, elapsed),
+                None => self.latency.penalize(&mut info.latency),
+            }
+        }
+    }
+
+    /// Guarda la plataforma que anuncia el nodo (`PLATFORM`).
+    pub(crate) fn set_node_platform(&self, service_type: &PoolName, unique_node_id: &NodeId, platform: Platform) {
+        let Some(lock) = self.pool(service_type) else {
+            return;
+        };
+        if let Some(node_info) = lock.write().unwrap().get_mut(unique_node_id).filter(|info| info.platform != platform) {
+            debug!("Discovery: El nodo ID {} ({}) anuncia la plataforma {:?}.", unique_node_id, service_type, platform);
+            node_info.platform = platform;
+            self.revisions.bump(service_type, unique_node_id);
+        }
+    }
+
+    /// Suma las peticiones que el proxy del nodo atendió por su cuenta durante una caída del
+    /// balanceador (`SPOOLED`). El nodo repite el total de la caída: sólo cuenta lo nuevo.
+    pub(crate) fn record_spooled(&self, service_type: &PoolName, unique_node_id: &NodeId, outage: u64, served: u64) {
+        let Some(lock) = self.pool(service_type) else {
+            return;
+        };
+        let mut nodes = lock.write().unwrap();
+        let Some(node_info) = nodes.get_mut(unique_node_id) else {
+            return;
+        };
+        let previous = node_info.spooled.filter(|(previous_outage, _)| *previous_outage == outage).map_or(0, |(_, previous)| previous);
+        let added = served.saturating_sub(previous);
+        node_info.spooled = Some((outage, served.max(previous)));
+        if added > 0 {
+            info!("Discovery: El nodo ID {} ({}) atendió {} peticiones por su cuenta mientras el balanceador no respondía.", unique_node_id, service_type, added);
+            node_info.stats.spooled += added;
+            self.metrics.spooled_requests.fetch_add(added, Ordering::Relaxed);
+        }
+    }
+
+    /// Guarda el dominio de fallo que anuncia el nodo (`DOMAIN`).
+    pub(crate) fn set_node_domain(&self, service_type: &PoolName, unique_node_id: &NodeId, domain: &str) {
+        let Some(lock) = self.pool(service_type) else {
+            return;
+        };
+        if let Some(node_info) = lock.write().unwrap().get_mut(unique_node_id).filter(|info| info.domain.as_deref() != Some(domain)) {
+            debug!("Discovery: El nodo ID {} ({}) está en el dominio de fallo '{}'.", unique_node_id, service_type, domain);
+            node_info.domain = Some(domain.to_string());
+            self.revisions.bump(service_type, unique_node_id);
+        }
+    }
+
+    /// Guarda la IP de origen del último anuncio del nodo.
+    pub(crate) fn set_node_source_ip(&self, service_type: &PoolName, unique_node_id: &NodeId, source_ip: IpAddr) {
+        let Some(lock) = self.pool(service_type) else {
+            return;
+        };
+        if let Some(node_info) = lock.write().unwrap().get_mut(unique_node_id).filter(|info| info.source_ip != Some(source_ip)) {
+            debug!("Discovery: El nodo ID {} ({}) se anuncia desde {}.", unique_node_id, service_type, source_ip);
+            node_info.source_ip = Some(source_ip);
+            self.revisions.bump(service_type, unique_node_id);
+        }
+    }
+
+    /// Guarda las etiquetas que anuncia el nodo (`TAGS`).
+    pub(crate) fn set_node_tags(&self, service_type: &PoolName, unique_node_id: &NodeId, tags: NodeTags) {
+        let Some(lock) = self.pool(service_type) else {
+            return;
+        };
+        if let Some(node_info) = lock.write().unwrap().get_mut(unique_node_id).filter(|info| info.tags != tags) {
+            debug!("Discovery: El nodo ID {} ({}) anuncia {} etiquetas.", unique_node_id, service_type, tags.len());
+            node_info.tags = tags;
+            self.revisions.bump(service_type, unique_node_id);
+        }
+    }
+
+    /// Guarda el peso que anuncia el nodo (`WEIGHT`).
+    pub(crate) fn set_node_weight(&self, service_type: &PoolName, unique_node_id: &NodeId, weight: u32) {
+        let Some(lock) = self.pool(service_type) else {
+            return;
+        };
+        if let Some(node_info) = lock.write().unwrap().get_mut(unique_node_id).filter(|info| info.weight != weight) {
+            debug!("Discovery: El nodo ID {} ({}) pasa a pesar {}.", unique_node_id, service_type, weight);
+            node_info.weight = weight;
+            self.revisions.bump(service_type, unique_node_id);
+        }
+    }
+
+    /// Guarda el tope de peticiones por minuto que anuncia el nodo (`MAX_RPM`).
+    pub(crate) fn set_node_max_rpm(&self, service_type: &PoolName, unique_node_id: &NodeId, max_rpm: u32) {
+        let Some(lock) = self.pool(service_type) else {
+            return;
+        };
+        if let Some(node_info) = lock.write().unwrap().get_mut(unique_node_id).filter(|info| info.max_rpm != Some(max_rpm)) {
+            debug!("Discovery: El nodo ID {} ({}) acepta como mucho {} peticiones por minuto.", unique_node_id, service_type, max_rpm);
+            node_info.max_rpm = Some(max_rpm);
+            self.revisions.bump(service_type, unique_node_id);
+        }
+    }
+
+    /// Guarda las capacidades que anuncia el nodo (`CAPS`). Las desconocidas se ignoran.
+    pub(crate) fn set_node_capabilities(&self, service_type: &PoolName, unique_node_id: &NodeId, capabilities: &[&str]) {
+        let Some(lock) = self.pool(service_type) else {
+            return;
+        };
+        let capabilities: BTreeSet<String> = capabilities
+            .iter()
+            .filter(|capability| tools::KNOWN_CAPABILITIES.contains(capability))
+            .map(|capability| capability.to_string())
+            .collect();
+        if let Some(node_info) = lock.write().unwrap().get_mut(unique_node_id) {
+            if node_info.capability_probe.covers(node_info.backend_version.as_deref()) {
+                trace!("Discovery: Se ignoran los CAPS del nodo ID {}: manda la prueba del balanceador.", unique_node_id);
+                return;
+            }
+            if node_info.capabilities != capabilities {
+                debug!("Discovery: El nodo ID {} anuncia las capacidades {:?}.", unique_node_id, capabilities);
+                node_info.capabilities = capabilities;
+                self.revisions.bump(service_type, unique_node_id);
+            }
+        }
+    }
+
+    /// Guarda la versión del backend del nodo. `true` si cambió respecto a una ya conocida: sus
+    /// capacidades quedan en duda hasta volver a probarlas.
+    pub(crate) fn set_node_backend_version(&self, service_type: &PoolName, unique_node_id: &NodeId, version: &str) -> bool {
+        let Some(lock) = self.pool(service_type) else {
+            return false;
+        };
+        let mut nodes = lock.write().unwrap();
+        let Some(node_info) = nodes.get_mut(unique_node_id) else {
+            return false;
+        };
+        if node_info.backend_version.as_deref() == Some(version) {
+            return false;
+        }
+        let previous = node_info.backend_version.replace(version.to_string());
+        self.revisions.bump(service_type, unique_node_id);
+        let Some(previous) = previous else {
+            return false;
+        };
+        info!("Discovery: El backend del nodo ID {} pasó de la versión {} a la {}.", unique_node_id, previous, version);
+        node_info.capability_probe.stale = true;
+        true
+    }
+
+    /// Si algún nodo de la pool, libre o no, tiene la capacidad.
+    fn pool_has_capability(&self, nodes_lock: &NodeMap, capability: &str) -> bool {
+        nodes_lock
+            .read()
+            .unwrap()
+            .iter()
+            .any(|(unique_node_id, info)| self.capability_overrides.has(unique_node_id, &info.capabilities, capability))
+    }
+
+    fn node_has_capability(&self, nodes_lock: &NodeMap, unique_node_id: &NodeId, capability: &str) -> bool {
+        nodes_lock
+            .read()
+            .unwrap()
+            .get(unique_node_id)
+            .is_some_and(|info| self.capability_overrides.has(unique_node_id, &info.capabilities, capability))
+    }
+
+    fn node_has_tags(&self, nodes_lock: &NodeMap, unique_node_id: &NodeId, tags: &TagRequirement) -> bool {
+        nodes_lock.read().unwrap().get(unique_node_id).is_some_and(|info| tags.matches(&info.tags))
+    }
+
+    fn node_under_rpm_cap(&self, nodes_lock: &NodeMap, unique_node_id: &NodeId) -> bool {
+        nodes_lock.read().unwrap().get(unique_node_id).is_some_and(|info| self.rpm_limits.admits(unique_node_id, info, Instant::now()))
+    }
+
+    fn node_serves_model(&self, nodes_lock: &NodeMap, unique_node_id: &NodeId, model: Option<&str>) -> bool {
+        let nodes = nodes_lock.read().unwrap();
+        let advertised = models::advertised(&nodes);
+        nodes.get(unique_node_id).is_some_and(|info| models::eligible(advertised, info, model))
+    }
+
+    /// Nodo de una pool directa (`direct`) al que la petición puede ir sin cola: el único de la
+    /// pool, utilizable y sin ritmo de despacho. No se ocupa; sólo se cuenta el despacho.
+    fn direct_node(&self, service: &PoolName, nodes_lock: &NodeMap, demand: NodeDemand) -> Option<(NodeId, ServiceUrl)> {
+        if !self.direct.is_direct(service) || self.workload.pools().any(|(pool, _)| *pool == **service) {
+            return None;
+        }
+        let mut nodes = nodes_lock.write().unwrap();
+        if nodes.len() != 1 {
+            return None;
+        }
+        let advertised = models::advertised(&nodes);
+        let (unique_id, info) = nodes.iter_mut().next()?;
+        if !self.node_usable(advertised, unique_id, info, demand) || self.dispatch_limits.rate_for(service, unique_id).is_some() || self.rpm_limits.cap_for(unique_id, info).is_some() || info.canary.is_some() {
+            return None;
+        }
+        info.stats.dispatched += 1;
+        if let Some(model) = demand.model {
+            info.last_model = Some((model.to_string(), Instant::now()));
+        }
+        Some((unique_id.clone(), info.service_url.clone()))
+    }
+
+    /// Si merece la pena esperar al nodo de una sesión: sigue en el registro, no está caído ni
+    /// drenándose y tiene el modelo y la capacidad que pide la petición.
+    fn session_node_usable(&self, nodes_lock: &NodeMap, unique_node_id: &NodeId, demand: NodeDemand) -> bool {
+        let nodes = nodes_lock.read().unwrap();
+        let advertised = models::advertised(&nodes);
+        nodes.get(unique_node_id).is_some_and(|info| self.node_usable(advertised, unique_node_id, info, demand))
+    }
+
+    /// Nodo que toca al usuario (`user_affinity`) entre los de la pool que pueden atender la petición.
+    fn user_node(&self, nodes_lock: &NodeMap, user: &str, demand: NodeDemand) -> Option<NodeId> {
+        let nodes = nodes_lock.read().unwrap();
+        let advertised = models::advertised(&nodes);
+        let usable = nodes.iter().filter(|(unique_id, info)| self.node_usable(advertised, unique_id, info, demand)).map(|(unique_id, _)| unique_id);
+        self.user_affinity.node_for(user, usable)
+    }
+
+    /// Si un nodo, libre o no, puede atender la petición: no está caído ni drenándose y tiene su
+    /// modelo y capacidad.
+    fn node_usable(&self, advertised: bool, unique_node_id: &NodeId, info: &NodeInfo, demand: NodeDemand) -> bool {
+        !matches!(info.state, NodeHealth::Failed(_) | NodeHealth::Draining(_))
+            && models::eligible(advertised, info, demand.model)
+            && demand.capability.is_none_or(|capability| self.capability_overrides.has(unique_node_id, &info.capabilities, capability))
+            && demand.context_tokens.is_none_or(|needed| self.context_limits.node_fits(info, demand.model, needed))
+            && demand.tags.is_none_or(|tags| tags.matches(&info.tags))
+            && (!demand.slow || self.slow_lane.node_admits(info))
+    }
+
+    /// Anota contra el nodo una respuesta con `tool_calls` mal formados.
+    fn record_invalid_tool_calls(&self, service_type: &PoolName, unique_node_id: &NodeId, invalid: &InvalidToolCalls) {
+        warn!("  -> El nodo ID {} devolvió tool_calls mal formados: {}", unique_node_id, invalid);
+        self.record_node_error(service_type, unique_node_id, ErrorCategory::InvalidToolCalls, invalid.to_string());
+    }
+
+    /// Anota una respuesta que no sigue el formato esperado en una pool con `strictness`.
+    pub(crate) fn record_invalid_response(&self, service_type: &PoolName, unique_node_id: &NodeId, mode: Strictness, violation: &str) {
+        warn!("  -> El nodo ID {} devolvió una respuesta inválida ({}): {}", unique_node_id, mode.label(), violation);
+        self.record_node_error(service_type, unique_node_id, ErrorCategory::InvalidResponse, violation.to_string());
+    }
+
+    /// Deja de dar peticiones nuevas a un nodo que se apagará dentro de `lead`. Devuelve el
+    /// instante en que hay que sacarlo (`finish_drain`) si empieza a drenarse ahora; un `DRAINING`
+    /// repetido sólo cuenta como señal de vida y el plazo sigue siendo el del primero.
+    pub(crate) fn start_drain(&self, service_type: &PoolName, unique_node_id: &NodeId, lead: Duration) -> Option<Instant> {
+        let lock = self.pool(service_type)?;
+        // Se calcula antes de tomar el lock: un pánico con él tomado envenenaría la pool.
+        let Some(deadline) = Instant::now().checked_add(lead) else {
+            warn!("Discovery: DRAINING del nodo ID {} ({}) con un plazo fuera de rango ({}s); se ignora.", unique_node_id, service_type, lead.as_secs());
+            return None;
+        };
+        {
+            let mut nodes = lock.write().unwrap();
+            let Some(node_info) = nodes.get_mut(unique_node_id) else {
+                debug!("Discovery: DRAINING de un nodo desconocido ID {} ({}); se ignora.", unique_node_id, service_type);
+                return None;
+            };
+            node_info.last_seen = Instant::now();
+            if matches!(node_info.state, NodeHealth::Draining(_)) {
+                return None;
+            }
+        }
+        info!("Discovery: Nodo ID {} ({}) se apagará en {}s; no recibe peticiones nuevas.", unique_node_id, service_type, lead.as_secs());
+        self.update_node_state(service_type, unique_node_id, NodeHealth::Draining(deadline), TransitionCause::Drain);
+        self.events.publish(BalancerEvent::NodeDraining {
+            service: service_type.to_string(),
+            node_id: unique_node_id.to_string(),
+            lead_secs: lead.as_secs(),
+        });
+        Some(deadline)
+    }
+
+    /// Saca un nodo cuyo plazo de drenaje venció, si sigue drenándose con ese plazo.
+    pub(crate) fn finish_drain(&self, service_type: &PoolName, unique_node_id: &NodeId, deadline: Instant) {
+        let Some(lock) = self.pool(service_type) else {
+            return;
+        };
+        let removed = {
+            let mut nodes = lock.write().unwrap();
+            if !nodes.get(unique_node_id).is_some_and(|info| matches!(info.state, NodeHealth::Draining(until) if until == deadline)) {
+                return;
+            }
+            self.revisions.bump(service_type, unique_node_id);
+            nodes.remove(unique_node_id)
+        };
+        if let Some(node_info) = removed {
+            info!("Discovery: Venció el plazo de drenaje del nodo ID {} ({}). Eliminado.", unique_node_id, service_type);
+            self.record_removal(service_type, unique_node_id, &node_info, RemovalReason::Drained);
+        }
+    }
+
+    /// Elimina un nodo que anunció explícitamente su salida.
+    pub(crate) fn deregister_node(&self, service_type: &PoolName, unique_node_id: &NodeId) {
+        let Some(lock) = self.pool(service_type) else {
+            return;
+        };
+        let removed = {
+            let mut nodes = lock.write().unwrap();
+            let removed = nodes.remove(unique_node_id);
+            if removed.is_some() {
+                self.revisions.bump(service_type, unique_node_id);
+            }
+            removed
+        };
+        if let Some(node_info) = removed {
+            info!("Discovery: Nodo ID {} ({}) se ha despedido. Eliminado.", unique_node_id, service_type);
+            self.record_removal(service_type, unique_node_id, &node_info, RemovalReason::Goodbye);
+        }
+    }
+}
+
+#[get("/events")]
+async fn events_handler(state: web::Data<AppState>) -> impl Responder {
+    match events::subscribe(state) {
+        Some(stream) => HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .insert_header((header::CACHE_CONTROL, "no-cache"))
+            .streaming(stream),
+        None => HttpResponse::ServiceUnavailable().json(serde_json::json!({
+            "error": "Se alcanzó el máximo de suscriptores de eventos.",
+        })),
+    }
+}
+
+#[get("/metrics")]
+async fn metrics_handler(state: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(state.metrics.render() + &state.stream_limiter.render_metrics() + &state.model_queues.render_metrics() + &limits::render_metrics(&state))
+}
+
+/// Configuración resuelta para `/config` y el paquete de soporte.
+pub(crate) fn config_snapshot(state: &AppState) -> serde_json::Value {
+    serde_json::json!({
+        "active_profile": state.profiles.active_name(),
+        "pinned": state.profiles.is_pinned(),
+        "settings": state.profiles.settings(),
+        "profiles": state.profiles.describe(),
+        "pool_aliases": state.pool_aliases.iter().collect::<BTreeMap<_, _>>(),
+        "routing_rules": state.routing_rules.read().unwrap().rules(),
+        "config_history": state.config_history.describe(),
+        "spill": state.spill.describe(),
+        "node_selection": state.selector.label(),
+        "node_anti_affinity": state.selector.avoids_last(),
+        "latency": state.latency.describe(),
+        "node_tiers": state.tiers.describe(),
+        "workload": state.workload.describe(),
+        "interleave": state.interleave.describe(),
+        "slow_lane": state.slow_lane.describe(),
+        "tombstones": state.tombstones.describe(),
+        "node_capabilities": state.capability_overrides.describe(),
+        "failure_domains": state.domains.describe(),
+        "node_max_rpm": state.rpm_limits.describe(),
+        "canary": state.canary.describe(),
+        "locality": state.locality.describe(),
+        "direct_pools": state.direct.describe(),
+        "max_client_in_flight": state.client_limiter.default_max(),
+        "warm_model": state.warm.describe(),
+        "stale_busy_grace_secs": state.stale_busy_grace.map(|grace| grace.as_secs()),
+        "drain": state.drain.describe(),
+        "user_affinity": state.user_affinity.describe(),
+        "benchmark": state.benchmarks.describe(),
+        "node_pinning": state.pins.describe(),
+        "retry_invalid_tool_calls": state.retry_invalid_tool_calls,
+        "retry": state.retry.describe(),
+        "warmup": state.warmup.describe(),
+        "persistence": state.persistence.as_ref().map(Store::describe),
+        "usage_estimate": state.usage_estimator.describe(),
+    })
+}
+
+#[get("/config")]
+async fn config_handler(state: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(config_snapshot(&state))
+}
+
+#[derive(serde::Deserialize)]
+struct ProfileOverride {
+    name: Option<String>,
+}
+
+/// Fija un perfil (`{"name":"night"}`) o vuelve a los horarios (`{"name":null}`).
+#[post("/admin/profile")]
+async fn admin_profile_handler(
+    state: web::Data<AppState>,
+    body: web::Json<ProfileOverride>,
+) -> impl Responder {
+    let switch = match &body.name {
+        Some(name) => match state.profiles.pin(name) {
+            Ok(switch) => switch.map(|s| (s, "fijado manualmente")),
+            Err(e) => return HttpResponse::NotFound().json(serde_json::json!({ "error": e.to_string() })),
+        },
+        None => state.profiles.unpin(&chrono::Local::now()).map(|s| (s, "fijación retirada")),
+    };
+    if let Some((switch, reason)) = switch {
+        profiles::announce_switch(&state, switch, reason);
+    }
+    HttpResponse::Ok().json(serde_json::json!({
+        "active_profile": state.profiles.active_name(),
+        "pinned": state.profiles.is_pinned(),
+    }))
 }
 
-async fn forward_request(
-    client: &reqwest::Client,
-    node_service_url: &str,
-    req_body: web::Bytes,
-) -> Result<reqwest::Response, reqwest::Error> {
-     debug!("  -> forward_request: Enviando POST a {} con body size: {}", node_service_url, req_body.len());
-     client.post(node_service_url)
-         .header(reqwest::header::CONTENT_TYPE, "application/json")
-         .body(req_body)
-         .send()
-         .await
+#[derive(serde::Deserialize)]
+struct AuditExportQuery {
+    from: Option<String>,
+    to: Option<String>,
 }
 
-async fn handle_service_request(
-    service_name: &str,
-    nodes_lock: Arc<RwLock<HashMap<String, NodeInfo>>>,
-    client: web::Data<reqwest::Client>,
-    req_body: web::Bytes,
-    queue_timeout: Duration,
-    queue_poll_interval: Duration,
+#[get("/audit/export")]
+async fn audit_export_handler(
+    state: web::Data<AppState>,
+    query: web::Query<AuditExportQuery>,
 ) -> impl Responder {
-    info!("Balancer handle_service_request para '{}' RECIBIDO.", service_name);
-    debug!("  -> Tamaño del body recibido: {} bytes", req_body.len());
-
-    if req_body.len() > 0 && req_body.len() < 1024 {
-         match std::str::from_utf8(&req_body) {
-             Ok(body_str) => trace!("  -> Contenido del body recibido: {}", body_str),
-             Err(_) => trace!("  -> Contenido del body recibido: (No es UTF-8 válido o muy largo)"),
-         }
-    } else if req_body.len() == 0 {
-         debug!("  -> Contenido del body recibido: ¡¡¡VACÍO!!!");
+    if state.persistence.is_none() {
+        return HttpResponse::NotFound().json(serde_json::json!({
+            "error": "El registro de auditoría no está habilitado (--state-dir o --audit-file).",
+        }));
     }
 
-    let start_time = Instant::now();
-
-    let (unique_node_id, node_service_url) = loop {
-        if let Some(found) = AppState::find_and_occupy_node(&nodes_lock) {
-            debug!("  -> Nodo encontrado y ocupado: ID {}, URL {}", found.0, found.1);
-            break found;
+    let mut bounds = [None, None];
+    for (bound, value) in bounds.iter_mut().zip([&query.from, &query.to]) {
+        if let Some(value) = value {
+            match audit::parse_bound(value) {
+                Some(parsed) => *bound = Some(parsed),
+                None => {
+                    return HttpResponse::BadRequest().json(serde_json::json!({
+                        "error": format!("Fecha inválida '{}'. Usa RFC 3339 o AAAA-MM-DD.", value),
+                    }));
+                }
+            }
         }
+    }
 
-        if start_time.elapsed() > queue_timeout {
-            error!("  -> ERROR: No se encontraron nodos disponibles para '{}' dentro del tiempo de espera ({}s).", service_name, queue_timeout.as_secs());
-            return HttpResponse::ServiceUnavailable().body(format!(
-                "No hay nodos {} disponibles (timeout {}s)",
-                service_name,
-                queue_timeout.as_secs()
-            ));
+    let [from, to] = bounds;
+    let records = web::block(move || state.persistence.as_ref().map(|store| store.export_requests(from, to))).await;
+    match records {
+        Ok(Some(Ok(records))) => HttpResponse::Ok()
+            .content_type("application/x-ndjson")
+            .streaming(records),
+        Ok(Some(Err(e))) => {
+            error!("Audit: Error al abrir el registro para exportar: {}", e);
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "No se pudo leer el registro de auditoría.",
+            }))
         }
+        Ok(None) | Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
 
-        trace!("  -> No hay nodos {} disponibles. Esperando {}ms...", service_name, queue_poll_interval.as_millis());
-        sleep(queue_poll_interval).await;
-    };
+/// Si el listener puede tocar la pool anunciada; si no, cuenta el rechazo.
+fn listener_permits(app_state: &AppState, listener: &DiscoveryListener, pool: &str, src_addr: SocketAddr) -> bool {
+    if listener.allows(pool, app_state.canonical_service(pool)) {
+        return true;
+    }
+    listener.record_rejected(pool, src_addr);
+    false
+}
 
-    info!("  -> Intentando reenviar petición a ID: {}, URL: {}", unique_node_id, node_service_url);
+/// Si el mensaje del nodo llega desde la IP a la que está fijado (`pins`).
+fn pin_permits(app_state: &AppState, unique_node_id: &str, src_addr: SocketAddr) -> bool {
+    app_state.pins.permits(&app_state.events, unique_node_id, src_addr.ip())
+}
 
-    match forward_request(&client, &node_service_url, req_body).await {
-        Ok(response) => {
-            let status = response.status();
-            info!("  -> Respuesta recibida del nodo ID {} (URL {}) con estado: {}", unique_node_id, node_service_url, status);
-            match response.bytes().await {
-                Ok(body_bytes) => {
-                    let new_health = if status.is_success() {
-                        NodeHealth::Available
-                    } else {
-                         warn!("  -> Nodo ID {} respondió con estado no exitoso: {}", unique_node_id, status);
-                         NodeHealth::Failed(Instant::now())
-                    };
-                    AppState::update_node_state(&nodes_lock, &unique_node_id, new_health.clone());
-                    debug!("  -> Marcando nodo ID {} como {:?}.", unique_node_id, new_health);
-                    HttpResponse::build(status).body(body_bytes)
-                }
-                Err(e) => {
-                     error!("  -> Error al leer la respuesta del nodo ID {}: {}", unique_node_id, e);
-                     AppState::update_node_state(&nodes_lock, &unique_node_id, NodeHealth::Failed(Instant::now()));
-                     debug!("  -> Marcando nodo ID {} como Failed.", unique_node_id);
-                     HttpResponse::InternalServerError().body(format!("Error leyendo respuesta de {}", service_name))
-                }
-            }
-        }
+/// Pool e ID del nodo de un mensaje de metadatos, si el listener y la fijación de IP lo permiten.
+/// Una pool desconocida se ignora como antes; un ID inválido cuenta como mensaje mal formado.
+fn metadata_target(
+    app_state: &AppState,
+    listener: &DiscoveryListener,
+    service_type: &str,
+    unique_node_id: &str,
+    src_addr: SocketAddr,
+) -> Option<(&'static PoolName, NodeId)> {
+    if !listener_permits(app_state, listener, service_type, src_addr) || !pin_permits(app_state, unique_node_id, src_addr) {
+        return None;
+    }
+    let service_type = app_state.pool_name(service_type)?;
+    match NodeId::new(unique_node_id) {
+        Ok(unique_node_id) => Some((service_type, unique_node_id)),
         Err(e) => {
-             error!("  -> Error al reenviar la solicitud al nodo ID {}: {}", unique_node_id, e);
-             AppState::update_node_state(&nodes_lock, &unique_node_id, NodeHealth::Failed(Instant::now()));
-             debug!("  -> Marcando nodo ID {} como Failed.", unique_node_id);
-            HttpResponse::InternalServerError()
-                .body(format!("Error reenviando a {}: {}", service_name, e))
+            warn!("UDP Listener: Mensaje de {} descartado: {}", src_addr, e);
+            listener.record_malformed();
+            None
         }
     }
 }
 
-#[post("/lmstudio")]
-async fn lm_studio_handler(
-    state: web::Data<AppState>,
-    req_body: web::Bytes,
-) -> impl Responder {
-     info!("Balancer /lmstudio handler RECIBIDO request. Body size: {}", req_body.len());
-     let client_ref = web::Data::new(state.client.clone());
-     handle_service_request(
-         "LM Studio",
-         state.lm_studio_nodes.clone(),
-         client_ref,
-         req_body,
-         state.queue_timeout,
-         state.queue_poll_interval
-     ).await
-}
-
-#[post("/ollama")]
-async fn ollama_handler(
-    state: web::Data<AppState>,
-    req_body: web::Bytes,
-) -> impl Responder {
-     info!("Balancer /ollama handler RECIBIDO request. Body size: {}", req_body.len());
-     let client_ref = web::Data::new(state.client.clone());
-     handle_service_request(
-        "Ollama",
-        state.ollama_nodes.clone(),
-        client_ref,
-        req_body,
-        state.queue_timeout,
-        state.queue_poll_interval
-    ).await
-}
-
-async fn udp_discovery_listener(
-    udp_addr: String,
+pub(crate) async fn udp_discovery_listener(
+    listener: Arc<DiscoveryListener>,
     app_state: web::Data<AppState>,
+    accept_legacy: bool,
 ) -> std::io::Result<()> {
-    let socket = UdpSocket::bind(&udp_addr).await?;
-    info!("Escuchando anuncios UDP en {}", udp_addr);
-    let mut buf = [0u8; 1024];
+    let socket = UdpSocket::bind(&listener.bind_addr).await?;
+    info!("Escuchando anuncios UDP en {} (pools: {})", listener.bind_addr, listener.allowed_pools_label());
+    // Tamaño máximo de un datagrama UDP: el límite real lo pone el presupuesto configurado en el nodo.
+    let mut buf = vec![0u8; 65536];
+    let mut models_reassembler = ModelsReassembler::default();
+    // Nodos de formato antiguo ya avisados, para no repetir el aviso en cada anuncio.
+    let mut legacy_warned: HashSet<NodeId> = HashSet::new();
 
     loop {
         match socket.recv_from(&mut buf).await {
              Ok((len, src_addr)) => {
+                listener.record_packet();
                 let msg = String::from_utf8_lossy(&buf[..len]);
+                // Los mensajes de metadatos de una pool no permitida se descartan sin aviso: ya avisa su DISCOVER.
+                if let Some(chunk) = discovery::parse_models_message(msg.trim()) {
+                    let Some((service_type, unique_node_id)) = metadata_target(&app_state, &listener, chunk.service, chunk.unique_node_id, src_addr) else {
+                        continue;
+                    };
+                    trace!("UDP Listener: Fragmento de modelos {}/{} de ID {} ({})", chunk.seq + 1, chunk.total, unique_node_id, service_type);
+                    if let Some(models) = models_reassembler.push(chunk, src_addr.ip()) {
+                        app_state.set_node_models(service_type, &unique_node_id, models);
+                    }
+                    continue;
+                }
+                if let Some((service_type, unique_node_id, report)) = discovery::parse_storage_message(msg.trim()) {
+                    let Some((service_type, unique_node_id)) = metadata_target(&app_state, &listener, service_type, unique_node_id, src_addr) else {
+                        continue;
+                    };
+                    app_state.set_node_storage(service_type, &unique_node_id, report);
+                    continue;
+                }
+                if let Some(chunk) = discovery::parse_context_message(msg.trim()) {
+                    let Some((service_type, unique_node_id)) = metadata_target(&app_state, &listener, chunk.service, chunk.unique_node_id, src_addr) else {
+                        continue;
+                    };
+                    app_state.set_node_context_windows(service_type, &unique_node_id, chunk.windows);
+                    continue;
+                }
+                if let Some((service_type, unique_node_id, tokens)) = discovery::parse_max_context_message(msg.trim()) {
+                    let Some((service_type, unique_node_id)) = metadata_target(&app_state, &listener, service_type, unique_node_id, src_addr) else {
+                        continue;
+                    };
+                    app_state.set_node_max_context(service_type, &unique_node_id, tokens);
+                    continue;
+                }
+                if let Some((service_type, unique_node_id, capabilities)) = discovery::parse_capabilities_message(msg.trim()) {
+                    let Some((service_type, unique_node_id)) = metadata_target(&app_state, &listener, service_type, unique_node_id, src_addr) else {
+                        continue;
+                    };
+                    app_state.set_node_capabilities(service_type, &unique_node_id, &capabilities);
+                    continue;
+                }
+                if let Some((service_type, unique_node_id, version)) = discovery::parse_backend_version_message(msg.trim()) {
+                    let Some((service_type, unique_node_id)) = metadata_target(&app_state, &listener, service_type, unique_node_id, src_addr) else {
+                        continue;
+                    };
+                    if app_state.set_node_backend_version(service_type, &unique_node_id, version) {
+                        reprobe::spawn(app_state.clone(), unique_node_id);
+                    }
+                    continue;
+                }
+                if let Some((service_type, unique_node_id, platform)) = discovery::parse_platform_message(msg.trim()) {
+                    let Some((service_type, unique_node_id)) = metadata_target(&app_state, &listener, service_type, unique_node_id, src_addr) else {
+                        continue;
+                    };
+                    app_state.set_node_platform(service_type, &unique_node_id, platform);
+                    continue;
+                }
+                if let Some((service_type, unique_node_id, lead_secs)) = discovery::parse_draining_message(msg.trim()) {
+                    let Some((service_type, unique_node_id)) = metadata_target(&app_state, &listener, service_type, unique_node_id, src_addr) else {
+                        continue;
+                    };
+                    if let Some(deadline) = app_state.start_drain(service_type, &unique_node_id, Duration::from_secs(lead_secs)) {
+                        let state = app_state.clone();
+                        tokio::spawn(async move {
+                            sleep_until(deadline.into()).await;
+                            state.finish_drain(service_type, &unique_node_id, deadline);
+                        });
+                    }
+                    continue;
+                }
+                if let Some((service_type, unique_node_id, outage, served)) = discovery::parse_spooled_message(msg.trim()) {
+                    let Some((service_type, unique_node_id)) = metadata_target(&app_state, &listener, service_type, unique_node_id, src_addr) else {
+                        continue;
+                    };
+                    app_state.record_spooled(service_type, &unique_node_id, outage, served);
+                    continue;
+                }
+                if let Some((service_type, unique_node_id)) = discovery::parse_goodbye_message(msg.trim()) {
+                    let Some((service_type, unique_node_id)) = metadata_target(&app_state, &listener, service_type, unique_node_id, src_addr) else {
+                        continue;
+                    };
+                    app_state.deregister_node(service_type, &unique_node_id);
+                    continue;
+                }
+                if let Some((service_type, unique_node_id, domain)) = discovery::parse_domain_message(msg.trim()) {
+                    let Some((service_type, unique_node_id)) = metadata_target(&app_state, &listener, service_type, unique_node_id, src_addr) else {
+                        continue;
+                    };
+                    app_state.set_node_domain(service_type, &unique_node_id, domain);
+                    continue;
+                }
+                if let Some((service_type, unique_node_id, tags)) = discovery::parse_tags_message(msg.trim()) {
+                    let Some((service_type, unique_node_id)) = metadata_target(&app_state, &listener, service_type, unique_node_id, src_addr) else {
+                        continue;
+                    };
+                    app_state.set_node_tags(service_type, &unique_node_id, tags);
+                    continue;
+                }
+                if let Some((service_type, unique_node_id, weight)) = discovery::parse_weight_message(msg.trim()) {
+                    let Some((service_type, unique_node_id)) = metadata_target(&app_state, &listener, service_type, unique_node_id, src_addr) else {
+                        continue;
+                    };
+                    app_state.set_node_weight(service_type, &unique_node_id, weight);
+                    continue;
+                }
+                if let Some((service_type, unique_node_id, max_rpm)) = discovery::parse_max_rpm_message(msg.trim()) {
+                    let Some((service_type, unique_node_id)) = metadata_target(&app_state, &listener, service_type, unique_node_id, src_addr) else {
+                        continue;
+                    };
+                    app_state.set_node_max_rpm(service_type, &unique_node_id, max_rpm);
+                    continue;
+                }
+                if let Some((service_type, unique_node_id, version)) = discovery::parse_version_message(msg.trim()) {
+                    let Some((service_type, unique_node_id)) = metadata_target(&app_state, &listener, service_type, unique_node_id, src_addr) else {
+                        continue;
+                    };
+                    app_state.set_node_version(service_type, &unique_node_id, version);
+                    continue;
+                }
+                if let Some((service_type, address)) = discovery::parse_legacy_discover(msg.trim()).filter(|_| accept_legacy) {
+                    if !listener_permits(&app_state, &listener, service_type, src_addr) {
+                        warn!("UDP Listener: Anuncio antiguo de {} para la pool '{}' rechazado: el listener {} no la tiene permitida.",
+                              src_addr, service_type, listener.bind_addr);
+                        continue;
+                    }
+                    let legacy_url = app_state.pool_name(service_type).and_then(|pool| Some((pool, discovery::legacy_service_url(pool, address)?)));
+                    let (service_type, announced_url) = match legacy_url.map(|(pool, url)| ServiceUrl::parse(url).map(|url| (pool, url))) {
+                        Some(Ok(parsed)) => parsed,
+                        Some(Err(e)) => {
+                            warn!("UDP Listener: Anuncio antiguo de {} descartado: {}", src_addr, e);
+                            listener.record_malformed();
+                            continue;
+                        }
+                        None => {
+                            warn!("UDP Listener: Mensaje UDP de descubrimiento con servicio desconocido: {}", msg);
+                            listener.record_malformed();
+                            continue;
+                        }
+                    };
+                    let effective_service_url = effective_service_url(announced_url, src_addr.ip(), address);
+                    // Si el mismo backend ya se anuncia con el formato nuevo, se refresca ese nodo en lugar de duplicarlo.
+                    let unique_node_id = match app_state.node_id_by_url(service_type, &effective_service_url) {
+                        Some(existing) => existing,
+                        None => match NodeId::new(format!("{}{}", discovery::LEGACY_ID_PREFIX, effective_service_url.address().unwrap_or_else(|| address.to_string()))) {
+                            Ok(legacy_id) => legacy_id,
+                            Err(e) => {
+                                warn!("UDP Listener: Anuncio antiguo de {} descartado: {}", src_addr, e);
+                                listener.record_malformed();
+                                continue;
+                            }
+                        },
+                    };
+                    if legacy_warned.insert(unique_node_id.clone()) {
+                        warn!("UDP Listener: {} ({}) usa el formato de anuncio antiguo 'DISCOVER,<svc>,<ip:puerto>'. Actualiza el nodo; este formato dejará de aceptarse.",
+                              src_addr, unique_node_id);
+                    }
+                    if !pin_permits(&app_state, &unique_node_id, src_addr) {
+                        continue;
+                    }
+                    debug!("UDP Listener: Anuncio antiguo de {} registrado como ID {} (URL {}).", address, unique_node_id, effective_service_url);
+                    match app_state.register_node(service_type, &unique_node_id, effective_service_url) {
+                        Ok(()) => {
+                            listener.record_registered();
+                            app_state.pins.pin(&unique_node_id, src_addr.ip());
+                            app_state.set_node_source_ip(service_type, &unique_node_id, src_addr.ip());
+                        }
+                        Err(e) => warn!("UDP Listener: Anuncio antiguo de ID {} no registrado: {}", unique_node_id, e),
+                    }
+                    continue;
+                }
                 let parts: Vec<&str> = msg.trim().splitn(4, ',').collect();
 
                 if parts.len() == 4 && parts[0] == "DISCOVER" {
-                    let service_type = parts[1];
-                    let unique_node_id = parts[2].to_string();
-                    let announced_service_url = parts[3].to_string();
-
-                    let mut effective_service_url = announced_service_url.clone();
-                    match Url::parse(&announced_service_url) {
-                        Ok(mut parsed_url) => {
-                            if let Some(host_str) = parsed_url.host_str() {
-                                if host_str == "localhost" || host_str == "127.0.0.1" {
-                                    let source_ip = src_addr.ip().to_string();
-                                    if let Err(e) = parsed_url.set_host(Some(&source_ip)) {
-                                        warn!("UDP Listener: No se pudo establecer el host '{}' en la URL parseada para {}: {}", source_ip, unique_node_id, e);
-                                    } else {
-                                        effective_service_url = parsed_url.to_string();
-                                        debug!("UDP Listener: Reemplazado host 'localhost'/'127.0.0.1' con '{}' para nodo {}", source_ip, unique_node_id);
-                                    }
-                                }
-                            }
-                        }
+                    if !listener_permits(&app_state, &listener, parts[1], src_addr) {
+                        warn!("UDP Listener: Anuncio de ID {} desde {} para la pool '{}' rechazado: el listener {} no la tiene permitida.",
+                              parts[2], src_addr, parts[1], listener.bind_addr);
+                        continue;
+                    }
+                    let Some(service_type) = app_state.pool_name(parts[1]) else {
+                        warn!("UDP Listener: Anuncio de ID {} para '{}' no registrado: {}", parts[2], parts[1], RegisterError::UnknownService);
+                        continue;
+                    };
+                    let parsed = NodeId::new(parts[2]).and_then(|id| Ok((id, ServiceUrl::parse(parts[3])?)));
+                    let (unique_node_id, announced_url) = match parsed {
+                        Ok(parsed) => parsed,
                         Err(e) => {
-                            warn!("UDP Listener: No se pudo parsear la URL '{}' anunciada por {}: {}. Usando URL original.", announced_service_url, unique_node_id, e);
+                            warn!("UDP Listener: Anuncio de {} descartado: {}", src_addr, e);
+                            listener.record_malformed();
+                            continue;
                         }
+                    };
+                    let unique_node_id = &unique_node_id;
+                    if !pin_permits(&app_state, unique_node_id, src_addr) {
+                        continue;
                     }
+                    let effective_service_url = effective_service_url(announced_url, src_addr.ip(), unique_node_id);
 
                     info!("UDP Listener: Recibido anuncio de ID {} (URL efectiva {}) para {} desde {}",
                           unique_node_id, effective_service_url, service_type, src_addr);
 
-
-                    let nodes_lock = match service_type {
-                        "lmstudio" => Some(app_state.lm_studio_nodes.clone()),
-                        "ollama" => Some(app_state.ollama_nodes.clone()),
-                        _ => {
-                            warn!("UDP Listener: Mensaje UDP de descubrimiento con servicio desconocido: {}", msg);
-                            None
+                    if accept_legacy {
+                        app_state.retire_legacy_duplicate(service_type, unique_node_id, &effective_service_url);
+                    }
+                    if let Err(e) = app_state.register_node(service_type, unique_node_id, effective_service_url) {
+                        warn!("UDP Listener: Anuncio de ID {} para '{}' no registrado: {}", unique_node_id, service_type, e);
+                    } else {
+                        listener.record_registered();
+                        app_state.pins.pin(unique_node_id, src_addr.ip());
+                        app_state.set_node_source_ip(service_type, unique_node_id, src_addr.ip());
+                        if let Err(e) = socket.send_to(discovery::ack_message(unique_node_id).as_bytes(), src_addr).await {
+                            debug!("UDP Listener: No se pudo enviar ACK a {}: {}", src_addr, e);
                         }
-                    };
-
-                    if let Some(lock) = nodes_lock {
-                         let mut nodes = lock.write().unwrap();
-                         debug!("UDP Listener: Añadiendo/Actualizando nodo ID {} para servicio {} como Available.", unique_node_id, service_type);
-                         nodes.insert(unique_node_id.clone(), NodeInfo {
-                             state: NodeHealth::Available,
-                             service_url: effective_service_url,
-                             last_seen: Instant::now(),
-                         });
                     }
-
                 } else {
+                     listener.record_malformed();
                      warn!("UDP Listener: Mensaje UDP mal formado recibido de {} (Esperado 'DISCOVER,<svc>,<id>,<url>'): {}", src_addr, msg);
                 }
             }
@@ -269,69 +3113,24 @@ async fn udp_discovery_listener(
     }
 }
 
-async fn terminal_ui(app_state: web::Data<AppState>) {
-    let listen_addr = app_state.listen_addr.clone();
-
-    loop {
-        print!("\x1B[2J\x1B[1;1H");
-        let _ = io::stdout().flush();
-
-
-        info!("== Estado del Balanceador de Cargas ==");
-        info!("API Global escuchando en: http://{}", listen_addr);
-        info!("Timeout cola peticiones: {}s", app_state.queue_timeout.as_secs());
-
-        let now = Instant::now();
-        let _failure_threshold = Duration::from_secs(60);
-
-        let print_nodes = |service_name: &str, nodes_lock: &Arc<RwLock<HashMap<String, NodeInfo>>>| {
-            let nodes = nodes_lock.read().unwrap();
-            info!("\n-- {} Nodes --", service_name);
-            info!("{:<45} {:<60} {:<15} {:<10}", "Node ID", "Service URL", "State", "Last Seen");
-            info!("{}", "-".repeat(135));
-
-            if nodes.is_empty() {
-                info!("(No nodes registered)");
-            } else {
-                let mut sorted_nodes: Vec<_> = nodes.iter().collect();
-                sorted_nodes.sort_by_key(|(id, _)| *id);
-
-                for (id, info) in sorted_nodes {
-                    let state_str = match info.state {
-                        NodeHealth::Available => "Available".to_string(),
-                        NodeHealth::Busy => "Busy".to_string(),
-                        NodeHealth::Failed(failed_time) => {
-                            let elapsed = now.duration_since(failed_time);
-                             format!("Failed ({}s)", elapsed.as_secs())
-                        }
-                    };
-                    let seen_ago = now.duration_since(info.last_seen).as_secs();
-                    info!("{:<45} {:<60} {:<15} {:<10}", id, info.service_url, state_str, format!("{}s ago", seen_ago));
-                }
-            }
-        };
-
-        print_nodes("LM Studio", &app_state.lm_studio_nodes);
-        print_nodes("Ollama", &app_state.ollama_nodes);
-
-        info!("\nCtrl+C para detener.");
-
-        sleep(Duration::from_secs(2)).await;
-    }
-}
-
-fn remove_stale_nodes(
-    nodes_map: &mut HashMap<String, NodeInfo>,
+pub(crate) fn remove_stale_nodes(
+    nodes_map: &mut HashMap<NodeId, NodeInfo>,
+    app_state: &AppState,
     timeout: Duration,
     service_name: &str,
+    service: &PoolName,
 ) {
     let now = Instant::now();
     let initial_len = nodes_map.len();
     let mut removed_nodes = Vec::new();
 
     nodes_map.retain(|node_id, node_info| {
-        let is_stale = now.duration_since(node_info.last_seen) > timeout;
+        // Los nodos estáticos no se anuncian; su salud la vigilan los health checks.
+        let is_stale = node_info.source == NodeSource::Announced
+            && now.duration_since(node_info.last_seen) > timeout;
         if is_stale {
+            app_state.revisions.bump(service, node_id);
+            app_state.record_removal(service, node_id, node_info, RemovalReason::Stale);
             removed_nodes.push(node_id.clone());
             false
         } else {
@@ -351,53 +3150,450 @@ fn remove_stale_nodes(
 }
 
 
-pub async fn run_balancer(listen_addr: &str, udp_addr: &str) -> std::io::Result<()> {
+/// La aplicación actix del balanceador: middleware de autorización y versionado, y todas las rutas.
+pub(crate) fn app(
+    state: web::Data<AppState>,
+) -> App<impl ServiceFactory<ServiceRequest, Config = (), Response = ServiceResponse<impl MessageBody>, Error = actix_web::Error, InitError = ()>> {
+    App::new()
+        .app_data(state)
+        .wrap(from_fn(auth::authorize))
+        .wrap(from_fn(api_types::versioning))
+        .service(index::index_handler)
+        .service(lm_studio_handler)
+        .service(ollama_handler)
+        .service(pool_handler)
+        .service(version_handler)
+        .service(warmup::readyz_handler)
+        .service(status::public_status_handler)
+        .service(openapi::openapi_handler)
+        .service(nodes_handler)
+        // Antes que /nodes/{id}, que también casaría con "watch".
+        .service(revisions::watch_handler)
+        .service(limits::memory_handler)
+        .service(node_detail_handler)
+        .service(dispatch_rate::dispatch_rate_handler)
+        .service(diagnose::diagnose_handler)
+        .service(reprobe::reprobe_handler)
+        .service(benchmark::benchmarks_handler)
+        .service(benchmark::benchmark_settings_handler)
+        .service(pins::repin_handler)
+        .service(tombstones::remove_handler)
+        .service(node_history_handler)
+        .service(audit_export_handler)
+        .service(persistence::usage_daily_handler)
+        .service(metrics_handler)
+        .service(events_handler)
+        .service(config_handler)
+        .service(support::support_bundle_handler)
+        .service(admin_profile_handler)
+        .service(reload::reload_handler)
+        .service(reload::rollback_handler)
+        .service(preview::preview_handler)
+        .service(rules::route_debug_handler)
+        .service(stats::reset_handler)
+        .service(stats::summary_handler)
+        .service(stats::fairness_handler)
+        .service(ollama::tags_handler)
+        .service(ollama::version_handler)
+}
+
+/// Arma el estado del balanceador a partir de su configuración, sin arrancar ninguna tarea.
+pub(crate) fn build_state(config: &BalancerConfig, persistence: Option<Store>) -> io::Result<AppState> {
+    let limits = match &config.limits_file {
+        Some(path) => {
+            let limits = Limits::load(path).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            info!("Límites de memoria cargados de {}.", path.display());
+            limits
+        }
+        None => Limits::default(),
+    };
+
     info!("Configurando cliente HTTP...");
+    let dns_resolver = CachingResolver::new(Duration::from_secs(config.dns_cache_ttl), limits.dns_hosts);
     let http_client = reqwest::Client::builder()
+        .dns_resolver(Arc::new(dns_resolver.clone()))
         .timeout(Duration::from_secs(300))
         .connect_timeout(Duration::from_secs(10))
         .build()
         .expect("No se pudo crear el cliente HTTP");
     info!("Cliente HTTP configurado.");
 
-    let queue_timeout = Duration::from_secs(30);
     let queue_poll_interval = Duration::from_millis(200);
+    let strictness = PoolStrictness::new(&config.strictness).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let base_settings = RuntimeSettings { queue_timeout_secs: 30, max_tokens: None, strictness };
+    let profile_manager = match &config.profiles_file {
+        Some(path) => {
+            let manager = ProfileManager::load(path, base_settings).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            info!("Perfiles cargados de {}.", path.display());
+            manager
+        }
+        None => ProfileManager::new(base_settings),
+    };
+
+
+    let header_whitelists = headers::build_whitelists(&["lmstudio", "ollama"], &config.forward_header, &config.strip_header)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    for (service, whitelist) in &header_whitelists {
+        info!("Cabeceras reenviadas a {}: {}", service, whitelist.names().collect::<Vec<_>>().join(", "));
+    }
+
+    let header_limits = HeaderLimits::new(config.max_forwarded_header_value_bytes, config.max_forwarded_header_bytes, config.max_forwarded_headers)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let postprocessors = Postprocessors::new(&["lmstudio", "ollama"], &config.postprocess, &config.strip_token)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    for (service, config) in postprocessors.iter() {
+        info!("Post-procesado de respuestas en {}: {}", service, config.names().join(", "));
+    }
+
+    let pool_aliases = PoolAliases::new(&["lmstudio", "ollama"], &config.pool_alias).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    for (alias, target) in pool_aliases.iter() {
+        info!("Alias de pool: {} -> {}", alias, target);
+        if ["lmstudio", "ollama"].contains(&alias) {
+            warn!("La pool {} queda tapada por su alias: sus peticiones y anuncios van a {}.", alias, target);
+        }
+    }
+
+    let is_pool = |pool: &str| ["lmstudio", "ollama"].contains(&pool) || pool_aliases.resolve(pool).is_some();
+    let discovery_listeners = listeners::build(config.udp_addr.as_deref(), &config.udp_listener, is_pool)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let routing_rules = match &config.rules_file {
+        Some(path) => {
+            let rules = RuleSet::load(path, is_pool).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            info!("{} reglas de enrutado cargadas de {}.", rules.len(), path.display());
+            rules
+        }
+        None => RuleSet::default(),
+    };
+    let config_history = ConfigHistory::new(config.config_history, &routing_rules, profile_manager.current());
+
+    let context_limits = ContextLimits::new(&["lmstudio", "ollama"], &config.context_window, config.context_headroom)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    if context_limits.len() > 0 {
+        info!("{} ventanas de contexto configuradas (margen {}%).", context_limits.len(), config.context_headroom);
+    }
+
+    let retry = RetryPolicies::new(&config.retry_policy).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let key_policies = match &config.api_keys_file {
+        Some(path) => {
+            let policies = KeyPolicies::load(path, is_pool).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            info!("Políticas de {} API keys cargadas de {}.", policies.len(), path.display());
+            policies
+        }
+        None => KeyPolicies::default(),
+    };
+
+    let dispatch_limits = DispatchLimits::new(&["lmstudio", "ollama"], &config.dispatch_rate)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    for (service, rate) in dispatch_limits.pools() {
+        info!("Ritmo máximo de despacho por nodo en {}: {}/s (ráfaga {}).", service, rate.per_second, rate.burst);
+    }
+
+    let spill = SpillPolicy::new(
+        &["lmstudio", "ollama"],
+        &config.pool_cost,
+        Duration::from_millis(config.spill_delay_ms),
+        config.spill_queue_depth,
+        &config.pool_spillover,
+        Duration::from_millis(config.pool_spillover_delay_ms),
+    )
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let workload = WorkloadPolicy::new(&["lmstudio", "ollama"], config.default_workload_class, &config.reserve_interactive)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    for (service, nodes) in workload.pools() {
+        info!("Reservados {} nodos de {} para peticiones interactivas.", nodes, service);
+    }
+    let interleave = Interleaver::new(config.short_dispatch_share, config.short_max_tokens)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let slow_lane = SlowLane::new(
+        config.slow_lane_body_bytes,
+        config.slow_lane_prompt_tokens,
+        config.slow_lane_pool.clone(),
+        config.slow_lane_tags.iter().cloned().collect(),
+        config.slow_lane_max_share,
+        config.slow_lane_queue_timeout_secs,
+        config.slow_lane_request_timeout_secs,
+    )
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    if let Some(pool) = slow_lane.pool().filter(|pool| !is_pool(pool)) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Pool desconocida '{}' en --slow-lane-pool", pool)));
+    }
+    if slow_lane.is_enabled() {
+        info!("Carril lento activo: {}.", slow_lane.lane_label());
+    }
+    let latency = LatencyPolicy::new(config.latency_alpha, Duration::from_millis(config.latency_failure_penalty_ms), config.latency_explore)
+        .map(Arc::new)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    if interleave.is_enabled() {
+        info!(
+            "Intercalado activo: {:.0}% de los despachos para peticiones cortas (sin stream o max_tokens <= {}).",
+            config.short_dispatch_share * 100.0,
+            config.short_max_tokens
+        );
+    }
+
+    let previous_registry = persistence::previous_registry(persistence.as_ref());
+    let warmup_dispatch_rate = config
+        .warmup_dispatch_rate
+        .as_deref()
+        .map(DispatchLimits::parse_rate)
+        .transpose()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    if !(config.warmup_capacity_fraction > 0.0 && config.warmup_capacity_fraction <= 1.0) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "--warmup-capacity-fraction debe estar en (0, 1]"));
+    }
+    let warmup = WarmUp::new(
+        Duration::from_secs(config.warmup_secs),
+        config.warmup_min_nodes,
+        previous_registry.as_ref().map(|snapshot| (snapshot.nodes.len(), config.warmup_capacity_fraction)),
+        warmup_dispatch_rate,
+        config.warmup_max_queued,
+        Duration::from_secs(config.warmup_retry_after_secs),
+    );
+
+    let tiers = if config.no_node_tiers { NodeTiers::default() } else { NodeTiers::new(&config.node_tier).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))? };
+    if tiers.is_enabled() {
+        info!("Niveles de nodos: {}.", tiers.legend().join(" > "));
+    }
+
+    let usage_estimator = UsageEstimator::new(config.usage_chars_per_token).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let capability_overrides = CapabilityOverrides::new(&config.node_capability)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let domains = FailureDomains::new(&config.node_domain).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let canary = CanaryPolicy::new(config.canary_secs, config.canary_requests, config.canary_percent, config.canary_max_error_rate)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let rpm_limits = RpmLimits::new(&config.node_max_rpm).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let locality = Locality::new(&config.local_prefix).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let direct = DirectPools::new(&["lmstudio", "ollama"], &config.direct_pool).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    for service in direct.pools() {
+        info!("Modo directo en {}: mientras tenga un solo nodo, sin cola.", service);
+    }
+    let pins = NodePins::new(config.node_pinning, previous_registry.map(|snapshot| snapshot.pins).unwrap_or_default());
+    let benchmarks = Benchmarks::new(config.benchmark_at.as_deref(), config.benchmark_regression_pct)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
 
+    let capacity = CapacityHints::new(!config.no_capacity_headers, config.pressure_medium_percent, config.pressure_high_percent)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
 
     info!("Creando estado de la aplicación...");
-    let app_state = web::Data::new(AppState {
+    let app_state = AppState {
+        started_at: Instant::now(),
+        // El registro arranca vacío tras cualquier parada, limpia o no (kill -9, OOM), y se
+        // repuebla con los anuncios: la instantánea de `--state-dir` sólo alimenta el
+        // calentamiento y los pines, así que no hay nodos Busy heredados ni peticiones en vuelo
+        // que reconciliar.
         lm_studio_nodes: Arc::new(RwLock::new(HashMap::new())),
         ollama_nodes: Arc::new(RwLock::new(HashMap::new())),
+        node_history: Arc::new(RwLock::new(NodeHistory::new(limits.history_nodes))),
+        upstream_errors: RwLock::new(ErrorLog::new(limits.error_log_nodes)),
         client: http_client,
-        listen_addr: listen_addr.to_string(),
-        queue_timeout,
+        listen_addr: config.listen_addr.clone(),
         queue_poll_interval,
-    });
+        persistence,
+        usage_estimator,
+        audit_token: config.audit_token.clone(),
+        admin_token: config.admin_token.clone(),
+        protect_read_endpoints: config.protect_read_endpoints,
+        metrics: Metrics::new(limits.error_metric_series),
+        header_whitelists,
+        header_limits,
+        postprocessors,
+        events: EventHub::new(config.max_event_subscribers, limits.event_buffer),
+        empty_pools: Mutex::new(BTreeSet::new()),
+        dns_resolver,
+        ollama_cache: OllamaCache::default(),
+        profiles: profile_manager,
+        stream_buffer_bytes: config.stream_buffer_bytes,
+        sse_keepalive: (config.sse_keepalive_secs > 0).then(|| Duration::from_secs(config.sse_keepalive_secs)),
+        key_policies,
+        context_limits,
+        retry,
+        pool_aliases,
+        routing_rules: RwLock::new(routing_rules),
+        config_history,
+        capacity,
+        revisions: RegistryRevisions::new(limits.revision_changes),
+        selector: selection::build(config.node_selection, latency.clone(), config.node_anti_affinity),
+        tiers,
+        fairness: FairnessTracker::new(Duration::from_secs(config.fairness_window_secs), config.fairness_skew_threshold, limits.fairness_dispatches),
+        idempotency: IdempotencyCache::new(
+            Duration::from_secs(config.idempotency_ttl_secs),
+            limits.idempotency_entries.unwrap_or(config.idempotency_max_entries),
+            config.idempotency_max_body_bytes,
+        ),
+        min_free_disk_bytes: config.min_free_disk_mb * 1024 * 1024,
+        model_loader: ModelLoader::new(&config.auto_load_model, Duration::from_secs(config.model_load_timeout)),
+        version_warnings: Mutex::new(HashSet::new()),
+        pipeline: PipelineReservations::new(Duration::from_millis(config.pipeline_window_ms), config.pipeline_min_available, limits.pipeline_reservations),
+        sessions: SessionAffinity::new(Duration::from_secs(config.session_ttl_secs), limits.sticky_sessions),
+        stream_limiter: StreamLimiter::new(&["lmstudio", "ollama"], config.max_streams, config.max_streams_per_pool, config.max_streams_per_key),
+        request_window: RequestWindow::new(limits.request_samples),
+        bounded: BoundedStores::new(limits.clone()),
+        discovery_listeners,
+        dispatch_limits,
+        spill,
+        capability_overrides,
+        domains,
+        rpm_limits,
+        canary,
+        locality,
+        direct,
+        client_limiter: ClientLimiter::new(config.max_client_in_flight, limits.client_in_flight),
+        warm: WarmAffinity::new(config.warm_model_window_secs),
+        stale_busy_grace: (config.stale_busy_grace_secs > 0).then(|| Duration::from_secs(config.stale_busy_grace_secs)),
+        drain: DrainDeadlines::new(config.drain_policy),
+        retry_invalid_tool_calls: config.retry_invalid_tool_calls,
+        warmup,
+        diagnostics: Diagnostics::new(Duration::from_secs(config.diagnose_interval_secs)),
+        reprober: Reprober::default(),
+        public_status_fields: config.public_status_fields.clone(),
+        ui_public: config.ui_public,
+        workload,
+        interleave,
+        slow_lane,
+        model_queues: ModelQueues::default(),
+        user_affinity: UserAffinity::new(config.user_affinity, config.user_affinity_header.clone(), config.user_affinity_fallback),
+        benchmarks,
+        pins,
+        tombstones: Tombstones::new(Duration::from_secs(config.tombstone_retention_secs), config.restore_node_stats, limits.tombstones),
+        latency,
+        recent_requests: Ring::new(limits.recent_requests),
+        recent_events: Ring::new(limits.recent_events),
+    };
     info!("Estado de la aplicación creado.");
+    Ok(app_state)
+}
+
+pub async fn run_balancer(config: BalancerConfig) -> std::io::Result<()> {
+    run_balancer_until(config, CancellationToken::new()).await
+}
+
+/// Como `run_balancer`, pero además se para al cancelar `stop`: el servidor HTTP deja de
+/// aceptar conexiones, termina las que tiene y se paran las tareas en segundo plano.
+pub(crate) async fn run_balancer_until(config: BalancerConfig, stop: CancellationToken) -> std::io::Result<()> {
+    let listen_addr = config.listen_addr.as_str();
+
+    // Un hueco en la documentación no impide arrancar: lo para `load_balancer openapi --check` en CI.
+    if let Err(e) = openapi::check() {
+        warn!("OpenAPI: El documento de /openapi.json no cubre la API:\n{}", e);
+    }
 
-    info!("Iniciando listener UDP...");
-    let udp_listener_state = app_state.clone();
-    let udp_addr_owned = udp_addr.to_string();
-    tokio::spawn(async move {
-        if let Err(e) = udp_discovery_listener(udp_addr_owned, udp_listener_state).await {
-            error!("CRITICAL: Error en el listener UDP: {}. El descubrimiento de nodos se ha detenido.", e);
+    let persistence = match Store::open(config.persistence, config.state_dir.as_deref(), config.audit_file.as_deref()) {
+        Ok(Some(store)) => {
+            info!("Persistencia habilitada: {}", store.describe());
+            Some(store)
         }
-    });
-    info!("Listener UDP iniciado en segundo plano.");
+        Ok(None) if config.require_audit => {
+            error!("--require-audit está activo pero no se configuró --state-dir ni --audit-file. Abortando.");
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "--require-audit exige --state-dir o --audit-file"));
+        }
+        Ok(None) => None,
+        // Sólo el almacén json, el de siempre, arranca sin persistencia si no se puede abrir.
+        Err(e) if config.require_audit || config.persistence != PersistenceBackend::Json => {
+            error!("No se pudo abrir el almacén de persistencia: {}. Abortando.", e);
+            return Err(io::Error::other(e));
+        }
+        Err(e) => {
+            warn!("No se pudo abrir el almacén de persistencia: {}. Se continúa sin auditoría.", e);
+            None
+        }
+    };
+
+    #[cfg(feature = "tls")]
+    let tls_config = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => {
+            Some(crate::tls::load_server_config(cert, key).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?)
+        }
+        _ => None,
+    };
+
+    let local_node = config.local_node.as_deref().map(ServiceUrl::parse).transpose().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let app_state = web::Data::new(build_state(&config, persistence)?);
+    let mut tasks = BackgroundTasks::default();
+    events::spawn_consumers(&mut tasks, &app_state);
+    tasks.spawn("warmup", warmup::run(app_state.clone()));
+    tasks.spawn("registry_snapshot", persistence::run(app_state.clone()));
+
+    info!("Iniciando listeners UDP...");
+    let accept_legacy = !config.disable_legacy_discovery;
+    for listener in app_state.discovery_listeners.iter().cloned() {
+        let udp_listener_state = app_state.clone();
+        tasks.spawn("udp_listener", async move {
+            let bind_addr = listener.bind_addr.clone();
+            if let Err(e) = udp_discovery_listener(listener, udp_listener_state, accept_legacy).await {
+                error!("CRITICAL: Error en el listener UDP {}: {}. El descubrimiento por este listener se ha detenido.", bind_addr, e);
+            }
+        });
+    }
+    info!("{} listener(s) UDP iniciado(s) en segundo plano.", app_state.discovery_listeners.len());
+
+    #[cfg(feature = "mdns")]
+    {
+        info!("Iniciando navegador mDNS...");
+        let mdns_state = app_state.clone();
+        tasks.spawn("mdns", crate::mdns::browse_nodes(mdns_state));
+    }
 
     info!("Iniciando UI de terminal...");
     let ui_state = app_state.clone();
-    tokio::spawn(async move {
-        terminal_ui(ui_state).await;
-    });
+    tasks.spawn("terminal_ui", ui::run(ui_state));
     info!("UI de terminal iniciada en segundo plano.");
 
+    let mut has_static_nodes = false;
+    if let Some(local_url) = local_node {
+        let service = config.local_node_service.as_str();
+        let registered = PoolName::new(service)
+            .and_then(|pool| Ok((NodeId::new(format!("local-{}", pool))?, pool)))
+            .map_err(|e| e.to_string())
+            .and_then(|(local_id, pool)| app_state.register_static_node(&pool, &local_id, local_url, NodeSource::Local).map_err(|e| e.to_string()));
+        if let Err(e) = registered {
+            tasks.shutdown(TASK_SHUTDOWN_TIMEOUT).await;
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("No se pudo registrar --local-node para '{}': {}", service, e)));
+        }
+        has_static_nodes = true;
+    }
+    if config.auto_local {
+        info!("Buscando backends locales (--auto-local)...");
+        let ports = [(&LMSTUDIO, config.auto_local_lmstudio_port), (&OLLAMA, config.auto_local_ollama_port)];
+        has_static_nodes |= crate::health::register_auto_local_nodes(&app_state, &ports).await > 0;
+    }
+    if has_static_nodes {
+        info!("Iniciando health checks de nodos estáticos...");
+        let health_state = app_state.clone();
+        tasks.spawn("health_checks", crate::health::health_check_static_nodes(health_state));
+    }
+
+    if config.rules_file.is_some() {
+        tasks.spawn("rules_reload", rules::watch(app_state.clone()));
+    }
+
+    if config.profiles_file.is_some() {
+        let scheduler_state = app_state.clone();
+        tasks.spawn("profile_scheduler", profiles::run_scheduler(scheduler_state));
+    }
+
+    tasks.spawn("fairness_watch", fairness::watch(app_state.clone()));
+    tasks.spawn("capacity_snapshot", capacity::refresh(app_state.clone()));
+    tasks.spawn("memory_limits", limits::watch(app_state.clone()));
+    tasks.spawn("clock_watch", clock::watch(app_state.clone()));
+    if app_state.benchmarks.is_enabled() {
+        tasks.spawn("benchmark", benchmark::run(app_state.clone()));
+    }
+    #[cfg(feature = "grpc")]
+    if let Some(grpc_addr) = config.grpc_listen {
+        tasks.spawn("grpc", crate::grpc::serve(app_state.clone(), grpc_addr));
+    }
+
     info!("Iniciando tarea de limpieza de nodos inactivos...");
     let cleanup_state = app_state.clone();
     let node_inactivity_timeout = Duration::from_secs(35);
     let cleanup_interval = Duration::from_secs(30);
 
-    tokio::spawn(async move {
+    tasks.spawn("cleanup", async move {
         info!("Tarea de limpieza iniciada. Intervalo: {:?}, Timeout inactividad: {:?}",
                cleanup_interval, node_inactivity_timeout);
         loop {
@@ -406,7 +3602,7 @@ pub async fn run_balancer(listen_addr: &str, udp_addr: &str) -> std::io::Result<
 
             match cleanup_state.lm_studio_nodes.write() {
                  Ok(mut nodes_guard) => {
-                    remove_stale_nodes(&mut nodes_guard, node_inactivity_timeout, "LM Studio");
+                    remove_stale_nodes(&mut nodes_guard, &cleanup_state, node_inactivity_timeout, "LM Studio", &LMSTUDIO);
                  }
                  Err(e) => {
                     error!("Cleanup Task: Error al obtener write lock para LM Studio nodes: {}", e);
@@ -416,28 +3612,149 @@ pub async fn run_balancer(listen_addr: &str, udp_addr: &str) -> std::io::Result<
 
              match cleanup_state.ollama_nodes.write() {
                  Ok(mut nodes_guard) => {
-                     remove_stale_nodes(&mut nodes_guard, node_inactivity_timeout, "Ollama");
+                     remove_stale_nodes(&mut nodes_guard, &cleanup_state, node_inactivity_timeout, "Ollama", &OLLAMA);
                  }
                  Err(e) => {
                     error!("Cleanup Task: Error al obtener write lock para Ollama nodes: {}", e);
                  }
             }
 
+            reconcile::sweep(&cleanup_state);
+            cleanup_state.tombstones.purge();
+            cleanup_state.sessions.purge();
             debug!("Cleanup Task: Limpieza completada.");
         }
     });
 
 
-    info!("Iniciando servidor HTTP del balanceador en {}", listen_addr);
+    let shutdown_state = app_state.clone();
     info!("UI en Terminal activa. Presiona Ctrl+C para detener.");
-    HttpServer::new(move || {
+
+    // Los listeners UDP se anuncian por mDNS para los nodos arrancados sin -i.
+    #[cfg(feature = "mdns")]
+    let mdns_advertiser = crate::mdns::MdnsAdvertiser::start_balancer(&app_state.discovery_listeners);
+    let server = HttpServer::new(move || {
         trace!("Configurando nueva instancia de Actix App...");
-        App::new()
-            .app_data(app_state.clone())
-            .service(lm_studio_handler)
-            .service(ollama_handler)
+        app(app_state.clone())
     })
-    .bind(listen_addr)?
-    .run()
-    .await
-}
\ No newline at end of file
+    .on_connect(cancel::on_connect);
+
+    #[cfg(feature = "tls")]
+    let bound = match tls_config {
+        Some(tls_config) => {
+            info!("Iniciando servidor HTTPS (HTTP/1.1 y HTTP/2) del balanceador en {}", listen_addr);
+            server.bind_rustls_0_23(listen_addr, tls_config)
+        }
+        None => {
+            info!("Iniciando servidor HTTP del balanceador en {}", listen_addr);
+            server.bind(listen_addr)
+        }
+    };
+    #[cfg(not(feature = "tls"))]
+    let bound = {
+        info!("Iniciando servidor HTTP del balanceador en {}", listen_addr);
+        server.bind(listen_addr)
+    };
+    let result = match bound {
+        Ok(server) => {
+            let mut server = server.run();
+            let handle = server.handle();
+            tokio::select! {
+                result = &mut server => result,
+                _ = stop.cancelled() => {
+                    info!("Parada solicitada: se cierra el servidor HTTP del balanceador.");
+                    // La parada la atiende el propio servidor: hay que seguir esperándolo a la vez.
+                    let (result, ()) = tokio::join!(server, handle.stop(true));
+                    result
+                }
+            }
+        }
+        Err(e) => Err(e),
+    };
+
+    // El servidor HTTP ya terminó (o no llegó a arrancar): se paran las tareas en segundo plano.
+    #[cfg(feature = "mdns")]
+    if let Some(advertiser) = mdns_advertiser {
+        advertiser.shutdown();
+    }
+    tasks.shutdown(TASK_SHUTDOWN_TIMEOUT).await;
+    persistence::save_if_changed(&shutdown_state, &mut 0);
+    result
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use serde_json::json;
+
+    use crate::testing;
+
+    fn chat() -> serde_json::Value {
+        json!({ "model": "m", "messages": [{ "role": "user", "content": "hola" }] })
+    }
+
+    /// Sin nodos, el balanceador responde 503 en vez de reenviarse la petición a sí mismo.
+    #[actix_web::test]
+    async fn fresh_balancer_without_nodes_answers_503() {
+        let state = testing::state_with_queue_timeout(0, &[]);
+        assert!(state.pools().iter().all(|(_, _, lock)| lock.read().unwrap().is_empty()));
+        let app = init_service(app(state.clone())).await;
+
+        for pool in ["/lmstudio", "/ollama"] {
+            let resp = call_service(&app, TestRequest::post().uri(pool).set_json(chat()).to_request()).await;
+            assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE, "{}", pool);
+            let body = read_body(resp).await;
+            assert!(String::from_utf8_lossy(&body).starts_with("No hay nodos"), "{}", pool);
+        }
+        assert!(state.pools().iter().all(|(_, _, lock)| lock.read().unwrap().is_empty()));
+    }
+
+    /// Lo que contesta `GET /` en `addr`, por una conexión propia que se cierra al terminar.
+    async fn get_index(addr: &str) -> Option<String> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let mut stream = tokio::net::TcpStream::connect(addr).await.ok()?;
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n").await.ok()?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.ok()?;
+        Some(response)
+    }
+
+    /// Arrancar y parar el balanceador veinte veces en el mismo proceso no deja puertos
+    /// ocupados ni tareas vivas.
+    #[actix_web::test]
+    async fn stops_cleanly_many_times_in_one_process() {
+        let alive = || tokio::runtime::Handle::current().metrics().num_alive_tasks();
+        let baseline = alive();
+        for round in 0..20 {
+            let http = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+            let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+            let config = testing::config(&["-l", &http, "-u", &udp]);
+            let stop = CancellationToken::new();
+            let running = actix_web::rt::spawn(run_balancer_until(config, stop.clone()));
+
+            let mut index = None;
+            for _ in 0..200 {
+                index = get_index(&http).await;
+                if index.is_some() {
+                    break;
+                }
+                sleep(Duration::from_millis(10)).await;
+            }
+            let index = index.unwrap_or_else(|| panic!("ronda {}: el balanceador no contestó en {}", round, http));
+            assert!(index.starts_with("HTTP/1.1 200"), "ronda {}: {}", round, index);
+            assert!(std::net::UdpSocket::bind(&udp).is_err(), "ronda {}: el listener UDP no estaba escuchando", round);
+
+            stop.cancel();
+            tokio::time::timeout(Duration::from_secs(10), running).await
+                .unwrap_or_else(|_| panic!("ronda {}: el balanceador no se paró", round))
+                .unwrap()
+                .unwrap();
+
+            // Los puertos quedan libres y las tareas en segundo plano, terminadas.
+            std::net::TcpListener::bind(&http).unwrap_or_else(|e| panic!("ronda {}: {} sigue ocupado: {}", round, http, e));
+            std::net::UdpSocket::bind(&udp).unwrap_or_else(|e| panic!("ronda {}: {} sigue ocupado: {}", round, udp, e));
+            testing::eventually(|| alive() <= baseline).await;
+        }
+        assert_eq!(alive(), baseline);
+    }
+}
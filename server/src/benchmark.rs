@@ -0,0 +1,416 @@
+// src/benchmark.rs
+//! Prueba de rendimiento periódica de los nodos (`--benchmark-at HH:MM`).
+//!
+//! Cada día a esa hora (local), el balanceador pasa un juego fijo de prompts cortos por cada nodo
+//! libre, uno detrás de otro, y guarda la latencia mediana y los tokens por segundo como una
+//! serie en la persistencia (`benchmarks.ndjson` o la tabla `benchmarks`). Cada resultado se
+//! compara con el anterior del mismo nodo y modelo: si la latencia sube o los tokens por segundo
+//! bajan más de `--benchmark-regression-pct`, se publica `benchmark_regression`. Así se ve el
+//! nodo que va un 40% más lento tras tocar la BIOS sin esperar a que se quejen los usuarios.
+//!
+//! La prueba no ocupa el nodo: sigue libre y cede en cuanto el balanceador lo elige para una
+//! petición de verdad, que aborta la prueba (sin resultado) en el acto. Un nodo se salta con
+//! `PATCH /nodes/{id}/benchmark {"skip": true}` hasta que se revierta o se reinicie el
+//! balanceador. La serie está en `GET /nodes/{id}/benchmarks` y el último resultado, en la
+//! columna `Bench` de la UI.
+use actix_web::{get, patch, web, HttpResponse, Responder};
+use chrono::{DateTime, Duration as ChronoDuration, Local, NaiveTime, TimeZone, Utc};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+
+use crate::balancer::{AppState, NodeHealth};
+use crate::events::BalancerEvent;
+use crate::ids::ServiceUrl;
+use crate::usage;
+
+pub const DEFAULT_BENCHMARK_REGRESSION_PCT: f64 = 25.0;
+
+/// Prompts de la prueba; fijos para que los resultados de distintos días sean comparables.
+const PROMPTS: [&str; 3] = [
+    "Resume en una frase qué es un balanceador de carga.",
+    "Escribe una función en Python que sume los números de una lista.",
+    "Enumera cinco capitales europeas, una por línea.",
+];
+const MAX_TOKENS: u64 = 64;
+const PROMPT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Resultados que devuelve `GET /nodes/{id}/benchmarks`.
+const HISTORY: usize = 30;
+
+#[derive(Debug)]
+pub struct BenchmarkConfigError(String);
+
+impl fmt::Display for BenchmarkConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for BenchmarkConfigError {}
+
+/// Resultado de una prueba completa de un nodo.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BenchmarkRun {
+    pub node_id: String,
+    pub service: String,
+    pub model: String,
+    pub started_at: DateTime<Utc>,
+    pub prompts: usize,
+    /// Mediana de la duración de cada prompt.
+    pub latency_ms: u64,
+    /// Tokens generados por segundo de petición; `None` si el backend no los cuenta.
+    pub tokens_per_sec: Option<f64>,
+    /// Empeora más del umbral frente al resultado anterior del nodo con el mismo modelo.
+    pub regressed: bool,
+}
+
+pub struct Benchmarks {
+    at: Option<NaiveTime>,
+    regression_pct: f64,
+    client: reqwest::Client,
+    skipped: Mutex<HashSet<String>>,
+    /// Nodos en prueba, con el aviso que la aborta.
+    running: Mutex<HashMap<String, CancellationToken>>,
+    /// Último resultado de cada nodo en esta ejecución, para la UI y como base de la siguiente.
+    latest: Mutex<HashMap<String, BenchmarkRun>>,
+}
+
+impl Benchmarks {
+    pub fn new(at: Option<&str>, regression_pct: f64) -> Result<Self, BenchmarkConfigError> {
+        let at = at
+            .map(|at| NaiveTime::parse_from_str(at.trim(), "%H:%M"))
+            .transpose()
+            .map_err(|_| BenchmarkConfigError(format!("--benchmark-at inválido '{}': se esperaba HH:MM", at.unwrap_or_default())))?;
+        if regression_pct.is_nan() || regression_pct <= 0.0 {
+            return Err(BenchmarkConfigError(format!("--benchmark-regression-pct debe ser mayor que 0, no {}", regression_pct)));
+        }
+        let client = reqwest::Client::builder().timeout(PROMPT_TIMEOUT).build().expect("cliente HTTP de las pruebas de rendimiento");
+        Ok(Self {
+            at,
+            regression_pct,
+            client,
+            skipped: Mutex::default(),
+            running: Mutex::default(),
+            latest: Mutex::default(),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.at.is_some()
+    }
+
+    /// El nodo se ha elegido para una petición: si está en prueba, la prueba cede.
+    pub fn yield_node(&self, unique_node_id: &str) {
+        if let Some(token) = self.running.lock().unwrap().get(unique_node_id) {
+            token.cancel();
+        }
+    }
+
+    pub fn is_skipped(&self, unique_node_id: &str) -> bool {
+        self.skipped.lock().unwrap().contains(unique_node_id)
+    }
+
+    fn set_skipped(&self, unique_node_id: &str, skip: bool) {
+        let mut skipped = self.skipped.lock().unwrap();
+        if skip {
+            skipped.insert(unique_node_id.to_string());
+        } else {
+            skipped.remove(unique_node_id);
+        }
+    }
+
+    pub fn latest(&self, unique_node_id: &str) -> Option<BenchmarkRun> {
+        self.latest.lock().unwrap().get(unique_node_id).cloned()
+    }
+
+    /// Configuración para `/config`.
+    pub fn describe(&self) -> Value {
+        let mut skipped: Vec<String> = self.skipped.lock().unwrap().iter().cloned().collect();
+        skipped.sort();
+        json!({
+            "at": self.at.map(|at| at.format("%H:%M").to_string()),
+            "regression_pct": self.regression_pct,
+            "skipped": skipped,
+        })
+    }
+}
+
+/// Lo que sale de la prueba de un nodo.
+enum Outcome {
+    Done(BenchmarkRun),
+    /// El nodo se eligió para una petición.
+    Yielded,
+    Failed(String),
+}
+
+/// Nodo libre que toca probar.
+struct Candidate {
+    service: &'static str,
+    unique_node_id: String,
+    service_url: ServiceUrl,
+    model: String,
+}
+
+/// Nodos libres, sin peticiones en curso y con algún modelo anunciado, que no se saltan.
+fn candidates(state: &AppState) -> Vec<Candidate> {
+    let mut candidates = Vec::new();
+    for (_, service, lock) in state.pools() {
+        for (unique_id, info) in lock.read().unwrap().iter() {
+            if !matches!(info.state, NodeHealth::Available) || info.in_flight() > 0 || state.benchmarks.is_skipped(unique_id) {
+                continue;
+            }
+            let Some(model) = info.models.first() else {
+                continue;
+            };
+            candidates.push(Candidate {
+                service,
+                unique_node_id: unique_id.to_string(),
+                service_url: info.service_url.clone(),
+                model: model.clone(),
+            });
+        }
+    }
+    candidates.sort_by(|a, b| (a.service, &a.unique_node_id).cmp(&(b.service, &b.unique_node_id)));
+    candidates
+}
+
+/// Mientras vive, el nodo cuenta como en prueba.
+struct Running<'a> {
+    benchmarks: &'a Benchmarks,
+    unique_node_id: &'a str,
+}
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.benchmarks.running.lock().unwrap().remove(self.unique_node_id);
+    }
+}
+
+/// Petición de un prompt, con el tope en los dos dialectos (OpenAI y Ollama nativo), como el diagnóstico.
+fn prompt_request(model: &str, prompt: &str) -> Value {
+    json!({
+        "model": model,
+        "messages": [{ "role": "user", "content": prompt }],
+        "max_tokens": MAX_TOKENS,
+        "temperature": 0,
+        "options": { "num_predict": MAX_TOKENS, "temperature": 0 },
+        "stream": false,
+    })
+}
+
+/// Un prompt: duración y tokens generados.
+async fn run_prompt(client: &reqwest::Client, service_url: &ServiceUrl, model: &str, prompt: &str) -> Result<(Duration, Option<u64>), String> {
+    let started = Instant::now();
+    let response = client.post(service_url.as_str()).json(&prompt_request(model, prompt)).send().await.map_err(|e| e.to_string())?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(format!("HTTP {}", status.as_u16()));
+    }
+    let body: Value = response.json().await.map_err(|e| e.to_string())?;
+    let (_, completion_tokens, _) = usage::json_usage(&body);
+    Ok((started.elapsed(), completion_tokens))
+}
+
+async fn benchmark_node(state: &AppState, candidate: &Candidate) -> Outcome {
+    let benchmarks = &state.benchmarks;
+    let token = CancellationToken::new();
+    benchmarks.running.lock().unwrap().insert(candidate.unique_node_id.clone(), token.clone());
+    let _running = Running { benchmarks, unique_node_id: &candidate.unique_node_id };
+    let started_at = Utc::now();
+
+    let mut durations = Vec::with_capacity(PROMPTS.len());
+    let mut tokens = Some(0);
+    for prompt in PROMPTS {
+        let result = tokio::select! {
+            result = run_prompt(&benchmarks.client, &candidate.service_url, &candidate.model, prompt) => result,
+            _ = token.cancelled() => return Outcome::Yielded,
+        };
+        match result {
+            Ok((duration, generated)) => {
+                durations.push(duration);
+                tokens = tokens.zip(generated).map(|(total, generated)| total + generated);
+            }
+            Err(e) => return Outcome::Failed(e),
+        }
+    }
+
+    let total_secs: f64 = durations.iter().map(Duration::as_secs_f64).sum();
+    durations.sort();
+    let run = BenchmarkRun {
+        node_id: candidate.unique_node_id.clone(),
+        service: candidate.service.to_string(),
+        model: candidate.model.clone(),
+        started_at,
+        prompts: PROMPTS.len(),
+        latency_ms: durations[durations.len() / 2].as_millis() as u64,
+        tokens_per_sec: tokens.filter(|_| total_secs > 0.0).map(|tokens| (tokens as f64 / total_secs * 10.0).round() / 10.0),
+        regressed: false,
+    };
+    Outcome::Done(run)
+}
+
+/// Resultado anterior del nodo con el mismo modelo: el de esta ejecución o el último guardado.
+fn baseline(state: &AppState, run: &BenchmarkRun) -> Option<BenchmarkRun> {
+    let same_model = |previous: &BenchmarkRun| previous.service == run.service && previous.model == run.model;
+    if let Some(latest) = state.benchmarks.latest(&run.node_id).filter(same_model) {
+        return Some(latest);
+    }
+    let stored = state.persistence.as_ref()?.benchmarks(&run.node_id, HISTORY);
+    stored.into_iter().rev().find(same_model)
+}
+
+/// Compara con el resultado anterior y avisa de cada métrica que empeora más del umbral.
+fn compare(state: &AppState, run: &mut BenchmarkRun) {
+    let Some(previous) = baseline(state, run) else {
+        return;
+    };
+    // Cambio en %, con signo tal que positivo es peor.
+    let mut changes = vec![("latency_ms", previous.latency_ms as f64, run.latency_ms as f64, 1.0)];
+    if let (Some(before), Some(now)) = (previous.tokens_per_sec, run.tokens_per_sec) {
+        changes.push(("tokens_per_sec", before, now, -1.0));
+    }
+    for (metric, before, now, sign) in changes {
+        if before <= 0.0 {
+            continue;
+        }
+        let change_pct = sign * (now - before) / before * 100.0;
+        if change_pct > state.benchmarks.regression_pct {
+            run.regressed = true;
+            state.events.publish(BalancerEvent::BenchmarkRegression {
+                service: run.service.clone(),
+                node_id: run.node_id.clone(),
+                model: run.model.clone(),
+                metric,
+                baseline: before,
+                current: now,
+                change_pct: (change_pct * 10.0).round() / 10.0,
+            });
+        }
+    }
+}
+
+/// Prueba uno a uno los nodos libres.
+pub async fn run_all(state: &AppState) {
+    let candidates = candidates(state);
+    info!("Benchmark: Probando {} nodos libres.", candidates.len());
+    for candidate in candidates {
+        // Puede haberse ocupado mientras se probaba el anterior.
+        let idle = state
+            .pool(candidate.service)
+            .and_then(|lock| lock.read().unwrap().get(candidate.unique_node_id.as_str()).map(|info| matches!(info.state, NodeHealth::Available) && info.in_flight() == 0));
+        if idle != Some(true) {
+            info!("Benchmark: El nodo {} ya no está libre; se salta.", candidate.unique_node_id);
+            continue;
+        }
+        match benchmark_node(state, &candidate).await {
+            Outcome::Done(mut run) => {
+                compare(state, &mut run);
+                info!(
+                    "Benchmark: Nodo {} ({}, {}): {}ms de mediana, {} t/s{}.",
+                    run.node_id,
+                    run.service,
+                    run.model,
+                    run.latency_ms,
+                    run.tokens_per_sec.map_or_else(|| "?".to_string(), |tps| tps.to_string()),
+                    if run.regressed { ", PEOR que el anterior" } else { "" }
+                );
+                if let Some(store) = &state.persistence {
+                    store.record_benchmark(&run);
+                }
+                state.benchmarks.latest.lock().unwrap().insert(run.node_id.clone(), run);
+            }
+            Outcome::Yielded => info!("Benchmark: El nodo {} se eligió para una petición; prueba abortada.", candidate.unique_node_id),
+            Outcome::Failed(e) => warn!("Benchmark: La prueba del nodo {} falló: {}", candidate.unique_node_id, e),
+        }
+    }
+}
+
+/// Tiempo hasta la próxima `at` local.
+fn until_next(at: NaiveTime, now: DateTime<Local>) -> Duration {
+    let today = now.date_naive().and_time(at);
+    let next = if today > now.naive_local() { today } else { today + ChronoDuration::days(1) };
+    Local
+        .from_local_datetime(&next)
+        .earliest()
+        .and_then(|next| (next - now).to_std().ok())
+        .unwrap_or(Duration::from_secs(24 * 3600))
+}
+
+/// Lanza la prueba cada día a la hora de `--benchmark-at`.
+pub async fn run(state: web::Data<AppState>) {
+    let Some(at) = state.benchmarks.at else {
+        return;
+    };
+    loop {
+        let wait = until_next(at, Local::now());
+        info!("Benchmark: Próxima prueba de rendimiento en {}s.", wait.as_secs());
+        sleep(wait).await;
+        run_all(&state).await;
+    }
+}
+
+/// Si algún registro tiene el nodo.
+fn known(state: &AppState, unique_node_id: &str) -> bool {
+    state.pools().into_iter().any(|(_, _, lock)| lock.read().unwrap().contains_key(unique_node_id))
+}
+
+/// Serie de resultados del nodo, del más antiguo al más reciente.
+#[get("/nodes/{id}/benchmarks")]
+async fn benchmarks_handler(state: web::Data<AppState>, path: web::Path<String>) -> impl Responder {
+    let unique_node_id = path.into_inner();
+    let latest = state.benchmarks.latest(&unique_node_id);
+    if !known(&state, &unique_node_id) && latest.is_none() {
+        return HttpResponse::NotFound().json(json!({ "error": format!("Nodo {} desconocido", unique_node_id) }));
+    }
+    let id = unique_node_id.clone();
+    let stored = web::block({
+        let state = state.clone();
+        move || state.persistence.as_ref().map(|store| store.benchmarks(&id, HISTORY))
+    })
+    .await;
+    let runs = match stored {
+        Ok(Some(runs)) => runs,
+        Ok(None) => latest.into_iter().collect(),
+        Err(e) => {
+            error!("Benchmark: No se pudo leer la serie del nodo {}: {}", unique_node_id, e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    HttpResponse::Ok().json(json!({
+        "node_id": unique_node_id,
+        "skip": state.benchmarks.is_skipped(&unique_node_id),
+        "running": state.benchmarks.running.lock().unwrap().contains_key(&unique_node_id),
+        "runs": runs,
+    }))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BenchmarkSettings {
+    skip: bool,
+}
+
+/// Salta (o vuelve a probar) un nodo en las pruebas de rendimiento.
+#[patch("/nodes/{id}/benchmark")]
+async fn benchmark_settings_handler(state: web::Data<AppState>, path: web::Path<String>, body: web::Json<BenchmarkSettings>) -> impl Responder {
+    let unique_node_id = path.into_inner();
+    if !known(&state, &unique_node_id) {
+        return HttpResponse::NotFound().json(json!({ "error": format!("Nodo {} desconocido", unique_node_id) }));
+    }
+    let skip = body.skip;
+    state.benchmarks.set_skipped(&unique_node_id, skip);
+    if skip {
+        info!("Benchmark: El nodo {} no se probará.", unique_node_id);
+        state.benchmarks.yield_node(&unique_node_id);
+    } else {
+        info!("Benchmark: El nodo {} vuelve a probarse.", unique_node_id);
+    }
+    HttpResponse::Ok().json(json!({ "node_id": unique_node_id, "skip": skip }))
+}
@@ -0,0 +1,32 @@
+// src/build_info.rs
+//! Versión e información de compilación incrustadas por `build.rs`.
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_HASH: &str = env!("LMSERVER_GIT_HASH");
+pub const BUILD_TIMESTAMP: &str = env!("LMSERVER_BUILD_TIMESTAMP");
+pub const FEATURES: &str = env!("LMSERVER_FEATURES");
+
+/// Texto de `--version`.
+pub const LONG_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    "\ncommit: ",
+    env!("LMSERVER_GIT_HASH"),
+    "\ncompilado: ",
+    env!("LMSERVER_BUILD_TIMESTAMP"),
+    "\nfeatures: ",
+    env!("LMSERVER_FEATURES"),
+);
+
+/// Versión completa en una línea, para banners y anuncios de descubrimiento.
+pub fn summary() -> String {
+    format!("{} ({})", VERSION, GIT_HASH)
+}
+
+pub fn to_json() -> serde_json::Value {
+    serde_json::json!({
+        "version": VERSION,
+        "git_commit": GIT_HASH,
+        "build_timestamp": BUILD_TIMESTAMP,
+        "features": FEATURES.split(',').filter(|f| *f != "none").collect::<Vec<_>>(),
+    })
+}
@@ -0,0 +1,226 @@
+// src/canary.rs
+//! Fase canaria de los nodos recién llegados (`--canary-secs`, `--canary-requests`).
+//!
+//! Un nodo mal configurado que se anuncia recibe enseguida todo el tráfico y falla muchas
+//! peticiones antes de que nadie lo note. Con la fase activada, un nodo que entra en el registro
+//! por un anuncio (el primero o el que sigue a haber salido de él) sólo se lleva
+//! `--canary-percent` de las peticiones que podría atender. La fase dura `--canary-secs` o las
+//! primeras `--canary-requests` peticiones, lo que llegue antes si hay los dos. Al terminar se
+//! juzga con su tasa de errores (respuestas 5xx y fallos al reenviar):
+//!
+//! - por debajo o igual a `--canary-max-error-rate`, el nodo pasa a recibir tráfico completo;
+//! - por encima, se marca Failed y empieza otra fase canaria, así que vuelve con tráfico
+//!   reducido cuando se anuncie de nuevo.
+//!
+//! Se juzga al terminar cada petición del nodo; sin ninguna no hay nada que juzgar y la fase
+//! sigue hasta la primera. Mientras no haya en la pool un nodo fuera de la fase que pueda
+//! atender la petición, los canarios la atienden sin reparto (tras reiniciar el balanceador
+//! todos vuelven a empezar). Tampoco se reparte una petición dirigida a un nodo concreto ni una
+//! reserva de `X-Pipeline`, aunque cuentan para el juicio. Los nodos estáticos no pasan por la
+//! fase.
+use serde_json::{json, Value};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::api_types::CanaryStatus;
+
+pub const DEFAULT_CANARY_PERCENT: u32 = 10;
+pub const DEFAULT_CANARY_MAX_ERROR_RATE: f64 = 0.2;
+
+#[derive(Debug)]
+pub struct CanaryConfigError(String);
+
+impl fmt::Display for CanaryConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for CanaryConfigError {}
+
+/// Fase canaria de un nodo. Vive en su `NodeInfo`, así que se consulta y anota con el lock de
+/// la pool.
+#[derive(Clone, Debug)]
+pub struct CanaryState {
+    started_at: Instant,
+    requests: u32,
+    errors: u32,
+    /// Reparto acumulado, en centésimas de petición: cada vez que el nodo podría atender una
+    /// suma el porcentaje y la atiende al llegar a 100.
+    credit: u32,
+}
+
+/// Cómo terminó la fase canaria de un nodo.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CanaryVerdict {
+    Promoted,
+    Failed,
+}
+
+impl CanaryVerdict {
+    pub fn label(self) -> &'static str {
+        match self {
+            CanaryVerdict::Promoted => "promoted",
+            CanaryVerdict::Failed => "failed",
+        }
+    }
+}
+
+/// Configuración de la fase canaria.
+pub struct CanaryPolicy {
+    duration: Option<Duration>,
+    requests: Option<u32>,
+    percent: u32,
+    max_error_rate: f64,
+}
+
+impl CanaryPolicy {
+    /// `secs` y `requests` a 0 no limitan por su lado; los dos a 0 desactivan la fase.
+    pub fn new(secs: u64, requests: u32, percent: u32, max_error_rate: f64) -> Result<Self, CanaryConfigError> {
+        if !(1..=100).contains(&percent) {
+            return Err(CanaryConfigError(format!("--canary-percent inválido {}: debe ir de 1 a 100", percent)));
+        }
+        if !(0.0..=1.0).contains(&max_error_rate) {
+            return Err(CanaryConfigError(format!("--canary-max-error-rate inválido {}: debe ir de 0 a 1", max_error_rate)));
+        }
+        Ok(Self {
+            duration: (secs > 0).then(|| Duration::from_secs(secs)),
+            requests: (requests > 0).then_some(requests),
+            percent,
+            max_error_rate,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.duration.is_some() || self.requests.is_some()
+    }
+
+    /// Fase de un nodo que acaba de entrar en el registro; `None` sin fase configurada.
+    pub fn start(&self) -> Option<CanaryState> {
+        self.is_enabled().then(|| CanaryState { started_at: Instant::now(), requests: 0, errors: 0, credit: 0 })
+    }
+
+    /// Si al nodo le toca esta petición según el reparto. Si no le toca, acumula su parte; si
+    /// le toca, no gasta nada hasta que `dispatched` confirme que se la llevó: otra comprobación
+    /// posterior (el cubo de `--dispatch-rate`) aún puede descartarlo y no debe perder el turno.
+    pub fn admits(&self, canary: &mut CanaryState) -> bool {
+        if canary.credit + self.percent < 100 {
+            canary.credit += self.percent;
+            return false;
+        }
+        true
+    }
+
+    /// Cobra el turno de un nodo que `admits` dejó pasar y que se llevó la petición.
+    pub fn dispatched(&self, canary: &mut CanaryState) {
+        canary.credit = canary.credit + self.percent - 100;
+    }
+
+    /// Anota el resultado de una petición atendida por el nodo.
+    pub fn record(&self, canary: &mut CanaryState, ok: bool) {
+        canary.requests += 1;
+        if !ok {
+            canary.errors += 1;
+        }
+    }
+
+    /// Veredicto si la fase ya terminó; `None` mientras sigue.
+    pub fn verdict(&self, canary: &CanaryState, now: Instant) -> Option<CanaryVerdict> {
+        let elapsed = self.duration.is_some_and(|duration| now.saturating_duration_since(canary.started_at) >= duration);
+        let served = self.requests.is_some_and(|requests| canary.requests >= requests);
+        if canary.requests == 0 || !(elapsed || served) {
+            return None;
+        }
+        if error_rate(canary) <= self.max_error_rate {
+            Some(CanaryVerdict::Promoted)
+        } else {
+            Some(CanaryVerdict::Failed)
+        }
+    }
+
+    /// Estado de la fase para `/nodes` y la UI.
+    pub fn status(&self, canary: &CanaryState) -> CanaryStatus {
+        CanaryStatus { elapsed_secs: canary.started_at.elapsed().as_secs(), requests: canary.requests, errors: canary.errors }
+    }
+
+    /// Configuración para `/config`.
+    pub fn describe(&self) -> Value {
+        json!({
+            "enabled": self.is_enabled(),
+            "secs": self.duration.map(|duration| duration.as_secs()),
+            "requests": self.requests,
+            "percent": self.percent,
+            "max_error_rate": self.max_error_rate,
+        })
+    }
+}
+
+/// Fracción de peticiones fallidas en la fase.
+pub fn error_rate(canary: &CanaryState) -> f64 {
+    if canary.requests == 0 {
+        return 0.0;
+    }
+    canary.errors as f64 / canary.requests as f64
+}
+
+/// Peticiones y errores de la fase, para el log y los eventos.
+pub fn counts(canary: &CanaryState) -> (u32, u32) {
+    (canary.requests, canary.errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, TestRequest};
+
+    use crate::balancer;
+    use crate::sessions::NODE_ID_HEADER;
+    use crate::testing;
+
+    #[test]
+    fn a_turn_is_charged_only_when_the_node_takes_it() {
+        let policy = CanaryPolicy::new(0, 100, 50, DEFAULT_CANARY_MAX_ERROR_RATE).unwrap();
+        let mut canary = policy.start().unwrap();
+        assert!(!policy.admits(&mut canary));
+        // Le toca, pero otra comprobación lo descarta: el turno sigue siendo suyo.
+        assert!(policy.admits(&mut canary));
+        assert!(policy.admits(&mut canary));
+        policy.dispatched(&mut canary);
+        assert!(!policy.admits(&mut canary));
+        assert!(policy.admits(&mut canary));
+    }
+
+    /// Un canario con el cubo de `--dispatch-rate` vacío no pierde su turno: se lo lleva en
+    /// cuanto el cubo vuelve a tener ficha.
+    #[actix_web::test]
+    async fn an_empty_dispatch_bucket_does_not_cost_the_canary_its_turn() {
+        let state = testing::state(&["--node-selection", "first-available", "--canary-requests", "100", "--canary-percent", "50", "--admin-token", "admin"]);
+        let url = testing::chat_node(Duration::ZERO);
+        for id in ["a-canary", "b-stable"] {
+            testing::announce(&state, "lmstudio", id, &url);
+        }
+        state.pool("lmstudio").unwrap().write().unwrap().get_mut("b-stable").unwrap().canary = None;
+        let app = init_service(balancer::app(state.clone())).await;
+        let limit = TestRequest::patch()
+            .uri("/nodes/a-canary/dispatch-rate")
+            .insert_header(("Authorization", "Bearer admin"))
+            .set_json(json!({ "per_second": 2, "burst": 1 }))
+            .to_request();
+        assert_eq!(call_service(&app, limit).await.status(), 200);
+        let served_by = || async {
+            let res = call_service(&app, testing::chat().to_request()).await;
+            assert_eq!(res.status(), 200);
+            res.headers().get(NODE_ID_HEADER).unwrap().to_str().unwrap().to_string()
+        };
+
+        // Una de cada dos para el canario; la cuarta le toca con el cubo ya vacío.
+        let mut served = Vec::new();
+        for _ in 0..4 {
+            served.push(served_by().await);
+        }
+        assert_eq!(served, ["b-stable", "a-canary", "b-stable", "b-stable"]);
+
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert_eq!(served_by().await, "a-canary");
+    }
+}
@@ -0,0 +1,196 @@
+// src/cancel.rs
+//! Cancelación cooperativa: si el cliente se va, el nodo deja de trabajar para nadie.
+//!
+//! actix no suelta el handler cuando el cliente cierra la conexión: una petición abandonada
+//! seguía ocupando su nodo, y el backend generando, hasta el final. El contrato:
+//!
+//! 1. Cada petición de inferencia lleva un `X-Request-Id`, el del cliente si es válido o uno
+//!    nuevo, que se reenvía al nodo para que los dos lados registren el mismo id.
+//! 2. Mientras espera en la cola o al nodo, el balanceador mira cada `CHECK_INTERVAL` si el
+//!    cliente cerró la conexión. Si la cerró, abandona la petición: reqwest cierra la conexión
+//!    con el nodo en lugar de devolverla al pool, el nodo vuelve a estar disponible y la
+//!    cancelación se registra con el id. Los streams lo notan ellos solos cuando actix suelta el
+//!    cuerpo (`streaming::relay`).
+//! 3. Lo que haya delante del backend en el nodo debe hacer lo mismo al ver cerrada la conexión
+//!    del balanceador: soltar su petición al backend y registrar la cancelación con el mismo id.
+//!    El proxy de `--spool-listen` (`spool`) lo hace así.
+//!
+//! Para saber si el cliente sigue ahí se hace `peek` sobre una copia del descriptor de su
+//! socket, guardada al aceptar la conexión. Sólo funciona con HTTP sin TLS en Unix; en el resto
+//! de casos la petición sigue hasta el final, como antes.
+use actix_web::dev::Extensions;
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse};
+use std::any::Any;
+use std::time::Duration;
+use tokio::time::sleep;
+use uuid::Uuid;
+
+/// Cabecera con el id que comparten el balanceador y el nodo.
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Más largo que esto, el id del cliente se sustituye por uno nuevo.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Cada cuánto se mira si el cliente sigue conectado.
+pub const CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Estado con el que se registran las peticiones abandonadas (el 499 de nginx).
+pub const CLIENT_CLOSED_REQUEST: u16 = 499;
+
+/// Id de la petición: el del cliente si es ASCII visible y no muy largo, o uno nuevo.
+pub fn request_id(req: &HttpRequest) -> String {
+    req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic()))
+        .map_or_else(|| Uuid::new_v4().to_string(), str::to_string)
+}
+
+/// Respuesta de una petición abandonada; no la lee nadie.
+pub fn client_closed() -> HttpResponse {
+    HttpResponse::build(StatusCode::from_u16(CLIENT_CLOSED_REQUEST).expect("499 es un estado válido")).finish()
+}
+
+/// Copia del socket del cliente, en los datos de la conexión.
+#[cfg(unix)]
+struct ClientSocket(std::net::TcpStream);
+
+/// Para `HttpServer::on_connect`: guarda la copia del socket si la conexión es TCP sin TLS.
+#[cfg(unix)]
+pub fn on_connect(conn: &dyn Any, extensions: &mut Extensions) {
+    use std::os::fd::AsFd;
+
+    let Some(stream) = conn.downcast_ref::<actix_web::rt::net::TcpStream>() else {
+        return;
+    };
+    match stream.as_fd().try_clone_to_owned() {
+        // El descriptor comparte el modo no bloqueante del original: `peek` nunca espera.
+        Ok(fd) => {
+            extensions.insert(ClientSocket(std::net::TcpStream::from(fd)));
+        }
+        Err(e) => log::debug!("Cancelación: No se pudo duplicar el socket del cliente: {}", e),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn on_connect(_conn: &dyn Any, _extensions: &mut Extensions) {}
+
+/// Si el cliente ya cerró la conexión. `false` si no se puede saber.
+#[cfg(unix)]
+pub fn client_gone(req: &HttpRequest) -> bool {
+    use std::io::ErrorKind;
+
+    let Some(ClientSocket(socket)) = req.conn_data::<ClientSocket>() else {
+        return false;
+    };
+    let mut byte = [0u8; 1];
+    match socket.peek(&mut byte) {
+        Ok(0) => true,
+        Ok(_) => false,
+        Err(e) => !matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn client_gone(_req: &HttpRequest) -> bool {
+    false
+}
+
+/// Termina cuando el cliente cierra la conexión; nunca, si no se puede saber.
+pub async fn disconnected(req: &HttpRequest) {
+    #[cfg(unix)]
+    if req.conn_data::<ClientSocket>().is_some() {
+        loop {
+            sleep(CHECK_INTERVAL).await;
+            if client_gone(req) {
+                return;
+            }
+        }
+    }
+    std::future::pending().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use actix_web::test::TestRequest;
+    use serde_json::json;
+    use std::sync::atomic::Ordering;
+    use std::time::Instant;
+    use tokio::time::timeout;
+
+    fn id_of(value: &str) -> String {
+        request_id(&TestRequest::default().insert_header((REQUEST_ID_HEADER, value)).to_http_request())
+    }
+
+    #[test]
+    fn valid_client_ids_are_kept() {
+        assert_eq!(id_of("abc-123"), "abc-123");
+        assert_eq!(id_of("  con-espacios  "), "con-espacios");
+        assert_eq!(id_of(&"x".repeat(MAX_REQUEST_ID_LEN)), "x".repeat(MAX_REQUEST_ID_LEN));
+    }
+
+    #[test]
+    fn invalid_client_ids_are_replaced() {
+        for value in ["", "   ", "dos partes", &"x".repeat(MAX_REQUEST_ID_LEN + 1)] {
+            let id = id_of(value);
+            assert!(Uuid::parse_str(&id).is_ok(), "{:?} -> {:?}", value, id);
+        }
+        let missing = request_id(&TestRequest::default().to_http_request());
+        assert!(Uuid::parse_str(&missing).is_ok());
+        assert_ne!(missing, request_id(&TestRequest::default().to_http_request()));
+    }
+
+    /// Manda una petición de chat que el cliente abandona a los `after` con este id.
+    async fn abandoned_request(balancer: &str, id: &str, after: Duration) {
+        let sent = reqwest::Client::new()
+            .post(format!("{}lmstudio", balancer))
+            .header(REQUEST_ID_HEADER, id)
+            .json(&json!({ "messages": [{ "role": "user", "content": "hola" }] }))
+            .timeout(after)
+            .send()
+            .await;
+        assert!(sent.unwrap_err().is_timeout());
+    }
+
+    #[actix_web::test]
+    async fn client_disconnect_closes_the_node_connection() {
+        let state = testing::state(&[]);
+        let (node, mut closures) = testing::silent_backend().await;
+        testing::announce(&state, "lmstudio", "box0", &node);
+        let balancer = testing::serve(state.clone());
+
+        let started = Instant::now();
+        abandoned_request(&balancer, "corte-1", Duration::from_millis(300)).await;
+        let received = timeout(Duration::from_secs(2), closures.recv()).await.expect("el nodo no vio el corte").unwrap();
+        // Se detecta en la siguiente comprobación del socket del cliente.
+        assert!(started.elapsed() < Duration::from_millis(300) + CHECK_INTERVAL * 3, "{:?}", started.elapsed());
+        assert!(received.to_ascii_lowercase().contains("x-request-id: corte-1\r\n"), "{}", received);
+
+        testing::eventually(|| testing::node_state(&state, "lmstudio", "box0") == "available").await;
+        assert_eq!(state.metrics.cancelled_requests.load(Ordering::Relaxed), 1);
+    }
+
+    #[actix_web::test]
+    async fn queued_request_leaves_the_queue_with_its_client() {
+        let state = testing::state(&[]);
+        let (node, mut closures) = testing::silent_backend().await;
+        testing::announce(&state, "lmstudio", "box0", &node);
+        let balancer = testing::serve(state.clone());
+
+        // La primera ocupa el nodo; la segunda espera en la cola y se va antes.
+        let holder = {
+            let balancer = balancer.clone();
+            tokio::spawn(async move { abandoned_request(&balancer, "ocupa", Duration::from_secs(3)).await })
+        };
+        testing::eventually(|| testing::node_state(&state, "lmstudio", "box0") == "busy").await;
+        abandoned_request(&balancer, "en-cola", Duration::from_millis(300)).await;
+        testing::eventually(|| state.metrics.cancelled_requests.load(Ordering::Relaxed) == 1).await;
+        assert_eq!(testing::node_state(&state, "lmstudio", "box0"), "busy");
+        assert!(closures.try_recv().is_err());
+        holder.abort();
+    }
+}
@@ -0,0 +1,300 @@
+// src/capacity.rs
+//! Pistas de carga del clúster en las respuestas con éxito.
+//!
+//! Cada respuesta reenviada lleva cuántos nodos de la pool están libres y ocupados, cuántas
+//! peticiones esperan en su cola y una presión gruesa (`low|medium|high`) con la que el
+//! cliente puede frenar antes de recibir errores. Los valores salen de contadores atómicos
+//! que se recuentan al ocupar o liberar un nodo (con el lock del registro ya tomado) y, para
+//! el resto de cambios, cada poco en segundo plano; las cabeceras no tocan el registro.
+use actix_web::web;
+use actix_web::HttpResponseBuilder;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::time::interval;
+
+use crate::balancer::{AppState, NodeHealth, NodeInfo};
+use crate::ids::NodeId;
+
+pub const POOL_AVAILABLE_HEADER: &str = "X-LMServer-Pool-Available";
+pub const POOL_BUSY_HEADER: &str = "X-LMServer-Pool-Busy";
+pub const QUEUE_DEPTH_HEADER: &str = "X-LMServer-Queue-Depth";
+pub const PRESSURE_HEADER: &str = "X-LMServer-Pressure";
+
+pub const DEFAULT_PRESSURE_MEDIUM_PERCENT: u64 = 50;
+pub const DEFAULT_PRESSURE_HIGH_PERCENT: u64 = 90;
+
+/// Cada cuánto se recuentan los nodos de cada pool.
+const SNAPSHOT_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug)]
+pub struct PressureConfigError(String);
+
+impl fmt::Display for PressureConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for PressureConfigError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pressure {
+    Low,
+    Medium,
+    High,
+}
+
+impl Pressure {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Pressure::Low => "low",
+            Pressure::Medium => "medium",
+            Pressure::High => "high",
+        }
+    }
+}
+
+/// Contadores de una pool. `queued` se mantiene al entrar y salir de la cola; el resto lo
+/// recuenta la tarea de `refresh`.
+#[derive(Default)]
+pub struct PoolCapacity {
+    available: AtomicU64,
+    busy: AtomicU64,
+    pub(crate) queued: AtomicU64,
+}
+
+pub struct CapacityHints {
+    enabled: bool,
+    /// Ocupación (en %) a partir de la que la presión es media y alta.
+    medium_percent: u64,
+    high_percent: u64,
+    lmstudio: PoolCapacity,
+    ollama: PoolCapacity,
+}
+
+impl CapacityHints {
+    pub fn new(enabled: bool, medium_percent: u64, high_percent: u64) -> Result<Self, PressureConfigError> {
+        if medium_percent > high_percent || high_percent > 100 {
+            return Err(PressureConfigError(format!(
+                "Umbrales de presión inválidos ({}% medio, {}% alto): se espera medio <= alto <= 100",
+                medium_percent, high_percent
+            )));
+        }
+        Ok(Self {
+            enabled,
+            medium_percent,
+            high_percent,
+            lmstudio: PoolCapacity::default(),
+            ollama: PoolCapacity::default(),
+        })
+    }
+
+    pub fn pool(&self, service: &str) -> Option<&PoolCapacity> {
+        match service {
+            "lmstudio" => Some(&self.lmstudio),
+            "ollama" => Some(&self.ollama),
+            _ => None,
+        }
+    }
+
+    /// Recuenta los nodos libres y ocupados de la pool. Los nodos cargando un modelo cuentan
+    /// como ocupados; los caídos y los que se drenan no cuentan.
+    pub fn record(&self, service: &str, nodes: &HashMap<NodeId, NodeInfo>) {
+        let Some(pool) = self.pool(service) else {
+            return;
+        };
+        let (mut available, mut busy) = (0, 0);
+        for info in nodes.values() {
+            match info.state {
+                NodeHealth::Available => available += 1,
+                NodeHealth::Busy | NodeHealth::Loading => busy += 1,
+                NodeHealth::Failed(_) | NodeHealth::Draining(_) => {}
+            }
+        }
+        pool.available.store(available, Ordering::Relaxed);
+        pool.busy.store(busy, Ordering::Relaxed);
+    }
+
+    /// Presión de la pool. Cualquier petición en cola, o una pool sin nodos que atiendan, es alta.
+    pub fn pressure(&self, available: u64, busy: u64, queued: u64) -> Pressure {
+        let serving = available + busy;
+        if queued > 0 || serving == 0 {
+            return Pressure::High;
+        }
+        let used_percent = busy * 100 / serving;
+        if used_percent >= self.high_percent {
+            Pressure::High
+        } else if used_percent >= self.medium_percent {
+            Pressure::Medium
+        } else {
+            Pressure::Low
+        }
+    }
+
+    /// Añade las cabeceras de carga de la pool, si están activadas.
+    pub fn insert_headers(&self, service: &str, builder: &mut HttpResponseBuilder) {
+        let Some(pool) = self.pool(service).filter(|_| self.enabled) else {
+            return;
+        };
+        let available = pool.available.load(Ordering::Relaxed);
+        let busy = pool.busy.load(Ordering::Relaxed);
+        let queued = pool.queued.load(Ordering::Relaxed);
+        builder.insert_header((POOL_AVAILABLE_HEADER, available));
+        builder.insert_header((POOL_BUSY_HEADER, busy));
+        builder.insert_header((QUEUE_DEPTH_HEADER, queued));
+        builder.insert_header((PRESSURE_HEADER, self.pressure(available, busy, queued).label()));
+    }
+}
+
+/// Recuenta cada poco todas las pools, para recoger altas, bajas y health checks.
+pub async fn refresh(state: web::Data<AppState>) {
+    let mut ticker = interval(SNAPSHOT_INTERVAL);
+    loop {
+        ticker.tick().await;
+        for service in ["lmstudio", "ollama"] {
+            if let Some(nodes_lock) = state.pool(service) {
+                state.capacity.record(service, &nodes_lock.read().unwrap());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::HeaderMap;
+    use actix_web::test::{call_service, init_service};
+    use futures_util::future::join_all;
+    use std::time::Instant;
+
+    use crate::balancer;
+    use crate::testing;
+
+    fn hints() -> CapacityHints {
+        CapacityHints::new(true, DEFAULT_PRESSURE_MEDIUM_PERCENT, DEFAULT_PRESSURE_HIGH_PERCENT).unwrap()
+    }
+
+    #[test]
+    fn pressure_follows_utilisation_and_queue() {
+        let hints = hints();
+        let cases = [
+            // (libres, ocupados, en cola, presión)
+            (4, 0, 0, Pressure::Low),
+            (3, 1, 0, Pressure::Low),
+            (2, 2, 0, Pressure::Medium),
+            (1, 3, 0, Pressure::Medium),
+            (1, 9, 0, Pressure::High),
+            (0, 4, 0, Pressure::High),
+            (4, 0, 1, Pressure::High),
+            (0, 0, 0, Pressure::High),
+        ];
+        for (available, busy, queued, expected) in cases {
+            assert_eq!(hints.pressure(available, busy, queued), expected, "{} libres, {} ocupados, {} en cola", available, busy, queued);
+        }
+    }
+
+    #[test]
+    fn thresholds_are_configurable_and_validated() {
+        let hints = CapacityHints::new(true, 10, 20).unwrap();
+        assert_eq!(hints.pressure(9, 1, 0), Pressure::Medium);
+        assert_eq!(hints.pressure(8, 2, 0), Pressure::High);
+
+        assert!(CapacityHints::new(true, 60, 50).is_err());
+        assert!(CapacityHints::new(true, 50, 101).is_err());
+        assert!(CapacityHints::new(true, 100, 100).is_ok());
+    }
+
+    #[test]
+    fn record_counts_serving_nodes_by_state() {
+        let state = testing::state(&[]);
+        let states = [
+            NodeHealth::Available,
+            NodeHealth::Available,
+            NodeHealth::Busy,
+            NodeHealth::Loading,
+            NodeHealth::Failed(Instant::now()),
+            NodeHealth::Draining(Instant::now()),
+        ];
+        for (i, health) in states.into_iter().enumerate() {
+            let id = format!("box{}", i);
+            testing::announce(&state, "lmstudio", &id, &format!("http://127.0.0.1:{}/", 9000 + i));
+            state.lm_studio_nodes.write().unwrap().get_mut(id.as_str()).unwrap().state = health;
+        }
+        let hints = hints();
+        hints.record("lmstudio", &state.lm_studio_nodes.read().unwrap());
+
+        let pool = hints.pool("lmstudio").unwrap();
+        assert_eq!((pool.available.load(Ordering::Relaxed), pool.busy.load(Ordering::Relaxed)), (2, 2));
+        let ollama = hints.pool("ollama").unwrap();
+        assert_eq!((ollama.available.load(Ordering::Relaxed), ollama.busy.load(Ordering::Relaxed)), (0, 0));
+    }
+
+    /// (libres, ocupados, en cola, presión) de las cabeceras de una respuesta.
+    fn hinted(headers: &HeaderMap) -> (u64, u64, u64, String) {
+        let number = |name: &str| headers.get(name).unwrap_or_else(|| panic!("falta {}", name)).to_str().unwrap().parse::<u64>().unwrap();
+        let pressure = headers.get(PRESSURE_HEADER).unwrap().to_str().unwrap().to_string();
+        (number(POOL_AVAILABLE_HEADER), number(POOL_BUSY_HEADER), number(QUEUE_DEPTH_HEADER), pressure)
+    }
+
+    #[actix_web::test]
+    async fn saturated_pool_reports_queue_and_high_pressure() {
+        let state = testing::state_with_queue_timeout(10, &[]);
+        testing::announce(&state, "lmstudio", "box1", &testing::chat_node(Duration::from_millis(200)));
+        let app = init_service(balancer::app(state.clone())).await;
+
+        let responses = join_all((0..3).map(|_| call_service(&app, testing::chat().to_request()))).await;
+        let mut depths = Vec::new();
+        for resp in &responses {
+            assert!(resp.status().is_success(), "{}", resp.status());
+            let (available, busy, queued, pressure) = hinted(resp.headers());
+            assert_eq!(available + busy, 1, "la pool tiene un nodo");
+            assert!(queued <= 2, "{} en cola con 3 peticiones", queued);
+            assert_eq!(pressure, state.capacity.pressure(available, busy, queued).label());
+            depths.push(queued);
+        }
+        // La primera en terminar ve a las otras dos esperando; la última, la cola vacía.
+        depths.sort();
+        assert_eq!(depths, [0, 1, 2]);
+        assert_eq!(hinted(responses.iter().find(|resp| hinted(resp.headers()).2 == 2).unwrap().headers()).3, "high");
+    }
+
+    /// Pool de cuatro nodos con `busy` de ellos ocupados por otras peticiones.
+    async fn partly_busy(args: &[&str], busy: usize) -> (u64, u64, u64, String) {
+        let state = testing::state(args);
+        for id in ["box1", "box2", "box3", "box4"] {
+            testing::announce(&state, "lmstudio", id, &testing::chat_node(Duration::ZERO));
+        }
+        for id in ["box1", "box2", "box3", "box4"].into_iter().take(busy) {
+            state.lm_studio_nodes.write().unwrap().get_mut(id).unwrap().state = NodeHealth::Busy;
+        }
+        let app = init_service(balancer::app(state)).await;
+
+        let resp = call_service(&app, testing::chat().to_request()).await;
+        assert!(resp.status().is_success(), "{}", resp.status());
+        hinted(resp.headers())
+    }
+
+    #[actix_web::test]
+    async fn pressure_reflects_the_busy_share_of_the_pool() {
+        // Sin stream, el nodo que atendió la petición ya se liberó al construir la respuesta.
+        assert_eq!(partly_busy(&[], 0).await, (4, 0, 0, "low".to_string()));
+        assert_eq!(partly_busy(&[], 2).await, (2, 2, 0, "medium".to_string()));
+        assert_eq!(partly_busy(&[], 3).await, (1, 3, 0, "medium".to_string()));
+        assert_eq!(partly_busy(&["--pressure-high-percent", "75"], 3).await, (1, 3, 0, "high".to_string()));
+    }
+
+    #[actix_web::test]
+    async fn headers_can_be_disabled() {
+        let state = testing::state(&["--no-capacity-headers"]);
+        testing::announce(&state, "lmstudio", "box1", &testing::chat_node(Duration::ZERO));
+        let app = init_service(balancer::app(state)).await;
+
+        let resp = call_service(&app, testing::chat().to_request()).await;
+        assert!(resp.status().is_success(), "{}", resp.status());
+        for header in [POOL_AVAILABLE_HEADER, POOL_BUSY_HEADER, QUEUE_DEPTH_HEADER, PRESSURE_HEADER] {
+            assert!(resp.headers().get(header).is_none(), "{} con --no-capacity-headers", header);
+        }
+    }
+}
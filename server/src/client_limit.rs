@@ -0,0 +1,179 @@
+// src/client_limit.rs
+//! Tope de peticiones simultáneas por cliente (`--max-client-in-flight`).
+//!
+//! Un script agresivo puede ocupar todos los nodos y dejar a los demás esperando hasta el tiempo
+//! de cola. Cada petición de inferencia cuenta contra su cliente desde que entra hasta que el
+//! nodo termina de responder, esté en cola o ejecutándose; la que pasaría del tope recibe ya un
+//! 429 con `Retry-After`, sin entrar en la cola ni ocupar un nodo.
+//!
+//! El cliente es su API key si manda una y, si no, la IP de la conexión (detrás de un proxy,
+//! todos sus clientes comparten la del proxy). Se siguen como mucho `client_in_flight` clientes
+//! a la vez (sección `[limits]`); con la tabla llena, un cliente nuevo recibe el mismo 429 hasta
+//! que otro termine. Una key de `--api-keys-file` puede cambiar el tope
+//! con `max_in_flight`:
+//!
+//! ```toml
+//! [keys.nightly-jobs]
+//! key = "sk-nightly-..."
+//! max_in_flight = 2
+//! ```
+use actix_web::web;
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::balancer::AppState;
+use crate::limits::StoreUsage;
+
+/// Lo que se sugiere esperar en `Retry-After` al rechazar una petición por el tope.
+pub const RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Peticiones en curso por cliente.
+pub struct ClientLimiter {
+    default_max: Option<usize>,
+    /// Clientes con peticiones en curso como mucho.
+    max_clients: usize,
+    counts: Mutex<HashMap<String, usize>>,
+}
+
+impl ClientLimiter {
+    pub fn new(default_max: Option<usize>, max_clients: usize) -> Self {
+        Self { default_max, max_clients, counts: Mutex::default() }
+    }
+
+    pub fn default_max(&self) -> Option<usize> {
+        self.default_max
+    }
+
+    pub fn usage(&self) -> StoreUsage {
+        let counts = self.counts.lock().unwrap();
+        let bytes = counts.keys().map(|client| size_of::<(String, usize)>() + client.len()).sum();
+        StoreUsage { entries: counts.len(), bytes }
+    }
+}
+
+/// Plaza de un cliente; se devuelve al soltarla.
+pub struct ClientPermit {
+    state: web::Data<AppState>,
+    client: String,
+}
+
+impl Drop for ClientPermit {
+    fn drop(&mut self) {
+        let mut counts = self.state.client_limiter.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.client) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.client);
+            }
+        }
+    }
+}
+
+/// Reserva una plaza para el cliente si tiene menos de `max` peticiones en curso y, si es nuevo,
+/// queda sitio en la tabla de clientes.
+pub fn try_acquire(state: web::Data<AppState>, client: &str, max: usize) -> Option<ClientPermit> {
+    {
+        let mut counts = state.client_limiter.counts.lock().unwrap();
+        match counts.get(client) {
+            Some(&count) if count >= max => return None,
+            None if counts.len() >= state.client_limiter.max_clients => return None,
+            _ => {}
+        }
+        *counts.entry(client.to_string()).or_default() += 1;
+    }
+    Some(ClientPermit { state, client: client.to_string() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use futures_util::future::join_all;
+    use std::sync::atomic::Ordering;
+    use std::time::Instant;
+
+    use crate::balancer;
+    use crate::testing;
+
+    #[test]
+    fn permits_are_counted_per_client_and_returned_on_drop() {
+        let state = testing::state(&[]);
+        let first = try_acquire(state.clone(), "ip:10.0.0.1", 2).unwrap();
+        let second = try_acquire(state.clone(), "ip:10.0.0.1", 2).unwrap();
+        assert!(try_acquire(state.clone(), "ip:10.0.0.1", 2).is_none());
+        assert!(try_acquire(state.clone(), "ip:10.0.0.2", 2).is_some());
+        drop(first);
+        let third = try_acquire(state.clone(), "ip:10.0.0.1", 2).unwrap();
+        drop((second, third));
+        assert!(state.client_limiter.counts.lock().unwrap().is_empty());
+    }
+
+    fn chat(ip: &str, key: Option<&str>) -> TestRequest {
+        let req = testing::chat().peer_addr(format!("{}:40000", ip).parse().unwrap());
+        match key {
+            Some(key) => req.insert_header(("Authorization", format!("Bearer {}", key))),
+            None => req,
+        }
+    }
+
+    fn dispatched(state: &AppState) -> u64 {
+        state.lm_studio_nodes.read().unwrap().values().map(|info| info.stats.dispatched).sum()
+    }
+
+    #[actix_web::test]
+    async fn ten_requests_against_a_cap_of_two_get_eight_429s() {
+        let state = testing::state(&["--max-client-in-flight", "2"]);
+        let (url, hits) = testing::counting_chat_node(Duration::from_millis(300));
+        for id in ["box1", "box2", "box3"] {
+            testing::announce(&state, "lmstudio", id, &url);
+        }
+        let app = init_service(balancer::app(state.clone())).await;
+        let app = &app;
+
+        let start = Instant::now();
+        let responses = join_all((0..10).map(|_| async move {
+            let res = call_service(app, chat("10.0.0.1", None).to_request()).await;
+            let retry_after = res.headers().get("Retry-After").map(|value| value.to_str().unwrap().to_string());
+            (res.status().as_u16(), retry_after, start.elapsed())
+        }))
+        .await;
+        let rejected: Vec<_> = responses.iter().filter(|(status, _, _)| *status == 429).collect();
+        assert_eq!(rejected.len(), 8, "{:?}", responses);
+        assert_eq!(responses.iter().filter(|(status, _, _)| *status == 200).count(), 2);
+        // Se rechazan en el acto, sin esperar a un nodo aunque haya libres.
+        assert!(rejected.iter().all(|(_, retry_after, elapsed)| retry_after.as_deref() == Some("1") && *elapsed < Duration::from_millis(250)), "{:?}", rejected);
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+        assert_eq!(dispatched(&state), 2);
+        assert_eq!(state.metrics.rejected_client_in_flight.load(Ordering::Relaxed), 8);
+
+        // Al terminar, las plazas vuelven.
+        assert!(state.client_limiter.counts.lock().unwrap().is_empty());
+        assert_eq!(call_service(app, chat("10.0.0.1", None).to_request()).await.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn clients_are_told_apart_and_keys_can_change_the_cap() {
+        let keys = testing::temp_file("keys.toml", "[keys.nightly]\nkey = \"sk-nightly\"\nmax_in_flight = 1\n\n[keys.team]\nkey = \"sk-team\"\n");
+        let state = testing::state(&["--max-client-in-flight", "2", "--api-keys-file", keys.to_str().unwrap()]);
+        let (url, _) = testing::counting_chat_node(Duration::from_millis(300));
+        for id in ["box1", "box2", "box3", "box4", "box5", "box6"] {
+            testing::announce(&state, "lmstudio", id, &url);
+        }
+        let app = init_service(balancer::app(state.clone())).await;
+        let app = &app;
+
+        let clients = [("10.0.0.1", Some("sk-nightly")), ("10.0.0.1", Some("sk-team")), ("10.0.0.2", Some("sk-team")), ("10.0.0.3", None), ("10.0.0.4", None)];
+        let statuses = join_all(clients.iter().flat_map(|client| std::iter::repeat_n(*client, 3)).map(|(ip, key)| async move {
+            ((ip, key), call_service(app, chat(ip, key).to_request()).await.status().as_u16())
+        }))
+        .await;
+        let accepted = |client: (&str, Option<&str>)| statuses.iter().filter(|(sent, status)| *sent == client && *status == 200).count();
+        assert_eq!(accepted(("10.0.0.1", Some("sk-nightly"))), 1, "{:?}", statuses);
+        // La key manda sobre la IP: dos IPs con la misma key comparten su tope de 2.
+        assert_eq!(accepted(("10.0.0.1", Some("sk-team"))) + accepted(("10.0.0.2", Some("sk-team"))), 2, "{:?}", statuses);
+        // Sin key, cada IP tiene el suyo.
+        assert_eq!((accepted(("10.0.0.3", None)), accepted(("10.0.0.4", None))), (2, 2), "{:?}", statuses);
+    }
+}
@@ -0,0 +1,154 @@
+// src/clock.rs
+//! Detección de saltos del reloj del sistema (p.ej. NTP corrigiendo varios minutos de golpe).
+//!
+//! Los intervalos del balanceador (caducidad de nodos, enfriamientos, tiempos de ocupación,
+//! ventanas de métricas) se miden con `Instant`, que es monótono y no salta. El reloj de pared
+//! sólo se usa para mostrar marcas de tiempo y para los horarios de los perfiles, que se
+//! evalúan sin estado en cada comprobación. Esta tarea compara ambos relojes: si divergen, lo
+//! avisa, lo cuenta en `/metrics` y reevalúa los horarios al momento en lugar de esperar.
+use actix_web::web;
+use chrono::{DateTime, Local};
+use log::warn;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use tokio::time::interval;
+
+use crate::balancer::AppState;
+use crate::profiles;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Diferencia entre ambos relojes a partir de la que se considera un salto.
+const JUMP_THRESHOLD_MS: i64 = 2_000;
+
+/// Última lectura de ambos relojes.
+struct ClockReading {
+    wall: DateTime<Local>,
+    mono: Instant,
+}
+
+impl ClockReading {
+    fn now() -> Self {
+        Self { wall: Local::now(), mono: Instant::now() }
+    }
+
+    /// Milisegundos que el reloj de pared avanzó de más (o de menos, si es negativo) respecto
+    /// al monótono desde la lectura anterior.
+    fn skew_ms(&self, previous: &ClockReading) -> i64 {
+        let wall_ms = (self.wall - previous.wall).num_milliseconds();
+        let mono_ms = self.mono.duration_since(previous.mono).as_millis() as i64;
+        wall_ms - mono_ms
+    }
+}
+
+pub async fn watch(state: web::Data<AppState>) {
+    let mut ticker = interval(CHECK_INTERVAL);
+    let mut previous = ClockReading::now();
+    loop {
+        ticker.tick().await;
+        let reading = ClockReading::now();
+        let skew_ms = reading.skew_ms(&previous);
+        previous = reading;
+        if skew_ms.abs() < JUMP_THRESHOLD_MS {
+            continue;
+        }
+        state.metrics.clock_jumps.fetch_add(1, Ordering::Relaxed);
+        warn!(
+            "Reloj: El reloj del sistema saltó {:+.1}s. Las caducidades no cambian; las marcas de tiempo y los horarios siguen la hora nueva.",
+            skew_ms as f64 / 1000.0
+        );
+        if let Some(switch) = state.profiles.refresh(&previous.wall) {
+            profiles::announce_switch(&state, switch, "salto de reloj");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use std::net::IpAddr;
+
+    use crate::balancer::{build_state, remove_stale_nodes, LMSTUDIO};
+    use crate::persistence::{PersistenceBackend, RegistrySnapshot, SnapshotNode, Store};
+    use crate::pins::NodePin;
+    use crate::testing;
+    use crate::warmup;
+
+    #[test]
+    fn skew_measures_how_far_the_wall_clock_jumped() {
+        let previous = ClockReading::now();
+        let reading = |wall_secs: i64, mono_ms: u64| ClockReading {
+            wall: previous.wall + chrono::Duration::seconds(wall_secs) + chrono::Duration::milliseconds(mono_ms as i64),
+            mono: previous.mono + Duration::from_millis(mono_ms),
+        };
+
+        assert_eq!(reading(0, 5_000).skew_ms(&previous), 0);
+        assert_eq!(reading(300, 5_000).skew_ms(&previous), 300_000);
+        assert_eq!(reading(-300, 5_000).skew_ms(&previous), -300_000);
+        assert!(reading(1, 5_000).skew_ms(&previous).abs() < JUMP_THRESHOLD_MS);
+        assert!(reading(-2, 5_000).skew_ms(&previous).abs() >= JUMP_THRESHOLD_MS);
+    }
+
+    /// Un salto hacia atrás sólo cambia el perfil en la siguiente evaluación, sin entrar en pánico.
+    #[test]
+    fn profile_schedules_follow_backward_jumps() {
+        let profiles = testing::temp_file("profiles.toml", "[profiles.day]\nactive = \"09:00-18:00\"\n");
+        let state = testing::state(&["--profiles-file", profiles.to_str().unwrap()]);
+        let at = |hour: u32| Local.with_ymd_and_hms(2026, 3, 10, hour, 30, 0).unwrap();
+
+        assert_eq!(state.profiles.refresh(&at(10)), Some(("default".to_string(), "day".to_string())));
+        assert_eq!(state.profiles.refresh(&at(8)), Some(("day".to_string(), "default".to_string())));
+        assert_eq!(state.profiles.refresh(&at(8)), None);
+        assert_eq!(state.profiles.refresh(&at(10)), Some(("default".to_string(), "day".to_string())));
+    }
+
+    /// Estado arrancado con una instantánea del registro guardada en `saved_at`.
+    fn restored(saved_at: chrono::DateTime<Utc>) -> web::Data<AppState> {
+        let dir = testing::temp_dir();
+        let store = Store::open(PersistenceBackend::Json, Some(&dir), None).unwrap().unwrap();
+        let pin = NodePin { ip: "10.0.0.7".parse::<IpAddr>().unwrap(), pinned_at: saved_at };
+        store.save_registry(&RegistrySnapshot {
+            saved_at,
+            nodes: vec![SnapshotNode {
+                service: "lmstudio".to_string(),
+                node_id: "box1".to_string(),
+                service_url: "http://10.0.0.7:1234/".to_string(),
+                source: "announced".to_string(),
+                models: vec!["llama3".to_string()],
+            }],
+            pins: [("box1".to_string(), pin)].into(),
+        });
+        let config = testing::config(&["--state-dir", dir.to_str().unwrap(), "--warmup-secs", "1", "--node-pinning", "warn"]);
+        let store = Store::open(PersistenceBackend::Json, Some(&dir), None).unwrap();
+        web::Data::new(build_state(&config, store).unwrap())
+    }
+
+    /// Una instantánea con la hora de otro reloj (adelantado o muy atrasado) no resucita nodos
+    /// como recién vistos ni los deja para siempre: sólo vuelven al anunciarse, y desde ese
+    /// anuncio caducan con el reloj monótono como cualquier otro.
+    #[actix_web::test]
+    async fn skewed_snapshot_timestamps_neither_resurrect_nor_keep_nodes() {
+        let now = Utc::now();
+        for saved_at in [now + chrono::Duration::days(1), now - chrono::Duration::days(365), Utc.timestamp_opt(0, 0).unwrap()] {
+            let state = restored(saved_at);
+            assert_eq!(testing::node_state(&state, "lmstudio", "box1"), "absent", "guardada en {}", saved_at);
+            assert_eq!(state.warmup.describe()["previous_nodes"], 1);
+            assert_eq!(state.pins.pinned_ip("box1"), Some("10.0.0.7".parse().unwrap()));
+
+            // El calentamiento, que espera a los nodos de la instantánea, termina con su ventana.
+            let start = Instant::now();
+            warmup::run(state.clone()).await;
+            assert!(!state.warmup.is_active());
+            assert!(start.elapsed() < Duration::from_secs(3), "{:?}", start.elapsed());
+
+            testing::announce(&state, "lmstudio", "box1", "http://10.0.0.7:1234/");
+            remove_stale_nodes(&mut state.lm_studio_nodes.write().unwrap(), &state, Duration::from_secs(35), "LM Studio", &LMSTUDIO);
+            assert_eq!(testing::node_state(&state, "lmstudio", "box1"), "available", "recién anunciado no caduca");
+
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            remove_stale_nodes(&mut state.lm_studio_nodes.write().unwrap(), &state, Duration::from_millis(10), "LM Studio", &LMSTUDIO);
+            assert_eq!(testing::node_state(&state, "lmstudio", "box1"), "absent", "sin anuncios, caduca");
+        }
+    }
+}
@@ -0,0 +1,235 @@
+// src/config.rs
+use std::path::PathBuf;
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct BalancerConfig {
+    #[arg(short, long, default_value = "0.0.0.0:8080", help = "Dirección IP y puerto donde escuchará el balanceador.")]
+    pub listen_addr: String,
+    #[arg(short, long, help = "Dirección IP y puerto para escuchar los anuncios UDP de los nodos, para todas las pools. Sin -u ni --udp-listener se usa 0.0.0.0:4000.")]
+    pub udp_addr: Option<String>,
+    #[arg(long = "udp-listener", value_name = "ADDR[=POOL,...]", help = "Listener UDP de descubrimiento adicional (repetible), p.ej. '10.0.2.1:4001=rack'. Con pools, sólo registra nodos en ellas (o en sus alias) y rechaza el resto de anuncios.")]
+    pub udp_listener: Vec<String>,
+    #[arg(long, value_enum, default_value = "json", help = "Almacén de la auditoría, el uso diario y la instantánea del registro: json (ficheros) o sqlite (exige la feature sqlite).")]
+    pub persistence: crate::persistence::PersistenceBackend,
+    #[arg(long, value_name = "DIR", help = "Directorio del almacén de persistencia: auditoría, uso diario (GET /usage/daily) e instantánea del registro de nodos.")]
+    pub state_dir: Option<PathBuf>,
+    #[arg(long, value_name = "FILE", help = "Archivo NDJSON de sólo anexado para el registro de auditoría de peticiones (sólo con --persistence json; por defecto, audit.ndjson en --state-dir).")]
+    pub audit_file: Option<PathBuf>,
+    #[arg(long, help = "Negarse a arrancar si el registro de auditoría no está habilitado.")]
+    pub require_audit: bool,
+    #[arg(long, value_name = "CHARS", default_value_t = crate::usage::DEFAULT_CHARS_PER_TOKEN, help = "Caracteres por token con los que se estiman los tokens de salida de una respuesta en streaming que no trae usage; la estimación se guarda marcada con estimated: true. Con la feature tiktoken se usa el tokenizador.")]
+    pub usage_chars_per_token: f64,
+    #[arg(long, value_name = "TOKEN", env = "LMSERVER_AUDIT_TOKEN", help = "Token Bearer exigido por GET /audit/export (independiente de otros endpoints de administración).")]
+    pub audit_token: Option<String>,
+    #[arg(long, value_name = "TOKEN", env = "LMSERVER_ADMIN_TOKEN", help = "Token Bearer con acceso a todos los endpoints de administración (todos los scopes salvo usage:read).")]
+    pub admin_token: Option<String>,
+    #[arg(long, help = "Exige también scope (nodes:read, stats:read, config:read) en los endpoints de lectura, que por defecto son públicos.")]
+    pub protect_read_endpoints: bool,
+    #[arg(long, value_name = "FILE", help = "Archivo TOML con perfiles de configuración por horario ([profiles.<nombre>]).")]
+    pub profiles_file: Option<PathBuf>,
+    #[arg(long, value_name = "FILE", help = "Archivo TOML con parámetros por defecto/forzados y modelos permitidos por API key ([keys.<nombre>]).")]
+    pub api_keys_file: Option<PathBuf>,
+    #[arg(long, value_name = "FILE", help = "Archivo TOML con reglas de enrutado por contenido ([[rules]]). Se recarga al modificarlo.")]
+    pub rules_file: Option<PathBuf>,
+    #[arg(long, value_name = "N", default_value_t = crate::reload::DEFAULT_CONFIG_HISTORY, help = "Configuraciones aplicadas (arranque, POST /admin/reload y recargas de --rules-file) que se guardan en memoria para volver atrás con POST /admin/config/rollback.")]
+    pub config_history: usize,
+    #[arg(long, value_name = "FILE", help = "Archivo TOML con los topes de memoria de registros y cachés internas (sección [limits]).")]
+    pub limits_file: Option<PathBuf>,
+    #[arg(long, value_name = "URL", help = "URL de un backend que corre en esta misma máquina (p.ej. http://127.0.0.1:1234/v1/chat/completions). Se registra como nodo estático con health checks.")]
+    pub local_node: Option<String>,
+    #[arg(long, value_name = "SERVICE", default_value = "lmstudio", help = "Pool en la que se registra --local-node (lmstudio u ollama).")]
+    pub local_node_service: String,
+    #[arg(long, help = "Detecta LM Studio y Ollama en esta máquina y los registra como nodos estáticos.")]
+    pub auto_local: bool,
+    #[arg(long, help = "Rechaza los anuncios UDP antiguos de tres campos ('DISCOVER,<svc>,<ip:puerto>') una vez actualizados todos los nodos.")]
+    pub disable_legacy_discovery: bool,
+    #[arg(long, value_name = "MODEL", help = "Modelo que el balanceador puede cargar automáticamente en un nodo Ollama, descargando los que tenga en memoria, si ningún nodo libre lo tiene residente. Repetible; sin ninguno, la carga automática está desactivada.")]
+    pub auto_load_model: Vec<String>,
+    #[arg(long, value_name = "SECONDS", default_value_t = crate::loader::DEFAULT_MODEL_LOAD_TIMEOUT_SECS, help = "Tiempo máximo que una petición espera a que se descarguen y carguen modelos en el nodo elegido.")]
+    pub model_load_timeout: u64,
+    #[arg(long, default_value_t = 1234, help = "Puerto local de LM Studio que prueba --auto-local.")]
+    pub auto_local_lmstudio_port: u16,
+    #[arg(long, default_value_t = 11434, help = "Puerto local de Ollama que prueba --auto-local.")]
+    pub auto_local_ollama_port: u16,
+    #[arg(long = "forward-header", value_name = "POOL=HEADER", help = "Añade una cabecera a la whitelist de reenvío de la pool (repetible).")]
+    pub forward_header: Vec<String>,
+    #[arg(long = "strip-header", value_name = "POOL=HEADER", help = "Quita una cabecera de la whitelist de reenvío de la pool (repetible).")]
+    pub strip_header: Vec<String>,
+    #[arg(long, value_name = "BYTES", default_value_t = crate::headers::DEFAULT_MAX_HEADER_VALUE_BYTES, help = "Tamaño máximo del valor de una cabecera reenviada a los nodos; las mayores no se reenvían (Authorization rechaza la petición con 400).")]
+    pub max_forwarded_header_value_bytes: usize,
+    #[arg(long, value_name = "BYTES", default_value_t = crate::headers::DEFAULT_MAX_FORWARDED_HEADER_BYTES, help = "Tamaño máximo del conjunto de cabeceras reenviadas a los nodos (nombres y valores); las que no caben no se reenvían.")]
+    pub max_forwarded_header_bytes: usize,
+    #[arg(long, value_name = "N", default_value_t = crate::headers::DEFAULT_MAX_FORWARDED_HEADERS, help = "Número máximo de cabeceras reenviadas a los nodos; las que sobran no se reenvían.")]
+    pub max_forwarded_headers: usize,
+    #[arg(long = "postprocess", value_name = "POOL=HOOK", help = "Post-procesado de las respuestas de la pool (repetible): 'stop' corta en las secuencias stop del cliente, 'trim' quita espacios al principio y al final.")]
+    pub postprocess: Vec<String>,
+    #[arg(long = "strip-token", value_name = "POOL=TOKEN", help = "Token especial que se elimina de las respuestas de la pool, p.ej. 'lmstudio=<|im_end|>' (repetible).")]
+    pub strip_token: Vec<String>,
+    #[arg(long = "pool-alias", value_name = "ALIAS=POOL", help = "Nombre alternativo de una pool, para migraciones (repetible). Se resuelve en cada petición (/<alias> y /pool/<alias>) y en los anuncios de los nodos.")]
+    pub pool_alias: Vec<String>,
+    #[arg(long = "pool-cost", value_name = "POOL=COST", help = "Coste relativo de la pool (repetible). Las pools con coste se prueban de la más barata a la más cara y una petición sólo desborda a una más cara bajo presión (--spill-delay-ms, --spill-queue-depth).")]
+    pub pool_cost: Vec<String>,
+    #[arg(long, value_name = "MS", default_value_t = crate::spill::DEFAULT_SPILL_DELAY_MS, help = "Espera máxima por un nodo de una pool con coste antes de desbordar a la siguiente más cara.")]
+    pub spill_delay_ms: u64,
+    #[arg(long, value_name = "N", default_value_t = crate::spill::DEFAULT_SPILL_QUEUE_DEPTH, help = "Peticiones en cola en una pool con coste a partir de las que se desborda a la siguiente más cara sin esperar.")]
+    pub spill_queue_depth: u64,
+    #[arg(long = "pool-spillover", value_name = "FROM=TO", help = "Desborda de una pool a otra, en un solo sentido, cuando la primera no tiene nodo libre en --pool-spillover-delay-ms, p.ej. 'lmstudio=ollama' (repetible; desactivado por defecto). Si el nodo de destino anunció una ruta nativa de Ollama, la petición va a su /v1/chat/completions.")]
+    pub pool_spillover: Vec<String>,
+    #[arg(long, value_name = "MS", default_value_t = crate::spill::DEFAULT_SPILLOVER_DELAY_MS, help = "Espera por un nodo de la pool de entrada antes de probar también la de --pool-spillover.")]
+    pub pool_spillover_delay_ms: u64,
+    #[arg(long = "reserve-interactive", value_name = "POOL=N", help = "Nodos de la pool reservados para peticiones interactivas (repetible): las batch sólo ocupan a la vez el resto y esperan en cola. La reserva se recorta si caen nodos.")]
+    pub reserve_interactive: Vec<String>,
+    #[arg(long, value_enum, default_value_t = crate::workload::WorkloadClass::Interactive, help = "Clase de las peticiones que no la declaran con X-Workload-Class ni la reciben de su API key o de una regla.")]
+    pub default_workload_class: crate::workload::WorkloadClass,
+    #[arg(long, value_name = "SECS", default_value_t = crate::tombstones::DEFAULT_RETENTION_SECS, help = "Segundos que se guarda la lápida de un nodo que sale del registro (GET /nodes?include=removed). 0 no guarda lápidas.")]
+    pub tombstone_retention_secs: u64,
+    #[arg(long, help = "Un nodo que vuelve a registrarse con lápida recupera sus acumulados (peticiones asignadas, fallos, fecha de registro) en lugar de empezar de cero.")]
+    pub restore_node_stats: bool,
+    #[arg(long, value_enum, default_value_t = crate::selection::NodeSelection::RoundRobin, help = "Cómo se elige entre los nodos libres de una pool: round-robin (por turnos), least-connections (el de menos peticiones en curso; a igualdad, por turnos), weighted (en proporción al peso que anuncia cada nodo con --weight), latency (el de menor latencia reciente, con algunas elecciones por turnos), first-available (el primero por orden de ID) o random (al azar).")]
+    pub node_selection: crate::selection::NodeSelection,
+    #[arg(long, help = "Deja para el final el último nodo elegido en cada pool, sea cual sea --node-selection: con varios nodos libres, la siguiente petición va a otro. Si es el único libre, se reutiliza.")]
+    pub node_anti_affinity: bool,
+    #[arg(long = "node-tier", value_name = "LABEL=VALUE[,...]", default_value = crate::tiers::DEFAULT_NODE_TIER, help = "Nivel de nodos, en orden (repetible): los nodos que cumplen todas sus etiquetas (gpu, os, arch, accelerator, memory, de la plataforma que anuncian) se prueban antes que los de niveles posteriores y que los que no cumplen ninguno, p.ej. 'gpu=true' o 'accelerator=cuda,memory=discrete'. Dentro de un nivel decide --node-selection.")]
+    pub node_tier: Vec<String>,
+    #[arg(long, help = "Trata a todos los nodos por igual, sin los niveles de --node-tier.")]
+    pub no_node_tiers: bool,
+    #[arg(long, value_name = "ALPHA", default_value_t = crate::latency::DEFAULT_LATENCY_ALPHA, help = "Peso de cada respuesta nueva (0-1] en la media móvil de latencia de un nodo para --node-selection latency. Más alto olvida antes.")]
+    pub latency_alpha: f64,
+    #[arg(long, value_name = "MS", default_value_t = crate::latency::DEFAULT_FAILURE_PENALTY_MS, help = "Latencia con la que entra en la media un reenvío fallido (error de conexión o estado no exitoso).")]
+    pub latency_failure_penalty_ms: u64,
+    #[arg(long, value_name = "F", default_value_t = crate::latency::DEFAULT_LATENCY_EXPLORE, help = "Fracción (0-1) de las elecciones de --node-selection latency que van por turnos, para que los nodos lentos sigan recibiendo alguna petición y puedan recuperarse.")]
+    pub latency_explore: f64,
+    #[arg(long, value_name = "SECS", default_value_t = 0, help = "Ventana de la afinidad por el modelo caliente (0 la desactiva): entre los nodos que pueden atender la petición, los que atendieron su mismo 'model' en los últimos SECS segundos se prueban antes, para no recargarlo en uno frío.")]
+    pub warm_model_window_secs: u64,
+    #[arg(long, value_name = "SECS", default_value_t = crate::reconcile::DEFAULT_STALE_BUSY_GRACE_SECS, help = "Margen tras el que la tarea de limpieza devuelve a Available un nodo Busy sin ninguna petición en curso ni reserva (o en Loading más allá de --model-load-timeout), avisando de la fuga de estado (0 lo desactiva).")]
+    pub stale_busy_grace_secs: u64,
+    #[arg(long, value_name = "SECS", default_value_t = 0, help = "Duración de la fase canaria de un nodo recién anunciado, en la que sólo recibe --canary-percent de las peticiones que podría atender. Con --canary-requests, termina con lo que llegue antes; los dos a 0 desactivan la fase.")]
+    pub canary_secs: u64,
+    #[arg(long, value_name = "N", default_value_t = 0, help = "Peticiones que atiende un nodo recién anunciado antes de juzgar su fase canaria (0: sólo cuenta --canary-secs).")]
+    pub canary_requests: u32,
+    #[arg(long, value_name = "PCT", default_value_t = crate::canary::DEFAULT_CANARY_PERCENT, help = "Porcentaje (1-100) de las peticiones que podría atender que recibe un nodo en fase canaria.")]
+    pub canary_percent: u32,
+    #[arg(long, value_name = "F", default_value_t = crate::canary::DEFAULT_CANARY_MAX_ERROR_RATE, help = "Tasa de errores (0-1; 5xx y fallos al reenviar) con la que un nodo aún supera su fase canaria. Por encima se marca Failed y repite la fase.")]
+    pub canary_max_error_rate: f64,
+    #[arg(long, value_name = "F", default_value_t = 0.0, help = "Fracción de los despachos (0-1) reservada a las peticiones cortas mientras en la pool esperan cortas y largas, para que no queden detrás de una fila de streams largos. 0 lo desactiva.")]
+    pub short_dispatch_share: f64,
+    #[arg(long, value_name = "TOKENS", default_value_t = crate::interleave::DEFAULT_SHORT_MAX_TOKENS, help = "Una petición en streaming con max_tokens hasta este valor cuenta como corta para --short-dispatch-share; sin stream, siempre lo es.")]
+    pub short_max_tokens: u64,
+    #[arg(long, value_name = "BYTES", help = "Una petición cuyo cuerpo pase de BYTES va al carril lento (ver --slow-lane-pool, --slow-lane-tag y --slow-lane-max-share).")]
+    pub slow_lane_body_bytes: Option<usize>,
+    #[arg(long, value_name = "TOKENS", help = "Una petición cuyo prompt estimado pase de TOKENS va al carril lento.")]
+    pub slow_lane_prompt_tokens: Option<u64>,
+    #[arg(long, value_name = "POOL", help = "Pool a la que van las peticiones del carril lento, sin desbordar a otras.")]
+    pub slow_lane_pool: Option<String>,
+    #[arg(long = "slow-lane-tag", value_name = "KEY=VALUE", value_parser = crate::tags::parse_tag, help = "Las peticiones del carril lento sólo van a nodos con esta etiqueta (--tag del nodo; repetible).")]
+    pub slow_lane_tags: Vec<(String, String)>,
+    #[arg(long, value_name = "F", default_value_t = crate::slow_lane::DEFAULT_SLOW_LANE_MAX_SHARE, help = "Sin --slow-lane-pool ni --slow-lane-tag, fracción (0-1] de los nodos de cada pool que pueden ocupar a la vez las peticiones del carril lento (al menos uno).")]
+    pub slow_lane_max_share: f64,
+    #[arg(long, value_name = "SECS", help = "Tiempo de espera en cola de las peticiones del carril lento; por defecto, el de las demás.")]
+    pub slow_lane_queue_timeout_secs: Option<u64>,
+    #[arg(long, value_name = "SECS", help = "Tiempo máximo de respuesta del nodo a una petición del carril lento; por defecto, el de las demás (300s).")]
+    pub slow_lane_request_timeout_secs: Option<u64>,
+    #[arg(long = "context-window", value_name = "POOL:MODEL=TOKENS", help = "Ventana de contexto de un modelo en la pool (repetible). Tiene prioridad sobre la que anuncien los nodos.")]
+    pub context_window: Vec<String>,
+    #[arg(long = "dispatch-rate", value_name = "POOL=RPS[:BURST]", help = "Ritmo máximo de despacho a cada nodo de la pool, en peticiones por segundo con una ráfaga opcional (por defecto 1), p.ej. 'lmstudio=2:1' (repetible). PATCH /nodes/{id}/dispatch-rate lo cambia para un nodo.")]
+    pub dispatch_rate: Vec<String>,
+    #[arg(long = "node-capability", value_name = "NODE_ID=[-]CAP", help = "Fija una capacidad de un nodo sin importar lo que anuncie, p.ej. 'mi-nodo=tool-calling' o 'mi-nodo=-tool-calling' para quitarla (repetible). Sirve para nodos estáticos y backends que pasan la prueba pero fallan en la práctica.")]
+    pub node_capability: Vec<String>,
+    #[arg(long = "node-domain", value_name = "NODE_ID=DOMAIN", help = "Fija el dominio de fallo de un nodo (rack, PDU, switch) sin importar lo que anuncie con --failure-domain, p.ej. 'gpu-01=rack-a' (repetible). Los reintentos prefieren nodos de otro dominio y se avisa cuando todos los nodos utilizables de una pool quedan en uno solo.")]
+    pub node_domain: Vec<String>,
+    #[arg(long = "node-max-rpm", value_name = "NODE_ID=RPM", help = "Tope de peticiones por minuto de un nodo, p.ej. 'gpu-01=30' (repetible). Si el nodo anuncia otro con --max-rpm, manda el menor. Un nodo en su tope no recibe peticiones hasta que la ventana deslizante del último minuto baje de él.")]
+    pub node_max_rpm: Vec<String>,
+    #[arg(long = "local-prefix", value_name = "CIDR", help = "Prefijo de la red local, p.ej. '192.168.1.0/24' (repetible). Los nodos cuya IP de origen está en alguno se prueban antes que los remotos, que sólo atienden cuando no queda un local libre.")]
+    pub local_prefix: Vec<String>,
+    #[arg(long = "direct-pool", value_name = "POOL", help = "Modo directo de una pool de un solo nodo (repetible): mientras tenga un único nodo sin ritmo de despacho, las peticiones se reenvían sin cola ni ocupar el nodo, varias a la vez. Se apaga solo al registrarse un segundo nodo.")]
+    pub direct_pool: Vec<String>,
+    #[arg(long = "retry-policy", value_name = "SITE:KEY=VALUE,...", help = "Reintentos de un punto de llamadas salientes: forward (reenvío al nodo, sólo si no se pudo conectar) o health (prueba de salud de los nodos estáticos). Claves: attempts (en total, por defecto 1: sin reintentos), base_ms, multiplier, max_ms y jitter (fracción 0-1), p.ej. 'forward:attempts=3,base_ms=100' (repetible).")]
+    pub retry_policy: Vec<String>,
+    #[arg(long, value_enum, default_value_t = crate::drain::DrainPolicy::Wait, help = "Qué hacer con las peticiones en curso de un nodo cuando vence su plazo de drenaje: wait (esperar a que terminen) o migrate (reenviar a otro nodo las que aún no tienen respuesta y cortar los streams con un evento de error con resume_hint).")]
+    pub drain_policy: crate::drain::DrainPolicy,
+    #[arg(long, help = "Repite una vez en otro nodo con la capacidad las peticiones cuya respuesta trae tool_calls mal formados, en lugar de devolver el error directamente.")]
+    pub retry_invalid_tool_calls: bool,
+    #[arg(long = "strictness", value_name = "[POOL=]MODE", help = "Validación estricta de una pool, p.ej. 'ollama=enforce' (repetible; sin pool, para todas): off (por defecto), warn (avisa y cuenta las peticiones con campos de primer nivel desconocidos y las respuestas sin el mensaje esperado) o enforce (además las rechaza con 400 y 502 y marca Failed al nodo). Los perfiles lo cambian con strictness = { <pool> = \"<modo>\" }.")]
+    pub strictness: Vec<String>,
+    #[arg(long, value_name = "SECONDS", default_value_t = 0, help = "Ventana de calentamiento tras arrancar (0 la desactiva): /readyz responde 503, se aplica --warmup-dispatch-rate y el exceso de carga recibe 503 con Retry-After en lugar de encolarse.")]
+    pub warmup_secs: u64,
+    #[arg(long, value_name = "N", default_value_t = crate::warmup::DEFAULT_WARMUP_MIN_NODES, help = "Nodos disponibles, sumando todas las pools, con los que el calentamiento termina antes de la ventana.")]
+    pub warmup_min_nodes: usize,
+    #[arg(long, value_name = "RPS[:BURST]", help = "Ritmo máximo de despacho a cada nodo durante el calentamiento, si es más lento que el suyo.")]
+    pub warmup_dispatch_rate: Option<String>,
+    #[arg(long, value_name = "N", default_value_t = crate::warmup::DEFAULT_WARMUP_MAX_QUEUED, help = "Peticiones en cola por pool a partir de las que, durante el calentamiento, se responde 503 con Retry-After.")]
+    pub warmup_max_queued: u64,
+    #[arg(long, value_name = "SECONDS", default_value_t = crate::warmup::DEFAULT_WARMUP_RETRY_AFTER_SECS, help = "Retry-After de las peticiones rechazadas durante el calentamiento.")]
+    pub warmup_retry_after_secs: u64,
+    #[arg(long, value_name = "FRACTION", default_value_t = crate::warmup::DEFAULT_WARMUP_CAPACITY_FRACTION, help = "Fracción de los nodos de la última instantánea guardada (--state-dir) que tiene que volver a estar disponible para terminar el calentamiento antes de la ventana.")]
+    pub warmup_capacity_fraction: f64,
+    #[arg(long, value_name = "SECONDS", default_value_t = crate::diagnose::DEFAULT_DIAGNOSE_INTERVAL_SECS, help = "Tiempo mínimo entre dos diagnósticos (POST /nodes/{id}/diagnose) del mismo nodo.")]
+    pub diagnose_interval_secs: u64,
+    #[arg(long, value_enum, value_delimiter = ',', default_value = crate::status::DEFAULT_PUBLIC_FIELDS, help = "Campos de GET /status/public y de la UI con --ui-public, separados por comas. Ninguno identifica nodos, URLs ni API keys.")]
+    pub public_status_fields: Vec<crate::status::PublicField>,
+    #[arg(long, help = "La UI de terminal muestra sólo la vista pública (recuentos, ritmo, p95 y uptime), sin IDs, URLs ni keys.")]
+    pub ui_public: bool,
+    #[arg(long, value_name = "PERCENT", default_value_t = crate::context::DEFAULT_CONTEXT_HEADROOM_PERCENT, help = "Margen sobre la ventana de contexto antes de rechazar un prompt, para compensar lo aproximado de la estimación de tokens.")]
+    pub context_headroom: u64,
+    #[arg(long, value_name = "MB", default_value_t = crate::storage::DEFAULT_MIN_FREE_DISK_MB, help = "Espacio libre mínimo en el volumen de modelos de un nodo; por debajo se marca con un aviso en la UI y en /nodes.")]
+    pub min_free_disk_mb: u64,
+    #[arg(long, value_name = "SECONDS", default_value_t = crate::idempotency::DEFAULT_IDEMPOTENCY_TTL_SECS, help = "Segundos que se guarda la respuesta de una petición con Idempotency-Key para repetirla a los reintentos (0 desactiva la cabecera).")]
+    pub idempotency_ttl_secs: u64,
+    #[arg(long, value_name = "N", default_value_t = crate::idempotency::DEFAULT_IDEMPOTENCY_MAX_ENTRIES, help = "Máximo de claves de idempotencia recordadas a la vez; al llenarse se olvidan primero las que antes caducan.")]
+    pub idempotency_max_entries: usize,
+    #[arg(long, value_name = "BYTES", default_value_t = crate::idempotency::DEFAULT_IDEMPOTENCY_MAX_BODY_BYTES, help = "Tamaño máximo de una respuesta guardada para idempotencia. Los reintentos de respuestas mayores reciben un error en lugar de la respuesta.")]
+    pub idempotency_max_body_bytes: usize,
+    #[arg(long, value_name = "SECONDS", default_value_t = crate::fairness::DEFAULT_FAIRNESS_WINDOW_SECS, help = "Ventana deslizante sobre la que se mide el reparto de peticiones entre nodos (GET /stats/fairness).")]
+    pub fairness_window_secs: u64,
+    #[arg(long, value_name = "RATIO", default_value_t = crate::fairness::DEFAULT_FAIRNESS_SKEW_THRESHOLD, help = "Desviación del índice de sesgo respecto a 1.0 a partir de la cual un reparto sostenido se avisa con un evento fairness_skew.")]
+    pub fairness_skew_threshold: f64,
+    #[arg(long, help = "No añade a las respuestas las cabeceras X-LMServer-Pool-Available/-Busy, -Queue-Depth y -Pressure.")]
+    pub no_capacity_headers: bool,
+    #[arg(long, value_name = "PERCENT", default_value_t = crate::capacity::DEFAULT_PRESSURE_MEDIUM_PERCENT, help = "Ocupación de la pool a partir de la que X-LMServer-Pressure es 'medium'.")]
+    pub pressure_medium_percent: u64,
+    #[arg(long, value_name = "PERCENT", default_value_t = crate::capacity::DEFAULT_PRESSURE_HIGH_PERCENT, help = "Ocupación de la pool a partir de la que X-LMServer-Pressure es 'high'. Con peticiones en cola es siempre 'high'.")]
+    pub pressure_high_percent: u64,
+    #[arg(long, default_value_t = 50, help = "Máximo de suscriptores concurrentes de GET /events.")]
+    pub max_event_subscribers: usize,
+    #[arg(long, value_name = "SECONDS", default_value_t = 30, help = "Segundos que se reutiliza la resolución DNS de los nodos registrados por nombre.")]
+    pub dns_cache_ttl: u64,
+    #[arg(long, value_name = "BYTES", default_value_t = crate::streaming::DEFAULT_STREAM_BUFFER_BYTES, help = "Buffer por respuesta en streaming. El nodo se libera al terminar de generar aunque el cliente siga leyendo el buffer.")]
+    pub stream_buffer_bytes: usize,
+    #[arg(long, value_name = "SECONDS", default_value_t = crate::streaming::DEFAULT_SSE_KEEPALIVE_SECS, help = "Segundos de silencio del nodo tras los que se envía un comentario de keep-alive en respuestas SSE (0 lo desactiva).")]
+    pub sse_keepalive_secs: u64,
+    #[cfg(feature = "grpc")]
+    #[arg(long, value_name = "ADDR", help = "Dirección IP y puerto de la API gRPC (registro de nodos, cambios del registro y estado). Sin ella no se sirve gRPC.")]
+    pub grpc_listen: Option<std::net::SocketAddr>,
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "FILE", requires = "tls_key", help = "Certificado PEM para servir la API por HTTPS (con HTTP/2 negociado por ALPN).")]
+    pub tls_cert: Option<PathBuf>,
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "FILE", requires = "tls_cert", help = "Clave privada PEM del certificado de --tls-cert.")]
+    pub tls_key: Option<PathBuf>,
+    #[arg(long, value_name = "N", help = "Máximo de respuestas en streaming simultáneas en total (sin límite si se omite).")]
+    pub max_streams: Option<usize>,
+    #[arg(long, value_name = "N", help = "Máximo de respuestas en streaming simultáneas por pool.")]
+    pub max_streams_per_pool: Option<usize>,
+    #[arg(long, value_name = "N", help = "Máximo de respuestas en streaming simultáneas por API key.")]
+    pub max_streams_per_key: Option<usize>,
+    #[arg(long, value_name = "N", help = "Máximo de peticiones de inferencia en curso (en cola o ejecutándose) por cliente: su API key o, sin key, su IP. Las que pasan reciben 429 con Retry-After sin entrar en la cola. max_in_flight en --api-keys-file lo cambia por key.")]
+    pub max_client_in_flight: Option<usize>,
+    #[arg(long, value_name = "MS", default_value_t = crate::pipeline::DEFAULT_PIPELINE_WINDOW_MS, help = "Ventana durante la que un nodo queda reservado para la siguiente petición con el mismo X-Pipeline (0 lo desactiva).")]
+    pub pipeline_window_ms: u64,
+    #[arg(long, value_name = "N", default_value_t = 1, help = "No se reservan nodos para X-Pipeline si la pool tiene menos de N nodos disponibles.")]
+    pub pipeline_min_available: usize,
+    #[arg(long, value_name = "SECS", default_value_t = crate::sessions::DEFAULT_SESSION_TTL_SECS, help = "Una sesión X-Session-Id sin peticiones durante SECS deja de estar fijada a su nodo (0 ignora la cabecera).")]
+    pub session_ttl_secs: u64,
+    #[arg(long, help = "Lleva todas las peticiones de un mismo usuario (campo 'user' de OpenAI) a un mismo nodo por hash consistente; si está ocupado, la petición lo espera.")]
+    pub user_affinity: bool,
+    #[arg(long, value_name = "NAME", requires = "user_affinity", help = "Cabecera de la que leer el usuario para --user-affinity; sin ella se usa el campo 'user'.")]
+    pub user_affinity_header: Option<String>,
+    #[arg(long, requires = "user_affinity", help = "Con --user-affinity, si el nodo del usuario está ocupado la petición va a otro libre en lugar de esperarlo.")]
+    pub user_affinity_fallback: bool,
+    #[arg(long, value_name = "HH:MM", help = "Prueba el rendimiento de los nodos libres cada día a esta hora local y avisa si empeora frente a la prueba anterior.")]
+    pub benchmark_at: Option<String>,
+    #[arg(long, value_name = "PCT", default_value_t = crate::benchmark::DEFAULT_BENCHMARK_REGRESSION_PCT, help = "Empeoramiento, en %, de la latencia o de los tokens por segundo que dispara benchmark_regression.")]
+    pub benchmark_regression_pct: f64,
+    #[arg(long, value_enum, default_value_t = crate::pins::PinMode::Disabled, help = "Fija cada ID de nodo a la IP desde la que se registró por primera vez: warn avisa (node_identity_mismatch) de los mensajes con ese ID desde otra IP, reject además los descarta. POST /nodes/{id}/repin lo vuelve a fijar.")]
+    pub node_pinning: crate::pins::PinMode,
+}
@@ -0,0 +1,366 @@
+// src/context.rs
+//! Rechazo temprano de prompts que no caben en la ventana de contexto del modelo.
+//!
+//! El tamaño del prompt es una estimación aproximada: por defecto ~4 caracteres por token, o
+//! el tokenizador `cl100k_base` con la feature `tiktoken` (que tampoco es el de cada modelo).
+//! Por eso sólo se rechaza cuando `prompt + max_tokens` supera la ventana más un margen
+//! configurable. De los mensajes multimodales sólo cuentan las partes de texto.
+//!
+//! Una petición que supera la ventana configurada del modelo (`--context-window`) se rechaza con
+//! 400. Además, cada nodo puede anunciar la suya: por modelo (`CONTEXT`, de lo que lista LM
+//! Studio) o una para todo el nodo (`MAX_CONTEXT`, `--max-context` del nodo). La petición sólo
+//! va a nodos en cuya ventana cabe o que no anuncian ninguna; si no cabe en ninguno de la pool,
+//! se rechaza con 422 en lugar de reenviarla a que falle.
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::balancer::{AppState, NodeHealth, NodeInfo};
+use crate::models;
+
+/// Margen por defecto sobre la ventana, en porcentaje, antes de rechazar.
+pub const DEFAULT_CONTEXT_HEADROOM_PERCENT: u64 = 10;
+
+/// Tokens que se suman por mensaje por el formato del chat (rol, separadores).
+const MESSAGE_OVERHEAD_TOKENS: u64 = 4;
+
+#[derive(Debug)]
+pub struct ContextConfigError(String);
+
+impl fmt::Display for ContextConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ContextConfigError {}
+
+/// Ventanas de contexto configuradas por pool y modelo, y margen de tolerancia.
+#[derive(Default)]
+pub struct ContextLimits {
+    configured: HashMap<(String, String), u64>,
+    headroom_percent: u64,
+}
+
+impl ContextLimits {
+    /// Interpreta entradas `pool:modelo=tokens` de la línea de comandos.
+    pub fn new(pools: &[&str], entries: &[String], headroom_percent: u64) -> Result<Self, ContextConfigError> {
+        let mut configured = HashMap::new();
+        for entry in entries {
+            let parsed = entry
+                .split_once(':')
+                .and_then(|(pool, rest)| rest.rsplit_once('=').map(|(model, tokens)| (pool.trim(), model.trim(), tokens.trim())))
+                .filter(|(pool, model, _)| !pool.is_empty() && !model.is_empty());
+            let Some((pool, model, tokens)) = parsed else {
+                return Err(ContextConfigError(format!("Entrada de ventana de contexto inválida '{}': se esperaba <pool>:<modelo>=<tokens>", entry)));
+            };
+            if !pools.contains(&pool) {
+                return Err(ContextConfigError(format!("Pool desconocida '{}' en --context-window", pool)));
+            }
+            let tokens = tokens
+                .parse()
+                .ok()
+                .filter(|tokens| *tokens > 0)
+                .ok_or_else(|| ContextConfigError(format!("Número de tokens inválido en --context-window '{}'", entry)))?;
+            configured.insert((pool.to_string(), model.to_string()), tokens);
+        }
+        Ok(Self { configured, headroom_percent })
+    }
+
+    pub fn len(&self) -> usize {
+        self.configured.len()
+    }
+
+    /// Tokens que se toleran con una ventana de `window`.
+    fn allowed(&self, window: u64) -> u64 {
+        window + window * self.headroom_percent / 100
+    }
+
+    /// Si `needed` tokens caben en el nodo. Un nodo sin ventana anunciada se supone que sí.
+    pub fn node_fits(&self, info: &NodeInfo, model: Option<&str>, needed: u64) -> bool {
+        node_window(info, model).is_none_or(|window| needed <= self.allowed(window))
+    }
+}
+
+/// Ventana del nodo para `model`: la que anuncia para ese modelo o, si no, la de todo el nodo.
+fn node_window(info: &NodeInfo, model: Option<&str>) -> Option<u64> {
+    model.and_then(|model| info.context_windows.get(model).copied()).or(info.max_context)
+}
+
+/// Petición que no cabe en una ventana de contexto.
+#[derive(Debug)]
+pub struct ContextExceeded {
+    pub model: Option<String>,
+    pub estimated_prompt_tokens: u64,
+    pub max_tokens: u64,
+    pub context_window: u64,
+    pub allowed: u64,
+}
+
+/// Por qué se rechaza una petición que no cabe.
+#[derive(Debug)]
+pub enum ContextRejection {
+    /// Supera la ventana configurada del modelo (`--context-window`).
+    Model(ContextExceeded),
+    /// Ningún nodo de la pool anuncia una ventana en la que quepa.
+    Nodes(ContextExceeded),
+}
+
+impl fmt::Display for ContextRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContextRejection::Model(exceeded) => write!(
+                f,
+                "El prompt estimado (~{} tokens, estimación aproximada) más max_tokens ({}) supera la ventana de contexto de {} ({} tokens; se toleran hasta {}).",
+                exceeded.estimated_prompt_tokens,
+                exceeded.max_tokens,
+                exceeded.model.as_deref().unwrap_or_default(),
+                exceeded.context_window,
+                exceeded.allowed
+            ),
+            ContextRejection::Nodes(exceeded) => write!(
+                f,
+                "El prompt estimado (~{} tokens, estimación aproximada) más max_tokens ({}) no cabe en ningún nodo que pueda atender {}: la mayor ventana de contexto anunciada es de {} tokens (se toleran hasta {}).",
+                exceeded.estimated_prompt_tokens,
+                exceeded.max_tokens,
+                exceeded.model.as_deref().map_or_else(|| "la petición".to_string(), |model| model.to_string()),
+                exceeded.context_window,
+                exceeded.allowed
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "tiktoken")]
+pub(crate) fn text_tokens(text: &str) -> u64 {
+    tiktoken_rs::cl100k_base_singleton().encode_ordinary(text).len() as u64
+}
+
+#[cfg(not(feature = "tiktoken"))]
+fn text_tokens(text: &str) -> u64 {
+    text.chars().count().div_ceil(4) as u64
+}
+
+/// Estimación aproximada de los tokens del prompt: texto de los mensajes y definiciones de `tools`.
+pub fn estimate_prompt_tokens(request: &Value) -> u64 {
+    let messages = request.get("messages").and_then(Value::as_array).map(Vec::as_slice).unwrap_or_default();
+    let mut tokens = 0;
+    for message in messages {
+        tokens += MESSAGE_OVERHEAD_TOKENS;
+        match message.get("content") {
+            Some(Value::String(text)) => tokens += text_tokens(text),
+            // Imágenes, audio, etc. no se pueden estimar por texto: sólo cuentan las partes `text`.
+            Some(Value::Array(parts)) => {
+                tokens += parts
+                    .iter()
+                    .filter(|part| part.get("type").and_then(Value::as_str) == Some("text"))
+                    .filter_map(|part| part.get("text").and_then(Value::as_str))
+                    .map(text_tokens)
+                    .sum::<u64>();
+            }
+            _ => {}
+        }
+        if let Some(tool_calls) = message.get("tool_calls") {
+            tokens += text_tokens(&tool_calls.to_string());
+        }
+    }
+    if let Some(tools) = request.get("tools") {
+        tokens += text_tokens(&tools.to_string());
+    }
+    tokens
+}
+
+/// Tamaño estimado de una petición de chat: el prompt y lo que pide generar como mucho.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RequestSize {
+    pub prompt_tokens: u64,
+    pub max_tokens: u64,
+}
+
+impl RequestSize {
+    /// Tokens que tiene que admitir la ventana.
+    pub fn needed(self) -> u64 {
+        self.prompt_tokens + self.max_tokens
+    }
+}
+
+/// Tamaño de la petición: prompt estimado (`estimate_prompt_tokens`) y `max_tokens` (o
+/// `max_completion_tokens`), 0 si no pide un tope.
+pub fn request_size(request: &Value) -> RequestSize {
+    let max_tokens = ["max_tokens", "max_completion_tokens"]
+        .iter()
+        .find_map(|field| request.get(*field).and_then(Value::as_u64))
+        .unwrap_or(0);
+    RequestSize { prompt_tokens: estimate_prompt_tokens(request), max_tokens }
+}
+
+/// Comprueba que el prompt estimado más `max_tokens` quepa en la ventana configurada del modelo
+/// y en la de algún nodo de la pool que pueda atenderlo. Devuelve esos tokens, que son los que
+/// tiene que admitir el nodo elegido (`node_fits`).
+pub fn check(state: &AppState, service: &str, body: &[u8]) -> Result<Option<u64>, ContextRejection> {
+    let Ok(request) = serde_json::from_slice::<Value>(body) else {
+        return Ok(None);
+    };
+    let model = request.get("model").and_then(Value::as_str);
+    let size = request_size(&request);
+    let needed = size.needed();
+    let limits = &state.context_limits;
+    let exceeded = |window: u64| ContextExceeded {
+        model: model.map(str::to_string),
+        estimated_prompt_tokens: size.prompt_tokens,
+        max_tokens: size.max_tokens,
+        context_window: window,
+        allowed: limits.allowed(window),
+    };
+
+    let configured = model.and_then(|model| limits.configured.get(&(service.to_string(), model.to_string())).copied());
+    if let Some(window) = configured.filter(|window| needed > limits.allowed(*window)) {
+        return Err(ContextRejection::Model(exceeded(window)));
+    }
+    let Some(lock) = state.pool(service) else {
+        return Ok(Some(needed));
+    };
+    let nodes = lock.read().unwrap();
+    let advertised = models::advertised(&nodes);
+    let windows: Vec<Option<u64>> = nodes
+        .values()
+        .filter(|info| !matches!(info.state, NodeHealth::Failed(_) | NodeHealth::Draining(_)) && models::eligible(advertised, info, model))
+        .map(|info| node_window(info, model))
+        .collect();
+    // Sin nodos que puedan atenderla, la petición espera en la cola como cualquier otra.
+    if windows.is_empty() || windows.iter().any(|window| window.is_none_or(|window| needed <= limits.allowed(window))) {
+        return Ok(Some(needed));
+    }
+    Err(ContextRejection::Nodes(exceeded(windows.into_iter().flatten().max().unwrap_or_default())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use serde_json::json;
+    use std::time::{Duration, Instant};
+
+    use crate::balancer::{self, LMSTUDIO};
+    use crate::history::TransitionCause;
+    use crate::sessions::NODE_ID_HEADER;
+    use crate::testing;
+
+    #[test]
+    fn prompt_counts_text_parts_tool_calls_and_tools() {
+        let request = json!({
+            "messages": [
+                { "role": "system", "content": "Eres un asistente." },
+                { "role": "user", "content": [
+                    { "type": "text", "text": "¿Qué hay en la imagen?" },
+                    { "type": "image_url", "image_url": { "url": "data:image/png;base64,AAAAAAAAAAAAAAAAAAAAAAAAAAAA" } },
+                ] },
+                { "role": "assistant", "tool_calls": [{ "id": "call_1", "type": "function", "function": { "name": "f", "arguments": "{}" } }] },
+                { "role": "tool", "content": null },
+            ],
+            "tools": [{ "type": "function", "function": { "name": "f" } }],
+        });
+        let expected = 4 * MESSAGE_OVERHEAD_TOKENS
+            + text_tokens("Eres un asistente.")
+            + text_tokens("¿Qué hay en la imagen?")
+            + text_tokens(&request["messages"][2]["tool_calls"].to_string())
+            + text_tokens(&request["tools"].to_string());
+        assert_eq!(estimate_prompt_tokens(&request), expected);
+        assert_eq!(estimate_prompt_tokens(&json!({})), 0);
+        assert_eq!(estimate_prompt_tokens(&json!({ "messages": "hola" })), 0);
+    }
+
+    #[cfg(not(feature = "tiktoken"))]
+    #[test]
+    fn without_a_tokenizer_four_characters_are_one_token() {
+        assert_eq!(text_tokens(""), 0);
+        assert_eq!(text_tokens("abcd"), 1);
+        assert_eq!(text_tokens("abcde"), 2);
+        // Caracteres, no bytes.
+        assert_eq!(text_tokens("ñññññññ"), 2);
+        let request = json!({ "messages": [{ "role": "user", "content": "x".repeat(400) }], "max_tokens": 100 });
+        assert_eq!(request_size(&request), RequestSize { prompt_tokens: 104, max_tokens: 100 });
+    }
+
+    #[test]
+    fn max_tokens_falls_back_to_max_completion_tokens() {
+        let size = |request: Value| request_size(&request).max_tokens;
+        assert_eq!(size(json!({ "max_tokens": 10, "max_completion_tokens": 20 })), 10);
+        assert_eq!(size(json!({ "max_completion_tokens": 20 })), 20);
+        assert_eq!(size(json!({ "max_tokens": "mucho" })), 0);
+        assert_eq!(size(json!({})), 0);
+        assert_eq!(RequestSize { prompt_tokens: 7, max_tokens: 3 }.needed(), 10);
+    }
+
+    #[test]
+    fn configured_windows_are_validated() {
+        let pools = ["lmstudio", "ollama"];
+        let limits = ContextLimits::new(&pools, &["lmstudio:org/model:q4=8192".to_string()], 10).unwrap();
+        assert_eq!(limits.configured[&("lmstudio".to_string(), "org/model:q4".to_string())], 8192);
+        assert_eq!(limits.allowed(8192), 9011);
+        for entry in ["lmstudio=8192", "lmstudio:m", ":m=8192", "lmstudio:=8192", "vllm:m=8192", "lmstudio:m=0", "lmstudio:m=mucho"] {
+            assert!(ContextLimits::new(&pools, &[entry.to_string()], 10).is_err(), "{}", entry);
+        }
+    }
+
+    #[test]
+    fn nodes_fit_by_model_window_then_node_window() {
+        let state = testing::state(&[]);
+        for id in ["small", "big", "silent"] {
+            testing::announce(&state, "lmstudio", id, "http://127.0.0.1:1/");
+        }
+        state.set_node_max_context(&LMSTUDIO, &testing::node_id("small"), 8192);
+        state.set_node_context_windows(&LMSTUDIO, &testing::node_id("small"), vec![("long".to_string(), 32768)]);
+        state.set_node_max_context(&LMSTUDIO, &testing::node_id("big"), 131072);
+        let limits = ContextLimits::new(&["lmstudio"], &[], 0).unwrap();
+        let nodes = state.lm_studio_nodes.read().unwrap();
+        let fits = |id: &str, model: Option<&str>, needed: u64| limits.node_fits(&nodes[id], model, needed);
+        assert!(fits("small", None, 8192));
+        assert!(!fits("small", None, 8193));
+        assert!(fits("small", Some("long"), 32768));
+        assert!(!fits("small", Some("other"), 8193));
+        assert!(fits("big", Some("long"), 100_000));
+        // Un nodo que no anuncia ventana se supone que cabe.
+        assert!(fits("silent", None, u64::MAX));
+    }
+
+    /// Un prompt de unos 10 000 tokens (20 000 con `tiktoken`): no cabe en 8k y sí en 128k.
+    fn long_chat(model: &str, max_tokens: u64) -> TestRequest {
+        let prompt = "x ".repeat(20_000);
+        TestRequest::post().uri("/lmstudio").set_json(json!({ "model": model, "max_tokens": max_tokens, "messages": [{ "role": "user", "content": prompt }] }))
+    }
+
+    #[actix_web::test]
+    async fn long_prompts_go_to_nodes_whose_window_fits() {
+        let state = testing::state(&["--node-selection", "first-available", "--context-window", "lmstudio:tiny=4096"]);
+        for (id, window) in [("a-small", 8192), ("b-big", 131_072)] {
+            testing::announce(&state, "lmstudio", id, &testing::chat_node(Duration::ZERO));
+            state.set_node_max_context(&LMSTUDIO, &testing::node_id(id), window);
+        }
+        let app = init_service(balancer::app(state.clone())).await;
+
+        for _ in 0..3 {
+            let res = call_service(&app, long_chat("m", 100).to_request()).await;
+            assert_eq!(res.status(), 200);
+            assert_eq!(res.headers().get(NODE_ID_HEADER).unwrap(), "b-big");
+        }
+
+        // La ventana configurada del modelo se comprueba antes que las de los nodos.
+        let res = call_service(&app, long_chat("tiny", 100).to_request()).await;
+        assert_eq!(res.status(), 400);
+
+        // Sin el nodo grande no cabe en ninguno: 422 en lugar de reenviarla.
+        state.update_node_state(&LMSTUDIO, &testing::node_id("b-big"), NodeHealth::Failed(Instant::now()), TransitionCause::HealthCheck);
+        let res = call_service(&app, long_chat("m", 100).to_request()).await;
+        assert_eq!(res.status(), 422);
+        let body: Value = read_body_json(res).await;
+        assert_eq!(body["error"]["type"], "context_length_exceeded", "{}", body);
+        let message = body["error"]["message"].as_str().unwrap();
+        assert!(message.contains("8192 tokens") && message.contains("9011"), "{}", message);
+
+        // Tampoco cabe en el grande si pide generar más de lo que le queda.
+        state.update_node_state(&LMSTUDIO, &testing::node_id("b-big"), NodeHealth::Available, TransitionCause::HealthCheck);
+        assert_eq!(call_service(&app, long_chat("m", 140_000).to_request()).await.status(), 422);
+        assert_eq!(state.metrics.rejected_over_context.load(std::sync::atomic::Ordering::Relaxed), 3);
+    }
+}
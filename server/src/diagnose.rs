@@ -0,0 +1,249 @@
+// src/diagnose.rs
+//! Diagnóstico de conectividad a un nodo desde el balanceador (`POST /nodes/{id}/diagnose`).
+//!
+//! Repite los tres pasos manuales de siempre y los devuelve juntos en un informe JSON, que
+//! también se publica como evento `node_diagnosed`:
+//!
+//! 1. conexión TCP al `host:puerto` del nodo, con su tiempo;
+//! 2. `GET` de la ruta de modelos (la misma del health check), con estado y latencia;
+//! 3. una completion mínima (un token, en streaming) con el tiempo hasta el primer byte;
+//!
+//! más las cinco últimas categorías de error registradas para el nodo.
+//!
+//! Usa un cliente propio con timeouts cortos y no ocupa el nodo: la completion compite con las
+//! peticiones en curso como una más. Para no cargar un nodo por accidente, cada nodo admite un
+//! diagnóstico cada `--diagnose-interval-secs`.
+use actix_web::{post, web, HttpResponse, Responder};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use crate::balancer::AppState;
+use crate::events::BalancerEvent;
+use crate::health;
+use crate::ids::ServiceUrl;
+
+pub const DEFAULT_DIAGNOSE_INTERVAL_SECS: u64 = 30;
+
+/// Tiempo máximo de cada paso.
+pub const STEP_TIMEOUT: Duration = Duration::from_secs(5);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Errores recientes que incluye el informe.
+const RECENT_ERRORS: usize = 5;
+
+/// Cliente y límite por nodo de los diagnósticos.
+pub struct Diagnostics {
+    client: reqwest::Client,
+    min_interval: Duration,
+    last_run: Mutex<HashMap<String, Instant>>,
+}
+
+impl Diagnostics {
+    pub fn new(min_interval: Duration) -> Self {
+        // Sin el resolvedor con caché ni los timeouts del cliente de reenvío: se mide la red tal cual.
+        let client = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(STEP_TIMEOUT)
+            .build()
+            .expect("cliente HTTP de diagnóstico");
+        Self { client, min_interval, last_run: Mutex::new(HashMap::new()) }
+    }
+
+    /// Cliente de timeouts cortos, también para las pruebas de capacidades (`reprobe`).
+    pub fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    /// Reserva el turno del nodo. `Err` con lo que falta para el siguiente si es pronto.
+    fn try_start(&self, unique_node_id: &str) -> Result<(), Duration> {
+        let mut last_run = self.last_run.lock().unwrap();
+        let now = Instant::now();
+        if let Some(wait) = last_run
+            .get(unique_node_id)
+            .map(|last| self.min_interval.saturating_sub(now.duration_since(*last)))
+            .filter(|wait| !wait.is_zero())
+        {
+            return Err(wait);
+        }
+        // Los turnos ya vencidos no sirven de nada: así el mapa no crece con nodos dados de baja.
+        last_run.retain(|_, last| now.duration_since(*last) < self.min_interval);
+        last_run.insert(unique_node_id.to_string(), now);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct TcpStep {
+    address: Option<String>,
+    ok: bool,
+    connect_ms: Option<u64>,
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct HttpStep {
+    url: String,
+    status: Option<u16>,
+    latency_ms: Option<u64>,
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct CompletionStep {
+    url: String,
+    model: Option<String>,
+    status: Option<u16>,
+    /// Hasta el primer fragmento del cuerpo, no sólo las cabeceras.
+    ttfb_ms: Option<u64>,
+    total_ms: Option<u64>,
+    error: Option<String>,
+}
+
+fn elapsed_ms(since: Instant) -> u64 {
+    since.elapsed().as_millis() as u64
+}
+
+async fn tcp_step(service_url: &ServiceUrl) -> TcpStep {
+    let Some(address) = service_url.address() else {
+        return TcpStep { address: None, ok: false, connect_ms: None, error: Some("La URL no tiene host:puerto".to_string()) };
+    };
+    let started = Instant::now();
+    let result = timeout(STEP_TIMEOUT, TcpStream::connect(address.as_str())).await;
+    let (ok, error) = match result {
+        Ok(Ok(_)) => (true, None),
+        Ok(Err(e)) => (false, Some(e.to_string())),
+        Err(_) => (false, Some(format!("timeout tras {}ms", STEP_TIMEOUT.as_millis()))),
+    };
+    TcpStep { address: Some(address), ok, connect_ms: ok.then(|| elapsed_ms(started)), error }
+}
+
+async fn http_step(client: &reqwest::Client, service: &str, service_url: &ServiceUrl) -> HttpStep {
+    let url = health::probe_url(service, service_url);
+    let started = Instant::now();
+    match client.get(url.as_str()).send().await {
+        Ok(response) => HttpStep {
+            url: url.to_string(),
+            status: Some(response.status().as_u16()),
+            latency_ms: Some(elapsed_ms(started)),
+            error: None,
+        },
+        Err(e) => HttpStep { url: url.to_string(), status: None, latency_ms: None, error: Some(e.to_string()) },
+    }
+}
+
+/// Petición de un solo token. Lleva el tope en los dos dialectos (OpenAI y Ollama nativo):
+/// cada backend ignora el que no entiende.
+fn completion_request(model: &str) -> serde_json::Value {
+    json!({
+        "model": model,
+        "messages": [{ "role": "user", "content": "ping" }],
+        "max_tokens": 1,
+        "options": { "num_predict": 1 },
+        "stream": true,
+    })
+}
+
+async fn completion_step(client: &reqwest::Client, service_url: &ServiceUrl, model: Option<String>) -> CompletionStep {
+    let mut step = CompletionStep { url: service_url.to_string(), model, status: None, ttfb_ms: None, total_ms: None, error: None };
+    let Some(model) = &step.model else {
+        step.error = Some("El nodo no anuncia modelos; indica uno con {\"model\": ...}".to_string());
+        return step;
+    };
+    let started = Instant::now();
+    let mut response = match client.post(service_url.as_str()).json(&completion_request(model)).send().await {
+        Ok(response) => response,
+        Err(e) => {
+            step.error = Some(e.to_string());
+            return step;
+        }
+    };
+    step.status = Some(response.status().as_u16());
+    loop {
+        match response.chunk().await {
+            Ok(Some(_)) => {
+                step.ttfb_ms.get_or_insert_with(|| elapsed_ms(started));
+            }
+            Ok(None) => break,
+            Err(e) => {
+                step.error = Some(e.to_string());
+                break;
+            }
+        }
+    }
+    step.total_ms = Some(elapsed_ms(started));
+    step
+}
+
+#[derive(Deserialize, Default)]
+struct DiagnoseRequest {
+    /// Modelo de la completion; por defecto, el primero que anuncia el nodo.
+    model: Option<String>,
+}
+
+/// Lanza el diagnóstico del nodo y devuelve el informe. 429 si se diagnosticó hace poco.
+#[post("/nodes/{id}/diagnose")]
+async fn diagnose_handler(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    body: Option<web::Json<DiagnoseRequest>>,
+) -> impl Responder {
+    let unique_node_id = path.into_inner();
+    let node = state.pools().into_iter().find_map(|(_, service, lock)| {
+        let nodes = lock.read().unwrap();
+        nodes
+            .get(unique_node_id.as_str())
+            .map(|info| (service, info.service_url.clone(), info.state.label(), info.models.first().cloned()))
+    });
+    let Some((service, service_url, node_state, first_model)) = node else {
+        return HttpResponse::NotFound().json(json!({ "error": format!("Nodo {} desconocido", unique_node_id) }));
+    };
+    if let Err(wait) = state.diagnostics.try_start(&unique_node_id) {
+        let retry_after = wait.as_secs().max(1);
+        warn!("Diagnóstico: Nodo {} diagnosticado hace poco; reintenta en {}s.", unique_node_id, retry_after);
+        return HttpResponse::TooManyRequests()
+            .insert_header(("Retry-After", retry_after.to_string()))
+            .json(json!({ "error": format!("El nodo {} ya se diagnosticó hace poco.", unique_node_id), "retry_after_secs": retry_after }));
+    }
+
+    info!("Diagnóstico: Comprobando el nodo {} ({}) en {}.", unique_node_id, service, service_url);
+    let started = Instant::now();
+    let client = &state.diagnostics.client;
+    let model = body.and_then(|body| body.into_inner().model).or(first_model);
+    let tcp = tcp_step(&service_url).await;
+    let http = http_step(client, service, &service_url).await;
+    let completion = completion_step(client, &service_url, model).await;
+    let recent_errors: Vec<_> = {
+        let errors = state.upstream_errors.read().unwrap().recent(&unique_node_id);
+        errors
+            .iter()
+            .rev()
+            .take(RECENT_ERRORS)
+            .map(|error| json!({ "timestamp": error.timestamp, "category": error.category, "message": error.message }))
+            .collect()
+    };
+
+    let report = json!({
+        "node_id": unique_node_id,
+        "service": service,
+        "service_url": service_url,
+        "state": node_state,
+        "timestamp": chrono::Local::now().to_rfc3339(),
+        "elapsed_ms": elapsed_ms(started),
+        "tcp": tcp,
+        "http": http,
+        "completion": completion,
+        "recent_errors": recent_errors,
+    });
+    state.events.publish(BalancerEvent::NodeDiagnosed {
+        node_id: unique_node_id,
+        service: service.to_string(),
+        report: report.clone(),
+    });
+    HttpResponse::Ok().json(report)
+}
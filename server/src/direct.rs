@@ -0,0 +1,206 @@
+// src/direct.rs
+//! Modo directo de las pools de un solo nodo (`--direct-pool lmstudio`).
+//!
+//! En la instalación casera de un nodo, la cola y el ciclo ocupar/liberar sólo añaden latencia
+//! y maneras de fallar. En una pool directa con un único nodo registrado, que no está caído ni
+//! drenándose y puede atender la petición, ésta se reenvía en el acto: sin cola, sin turno de
+//! modelo y sin pasar el nodo a Busy, así que varias comparten el nodo a la vez y el backend
+//! decide cuántas atiende. Quedan el tiempo máximo de respuesta, los errores de siempre, la
+//! salud (un error marca el nodo como caído) y las estadísticas (`dispatched`, `in_flight`).
+//!
+//! El modo se decide en cada petición y se apaga solo en cuanto:
+//!
+//! - se registra un segundo nodo en la pool;
+//! - el nodo tiene un ritmo de despacho (`--dispatch-rate`, `PATCH /nodes/{id}/dispatch-rate` o
+//!   el del calentamiento) o un tope por minuto (`max_rpm`), está en su fase canaria, o la pool
+//!   reserva nodos con `--reserve-interactive`;
+//! - la petición puede desbordar a otra pool, va dirigida con `X-Target-Node` o es del carril
+//!   lento.
+//!
+//! Las peticiones directas en curso terminan sin tocar el estado del nodo, así que no liberan un
+//! nodo que entretanto haya ocupado una petición de la cola; al volver a quedar un nodo, las
+//! nuevas vuelven a ser directas.
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::fmt;
+
+#[derive(Debug)]
+pub struct DirectConfigError(String);
+
+impl fmt::Display for DirectConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for DirectConfigError {}
+
+/// Pools en modo directo (`--direct-pool`).
+#[derive(Default)]
+pub struct DirectPools {
+    pools: BTreeSet<String>,
+}
+
+impl DirectPools {
+    pub fn new(pools: &[&str], entries: &[String]) -> Result<Self, DirectConfigError> {
+        let mut direct = BTreeSet::new();
+        for entry in entries {
+            let pool = entry.trim();
+            if !pools.contains(&pool) {
+                return Err(DirectConfigError(format!("Pool desconocida '{}' en --direct-pool", pool)));
+            }
+            direct.insert(pool.to_string());
+        }
+        Ok(Self { pools: direct })
+    }
+
+    pub fn is_direct(&self, service: &str) -> bool {
+        self.pools.contains(service)
+    }
+
+    pub fn pools(&self) -> impl Iterator<Item = &str> {
+        self.pools.iter().map(String::as_str)
+    }
+
+    /// Configuración para `/config`.
+    pub fn describe(&self) -> Value {
+        json!({ "pools": self.pools })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::web;
+    use futures_util::future::join_all;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use crate::balancer::{self, LMSTUDIO};
+    use crate::testing;
+
+    #[test]
+    fn only_known_pools_can_be_direct() {
+        let direct = DirectPools::new(&["lmstudio", "ollama"], &[" lmstudio ".to_string()]).unwrap();
+        assert!(direct.is_direct("lmstudio"));
+        assert!(!direct.is_direct("ollama"));
+        assert!(DirectPools::new(&["lmstudio", "ollama"], &["vllm".to_string()]).is_err());
+    }
+
+    /// Peticiones que está atendiendo un nodo falso y el máximo que llegó a atender a la vez.
+    #[derive(Default)]
+    struct Concurrency {
+        current: AtomicUsize,
+        peak: AtomicUsize,
+    }
+
+    impl Concurrency {
+        fn peak(&self) -> usize {
+            self.peak.swap(0, Ordering::SeqCst)
+        }
+    }
+
+    /// Nodo que tarda 300 ms en contestar y anota cuántas peticiones atiende a la vez.
+    fn node() -> (String, Arc<Concurrency>) {
+        let concurrency = Arc::new(Concurrency::default());
+        let seen = concurrency.clone();
+        let url = testing::backend(move |cfg| {
+            let concurrency = concurrency.clone();
+            cfg.default_service(web::to(move || {
+                let concurrency = concurrency.clone();
+                async move {
+                    let now = concurrency.current.fetch_add(1, Ordering::SeqCst) + 1;
+                    concurrency.peak.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(300)).await;
+                    concurrency.current.fetch_sub(1, Ordering::SeqCst);
+                    testing::chat_reply()
+                }
+            }));
+        });
+        (url, seen)
+    }
+
+    fn direct_requests(state: &crate::balancer::AppState) -> u64 {
+        state.metrics.direct_requests.load(Ordering::Relaxed)
+    }
+
+    #[actix_web::test]
+    async fn direct_mode_follows_the_number_of_nodes_under_load() {
+        let state = testing::state(&["--direct-pool", "lmstudio"]);
+        let (url, box1) = node();
+        testing::announce(&state, "lmstudio", "box1", &url);
+        let app = init_service(balancer::app(state.clone())).await;
+        // Manda `count` peticiones a la vez y devuelve cuánto tardó la última.
+        let burst = |count: usize| {
+            let app = &app;
+            async move {
+                let start = Instant::now();
+                for res in join_all((0..count).map(|_| call_service(app, testing::chat().to_request()))).await {
+                    assert_eq!(res.status(), 200);
+                }
+                start.elapsed()
+            }
+        };
+
+        // Un solo nodo: todas a la vez, sin ocuparlo.
+        assert!(burst(4).await < Duration::from_millis(600));
+        assert_eq!(box1.peak(), 4);
+        assert_eq!(direct_requests(&state), 4);
+        assert_eq!(testing::node_state(&state, "lmstudio", "box1"), "available");
+
+        // Un segundo nodo mientras hay peticiones directas en curso: éstas terminan y las nuevas
+        // pasan por la cola, una por nodo.
+        let (url, box2) = node();
+        let (in_flight, later) = futures_util::join!(burst(3), async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            testing::announce(&state, "lmstudio", "box2", &url);
+            burst(4).await
+        });
+        assert!(in_flight < Duration::from_millis(600));
+        assert!(later >= Duration::from_millis(600), "{:?}", later);
+        assert_eq!(direct_requests(&state), 7);
+        // box1 pudo atender a la vez una de la cola y las directas que seguían en curso.
+        box1.peak();
+        assert_eq!(box2.peak(), 1);
+        assert!(burst(2).await < Duration::from_millis(600));
+        assert_eq!((box1.peak(), box2.peak()), (1, 1));
+        assert_eq!(direct_requests(&state), 7);
+
+        // Al irse, vuelve el modo directo.
+        state.deregister_node(&LMSTUDIO, &testing::node_id("box2"));
+        assert!(burst(4).await < Duration::from_millis(600));
+        assert_eq!(box1.peak(), 4);
+        assert_eq!(direct_requests(&state), 11);
+    }
+
+    #[actix_web::test]
+    async fn a_dispatch_rate_turns_direct_mode_off_and_back_on() {
+        let state = testing::state(&["--direct-pool", "lmstudio", "--admin-token", "secreto"]);
+        let (url, box1) = node();
+        testing::announce(&state, "lmstudio", "box1", &url);
+        let app = init_service(balancer::app(state.clone())).await;
+        let rate = |body: serde_json::Value| {
+            TestRequest::patch().uri("/nodes/box1/dispatch-rate").insert_header(("Authorization", "Bearer secreto")).set_json(body).to_request()
+        };
+        let burst = |count: usize| {
+            let app = &app;
+            async move {
+                for res in join_all((0..count).map(|_| call_service(app, testing::chat().to_request()))).await {
+                    assert_eq!(res.status(), 200);
+                }
+            }
+        };
+
+        assert_eq!(call_service(&app, rate(serde_json::json!({ "per_second": 100, "burst": 10 }))).await.status(), 200);
+        burst(3).await;
+        assert_eq!(box1.peak(), 1);
+        assert_eq!(direct_requests(&state), 0);
+
+        assert_eq!(call_service(&app, rate(serde_json::Value::Null)).await.status(), 200);
+        burst(3).await;
+        assert_eq!(box1.peak(), 3);
+        assert_eq!(direct_requests(&state), 3);
+    }
+}
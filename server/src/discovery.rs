@@ -0,0 +1,734 @@
+// src/discovery.rs
+//! Formato de los datagramas de descubrimiento compartido por el nodo y el balanceador.
+//!
+//! - `DISCOVER,<svc>,<id>,<url>`: anuncio principal, siempre en un único datagrama.
+//! - `MODELS,<svc>,<id>,<ronda>,<seq>,<total>,<m1>,<m2>,...`: lista de modelos repartida en
+//!   tantos datagramas como haga falta para no superar el presupuesto de bytes, y como mucho
+//!   `MAX_MODELS_DATAGRAMS`.
+//! - `VERSION,<svc>,<id>,<versión>`: versión del binario del nodo. Va aparte para que los
+//!   balanceadores que no lo conocen sigan entendiendo `DISCOVER`.
+//! - `BACKEND,<svc>,<id>,<versión>`: versión que informa el backend (hoy sólo Ollama, con
+//!   `/api/version`). Si cambia en un nodo conocido, el balanceador vuelve a probar sus
+//!   capacidades.
+//! - `CONTEXT,<svc>,<id>,<modelo>=<tokens>,...`: ventana de contexto de los modelos que la
+//!   conocen. Cada datagrama es independiente; el balanceador los va combinando.
+//! - `MAX_CONTEXT,<svc>,<id>,<tokens>`: ventana de contexto de todo el nodo, para los modelos
+//!   sin ventana propia en `CONTEXT` (p.ej. por la memoria de la máquina).
+//! - `STORAGE,<svc>,<id>,<libres>,<total>,<ficheros>,<bytes>`: espacio del volumen de modelos
+//!   (bytes libres y totales) y ficheros de modelos guardados con lo que ocupan.
+//! - `WEIGHT,<svc>,<id>,<peso>`: peso del nodo frente a los demás de su pool (entero >= 1). Un
+//!   nodo que no lo envía pesa 1.
+//! - `MAX_RPM,<svc>,<id>,<peticiones>`: tope de peticiones por minuto que acepta el nodo (`rpm`).
+//!   Un nodo que no lo envía no tiene tope propio.
+//! - `PLATFORM,<svc>,<id>,os=<os>,arch=<arch>,accelerator=<acc>,memory=<tipo>[,memory_bytes=<n>]`:
+//!   plataforma del nodo (`platform`). Los campos que faltan se toman como `unknown`.
+//! - `DOMAIN,<svc>,<id>,<dominio>`: dominio de fallo del nodo (`domains`): los nodos que caen
+//!   juntos lo comparten. Un nodo que no lo envía no comparte dominio con nadie.
+//! - `TAGS,<svc>,<id>,<clave>=<valor>,...`: etiquetas del nodo (`tags`), p.ej. `region=lab2`.
+//!   Cada uno sustituye a las anteriores; las entradas mal formadas se ignoran.
+//! - `CAPS,<svc>,<id>,<capacidad>,...`: capacidades del backend (p.ej. `tool-calling`). Sin
+//!   ninguna tras el ID, el nodo no tiene ninguna; un nodo que nunca lo envía tampoco.
+//! - `DRAINING,<svc>,<id>,<segundos>`: el nodo se apagará dentro de esos segundos. El
+//!   balanceador deja de darle peticiones nuevas y lo saca al vencer el plazo, o antes si llega
+//!   `GOODBYE`. Un `DISCOVER` antes del plazo cancela el drenaje. Un plazo de más de un día
+//!   (`MAX_DRAIN_LEAD_SECS`) no es válido.
+//! - `GOODBYE,<svc>,<id>`: el nodo se apaga ya; el balanceador lo saca sin esperar a que caduque.
+//! - `SPOOLED,<svc>,<id>,<caída>,<peticiones>`: peticiones que el proxy del nodo atendió contra
+//!   su backend mientras el balanceador no respondía (`spool`). `<caída>` identifica la caída
+//!   (segundos Unix de su inicio) y `<peticiones>` es el total acumulado en ella: el nodo lo
+//!   repite en varios anuncios y el balanceador sólo suma la diferencia con el último recibido.
+//! - `ACK,<id>`: respuesta opcional del balanceador a un `DISCOVER`, enviada al origen del
+//!   anuncio. Los nodos sólo aplican backoff si alguna vez recibieron uno.
+//! - `DISCOVER,<svc>,<ip:puerto>`: formato antiguo de tres campos, sin ID ni ruta. Se acepta
+//!   por compatibilidad con nodos sin actualizar.
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::domains;
+use crate::platform::{self, Platform};
+use crate::tags::{self, NodeTags};
+use crate::storage::StorageReport;
+
+/// Tamaño máximo por defecto de un datagrama de anuncio; por debajo del MTU típico de Ethernet.
+pub const DEFAULT_MAX_DATAGRAM_BYTES: usize = 1200;
+
+/// Plazo máximo de un `DRAINING`: el datagrama no está autenticado y un plazo enorme no es un apagado.
+pub const MAX_DRAIN_LEAD_SECS: u64 = 24 * 60 * 60;
+
+/// Datagramas `MODELS` de una ronda como mucho. Un `total` mayor no es válido: el balanceador
+/// reserva sitio para todos los fragmentos al recibir el primero.
+pub const MAX_MODELS_DATAGRAMS: usize = 1000;
+
+/// Listas de modelos incompletas que se guardan a la vez de una misma dirección; al llegar otra,
+/// se descarta la más antigua.
+const MAX_PENDING_MODELS_PER_SENDER: usize = 4;
+
+/// Tiempo máximo que se guarda una lista de modelos incompleta antes de descartarla.
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub fn discover_message(service: &str, unique_node_id: &str, service_url: &str) -> String {
+    format!("DISCOVER,{},{},{}", service, unique_node_id, service_url)
+}
+
+pub fn ack_message(unique_node_id: &str) -> String {
+    format!("ACK,{}", unique_node_id)
+}
+
+/// Interpreta un datagrama `ACK` y devuelve el ID confirmado.
+pub fn parse_ack_message(msg: &str) -> Option<&str> {
+    msg.strip_prefix("ACK,").filter(|id| !id.is_empty())
+}
+
+pub fn version_message(service: &str, unique_node_id: &str, version: &str) -> String {
+    format!("VERSION,{},{},{}", service, unique_node_id, version)
+}
+
+pub fn backend_version_message(service: &str, unique_node_id: &str, version: &str) -> String {
+    format!("BACKEND,{},{},{}", service, unique_node_id, version)
+}
+
+/// Interpreta un datagrama `BACKEND` como `(servicio, ID, versión)`.
+pub fn parse_backend_version_message(msg: &str) -> Option<(&str, &str, &str)> {
+    let mut parts = msg.splitn(4, ',');
+    if parts.next()? != "BACKEND" {
+        return None;
+    }
+    Some((parts.next()?, parts.next()?, parts.next().filter(|version| !version.is_empty())?))
+}
+
+pub fn weight_message(service: &str, unique_node_id: &str, weight: u32) -> String {
+    format!("WEIGHT,{},{},{}", service, unique_node_id, weight)
+}
+
+/// Interpreta un datagrama `WEIGHT` como `(servicio, ID, peso)`. Un peso de 0 no es válido.
+pub fn parse_weight_message(msg: &str) -> Option<(&str, &str, u32)> {
+    let parts: Vec<&str> = msg.split(',').collect();
+    let ["WEIGHT", service, unique_node_id, weight] = parts[..] else {
+        return None;
+    };
+    Some((service, unique_node_id, weight.parse().ok().filter(|weight| *weight > 0)?))
+}
+
+pub fn max_rpm_message(service: &str, unique_node_id: &str, max_rpm: u32) -> String {
+    format!("MAX_RPM,{},{},{}", service, unique_node_id, max_rpm)
+}
+
+/// Interpreta un datagrama `MAX_RPM` como `(servicio, ID, tope)`. Un tope de 0 no es válido.
+pub fn parse_max_rpm_message(msg: &str) -> Option<(&str, &str, u32)> {
+    let parts: Vec<&str> = msg.split(',').collect();
+    let ["MAX_RPM", service, unique_node_id, max_rpm] = parts[..] else {
+        return None;
+    };
+    Some((service, unique_node_id, max_rpm.parse().ok().filter(|max_rpm| *max_rpm > 0)?))
+}
+
+pub fn max_context_message(service: &str, unique_node_id: &str, tokens: u64) -> String {
+    format!("MAX_CONTEXT,{},{},{}", service, unique_node_id, tokens)
+}
+
+/// Interpreta un datagrama `MAX_CONTEXT` como `(servicio, ID, tokens)`. Una ventana de 0 no es válida.
+pub fn parse_max_context_message(msg: &str) -> Option<(&str, &str, u64)> {
+    let parts: Vec<&str> = msg.split(',').collect();
+    let ["MAX_CONTEXT", service, unique_node_id, tokens] = parts[..] else {
+        return None;
+    };
+    Some((service, unique_node_id, tokens.parse().ok().filter(|tokens| *tokens > 0)?))
+}
+
+pub fn domain_message(service: &str, unique_node_id: &str, domain: &str) -> String {
+    format!("DOMAIN,{},{},{}", service, unique_node_id, domain)
+}
+
+/// Interpreta un datagrama `DOMAIN` como `(servicio, ID, dominio)`.
+pub fn parse_domain_message(msg: &str) -> Option<(&str, &str, &str)> {
+    let parts: Vec<&str> = msg.split(',').collect();
+    let ["DOMAIN", service, unique_node_id, domain] = parts[..] else {
+        return None;
+    };
+    Some((service, unique_node_id, Some(domain).filter(|domain| domains::is_valid_label(domain))?))
+}
+
+pub fn tags_message(service: &str, unique_node_id: &str, tags: &NodeTags) -> String {
+    let mut msg = format!("TAGS,{},{}", service, unique_node_id);
+    for (key, value) in tags {
+        msg.push_str(&format!(",{}={}", key, value));
+    }
+    msg
+}
+
+/// Interpreta un datagrama `TAGS` como `(servicio, ID, etiquetas)`.
+pub fn parse_tags_message(msg: &str) -> Option<(&str, &str, NodeTags)> {
+    let mut parts = msg.split(',');
+    if parts.next()? != "TAGS" {
+        return None;
+    }
+    let service = parts.next()?;
+    let unique_node_id = parts.next()?;
+    Some((service, unique_node_id, parts.filter_map(|entry| tags::parse_tag(entry).ok()).collect()))
+}
+
+pub fn draining_message(service: &str, unique_node_id: &str, lead_secs: u64) -> String {
+    format!("DRAINING,{},{},{}", service, unique_node_id, lead_secs)
+}
+
+/// Interpreta un datagrama `DRAINING` como `(servicio, ID, segundos hasta el apagado)`. Un plazo
+/// de más de `MAX_DRAIN_LEAD_SECS` no es válido.
+pub fn parse_draining_message(msg: &str) -> Option<(&str, &str, u64)> {
+    let parts: Vec<&str> = msg.split(',').collect();
+    let ["DRAINING", service, unique_node_id, lead_secs] = parts[..] else {
+        return None;
+    };
+    let lead_secs = lead_secs.parse().ok().filter(|lead_secs| *lead_secs <= MAX_DRAIN_LEAD_SECS)?;
+    Some((service, unique_node_id, lead_secs))
+}
+
+pub fn spooled_message(service: &str, unique_node_id: &str, outage: u64, served: u64) -> String {
+    format!("SPOOLED,{},{},{},{}", service, unique_node_id, outage, served)
+}
+
+/// Interpreta un datagrama `SPOOLED` como `(servicio, ID, caída, peticiones)`.
+pub fn parse_spooled_message(msg: &str) -> Option<(&str, &str, u64, u64)> {
+    let parts: Vec<&str> = msg.split(',').collect();
+    let ["SPOOLED", service, unique_node_id, outage, served] = parts[..] else {
+        return None;
+    };
+    Some((service, unique_node_id, outage.parse().ok()?, served.parse().ok()?))
+}
+
+pub fn goodbye_message(service: &str, unique_node_id: &str) -> String {
+    format!("GOODBYE,{},{}", service, unique_node_id)
+}
+
+/// Interpreta un datagrama `GOODBYE` como `(servicio, ID)`.
+pub fn parse_goodbye_message(msg: &str) -> Option<(&str, &str)> {
+    let parts: Vec<&str> = msg.split(',').collect();
+    let ["GOODBYE", service, unique_node_id] = parts[..] else {
+        return None;
+    };
+    Some((service, unique_node_id))
+}
+
+/// Valor de un campo de `PLATFORM`: sin comas ni `=`, que lo romperían.
+fn platform_field(value: &str) -> &str {
+    if value.is_empty() || value.contains([',', '=']) {
+        platform::UNKNOWN
+    } else {
+        value
+    }
+}
+
+pub fn platform_message(service: &str, unique_node_id: &str, platform: &Platform) -> String {
+    let mut msg = format!(
+        "PLATFORM,{},{},os={},arch={},accelerator={},memory={}",
+        service,
+        unique_node_id,
+        platform_field(&platform.os),
+        platform_field(&platform.arch),
+        platform_field(&platform.accelerator),
+        platform_field(&platform.memory)
+    );
+    if let Some(bytes) = platform.memory_bytes {
+        msg.push_str(&format!(",memory_bytes={}", bytes));
+    }
+    msg
+}
+
+/// Interpreta un datagrama `PLATFORM` como `(servicio, ID, plataforma)`. Los campos que faltan
+/// quedan como `unknown` y los desconocidos se ignoran.
+pub fn parse_platform_message(msg: &str) -> Option<(&str, &str, Platform)> {
+    let mut parts = msg.split(',');
+    if parts.next()? != "PLATFORM" {
+        return None;
+    }
+    let service = parts.next()?;
+    let unique_node_id = parts.next()?;
+    let mut platform = Platform::default();
+    for (key, value) in parts.filter_map(|entry| entry.split_once('=')).filter(|(_, value)| !value.is_empty()) {
+        match key {
+            "os" => platform.os = value.to_string(),
+            "arch" => platform.arch = value.to_string(),
+            "accelerator" => platform.accelerator = value.to_string(),
+            "memory" => platform.memory = value.to_string(),
+            "memory_bytes" => platform.memory_bytes = value.parse().ok(),
+            _ => {}
+        }
+    }
+    Some((service, unique_node_id, platform))
+}
+
+pub fn capabilities_message(service: &str, unique_node_id: &str, capabilities: &[&str]) -> String {
+    let mut msg = format!("CAPS,{},{}", service, unique_node_id);
+    for capability in capabilities {
+        msg.push(',');
+        msg.push_str(capability);
+    }
+    msg
+}
+
+/// Interpreta un datagrama `CAPS` como `(servicio, ID, capacidades)`.
+pub fn parse_capabilities_message(msg: &str) -> Option<(&str, &str, Vec<&str>)> {
+    let mut parts = msg.split(',');
+    if parts.next()? != "CAPS" {
+        return None;
+    }
+    let service = parts.next()?;
+    let unique_node_id = parts.next()?;
+    Some((service, unique_node_id, parts.filter(|capability| !capability.is_empty()).collect()))
+}
+
+/// Prefijo de los IDs sintéticos de los nodos que usan el formato antiguo.
+pub const LEGACY_ID_PREFIX: &str = "legacy-";
+
+/// Interpreta un anuncio antiguo `DISCOVER,<svc>,<ip:puerto>` como `(servicio, dirección)`.
+pub fn parse_legacy_discover(msg: &str) -> Option<(&str, &str)> {
+    let parts: Vec<&str> = msg.splitn(4, ',').collect();
+    match parts[..] {
+        ["DISCOVER", service, address] if !address.is_empty() => Some((service, address)),
+        _ => None,
+    }
+}
+
+/// URL de servicio de un nodo antiguo: su dirección más la ruta por defecto del servicio.
+pub fn legacy_service_url(service: &str, address: &str) -> Option<String> {
+    // Ollama expone la API compatible con OpenAI también bajo /v1.
+    let path = match service {
+        "lmstudio" | "ollama" => "/v1/chat/completions",
+        _ => return None,
+    };
+    Some(format!("http://{}{}", address, path))
+}
+
+/// Interpreta un datagrama `VERSION` como `(servicio, ID, versión)`.
+pub fn parse_version_message(msg: &str) -> Option<(&str, &str, &str)> {
+    let mut parts = msg.splitn(4, ',');
+    if parts.next()? != "VERSION" {
+        return None;
+    }
+    Some((parts.next()?, parts.next()?, parts.next()?))
+}
+
+/// Reparte `models` en datagramas `MODELS` de como mucho `max_bytes` cada uno.
+/// Un modelo que por sí solo no cabe en el presupuesto se descarta.
+pub fn models_messages(
+    service: &str,
+    unique_node_id: &str,
+    round: u64,
+    models: &[String],
+    max_bytes: usize,
+) -> Vec<String> {
+    // Reserva para la cabecera con `seq` y `total` de hasta 5 dígitos.
+    let header_len = format!("MODELS,{},{},{},00000,00000", service, unique_node_id, round).len();
+    let budget = max_bytes.saturating_sub(header_len);
+
+    let mut chunks: Vec<Vec<&str>> = vec![Vec::new()];
+    let mut current_len = 0;
+    for model in models {
+        let entry_len = model.len() + 1;
+        if entry_len > budget || model.contains(',') {
+            continue;
+        }
+        if current_len + entry_len > budget {
+            if chunks.len() == MAX_MODELS_DATAGRAMS {
+                break;
+            }
+            chunks.push(Vec::new());
+            current_len = 0;
+        }
+        chunks.last_mut().unwrap().push(model);
+        current_len += entry_len;
+    }
+
+    let total = chunks.len();
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(seq, chunk)| {
+            let mut msg = format!("MODELS,{},{},{},{},{}", service, unique_node_id, round, seq, total);
+            for model in chunk {
+                msg.push(',');
+                msg.push_str(model);
+            }
+            msg
+        })
+        .collect()
+}
+
+/// Reparte las ventanas de contexto en datagramas `CONTEXT` de como mucho `max_bytes` cada uno.
+pub fn context_messages(service: &str, unique_node_id: &str, windows: &BTreeMap<String, u64>, max_bytes: usize) -> Vec<String> {
+    let header = format!("CONTEXT,{},{}", service, unique_node_id);
+    let mut messages = Vec::new();
+    let mut current = header.clone();
+    for (model, tokens) in windows {
+        if model.contains(',') || model.contains('=') {
+            continue;
+        }
+        let entry = format!(",{}={}", model, tokens);
+        if header.len() + entry.len() > max_bytes {
+            continue;
+        }
+        if current.len() + entry.len() > max_bytes {
+            messages.push(std::mem::replace(&mut current, header.clone()));
+        }
+        current.push_str(&entry);
+    }
+    if current.len() > header.len() {
+        messages.push(current);
+    }
+    messages
+}
+
+pub fn storage_message(service: &str, unique_node_id: &str, report: &StorageReport) -> String {
+    format!(
+        "STORAGE,{},{},{},{},{},{}",
+        service, unique_node_id, report.free_bytes, report.total_bytes, report.model_files, report.model_bytes
+    )
+}
+
+/// Interpreta un datagrama `STORAGE` como `(servicio, ID, informe)`.
+pub fn parse_storage_message(msg: &str) -> Option<(&str, &str, StorageReport)> {
+    let parts: Vec<&str> = msg.split(',').collect();
+    let ["STORAGE", service, unique_node_id, free, total, files, bytes] = parts[..] else {
+        return None;
+    };
+    let report = StorageReport {
+        free_bytes: free.parse().ok()?,
+        total_bytes: total.parse().ok()?,
+        model_files: files.parse().ok()?,
+        model_bytes: bytes.parse().ok()?,
+    };
+    Some((service, unique_node_id, report))
+}
+
+pub struct ContextChunk<'a> {
+    pub service: &'a str,
+    pub unique_node_id: &'a str,
+    pub windows: Vec<(String, u64)>,
+}
+
+/// Interpreta un datagrama `CONTEXT`. Las entradas mal formadas se ignoran.
+pub fn parse_context_message(msg: &str) -> Option<ContextChunk<'_>> {
+    let mut parts = msg.split(',');
+    if parts.next()? != "CONTEXT" {
+        return None;
+    }
+    let service = parts.next()?;
+    let unique_node_id = parts.next()?;
+    let windows = parts
+        .filter_map(|entry| entry.rsplit_once('='))
+        .filter_map(|(model, tokens)| Some((model.to_string(), tokens.parse().ok()?)))
+        .collect();
+    Some(ContextChunk { service, unique_node_id, windows })
+}
+
+pub struct ModelsChunk<'a> {
+    pub service: &'a str,
+    pub unique_node_id: &'a str,
+    pub round: u64,
+    pub seq: usize,
+    pub total: usize,
+    pub models: Vec<String>,
+}
+
+/// Interpreta un datagrama `MODELS`. Devuelve `None` si no lo es o está mal formado, también si
+/// anuncia más de `MAX_MODELS_DATAGRAMS` fragmentos.
+pub fn parse_models_message(msg: &str) -> Option<ModelsChunk<'_>> {
+    let mut parts = msg.splitn(7, ',');
+    if parts.next()? != "MODELS" {
+        return None;
+    }
+    let service = parts.next()?;
+    let unique_node_id = parts.next()?;
+    let round = parts.next()?.parse().ok()?;
+    let seq: usize = parts.next()?.parse().ok()?;
+    let total: usize = parts.next()?.parse().ok()?;
+    if total == 0 || total > MAX_MODELS_DATAGRAMS || seq >= total {
+        return None;
+    }
+    let models = parts
+        .next()
+        .map(|list| list.split(',').filter(|m| !m.is_empty()).map(str::to_string).collect())
+        .unwrap_or_default();
+    Some(ModelsChunk { service, unique_node_id, round, seq, total, models })
+}
+
+struct PendingModels {
+    sender: IpAddr,
+    round: u64,
+    chunks: Vec<Option<Vec<String>>>,
+    started: Instant,
+}
+
+/// Recompone las listas de modelos que llegan repartidas en varios datagramas.
+#[derive(Default)]
+pub struct ModelsReassembler {
+    pending: HashMap<(String, String), PendingModels>,
+}
+
+impl ModelsReassembler {
+    /// Añade un fragmento que llegó de `sender`. Devuelve la lista completa cuando han llegado
+    /// todos los de la ronda.
+    pub fn push(&mut self, chunk: ModelsChunk<'_>, sender: IpAddr) -> Option<Vec<String>> {
+        self.pending.retain(|_, pending| pending.started.elapsed() < REASSEMBLY_TIMEOUT);
+
+        let key = (chunk.service.to_string(), chunk.unique_node_id.to_string());
+        if !self.pending.contains_key(&key) {
+            let from_sender = self.pending.iter().filter(|(_, pending)| pending.sender == sender);
+            if from_sender.clone().count() >= MAX_PENDING_MODELS_PER_SENDER {
+                if let Some(oldest) = from_sender.min_by_key(|(_, pending)| pending.started).map(|(key, _)| key.clone()) {
+                    self.pending.remove(&oldest);
+                }
+            }
+        }
+        let pending = self.pending.entry(key.clone()).or_insert_with(|| PendingModels {
+            sender,
+            round: chunk.round,
+            chunks: vec![None; chunk.total],
+            started: Instant::now(),
+        });
+        // Una ronda nueva invalida los fragmentos de la anterior.
+        if pending.round != chunk.round || pending.chunks.len() != chunk.total {
+            *pending = PendingModels {
+                sender,
+                round: chunk.round,
+                chunks: vec![None; chunk.total],
+                started: Instant::now(),
+            };
+        }
+        pending.chunks[chunk.seq] = Some(chunk.models);
+
+        if pending.chunks.iter().all(Option::is_some) {
+            let pending = self.pending.remove(&key)?;
+            Some(pending.chunks.into_iter().flatten().flatten().collect())
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::balancer::{AppState, LMSTUDIO};
+    use crate::testing;
+
+    /// Cien modelos con nombres del largo de los de Ollama (`library/...:tag`).
+    fn hundred_models() -> Vec<String> {
+        (0..100).map(|i| format!("library/model-{:03}-instruct:70b-q4_K_M", i)).collect()
+    }
+
+    fn parse_all(datagrams: &[String]) -> Vec<ModelsChunk<'_>> {
+        datagrams.iter().map(|datagram| parse_models_message(datagram).unwrap()).collect()
+    }
+
+    #[test]
+    fn hundred_models_are_split_within_the_budget() {
+        let models = hundred_models();
+        let datagrams = models_messages("ollama", "box1", 7, &models, DEFAULT_MAX_DATAGRAM_BYTES);
+
+        assert!(datagrams.len() > 1);
+        assert!(datagrams.iter().all(|datagram| datagram.len() <= DEFAULT_MAX_DATAGRAM_BYTES));
+        let chunks = parse_all(&datagrams);
+        assert!(chunks.iter().enumerate().all(|(seq, chunk)| chunk.seq == seq && chunk.total == datagrams.len() && chunk.round == 7));
+        assert_eq!(chunks.into_iter().flat_map(|chunk| chunk.models).collect::<Vec<_>>(), models);
+    }
+
+    #[test]
+    fn reassembles_in_any_order() {
+        let models = hundred_models();
+        let datagrams = models_messages("ollama", "box1", 1, &models, DEFAULT_MAX_DATAGRAM_BYTES);
+        let mut reassembler = ModelsReassembler::default();
+
+        let mut chunks = parse_all(&datagrams);
+        chunks.reverse();
+        let last = chunks.pop().unwrap();
+        for chunk in chunks {
+            assert_eq!(reassembler.push(chunk, SENDER), None);
+        }
+        assert_eq!(reassembler.push(last, SENDER), Some(models));
+    }
+
+    #[test]
+    fn new_round_discards_the_fragments_of_the_previous_one() {
+        let models = hundred_models();
+        let old = models_messages("ollama", "box1", 1, &models[..50], DEFAULT_MAX_DATAGRAM_BYTES);
+        let new = models_messages("ollama", "box1", 2, &models, DEFAULT_MAX_DATAGRAM_BYTES);
+        let mut reassembler = ModelsReassembler::default();
+
+        assert_eq!(reassembler.push(parse_models_message(&old[0]).unwrap(), SENDER), None);
+        let mut complete = None;
+        for chunk in parse_all(&new) {
+            complete = reassembler.push(chunk, SENDER);
+        }
+        assert_eq!(complete, Some(models));
+    }
+
+    const SENDER: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+    #[test]
+    fn oversized_totals_are_rejected() {
+        assert!(parse_models_message(&format!("MODELS,ollama,box1,1,0,{},m", MAX_MODELS_DATAGRAMS)).is_some());
+        for total in [MAX_MODELS_DATAGRAMS + 1, 4_000_000_000, usize::MAX] {
+            assert!(parse_models_message(&format!("MODELS,ollama,box1,1,0,{},m", total)).is_none(), "{}", total);
+        }
+        // El nodo tampoco manda más fragmentos de los que se aceptan: el resto de modelos se queda fuera.
+        let models: Vec<String> = (0..MAX_MODELS_DATAGRAMS + 10).map(|i| format!("{:04}-{}", i, "x".repeat(1000))).collect();
+        let datagrams = models_messages("ollama", "box1", 0, &models, DEFAULT_MAX_DATAGRAM_BYTES);
+        assert_eq!(datagrams.len(), MAX_MODELS_DATAGRAMS);
+        assert!(parse_all(&datagrams).iter().all(|chunk| chunk.total == MAX_MODELS_DATAGRAMS));
+    }
+
+    #[test]
+    fn pending_lists_are_bounded_per_sender() {
+        let mut reassembler = ModelsReassembler::default();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        let first_of = |id: &str| format!("MODELS,ollama,{},1,0,2,a", id);
+        assert_eq!(reassembler.push(parse_models_message(&first_of("other")).unwrap(), other), None);
+        for i in 0..10 {
+            assert_eq!(reassembler.push(parse_models_message(&first_of(&format!("fake{}", i))).unwrap(), SENDER), None);
+        }
+        assert_eq!(reassembler.pending.values().filter(|pending| pending.sender == SENDER).count(), MAX_PENDING_MODELS_PER_SENDER);
+        // Las de otras direcciones no se tocan, y las más recientes siguen completándose.
+        assert_eq!(reassembler.push(parse_models_message("MODELS,ollama,other,1,1,2,b").unwrap(), other), Some(vec!["a".to_string(), "b".to_string()]));
+        assert_eq!(reassembler.push(parse_models_message("MODELS,ollama,fake9,1,1,2,b").unwrap(), SENDER), Some(vec!["a".to_string(), "b".to_string()]));
+        assert_eq!(reassembler.push(parse_models_message("MODELS,ollama,fake0,1,1,2,b").unwrap(), SENDER), None);
+    }
+
+    #[test]
+    fn models_that_cannot_travel_are_skipped() {
+        let models = vec!["ok".to_string(), "a,b".to_string(), "x".repeat(2000), "also-ok".to_string()];
+        let datagrams = models_messages("ollama", "box1", 0, &models, DEFAULT_MAX_DATAGRAM_BYTES);
+
+        let chunks = parse_all(&datagrams);
+        assert_eq!(chunks.into_iter().flat_map(|chunk| chunk.models).collect::<Vec<_>>(), ["ok", "also-ok"]);
+    }
+
+    #[test]
+    fn malformed_models_datagrams_are_rejected() {
+        for datagram in ["MODELS,ollama,box1,1,2,2,m", "MODELS,ollama,box1,1,0,0,m", "MODELS,ollama,box1,x,0,1,m", "MODELS,ollama", "DISCOVER,ollama,box1,http://h/"] {
+            assert!(parse_models_message(datagram).is_none(), "{}", datagram);
+        }
+    }
+
+    #[actix_web::test]
+    async fn listener_registers_all_hundred_models() {
+        let (state, socket) = testing::discovery(&[]).await;
+        let models = hundred_models();
+
+        socket.send(discover_message("ollama", "box1", "http://10.0.0.1:11434").as_bytes()).await.unwrap();
+        for datagram in models_messages("ollama", "box1", 0, &models, DEFAULT_MAX_DATAGRAM_BYTES) {
+            socket.send(datagram.as_bytes()).await.unwrap();
+        }
+
+        testing::eventually(|| state.ollama_nodes.read().unwrap().get("box1").is_some_and(|info| info.models.len() == models.len())).await;
+        assert_eq!(state.ollama_nodes.read().unwrap()["box1"].models, models);
+    }
+
+    /// Manda cada datagrama y espera a que el listener lo haya procesado antes del siguiente.
+    async fn send_in_order(state: &AppState, socket: &tokio::net::UdpSocket, datagrams: &[&str]) {
+        let listener = &state.discovery_listeners[0];
+        for datagram in datagrams {
+            let packets = listener.summary()["packets"].as_u64().unwrap();
+            socket.send(datagram.as_bytes()).await.unwrap();
+            testing::eventually(|| listener.summary()["packets"].as_u64().unwrap() > packets).await;
+        }
+    }
+
+    fn malformed(state: &AppState) -> u64 {
+        state.discovery_listeners[0].summary()["malformed"].as_u64().unwrap()
+    }
+
+    fn lmstudio_nodes(state: &AppState) -> Vec<(String, String)> {
+        let mut nodes: Vec<_> = state.lm_studio_nodes.read().unwrap().iter().map(|(id, info)| (id.to_string(), info.service_url.to_string())).collect();
+        nodes.sort();
+        nodes
+    }
+
+    const LEGACY: &str = "DISCOVER,lmstudio,127.0.0.1:1234";
+    const CURRENT: &str = "DISCOVER,lmstudio,box1,http://127.0.0.1:1234/v1/chat/completions";
+    const URL: &str = "http://127.0.0.1:1234/v1/chat/completions";
+
+    #[test]
+    fn legacy_announcements_parse_with_the_default_path() {
+        assert_eq!(parse_legacy_discover(LEGACY), Some(("lmstudio", "127.0.0.1:1234")));
+        assert_eq!(parse_legacy_discover(CURRENT), None);
+        assert_eq!(parse_legacy_discover("DISCOVER,lmstudio,"), None);
+        assert_eq!(legacy_service_url("ollama", "10.0.0.1:11434").as_deref(), Some("http://10.0.0.1:11434/v1/chat/completions"));
+        assert_eq!(legacy_service_url("vllm", "10.0.0.1:8000"), None);
+    }
+
+    #[actix_web::test]
+    async fn legacy_announcement_registers_a_synthetic_node() {
+        let (state, socket) = testing::discovery(&[]).await;
+
+        send_in_order(&state, &socket, &[LEGACY, LEGACY]).await;
+
+        assert_eq!(lmstudio_nodes(&state), [("legacy-127.0.0.1:1234".to_string(), URL.to_string())]);
+    }
+
+    #[actix_web::test]
+    async fn legacy_then_current_from_the_same_host_collapse_into_one_node() {
+        let (state, socket) = testing::discovery(&[]).await;
+
+        send_in_order(&state, &socket, &[LEGACY, CURRENT]).await;
+        assert_eq!(lmstudio_nodes(&state), [("box1".to_string(), URL.to_string())]);
+
+        // Un rezagado en formato antiguo refresca el nodo nuevo en vez de volver a duplicarlo.
+        let before = malformed(&state);
+        send_in_order(&state, &socket, &[LEGACY, CURRENT, LEGACY]).await;
+        assert_eq!(lmstudio_nodes(&state), [("box1".to_string(), URL.to_string())]);
+        assert_eq!(malformed(&state), before);
+    }
+
+    #[actix_web::test]
+    async fn current_then_legacy_from_the_same_host_keep_the_current_node() {
+        let (state, socket) = testing::discovery(&[]).await;
+
+        send_in_order(&state, &socket, &[CURRENT, LEGACY]).await;
+        let refreshed = state.lm_studio_nodes.read().unwrap()["box1"].last_seen;
+        send_in_order(&state, &socket, &[LEGACY]).await;
+
+        assert_eq!(lmstudio_nodes(&state), [("box1".to_string(), URL.to_string())]);
+        assert!(state.lm_studio_nodes.read().unwrap()["box1"].last_seen > refreshed);
+    }
+
+    #[actix_web::test]
+    async fn legacy_announcements_can_be_disabled() {
+        let (state, socket) = testing::discovery(&["--disable-legacy-discovery"]).await;
+        let before = malformed(&state);
+
+        send_in_order(&state, &socket, &[LEGACY, CURRENT, LEGACY]).await;
+
+        assert_eq!(lmstudio_nodes(&state), [("box1".to_string(), URL.to_string())]);
+        assert_eq!(malformed(&state), before + 2);
+    }
+
+    #[test]
+    fn draining_leads_beyond_a_day_are_invalid() {
+        assert_eq!(parse_draining_message("DRAINING,lmstudio,box1,90"), Some(("lmstudio", "box1", 90)));
+        assert_eq!(parse_draining_message(&draining_message("lmstudio", "box1", MAX_DRAIN_LEAD_SECS)), Some(("lmstudio", "box1", MAX_DRAIN_LEAD_SECS)));
+        assert_eq!(parse_draining_message(&draining_message("lmstudio", "box1", MAX_DRAIN_LEAD_SECS + 1)), None);
+        assert_eq!(parse_draining_message(&draining_message("lmstudio", "box1", u64::MAX)), None);
+    }
+
+    #[actix_web::test]
+    async fn a_huge_draining_lead_is_refused_and_the_pool_stays_usable() {
+        let (state, socket) = testing::discovery(&[]).await;
+        send_in_order(&state, &socket, &[CURRENT]).await;
+        let before = malformed(&state);
+
+        send_in_order(&state, &socket, &[&draining_message("lmstudio", "box1", u64::MAX)]).await;
+        assert_eq!(malformed(&state), before + 1);
+        assert_eq!(testing::node_state(&state, "lmstudio", "box1"), "available");
+
+        // Un plazo que no cabe en un `Instant` tampoco llega a tomar el lock de la pool.
+        assert!(state.start_drain(&LMSTUDIO, &testing::node_id("box1"), Duration::MAX).is_none());
+        assert_eq!(testing::node_state(&state, "lmstudio", "box1"), "available");
+
+        // La pool sigue sana: un DRAINING válido se aplica.
+        send_in_order(&state, &socket, &[&draining_message("lmstudio", "box1", 60)]).await;
+        assert_eq!(testing::node_state(&state, "lmstudio", "box1"), "draining");
+        assert_eq!(lmstudio_nodes(&state), [("box1".to_string(), URL.to_string())]);
+    }
+}
@@ -0,0 +1,344 @@
+// src/dispatch_rate.rs
+//! Límite de ritmo de despacho por nodo (token bucket).
+//!
+//! Algunos backends (p.ej. llama.cpp server) aguantan bien el ritmo medio pero se caen si
+//! reciben varias peticiones en los mismos milisegundos. Con un ritmo configurado, cada nodo
+//! tiene un cubo de `burst` fichas que se rellena a `per_second` fichas por segundo; elegir el
+//! nodo gasta una ficha. Un nodo con el cubo vacío no es elegible en ese momento: se prueba con
+//! otro y, si no hay ninguno, la petición sigue en la cola hasta que haya ficha.
+//!
+//! El ritmo de cada pool sale de `--dispatch-rate`; `PATCH /nodes/{id}/dispatch-rate` lo
+//! cambia para un nodo concreto (en todas las pools donde esté) hasta que se retire.
+use actix_web::{patch, web, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::RwLock;
+use std::time::Instant;
+use log::info;
+
+use crate::api_types::DispatchRateStatus;
+use crate::balancer::AppState;
+
+#[derive(Debug)]
+pub struct DispatchRateConfigError(String);
+
+impl fmt::Display for DispatchRateConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for DispatchRateConfigError {}
+
+/// Ritmo máximo de despacho a un nodo.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DispatchRate {
+    /// Peticiones por segundo sostenidas.
+    pub per_second: f64,
+    /// Peticiones que pueden salir seguidas con el cubo lleno.
+    #[serde(default = "default_burst")]
+    pub burst: u32,
+}
+
+fn default_burst() -> u32 {
+    1
+}
+
+impl DispatchRate {
+    fn validate(self) -> Result<Self, DispatchRateConfigError> {
+        if !(self.per_second.is_finite() && self.per_second > 0.0) {
+            return Err(DispatchRateConfigError(format!("Ritmo de despacho inválido {}: debe ser mayor que 0", self.per_second)));
+        }
+        if self.burst == 0 {
+            return Err(DispatchRateConfigError("La ráfaga de despacho debe ser al menos 1".to_string()));
+        }
+        Ok(self)
+    }
+}
+
+/// Fichas de un nodo. Vive en su `NodeInfo`, así que se consulta y gasta con el lock de la pool.
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn full(rate: DispatchRate, now: Instant) -> Self {
+        Self { tokens: rate.burst as f64, refilled_at: now }
+    }
+
+    fn level(&self, rate: DispatchRate, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.refilled_at).as_secs_f64();
+        (self.tokens + elapsed * rate.per_second).min(rate.burst as f64)
+    }
+}
+
+/// Ritmos configurados por pool y por nodo.
+#[derive(Default)]
+pub struct DispatchLimits {
+    per_pool: HashMap<String, DispatchRate>,
+    per_node: RwLock<HashMap<String, DispatchRate>>,
+    /// Ritmo tope de todos los nodos durante el calentamiento (`--warmup-dispatch-rate`).
+    warmup: RwLock<Option<DispatchRate>>,
+}
+
+impl DispatchLimits {
+    /// Interpreta entradas `pool=peticiones_por_segundo[:ráfaga]` de la línea de comandos.
+    pub fn new(pools: &[&str], entries: &[String]) -> Result<Self, DispatchRateConfigError> {
+        let mut per_pool = HashMap::new();
+        for entry in entries {
+            let Some((pool, spec)) = entry.split_once('=').map(|(pool, spec)| (pool.trim(), spec.trim())) else {
+                return Err(DispatchRateConfigError(format!("Ritmo de despacho inválido '{}': se esperaba <pool>=<por_segundo>[:<ráfaga>]", entry)));
+            };
+            if !pools.contains(&pool) {
+                return Err(DispatchRateConfigError(format!("Pool desconocida '{}' en --dispatch-rate", pool)));
+            }
+            per_pool.insert(pool.to_string(), Self::parse_rate(spec)?);
+        }
+        Ok(Self { per_pool, per_node: RwLock::new(HashMap::new()), warmup: RwLock::new(None) })
+    }
+
+    pub fn pools(&self) -> impl Iterator<Item = (&str, &DispatchRate)> {
+        self.per_pool.iter().map(|(pool, rate)| (pool.as_str(), rate))
+    }
+
+    /// Ritmo que se aplica al nodo en la pool; `None` si no está limitado. Durante el
+    /// calentamiento manda el más lento de los dos.
+    pub fn rate_for(&self, service: &str, unique_node_id: &str) -> Option<DispatchRate> {
+        let per_node = self.per_node.read().unwrap();
+        let configured = per_node.get(unique_node_id).or_else(|| self.per_pool.get(service)).copied();
+        match (configured, *self.warmup.read().unwrap()) {
+            (Some(configured), Some(warmup)) if configured.per_second <= warmup.per_second => Some(configured),
+            (configured, warmup) => warmup.or(configured),
+        }
+    }
+
+    pub fn set_warmup(&self, rate: Option<DispatchRate>) {
+        *self.warmup.write().unwrap() = rate;
+    }
+
+    /// Interpreta `peticiones_por_segundo[:ráfaga]`, como cada entrada de `--dispatch-rate`.
+    pub fn parse_rate(spec: &str) -> Result<DispatchRate, DispatchRateConfigError> {
+        let invalid = || DispatchRateConfigError(format!("Ritmo de despacho inválido '{}': se esperaba <por_segundo>[:<ráfaga>]", spec));
+        let (per_second, burst) = match spec.split_once(':') {
+            Some((per_second, burst)) => (per_second.trim(), Some(burst.trim())),
+            None => (spec.trim(), None),
+        };
+        let rate = DispatchRate {
+            per_second: per_second.parse().map_err(|_| invalid())?,
+            burst: burst.map(str::parse).transpose().map_err(|_| invalid())?.unwrap_or_else(default_burst),
+        };
+        rate.validate()
+    }
+
+    /// Gasta una ficha del nodo si la tiene. Sin ritmo aplicable siempre se puede despachar.
+    pub fn try_take(&self, service: &str, unique_node_id: &str, bucket: &mut Option<TokenBucket>, now: Instant) -> bool {
+        let Some(rate) = self.rate_for(service, unique_node_id) else {
+            *bucket = None;
+            return true;
+        };
+        let bucket = bucket.get_or_insert_with(|| TokenBucket::full(rate, now));
+        bucket.tokens = bucket.level(rate, now);
+        bucket.refilled_at = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Estado del cubo del nodo para `/nodes`; `null` si no está limitado.
+    pub fn describe(&self, service: &str, unique_node_id: &str, bucket: Option<&TokenBucket>) -> Option<DispatchRateStatus> {
+        let rate = self.rate_for(service, unique_node_id)?;
+        let now = Instant::now();
+        let tokens = bucket.map_or(rate.burst as f64, |bucket| bucket.level(rate, now));
+        Some(DispatchRateStatus { per_second: rate.per_second, burst: rate.burst, tokens: (tokens * 100.0).floor() / 100.0 })
+    }
+}
+
+/// Fija el ritmo de despacho de un nodo (`{"per_second":2,"burst":1}`) o vuelve al de su pool (`null`).
+#[patch("/nodes/{id}/dispatch-rate")]
+async fn dispatch_rate_handler(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    body: web::Json<Option<DispatchRate>>,
+) -> impl Responder {
+    let unique_node_id = path.into_inner();
+    let known = state.pools().into_iter().any(|(_, _, lock)| lock.read().unwrap().contains_key(unique_node_id.as_str()));
+    if !known {
+        return HttpResponse::NotFound().json(json!({ "error": format!("Nodo {} desconocido", unique_node_id) }));
+    }
+    let rate = match body.into_inner().map(DispatchRate::validate).transpose() {
+        Ok(rate) => rate,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "error": e.to_string() })),
+    };
+    let mut per_node = state.dispatch_limits.per_node.write().unwrap();
+    match rate {
+        Some(rate) => {
+            info!("Despacho: Ritmo del nodo {} fijado a {}/s (ráfaga {}).", unique_node_id, rate.per_second, rate.burst);
+            per_node.insert(unique_node_id.clone(), rate);
+        }
+        None => {
+            info!("Despacho: El nodo {} vuelve al ritmo de su pool.", unique_node_id);
+            per_node.remove(&unique_node_id);
+        }
+    }
+    drop(per_node);
+    let pools: Vec<_> = state
+        .pools()
+        .into_iter()
+        .filter_map(|(_, service, lock)| {
+            let nodes = lock.read().unwrap();
+            nodes.get(unique_node_id.as_str()).map(|info| json!({
+                "service": service,
+                "dispatch_rate": state.dispatch_limits.describe(service, &unique_node_id, info.dispatch_bucket.as_ref()),
+            }))
+        })
+        .collect();
+    HttpResponse::Ok().json(json!({ "node_id": unique_node_id, "pools": pools }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use futures_util::future::join_all;
+    use serde_json::Value;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use crate::balancer;
+    use crate::testing;
+
+    fn limits(entries: &[&str]) -> DispatchLimits {
+        DispatchLimits::new(&["lmstudio", "ollama"], &entries.iter().map(|e| e.to_string()).collect::<Vec<_>>()).unwrap()
+    }
+
+    #[test]
+    fn parses_rates_and_rejects_invalid_ones() {
+        assert_eq!(DispatchLimits::parse_rate("2").unwrap(), DispatchRate { per_second: 2.0, burst: 1 });
+        assert_eq!(DispatchLimits::parse_rate(" 0.5 : 3 ").unwrap(), DispatchRate { per_second: 0.5, burst: 3 });
+        for spec in ["", "0", "-1", "inf", "NaN", "2:0", "2:x", "x:1", "2:-1"] {
+            assert!(DispatchLimits::parse_rate(spec).is_err(), "{}", spec);
+        }
+        for entries in [&["lmstudio"][..], &["otra=1"], &["lmstudio=0"]] {
+            assert!(DispatchLimits::new(&["lmstudio", "ollama"], &entries.iter().map(|e| e.to_string()).collect::<Vec<_>>()).is_err(), "{:?}", entries);
+        }
+    }
+
+    #[test]
+    fn bucket_allows_the_burst_then_refills_at_the_rate() {
+        let limits = limits(&["lmstudio=2:3"]);
+        let start = Instant::now();
+        let mut bucket = None;
+        let take = |bucket: &mut Option<TokenBucket>, ms: u64| limits.try_take("lmstudio", "box1", bucket, start + Duration::from_millis(ms));
+
+        assert!((0..3).all(|_| take(&mut bucket, 0)), "la ráfaga sale entera");
+        assert!(!take(&mut bucket, 0));
+        // Una ficha cada 500 ms.
+        assert!(!take(&mut bucket, 499));
+        assert!(take(&mut bucket, 500));
+        assert!(!take(&mut bucket, 500));
+        // El cubo no pasa de la ráfaga por mucho que espere.
+        assert!((0..3).all(|_| take(&mut bucket, 60_000)));
+        assert!(!take(&mut bucket, 60_000));
+        // Un reloj que retrocede no resta fichas ni las regala.
+        assert!(!take(&mut bucket, 0));
+        assert!(take(&mut bucket, 60_500));
+    }
+
+    #[test]
+    fn node_override_beats_the_pool_and_warmup_caps_both() {
+        let limits = limits(&["lmstudio=4:2"]);
+        assert_eq!(limits.rate_for("lmstudio", "box1"), Some(DispatchRate { per_second: 4.0, burst: 2 }));
+        assert_eq!(limits.rate_for("ollama", "box1"), None);
+
+        let fast = DispatchRate { per_second: 10.0, burst: 5 };
+        limits.per_node.write().unwrap().insert("box1".to_string(), fast);
+        assert_eq!(limits.rate_for("lmstudio", "box1"), Some(fast));
+        assert_eq!(limits.rate_for("ollama", "box1"), Some(fast));
+        assert_eq!(limits.rate_for("lmstudio", "box2"), Some(DispatchRate { per_second: 4.0, burst: 2 }));
+
+        let warmup = DispatchRate { per_second: 5.0, burst: 1 };
+        limits.set_warmup(Some(warmup));
+        assert_eq!(limits.rate_for("lmstudio", "box1"), Some(warmup));
+        assert_eq!(limits.rate_for("lmstudio", "box2"), Some(DispatchRate { per_second: 4.0, burst: 2 }));
+        assert_eq!(limits.rate_for("ollama", "box3"), Some(warmup));
+        limits.set_warmup(None);
+        assert_eq!(limits.rate_for("ollama", "box3"), None);
+
+        // Sin ritmo aplicable el cubo se descarta.
+        let mut bucket = Some(TokenBucket::full(warmup, Instant::now()));
+        assert!(limits.try_take("ollama", "box3", &mut bucket, Instant::now()));
+        assert!(bucket.is_none());
+    }
+
+    /// Nodo falso que anota cuándo le llega cada petición.
+    fn timed_node() -> (String, Arc<Mutex<Vec<Instant>>>) {
+        let arrivals = Arc::new(Mutex::new(Vec::new()));
+        let seen = arrivals.clone();
+        let url = testing::backend(move |cfg| {
+            let seen = seen.clone();
+            cfg.default_service(web::to(move || {
+                seen.lock().unwrap().push(Instant::now());
+                async { testing::chat_reply() }
+            }));
+        });
+        (url, arrivals)
+    }
+
+    #[actix_web::test]
+    async fn burst_of_twenty_is_spread_out_over_time() {
+        let (url, arrivals) = timed_node();
+        let state = testing::state(&["--dispatch-rate", "lmstudio=10:2"]);
+        testing::announce(&state, "lmstudio", "box1", &url);
+        let app = init_service(balancer::app(state)).await;
+
+        let start = Instant::now();
+        let statuses: Vec<u16> = join_all((0..20).map(|_| call_service(&app, testing::chat().to_request()))).await.iter().map(|resp| resp.status().as_u16()).collect();
+        assert!(statuses.iter().all(|&status| status == 200), "{:?}", statuses);
+
+        let arrivals = arrivals.lock().unwrap().clone();
+        assert_eq!(arrivals.len(), 20);
+        // Dos de ráfaga y las otras 18 a 10 por segundo: al menos 1,8 s, nunca todas a la vez.
+        let last = arrivals.iter().max().unwrap().duration_since(start);
+        assert!(last >= Duration::from_millis(1_700), "la última llegó a los {:?}", last);
+        // En cualquier ventana de 500 ms caben como mucho la ráfaga y cinco fichas nuevas.
+        for first in &arrivals {
+            let within = arrivals.iter().filter(|at| **at >= *first && at.duration_since(*first) < Duration::from_millis(500)).count();
+            assert!(within <= 2 + 5 + 1, "{} peticiones en 500 ms", within);
+        }
+    }
+
+    #[actix_web::test]
+    async fn patch_sets_and_clears_a_node_override() {
+        let state = testing::state(&["--admin-token", "admin", "--dispatch-rate", "lmstudio=4:2"]);
+        testing::announce(&state, "lmstudio", "box1", "http://127.0.0.1:1/");
+        let app = init_service(balancer::app(state)).await;
+        let patch = |node: &str, body: Value| {
+            TestRequest::patch().uri(&format!("/nodes/{}/dispatch-rate", node)).insert_header(("Authorization", "Bearer admin")).set_json(body).to_request()
+        };
+
+        let resp = call_service(&app, patch("box1", json!({ "per_second": 1.5, "burst": 3 }))).await;
+        assert_eq!(resp.status(), 200);
+        let body: Value = read_body_json(resp).await;
+        assert_eq!(body["pools"][0]["dispatch_rate"], json!({ "per_second": 1.5, "burst": 3, "tokens": 3.0 }));
+
+        let nodes: Value = read_body_json(call_service(&app, TestRequest::get().uri("/nodes").to_request()).await).await;
+        assert_eq!(nodes["nodes"][0]["dispatch_rate"]["per_second"], 1.5);
+
+        let resp = call_service(&app, patch("box1", Value::Null)).await;
+        let body: Value = read_body_json(resp).await;
+        assert_eq!(body["pools"][0]["dispatch_rate"]["per_second"], 4.0);
+
+        assert_eq!(call_service(&app, patch("box1", json!({ "per_second": 0 }))).await.status(), 400);
+        assert_eq!(call_service(&app, patch("box1", json!({ "per_second": 1, "burst": 0 }))).await.status(), 400);
+        assert_eq!(call_service(&app, patch("box9", json!({ "per_second": 1 }))).await.status(), 404);
+        let anonymous = TestRequest::patch().uri("/nodes/box1/dispatch-rate").set_json(json!({ "per_second": 1 })).to_request();
+        assert_eq!(call_service(&app, anonymous).await.status(), 401);
+    }
+}
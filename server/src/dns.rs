@@ -0,0 +1,223 @@
+// src/dns.rs
+use hyper::client::connect::dns::Name;
+use log::{debug, info, warn};
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::limits::StoreUsage;
+
+struct CachedAddrs {
+    addrs: Vec<SocketAddr>,
+    resolved_at: Instant,
+}
+
+/// Fallo de resolución de un nodo; permite distinguir los errores DNS de los de conexión.
+#[derive(Debug)]
+pub struct DnsLookupError {
+    host: String,
+    source: std::io::Error,
+}
+
+impl std::fmt::Display for DnsLookupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "no se pudo resolver '{}': {}", self.host, self.source)
+    }
+}
+
+impl std::error::Error for DnsLookupError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Resolutor DNS para el cliente HTTP con caché de TTL acotado, de modo que los nodos
+/// registrados por nombre (`gpu1.lab.internal`) se re-resuelven aunque cambie su IP.
+#[derive(Clone)]
+pub struct CachingResolver {
+    ttl: Duration,
+    cache: Arc<Mutex<HashMap<String, CachedAddrs>>>,
+    /// Hosts en caché. Lleno, se olvida la resolución más antigua.
+    max_hosts: usize,
+    /// Direcciones fijas por host, al estilo de /etc/hosts, que se consultan antes que el DNS.
+    hosts: Arc<Mutex<HashMap<String, Vec<IpAddr>>>>,
+}
+
+impl CachingResolver {
+    pub fn new(ttl: Duration, max_hosts: usize) -> Self {
+        Self { ttl, cache: Arc::new(Mutex::new(HashMap::new())), max_hosts, hosts: Arc::default() }
+    }
+
+    /// Fija las direcciones de `host` sin pasar por el DNS (las pruebas simulan así un cambio de IP).
+    #[cfg(test)]
+    pub fn set_host(&self, host: &str, addrs: &[IpAddr]) {
+        self.hosts.lock().unwrap().insert(host.to_string(), addrs.to_vec());
+    }
+
+    async fn resolve_host(&self, host: &str) -> std::io::Result<Vec<SocketAddr>> {
+        let fixed = self.hosts.lock().unwrap().get(host).cloned();
+        match fixed {
+            Some(addrs) => Ok(addrs.into_iter().map(|ip| SocketAddr::new(ip, 0)).collect()),
+            None => Ok(tokio::net::lookup_host((host, 0)).await?.collect()),
+        }
+    }
+
+    pub fn usage(&self) -> StoreUsage {
+        let cache = self.cache.lock().unwrap();
+        let bytes = cache
+            .iter()
+            .map(|(host, cached)| std::mem::size_of::<(String, CachedAddrs)>() + host.len() + cached.addrs.len() * std::mem::size_of::<SocketAddr>())
+            .sum();
+        StoreUsage { entries: cache.len(), bytes }
+    }
+
+    /// Olvida la resolución de `host` para que la próxima conexión vuelva a consultar el DNS.
+    pub fn invalidate(&self, host: &str) {
+        if self.cache.lock().unwrap().remove(host).is_some() {
+            info!("DNS: Resolución de '{}' invalidada tras un error de conexión.", host);
+        }
+    }
+
+    async fn lookup(self, host: String) -> Result<Addrs, Box<dyn std::error::Error + Send + Sync>> {
+        if let Some(cached) = self.cache.lock().unwrap().get(&host) {
+            if cached.resolved_at.elapsed() < self.ttl {
+                return Ok(Box::new(cached.addrs.clone().into_iter()));
+            }
+        }
+
+        let resolved: Vec<SocketAddr> = match self.resolve_host(&host).await {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                // Si el DNS falla momentáneamente, mejor la última dirección conocida que nada.
+                let stale = self.cache.lock().unwrap().get(&host).map(|cached| cached.addrs.clone());
+                return match stale {
+                    Some(addrs) => {
+                        warn!("DNS: No se pudo re-resolver '{}' ({}). Usando la última resolución conocida.", host, e);
+                        Ok(Box::new(addrs.into_iter()))
+                    }
+                    None => Err(Box::new(DnsLookupError { host: host.clone(), source: e })),
+                };
+            }
+        };
+
+        let mut cache = self.cache.lock().unwrap();
+        let previous: Option<Vec<IpAddr>> = cache.get(&host).map(|c| c.addrs.iter().map(SocketAddr::ip).collect());
+        let current: Vec<IpAddr> = resolved.iter().map(SocketAddr::ip).collect();
+        match previous {
+            Some(previous) if previous != current => {
+                info!("DNS: La dirección de '{}' cambió de {:?} a {:?}", host, previous, current);
+            }
+            None => debug!("DNS: '{}' resuelto a {:?}", host, current),
+            _ => {}
+        }
+        if !cache.contains_key(&host) && cache.len() >= self.max_hosts {
+            let oldest = cache.iter().min_by_key(|(_, cached)| cached.resolved_at).map(|(host, _)| host.clone());
+            if let Some(oldest) = oldest {
+                cache.remove(&oldest);
+            }
+        }
+        cache.insert(host, CachedAddrs { addrs: resolved.clone(), resolved_at: Instant::now() });
+        Ok(Box::new(resolved.into_iter()))
+    }
+}
+
+impl Resolve for CachingResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(self.clone().lookup(name.as_str().to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, HttpResponse};
+    use serde_json::json;
+
+    use crate::balancer;
+    use crate::testing;
+
+    const HOST: &str = "gpu1.lab.internal";
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    async fn resolved(resolver: &CachingResolver, host: &str) -> Vec<IpAddr> {
+        resolver.clone().lookup(host.to_string()).await.unwrap().map(|addr| addr.ip()).collect()
+    }
+
+    #[actix_web::test]
+    async fn resolution_is_cached_for_the_ttl() {
+        let resolver = CachingResolver::new(Duration::from_secs(300), 16);
+        resolver.set_host(HOST, &[ip("10.0.0.1")]);
+        assert_eq!(resolved(&resolver, HOST).await, [ip("10.0.0.1")]);
+
+        resolver.set_host(HOST, &[ip("10.0.0.2")]);
+        assert_eq!(resolved(&resolver, HOST).await, [ip("10.0.0.1")]);
+
+        resolver.invalidate(HOST);
+        assert_eq!(resolved(&resolver, HOST).await, [ip("10.0.0.2")]);
+    }
+
+    #[actix_web::test]
+    async fn expired_resolution_is_looked_up_again() {
+        let resolver = CachingResolver::new(Duration::ZERO, 16);
+        resolver.set_host(HOST, &[ip("10.0.0.1")]);
+        assert_eq!(resolved(&resolver, HOST).await, [ip("10.0.0.1")]);
+
+        resolver.set_host(HOST, &[ip("10.0.0.2")]);
+        assert_eq!(resolved(&resolver, HOST).await, [ip("10.0.0.2")]);
+    }
+
+    #[actix_web::test]
+    async fn failed_lookup_falls_back_to_the_last_resolution() {
+        // `.invalid` nunca resuelve (RFC 6761): sin la entrada fija, la consulta al DNS falla.
+        let host = "gpu1.lab.invalid";
+        let resolver = CachingResolver::new(Duration::ZERO, 16);
+        assert!(resolver.clone().lookup(host.to_string()).await.is_err());
+
+        resolver.set_host(host, &[ip("10.0.0.1")]);
+        assert_eq!(resolved(&resolver, host).await, [ip("10.0.0.1")]);
+        resolver.hosts.lock().unwrap().clear();
+        assert_eq!(resolved(&resolver, host).await, [ip("10.0.0.1")]);
+    }
+
+    #[actix_web::test]
+    async fn cache_keeps_at_most_max_hosts() {
+        let resolver = CachingResolver::new(Duration::from_secs(300), 2);
+        for (i, host) in ["a.lab", "b.lab", "c.lab"].iter().enumerate() {
+            resolver.set_host(host, &[ip(&format!("10.0.0.{}", i + 1))]);
+            resolved(&resolver, host).await;
+        }
+        let cache = resolver.cache.lock().unwrap();
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.contains_key("a.lab"));
+    }
+
+    #[actix_web::test]
+    async fn connection_error_re_resolves_before_the_retry() {
+        let url = testing::backend(|cfg| {
+            cfg.default_service(web::to(|| async { HttpResponse::Ok().json(json!({ "choices": [] })) }));
+        });
+        let port = url::Url::parse(&url).unwrap().port().unwrap();
+        let state = testing::state(&["--retry-policy", "forward:attempts=2,base_ms=10,jitter=0"]);
+        // El nodo se resolvió cuando estaba en 127.0.0.2; el backend escucha sólo en 127.0.0.1.
+        state.dns_resolver.set_host(HOST, &[ip("127.0.0.2")]);
+        resolved(&state.dns_resolver, HOST).await;
+        state.dns_resolver.set_host(HOST, &[ip("127.0.0.1")]);
+        testing::announce(&state, "lmstudio", "gpu1", &format!("http://{}:{}/", HOST, port));
+        let app = init_service(balancer::app(state.clone())).await;
+
+        let req = TestRequest::post()
+            .uri("/lmstudio")
+            .set_json(json!({ "model": "m", "messages": [{ "role": "user", "content": "hola" }] }))
+            .to_request();
+        let resp = call_service(&app, req).await;
+
+        assert!(resp.status().is_success(), "{}", resp.status());
+        assert_eq!(resolved(&state.dns_resolver, HOST).await, [ip("127.0.0.1")]);
+    }
+}
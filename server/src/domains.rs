@@ -0,0 +1,256 @@
+// src/domains.rs
+//! Dominios de fallo: grupos de nodos que caen juntos (mismo rack, PDU o switch).
+//!
+//! Un nodo anuncia su dominio con `--failure-domain` (datagrama `DOMAIN`) y `--node-domain
+//! <nodo>=<dominio>` en el balanceador manda sobre lo anunciado. Un nodo sin dominio se trata
+//! como uno propio: no comparte caída con nadie, que es lo que se suponía de todos hasta ahora.
+//!
+//! El dominio se usa en tres sitios:
+//!
+//! - Al repetir una petición en otro nodo (`--retry-invalid-tool-calls`), los nodos de otro
+//!   dominio que el del que falló se prueban antes; uno del mismo sólo si no queda otro.
+//! - `/stats/fairness` desglosa el reparto de cada pool por dominio.
+//! - Cuando todos los nodos utilizables de una pool quedan en un mismo dominio, se publica
+//!   `domain_redundancy_lost`: si ese dominio cae, cae la pool entera. Al volver a haber nodos
+//!   en más de uno, `domain_redundancy_restored`. Las pools sin dominios nunca avisan.
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+
+use crate::balancer::{NodeHealth, NodeInfo};
+use crate::ids::NodeId;
+
+#[derive(Debug)]
+pub struct DomainConfigError(String);
+
+impl fmt::Display for DomainConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for DomainConfigError {}
+
+/// Si `label` sirve como dominio: no vacío y sin comas, que romperían el datagrama.
+pub fn is_valid_label(label: &str) -> bool {
+    !label.is_empty() && !label.contains(',') && !label.chars().any(char::is_whitespace)
+}
+
+/// Dominios fijados por configuración (`--node-domain`).
+#[derive(Default)]
+pub struct FailureDomains {
+    overrides: HashMap<String, String>,
+}
+
+impl FailureDomains {
+    /// Interpreta entradas `nodo=dominio` de la línea de comandos.
+    pub fn new(entries: &[String]) -> Result<Self, DomainConfigError> {
+        let mut overrides = HashMap::new();
+        for entry in entries {
+            let Some((node, domain)) = entry.split_once('=').map(|(node, domain)| (node.trim(), domain.trim())) else {
+                return Err(DomainConfigError(format!("Dominio inválido '{}': se esperaba <nodo>=<dominio>", entry)));
+            };
+            if node.is_empty() || !is_valid_label(domain) {
+                return Err(DomainConfigError(format!("Dominio inválido '{}': el nodo y el dominio no pueden estar vacíos ni el dominio llevar comas o espacios", entry)));
+            }
+            if overrides.insert(node.to_string(), domain.to_string()).is_some() {
+                return Err(DomainConfigError(format!("El nodo '{}' tiene más de un --node-domain", node)));
+            }
+        }
+        Ok(Self { overrides })
+    }
+
+    /// Dominio del nodo: el de la configuración o, si no, el que anuncia.
+    pub fn domain_of<'a>(&'a self, unique_node_id: &str, info: &'a NodeInfo) -> Option<&'a str> {
+        self.overrides.get(unique_node_id).or(info.domain.as_ref()).map(String::as_str)
+    }
+
+    /// Dominio que comparten todos los nodos utilizables de la pool (ni caídos ni drenándose), si
+    /// hay alguno y todos tienen el mismo. Un nodo sin dominio cuenta como uno propio.
+    pub fn sole_domain(&self, nodes: &HashMap<NodeId, NodeInfo>) -> Option<String> {
+        let mut domains = BTreeSet::new();
+        for (unique_id, info) in nodes {
+            if matches!(info.state, NodeHealth::Failed(_) | NodeHealth::Draining(_)) {
+                continue;
+            }
+            domains.insert(self.domain_of(unique_id, info)?);
+        }
+        let mut domains = domains.into_iter();
+        match (domains.next(), domains.next()) {
+            (Some(domain), None) => Some(domain.to_string()),
+            _ => None,
+        }
+    }
+
+    /// Configuración para `/config`.
+    pub fn describe(&self) -> Value {
+        let overrides: BTreeMap<_, _> = self.overrides.iter().collect();
+        json!({ "overrides": overrides })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::{web, HttpResponse};
+    use std::sync::Arc;
+    use std::time::Instant;
+    use tokio::sync::broadcast::Receiver;
+
+    use crate::balancer::{self, AppState, LMSTUDIO, OLLAMA};
+    use crate::events::{self, BalancerEvent};
+    use crate::history::TransitionCause;
+    use crate::sessions::NODE_ID_HEADER;
+    use crate::testing;
+
+    #[test]
+    fn overrides_are_validated() {
+        assert!(FailureDomains::new(&["box1=rack-a".to_string(), " box2 = rack-b ".to_string()]).is_ok());
+        for entries in [&["box1"][..], &["=rack-a"], &["box1="], &["box1=rack a"], &["box1=rack,a"], &["box1=rack-a", "box1=rack-b"]] {
+            let entries: Vec<String> = entries.iter().map(|entry| entry.to_string()).collect();
+            assert!(FailureDomains::new(&entries).is_err(), "{:?}", entries);
+        }
+    }
+
+    /// Pool de `lmstudio` con estos nodos y dominios anunciados (`None`: sin dominio).
+    fn pool(args: &[&str], nodes: &[(&str, Option<&str>)]) -> web::Data<AppState> {
+        let state = testing::state(args);
+        for (id, domain) in nodes {
+            testing::announce(&state, "lmstudio", id, "http://127.0.0.1:1/");
+            if let Some(domain) = domain {
+                state.set_node_domain(&LMSTUDIO, &testing::node_id(id), domain);
+            }
+        }
+        state
+    }
+
+    fn sole_domain(state: &AppState) -> Option<String> {
+        state.domains.sole_domain(&state.lm_studio_nodes.read().unwrap())
+    }
+
+    #[test]
+    fn configured_domain_wins_over_the_announced_one() {
+        let state = pool(&["--node-domain", "box1=rack-b"], &[("box1", Some("rack-a")), ("box2", Some("rack-a")), ("box3", None)]);
+        let nodes = state.lm_studio_nodes.read().unwrap();
+        assert_eq!(state.domains.domain_of("box1", &nodes["box1"]), Some("rack-b"));
+        assert_eq!(state.domains.domain_of("box2", &nodes["box2"]), Some("rack-a"));
+        assert_eq!(state.domains.domain_of("box3", &nodes["box3"]), None);
+    }
+
+    #[test]
+    fn sole_domain_counts_only_usable_nodes_and_undomained_ones_as_their_own() {
+        let state = pool(&[], &[("box1", Some("rack-a")), ("box2", Some("rack-a")), ("box3", Some("rack-b"))]);
+        assert_eq!(sole_domain(&state), None);
+        state.update_node_state(&LMSTUDIO, &testing::node_id("box3"), NodeHealth::Failed(Instant::now()), TransitionCause::HealthCheck);
+        assert_eq!(sole_domain(&state).as_deref(), Some("rack-a"));
+        state.update_node_state(&LMSTUDIO, &testing::node_id("box3"), NodeHealth::Draining(Instant::now()), TransitionCause::Drain);
+        assert_eq!(sole_domain(&state).as_deref(), Some("rack-a"));
+
+        // Un nodo sin dominio no comparte caída con nadie.
+        testing::announce(&state, "lmstudio", "box4", "http://127.0.0.1:1/");
+        assert_eq!(sole_domain(&state), None);
+        assert_eq!(sole_domain(&pool(&[], &[("box1", None)])), None);
+        assert_eq!(sole_domain(&pool(&[], &[])), None);
+    }
+
+    /// Nodo que contesta con una llamada a herramientas; mal formada (sin `id`) si `valid` es falso.
+    fn tool_node(valid: bool) -> String {
+        testing::backend(move |cfg| {
+            cfg.default_service(web::to(move || async move {
+                let mut call = serde_json::json!({ "id": "call_1", "type": "function", "function": { "name": "f", "arguments": "{}" } });
+                if !valid {
+                    call.as_object_mut().unwrap().remove("id");
+                }
+                HttpResponse::Ok().json(serde_json::json!({
+                    "choices": [{ "finish_reason": "tool_calls", "message": { "role": "assistant", "tool_calls": [call] } }]
+                }))
+            }));
+        })
+    }
+
+    #[actix_web::test]
+    async fn tool_call_retry_prefers_a_node_in_another_domain() {
+        let state = testing::state(&["--node-selection", "first-available", "--retry-invalid-tool-calls", "--node-domain", "a2=rack-a"]);
+        testing::announce(&state, "lmstudio", "a1", &tool_node(false));
+        state.set_node_domain(&LMSTUDIO, &testing::node_id("a1"), "rack-a");
+        // a2 va antes que b1 por ID: sin los dominios, el reintento sería para él.
+        testing::announce(&state, "lmstudio", "a2", &tool_node(true));
+        testing::announce(&state, "lmstudio", "b1", &tool_node(true));
+        state.set_node_domain(&LMSTUDIO, &testing::node_id("b1"), "rack-b");
+        let app = init_service(balancer::app(state.clone())).await;
+        let served_by = || {
+            let app = &app;
+            async move {
+                let req = TestRequest::post().uri("/lmstudio").set_json(serde_json::json!({ "messages": [{ "role": "user", "content": "hola" }] }));
+                let res = call_service(app, req.to_request()).await;
+                assert_eq!(res.status(), 200);
+                let node = res.headers().get(NODE_ID_HEADER).unwrap().to_str().unwrap().to_string();
+                let body: serde_json::Value = serde_json::from_slice(&read_body(res).await).unwrap();
+                assert_eq!(body["choices"][0]["message"]["tool_calls"][0]["id"], "call_1");
+                node
+            }
+        };
+
+        assert_eq!(served_by().await, "b1");
+        assert_eq!(served_by().await, "b1");
+
+        // Sin otro dominio disponible, se repite en el mismo.
+        state.update_node_state(&LMSTUDIO, &testing::node_id("b1"), NodeHealth::Failed(Instant::now()), TransitionCause::HealthCheck);
+        assert_eq!(served_by().await, "a2");
+        assert_eq!(state.metrics.tool_call_retries.load(std::sync::atomic::Ordering::Relaxed), 3);
+
+        // El reparto por dominio suma los nodos de cada uno.
+        let report = state.fairness.report();
+        let domains = &report["lmstudio"].domains;
+        assert_eq!(domains.keys().collect::<Vec<_>>(), ["rack-a", "rack-b"]);
+        assert_eq!((domains["rack-a"].nodes, domains["rack-a"].dispatched), (2, 4));
+        assert_eq!((domains["rack-b"].nodes, domains["rack-b"].dispatched), (1, 2));
+    }
+
+    /// Espera a que lleguen `count` avisos de redundancia y los devuelve resumidos.
+    async fn redundancy_events(events: &mut Receiver<Arc<BalancerEvent>>, count: usize) -> Vec<String> {
+        let mut seen = Vec::new();
+        testing::eventually(|| {
+            seen.extend(std::iter::from_fn(|| events.try_recv().ok()).filter_map(|event| match &*event {
+                BalancerEvent::DomainRedundancyLost { service, domain, nodes } => Some(format!("lost {} {} {}", service, domain, nodes)),
+                BalancerEvent::DomainRedundancyRestored { service } => Some(format!("restored {}", service)),
+                _ => None,
+            }));
+            seen.len() >= count
+        })
+        .await;
+        seen
+    }
+
+    #[actix_web::test]
+    async fn redundancy_is_lost_when_one_domain_is_left_and_restored_after() {
+        let state = pool(&[], &[("a1", Some("rack-a")), ("a2", Some("rack-a")), ("b1", Some("rack-b"))]);
+        // Una pool que nunca tuvo nodos en más de un dominio no avisa.
+        for id in ["c1", "c2"] {
+            testing::announce(&state, "ollama", id, "http://127.0.0.1:1/");
+            state.set_node_domain(&OLLAMA, &testing::node_id(id), "rack-c");
+        }
+        let _consumers = testing::consumers(&state);
+        let (mut events, _subscriber) = events::admit(state.clone()).unwrap();
+        let fail = |service: &str, id: &str| state.update_node_state(&testing::pool_name(service), &testing::node_id(id), NodeHealth::Failed(Instant::now()), TransitionCause::HealthCheck);
+        let recover = |service: &str, id: &str| state.update_node_state(&testing::pool_name(service), &testing::node_id(id), NodeHealth::Available, TransitionCause::HealthCheck);
+
+        // El vigilante lee la pool al procesar cada evento: se le deja ver cada cambio antes del siguiente.
+        let settle = || tokio::time::sleep(std::time::Duration::from_millis(50));
+
+        fail("ollama", "c1");
+        settle().await;
+        recover("ollama", "c1");
+        // Caer un nodo de rack-a deja otro dominio; caer el único de rack-b, no.
+        fail("lmstudio", "a2");
+        settle().await;
+        fail("lmstudio", "b1");
+        assert_eq!(redundancy_events(&mut events, 1).await, ["lost lmstudio rack-a 1"]);
+        // Con todo en rack-a, que vuelva otro nodo de rack-a no cambia nada.
+        recover("lmstudio", "a2");
+        settle().await;
+        recover("lmstudio", "b1");
+        assert_eq!(redundancy_events(&mut events, 1).await, ["restored lmstudio"]);
+    }
+}
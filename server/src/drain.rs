@@ -0,0 +1,112 @@
+// src/drain.rs
+//! Peticiones en curso en un nodo cuyo plazo de drenaje vence (`--drain-policy`).
+//!
+//! Un nodo que anuncia `DRAINING` deja de recibir peticiones nuevas y sale del registro al
+//! vencer el plazo, pero lo que ya tenía en curso sigue hasta que el nodo se apaga: un stream
+//! que el cliente deja abierto obliga a elegir entre esperar o cortarlo con un error. Las
+//! peticiones que esperan nodo en la cola nunca están atadas a uno que se drena (tampoco una
+//! reserva de `X-Pipeline`, que se pierde al pasar el nodo a draining), así que ya van a otro.
+//!
+//! - `wait` (por defecto): nada cambia; las peticiones en curso terminan cuando termine el nodo.
+//! - `migrate`: al vencer el plazo,
+//!   - una petición sin stream que aún espera la respuesta del nodo se reenvía a otro nodo libre
+//!     de la pool, esperando en la cola como mucho su tiempo de espera;
+//!   - un stream ya empezado se corta con un evento de error (`data: {"error": ...}` en SSE, una
+//!     línea `{"error": ...}` en NDJSON) que trae `resume_hint`: el id de la petición y
+//!     `resume: true`, para que el cliente repita la petición con lo que ya recibió.
+//!
+//!   Después el nodo queda libre para apagarse. Cada corte se publica como `request_terminated`
+//!   y queda en la auditoría con `terminated: "drain_timeout"`.
+use actix_web::web::Bytes;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+
+/// Motivo de un corte por drenaje, en eventos y auditoría.
+pub const DRAIN_TIMEOUT: &str = "drain_timeout";
+
+/// Qué hacer con las peticiones en curso de un nodo al vencer su plazo de drenaje.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum DrainPolicy {
+    /// Esperar a que terminen.
+    Wait,
+    /// Reenviar las que no tienen respuesta y cortar los streams con una pista para reanudar.
+    Migrate,
+}
+
+impl DrainPolicy {
+    pub fn label(&self) -> &'static str {
+        match self {
+            DrainPolicy::Wait => "wait",
+            DrainPolicy::Migrate => "migrate",
+        }
+    }
+}
+
+/// Aviso de plazo vencido para las peticiones en curso de cada nodo.
+pub struct DrainDeadlines {
+    policy: DrainPolicy,
+    /// Por (pool, nodo), el aviso que comparten sus peticiones en curso.
+    tokens: Mutex<HashMap<(String, String), CancellationToken>>,
+}
+
+impl DrainDeadlines {
+    pub fn new(policy: DrainPolicy) -> Self {
+        Self { policy, tokens: Mutex::default() }
+    }
+
+    /// Aviso que recibe una petición en curso en el nodo; `None` con `wait`.
+    pub fn watch(&self, service: &str, unique_node_id: &str) -> Option<CancellationToken> {
+        if self.policy == DrainPolicy::Wait {
+            return None;
+        }
+        let mut tokens = self.tokens.lock().unwrap();
+        Some(tokens.entry((service.to_string(), unique_node_id.to_string())).or_default().clone())
+    }
+
+    /// El nodo salió del registro; si fue por vencer su plazo (`expired`), avisa a sus peticiones.
+    pub fn node_removed(&self, service: &str, unique_node_id: &str, expired: bool) {
+        let token = self.tokens.lock().unwrap().remove(&(service.to_string(), unique_node_id.to_string()));
+        if let Some(token) = token.filter(|_| expired) {
+            token.cancel();
+        }
+    }
+
+    /// Configuración para `/config`.
+    pub fn describe(&self) -> Value {
+        json!({ "policy": self.policy.label() })
+    }
+}
+
+/// Espera el aviso de `token`; sin aviso (`wait`), no termina nunca.
+pub async fn expired(token: Option<&CancellationToken>) {
+    match token {
+        Some(token) => token.cancelled().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Pista para que el cliente repita la petición cortada con lo que ya recibió.
+pub fn resume_hint(request_id: &str) -> Value {
+    json!({ "request_id": request_id, "resume": true, "reason": DRAIN_TIMEOUT })
+}
+
+/// Último evento de un stream cortado por drenaje: SSE con el formato de error de OpenAI o una
+/// línea NDJSON con el de Ollama (`error` como texto).
+pub fn termination_event(request_id: &str, sse: bool) -> Bytes {
+    let message = "El nodo que atendía la petición se ha apagado; repítela con lo ya recibido.";
+    if sse {
+        let error = json!({
+            "error": {
+                "message": message,
+                "type": "node_drained",
+                "code": DRAIN_TIMEOUT,
+                "resume_hint": resume_hint(request_id),
+            }
+        });
+        Bytes::from(format!("data: {}\n\n", error))
+    } else {
+        Bytes::from(format!("{}\n", json!({ "error": message, "resume_hint": resume_hint(request_id) })))
+    }
+}
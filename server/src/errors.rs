@@ -0,0 +1,329 @@
+// src/errors.rs
+//! Clasificación de los errores del cliente HTTP al hablar con los nodos.
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::error::Error as StdError;
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::dns::DnsLookupError;
+use crate::limits::StoreUsage;
+
+/// Número máximo de errores recientes guardados por nodo.
+pub const MAX_ERRORS_PER_NODE: usize = 50;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCategory {
+    Dns,
+    ConnectTimeout,
+    ConnectRefused,
+    Tls,
+    ReadTimeout,
+    Reset,
+    BodyDecode,
+    /// La respuesta terminaba en `tool_calls` sin traerlos bien formados; no es un error de reqwest.
+    InvalidToolCalls,
+    /// La respuesta no seguía el formato esperado (`strictness`); tampoco es un error de reqwest.
+    InvalidResponse,
+    Other,
+}
+
+impl ErrorCategory {
+    pub fn label(&self) -> &'static str {
+        match self {
+            ErrorCategory::Dns => "dns",
+            ErrorCategory::ConnectTimeout => "connect_timeout",
+            ErrorCategory::ConnectRefused => "connect_refused",
+            ErrorCategory::Tls => "tls",
+            ErrorCategory::ReadTimeout => "read_timeout",
+            ErrorCategory::Reset => "reset",
+            ErrorCategory::BodyDecode => "body_decode",
+            ErrorCategory::InvalidToolCalls => "invalid_tool_calls",
+            ErrorCategory::InvalidResponse => "invalid_response",
+            ErrorCategory::Other => "other",
+        }
+    }
+}
+
+/// Recorre la cadena de `source()` del error.
+fn chain<'a>(error: &'a (dyn StdError + 'static)) -> impl Iterator<Item = &'a (dyn StdError + 'static)> {
+    std::iter::successors(Some(error), |&e| e.source())
+}
+
+/// Clasifica un error de reqwest a partir de sus métodos de inspección y de su cadena de causas.
+pub fn classify(error: &reqwest::Error) -> ErrorCategory {
+    let causes: Vec<&(dyn StdError + 'static)> = chain(error).collect();
+
+    if causes.iter().any(|e| e.is::<DnsLookupError>()) {
+        return ErrorCategory::Dns;
+    }
+    // El backend TLS no es una dependencia directa: se reconoce por el texto de la causa.
+    if causes.iter().any(|e| {
+        let text = e.to_string().to_ascii_lowercase();
+        text.contains("tls") || text.contains("ssl") || text.contains("certificate")
+    }) {
+        return ErrorCategory::Tls;
+    }
+    if error.is_timeout() {
+        return if error.is_connect() { ErrorCategory::ConnectTimeout } else { ErrorCategory::ReadTimeout };
+    }
+
+    let io_kind = causes.iter().find_map(|e| e.downcast_ref::<io::Error>()).map(io::Error::kind);
+    match io_kind {
+        Some(io::ErrorKind::ConnectionRefused) => return ErrorCategory::ConnectRefused,
+        Some(io::ErrorKind::TimedOut) if error.is_connect() => return ErrorCategory::ConnectTimeout,
+        Some(io::ErrorKind::TimedOut) => return ErrorCategory::ReadTimeout,
+        Some(
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof,
+        ) => return ErrorCategory::Reset,
+        _ => {}
+    }
+    if causes
+        .iter()
+        .filter_map(|e| e.downcast_ref::<hyper::Error>())
+        .any(|e| e.is_incomplete_message() || e.is_closed() || e.is_canceled())
+    {
+        return ErrorCategory::Reset;
+    }
+    if error.is_decode() || error.is_body() {
+        return ErrorCategory::BodyDecode;
+    }
+    ErrorCategory::Other
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct UpstreamError {
+    pub timestamp: String,
+    pub service: String,
+    pub category: ErrorCategory,
+    pub message: String,
+    #[serde(skip)]
+    pub at: Instant,
+}
+
+/// Errores recientes por nodo, para `GET /nodes/{id}`.
+pub struct ErrorLog {
+    entries: HashMap<String, VecDeque<UpstreamError>>,
+    /// Nodos con errores guardados. Lleno, se olvida el nodo con el error más antiguo.
+    max_nodes: usize,
+}
+
+impl ErrorLog {
+    pub fn new(max_nodes: usize) -> Self {
+        Self { entries: HashMap::new(), max_nodes }
+    }
+
+    pub fn record(&mut self, unique_node_id: &str, service: &str, category: ErrorCategory, message: String) {
+        if !self.entries.contains_key(unique_node_id) && self.entries.len() >= self.max_nodes {
+            let stalest = self.entries.iter().min_by_key(|(_, log)| log.back().map(|e| e.at)).map(|(id, _)| id.clone());
+            if let Some(stalest) = stalest {
+                self.entries.remove(&stalest);
+            }
+        }
+        let log = self.entries.entry(unique_node_id.to_string()).or_default();
+        if log.len() == MAX_ERRORS_PER_NODE {
+            log.pop_front();
+        }
+        log.push_back(UpstreamError {
+            timestamp: chrono::Local::now().to_rfc3339(),
+            service: service.to_string(),
+            category,
+            message,
+            at: Instant::now(),
+        });
+    }
+
+    /// Nodos con errores registrados en la pool indicada.
+    pub fn nodes_in(&self, service: &str) -> Vec<String> {
+        self.entries
+            .iter()
+            .filter(|(_, log)| log.iter().any(|error| error.service == service))
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    pub fn usage(&self) -> StoreUsage {
+        let bytes = self
+            .entries
+            .iter()
+            .map(|(id, log)| {
+                std::mem::size_of::<(String, VecDeque<UpstreamError>)>()
+                    + id.len()
+                    + log
+                        .iter()
+                        .map(|e| std::mem::size_of::<UpstreamError>() + e.timestamp.len() + e.service.len() + e.message.len())
+                        .sum::<usize>()
+            })
+            .sum();
+        StoreUsage { entries: self.entries.len(), bytes }
+    }
+
+    pub fn clear(&mut self, unique_node_id: &str) {
+        self.entries.remove(unique_node_id);
+    }
+
+    pub fn clear_all(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn recent(&self, unique_node_id: &str) -> Vec<UpstreamError> {
+        self.entries.get(unique_node_id).map_or_else(Vec::new, |log| log.iter().cloned().collect())
+    }
+
+    /// Errores del nodo por categoría dentro de la ventana indicada.
+    pub fn counts_within(&self, unique_node_id: &str, window: Duration) -> BTreeMap<&'static str, u64> {
+        let mut counts = BTreeMap::new();
+        for error in self.entries.get(unique_node_id).into_iter().flatten() {
+            if error.at.elapsed() <= window {
+                *counts.entry(error.category.label()).or_default() += 1;
+            }
+        }
+        counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body, read_body_json, TestRequest};
+    use actix_web::{web, HttpResponse};
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+    use crate::balancer;
+    use crate::dns::CachingResolver;
+    use crate::testing;
+
+    fn client() -> reqwest::Client {
+        reqwest::Client::builder()
+            .dns_resolver(Arc::new(CachingResolver::new(Duration::from_secs(60), 16)))
+            .connect_timeout(Duration::from_millis(300))
+            .timeout(Duration::from_millis(500))
+            .build()
+            .unwrap()
+    }
+
+    /// Categoría del error de un GET a `url`, leyendo la respuesta entera como JSON.
+    async fn category_of(url: &str) -> ErrorCategory {
+        let result = async { client().get(url).send().await?.error_for_status()?.json::<Value>().await }.await;
+        classify(&result.expect_err("la petición no falló"))
+    }
+
+    /// Servidor TCP que a cada conexión le lee la petición y contesta `response` tal cual.
+    async fn raw_server(response: &'static [u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut request = [0; 4096];
+                    let _ = socket.read(&mut request).await;
+                    let _ = socket.write_all(response).await;
+                });
+            }
+        });
+        format!("http://{}/", addr)
+    }
+
+    #[actix_web::test]
+    async fn dns_failure() {
+        assert_eq!(category_of("http://gpu1.lab.invalid/").await, ErrorCategory::Dns);
+    }
+
+    #[actix_web::test]
+    async fn connect_refused() {
+        assert_eq!(category_of(&testing::unreachable_url()).await, ErrorCategory::ConnectRefused);
+    }
+
+    #[actix_web::test]
+    async fn connect_timeout() {
+        // Un listener que no acepta nunca, con la cola llena: el SYN siguiente se descarta.
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let listener = socket.listen(1).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut queued = Vec::new();
+        for _ in 0..4 {
+            if let Ok(Ok(stream)) = tokio::time::timeout(Duration::from_millis(100), TcpStream::connect(addr)).await {
+                queued.push(stream);
+            }
+        }
+
+        assert_eq!(category_of(&format!("http://{}/", addr)).await, ErrorCategory::ConnectTimeout);
+    }
+
+    #[actix_web::test]
+    async fn tls_against_a_plain_http_node() {
+        let url = testing::backend(|cfg| {
+            cfg.default_service(web::to(|| async { HttpResponse::Ok().json(json!({})) }));
+        });
+        assert_eq!(category_of(&url.replace("http://", "https://")).await, ErrorCategory::Tls);
+    }
+
+    #[actix_web::test]
+    async fn read_timeout() {
+        let url = testing::backend(|cfg| {
+            cfg.default_service(web::to(|| async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                HttpResponse::Ok().finish()
+            }));
+        });
+        assert_eq!(category_of(&url).await, ErrorCategory::ReadTimeout);
+    }
+
+    #[actix_web::test]
+    async fn reset_before_the_response() {
+        let url = raw_server(b"").await;
+        assert_eq!(category_of(&url).await, ErrorCategory::Reset);
+    }
+
+    #[actix_web::test]
+    async fn reset_in_the_middle_of_the_body() {
+        let url = raw_server(b"HTTP/1.1 200 OK\r\ncontent-length: 100\r\ncontent-type: application/json\r\n\r\n{\"choices\"").await;
+        assert_eq!(category_of(&url).await, ErrorCategory::Reset);
+    }
+
+    #[actix_web::test]
+    async fn body_decode() {
+        let url = raw_server(b"HTTP/1.1 200 OK\r\ncontent-length: 10\r\ncontent-type: application/json\r\n\r\nno es json").await;
+        assert_eq!(category_of(&url).await, ErrorCategory::BodyDecode);
+    }
+
+    #[actix_web::test]
+    async fn anything_else_is_other() {
+        assert_eq!(category_of("http://[::1]:99999/").await, ErrorCategory::Other);
+        let url = raw_server(b"HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\n\r\n").await;
+        assert_eq!(category_of(&url).await, ErrorCategory::Other);
+    }
+
+    #[actix_web::test]
+    async fn failed_forward_is_recorded_by_category() {
+        let state = testing::state(&[]);
+        testing::announce(&state, "lmstudio", "box1", &testing::unreachable_url());
+        let app = init_service(balancer::app(state.clone())).await;
+
+        let req = TestRequest::post()
+            .uri("/lmstudio")
+            .set_json(json!({ "model": "m", "messages": [{ "role": "user", "content": "hola" }] }))
+            .to_request();
+        assert!(!call_service(&app, req).await.status().is_success());
+
+        let detail: Value = read_body_json(call_service(&app, TestRequest::get().uri("/nodes/box1").to_request()).await).await;
+        assert_eq!(detail["services"][0]["state"], "failed");
+        assert!(detail["services"][0]["last_error"].as_str().unwrap().starts_with("connect_refused: "), "{}", detail);
+        assert_eq!(detail["errors"]["total"]["connect_refused"], 1, "{}", detail);
+        assert_eq!(detail["errors"]["last_hour"], json!({ "connect_refused": 1 }));
+        assert_eq!(detail["errors"]["recent"][0]["category"], "connect_refused");
+
+        let metrics = read_body(call_service(&app, TestRequest::get().uri("/metrics").to_request()).await).await;
+        let metrics = String::from_utf8(metrics.to_vec()).unwrap();
+        assert!(metrics.contains(r#"lmserver_upstream_errors_total{node="box1",category="connect_refused"} 1"#), "{}", metrics);
+    }
+}
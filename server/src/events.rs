@@ -0,0 +1,670 @@
+// src/events.rs
+//! Bus interno de eventos del balanceador.
+//!
+//! Los puntos que cambian el estado publican un `BalancerEvent` en un único canal de difusión
+//! y cada consumidor (historial, log, alertas de la UI y `GET /events`) es un suscriptor
+//! independiente. La representación serde de `BalancerEvent` es el esquema externo estable:
+//! cada evento SSE lleva en `event:` el mismo nombre que el campo `type` de su JSON.
+use actix_web::web::{self, Bytes};
+use futures_util::stream::{self, Stream};
+use log::{debug, info, warn};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{interval, Interval, MissedTickBehavior};
+
+use crate::balancer::{AppState, NodeHealth};
+use crate::history::TransitionCause;
+use crate::limits::StoreUsage;
+use crate::profiles::RuntimeSettings;
+use crate::reload::ConfigChange;
+use crate::support::RecentEvent;
+use crate::tasks::BackgroundTasks;
+use crate::tombstones::RemovalReason;
+
+/// Intervalo de los comentarios `: ping`. Escribir periódicamente es lo que permite
+/// detectar conexiones muertas (portátil cerrado) en menos de un minuto.
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BalancerEvent {
+    /// Un nodo aparece en una pool.
+    NodeRegistered { node_id: String, service: String, state: &'static str, cause: TransitionCause, timestamp: String },
+    /// Un nodo sale de una pool; `tombstone_id` es su lápida en `/nodes?include=removed`.
+    NodeRemoved {
+        node_id: String,
+        service: String,
+        from: &'static str,
+        cause: TransitionCause,
+        reason: RemovalReason,
+        tombstone_id: Option<String>,
+        timestamp: String,
+    },
+    /// Un nodo anunció que se apagará dentro de `lead_secs` y deja de recibir peticiones nuevas.
+    NodeDraining { service: String, node_id: String, lead_secs: u64 },
+    /// Un nodo que se drenaba volvió a anunciarse antes de su plazo.
+    NodeDrainCancelled { service: String, node_id: String, remaining_secs: u64 },
+    StateChanged {
+        node_id: String,
+        service: String,
+        from: &'static str,
+        to: &'static str,
+        cause: TransitionCause,
+        timestamp: String,
+    },
+    /// La pool se quedó sin nodos que puedan atender peticiones.
+    PoolEmpty { service: String },
+    PoolRecovered { service: String },
+    /// Todos los nodos utilizables de la pool están en un mismo dominio de fallo (`domains`).
+    DomainRedundancyLost { service: String, domain: String, nodes: usize },
+    /// La pool vuelve a tener nodos utilizables en más de un dominio de fallo.
+    DomainRedundancyRestored { service: String },
+    RequestCompleted { service: String, node_id: String, status: u16, duration_ms: u64, streamed: bool },
+    ProfileChanged { from: String, to: String, reason: String, settings: RuntimeSettings },
+    StatsReset { scope: String, epoch: u64 },
+    ModelLoadDecision { service: String, node_id: String, model: String, unload: Vec<String>, reason: String },
+    ModelUnloaded { service: String, node_id: String, model: String },
+    ModelLoaded { service: String, node_id: String, model: String, elapsed_ms: u64 },
+    ModelLoadFailed { service: String, node_id: String, model: String, error: String, elapsed_ms: u64 },
+    /// El volumen de modelos del nodo bajó del mínimo de espacio libre.
+    DiskSpaceLow { service: String, node_id: String, free_bytes: u64, min_free_bytes: u64 },
+    DiskSpaceRecovered { service: String, node_id: String, free_bytes: u64 },
+    /// Se recargó el archivo de reglas de enrutado.
+    RulesReloaded { rules: usize },
+    /// Se aplicó una configuración (`reload`): recargada, o la anterior con `rollback`.
+    ConfigReloaded { operation: &'static str, revision: u64, changes: Vec<ConfigChange> },
+    /// El balanceador cortó una petición en curso: `redispatched` si se reenvió a otro nodo,
+    /// `terminated` si era un stream ya empezado y `failed` si no quedaba nodo al que reenviarla.
+    RequestTerminated { service: String, node_id: String, request_id: String, reason: &'static str, action: &'static str, streamed: bool },
+    /// Una petición pasa a una pool más cara de la cadena de coste.
+    PoolSpill { from: String, to: String, reason: &'static str, waited_ms: u64 },
+    /// Un nodo recibe, de forma sostenida, una cuota de peticiones muy distinta de la esperada.
+    FairnessSkew { service: String, node_id: String, share: f64, expected_share: f64, skew: f64, window_secs: u64 },
+    /// Informe de `POST /nodes/{id}/diagnose`.
+    NodeDiagnosed { node_id: String, service: String, report: serde_json::Value },
+    /// Una nueva prueba cambió las capacidades de un nodo (`reprobe`).
+    NodeCapabilitiesChanged { service: String, node_id: String, backend_version: Option<String>, before: Vec<String>, after: Vec<String> },
+    /// La prueba de rendimiento de un nodo empeora frente a la anterior más del umbral (`benchmark`).
+    BenchmarkRegression { service: String, node_id: String, model: String, metric: &'static str, baseline: f64, current: f64, change_pct: f64 },
+    /// Terminó la fase canaria de un nodo (`canary`): `promoted` si pasa a tráfico completo,
+    /// `failed` si se marcó Failed y repite la fase.
+    NodeCanaryEnded { service: String, node_id: String, outcome: &'static str, requests: u32, errors: u32, error_rate: f64 },
+    /// Llega un mensaje con el ID de un nodo desde una IP distinta de la fijada (`pins`);
+    /// `rejected` si se descartó.
+    NodeIdentityMismatch { node_id: String, pinned_ip: String, source_ip: String, rejected: bool },
+}
+
+impl BalancerEvent {
+    /// Nombre del evento; coincide con el campo `type` de su JSON.
+    pub fn name(&self) -> &'static str {
+        match self {
+            BalancerEvent::NodeRegistered { .. } => "node_registered",
+            BalancerEvent::NodeRemoved { .. } => "node_removed",
+            BalancerEvent::NodeDraining { .. } => "node_draining",
+            BalancerEvent::NodeDrainCancelled { .. } => "node_drain_cancelled",
+            BalancerEvent::StateChanged { .. } => "state_changed",
+            BalancerEvent::PoolEmpty { .. } => "pool_empty",
+            BalancerEvent::PoolRecovered { .. } => "pool_recovered",
+            BalancerEvent::DomainRedundancyLost { .. } => "domain_redundancy_lost",
+            BalancerEvent::DomainRedundancyRestored { .. } => "domain_redundancy_restored",
+            BalancerEvent::RequestCompleted { .. } => "request_completed",
+            BalancerEvent::ProfileChanged { .. } => "profile_changed",
+            BalancerEvent::StatsReset { .. } => "stats_reset",
+            BalancerEvent::ModelLoadDecision { .. } => "model_load_decision",
+            BalancerEvent::ModelUnloaded { .. } => "model_unloaded",
+            BalancerEvent::ModelLoaded { .. } => "model_loaded",
+            BalancerEvent::ModelLoadFailed { .. } => "model_load_failed",
+            BalancerEvent::DiskSpaceLow { .. } => "disk_space_low",
+            BalancerEvent::DiskSpaceRecovered { .. } => "disk_space_recovered",
+            BalancerEvent::RulesReloaded { .. } => "rules_reloaded",
+            BalancerEvent::ConfigReloaded { .. } => "config_reloaded",
+            BalancerEvent::FairnessSkew { .. } => "fairness_skew",
+            BalancerEvent::PoolSpill { .. } => "pool_spill",
+            BalancerEvent::RequestTerminated { .. } => "request_terminated",
+            BalancerEvent::NodeDiagnosed { .. } => "node_diagnosed",
+            BalancerEvent::NodeCapabilitiesChanged { .. } => "node_capabilities_changed",
+            BalancerEvent::BenchmarkRegression { .. } => "benchmark_regression",
+            BalancerEvent::NodeIdentityMismatch { .. } => "node_identity_mismatch",
+            BalancerEvent::NodeCanaryEnded { .. } => "node_canary_ended",
+        }
+    }
+
+    fn sse_frame(&self) -> String {
+        let payload = serde_json::to_string(self).unwrap_or_default();
+        format!("event: {}\ndata: {}\n\n", self.name(), payload)
+    }
+}
+
+/// Canal de difusión de eventos hacia todos los consumidores.
+pub struct EventHub {
+    sender: broadcast::Sender<Arc<BalancerEvent>>,
+    max_subscribers: usize,
+}
+
+impl EventHub {
+    /// `buffer` son los eventos en cola por suscriptor antes de considerarlo rezagado.
+    pub fn new(max_subscribers: usize, buffer: usize) -> Self {
+        let (sender, _) = broadcast::channel(buffer);
+        Self { sender, max_subscribers }
+    }
+
+    /// Eventos retenidos en el canal porque algún suscriptor aún no los leyó.
+    pub fn usage(&self) -> StoreUsage {
+        let entries = self.sender.len();
+        StoreUsage { entries, bytes: entries * (std::mem::size_of::<Arc<BalancerEvent>>() + std::mem::size_of::<BalancerEvent>()) }
+    }
+
+    pub fn publish(&self, event: BalancerEvent) {
+        let _ = self.sender.send(Arc::new(event));
+    }
+}
+
+/// Arranca un consumidor interno. Un consumidor rezagado pierde eventos pero sigue escuchando.
+fn spawn_consumer<F>(tasks: &mut BackgroundTasks, state: &web::Data<AppState>, name: &'static str, mut handle: F)
+where
+    F: FnMut(&AppState, &BalancerEvent) + Send + 'static,
+{
+    let mut receiver = state.events.sender.subscribe();
+    let state = state.clone();
+    tasks.spawn(name, async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => handle(&state, &event),
+                Err(RecvError::Lagged(skipped)) => warn!("Events: El consumidor '{}' perdió {} eventos.", name, skipped),
+                Err(RecvError::Closed) => return,
+            }
+        }
+    });
+}
+
+/// Arranca los consumidores internos: historial de transiciones, vigilancia de pools vacías,
+/// log estructurado y alertas de la UI.
+pub fn spawn_consumers(tasks: &mut BackgroundTasks, state: &web::Data<AppState>) {
+    spawn_consumer(tasks, state, "history", |state, event| {
+        let (node_id, service, from, to, cause) = match event {
+            BalancerEvent::NodeRegistered { node_id, service, state: to, cause, .. } => {
+                (node_id, service, NodeHealth::ABSENT_LABEL, *to, *cause)
+            }
+            BalancerEvent::NodeRemoved { node_id, service, from, cause, .. } => {
+                (node_id, service, *from, NodeHealth::ABSENT_LABEL, *cause)
+            }
+            BalancerEvent::StateChanged { node_id, service, from, to, cause, .. } => (node_id, service, *from, *to, *cause),
+            _ => return,
+        };
+        state.node_history.write().unwrap().record(node_id, service, from, to, cause);
+    });
+
+    // Las pools vacías se deducen de los cambios de los nodos. Una pool que nunca tuvo nodos no avisa.
+    // Igual la pérdida de redundancia: sólo avisa una pool que tuvo nodos en más de un dominio.
+    let mut serving: HashMap<String, bool> = HashMap::new();
+    let mut sole_domains: HashMap<String, Option<String>> = HashMap::new();
+    spawn_consumer(tasks, state, "pool_watch", move |state, event| {
+        let service = match event {
+            BalancerEvent::NodeRegistered { service, .. }
+            | BalancerEvent::NodeRemoved { service, .. }
+            | BalancerEvent::StateChanged { service, .. } => service,
+            _ => return,
+        };
+        let Some(lock) = state.pool(service) else {
+            return;
+        };
+        let nodes = lock.read().unwrap();
+        let now_serving = nodes.values().any(|info| !matches!(info.state, NodeHealth::Failed(_)));
+        let usable = nodes.values().filter(|info| !matches!(info.state, NodeHealth::Failed(_) | NodeHealth::Draining(_))).count();
+        let sole_domain = state.domains.sole_domain(&nodes);
+        drop(nodes);
+        match serving.insert(service.clone(), now_serving) {
+            Some(true) if !now_serving => state.events.publish(BalancerEvent::PoolEmpty { service: service.clone() }),
+            Some(false) if now_serving => state.events.publish(BalancerEvent::PoolRecovered { service: service.clone() }),
+            _ => {}
+        }
+        // Sin nodos utilizables ya avisa `pool_empty`; aquí sólo cuenta pasar de varios dominios a uno.
+        let diverse = usable > 0 && sole_domain.is_none();
+        match (sole_domains.get(service), &sole_domain) {
+            (Some(None), Some(domain)) => state.events.publish(BalancerEvent::DomainRedundancyLost {
+                service: service.clone(),
+                domain: domain.clone(),
+                nodes: usable,
+            }),
+            (Some(Some(_)), None) if diverse => state.events.publish(BalancerEvent::DomainRedundancyRestored { service: service.clone() }),
+            _ => {}
+        }
+        if sole_domain.is_some() || diverse {
+            sole_domains.insert(service.clone(), sole_domain);
+        }
+    });
+
+    spawn_consumer(tasks, state, "request_window", |state, event| {
+        if let BalancerEvent::RequestCompleted { duration_ms, .. } = event {
+            state.request_window.record(*duration_ms);
+        }
+    });
+
+    spawn_consumer(tasks, state, "recent_events", |state, event| {
+        state.recent_events.record(RecentEvent::new(event.clone()));
+    });
+
+    spawn_consumer(tasks, state, "log", |_, event| {
+        let payload = serde_json::to_string(event).unwrap_or_default();
+        match event {
+            BalancerEvent::RequestCompleted { .. } => debug!("Events: {} {}", event.name(), payload),
+            _ => info!("Events: {} {}", event.name(), payload),
+        }
+    });
+
+    spawn_consumer(tasks, state, "ui_alerts", |state, event| match event {
+        BalancerEvent::PoolEmpty { service } => {
+            warn!("¡ALERTA! La pool {} se ha quedado sin nodos disponibles.", service);
+            state.empty_pools.lock().unwrap().insert(service.clone());
+        }
+        BalancerEvent::PoolRecovered { service } => {
+            info!("La pool {} vuelve a tener nodos disponibles.", service);
+            state.empty_pools.lock().unwrap().remove(service);
+        }
+        BalancerEvent::DomainRedundancyLost { service, domain, nodes } => {
+            warn!("¡ALERTA! Los {} nodos utilizables de la pool {} están en el dominio de fallo '{}': si cae, cae la pool.", nodes, service, domain);
+        }
+        BalancerEvent::DomainRedundancyRestored { service } => {
+            info!("La pool {} vuelve a tener nodos en más de un dominio de fallo.", service);
+        }
+        BalancerEvent::BenchmarkRegression { node_id, model, metric, baseline, current, change_pct, .. } => {
+            warn!("¡ALERTA! El nodo {} rinde peor con {}: {} pasa de {} a {} ({}% peor).", node_id, model, metric, baseline, current, change_pct);
+        }
+        BalancerEvent::NodeIdentityMismatch { node_id, pinned_ip, source_ip, rejected } => {
+            let action = if *rejected { "se descarta" } else { "se atiende igualmente" };
+            warn!("¡ALERTA! Mensaje del nodo {} desde {}, pero está fijado a {}: {}.", node_id, source_ip, pinned_ip, action);
+        }
+        BalancerEvent::NodeCanaryEnded { service, node_id, outcome: "failed", requests, errors, .. } => {
+            warn!("¡ALERTA! El nodo {} ({}) no supera su fase canaria: {} errores en {} peticiones. Se marca Failed.", node_id, service, errors, requests);
+        }
+        _ => {}
+    });
+}
+
+/// Descuenta al suscriptor cuando su stream se destruye, termine como termine la conexión.
+pub struct SubscriberGuard {
+    state: web::Data<AppState>,
+}
+
+impl SubscriberGuard {
+    /// Cuenta la desconexión de un suscriptor que no leía a tiempo.
+    pub fn record_lag_disconnect(&self) {
+        self.state.metrics.event_lag_disconnects.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Drop for SubscriberGuard {
+    fn drop(&mut self) {
+        let remaining = self.state.metrics.event_subscribers.fetch_sub(1, Ordering::Relaxed) - 1;
+        info!("Events: Suscriptor desconectado. Suscriptores activos: {}", remaining);
+    }
+}
+
+struct Subscription {
+    receiver: broadcast::Receiver<Arc<BalancerEvent>>,
+    ping: Interval,
+    guard: SubscriberGuard,
+    closed: bool,
+}
+
+/// Admite un suscriptor externo (`GET /events` o gRPC) y le da su receptor. Devuelve `None`
+/// si se alcanzó el máximo de suscriptores concurrentes.
+pub fn admit(state: web::Data<AppState>) -> Option<(broadcast::Receiver<Arc<BalancerEvent>>, SubscriberGuard)> {
+    let max = state.events.max_subscribers as u64;
+    let admitted = state.metrics.event_subscribers.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
+        (current < max).then_some(current + 1)
+    });
+    if admitted.is_err() {
+        warn!("Events: Suscriptor rechazado; se alcanzó el máximo de {} suscriptores.", max);
+        return None;
+    }
+    Some((state.events.sender.subscribe(), SubscriberGuard { state }))
+}
+
+/// Registra un nuevo suscriptor. Devuelve `None` si se alcanzó el máximo de suscriptores concurrentes.
+pub fn subscribe(state: web::Data<AppState>) -> Option<impl Stream<Item = Result<Bytes, std::io::Error>>> {
+    let (receiver, guard) = admit(state)?;
+    let mut ping = interval(PING_INTERVAL);
+    ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let subscription = Subscription {
+        receiver,
+        ping,
+        guard,
+        closed: false,
+    };
+
+    Some(stream::unfold(subscription, |mut sub| async move {
+        if sub.closed {
+            return None;
+        }
+        let frame = tokio::select! {
+            event = sub.receiver.recv() => match event {
+                Ok(event) => event.sse_frame(),
+                Err(RecvError::Lagged(skipped)) => {
+                    // Un suscriptor que no lee no puede retener memoria: se le avisa y se le desconecta.
+                    warn!("Events: Suscriptor rezagado ({} eventos perdidos). Desconectando.", skipped);
+                    sub.guard.record_lag_disconnect();
+                    sub.closed = true;
+                    format!("event: lagged\ndata: {{\"skipped\":{}}}\n\n", skipped)
+                }
+                Err(RecvError::Closed) => return None,
+            },
+            _ = sub.ping.tick() => ": ping\n\n".to_string(),
+        };
+        Some((Ok(Bytes::from(frame)), sub))
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::http::StatusCode;
+    use futures_util::{FutureExt, StreamExt};
+    use serde_json::json;
+
+    use crate::balancer::{self, LMSTUDIO};
+    use crate::testing;
+
+    /// Estado con `buffer` eventos en cola por suscriptor.
+    fn state_with_buffer(buffer: usize, args: &[&str]) -> web::Data<AppState> {
+        let limits = testing::temp_file("limits.toml", &format!("[limits]\nevent_buffer = {}\n", buffer));
+        let mut args = args.to_vec();
+        args.extend(["--limits-file", limits.to_str().unwrap()]);
+        testing::state(&args)
+    }
+
+    fn draining(node_id: usize) -> BalancerEvent {
+        BalancerEvent::NodeDraining { service: "lmstudio".to_string(), node_id: format!("box{}", node_id), lead_secs: 30 }
+    }
+
+    fn frames_until_closed<S: Stream<Item = Result<Bytes, std::io::Error>> + Unpin>(stream: &mut S) -> Vec<String> {
+        let mut frames = Vec::new();
+        while let Some(frame) = stream.next().now_or_never().expect("el stream se quedó esperando") {
+            frames.push(String::from_utf8(frame.unwrap().to_vec()).unwrap());
+        }
+        frames
+    }
+
+    #[actix_web::test]
+    async fn subscriber_that_does_not_read_is_bounded_and_disconnected() {
+        let state = state_with_buffer(8, &[]);
+        let mut stream = Box::pin(subscribe(state.clone()).unwrap());
+        assert_eq!(state.metrics.event_subscribers.load(Ordering::Relaxed), 1);
+
+        for i in 0..1000 {
+            state.events.publish(draining(i));
+            assert!(state.events.usage().entries <= 8, "el canal retiene {} eventos", state.events.usage().entries);
+        }
+
+        let frames = frames_until_closed(&mut stream);
+        let lagged = frames.iter().filter(|frame| !frame.starts_with(": ping")).collect::<Vec<_>>();
+        assert_eq!(lagged, ["event: lagged\ndata: {\"skipped\":992}\n\n"]);
+        assert_eq!(state.metrics.event_lag_disconnects.load(Ordering::Relaxed), 1);
+
+        drop(stream);
+        assert_eq!(state.metrics.event_subscribers.load(Ordering::Relaxed), 0);
+    }
+
+    #[actix_web::test]
+    async fn subscriber_that_keeps_up_gets_every_event() {
+        let state = state_with_buffer(8, &[]);
+        let mut stream = Box::pin(subscribe(state.clone()).unwrap());
+
+        for round in 0..10 {
+            for i in 0..8 {
+                state.events.publish(draining(round * 8 + i));
+            }
+            let mut events = 0;
+            while let Some(frame) = stream.next().now_or_never() {
+                events += !frame.unwrap().unwrap().starts_with(b": ping") as usize;
+            }
+            assert_eq!(events, 8, "ronda {}", round);
+        }
+        assert_eq!(state.metrics.event_lag_disconnects.load(Ordering::Relaxed), 0);
+    }
+
+    #[actix_web::test]
+    async fn subscribers_over_the_cap_get_503() {
+        let state = testing::state(&["--max-event-subscribers", "2"]);
+        let app = init_service(balancer::app(state.clone())).await;
+        let get = || TestRequest::get().uri("/events").to_request();
+
+        let first = call_service(&app, get()).await;
+        let second = call_service(&app, get()).await;
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(second.status(), StatusCode::OK);
+        assert_eq!(call_service(&app, get()).await.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(state.metrics.event_subscribers.load(Ordering::Relaxed), 2);
+
+        // Al cerrarse una conexión queda sitio para otra.
+        drop(first);
+        assert_eq!(state.metrics.event_subscribers.load(Ordering::Relaxed), 1);
+        assert_eq!(call_service(&app, get()).await.status(), StatusCode::OK);
+        drop(second);
+    }
+
+    #[actix_web::test]
+    async fn idle_subscriber_is_pinged_within_a_minute() {
+        tokio::time::pause();
+        let state = testing::state(&[]);
+        let mut stream = Box::pin(subscribe(state).unwrap());
+
+        let start = tokio::time::Instant::now();
+        for _ in 0..4 {
+            let frame = tokio::time::timeout(Duration::from_secs(60), stream.next()).await.expect("ningún ping en un minuto");
+            assert_eq!(frame.unwrap().unwrap(), ": ping\n\n");
+        }
+        assert!(start.elapsed() < Duration::from_secs(60), "{:?}", start.elapsed());
+    }
+
+    /// Receptor del bus, como el de cualquier consumidor.
+    fn listen(state: &AppState) -> broadcast::Receiver<Arc<BalancerEvent>> {
+        state.events.sender.subscribe()
+    }
+
+    /// Campos que cambian de una ejecución a otra; `tombstone_id` sólo se comprueba que exista.
+    const VOLATILE: [&str; 3] = ["timestamp", "duration_ms", "remaining_secs"];
+
+    /// Eventos publicados desde la última llamada, en su JSON externo y sin los campos volátiles.
+    fn published(receiver: &mut broadcast::Receiver<Arc<BalancerEvent>>) -> Vec<serde_json::Value> {
+        std::iter::from_fn(|| receiver.try_recv().ok())
+            .map(|event| {
+                let mut json = serde_json::to_value(&*event).unwrap();
+                let object = json.as_object_mut().unwrap();
+                assert_eq!(object["type"], event.name());
+                for field in VOLATILE {
+                    object.remove(field);
+                }
+                if let Some(tombstone_id) = object.get_mut("tombstone_id") {
+                    assert!(tombstone_id.is_string());
+                    *tombstone_id = json!("<lápida>");
+                }
+                json
+            })
+            .collect()
+    }
+
+    /// Comprueba que se publicaron exactamente `expected`, incluidos los que los consumidores
+    /// (`pool_watch`) publican por su cuenta un poco después.
+    async fn assert_published(receiver: &mut broadcast::Receiver<Arc<BalancerEvent>>, expected: Vec<serde_json::Value>) {
+        let mut events = Vec::new();
+        testing::eventually(|| {
+            events.extend(published(receiver));
+            events.len() >= expected.len()
+        })
+        .await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        events.extend(published(receiver));
+        assert_eq!(events, expected);
+    }
+
+    fn state_changed(node_id: &str, from: &str, to: &str, cause: &str) -> serde_json::Value {
+        json!({ "type": "state_changed", "node_id": node_id, "service": "lmstudio", "from": from, "to": to, "cause": cause })
+    }
+
+    #[actix_web::test]
+    async fn registration_publishes_node_registered_once() {
+        let state = testing::state(&[]);
+        let mut events = listen(&state);
+
+        testing::announce(&state, "lmstudio", "box1", "http://10.0.0.1:1234/");
+        testing::announce(&state, "lmstudio", "box1", "http://10.0.0.1:1234/");
+
+        assert_published(
+            &mut events,
+            vec![json!({ "type": "node_registered", "node_id": "box1", "service": "lmstudio", "state": "available", "cause": "heartbeat" })],
+        )
+        .await;
+    }
+
+    #[actix_web::test]
+    async fn request_publishes_only_its_summary() {
+        let url = testing::chat_node(Duration::ZERO);
+        let state = testing::state(&[]);
+        let _consumers = testing::consumers(&state);
+        testing::announce(&state, "lmstudio", "box1", &url);
+        let app = init_service(balancer::app(state.clone())).await;
+        let mut events = listen(&state);
+
+        call_service(&app, testing::chat().to_request()).await;
+
+        // Ocupar y liberar el nodo no son transiciones de ciclo de vida.
+        assert_published(
+            &mut events,
+            vec![json!({ "type": "request_completed", "node_id": "box1", "service": "lmstudio", "status": 200, "streamed": false })],
+        )
+        .await;
+    }
+
+    #[actix_web::test]
+    async fn failed_request_publishes_the_failure_and_the_empty_pool() {
+        let state = testing::state(&[]);
+        let _consumers = testing::consumers(&state);
+        testing::announce(&state, "lmstudio", "box1", &testing::unreachable_url());
+        let app = init_service(balancer::app(state.clone())).await;
+        let mut events = listen(&state);
+        // `pool_watch` ya vio la pool con nodos.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        call_service(&app, testing::chat().to_request()).await;
+
+        assert_published(
+            &mut events,
+            vec![
+                state_changed("box1", "busy", "failed", "request_failure"),
+                json!({ "type": "request_completed", "node_id": "box1", "service": "lmstudio", "status": 500, "streamed": false }),
+                json!({ "type": "pool_empty", "service": "lmstudio" }),
+            ],
+        )
+        .await;
+    }
+
+    #[actix_web::test]
+    async fn health_checks_publish_the_change_and_the_pool_alerts() {
+        let state = testing::state(&[]);
+        let _consumers = testing::consumers(&state);
+        testing::announce(&state, "lmstudio", "box1", "http://10.0.0.1:1234/");
+        testing::announce(&state, "lmstudio", "box2", "http://10.0.0.2:1234/");
+        let mut events = listen(&state);
+
+        state.apply_health_check(&LMSTUDIO, &testing::node_id("box1"), false);
+        assert_published(&mut events, vec![state_changed("box1", "available", "failed", "health_check")]).await;
+
+        state.apply_health_check(&LMSTUDIO, &testing::node_id("box2"), false);
+        assert_published(
+            &mut events,
+            vec![state_changed("box2", "available", "failed", "health_check"), json!({ "type": "pool_empty", "service": "lmstudio" })],
+        )
+        .await;
+
+        state.apply_health_check(&LMSTUDIO, &testing::node_id("box2"), false);
+        assert_published(&mut events, vec![]).await;
+
+        state.apply_health_check(&LMSTUDIO, &testing::node_id("box1"), true);
+        assert_published(
+            &mut events,
+            vec![state_changed("box1", "failed", "available", "health_check"), json!({ "type": "pool_recovered", "service": "lmstudio" })],
+        )
+        .await;
+    }
+
+    #[actix_web::test]
+    async fn drain_and_its_cancellation_are_published() {
+        let state = testing::state(&[]);
+        testing::announce(&state, "lmstudio", "box1", "http://10.0.0.1:1234/");
+        let mut events = listen(&state);
+
+        state.start_drain(&LMSTUDIO, &testing::node_id("box1"), Duration::from_secs(30));
+        assert_published(
+            &mut events,
+            vec![
+                state_changed("box1", "available", "draining", "drain"),
+                json!({ "type": "node_draining", "node_id": "box1", "service": "lmstudio", "lead_secs": 30 }),
+            ],
+        )
+        .await;
+
+        testing::announce(&state, "lmstudio", "box1", "http://10.0.0.1:1234/");
+        assert_published(
+            &mut events,
+            vec![
+                state_changed("box1", "draining", "available", "heartbeat"),
+                json!({ "type": "node_drain_cancelled", "node_id": "box1", "service": "lmstudio" }),
+            ],
+        )
+        .await;
+    }
+
+    #[actix_web::test]
+    async fn removals_publish_node_removed_with_their_reason() {
+        let state = testing::state(&["--admin-token", "secreto"]);
+        let _consumers = testing::consumers(&state);
+        testing::announce(&state, "lmstudio", "box1", "http://10.0.0.1:1234/");
+        testing::announce(&state, "lmstudio", "box2", "http://10.0.0.2:1234/");
+        let app = init_service(balancer::app(state.clone())).await;
+        let mut events = listen(&state);
+        // `pool_watch` ya vio la pool con nodos.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        state.deregister_node(&LMSTUDIO, &testing::node_id("box1"));
+        assert_published(
+            &mut events,
+            vec![json!({
+                "type": "node_removed", "node_id": "box1", "service": "lmstudio", "from": "available",
+                "cause": "goodbye", "reason": "goodbye", "tombstone_id": "<lápida>",
+            })],
+        )
+        .await;
+
+        let req = TestRequest::post().uri("/nodes/box2/remove").insert_header(("Authorization", "Bearer secreto")).to_request();
+        assert!(call_service(&app, req).await.status().is_success());
+        assert_published(
+            &mut events,
+            vec![
+                json!({
+                    "type": "node_removed", "node_id": "box2", "service": "lmstudio", "from": "available",
+                    "cause": "admin", "reason": "admin_delete", "tombstone_id": "<lápida>",
+                }),
+                json!({ "type": "pool_empty", "service": "lmstudio" }),
+            ],
+        )
+        .await;
+    }
+
+    #[actix_web::test]
+    async fn stats_reset_is_published() {
+        let state = testing::state(&["--admin-token", "secreto"]);
+        let app = init_service(balancer::app(state.clone())).await;
+        let mut events = listen(&state);
+
+        let req = TestRequest::post().uri("/stats/reset").insert_header(("Authorization", "Bearer secreto")).to_request();
+        assert!(call_service(&app, req).await.status().is_success());
+
+        assert_published(&mut events, vec![json!({ "type": "stats_reset", "scope": "all", "epoch": 1 })]).await;
+    }
+}
@@ -0,0 +1,326 @@
+// src/fairness.rs
+//! Reparto de peticiones entre nodos medido sobre el tráfico real.
+//!
+//! Con cada asignación se guarda qué nodos podían haberla recibido (un bitset sobre índices
+//! estables por pool). La cuota esperada de un nodo es la suma, sobre las asignaciones en las
+//! que era elegible, de `1 / elegibles`; la real, las que recibió. Su cociente es el índice
+//! de sesgo: 1.0 es un reparto justo, >1 el nodo recibe de más y <1 de menos. Con
+//! `--node-selection weighted` la porción de cada elegible es su peso entre la suma de los
+//! pesos de los elegibles, con el último peso que anunció el nodo. Con niveles de nodos
+//! (`tiers`) sólo son elegibles los libres del nivel del elegido: que un nivel posterior espere
+//! mientras hay sitio en uno anterior no es sesgo.
+//!
+//! Con dominios de fallo (`domains`), cada pool desglosa además el reparto por dominio: la suma
+//! de lo recibido y de lo esperado por sus nodos. Los nodos sin dominio no entran en el desglose.
+//!
+//! Un sesgo grande que se mantiene en varias comprobaciones seguidas se avisa con un evento:
+//! suele indicar un fallo del reparto o una restricción oculta (p.ej. reservas X-Pipeline)
+//! que concentra la carga.
+use actix_web::web;
+use log::warn;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::time::interval;
+
+use crate::balancer::{AppState, NodeHealth, NodeInfo};
+use crate::domains::FailureDomains;
+use crate::events::BalancerEvent;
+use crate::ids::NodeId;
+use crate::limits::StoreUsage;
+use crate::tiers::NodeTiers;
+
+pub const DEFAULT_FAIRNESS_WINDOW_SECS: u64 = 600;
+pub const DEFAULT_FAIRNESS_SKEW_THRESHOLD: f64 = 0.5;
+
+/// Cada cuánto se revisa el sesgo para avisar.
+const FAIRNESS_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Comprobaciones seguidas por encima del umbral antes de avisar.
+const SUSTAINED_CHECKS: u32 = 3;
+
+/// Asignaciones mínimas en la ventana para que el índice de una pool sea significativo.
+const MIN_DISPATCHES: usize = 50;
+
+/// Nodos elegibles en una asignación, como bits sobre los índices de la pool.
+#[derive(Default)]
+struct EligibleSet(Vec<u64>);
+
+impl EligibleSet {
+    fn insert(&mut self, slot: usize) {
+        let word = slot / 64;
+        if self.0.len() <= word {
+            self.0.resize(word + 1, 0);
+        }
+        self.0[word] |= 1 << (slot % 64);
+    }
+
+    fn slots(&self) -> impl Iterator<Item = usize> + '_ {
+        self.0.iter().enumerate().flat_map(|(word, bits)| (0..64).filter(move |bit| bits & (1 << bit) != 0).map(move |bit| word * 64 + bit))
+    }
+}
+
+struct Dispatch {
+    at: Instant,
+    chosen: usize,
+    eligible: EligibleSet,
+}
+
+/// Índices estables de los nodos de una pool y sus asignaciones en la ventana.
+#[derive(Default)]
+struct PoolWindow {
+    node_ids: Vec<String>,
+    /// Último peso de cada índice; todos 1 sin reparto ponderado.
+    weights: Vec<u32>,
+    /// Último dominio de fallo de cada índice.
+    domains: Vec<Option<String>>,
+    slots: HashMap<String, usize>,
+    dispatches: VecDeque<Dispatch>,
+}
+
+impl PoolWindow {
+    fn slot(&mut self, node_id: &str) -> usize {
+        if let Some(slot) = self.slots.get(node_id) {
+            return *slot;
+        }
+        self.node_ids.push(node_id.to_string());
+        self.weights.push(1);
+        self.domains.push(None);
+        self.slots.insert(node_id.to_string(), self.node_ids.len() - 1);
+        self.node_ids.len() - 1
+    }
+
+    fn prune(&mut self, window: Duration, max_dispatches: usize) {
+        let now = Instant::now();
+        while self.dispatches.front().is_some_and(|d| now.duration_since(d.at) > window) || self.dispatches.len() > max_dispatches {
+            self.dispatches.pop_front();
+        }
+        // Sin asignaciones pendientes los índices pueden reasignarse; así no crecen con nodos que ya no existen.
+        if self.dispatches.is_empty() {
+            self.node_ids.clear();
+            self.weights.clear();
+            self.domains.clear();
+            self.slots.clear();
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct NodeFairness {
+    pub dispatched: u64,
+    /// Fracción de las asignaciones de la pool que recibió el nodo.
+    pub share: f64,
+    /// Fracción que le habría tocado repartiendo entre los elegibles de cada asignación (por
+    /// igual, o según su peso con reparto ponderado).
+    pub expected_share: f64,
+    /// `share / expected_share`; `None` si nunca fue elegible.
+    pub skew: Option<f64>,
+    /// Dominio de fallo del nodo en su última asignación.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+}
+
+/// Reparto de los nodos de un dominio de fallo, sumados.
+#[derive(Clone, Debug, Serialize)]
+pub struct DomainFairness {
+    pub nodes: usize,
+    pub dispatched: u64,
+    pub share: f64,
+    pub expected_share: f64,
+    pub skew: Option<f64>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PoolFairness {
+    pub dispatches: usize,
+    /// Si hay asignaciones suficientes para que el índice signifique algo.
+    pub significant: bool,
+    pub nodes: BTreeMap<String, NodeFairness>,
+    /// Desglose por dominio de fallo; vacío si ningún nodo tiene dominio.
+    pub domains: BTreeMap<String, DomainFairness>,
+}
+
+pub struct FairnessTracker {
+    window: Duration,
+    /// Tope de asignaciones guardadas por pool, por si la ventana es muy larga y el tráfico muy alto.
+    max_dispatches: usize,
+    pub skew_threshold: f64,
+    pools: Mutex<HashMap<String, PoolWindow>>,
+    /// Nodos (pool, id) con sesgo sostenido ya avisado.
+    flagged: Mutex<HashSet<(String, String)>>,
+}
+
+impl FairnessTracker {
+    pub fn new(window: Duration, skew_threshold: f64, max_dispatches: usize) -> Self {
+        Self { window, max_dispatches, skew_threshold, pools: Mutex::default(), flagged: Mutex::default() }
+    }
+
+    /// Asignaciones guardadas por pool.
+    pub fn usage(&self) -> Vec<(String, StoreUsage)> {
+        let pools = self.pools.lock().unwrap();
+        let mut usage: Vec<_> = pools
+            .iter()
+            .map(|(service, pool)| {
+                let bytes = pool.dispatches.iter().map(|d| std::mem::size_of::<Dispatch>() + d.eligible.0.len() * 8).sum::<usize>()
+                    + pool.node_ids.iter().map(|id| 2 * (std::mem::size_of::<String>() + id.len()) + std::mem::size_of::<u32>()).sum::<usize>()
+                    + pool.domains.iter().map(|domain| std::mem::size_of::<Option<String>>() + domain.as_ref().map_or(0, String::len)).sum::<usize>();
+                (service.clone(), StoreUsage { entries: pool.dispatches.len(), bytes })
+            })
+            .collect();
+        usage.sort_by(|a, b| a.0.cmp(&b.0));
+        usage
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Anota la asignación de `chosen`. Elegibles son los nodos disponibles más el elegido
+    /// (que puede estar ya ocupado si venía reservado o lo preparó el cargador de modelos). Con
+    /// `weighted`, cada uno cuenta con su peso.
+    pub fn record(
+        &self,
+        service: &str,
+        nodes: &HashMap<NodeId, NodeInfo>,
+        chosen: &str,
+        weighted: bool,
+        tiers: &NodeTiers,
+        domains: &FailureDomains,
+    ) {
+        let mut pools = self.pools.lock().unwrap();
+        let pool = pools.entry(service.to_string()).or_default();
+        pool.prune(self.window, self.max_dispatches);
+        let chosen_tier = nodes.get(chosen).map(|info| tiers.tier_of(&info.platform));
+        let mut eligible = EligibleSet::default();
+        for (id, info) in nodes {
+            let same_tier = chosen_tier.is_none_or(|tier| tiers.tier_of(&info.platform) == tier);
+            if (matches!(info.state, NodeHealth::Available) && same_tier) || id.as_str() == chosen {
+                let slot = pool.slot(id.as_str());
+                pool.weights[slot] = if weighted { info.weight } else { 1 };
+                pool.domains[slot] = domains.domain_of(id, info).map(str::to_string);
+                eligible.insert(slot);
+            }
+        }
+        let chosen = pool.slot(chosen);
+        eligible.insert(chosen);
+        pool.dispatches.push_back(Dispatch { at: Instant::now(), chosen, eligible });
+        // `prune` deja hasta el tope antes de esta asignación: con ella, se descarta la más antigua.
+        if pool.dispatches.len() > self.max_dispatches {
+            pool.dispatches.pop_front();
+        }
+    }
+
+    pub fn report(&self) -> BTreeMap<String, PoolFairness> {
+        let mut pools = self.pools.lock().unwrap();
+        let mut report = BTreeMap::new();
+        for (service, pool) in pools.iter_mut() {
+            pool.prune(self.window, self.max_dispatches);
+            let mut dispatched = vec![0u64; pool.node_ids.len()];
+            let mut expected = vec![0f64; pool.node_ids.len()];
+            for dispatch in &pool.dispatches {
+                dispatched[dispatch.chosen] += 1;
+                let total_weight: u32 = dispatch.eligible.slots().map(|slot| pool.weights[slot]).sum();
+                for slot in dispatch.eligible.slots() {
+                    expected[slot] += f64::from(pool.weights[slot]) / f64::from(total_weight);
+                }
+            }
+            let total = pool.dispatches.len().max(1) as f64;
+            let nodes = pool
+                .node_ids
+                .iter()
+                .enumerate()
+                .map(|(slot, id)| {
+                    let node = NodeFairness {
+                        dispatched: dispatched[slot],
+                        share: dispatched[slot] as f64 / total,
+                        expected_share: expected[slot] / total,
+                        skew: (expected[slot] > 0.0).then(|| dispatched[slot] as f64 / expected[slot]),
+                        domain: pool.domains[slot].clone(),
+                    };
+                    (id.clone(), node)
+                })
+                .collect();
+            let mut by_domain: BTreeMap<String, (usize, u64, f64)> = BTreeMap::new();
+            for (slot, domain) in pool.domains.iter().enumerate() {
+                if let Some(domain) = domain {
+                    let entry = by_domain.entry(domain.clone()).or_default();
+                    entry.0 += 1;
+                    entry.1 += dispatched[slot];
+                    entry.2 += expected[slot];
+                }
+            }
+            let domains = by_domain
+                .into_iter()
+                .map(|(domain, (nodes, dispatched, expected))| {
+                    let fairness = DomainFairness {
+                        nodes,
+                        dispatched,
+                        share: dispatched as f64 / total,
+                        expected_share: expected / total,
+                        skew: (expected > 0.0).then(|| dispatched as f64 / expected),
+                    };
+                    (domain, fairness)
+                })
+                .collect();
+            report.insert(
+                service.clone(),
+                PoolFairness { dispatches: pool.dispatches.len(), significant: pool.dispatches.len() >= MIN_DISPATCHES, nodes, domains },
+            );
+        }
+        report
+    }
+
+    /// Olvida las asignaciones de la ventana (`POST /stats/reset` de todo).
+    pub fn reset(&self) {
+        self.pools.lock().unwrap().clear();
+        self.flagged.lock().unwrap().clear();
+    }
+
+    /// Si el nodo tiene ahora mismo un sesgo sostenido avisado.
+    pub fn is_flagged(&self, service: &str, node_id: &str) -> bool {
+        self.flagged.lock().unwrap().contains(&(service.to_string(), node_id.to_string()))
+    }
+}
+
+/// Revisa el sesgo periódicamente y avisa de los nodos que se mantienen fuera del umbral.
+pub async fn watch(state: web::Data<AppState>) {
+    let tracker = &state.fairness;
+    let mut strikes: HashMap<(String, String), u32> = HashMap::new();
+    let mut ticker = interval(FAIRNESS_CHECK_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let mut skewed = HashSet::new();
+        for (service, pool) in tracker.report() {
+            if !pool.significant {
+                continue;
+            }
+            for (node_id, node) in pool.nodes {
+                let Some(skew) = node.skew.filter(|skew| (skew - 1.0).abs() > tracker.skew_threshold) else {
+                    continue;
+                };
+                let key = (service.clone(), node_id.clone());
+                let count = strikes.entry(key.clone()).or_default();
+                *count += 1;
+                if *count == SUSTAINED_CHECKS {
+                    warn!(
+                        "Fairness: El nodo {} de {} recibe el {:.1}% de las peticiones frente al {:.1}% esperado (sesgo {:.2}).",
+                        node_id, service, node.share * 100.0, node.expected_share * 100.0, skew
+                    );
+                    tracker.flagged.lock().unwrap().insert(key.clone());
+                    state.events.publish(BalancerEvent::FairnessSkew {
+                        service: service.clone(),
+                        node_id,
+                        share: node.share,
+                        expected_share: node.expected_share,
+                        skew,
+                        window_secs: tracker.window.as_secs(),
+                    });
+                }
+                skewed.insert(key);
+            }
+        }
+        strikes.retain(|key, _| skewed.contains(key));
+        tracker.flagged.lock().unwrap().retain(|key| skewed.contains(key));
+    }
+}
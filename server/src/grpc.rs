@@ -0,0 +1,297 @@
+// src/grpc.rs
+//! API gRPC (feature `grpc`, `--grpc-listen`) junto al servidor HTTP, para orquestadores que
+//! prefieren gRPC a sondear JSON. Esquema en `proto/lmserver.proto`.
+//!
+//! Los latidos de `RegisterNode` entran por el mismo `register_node` que los anuncios UDP, así
+//! que un nodo registrado por gRPC se planifica igual que cualquier otro y se fija a la IP del
+//! cliente con `--node-pinning`. `WatchNodes` es un suscriptor más del bus de eventos y cuenta
+//! contra `--max-event-subscribers`.
+// tonic impone `Result<_, Status>` en toda la API generada y en sus streams.
+#![allow(clippy::result_large_err)]
+use actix_web::web;
+use futures_util::stream::{self, Stream, StreamExt};
+use log::{error, info, warn};
+use std::collections::BTreeSet;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use tokio::sync::broadcast::error::RecvError;
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::balancer::{effective_service_url, AppState, NodeHealth};
+use crate::build_info;
+use crate::events::{self, BalancerEvent};
+use crate::history::TransitionCause;
+use crate::ids::{NodeId, PoolName, ServiceUrl};
+
+mod proto {
+    tonic::include_proto!("lmserver.v1");
+}
+
+use proto::registry_server::{Registry, RegistryServer};
+use proto::{Heartbeat, NodeEvent, PoolStatus, RegisterAck, StatusReply, StatusRequest, WatchRequest};
+
+type ResponseStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+struct RegistryService {
+    state: web::Data<AppState>,
+}
+
+/// Registra o refresca el nodo de un latido, como un anuncio UDP `DISCOVER`.
+fn register_heartbeat(state: &AppState, peer_ip: Option<IpAddr>, heartbeat: Heartbeat) -> RegisterAck {
+    let parsed = PoolName::new(state.canonical_service(&heartbeat.pool)).and_then(|service| Ok((service, NodeId::new(heartbeat.node_id.as_str())?)));
+    let registered = parsed.map_err(|e| e.to_string()).and_then(|(service, unique_node_id)| {
+        let announced_url = ServiceUrl::parse(heartbeat.service_url.as_str()).map_err(|e| e.to_string())?;
+        if peer_ip.is_some_and(|ip| !state.pins.permits(&state.events, &unique_node_id, ip)) {
+            return Err("el ID está fijado a otra IP (--node-pinning)".to_string());
+        }
+        let service_url = match peer_ip {
+            Some(ip) => effective_service_url(announced_url, ip, &unique_node_id),
+            None => announced_url,
+        };
+        state.register_node(&service, &unique_node_id, service_url).map_err(|e| e.to_string())?;
+        if let Some(ip) = peer_ip {
+            state.pins.pin(&unique_node_id, ip);
+            state.set_node_source_ip(&service, &unique_node_id, ip);
+        }
+        Ok((service, unique_node_id))
+    });
+    match registered {
+        Ok((service, unique_node_id)) => {
+            if !heartbeat.models.is_empty() {
+                state.set_node_models(&service, &unique_node_id, heartbeat.models);
+            }
+            if !heartbeat.version.is_empty() {
+                state.set_node_version(&service, &unique_node_id, &heartbeat.version);
+            }
+            RegisterAck { node_id: heartbeat.node_id, accepted: true, error: String::new() }
+        }
+        Err(error) => {
+            warn!("gRPC: Latido de ID {} para '{}' rechazado: {}", heartbeat.node_id, heartbeat.pool, error);
+            RegisterAck { node_id: heartbeat.node_id, accepted: false, error }
+        }
+    }
+}
+
+fn cause_label(cause: &TransitionCause) -> String {
+    serde_json::to_value(cause).ok().and_then(|value| value.as_str().map(str::to_string)).unwrap_or_default()
+}
+
+/// Evento de `WatchNodes` para los eventos del bus que cambian el registro.
+fn node_event(event: &BalancerEvent) -> Option<NodeEvent> {
+    let (node_id, pool, from, to, cause, timestamp) = match event {
+        BalancerEvent::NodeRegistered { node_id, service, state, cause, timestamp } => (node_id, service, "", *state, cause, timestamp),
+        BalancerEvent::NodeRemoved { node_id, service, from, cause, timestamp, .. } => (node_id, service, *from, "", cause, timestamp),
+        BalancerEvent::StateChanged { node_id, service, from, to, cause, timestamp } => (node_id, service, *from, *to, cause, timestamp),
+        _ => return None,
+    };
+    Some(NodeEvent {
+        r#type: event.name().to_string(),
+        node_id: node_id.clone(),
+        pool: pool.clone(),
+        from: from.to_string(),
+        to: to.to_string(),
+        cause: cause_label(cause),
+        timestamp: timestamp.clone(),
+    })
+}
+
+#[tonic::async_trait]
+impl Registry for RegistryService {
+    type RegisterNodeStream = ResponseStream<RegisterAck>;
+    type WatchNodesStream = ResponseStream<NodeEvent>;
+
+    async fn register_node(&self, request: Request<Streaming<Heartbeat>>) -> Result<Response<Self::RegisterNodeStream>, Status> {
+        let peer_ip = request.remote_addr().map(|addr| addr.ip());
+        let state = self.state.clone();
+        let acks = request
+            .into_inner()
+            .map(move |heartbeat| heartbeat.map(|heartbeat| register_heartbeat(&state, peer_ip, heartbeat)));
+        Ok(Response::new(Box::pin(acks)))
+    }
+
+    async fn watch_nodes(&self, request: Request<WatchRequest>) -> Result<Response<Self::WatchNodesStream>, Status> {
+        let pools: BTreeSet<String> = request.into_inner().pools.iter().map(|pool| self.state.canonical_service(pool).to_string()).collect();
+        // Se suscribe antes de leer el registro para no perder un cambio entre medias.
+        let Some((receiver, guard)) = events::admit(self.state.clone()) else {
+            return Err(Status::resource_exhausted("Se alcanzó el máximo de suscriptores de eventos."));
+        };
+
+        let mut present = Vec::new();
+        for (_, service, lock) in self.state.pools() {
+            if !pools.is_empty() && !pools.contains(service.as_str()) {
+                continue;
+            }
+            for (unique_node_id, info) in lock.read().unwrap().iter() {
+                present.push(Ok(NodeEvent {
+                    r#type: "present".to_string(),
+                    node_id: unique_node_id.to_string(),
+                    pool: service.to_string(),
+                    to: info.state.label().to_string(),
+                    ..Default::default()
+                }));
+            }
+        }
+
+        let changes = stream::unfold((receiver, guard, pools), |(mut receiver, guard, pools)| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => {
+                        let Some(event) = node_event(&event) else {
+                            continue;
+                        };
+                        if pools.is_empty() || pools.contains(&event.pool) {
+                            return Some((Ok(event), (receiver, guard, pools)));
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("gRPC: Suscriptor de WatchNodes rezagado ({} eventos perdidos). Desconectando.", skipped);
+                        guard.record_lag_disconnect();
+                        // Tras un error, tonic cierra el stream sin volver a pedir elementos.
+                        let status = Status::data_loss(format!("Se perdieron {} eventos; vuelve a suscribirte.", skipped));
+                        return Some((Err(status), (receiver, guard, pools)));
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(stream::iter(present).chain(changes))))
+    }
+
+    async fn get_status(&self, _request: Request<StatusRequest>) -> Result<Response<StatusReply>, Status> {
+        let mut pools = Vec::new();
+        for (_, service, lock) in self.state.pools() {
+            let mut status = PoolStatus { pool: service.to_string(), ..Default::default() };
+            for info in lock.read().unwrap().values() {
+                match info.state {
+                    NodeHealth::Available => status.available += 1,
+                    NodeHealth::Busy => status.busy += 1,
+                    NodeHealth::Loading => status.loading += 1,
+                    NodeHealth::Failed(_) => status.failed += 1,
+                    NodeHealth::Draining(_) => status.draining += 1,
+                }
+            }
+            status.queued = self.state.capacity.pool(service).map_or(0, |pool| pool.queued.load(Ordering::Relaxed));
+            pools.push(status);
+        }
+        Ok(Response::new(StatusReply {
+            version: build_info::summary(),
+            revision: self.state.revisions.current(),
+            pools,
+        }))
+    }
+}
+
+/// Sirve la API gRPC en `addr` hasta que se cancele la tarea.
+pub async fn serve(state: web::Data<AppState>, addr: SocketAddr) {
+    info!("gRPC: Escuchando en {}", addr);
+    let service = RegistryServer::new(RegistryService { state });
+    if let Err(e) = tonic::transport::Server::builder().add_service(service).serve(addr).await {
+        error!("gRPC: El servidor gRPC en {} se ha detenido: {}", addr, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use serde_json::{json, Value};
+    use std::time::Duration;
+    use tonic::transport::Channel;
+
+    use crate::balancer::{self, OLLAMA};
+    use crate::testing;
+    use proto::registry_client::RegistryClient;
+
+    /// Sirve la API gRPC de `state` en un puerto libre y devuelve un cliente ya conectado.
+    async fn client(state: &web::Data<AppState>) -> RegistryClient<Channel> {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        tokio::spawn(serve(state.clone(), addr));
+        for _ in 0..100 {
+            if let Ok(client) = RegistryClient::connect(format!("http://{}", addr)).await {
+                return client;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("el servidor gRPC de prueba no arrancó");
+    }
+
+    fn heartbeat(node_id: &str, service_url: &str) -> Heartbeat {
+        Heartbeat {
+            pool: "lmstudio".to_string(),
+            node_id: node_id.to_string(),
+            service_url: service_url.to_string(),
+            models: vec!["m".to_string()],
+            version: "1.0.0".to_string(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn node_registered_over_grpc_is_scheduled_over_http() {
+        let (url, hits) = testing::counting_chat_node(Duration::ZERO);
+        let state = testing::state(&[]);
+        let mut client = client(&state).await;
+        let mut watch = client.watch_nodes(WatchRequest { pools: vec!["lmstudio".to_string()] }).await.unwrap().into_inner();
+
+        let mut acks = client.register_node(stream::iter(vec![heartbeat("box1", &url)])).await.unwrap().into_inner();
+        let ack = acks.message().await.unwrap().unwrap();
+        assert!(ack.accepted, "{}", ack.error);
+        assert_eq!(ack.node_id, "box1");
+
+        let event = watch.message().await.unwrap().unwrap();
+        assert_eq!((event.r#type.as_str(), event.node_id.as_str(), event.pool.as_str()), ("node_registered", "box1", "lmstudio"));
+
+        let status = client.get_status(StatusRequest {}).await.unwrap().into_inner();
+        let lmstudio = status.pools.iter().find(|pool| pool.pool == "lmstudio").unwrap();
+        assert_eq!((lmstudio.available, lmstudio.busy, lmstudio.failed), (1, 0, 0));
+
+        let app = init_service(balancer::app(state.clone())).await;
+        let resp = call_service(&app, testing::chat_with(json!({ "model": "m" })).to_request()).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        let nodes: Value = read_body_json(call_service(&app, TestRequest::get().uri("/nodes").to_request()).await).await;
+        assert_eq!(nodes["nodes"][0]["version"], "1.0.0", "{}", nodes);
+        assert_eq!(state.pool("lmstudio").unwrap().read().unwrap()["box1"].models, ["m"]);
+    }
+
+    /// Cada latido recibe su ack; uno inválido no corta el stream ni registra nada.
+    #[actix_web::test]
+    async fn invalid_heartbeats_are_rejected_without_closing_the_stream() {
+        let (url, _) = testing::counting_chat_node(Duration::ZERO);
+        let state = testing::state(&[]);
+        let mut client = client(&state).await;
+
+        let beats = vec![heartbeat("no válido", &url), heartbeat("box2", "no es una url"), heartbeat("box1", &url)];
+        let mut acks = client.register_node(stream::iter(beats)).await.unwrap().into_inner();
+        let mut seen = Vec::new();
+        while let Some(ack) = acks.message().await.unwrap() {
+            seen.push((ack.node_id, ack.accepted, ack.error.is_empty()));
+        }
+        assert_eq!(seen, [
+            ("no válido".to_string(), false, false),
+            ("box2".to_string(), false, false),
+            ("box1".to_string(), true, true),
+        ]);
+        assert_eq!(testing::node_state(&state, "lmstudio", "box1"), "available");
+        assert_eq!(testing::node_state(&state, "lmstudio", "box2"), "absent");
+    }
+
+    /// `WatchNodes` empieza por los nodos ya registrados y filtra por pool.
+    #[actix_web::test]
+    async fn watch_starts_with_present_nodes_of_the_requested_pools() {
+        let state = testing::state(&[]);
+        testing::announce(&state, "lmstudio", "box1", "http://127.0.0.1:1/");
+        testing::announce(&state, "ollama", "box2", "http://127.0.0.1:2/");
+        let mut client = client(&state).await;
+
+        let mut watch = client.watch_nodes(WatchRequest { pools: vec!["ollama".to_string()] }).await.unwrap().into_inner();
+        let first = watch.message().await.unwrap().unwrap();
+        assert_eq!((first.r#type.as_str(), first.node_id.as_str(), first.to.as_str()), ("present", "box2", "available"));
+
+        testing::announce(&state, "lmstudio", "box3", "http://127.0.0.1:3/");
+        state.deregister_node(&OLLAMA, &testing::node_id("box2"));
+        let next = watch.message().await.unwrap().unwrap();
+        assert_eq!((next.r#type.as_str(), next.node_id.as_str(), next.pool.as_str()), ("node_removed", "box2", "ollama"));
+    }
+}
@@ -0,0 +1,467 @@
+// src/headers.rs
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+use crate::labels;
+
+/// Cabeceras que se reenvían a los nodos si la pool no dice lo contrario.
+pub const DEFAULT_FORWARDED_HEADERS: &[&str] = &[
+    "accept",
+    "openai-organization",
+    "openai-project",
+    "openai-beta",
+];
+
+/// Cabeceras hop-by-hop (RFC 9110 §7.6.1) y de framing que nunca se reenvían, se configure lo que se configure.
+pub const ALWAYS_STRIPPED_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "host",
+    "content-length",
+];
+
+/// Indica si la cabecera puede llevar un secreto y debe enmascararse al mostrarla.
+pub fn is_sensitive(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    name == "authorization" || name == "cookie" || ["key", "token", "secret"].iter().any(|word| name.contains(word))
+}
+
+/// Enmascara un valor dejando visibles sólo sus últimos 4 caracteres si es lo bastante largo.
+pub fn mask_value(value: &str) -> String {
+    let chars: Vec<char> = value.chars().collect();
+    if chars.len() <= 8 {
+        return "***".to_string();
+    }
+    format!("***{}", chars[chars.len() - 4..].iter().collect::<String>())
+}
+
+#[derive(Debug)]
+pub struct HeaderConfigError(String);
+
+impl fmt::Display for HeaderConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for HeaderConfigError {}
+
+/// Conjunto de cabeceras (en minúsculas) que una pool reenvía a sus nodos.
+#[derive(Clone, Debug)]
+pub struct HeaderWhitelist {
+    allowed: BTreeSet<String>,
+}
+
+impl Default for HeaderWhitelist {
+    fn default() -> Self {
+        Self { allowed: DEFAULT_FORWARDED_HEADERS.iter().map(|h| h.to_string()).collect() }
+    }
+}
+
+impl HeaderWhitelist {
+    pub fn allows(&self, name: &str) -> bool {
+        self.allowed.contains(&name.to_ascii_lowercase())
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.allowed.iter().map(String::as_str)
+    }
+}
+
+/// Separa una entrada `pool=Cabecera` de la línea de comandos.
+fn split_entry(entry: &str) -> Result<(&str, String), HeaderConfigError> {
+    match entry.split_once('=') {
+        Some((pool, header)) if !pool.trim().is_empty() && !header.trim().is_empty() => {
+            Ok((pool.trim(), header.trim().to_ascii_lowercase()))
+        }
+        _ => Err(HeaderConfigError(format!("Entrada de cabecera inválida '{}': se esperaba <pool>=<cabecera>", entry))),
+    }
+}
+
+/// Construye la whitelist de cada pool a partir de los valores por defecto y de las
+/// adiciones/eliminaciones configuradas.
+pub fn build_whitelists(
+    pools: &[&str],
+    additions: &[String],
+    removals: &[String],
+) -> Result<HashMap<String, HeaderWhitelist>, HeaderConfigError> {
+    let mut whitelists: HashMap<String, HeaderWhitelist> = pools
+        .iter()
+        .map(|pool| (pool.to_string(), HeaderWhitelist::default()))
+        .collect();
+
+    for entry in additions {
+        let (pool, header) = split_entry(entry)?;
+        if ALWAYS_STRIPPED_HEADERS.contains(&header.as_str()) {
+            return Err(HeaderConfigError(format!(
+                "No se puede reenviar '{}' en la pool '{}': las cabeceras hop-by-hop siempre se eliminan ({}).",
+                header,
+                pool,
+                ALWAYS_STRIPPED_HEADERS.join(", ")
+            )));
+        }
+        if header.eq_ignore_ascii_case(labels::LABELS_HEADER) {
+            return Err(HeaderConfigError(format!(
+                "No se puede reenviar '{}' en la pool '{}': las etiquetas de analítica no salen del balanceador.",
+                header, pool
+            )));
+        }
+        let whitelist = whitelists
+            .get_mut(pool)
+            .ok_or_else(|| HeaderConfigError(format!("Pool desconocida '{}' en --forward-header", pool)))?;
+        whitelist.allowed.insert(header);
+    }
+
+    for entry in removals {
+        let (pool, header) = split_entry(entry)?;
+        let whitelist = whitelists
+            .get_mut(pool)
+            .ok_or_else(|| HeaderConfigError(format!("Pool desconocida '{}' en --strip-header", pool)))?;
+        whitelist.allowed.remove(&header);
+    }
+
+    Ok(whitelists)
+}
+
+/// Tamaño máximo por defecto del valor de una cabecera reenviada.
+pub const DEFAULT_MAX_HEADER_VALUE_BYTES: usize = 8 * 1024;
+/// Tamaño máximo por defecto del conjunto de cabeceras reenviadas (nombres y valores).
+pub const DEFAULT_MAX_FORWARDED_HEADER_BYTES: usize = 32 * 1024;
+/// Número máximo por defecto de cabeceras reenviadas.
+pub const DEFAULT_MAX_FORWARDED_HEADERS: usize = 32;
+
+/// Cabecera que avisa al cliente de las cabeceras que no se reenviaron por los límites.
+pub const DROPPED_HEADERS_HEADER: &str = "X-LMServer-Dropped-Headers";
+
+/// Motivo por el que una cabecera no se reenvió.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DropReason {
+    ValueTooLong,
+    TotalBytes,
+    Count,
+}
+
+impl DropReason {
+    pub const ALL: [DropReason; 3] = [DropReason::ValueTooLong, DropReason::TotalBytes, DropReason::Count];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            DropReason::ValueTooLong => "value_too_long",
+            DropReason::TotalBytes => "total_bytes",
+            DropReason::Count => "count",
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// `Authorization` supera el tamaño máximo de valor; la petición se rechaza en vez de reenviarla sin ella.
+#[derive(Debug)]
+pub struct OversizedAuthorization {
+    pub len: usize,
+    pub max: usize,
+}
+
+impl fmt::Display for OversizedAuthorization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "La cabecera Authorization ocupa {} bytes; el máximo reenviable es {}.", self.len, self.max)
+    }
+}
+
+impl std::error::Error for OversizedAuthorization {}
+
+/// Cabeceras que se reenvían tras aplicar los límites y las que se quitaron.
+#[derive(Debug, Default)]
+pub struct LimitedHeaders {
+    pub forwarded: Vec<(String, Vec<u8>)>,
+    pub dropped: Vec<(String, DropReason)>,
+}
+
+/// Límites del conjunto de cabeceras que se reenvía a los nodos.
+#[derive(Clone, Copy, Debug)]
+pub struct HeaderLimits {
+    max_value_bytes: usize,
+    max_total_bytes: usize,
+    max_count: usize,
+}
+
+impl Default for HeaderLimits {
+    fn default() -> Self {
+        Self {
+            max_value_bytes: DEFAULT_MAX_HEADER_VALUE_BYTES,
+            max_total_bytes: DEFAULT_MAX_FORWARDED_HEADER_BYTES,
+            max_count: DEFAULT_MAX_FORWARDED_HEADERS,
+        }
+    }
+}
+
+impl HeaderLimits {
+    pub fn new(max_value_bytes: usize, max_total_bytes: usize, max_count: usize) -> Result<Self, HeaderConfigError> {
+        if max_value_bytes == 0 || max_total_bytes == 0 || max_count == 0 {
+            return Err(HeaderConfigError("Los límites de cabeceras reenviadas deben ser mayores que 0".to_string()));
+        }
+        Ok(Self { max_value_bytes, max_total_bytes, max_count })
+    }
+
+    /// Quita las cabeceras que no caben en los límites. Se recorren por nombre (el mapa de
+    /// cabeceras no conserva el orden de llegada), con `Authorization` primero para que no la
+    /// desplace ninguna otra. Cada cabecera cuenta nombre más valor.
+    pub fn apply(&self, mut headers: Vec<(String, Vec<u8>)>) -> Result<LimitedHeaders, OversizedAuthorization> {
+        headers.sort_by(|(a, _), (b, _)| (a != "authorization", a).cmp(&(b != "authorization", b)));
+        let mut forwarded = Vec::new();
+        let mut dropped = Vec::new();
+        let mut total_bytes = 0;
+        for (name, value) in headers {
+            let reason = if value.len() > self.max_value_bytes {
+                Some(DropReason::ValueTooLong)
+            } else if forwarded.len() >= self.max_count {
+                Some(DropReason::Count)
+            } else if total_bytes + name.len() + value.len() > self.max_total_bytes {
+                Some(DropReason::TotalBytes)
+            } else {
+                None
+            };
+            match reason {
+                Some(_) if name == "authorization" => {
+                    let max = self.max_value_bytes.min(self.max_total_bytes.saturating_sub(name.len()));
+                    return Err(OversizedAuthorization { len: value.len(), max });
+                }
+                Some(reason) => dropped.push((name, reason)),
+                None => {
+                    total_bytes += name.len() + value.len();
+                    forwarded.push((name, value));
+                }
+            }
+        }
+        Ok(LimitedHeaders { forwarded, dropped })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::HeaderMap;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::{web, HttpRequest};
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+
+    use crate::balancer;
+    use crate::testing;
+
+    /// Nodo falso que guarda las cabeceras de cada petición que recibe.
+    fn recording_node() -> (String, Arc<Mutex<Vec<HeaderMap>>>) {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let url = testing::backend(move |cfg| {
+            let seen = seen.clone();
+            cfg.default_service(web::to(move |req: HttpRequest| {
+                seen.lock().unwrap().push(req.headers().clone());
+                async { testing::chat_reply() }
+            }));
+        });
+        (url, recorded)
+    }
+
+    /// Manda una petición de chat con estas cabeceras a la pool y devuelve las que llegaron al nodo.
+    async fn forwarded(args: &[&str], pool: &str, headers: &[(&str, &str)]) -> HeaderMap {
+        let (url, seen) = recording_node();
+        let state = testing::state(args);
+        testing::announce(&state, pool, "box1", &url);
+        let app = init_service(balancer::app(state)).await;
+
+        let mut req = TestRequest::post().uri(&format!("/{}", pool));
+        for header in headers {
+            req = req.insert_header(*header);
+        }
+        let resp = call_service(&app, req.set_json(json!({ "model": "m", "messages": [{ "role": "user", "content": "hola" }] })).to_request()).await;
+
+        assert!(resp.status().is_success(), "{}", resp.status());
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 1);
+        seen[0].clone()
+    }
+
+    const CLIENT_HEADERS: [(&str, &str); 5] = [
+        ("OpenAI-Organization", "org-1"),
+        ("OpenAI-Project", "proj-1"),
+        ("OpenAI-Beta", "assistants=v2"),
+        ("X-Tenant", "acme"),
+        ("Cookie", "session=secreto"),
+    ];
+
+    #[actix_web::test]
+    async fn openai_headers_reach_the_node_by_default() {
+        let headers = forwarded(&[], "lmstudio", &CLIENT_HEADERS).await;
+
+        assert_eq!(headers.get("openai-organization").unwrap(), "org-1");
+        assert_eq!(headers.get("openai-project").unwrap(), "proj-1");
+        assert_eq!(headers.get("openai-beta").unwrap(), "assistants=v2");
+        assert!(headers.get("x-tenant").is_none());
+        assert!(headers.get("cookie").is_none());
+    }
+
+    #[actix_web::test]
+    async fn pool_whitelist_adds_and_removes_headers() {
+        let args = ["--forward-header", "lmstudio=X-Tenant", "--strip-header", "lmstudio=OpenAI-Beta"];
+
+        let headers = forwarded(&args, "lmstudio", &CLIENT_HEADERS).await;
+        assert_eq!(headers.get("x-tenant").unwrap(), "acme");
+        assert!(headers.get("openai-beta").is_none());
+        assert_eq!(headers.get("openai-organization").unwrap(), "org-1");
+
+        // La otra pool conserva la whitelist por defecto.
+        let headers = forwarded(&args, "ollama", &CLIENT_HEADERS).await;
+        assert!(headers.get("x-tenant").is_none());
+        assert_eq!(headers.get("openai-beta").unwrap(), "assistants=v2");
+    }
+
+    #[test]
+    fn hop_by_hop_headers_cannot_be_whitelisted() {
+        for header in ["Connection", "transfer-encoding", "Host"] {
+            let err = build_whitelists(&["lmstudio"], &[format!("lmstudio={}", header)], &[]).unwrap_err().to_string();
+            assert!(err.contains(&header.to_ascii_lowercase()), "{}", err);
+            assert!(err.contains(&ALWAYS_STRIPPED_HEADERS.join(", ")), "el error no documenta las cabeceras hop-by-hop: {}", err);
+        }
+    }
+
+    #[test]
+    fn invalid_whitelist_entries_are_rejected() {
+        let pools = ["lmstudio", "ollama"];
+        assert!(build_whitelists(&pools, &["vllm=X-Tenant".to_string()], &[]).is_err());
+        assert!(build_whitelists(&pools, &[], &["vllm=accept".to_string()]).is_err());
+        assert!(build_whitelists(&pools, &["X-Tenant".to_string()], &[]).is_err());
+        assert!(build_whitelists(&pools, &["lmstudio=".to_string()], &[]).is_err());
+        assert!(build_whitelists(&pools, &[format!("lmstudio={}", labels::LABELS_HEADER)], &[]).is_err());
+    }
+
+    fn header(name: &str, len: usize) -> (String, Vec<u8>) {
+        (name.to_string(), vec![b'x'; len])
+    }
+
+    fn names(headers: &[(String, Vec<u8>)]) -> Vec<&str> {
+        headers.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    #[test]
+    fn value_limit_keeps_the_boundary_and_drops_one_byte_more() {
+        let limits = HeaderLimits::new(10, 1_000, 100).unwrap();
+        let limited = limits.apply(vec![header("x-a", 10), header("x-b", 11), header("x-c", 0)]).unwrap();
+        assert_eq!(names(&limited.forwarded), ["x-a", "x-c"]);
+        assert_eq!(limited.dropped, [("x-b".to_string(), DropReason::ValueTooLong)]);
+    }
+
+    #[test]
+    fn count_limit_drops_the_extra_headers_by_name() {
+        let limits = HeaderLimits::new(100, 1_000, 3).unwrap();
+        let limited = limits.apply(vec![header("x-d", 1), header("x-b", 1), header("x-a", 1)]).unwrap();
+        assert_eq!(names(&limited.forwarded), ["x-a", "x-b", "x-d"]);
+        assert!(limited.dropped.is_empty());
+
+        let limited = limits.apply(vec![header("x-d", 1), header("x-b", 1), header("x-c", 1), header("x-a", 1)]).unwrap();
+        assert_eq!(names(&limited.forwarded), ["x-a", "x-b", "x-c"]);
+        assert_eq!(limited.dropped, [("x-d".to_string(), DropReason::Count)]);
+    }
+
+    #[test]
+    fn total_limit_counts_names_and_values() {
+        // Cada una ocupa 3 + 7 = 10 bytes.
+        let limits = HeaderLimits::new(100, 30, 100).unwrap();
+        let limited = limits.apply(vec![header("x-c", 7), header("x-a", 7), header("x-b", 7)]).unwrap();
+        assert_eq!(names(&limited.forwarded), ["x-a", "x-b", "x-c"]);
+
+        let limited = limits.apply(vec![header("x-c", 8), header("x-a", 7), header("x-b", 7)]).unwrap();
+        assert_eq!(names(&limited.forwarded), ["x-a", "x-b"]);
+        assert_eq!(limited.dropped, [("x-c".to_string(), DropReason::TotalBytes)]);
+        // Una que no cabe no impide que quepa la siguiente, más pequeña.
+        let limited = limits.apply(vec![header("x-b", 18), header("x-a", 7), header("x-c", 1)]).unwrap();
+        assert_eq!(names(&limited.forwarded), ["x-a", "x-c"]);
+        assert_eq!(limited.dropped, [("x-b".to_string(), DropReason::TotalBytes)]);
+    }
+
+    #[test]
+    fn oversized_authorization_fails_instead_of_being_dropped() {
+        let limits = HeaderLimits::new(20, 100, 2).unwrap();
+        // Va primero: las demás no la desplazan ni por número ni por tamaño.
+        let limited = limits.apply(vec![header("x-a", 20), header("x-b", 1), header("authorization", 20)]).unwrap();
+        assert_eq!(names(&limited.forwarded), ["authorization", "x-a"]);
+        assert_eq!(limited.dropped, [("x-b".to_string(), DropReason::Count)]);
+
+        let err = limits.apply(vec![header("authorization", 21)]).unwrap_err();
+        assert_eq!((err.len, err.max), (21, 20));
+        // También si tendría que quitar otra para caber: nunca se descarta.
+        let limits = HeaderLimits::new(20, 40, 2).unwrap();
+        let limited = limits.apply(vec![header("x-a", 20), header("authorization", 20)]).unwrap();
+        assert_eq!(names(&limited.forwarded), ["authorization"]);
+        assert_eq!(limited.dropped, [("x-a".to_string(), DropReason::TotalBytes)]);
+        // El total también la limita: 40 - 13 bytes del nombre.
+        let limits = HeaderLimits::new(100, 40, 2).unwrap();
+        assert!(limits.apply(vec![header("authorization", 27)]).is_ok());
+        let err = limits.apply(vec![header("authorization", 28)]).unwrap_err();
+        assert_eq!((err.len, err.max), (28, 27));
+    }
+
+    #[test]
+    fn zero_limits_are_rejected() {
+        for (value, total, count) in [(0, 1, 1), (1, 0, 1), (1, 1, 0)] {
+            assert!(HeaderLimits::new(value, total, count).is_err());
+        }
+    }
+
+    #[actix_web::test]
+    async fn dropped_headers_are_reported_and_counted() {
+        let (url, seen) = recording_node();
+        let state = testing::state(&["--forward-header", "lmstudio=X-Big", "--forward-header", "lmstudio=X-Small", "--max-forwarded-header-value-bytes", "16"]);
+        testing::announce(&state, "lmstudio", "box1", &url);
+        let app = init_service(balancer::app(state.clone())).await;
+
+        let req = TestRequest::post()
+            .uri("/lmstudio")
+            .insert_header(("X-Big", "x".repeat(17)))
+            .insert_header(("X-Small", "x".repeat(16)))
+            .set_json(json!({ "model": "m", "messages": [{ "role": "user", "content": "hola" }] }));
+        let resp = call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get(DROPPED_HEADERS_HEADER).unwrap(), "x-big");
+        let headers = seen.lock().unwrap()[0].clone();
+        assert!(headers.get("x-big").is_none());
+        assert_eq!(headers.get("x-small").unwrap().len(), 16);
+
+        let metrics = String::from_utf8(read_body(call_service(&app, TestRequest::get().uri("/metrics").to_request()).await).await.to_vec()).unwrap();
+        assert!(metrics.contains("lmserver_forwarded_headers_dropped_total{reason=\"value_too_long\"} 1"), "{}", metrics);
+        assert!(metrics.contains("lmserver_forwarded_headers_dropped_total{reason=\"count\"} 0"), "{}", metrics);
+    }
+
+    #[actix_web::test]
+    async fn oversized_authorization_is_rejected_without_occupying_a_node() {
+        let (url, seen) = recording_node();
+        let state = testing::state(&["--forward-header", "lmstudio=Authorization", "--max-forwarded-header-value-bytes", "16"]);
+        testing::announce(&state, "lmstudio", "box1", &url);
+        let app = init_service(balancer::app(state.clone())).await;
+        let chat = |authorization: String| {
+            TestRequest::post()
+                .uri("/lmstudio")
+                .insert_header(("Authorization", authorization))
+                .set_json(json!({ "model": "m", "messages": [{ "role": "user", "content": "hola" }] }))
+                .to_request()
+        };
+
+        let resp = call_service(&app, chat(format!("Bearer {}", "k".repeat(9)))).await;
+        assert_eq!(resp.status(), 200);
+        assert!(resp.headers().get(DROPPED_HEADERS_HEADER).is_none());
+
+        let resp = call_service(&app, chat(format!("Bearer {}", "k".repeat(10)))).await;
+        assert_eq!(resp.status(), 400);
+        assert_eq!(seen.lock().unwrap().len(), 1, "la petición rechazada no llegó al nodo");
+        assert_eq!(testing::node_state(&state, "lmstudio", "box1"), "available");
+        assert_eq!(state.metrics.rejected_oversized_authorization.load(std::sync::atomic::Ordering::Relaxed), 1);
+    }
+}
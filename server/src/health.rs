@@ -0,0 +1,168 @@
+// src/health.rs
+use actix_web::web;
+use log::{debug, info, warn};
+use std::time::{Duration, Instant};
+use tokio::time::interval;
+use url::Url;
+
+use crate::balancer::{AppState, NodeSource};
+use crate::ids::{NodeId, PoolName, ServiceUrl};
+use crate::retry::{self, RetrySite};
+
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Ruta ligera que responde cada backend sin generar nada.
+pub fn probe_url(service_type: &str, service_url: &ServiceUrl) -> Url {
+    let path = match service_type {
+        "ollama" => "/api/tags",
+        _ => "/v1/models",
+    };
+    service_url.endpoint(path)
+}
+
+async fn probe(client: &reqwest::Client, url: Url) -> bool {
+    match client.get(url.as_str()).timeout(HEALTH_CHECK_TIMEOUT).send().await {
+        Ok(response) => response.status().is_success(),
+        Err(e) => {
+            debug!("Health Check: {} no responde: {}", url, e);
+            false
+        }
+    }
+}
+
+/// Comprueba periódicamente los nodos estáticos, que no tienen un proceso nodo que los anuncie.
+/// Un nodo que no responde se prueba otra vez según `--retry-policy health` antes de darlo por
+/// caído, sin pasar de la siguiente ronda.
+pub async fn health_check_static_nodes(app_state: web::Data<AppState>) {
+    info!("Health checks iniciados. Intervalo: {:?}", HEALTH_CHECK_INTERVAL);
+    let mut ticker = interval(HEALTH_CHECK_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let deadline = Instant::now() + HEALTH_CHECK_INTERVAL;
+        for (_, service_type, nodes_lock) in app_state.pools() {
+            let targets: Vec<(NodeId, ServiceUrl)> = nodes_lock
+                .read()
+                .unwrap()
+                .iter()
+                .filter(|(_, info)| info.source != NodeSource::Announced)
+                .map(|(id, info)| (id.clone(), info.service_url.clone()))
+                .collect();
+
+            for (unique_node_id, service_url) in targets {
+                check_node(&app_state, service_type, &unique_node_id, &service_url, deadline).await;
+            }
+        }
+    }
+}
+
+/// Prueba un nodo, repitiendo según `--retry-policy health` sin esperar más allá de `deadline`,
+/// y lo da por caído o recuperado según el resultado.
+async fn check_node(app_state: &AppState, service_type: &PoolName, unique_node_id: &NodeId, service_url: &ServiceUrl, deadline: Instant) {
+    let (client, url) = (&app_state.client, probe_url(service_type, service_url));
+    let healthy = retry::execute(&app_state.retry, RetrySite::Health, &app_state.metrics, deadline, |_| true, || async {
+        if probe(client, url.clone()).await { Ok(()) } else { Err(()) }
+    })
+    .await
+    .is_ok();
+    app_state.apply_health_check(service_type, unique_node_id, healthy);
+}
+
+/// Prueba los puertos locales conocidos y registra como nodo estático cada backend que responda.
+/// Es un mecanismo de conveniencia: los fallos no se registran. Devuelve cuántos nodos registró.
+pub async fn register_auto_local_nodes(app_state: &AppState, ports: &[(&PoolName, u16)]) -> usize {
+    let mut registered = 0;
+    for (service_type, port) in ports {
+        let Ok(service_url) = ServiceUrl::parse(format!("http://127.0.0.1:{}/v1/chat/completions", port)) else {
+            continue;
+        };
+        if !probe(&app_state.client, probe_url(service_type, &service_url)).await {
+            continue;
+        }
+        info!("Auto-local: Detectado {} en el puerto {}.", service_type, port);
+        let Ok(unique_node_id) = NodeId::new(format!("local-auto-{}", service_type)) else {
+            continue;
+        };
+        match app_state.register_static_node(service_type, &unique_node_id, service_url, NodeSource::LocalAuto) {
+            Ok(()) => registered += 1,
+            Err(e) => warn!("Auto-local: No se pudo registrar {}: {}", service_type, e),
+        }
+    }
+    registered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, HttpResponse};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    use crate::balancer::LMSTUDIO;
+    use crate::testing;
+
+    /// Backend cuyas primeras `failures` pruebas de salud fallan. Devuelve su URL y las pruebas recibidas.
+    fn flaky(failures: u32) -> (String, Arc<AtomicU32>) {
+        let probes = Arc::new(AtomicU32::new(0));
+        let seen = probes.clone();
+        let url = testing::backend(move |cfg| {
+            let probes = probes.clone();
+            cfg.route("/v1/models", web::get().to(move || {
+                let probe = probes.fetch_add(1, Ordering::Relaxed) + 1;
+                async move { if probe <= failures { HttpResponse::ServiceUnavailable().finish() } else { HttpResponse::Ok().json(serde_json::json!({ "data": [] })) } }
+            }));
+        });
+        (url, seen)
+    }
+
+    /// Prueba una vez el nodo `box1`, anunciado en `url`, y devuelve su estado.
+    async fn check(state: &AppState, url: &str, deadline: Duration) -> &'static str {
+        testing::announce(state, "lmstudio", "box1", url);
+        let service_url = ServiceUrl::parse(url).unwrap();
+        check_node(state, &LMSTUDIO, &testing::node_id("box1"), &service_url, Instant::now() + deadline).await;
+        testing::node_state(state, "lmstudio", "box1")
+    }
+
+    fn retries(state: &AppState) -> Option<u64> {
+        state.metrics.render().lines().find_map(|line| line.strip_prefix("lmserver_retries_total{site=\"health\"}")?.trim().parse().ok())
+    }
+
+    #[actix_web::test]
+    async fn a_node_is_failed_only_after_its_retries() {
+        let retrying = ["--retry-policy", "health:attempts=3,base_ms=10,jitter=0"];
+
+        // Sin reintentos, un fallo pasajero lo da por caído.
+        let (url, probes) = flaky(2);
+        assert_eq!(check(&testing::state(&[]), &url, HEALTH_CHECK_INTERVAL).await, "failed");
+        assert_eq!(probes.load(Ordering::Relaxed), 1);
+
+        let (url, probes) = flaky(2);
+        let state = testing::state(&retrying);
+        assert_eq!(check(&state, &url, HEALTH_CHECK_INTERVAL).await, "available");
+        assert_eq!(probes.load(Ordering::Relaxed), 3);
+        assert_eq!(retries(&state), Some(2));
+
+        let (url, probes) = flaky(3);
+        assert_eq!(check(&testing::state(&retrying), &url, HEALTH_CHECK_INTERVAL).await, "failed");
+        assert_eq!(probes.load(Ordering::Relaxed), 3);
+    }
+
+    #[actix_web::test]
+    async fn retries_stop_at_the_next_round() {
+        let (url, probes) = flaky(5);
+        let state = testing::state(&["--retry-policy", "health:attempts=5,base_ms=200,jitter=0"]);
+        assert_eq!(check(&state, &url, Duration::from_millis(100)).await, "failed");
+        assert_eq!(probes.load(Ordering::Relaxed), 1);
+        assert_eq!(retries(&state), None);
+
+        // Un nodo caído sigue caído mientras falle y se recupera en cuanto vuelve a responder.
+        let service_url = ServiceUrl::parse(&url).unwrap();
+        for _ in 0..4 {
+            check_node(&state, &LMSTUDIO, &testing::node_id("box1"), &service_url, Instant::now() + Duration::from_millis(100)).await;
+            assert_eq!(testing::node_state(&state, "lmstudio", "box1"), "failed");
+        }
+        check_node(&state, &LMSTUDIO, &testing::node_id("box1"), &service_url, Instant::now() + Duration::from_millis(100)).await;
+        assert_eq!(testing::node_state(&state, "lmstudio", "box1"), "available");
+        assert_eq!(probes.load(Ordering::Relaxed), 6);
+    }
+}
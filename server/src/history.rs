@@ -0,0 +1,270 @@
+// src/history.rs
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::balancer::NodeHealth;
+use crate::limits::StoreUsage;
+
+/// Número máximo de transiciones guardadas por nodo.
+pub const MAX_TRANSITIONS_PER_NODE: usize = 50;
+
+/// Motivo por el que un nodo cambió de estado.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransitionCause {
+    RequestCompleted,
+    RequestFailure,
+    HealthCheck,
+    Heartbeat,
+    Goodbye,
+    Cleanup,
+    Admin,
+    ModelLoad,
+    /// El nodo anunció que se apagará (`DRAINING`).
+    Drain,
+    /// El nodo no superó su fase canaria (`canary`).
+    Canary,
+    /// Se liberó un nodo Busy o Loading que nada ocupaba (`reconcile`).
+    Reconcile,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct StateTransition {
+    pub timestamp: String,
+    pub service: String,
+    pub from: &'static str,
+    pub to: &'static str,
+    pub cause: TransitionCause,
+    #[serde(skip)]
+    pub at: Instant,
+}
+
+/// Historial acotado de transiciones de estado, indexado por ID de nodo.
+pub struct NodeHistory {
+    entries: HashMap<String, VecDeque<StateTransition>>,
+    /// Nodos con historial. Lleno, se olvida el nodo con la transición más antigua.
+    max_nodes: usize,
+}
+
+impl NodeHistory {
+    pub fn new(max_nodes: usize) -> Self {
+        Self { entries: HashMap::new(), max_nodes }
+    }
+
+
+    pub fn record(
+        &mut self,
+        unique_node_id: &str,
+        service: &str,
+        from: &'static str,
+        to: &'static str,
+        cause: TransitionCause,
+    ) -> StateTransition {
+        if !self.entries.contains_key(unique_node_id) && self.entries.len() >= self.max_nodes {
+            let stalest = self.entries.iter().min_by_key(|(_, log)| log.back().map(|t| t.at)).map(|(id, _)| id.clone());
+            if let Some(stalest) = stalest {
+                self.entries.remove(&stalest);
+            }
+        }
+        let log = self.entries.entry(unique_node_id.to_string()).or_default();
+        if log.len() == MAX_TRANSITIONS_PER_NODE {
+            log.pop_front();
+        }
+        let transition = StateTransition {
+            timestamp: chrono::Local::now().to_rfc3339(),
+            service: service.to_string(),
+            from,
+            to,
+            cause,
+            at: Instant::now(),
+        };
+        log.push_back(transition.clone());
+        transition
+    }
+
+    pub fn usage(&self) -> StoreUsage {
+        let bytes = self
+            .entries
+            .iter()
+            .map(|(id, log)| {
+                std::mem::size_of::<(String, VecDeque<StateTransition>)>()
+                    + id.len()
+                    + log.iter().map(|t| std::mem::size_of::<StateTransition>() + t.timestamp.len() + t.service.len()).sum::<usize>()
+            })
+            .sum();
+        StoreUsage { entries: self.entries.len(), bytes }
+    }
+
+    pub fn get(&self, unique_node_id: &str) -> Option<&VecDeque<StateTransition>> {
+        self.entries.get(unique_node_id)
+    }
+
+    /// Cuenta las veces que el nodo pasó a `failed` dentro de la ventana indicada.
+    pub fn flap_count(&self, unique_node_id: &str, service: &str, window: Duration) -> usize {
+        self.entries.get(unique_node_id).map_or(0, |log| {
+            log.iter()
+                .filter(|t| t.service == service && t.to == NodeHealth::FAILED_LABEL && t.at.elapsed() <= window)
+                .count()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, HttpResponse};
+    use serde_json::json;
+
+    use crate::balancer::{self, AppState, NodeSource, LMSTUDIO, OLLAMA};
+    use crate::ids::ServiceUrl;
+    use crate::loader;
+    use crate::testing;
+
+    type Entry = (&'static str, &'static str, TransitionCause);
+
+    fn transitions(state: &AppState, unique_node_id: &str) -> Vec<Entry> {
+        let history = state.node_history.read().unwrap();
+        history.get(unique_node_id).map_or(Vec::new(), |log| log.iter().map(|t| (t.from, t.to, t.cause)).collect())
+    }
+
+    /// Espera a que el consumidor del historial anote exactamente `expected`.
+    async fn assert_recorded(state: &AppState, unique_node_id: &str, expected: &[Entry]) {
+        testing::eventually(|| transitions(state, unique_node_id).len() >= expected.len()).await;
+        assert_eq!(transitions(state, unique_node_id), expected);
+    }
+
+    const REGISTERED: Entry = ("absent", "available", TransitionCause::Heartbeat);
+
+    #[actix_web::test]
+    async fn request_failure_is_recorded() {
+        let state = testing::state(&[]);
+        let _consumers = testing::consumers(&state);
+        testing::announce(&state, "lmstudio", "box1", &testing::unreachable_url());
+        let app = init_service(balancer::app(state.clone())).await;
+
+        let req = TestRequest::post()
+            .uri("/lmstudio")
+            .set_json(json!({ "model": "m", "messages": [{ "role": "user", "content": "hola" }] }))
+            .to_request();
+        let resp = call_service(&app, req).await;
+
+        assert!(!resp.status().is_success());
+        assert_recorded(&state, "box1", &[REGISTERED, ("busy", "failed", TransitionCause::RequestFailure)]).await;
+    }
+
+    #[actix_web::test]
+    async fn drain_is_recorded() {
+        let state = testing::state(&[]);
+        let _consumers = testing::consumers(&state);
+        testing::announce(&state, "lmstudio", "box1", "http://10.0.0.1:1234/");
+
+        assert!(state.start_drain(&LMSTUDIO, &testing::node_id("box1"), Duration::from_secs(30)).is_some());
+
+        assert_recorded(&state, "box1", &[REGISTERED, ("available", "draining", TransitionCause::Drain)]).await;
+    }
+
+    #[actix_web::test]
+    async fn revival_by_announcement_is_a_heartbeat() {
+        let state = testing::state(&[]);
+        let _consumers = testing::consumers(&state);
+        testing::announce(&state, "lmstudio", "box1", "http://10.0.0.1:1234/");
+        state.apply_health_check(&LMSTUDIO, &testing::node_id("box1"), false);
+        testing::announce(&state, "lmstudio", "box1", "http://10.0.0.1:1234/");
+
+        assert_recorded(
+            &state,
+            "box1",
+            &[
+                REGISTERED,
+                ("available", "failed", TransitionCause::HealthCheck),
+                ("failed", "available", TransitionCause::Heartbeat),
+            ],
+        )
+        .await;
+    }
+
+    #[actix_web::test]
+    async fn revival_by_health_check_is_recorded() {
+        let state = testing::state(&[]);
+        let _consumers = testing::consumers(&state);
+        testing::announce(&state, "lmstudio", "box1", "http://10.0.0.1:1234/");
+        state.apply_health_check(&LMSTUDIO, &testing::node_id("box1"), false);
+        state.apply_health_check(&LMSTUDIO, &testing::node_id("box1"), true);
+
+        assert_recorded(
+            &state,
+            "box1",
+            &[
+                REGISTERED,
+                ("available", "failed", TransitionCause::HealthCheck),
+                ("failed", "available", TransitionCause::HealthCheck),
+            ],
+        )
+        .await;
+    }
+
+    #[actix_web::test]
+    async fn model_load_is_recorded() {
+        let backend = testing::backend(|cfg| {
+            cfg.route("/api/ps", web::get().to(|| async { HttpResponse::Ok().json(json!({ "models": [{ "name": "small" }] })) }))
+                .route("/api/generate", web::post().to(|| async { HttpResponse::Ok().json(json!({ "done": true })) }));
+        });
+        let state = testing::state(&["--auto-load-model", "big"]);
+        let _consumers = testing::consumers(&state);
+        testing::announce(&state, "ollama", "box1", &backend);
+        state.set_node_models(&OLLAMA, &testing::node_id("box1"), vec!["big".to_string()]);
+
+        let loaded = loader::ensure_model(&state, &OLLAMA, Some("big")).await.unwrap();
+
+        assert_eq!(loaded.map(|(id, _)| id.to_string()).as_deref(), Some("box1"));
+        assert_recorded(
+            &state,
+            "box1",
+            &[
+                REGISTERED,
+                ("available", "loading", TransitionCause::ModelLoad),
+                ("loading", "busy", TransitionCause::ModelLoad),
+            ],
+        )
+        .await;
+    }
+
+    #[actix_web::test]
+    async fn manual_registration_and_removal_are_admin() {
+        let state = testing::state(&["--admin-token", "secreto"]);
+        let _consumers = testing::consumers(&state);
+        let box1 = testing::node_id("box1");
+        state.register_static_node(&LMSTUDIO, &box1, ServiceUrl::parse("http://10.0.0.1:1234/").unwrap(), NodeSource::Local).unwrap();
+        let app = init_service(balancer::app(state.clone())).await;
+
+        let req = TestRequest::post()
+            .uri("/nodes/box1/remove")
+            .insert_header(("Authorization", "Bearer secreto"))
+            .to_request();
+        let resp = call_service(&app, req).await;
+
+        assert!(resp.status().is_success());
+        assert_recorded(
+            &state,
+            "box1",
+            &[("absent", "available", TransitionCause::Admin), ("available", "absent", TransitionCause::Admin)],
+        )
+        .await;
+    }
+
+    #[test]
+    fn history_keeps_the_newest_transitions() {
+        let mut history = NodeHistory::new(10);
+        for _ in 0..MAX_TRANSITIONS_PER_NODE + 5 {
+            history.record("box1", "lmstudio", "available", "failed", TransitionCause::RequestFailure);
+        }
+        history.record("box1", "lmstudio", "failed", "available", TransitionCause::Heartbeat);
+
+        let log = history.get("box1").unwrap();
+        assert_eq!(log.len(), MAX_TRANSITIONS_PER_NODE);
+        assert_eq!(log.back().map(|t| t.cause), Some(TransitionCause::Heartbeat));
+        assert_eq!(history.flap_count("box1", "lmstudio", Duration::from_secs(3600)), MAX_TRANSITIONS_PER_NODE - 1);
+    }
+}
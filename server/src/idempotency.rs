@@ -0,0 +1,279 @@
+// src/idempotency.rs
+//! Cabecera `Idempotency-Key`: un reintento del cliente no genera una segunda respuesta.
+//!
+//! Se guarda, por cliente (su API key o, sin ella, su IP), pool resuelta e `Idempotency-Key`,
+//! la respuesta final de las peticiones con éxito durante un tiempo limitado. Un reintento
+//! recibe la respuesta guardada o, si la original sigue en curso, espera a que termine. Si la
+//! original falló no se guarda nada y el reintento se atiende con normalidad. Las respuestas que
+//! superan el tope de tamaño no se guardan: el reintento recibe un error que lo explica. Cada
+//! clave recuerda el hash del cuerpo con el que llegó; reutilizarla con otro cuerpo es un error
+//! del cliente (422), no un reintento.
+use actix_web::http::header::{HeaderName, HeaderValue, CONTENT_LENGTH};
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use actix_web::HttpResponse;
+use log::warn;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+use crate::limits::StoreUsage;
+
+pub const IDEMPOTENCY_HEADER: &str = "Idempotency-Key";
+
+/// Cabecera añadida a las respuestas servidas desde la caché.
+pub const REPLAYED_HEADER: &str = "Idempotent-Replayed";
+
+pub const DEFAULT_IDEMPOTENCY_TTL_SECS: u64 = 300;
+pub const DEFAULT_IDEMPOTENCY_MAX_ENTRIES: usize = 10_000;
+pub const DEFAULT_IDEMPOTENCY_MAX_BODY_BYTES: usize = 1024 * 1024;
+
+/// Respuesta guardada para repetirla tal cual.
+pub struct StoredResponse {
+    status: StatusCode,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    body: Bytes,
+}
+
+impl StoredResponse {
+    pub fn to_response(&self) -> HttpResponse {
+        let mut builder = HttpResponse::build(self.status);
+        for header in &self.headers {
+            builder.insert_header(header.clone());
+        }
+        builder.insert_header((REPLAYED_HEADER, "true"));
+        builder.body(self.body.clone())
+    }
+}
+
+#[derive(Clone)]
+pub enum Outcome {
+    Pending,
+    Stored(Arc<StoredResponse>),
+    /// Terminó bien pero la respuesta superaba el tope y no se guardó.
+    TooLarge,
+    /// Falló o se abandonó: el siguiente intento se atiende con normalidad.
+    Failed,
+}
+
+struct Entry {
+    outcome: watch::Receiver<Outcome>,
+    /// `None` mientras está en curso; las entradas en curso no caducan.
+    expires_at: Option<Instant>,
+    /// Hash del cuerpo de la petición original.
+    body_hash: u64,
+}
+
+/// (cliente, pool, `Idempotency-Key`).
+type CacheKey = (String, String, String);
+
+fn body_hash(body: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Resultado de reclamar una clave.
+pub enum Claim<'a> {
+    /// Primera vez que se ve la clave: esta petición es la que genera la respuesta.
+    Owner(InFlight<'a>),
+    Replay(Arc<StoredResponse>),
+    TooLarge,
+    /// Otra petición con la misma clave está en curso.
+    Wait(watch::Receiver<Outcome>),
+    /// La caché está llena de peticiones en curso; se atiende sin protección.
+    Full,
+    /// La clave ya se usó con otro cuerpo.
+    Mismatch,
+}
+
+pub struct IdempotencyCache {
+    entries: Mutex<HashMap<CacheKey, Entry>>,
+    ttl: Duration,
+    max_entries: usize,
+    pub max_body_bytes: usize,
+}
+
+impl IdempotencyCache {
+    pub fn new(ttl: Duration, max_entries: usize, max_body_bytes: usize) -> Self {
+        Self { entries: Mutex::new(HashMap::new()), ttl, max_entries, max_body_bytes }
+    }
+
+    pub fn max_entries(&self) -> usize {
+        self.max_entries
+    }
+
+    pub fn usage(&self) -> StoreUsage {
+        let entries = self.entries.lock().unwrap();
+        let bytes = entries
+            .iter()
+            .map(|((client, pool, key), entry)| {
+                let stored = match &*entry.outcome.borrow() {
+                    Outcome::Stored(stored) => stored.body.len() + stored.headers.iter().map(|(n, v)| n.as_str().len() + v.len()).sum::<usize>(),
+                    _ => 0,
+                };
+                std::mem::size_of::<(CacheKey, Entry)>() + client.len() + pool.len() + key.len() + stored
+            })
+            .sum();
+        StoreUsage { entries: entries.len(), bytes }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.ttl.is_zero() && self.max_entries > 0
+    }
+
+    /// Reclama la clave de `client` en `pool` para una petición con este cuerpo.
+    pub fn claim(&self, client: &str, pool: &str, idempotency_key: &str, body: &[u8]) -> Claim<'_> {
+        let key = (client.to_string(), pool.to_string(), idempotency_key.to_string());
+        let body_hash = body_hash(body);
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let expired = |entry: &Entry| entry.expires_at.is_some_and(|at| at <= now);
+        if entries.get(&key).is_some_and(expired) {
+            entries.remove(&key);
+        }
+        if let Some(entry) = entries.get(&key) {
+            if entry.body_hash != body_hash {
+                return Claim::Mismatch;
+            }
+            return match &*entry.outcome.borrow() {
+                Outcome::Pending => Claim::Wait(entry.outcome.clone()),
+                Outcome::Stored(stored) => Claim::Replay(stored.clone()),
+                Outcome::TooLarge => Claim::TooLarge,
+                // Al fallar la entrada se retira antes de avisar; quien espere vuelve a reclamar.
+                Outcome::Failed => Claim::Wait(entry.outcome.clone()),
+            };
+        }
+        if entries.len() >= self.max_entries {
+            entries.retain(|_, entry| !expired(entry));
+        }
+        if entries.len() >= self.max_entries {
+            // Se hace sitio con la respuesta guardada que antes caduque.
+            let oldest = entries
+                .iter()
+                .filter_map(|(key, entry)| entry.expires_at.map(|at| (at, key.clone())))
+                .min()
+                .map(|(_, key)| key);
+            match oldest {
+                Some(oldest) => {
+                    entries.remove(&oldest);
+                }
+                None => return Claim::Full,
+            }
+        }
+        let (sender, outcome) = watch::channel(Outcome::Pending);
+        entries.insert(key.clone(), Entry { outcome, expires_at: None, body_hash });
+        Claim::Owner(InFlight { cache: self, key, sender, finished: false })
+    }
+}
+
+/// Petición en curso dueña de una clave. Si se suelta sin `finish` (error, cliente
+/// desconectado), la clave se libera y las peticiones que esperaban lo reintentan.
+pub struct InFlight<'a> {
+    cache: &'a IdempotencyCache,
+    key: CacheKey,
+    sender: watch::Sender<Outcome>,
+    finished: bool,
+}
+
+impl InFlight<'_> {
+    /// Guarda la respuesta si fue un éxito y la devuelve al cliente original. Las respuestas
+    /// no exitosas no se guardan: la clave se libera para que un reintento se atienda de nuevo.
+    pub async fn finish(mut self, response: HttpResponse) -> HttpResponse {
+        if !response.status().is_success() {
+            return response;
+        }
+        let (head, body) = response.into_parts();
+        let body = match actix_web::body::to_bytes(body).await {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("Idempotencia: No se pudo leer la respuesta para guardarla: {}", e);
+                return head.set_body(Bytes::new()).map_into_boxed_body();
+            }
+        };
+        if body.len() > self.cache.max_body_bytes {
+            self.complete(Outcome::TooLarge);
+        } else {
+            let headers = head
+                .headers()
+                .iter()
+                .filter(|(name, _)| *name != CONTENT_LENGTH)
+                .map(|(name, value)| (name.clone(), value.clone())).collect();
+            self.complete(Outcome::Stored(Arc::new(StoredResponse { status: head.status(), headers, body: body.clone() })));
+        }
+        head.set_body(body).map_into_boxed_body()
+    }
+
+    fn complete(&mut self, outcome: Outcome) {
+        if let Some(entry) = self.cache.entries.lock().unwrap().get_mut(&self.key) {
+            entry.expires_at = Some(Instant::now() + self.cache.ttl);
+        }
+        let _ = self.sender.send(outcome);
+        self.finished = true;
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.cache.entries.lock().unwrap().remove(&self.key);
+            let _ = self.sender.send(Outcome::Failed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use serde_json::{json, Value};
+    use std::sync::atomic::Ordering;
+
+    use crate::balancer;
+    use crate::testing;
+
+    fn chat(ip: &str, content: &str) -> TestRequest {
+        testing::chat_with(json!({ "messages": [{ "role": "user", "content": content }] }))
+            .peer_addr(format!("{}:40000", ip).parse().unwrap())
+            .insert_header((IDEMPOTENCY_HEADER, "pedido-1"))
+    }
+
+    #[actix_web::test]
+    async fn anonymous_callers_do_not_share_keys() {
+        let state = testing::state(&[]);
+        let (url, hits) = testing::counting_chat_node(Duration::ZERO);
+        testing::announce(&state, "lmstudio", "box1", &url);
+        let app = init_service(balancer::app(state.clone())).await;
+
+        for ip in ["10.0.0.1", "10.0.0.2"] {
+            let res = call_service(&app, chat(ip, "hola").to_request()).await;
+            assert_eq!(res.status(), 200);
+            assert!(res.headers().get(REPLAYED_HEADER).is_none(), "{} recibió la respuesta de otro cliente", ip);
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+        // El mismo cliente sí recibe la suya.
+        let res = call_service(&app, chat("10.0.0.1", "hola").to_request()).await;
+        assert_eq!(res.headers().get(REPLAYED_HEADER).unwrap(), "true");
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[actix_web::test]
+    async fn a_reused_key_with_another_body_is_rejected() {
+        let state = testing::state(&[]);
+        let (url, hits) = testing::counting_chat_node(Duration::ZERO);
+        testing::announce(&state, "lmstudio", "box1", &url);
+        let app = init_service(balancer::app(state.clone())).await;
+
+        assert_eq!(call_service(&app, chat("10.0.0.1", "hola").to_request()).await.status(), 200);
+        let res = call_service(&app, chat("10.0.0.1", "adiós").to_request()).await;
+        assert_eq!(res.status(), 422);
+        let body: Value = read_body_json(res).await;
+        assert_eq!(body["error"]["type"], "idempotency_key_reused");
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        assert_eq!(state.metrics.idempotent_replays.load(Ordering::Relaxed), 0);
+    }
+}
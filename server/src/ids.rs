@@ -0,0 +1,241 @@
+// src/ids.rs
+//! Tipos para los identificadores del registro: ID de nodo, nombre de pool y URL de servicio.
+//!
+//! Antes viajaban como `String` posicionales y era fácil pasar una URL donde iba un ID. Se
+//! validan una sola vez al entrar (anuncios, configuración, API de administración) y se
+//! serializan como el texto original, así que el JSON de estado no cambia.
+use serde::{Deserialize, Serialize};
+use std::borrow::{Borrow, Cow};
+use std::fmt;
+use url::{Host, Url};
+
+/// Longitud máxima de un ID de nodo o nombre de pool.
+const MAX_NAME_LEN: usize = 128;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdError(String);
+
+impl fmt::Display for IdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for IdError {}
+
+/// ID único de un nodo. No puede contener comas ni espacios: viaja en los datagramas de descubrimiento.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct NodeId(String);
+
+impl NodeId {
+    pub fn new(id: impl Into<String>) -> Result<Self, IdError> {
+        let id = id.into();
+        if id.is_empty() || id.len() > MAX_NAME_LEN {
+            return Err(IdError(format!("ID de nodo inválido '{}': debe tener entre 1 y {} caracteres", id, MAX_NAME_LEN)));
+        }
+        if id.chars().any(|c| c == ',' || c.is_whitespace() || c.is_control()) {
+            return Err(IdError(format!("ID de nodo inválido '{}': no puede contener comas ni espacios", id)));
+        }
+        Ok(Self(id))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Nombre de pool o de alias: minúsculas, dígitos, `-` y `_`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PoolName(Cow<'static, str>);
+
+impl PoolName {
+    pub fn new(name: impl Into<String>) -> Result<Self, IdError> {
+        let name = name.into();
+        if !Self::is_valid(&name) {
+            return Err(IdError(format!("Nombre de pool inválido '{}': sólo minúsculas, dígitos, '-' y '_'", name)));
+        }
+        Ok(Self(Cow::Owned(name)))
+    }
+
+    /// Nombre fijo de una pool integrada; uno inválido no compila si se usa en un `static`.
+    pub const fn from_static(name: &'static str) -> Self {
+        assert!(Self::is_valid(name), "Nombre de pool inválido");
+        Self(Cow::Borrowed(name))
+    }
+
+    const fn is_valid(name: &str) -> bool {
+        let bytes = name.as_bytes();
+        if bytes.is_empty() || bytes.len() > MAX_NAME_LEN {
+            return false;
+        }
+        let mut i = 0;
+        while i < bytes.len() {
+            let c = bytes[i];
+            if !(c.is_ascii_lowercase() || c.is_ascii_digit() || c == b'-' || c == b'_') {
+                return false;
+            }
+            i += 1;
+        }
+        true
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// URL del endpoint de chat de un nodo, parseada una sola vez. Conserva el texto original para
+/// mostrarlo y serializarlo tal cual se anunció.
+#[derive(Debug, Clone)]
+pub struct ServiceUrl {
+    raw: String,
+    url: Url,
+}
+
+impl ServiceUrl {
+    pub fn parse(raw: impl Into<String>) -> Result<Self, IdError> {
+        let raw = raw.into();
+        let url = Url::parse(&raw).map_err(|e| IdError(format!("URL de servicio inválida '{}': {}", raw, e)))?;
+        if !matches!(url.scheme(), "http" | "https") || url.host().is_none() {
+            return Err(IdError(format!("URL de servicio inválida '{}': se espera http(s)://<host>/...", raw)));
+        }
+        Ok(Self { raw, url })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.raw
+    }
+
+    pub fn path(&self) -> &str {
+        self.url.path()
+    }
+
+    pub fn host(&self) -> Option<Host<&str>> {
+        self.url.host()
+    }
+
+    /// `host:puerto`, con el puerto por defecto del esquema si la URL no lo indica.
+    pub fn address(&self) -> Option<String> {
+        Some(format!("{}:{}", self.url.host_str()?, self.url.port_or_known_default()?))
+    }
+
+    /// URL de otra ruta del mismo backend (p.ej. `/v1/models`), sin query.
+    pub fn endpoint(&self, path: &str) -> Url {
+        let mut url = self.url.clone();
+        url.set_path(path);
+        url.set_query(None);
+        url
+    }
+
+    /// La misma URL con otro host.
+    pub fn with_host(&self, host: &str) -> Result<Self, IdError> {
+        let mut url = self.url.clone();
+        url.set_host(Some(host)).map_err(|e| IdError(format!("Host '{}' inválido para {}: {}", host, self.raw, e)))?;
+        Ok(Self { raw: url.to_string(), url })
+    }
+}
+
+impl PartialEq for ServiceUrl {
+    fn eq(&self, other: &Self) -> bool {
+        self.raw == other.raw
+    }
+}
+
+impl Eq for ServiceUrl {}
+
+impl Serialize for ServiceUrl {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.raw)
+    }
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(&self.0)
+    }
+}
+
+/// Los registros de eventos, historial y métricas siguen guardando texto.
+impl std::ops::Deref for NodeId {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<str> for NodeId {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl PartialEq<str> for NodeId {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl TryFrom<String> for NodeId {
+    type Error = IdError;
+
+    fn try_from(value: String) -> Result<Self, IdError> {
+        Self::new(value)
+    }
+}
+
+impl From<NodeId> for String {
+    fn from(value: NodeId) -> String {
+        value.0
+    }
+}
+
+impl fmt::Display for PoolName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(&self.0)
+    }
+}
+
+/// Los registros de eventos, historial y métricas siguen guardando texto.
+impl std::ops::Deref for PoolName {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<str> for PoolName {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl PartialEq<str> for PoolName {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl TryFrom<String> for PoolName {
+    type Error = IdError;
+
+    fn try_from(value: String) -> Result<Self, IdError> {
+        Self::new(value)
+    }
+}
+
+impl From<PoolName> for String {
+    fn from(value: PoolName) -> String {
+        value.0.into_owned()
+    }
+}
+
+impl fmt::Display for ServiceUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // `pad` respeta el ancho de las columnas de la UI de terminal.
+        f.pad(&self.raw)
+    }
+}
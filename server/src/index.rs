@@ -0,0 +1,311 @@
+// src/index.rs
+//! `GET /`: estado mínimo del balanceador, sin autenticación.
+//!
+//! Sólo da recuentos (nunca IDs ni URLs de nodos). Se responde en texto alineado salvo que el
+//! cliente prefiera JSON de forma explícita en `Accept`: `curl` (que envía `*/*`) recibe texto.
+//! Los recuentos salen de `ui::snapshot`, así que coinciden con la UI de terminal. Con
+//! `--reserve-interactive` se añade la capacidad efectiva de cada clase de carga por pool.
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::{get, web, HttpRequest, HttpResponse, Responder};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::balancer::{AppState, NodeHealth};
+use crate::ui::{self, UiSnapshot};
+use crate::interleave::InterleaveStatus;
+use crate::workload::ClassCapacity;
+
+pub const STATES: [&str; 5] = ["available", "busy", "loading", NodeHealth::FAILED_LABEL, "draining"];
+
+#[derive(Serialize)]
+struct IndexSnapshot {
+    version: String,
+    uptime_secs: u64,
+    queued_requests: u64,
+    active_streams: usize,
+    /// Nodos por pool y estado.
+    pools: BTreeMap<&'static str, BTreeMap<&'static str, usize>>,
+    /// Nodos que puede ocupar cada clase de carga, en las pools con reserva.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    workload: BTreeMap<&'static str, ClassCapacity>,
+    /// Peticiones cortas y largas en espera y en nodos, con `--short-dispatch-share`.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    interleave: BTreeMap<&'static str, InterleaveStatus>,
+}
+
+/// Nodos por pool y estado; cada pool trae todos los estados, aunque estén a cero.
+pub fn pool_counts(ui: &UiSnapshot) -> BTreeMap<&'static str, BTreeMap<&'static str, usize>> {
+    ui.pools
+        .iter()
+        .map(|pool| {
+            let mut counts: BTreeMap<&'static str, usize> = STATES.iter().map(|s| (*s, 0)).collect();
+            for row in &pool.nodes {
+                *counts.entry(row.state).or_default() += 1;
+            }
+            (pool.service, counts)
+        })
+        .collect()
+}
+
+/// Recuentos de la misma instantánea que pinta la UI de terminal.
+fn snapshot(state: &AppState) -> IndexSnapshot {
+    let ui = ui::snapshot(state);
+    let pools = pool_counts(&ui);
+    IndexSnapshot {
+        version: ui.version,
+        uptime_secs: ui.uptime_secs,
+        queued_requests: ui.queued_requests,
+        active_streams: ui.active_streams,
+        pools,
+        workload: workload_capacity(state),
+        interleave: interleave_status(state),
+    }
+}
+
+/// Capacidad por clase de las pools con nodos reservados para interactivo.
+fn workload_capacity(state: &AppState) -> BTreeMap<&'static str, ClassCapacity> {
+    if !state.workload.is_enabled() {
+        return BTreeMap::new();
+    }
+    state
+        .pools()
+        .into_iter()
+        .map(|(_, service, lock)| (service.as_str(), state.workload.capacity(service, &lock.read().unwrap())))
+        .filter(|(_, capacity)| capacity.reserved > 0)
+        .collect()
+}
+
+/// Cortas y largas de cada pool, si el intercalado está activo.
+fn interleave_status(state: &AppState) -> BTreeMap<&'static str, InterleaveStatus> {
+    if !state.interleave.is_enabled() {
+        return BTreeMap::new();
+    }
+    state
+        .pools()
+        .into_iter()
+        .map(|(_, service, lock)| (service.as_str(), state.interleave.status(service, &lock.read().unwrap())))
+        .collect()
+}
+
+/// Indica si el cliente prefiere JSON: `application/json` debe aparecer antes que cualquier
+/// tipo de texto o comodín en el orden de preferencia de `Accept`.
+pub fn prefers_json(headers: &HeaderMap) -> bool {
+    let Some(accept) = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    let mut ranked: Vec<(&str, f32)> = accept
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';').map(str::trim);
+            let mime = parts.next().filter(|m| !m.is_empty())?;
+            let quality = parts
+                .find_map(|p| p.strip_prefix("q="))
+                .and_then(|q| q.parse().ok())
+                .unwrap_or(1.0);
+            Some((mime, quality))
+        })
+        .filter(|(_, quality)| *quality > 0.0)
+        .collect();
+    // Orden estable: a igual calidad manda el orden en que los envió el cliente.
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+    ranked
+        .iter()
+        .find_map(|(mime, _)| match mime.to_ascii_lowercase().as_str() {
+            "application/json" => Some(true),
+            "text/plain" | "text/*" | "*/*" => Some(false),
+            _ => None,
+        })
+        .unwrap_or(false)
+}
+
+pub fn format_uptime(secs: u64) -> String {
+    let (days, hours, minutes, seconds) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    if days > 0 {
+        format!("{}d {:02}h {:02}m {:02}s", days, hours, minutes, seconds)
+    } else {
+        format!("{:02}h {:02}m {:02}s", hours, minutes, seconds)
+    }
+}
+
+fn render_text(snapshot: &IndexSnapshot) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "lmServer {}", snapshot.version);
+    let _ = writeln!(out, "{:<10} {}", "uptime", format_uptime(snapshot.uptime_secs));
+    let _ = writeln!(out, "{:<10} {}", "queued", snapshot.queued_requests);
+    let _ = writeln!(out, "{:<10} {}", "streams", snapshot.active_streams);
+    let _ = writeln!(out);
+    out.push_str(&render_pools(&snapshot.pools));
+    if !snapshot.workload.is_empty() {
+        let _ = writeln!(out);
+        let _ = writeln!(out, "{:<10} {:>10} {:>11} {:>10} {:>10}", "pool", "reserved", "interactive", "batch", "batch-busy");
+        for (pool, capacity) in &snapshot.workload {
+            let reserved = if capacity.effective_reserved < capacity.reserved {
+                format!("{}/{}", capacity.effective_reserved, capacity.reserved)
+            } else {
+                capacity.reserved.to_string()
+            };
+            let _ = writeln!(
+                out,
+                "{:<10} {:>10} {:>11} {:>10} {:>10}",
+                pool, reserved, capacity.interactive, capacity.batch, capacity.batch_busy
+            );
+        }
+    }
+    if !snapshot.interleave.is_empty() {
+        let _ = writeln!(out);
+        let _ = writeln!(out, "{:<10} {:>13} {:>12} {:>10} {:>9} {:>12}", "pool", "short-waiting", "long-waiting", "short-busy", "long-busy", "short-credit");
+        for (pool, status) in &snapshot.interleave {
+            let _ = writeln!(
+                out,
+                "{:<10} {:>13} {:>12} {:>10} {:>9} {:>12.2}",
+                pool, status.short_waiting, status.long_waiting, status.short_busy, status.long_busy, status.short_credit
+            );
+        }
+    }
+    out
+}
+
+/// Tabla de nodos por pool y estado.
+pub fn render_pools(pools: &BTreeMap<&'static str, BTreeMap<&'static str, usize>>) -> String {
+    let mut out = String::new();
+    let _ = write!(out, "{:<10}", "pool");
+    for state in STATES {
+        let _ = write!(out, " {:>10}", state);
+    }
+    let _ = writeln!(out);
+    for (pool, counts) in pools {
+        let _ = write!(out, "{:<10}", pool);
+        for state in STATES {
+            let _ = write!(out, " {:>10}", counts.get(state).copied().unwrap_or(0));
+        }
+        let _ = writeln!(out);
+    }
+    out
+}
+
+#[get("/")]
+async fn index_handler(state: web::Data<AppState>, req: HttpRequest) -> impl Responder {
+    let snapshot = snapshot(&state);
+    if prefers_json(req.headers()) {
+        HttpResponse::Ok().insert_header((header::VARY, "Accept")).json(snapshot)
+    } else {
+        HttpResponse::Ok()
+            .content_type("text/plain; charset=utf-8")
+            .insert_header((header::VARY, "Accept"))
+            .body(render_text(&snapshot))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::HeaderValue;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use serde_json::{json, Value};
+    use std::time::Instant;
+
+    use crate::balancer::{self, LMSTUDIO, OLLAMA};
+    use crate::build_info;
+    use crate::history::TransitionCause;
+    use crate::testing;
+
+    #[test]
+    fn json_only_when_preferred_explicitly() {
+        let cases = [
+            (None, false),
+            (Some("*/*"), false),
+            (Some("text/plain"), false),
+            (Some("application/json"), true),
+            (Some("application/json, text/plain"), true),
+            (Some("text/plain, application/json"), false),
+            (Some("text/plain;q=0.5, application/json"), true),
+            (Some("*/*, application/json;q=0.9"), false),
+            (Some("text/html, application/json;q=0.9, */*;q=0.8"), true),
+            (Some("application/json;q=0, */*"), false),
+            (Some("text/html"), false),
+        ];
+        for (accept, expected) in cases {
+            let mut headers = HeaderMap::new();
+            if let Some(accept) = accept {
+                headers.insert(header::ACCEPT, HeaderValue::from_static(accept));
+            }
+            assert_eq!(prefers_json(&headers), expected, "Accept: {:?}", accept);
+        }
+    }
+
+    /// Dos nodos de LM Studio, uno ocupado, y uno de Ollama caído.
+    fn state() -> web::Data<AppState> {
+        let state = testing::state(&["--admin-token", "secreto", "--protect-read-endpoints"]);
+        testing::announce(&state, "lmstudio", "box1", "http://10.0.0.1:1234/");
+        testing::announce(&state, "lmstudio", "box2", "http://10.0.0.2:1234/");
+        testing::announce(&state, "ollama", "box3", "http://10.0.0.3:11434/");
+        state.update_node_state(&LMSTUDIO, &testing::node_id("box2"), NodeHealth::Busy, TransitionCause::Admin);
+        state.update_node_state(&OLLAMA, &testing::node_id("box3"), NodeHealth::Failed(Instant::now()), TransitionCause::HealthCheck);
+        state
+    }
+
+    async fn get_index(accept: Option<&str>) -> (StatusCode, HeaderMap, String) {
+        let app = init_service(balancer::app(state())).await;
+        let mut req = TestRequest::get().uri("/");
+        if let Some(accept) = accept {
+            req = req.insert_header((header::ACCEPT, accept));
+        }
+        let resp = call_service(&app, req.to_request()).await;
+        let (status, headers) = (resp.status(), resp.headers().clone());
+        (status, headers, String::from_utf8(read_body(resp).await.to_vec()).unwrap())
+    }
+
+    fn assert_no_node_details(body: &str) {
+        for secret in ["box1", "box2", "box3", "10.0.0."] {
+            assert!(!body.contains(secret), "la página muestra {}: {}", secret, body);
+        }
+    }
+
+    #[actix_web::test]
+    async fn curl_gets_aligned_text() {
+        let (status, headers, body) = get_index(Some("*/*")).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers.get(header::CONTENT_TYPE).unwrap(), "text/plain; charset=utf-8");
+        assert_eq!(headers.get(header::VARY).unwrap(), "Accept");
+        assert!(body.starts_with(&format!("lmServer {}\nuptime     00h 00m 0", build_info::summary())), "{}", body);
+        assert!(body.contains("\nqueued     0\n"), "{}", body);
+        let table = format!(
+            "{:<10} {:>10} {:>10} {:>10} {:>10} {:>10}\n{:<10} {:>10} {:>10} {:>10} {:>10} {:>10}\n{:<10} {:>10} {:>10} {:>10} {:>10} {:>10}\n",
+            "pool", "available", "busy", "loading", "failed", "draining",
+            "lmstudio", 1, 1, 0, 0, 0,
+            "ollama", 0, 0, 0, 1, 0,
+        );
+        assert!(body.ends_with(&table), "{}", body);
+        assert_no_node_details(&body);
+    }
+
+    #[actix_web::test]
+    async fn json_clients_get_the_same_counts() {
+        let (status, headers, body) = get_index(Some("application/json")).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers.get(header::CONTENT_TYPE).unwrap(), "application/json");
+        assert_eq!(headers.get(header::VARY).unwrap(), "Accept");
+        let snapshot: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(snapshot["version"], build_info::summary());
+        assert_eq!(snapshot["queued_requests"], 0);
+        assert_eq!(
+            snapshot["pools"],
+            json!({
+                "lmstudio": { "available": 1, "busy": 1, "loading": 0, "failed": 0, "draining": 0 },
+                "ollama": { "available": 0, "busy": 0, "loading": 0, "failed": 1, "draining": 0 },
+            })
+        );
+        assert_no_node_details(&body);
+    }
+
+    #[actix_web::test]
+    async fn no_accept_header_gets_text() {
+        let (status, headers, _) = get_index(None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(headers.get(header::CONTENT_TYPE).unwrap().to_str().unwrap().starts_with("text/plain"));
+    }
+}
@@ -0,0 +1,330 @@
+// src/interleave.rs
+//! Intercalado de peticiones cortas y largas en la cola.
+//!
+//! Una generación en streaming retiene su nodo durante minutos. Si la cola se llena de ellas,
+//! cada nodo que se libera se lo lleva otra larga y las peticiones cortas, que tardarían un
+//! segundo, esperan detrás indefinidamente. Con `--short-dispatch-share F`, mientras esperan
+//! peticiones de las dos longitudes en una pool, una fracción F de los despachos queda para
+//! las cortas.
+//!
+//! Es corta una petición sin `stream` o con `max_tokens` hasta `--short-max-tokens`; el resto
+//! es larga. No hay dos colas: cada pool lleva un contador de déficit. Cada despacho con las
+//! dos longitudes esperando suma F al crédito de las cortas, y cada despacho a una corta le
+//! resta 1. Una larga no ocupa nodo si con su despacho se debería a las cortas uno entero:
+//! con F = 0.5 se alternan, con F = 0.25 van tres largas por cada corta.
+//! Las cortas nunca esperan por las largas, y el orden dentro de cada longitud es el de
+//! siempre. Sin cortas esperando, el crédito vuelve a cero: no se acumula para después.
+//!
+//! Cada nodo ocupado recuerda la longitud de su petición (`NodeInfo::length`), para ver en `/`
+//! cuántos nodos retienen las largas.
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+
+use crate::balancer::{NodeHealth, NodeInfo};
+use crate::ids::NodeId;
+
+pub const DEFAULT_SHORT_MAX_TOKENS: u64 = 256;
+
+#[derive(Debug)]
+pub struct InterleaveConfigError(String);
+
+impl fmt::Display for InterleaveConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for InterleaveConfigError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RequestLength {
+    /// Sin streaming o con pocos tokens: ocupa el nodo poco tiempo.
+    Short,
+    /// Streaming sin tope bajo de tokens: puede retener el nodo minutos.
+    Long,
+}
+
+impl RequestLength {
+    fn index(self) -> usize {
+        match self {
+            RequestLength::Short => 0,
+            RequestLength::Long => 1,
+        }
+    }
+}
+
+#[derive(Default)]
+struct PoolDeficit {
+    /// Peticiones esperando nodo, por longitud (`RequestLength::index`).
+    waiting: [usize; 2],
+    /// Despachos que se deben a las cortas.
+    credit: f64,
+}
+
+/// Estado del intercalado en una pool, para `/`.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct InterleaveStatus {
+    pub short_waiting: usize,
+    pub long_waiting: usize,
+    pub short_busy: usize,
+    pub long_busy: usize,
+    pub short_credit: f64,
+}
+
+pub struct Interleaver {
+    /// Fracción de los despachos reservada a las cortas; 0 lo desactiva.
+    share: f64,
+    short_max_tokens: u64,
+    pools: Mutex<HashMap<String, PoolDeficit>>,
+}
+
+impl Interleaver {
+    pub fn new(share: f64, short_max_tokens: u64) -> Result<Self, InterleaveConfigError> {
+        if !(0.0..=1.0).contains(&share) {
+            return Err(InterleaveConfigError(format!("--short-dispatch-share debe estar entre 0 y 1, no {}", share)));
+        }
+        Ok(Self { share, short_max_tokens, pools: Mutex::new(HashMap::new()) })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.share > 0.0
+    }
+
+    pub fn classify(&self, stream: bool, max_tokens: Option<u64>) -> RequestLength {
+        if !stream || max_tokens.is_some_and(|tokens| tokens <= self.short_max_tokens) {
+            RequestLength::Short
+        } else {
+            RequestLength::Long
+        }
+    }
+
+    /// Apunta la petición como en espera en la pool hasta que se suelte la guarda.
+    pub fn wait(&self, service: &str, length: RequestLength) -> Waiting<'_> {
+        if self.is_enabled() {
+            self.pools.lock().unwrap().entry(service.to_string()).or_default().waiting[length.index()] += 1;
+        }
+        Waiting { interleaver: self, service: service.to_string(), length }
+    }
+
+    /// Si una petición de `length` puede ocupar un nodo de la pool ahora mismo.
+    pub fn admits(&self, service: &str, length: RequestLength) -> bool {
+        if !self.is_enabled() || length == RequestLength::Short {
+            return true;
+        }
+        let pools = self.pools.lock().unwrap();
+        pools.get(service).is_none_or(|pool| pool.waiting[RequestLength::Short.index()] == 0 || pool.credit + self.share < 1.0)
+    }
+
+    /// Anota un despacho en la pool.
+    pub fn dispatched(&self, service: &str, length: RequestLength) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut pools = self.pools.lock().unwrap();
+        let Some(pool) = pools.get_mut(service) else {
+            return;
+        };
+        let [short, long] = pool.waiting;
+        if short == 0 {
+            pool.credit = 0.0;
+        } else if long > 0 {
+            pool.credit += self.share;
+            if length == RequestLength::Short {
+                pool.credit = (pool.credit - 1.0).max(0.0);
+            }
+        }
+    }
+
+    /// Estado de la pool. Se llama con el lock del registro tomado.
+    pub fn status(&self, service: &str, nodes: &HashMap<NodeId, NodeInfo>) -> InterleaveStatus {
+        let busy = |length| nodes.values().filter(|info| matches!(info.state, NodeHealth::Busy) && info.length == Some(length)).count();
+        let (waiting, credit) = self.pools.lock().unwrap().get(service).map_or(([0, 0], 0.0), |pool| (pool.waiting, pool.credit));
+        InterleaveStatus {
+            short_waiting: waiting[0],
+            long_waiting: waiting[1],
+            short_busy: busy(RequestLength::Short),
+            long_busy: busy(RequestLength::Long),
+            short_credit: (credit * 100.0).round() / 100.0,
+        }
+    }
+
+    /// Configuración para `/config`.
+    pub fn describe(&self) -> serde_json::Value {
+        json!({
+            "short_dispatch_share": self.share,
+            "short_max_tokens": self.short_max_tokens,
+        })
+    }
+}
+
+/// Petición en espera; al soltarla deja de contar.
+pub struct Waiting<'a> {
+    interleaver: &'a Interleaver,
+    service: String,
+    length: RequestLength,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if !self.interleaver.is_enabled() {
+            return;
+        }
+        let mut pools = self.interleaver.pools.lock().unwrap();
+        if let Some(pool) = pools.get_mut(&self.service) {
+            pool.waiting[self.length.index()] -= 1;
+            if pool.waiting == [0, 0] {
+                pools.remove(&self.service);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service};
+    use actix_web::{web, HttpResponse};
+    use futures_util::future::join_all;
+    use serde_json::Value;
+    use std::time::{Duration, Instant};
+
+    use crate::balancer;
+    use crate::testing;
+
+    use RequestLength::{Long, Short};
+
+    #[test]
+    fn share_must_be_a_fraction() {
+        assert!(Interleaver::new(-0.1, 256).is_err());
+        assert!(Interleaver::new(1.5, 256).is_err());
+        assert!(!Interleaver::new(0.0, 256).unwrap().is_enabled());
+        assert!(Interleaver::new(1.0, 256).unwrap().is_enabled());
+    }
+
+    #[test]
+    fn short_means_no_stream_or_few_tokens() {
+        let interleaver = Interleaver::new(0.5, 256).unwrap();
+        assert_eq!(interleaver.classify(false, None), Short);
+        assert_eq!(interleaver.classify(false, Some(100_000)), Short);
+        assert_eq!(interleaver.classify(true, Some(256)), Short);
+        assert_eq!(interleaver.classify(true, Some(257)), Long);
+        assert_eq!(interleaver.classify(true, None), Long);
+    }
+
+    /// Despacha siempre lo que el intercalado admite, con una cola de cortas y largas que nunca
+    /// se vacía; devuelve las longitudes en orden.
+    fn dispatches(share: f64, count: usize) -> Vec<RequestLength> {
+        let interleaver = Interleaver::new(share, 256).unwrap();
+        let _short = interleaver.wait("lmstudio", Short);
+        let _long = interleaver.wait("lmstudio", Long);
+        (0..count)
+            .map(|_| {
+                let length = if interleaver.admits("lmstudio", Long) { Long } else { Short };
+                interleaver.dispatched("lmstudio", length);
+                length
+            })
+            .collect()
+    }
+
+    #[test]
+    fn deficit_counter_reserves_the_share_for_short_requests() {
+        assert_eq!(dispatches(0.5, 6), [Long, Short, Long, Short, Long, Short]);
+        assert_eq!(dispatches(0.25, 8), [Long, Long, Long, Short, Long, Long, Long, Short]);
+        assert_eq!(dispatches(1.0, 3), [Short, Short, Short]);
+        for share in [0.1, 0.3, 0.5, 0.75] {
+            let shorts = dispatches(share, 100).into_iter().filter(|length| *length == Short).count();
+            assert!((shorts as f64 - share * 100.0).abs() <= 1.0, "{} -> {} cortas", share, shorts);
+        }
+    }
+
+    #[test]
+    fn credit_is_not_saved_up_without_short_requests() {
+        let interleaver = Interleaver::new(0.5, 256).unwrap();
+        let short = interleaver.wait("lmstudio", Short);
+        let _long = interleaver.wait("lmstudio", Long);
+        interleaver.dispatched("lmstudio", Long);
+        assert!(!interleaver.admits("lmstudio", Long));
+        // Sin cortas esperando las largas pasan, y el crédito se pierde.
+        drop(short);
+        assert!(interleaver.admits("lmstudio", Long));
+        interleaver.dispatched("lmstudio", Long);
+        let _short = interleaver.wait("lmstudio", Short);
+        assert!(interleaver.admits("lmstudio", Long));
+        // Cada pool lleva su contador, y las cortas nunca esperan.
+        assert!(interleaver.admits("ollama", Long));
+        assert!(interleaver.admits("lmstudio", Short));
+    }
+
+    #[test]
+    fn waiting_guards_are_counted_until_dropped() {
+        let interleaver = Interleaver::new(0.5, 256).unwrap();
+        let nodes = HashMap::new();
+        {
+            let _waiting = [interleaver.wait("lmstudio", Short), interleaver.wait("lmstudio", Long), interleaver.wait("lmstudio", Long)];
+            let status = interleaver.status("lmstudio", &nodes);
+            assert_eq!((status.short_waiting, status.long_waiting), (1, 2));
+        }
+        let status = interleaver.status("lmstudio", &nodes);
+        assert_eq!((status.short_waiting, status.long_waiting), (0, 0));
+        assert!(interleaver.pools.lock().unwrap().is_empty());
+        // Desactivado no lleva la cuenta.
+        let off = Interleaver::new(0.0, 256).unwrap();
+        let _waiting = off.wait("lmstudio", Long);
+        assert!(off.pools.lock().unwrap().is_empty());
+    }
+
+    /// Nodo que tarda `long` con las peticiones en streaming y `short` con el resto.
+    fn node(long: Duration, short: Duration) -> String {
+        testing::backend(move |cfg| {
+            cfg.default_service(web::to(move |body: web::Json<Value>| async move {
+                if body["stream"] == true {
+                    tokio::time::sleep(long).await;
+                    HttpResponse::Ok().content_type("text/event-stream").body("data: {\"choices\":[{\"delta\":{\"content\":\"hola\"}}]}\n\ndata: [DONE]\n\n")
+                } else {
+                    tokio::time::sleep(short).await;
+                    testing::chat_reply()
+                }
+            }));
+        })
+    }
+
+    #[actix_web::test]
+    async fn short_requests_are_not_starved_by_long_streams() {
+        let state = testing::state(&["--short-dispatch-share", "0.5", "--sse-keepalive-secs", "0"]);
+        for id in ["box1", "box2"] {
+            testing::announce(&state, "lmstudio", id, &node(Duration::from_millis(1_000), Duration::from_millis(50)));
+        }
+        let app = init_service(balancer::app(state.clone())).await;
+
+        let app = &app;
+        let start = Instant::now();
+        let long = join_all((0..5).map(|_| async {
+            let res = call_service(app, testing::chat_with(serde_json::json!({ "stream": true })).to_request()).await;
+            assert_eq!(res.status(), 200);
+            actix_web::test::read_body(res).await;
+        }));
+        let short = join_all((0..20).map(|i| async move {
+            // Llegan detrás de las largas, que ya llenan la cola.
+            tokio::time::sleep(Duration::from_millis(20 + i)).await;
+            let sent = Instant::now();
+            let res = call_service(app, testing::chat_with(serde_json::json!({ "stream": false })).to_request()).await;
+            assert_eq!(res.status(), 200);
+            sent.elapsed()
+        }));
+        let (_, mut waits) = tokio::join!(long, short);
+        waits.sort();
+
+        // Las dos primeras largas entran antes de que esperen cortas. Al acabar, la reserva da
+        // uno de los dos nodos a una corta; sin ella las largas se quedan los dos otra vez.
+        assert!(waits[0] < Duration::from_millis(1_600), "esperas {:?}", waits);
+        // La cola se consulta cada 200ms: las cortas salen a ese ritmo detrás de cada larga.
+        let p95 = waits[18];
+        assert!(p95 < Duration::from_secs(5), "p95 de las cortas {:?}, esperas {:?}", p95, waits);
+        assert!(start.elapsed() < Duration::from_secs(6), "{:?}", start.elapsed());
+        let status = state.interleave.status("lmstudio", &state.pool("lmstudio").unwrap().read().unwrap());
+        assert_eq!((status.short_waiting, status.long_waiting, status.short_busy, status.long_busy), (0, 0, 0, 0));
+    }
+}
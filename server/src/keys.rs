@@ -0,0 +1,208 @@
+// src/keys.rs
+//! Parámetros por defecto, forzados y modelos permitidos por API key.
+//!
+//! ```toml
+//! [keys.support-bot]
+//! key = "sk-support-..."
+//! forced = { temperature = 0.2, model = "llama3:8b" }
+//! allowed_models = ["llama3:8b"]
+//!
+//! [keys.research]
+//! key = "sk-research-..."
+//! defaults = { max_tokens = 4096 }
+//!
+//! [keys.premium]
+//! key = "sk-premium-..."
+//! pool = "lmstudio"
+//!
+//! [keys.nightly-jobs]
+//! key = "sk-nightly-..."
+//! class = "batch"
+//!
+//! [keys.search-frontend]
+//! key = "sk-search-..."
+//! labels = { forced = { feature = "search" }, forbidden = ["customer"] }
+//!
+//! [keys.scraper]
+//! key = "sk-scraper-..."
+//! max_in_flight = 2
+//!
+//! [keys.oncall]
+//! key = "sk-oncall-..."
+//! scopes = ["nodes:read", "stats:read", "stats:write"]
+//! ```
+//!
+//! Los scopes se aplican en `auth`; una key sin `scopes` sólo puede hacer inferencia. `pool`
+//! manda todas las peticiones de la key a esa pool, sin pasar por el reparto por coste (`spill`).
+//! `class` fija la clase de carga de sus peticiones (ver `workload`). `labels` fuerza o prohíbe
+//! etiquetas de analítica (ver `labels`).
+//! `max_in_flight` cambia para la key el tope de peticiones en curso (ver `client_limit`).
+use actix_web::web::Bytes;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+use crate::auth::Scope;
+use crate::labels::LabelPolicy;
+use crate::workload::WorkloadClass;
+
+/// Cabecera de respuesta con los campos que la política de la key sobrescribió.
+pub const OVERRIDE_HEADER: &str = "X-LMServer-Overridden";
+
+#[derive(Debug)]
+pub struct KeyConfigError(String);
+
+impl fmt::Display for KeyConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for KeyConfigError {}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ParamSet {
+    pub model: Option<String>,
+    pub temperature: Option<f64>,
+    pub max_tokens: Option<u64>,
+}
+
+impl ParamSet {
+    fn entries(&self) -> Vec<(&'static str, serde_json::Value)> {
+        let mut entries = Vec::new();
+        if let Some(model) = &self.model {
+            entries.push(("model", model.clone().into()));
+        }
+        if let Some(temperature) = self.temperature {
+            entries.push(("temperature", temperature.into()));
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            entries.push(("max_tokens", max_tokens.into()));
+        }
+        entries
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyPolicy {
+    pub key: String,
+    #[serde(default)]
+    pub defaults: ParamSet,
+    #[serde(default)]
+    pub forced: ParamSet,
+    pub allowed_models: Option<Vec<String>>,
+    /// Rutas a las que da acceso la key; `None` es sólo inferencia.
+    pub scopes: Option<Vec<Scope>>,
+    /// Pool (o alias) fija para las peticiones de la key.
+    pub pool: Option<String>,
+    /// Clase de carga de las peticiones de la key; manda sobre `X-Workload-Class`.
+    pub class: Option<WorkloadClass>,
+    /// Etiquetas de `X-LMServer-Labels` forzadas o prohibidas.
+    #[serde(default)]
+    pub labels: LabelPolicy,
+    /// Tope de peticiones en curso de la key; manda sobre `--max-client-in-flight`.
+    pub max_in_flight: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeysFile {
+    #[serde(default)]
+    keys: BTreeMap<String, KeyPolicy>,
+}
+
+/// Resultado de aplicar una política a una petición.
+pub struct AppliedPolicy {
+    /// Cuerpo reescrito; `None` si no hubo que tocar nada.
+    pub body: Option<Bytes>,
+    /// Campos enviados por el cliente que se sobrescribieron con un valor forzado.
+    pub overridden: Vec<&'static str>,
+    /// Modelo efectivo tras aplicar la política.
+    pub model: Option<String>,
+}
+
+/// La petición pide un modelo que la key no puede usar.
+pub struct ModelNotAllowed {
+    pub model: Option<String>,
+    pub allowed: Vec<String>,
+}
+
+impl KeyPolicy {
+    /// Aplica valores por defecto y forzados y comprueba la lista de modelos permitidos.
+    pub fn apply(&self, body: &[u8]) -> Result<AppliedPolicy, ModelNotAllowed> {
+        let mut json: serde_json::Value = serde_json::from_slice(body).unwrap_or_default();
+        let mut changed = false;
+        let mut overridden = Vec::new();
+        if let Some(object) = json.as_object_mut() {
+            for (field, value) in self.defaults.entries() {
+                if !object.contains_key(field) {
+                    object.insert(field.to_string(), value);
+                    changed = true;
+                }
+            }
+            for (field, value) in self.forced.entries() {
+                match object.insert(field.to_string(), value.clone()) {
+                    Some(previous) if previous != value => overridden.push(field),
+                    Some(_) => continue,
+                    None => {}
+                }
+                changed = true;
+            }
+        }
+
+        let model = json.get("model").and_then(|m| m.as_str()).map(str::to_string);
+        if let Some(allowed) = &self.allowed_models {
+            if !model.as_ref().is_some_and(|m| allowed.contains(m)) {
+                return Err(ModelNotAllowed { model, allowed: allowed.clone() });
+            }
+        }
+        let body = changed.then(|| serde_json::to_vec(&json).ok().map(Bytes::from)).flatten();
+        Ok(AppliedPolicy { body, overridden, model })
+    }
+}
+
+/// Políticas cargadas, indexadas por la API key.
+#[derive(Default)]
+pub struct KeyPolicies {
+    by_key: BTreeMap<String, (String, KeyPolicy)>,
+}
+
+impl KeyPolicies {
+    pub fn load(path: &Path, is_pool: impl Fn(&str) -> bool) -> Result<Self, KeyConfigError> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| KeyConfigError(format!("No se pudo leer {}: {}", path.display(), e)))?;
+        let file: KeysFile = toml::from_str(&content)
+            .map_err(|e| KeyConfigError(format!("Archivo de API keys inválido {}: {}", path.display(), e)))?;
+        let mut policies = Self::default();
+        for (name, policy) in file.keys {
+            if let Some(pool) = policy.pool.as_deref().filter(|pool| !is_pool(pool)) {
+                return Err(KeyConfigError(format!("La key '{}' fija una pool desconocida '{}'.", name, pool)));
+            }
+            if let Err(e) = policy.labels.validate() {
+                return Err(KeyConfigError(format!("Etiquetas inválidas en la key '{}': {}.", name, e)));
+            }
+            if let Some((other, _)) = policies.by_key.get(&policy.key) {
+                return Err(KeyConfigError(format!("Las secciones '{}' y '{}' usan la misma API key.", other, name)));
+            }
+            policies.by_key.insert(policy.key.clone(), (name, policy));
+        }
+        Ok(policies)
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_key.len()
+    }
+
+    /// Si alguna key tiene el scope.
+    pub fn grants(&self, scope: Scope) -> bool {
+        self.by_key.values().any(|(_, policy)| policy.scopes.as_ref().is_some_and(|scopes| scopes.contains(&scope)))
+    }
+
+    /// Política de la key, con el nombre de su sección para los logs.
+    pub fn lookup(&self, api_key: &str) -> Option<(&str, &KeyPolicy)> {
+        self.by_key.get(api_key).map(|(name, policy)| (name.as_str(), policy))
+    }
+}
@@ -0,0 +1,117 @@
+// src/labels.rs
+//! Etiquetas de analítica de las peticiones (`X-LMServer-Labels`).
+//!
+//! La aplicación que llama sabe cosas que el balanceador no, como la función o el cliente
+//! detrás de cada petición. Con `X-LMServer-Labels: feature=search,customer=acme` las deja en
+//! el registro de auditoría (y su exportación), en el uso diario y en el log, y
+//! `GET /usage/daily?group_by=label:feature` suma el uso por el valor de una de ellas.
+//!
+//! Son metadatos del balanceador: nunca se reenvían a los nodos, ni siquiera con
+//! `--forward-header`. Se admiten hasta `MAX_LABELS` pares, con claves de hasta
+//! `MAX_KEY_BYTES` y valores de hasta `MAX_VALUE_BYTES` en ASCII alfanumérico, `-`, `_` y `.`;
+//! una cabecera que no cumple se rechaza con 400 indicando el par culpable.
+//!
+//! Cada API key puede forzar etiquetas o prohibir claves en su sección de `--api-keys-file`:
+//!
+//! ```toml
+//! [keys.search-frontend]
+//! key = "sk-search-..."
+//! labels = { forced = { feature = "search" }, forbidden = ["customer"] }
+//! ```
+//!
+//! Una etiqueta forzada sustituye a la que mande el cliente; una clave prohibida rechaza la
+//! petición.
+use actix_web::http::header::HeaderMap;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+pub const LABELS_HEADER: &str = "X-LMServer-Labels";
+
+pub const MAX_LABELS: usize = 8;
+pub const MAX_KEY_BYTES: usize = 32;
+pub const MAX_VALUE_BYTES: usize = 64;
+
+/// Etiquetas de una petición por clave.
+pub type RequestLabels = BTreeMap<String, String>;
+
+fn valid_part(part: &str, max_bytes: usize) -> bool {
+    !part.is_empty() && part.len() <= max_bytes && part.bytes().all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.'))
+}
+
+/// Interpreta un par `clave=valor`; el error lo nombra.
+fn parse_label(entry: &str) -> Result<(String, String), String> {
+    let entry = entry.trim();
+    match entry.split_once('=') {
+        Some((key, value)) if valid_part(key, MAX_KEY_BYTES) && valid_part(value, MAX_VALUE_BYTES) => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!(
+            "par inválido '{}': se esperaba clave=valor con clave de hasta {} y valor de hasta {} caracteres alfanuméricos, '-', '_' o '.'",
+            entry, MAX_KEY_BYTES, MAX_VALUE_BYTES
+        )),
+    }
+}
+
+/// Lee `X-LMServer-Labels`. Sin cabecera no hay etiquetas.
+pub fn from_headers(headers: &HeaderMap) -> Result<RequestLabels, String> {
+    let mut labels = RequestLabels::new();
+    let Some(value) = headers.get(LABELS_HEADER) else {
+        return Ok(labels);
+    };
+    let value = value.to_str().map_err(|_| "la cabecera no es texto".to_string())?;
+    for entry in value.split(',').filter(|entry| !entry.trim().is_empty()) {
+        let (key, value) = parse_label(entry)?;
+        if labels.contains_key(&key) {
+            return Err(format!("la clave '{}' aparece más de una vez", key));
+        }
+        if labels.len() == MAX_LABELS {
+            return Err(format!("par '{}' de más: se admiten hasta {} etiquetas", entry.trim(), MAX_LABELS));
+        }
+        labels.insert(key, value);
+    }
+    Ok(labels)
+}
+
+/// Etiquetas forzadas y claves prohibidas de una API key.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LabelPolicy {
+    #[serde(default)]
+    pub forced: BTreeMap<String, String>,
+    #[serde(default)]
+    pub forbidden: Vec<String>,
+}
+
+impl LabelPolicy {
+    /// Comprueba que las etiquetas forzadas y las claves prohibidas son válidas.
+    pub fn validate(&self) -> Result<(), String> {
+        for (key, value) in &self.forced {
+            parse_label(&format!("{}={}", key, value))?;
+            if self.forbidden.contains(key) {
+                return Err(format!("la clave '{}' está a la vez forzada y prohibida", key));
+            }
+        }
+        if self.forced.len() > MAX_LABELS {
+            return Err(format!("se admiten hasta {} etiquetas forzadas", MAX_LABELS));
+        }
+        match self.forbidden.iter().find(|key| !valid_part(key, MAX_KEY_BYTES)) {
+            Some(key) => Err(format!("clave prohibida inválida '{}'", key)),
+            None => Ok(()),
+        }
+    }
+
+    /// Rechaza las claves prohibidas y pone las forzadas.
+    pub fn apply(&self, labels: &mut RequestLabels) -> Result<(), String> {
+        if let Some((key, value)) = labels.iter().find(|(key, _)| self.forbidden.contains(key)) {
+            return Err(format!("la etiqueta '{}={}' no está permitida para esta API key", key, value));
+        }
+        labels.extend(self.forced.iter().map(|(key, value)| (key.clone(), value.clone())));
+        if labels.len() > MAX_LABELS {
+            return Err(format!("con las etiquetas forzadas de la API key se pasa de {} etiquetas", MAX_LABELS));
+        }
+        Ok(())
+    }
+}
+
+/// Forma `clave=valor,...` para el log.
+pub fn label(labels: &RequestLabels) -> String {
+    labels.iter().map(|(key, value)| format!("{}={}", key, value)).collect::<Vec<_>>().join(",")
+}
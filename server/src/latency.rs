@@ -0,0 +1,98 @@
+// src/latency.rs
+//! Latencia reciente de cada nodo, para `--node-selection latency`.
+//!
+//! Cada reenvío a un nodo mide lo que tarda su respuesta (hasta las cabeceras: en un stream es
+//! el tiempo al primer byte) y lo mezcla en una media móvil exponencial (EWMA) guardada en
+//! `NodeInfo::latency`: `ewma = alpha * muestra + (1 - alpha) * ewma`. Un fallo (error de
+//! conexión o estado no exitoso) entra como una muestra de `--latency-failure-penalty-ms`.
+//! La media vive con el nodo en el registro: un nodo que sale y vuelve a registrarse empieza
+//! sin ella.
+//!
+//! Con la estrategia `latency` los nodos libres se prueban de menor a mayor media; los que aún
+//! no tienen ninguna van primero, para medirlos. Una fracción `--latency-explore` de las
+//! elecciones ignora la media y va por turnos, para que un nodo lento siga recibiendo alguna
+//! petición y pueda recuperarse cuando deje de serlo.
+use serde_json::json;
+use std::fmt;
+use std::time::Duration;
+
+use crate::selection::Xorshift;
+
+pub const DEFAULT_LATENCY_ALPHA: f64 = 0.3;
+pub const DEFAULT_FAILURE_PENALTY_MS: u64 = 30_000;
+pub const DEFAULT_LATENCY_EXPLORE: f64 = 0.1;
+
+#[derive(Debug)]
+pub struct LatencyConfigError(String);
+
+impl fmt::Display for LatencyConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for LatencyConfigError {}
+
+/// Media móvil de la latencia de un nodo, en milisegundos.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LatencyEwma {
+    ms: Option<f64>,
+}
+
+impl LatencyEwma {
+    pub fn ms(&self) -> Option<f64> {
+        self.ms
+    }
+}
+
+pub struct LatencyPolicy {
+    alpha: f64,
+    failure_penalty: Duration,
+    /// Fracción de las elecciones que van por turnos.
+    explore: f64,
+    /// Sorteo de las elecciones que exploran.
+    rng: Xorshift,
+}
+
+impl LatencyPolicy {
+    pub fn new(alpha: f64, failure_penalty: Duration, explore: f64) -> Result<Self, LatencyConfigError> {
+        if !(alpha > 0.0 && alpha <= 1.0) {
+            return Err(LatencyConfigError(format!("--latency-alpha debe estar en (0, 1], no {}", alpha)));
+        }
+        if !(0.0..=1.0).contains(&explore) {
+            return Err(LatencyConfigError(format!("--latency-explore debe estar entre 0 y 1, no {}", explore)));
+        }
+        Ok(Self { alpha, failure_penalty, explore, rng: Xorshift::default() })
+    }
+
+    /// Mezcla una respuesta que tardó `elapsed`.
+    pub fn observe(&self, ewma: &mut LatencyEwma, elapsed: Duration) {
+        let sample = elapsed.as_secs_f64() * 1000.0;
+        ewma.ms = Some(match ewma.ms {
+            Some(previous) => self.alpha * sample + (1.0 - self.alpha) * previous,
+            None => sample,
+        });
+    }
+
+    /// Mezcla un fallo como una respuesta de `failure_penalty`.
+    pub fn penalize(&self, ewma: &mut LatencyEwma) {
+        self.observe(ewma, self.failure_penalty);
+    }
+
+    /// Si esta elección debe ir por turnos en lugar de por latencia.
+    pub fn explores(&self) -> bool {
+        if self.explore <= 0.0 {
+            return false;
+        }
+        self.rng.next_f64() < self.explore
+    }
+
+    /// Configuración para `/config`.
+    pub fn describe(&self) -> serde_json::Value {
+        json!({
+            "alpha": self.alpha,
+            "failure_penalty_ms": self.failure_penalty.as_millis() as u64,
+            "explore": self.explore,
+        })
+    }
+}
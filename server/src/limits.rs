@@ -0,0 +1,487 @@
+// src/limits.rs
+//! Topes de memoria de todas las estructuras que crecen con el tráfico o con los nodos.
+//!
+//! Cada estructura se construye con su tope (sección `[limits]` de `--limits-file`; sin archivo,
+//! los valores por defecto) y, al llegar a él, descarta la entrada menos útil en lugar de crecer.
+//! `report` reúne en un único sitio el tamaño actual, el tope y una estimación de bytes de cada
+//! una; se publica en `GET /debug/memory` y como gauges en `/metrics`, y se avisa en el log
+//! cuando alguna pasa del 90% de su tope.
+use actix_web::{get, web, HttpResponse, Responder};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashSet;
+use std::fmt;
+use std::mem::size_of;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::interval;
+
+use crate::balancer::{AppState, NodeInfo};
+use crate::ids::NodeId;
+use crate::metrics::{escape_label, write_labeled_metric};
+
+/// Cada cuánto se revisa si alguna estructura se acerca a su tope.
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Porcentaje del tope a partir del que se avisa.
+const WARN_PERCENT: usize = 90;
+
+#[derive(Debug)]
+pub struct LimitsConfigError(String);
+
+impl fmt::Display for LimitsConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for LimitsConfigError {}
+
+/// Topes de cada estructura en memoria.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Nodos por pool. Lleno, un nodo nuevo sustituye al fallido más antiguo o se rechaza.
+    pub nodes_per_pool: usize,
+    /// Nodos con historial de transiciones (cada uno guarda las últimas 50).
+    pub history_nodes: usize,
+    /// Nodos con errores de reenvío recientes (cada uno guarda los últimos 50).
+    pub error_log_nodes: usize,
+    /// Series (nodo, categoría) del contador de errores de `/metrics`.
+    pub error_metric_series: usize,
+    /// Respuestas de la caché de idempotencia. Si no se indica, vale `--idempotency-max-entries`.
+    pub idempotency_entries: Option<usize>,
+    /// Asignaciones por pool en la ventana de reparto.
+    pub fairness_dispatches: usize,
+    /// Cambios del registro que se guardan para `GET /nodes/watch`.
+    pub revision_changes: usize,
+    /// Eventos en cola por suscriptor del bus de eventos.
+    pub event_buffer: usize,
+    pub dns_hosts: usize,
+    pub pipeline_reservations: usize,
+    /// Sesiones `X-Session-Id` fijadas a un nodo. Lleno, se olvida la usada hace más tiempo.
+    pub sticky_sessions: usize,
+    /// Pares (nodo, versión) de los que ya se avisó por versión distinta.
+    pub version_warnings: usize,
+    /// Duraciones de peticiones en la ventana de `GET /status/public`.
+    pub request_samples: usize,
+    /// Lápidas de nodos que salieron del registro. Lleno, se olvida la más antigua.
+    pub tombstones: usize,
+    /// Últimas peticiones de inferencia, sin cuerpo, para el paquete de soporte.
+    pub recent_requests: usize,
+    /// Últimos eventos del bus, para el paquete de soporte.
+    pub recent_events: usize,
+    /// Clientes con peticiones en curso (`--max-client-in-flight`). Lleno, un cliente nuevo se
+    /// rechaza hasta que otro termine: no se puede olvidar a uno con peticiones en curso.
+    pub client_in_flight: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            nodes_per_pool: 1024,
+            history_nodes: 4096,
+            error_log_nodes: 4096,
+            error_metric_series: 16_384,
+            idempotency_entries: None,
+            fairness_dispatches: 100_000,
+            revision_changes: 4096,
+            event_buffer: 1024,
+            dns_hosts: 1024,
+            pipeline_reservations: 1024,
+            sticky_sessions: 16_384,
+            version_warnings: 1024,
+            request_samples: 10_000,
+            tombstones: 4096,
+            recent_requests: 200,
+            recent_events: 200,
+            client_in_flight: 16_384,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LimitsFile {
+    #[serde(default)]
+    limits: Limits,
+}
+
+impl Limits {
+    pub fn load(path: &Path) -> Result<Self, LimitsConfigError> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| LimitsConfigError(format!("No se pudo leer {}: {}", path.display(), e)))?;
+        let file: LimitsFile = toml::from_str(&content)
+            .map_err(|e| LimitsConfigError(format!("Archivo de límites inválido {}: {}", path.display(), e)))?;
+        let limits = file.limits;
+        let caps = [
+            ("nodes_per_pool", limits.nodes_per_pool),
+            ("history_nodes", limits.history_nodes),
+            ("error_log_nodes", limits.error_log_nodes),
+            ("error_metric_series", limits.error_metric_series),
+            ("fairness_dispatches", limits.fairness_dispatches),
+            ("revision_changes", limits.revision_changes),
+            ("event_buffer", limits.event_buffer),
+            ("dns_hosts", limits.dns_hosts),
+            ("request_samples", limits.request_samples),
+            ("sticky_sessions", limits.sticky_sessions),
+            ("tombstones", limits.tombstones),
+            ("recent_requests", limits.recent_requests),
+            ("recent_events", limits.recent_events),
+            ("client_in_flight", limits.client_in_flight),
+        ];
+        if let Some((name, _)) = caps.iter().find(|(_, cap)| *cap == 0) {
+            return Err(LimitsConfigError(format!("El límite '{}' de {} debe ser mayor que 0", name, path.display())));
+        }
+        Ok(limits)
+    }
+}
+
+/// Tamaño de una estructura: entradas y bytes aproximados (sólo lo que guarda, sin la sobrecarga del allocator).
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct StoreUsage {
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct StoreReport {
+    pub name: String,
+    pub entries: usize,
+    pub cap: usize,
+    pub bytes: usize,
+}
+
+/// Topes configurados y estructuras que ya están por encima del umbral de aviso.
+pub struct BoundedStores {
+    pub limits: Limits,
+    warned: Mutex<HashSet<String>>,
+}
+
+impl BoundedStores {
+    pub fn new(limits: Limits) -> Self {
+        Self { limits, warned: Mutex::default() }
+    }
+}
+
+/// Bytes aproximados de una pool de nodos.
+fn node_usage(nodes: &std::collections::HashMap<NodeId, NodeInfo>) -> StoreUsage {
+    let bytes = nodes
+        .iter()
+        .map(|(id, info)| {
+            size_of::<(NodeId, NodeInfo)>()
+                + id.len()
+                + 2 * info.service_url.as_str().len()
+                + info.models.iter().map(|model| size_of::<String>() + model.len()).sum::<usize>()
+                + info.context_windows.keys().map(|model| size_of::<(String, u64)>() + model.len()).sum::<usize>()
+                + info.last_error.as_ref().map_or(0, String::len)
+        })
+        .sum();
+    StoreUsage { entries: nodes.len(), bytes }
+}
+
+/// Tamaño actual y tope de cada estructura acotada del balanceador.
+pub fn report(state: &AppState) -> Vec<StoreReport> {
+    let limits = &state.bounded.limits;
+    let mut stores = Vec::new();
+    let mut push = |name: String, cap: usize, usage: StoreUsage| stores.push(StoreReport { name, entries: usage.entries, cap, bytes: usage.bytes });
+    for (_, service, lock) in state.pools() {
+        push(format!("nodes.{}", service), limits.nodes_per_pool, node_usage(&lock.read().unwrap()));
+    }
+    push("node_history".to_string(), limits.history_nodes, state.node_history.read().unwrap().usage());
+    push("upstream_errors".to_string(), limits.error_log_nodes, state.upstream_errors.read().unwrap().usage());
+    push("upstream_error_series".to_string(), limits.error_metric_series, state.metrics.upstream_error_usage());
+    push("idempotency".to_string(), state.idempotency.max_entries(), state.idempotency.usage());
+    for (service, usage) in state.fairness.usage() {
+        push(format!("fairness.{}", service), limits.fairness_dispatches, usage);
+    }
+    push("revisions".to_string(), limits.revision_changes, state.revisions.usage());
+    push("events".to_string(), limits.event_buffer, state.events.usage());
+    push("dns_cache".to_string(), limits.dns_hosts, state.dns_resolver.usage());
+    push("pipeline_reservations".to_string(), limits.pipeline_reservations, state.pipeline.usage());
+    push("sticky_sessions".to_string(), limits.sticky_sessions, state.sessions.usage());
+    let version_warnings = state.version_warnings.lock().unwrap();
+    let bytes = version_warnings.iter().map(|(node, version)| size_of::<(String, String)>() + node.len() + version.len()).sum();
+    push("version_warnings".to_string(), limits.version_warnings, StoreUsage { entries: version_warnings.len(), bytes });
+    push("request_window".to_string(), limits.request_samples, state.request_window.usage());
+    push("tombstones".to_string(), limits.tombstones, state.tombstones.usage());
+    push("recent_requests".to_string(), limits.recent_requests, state.recent_requests.usage());
+    push("recent_events".to_string(), limits.recent_events, state.recent_events.usage());
+    push("client_in_flight".to_string(), limits.client_in_flight, state.client_limiter.usage());
+    stores
+}
+
+/// Gauges de `/metrics` con el tamaño, el tope y los bytes de cada estructura.
+pub fn render_metrics(state: &AppState) -> String {
+    let stores = report(state);
+    let mut out = String::new();
+    let mut gauge = |name: &str, help: &str, value: fn(&StoreReport) -> usize| {
+        let samples = stores.iter().map(|store| (format!("store=\"{}\"", escape_label(&store.name)), value(store) as u64));
+        write_labeled_metric(&mut out, name, help, "gauge", samples);
+    };
+    gauge("lmserver_store_entries", "Entradas de cada estructura en memoria.", |store| store.entries);
+    gauge("lmserver_store_capacity", "Tope de entradas de cada estructura en memoria.", |store| store.cap);
+    gauge("lmserver_store_bytes", "Bytes aproximados de cada estructura en memoria.", |store| store.bytes);
+    out
+}
+
+/// Avisa, una vez por cruce, de las estructuras que pasan del 90% de su tope.
+pub async fn watch(state: web::Data<AppState>) {
+    let mut ticker = interval(CHECK_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let stores = report(&state);
+        let mut warned = state.bounded.warned.lock().unwrap();
+        for store in stores {
+            let near_cap = store.cap > 0 && store.entries * 100 >= store.cap * WARN_PERCENT;
+            if near_cap && warned.insert(store.name.clone()) {
+                warn!(
+                    "Límites: '{}' está al {}% de su tope ({} de {} entradas, ~{} KiB). Al llenarse descartará entradas.",
+                    store.name,
+                    store.entries * 100 / store.cap,
+                    store.entries,
+                    store.cap,
+                    store.bytes / 1024
+                );
+            } else if !near_cap && warned.remove(&store.name) {
+                info!("Límites: '{}' vuelve a estar por debajo del {}% de su tope.", store.name, WARN_PERCENT);
+            }
+        }
+    }
+}
+
+/// `GET /debug/memory`: tamaño, tope y bytes aproximados de cada estructura en memoria.
+#[get("/debug/memory")]
+async fn memory_handler(state: web::Data<AppState>) -> impl Responder {
+    let stores = report(&state);
+    let total_bytes: usize = stores.iter().map(|store| store.bytes).sum();
+    HttpResponse::Ok().json(json!({ "stores": stores, "total_bytes": total_bytes, "limits": state.bounded.limits }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body, read_body_json, TestRequest};
+    use actix_web::HttpResponse;
+    use serde_json::Value;
+
+    use crate::balancer::{self, NodeHealth, RegisterError, LMSTUDIO};
+    use crate::client_limit;
+    use crate::errors::ErrorCategory;
+    use crate::events::BalancerEvent;
+    use crate::history::TransitionCause;
+    use crate::idempotency::Claim;
+    use crate::ids::ServiceUrl;
+    use crate::pipeline::{self, PipelineToken, PIPELINE_HEADER};
+    use crate::sessions::{Session, SESSION_HEADER};
+    use crate::support::{RecentEvent, RecentRequest};
+    use crate::testing;
+
+    /// Estado con estos topes en `[limits]`.
+    fn state_with_limits(limits: &str, args: &[&str]) -> web::Data<AppState> {
+        let path = testing::temp_file("limits.toml", &format!("[limits]\n{}", limits));
+        let mut args = args.to_vec();
+        args.extend(["--limits-file", path.to_str().unwrap()]);
+        testing::state(&args)
+    }
+
+    /// (entradas, tope) de la estructura `name` en el informe.
+    fn usage(state: &AppState, name: &str) -> (usize, usize) {
+        let store = report(state).into_iter().find(|store| store.name == name).unwrap_or_else(|| panic!("falta '{}' en el informe", name));
+        (store.entries, store.cap)
+    }
+
+    fn url(i: usize) -> String {
+        format!("http://127.0.0.1:{}/", 9000 + i)
+    }
+
+    #[test]
+    fn zero_and_unknown_limits_are_rejected() {
+        let caps = [
+            "nodes_per_pool", "history_nodes", "error_log_nodes", "error_metric_series", "fairness_dispatches", "revision_changes",
+            "event_buffer", "dns_hosts", "request_samples", "sticky_sessions", "tombstones", "recent_requests", "recent_events",
+            "client_in_flight",
+        ];
+        for cap in caps {
+            let err = Limits::load(&testing::temp_file("limits.toml", &format!("[limits]\n{} = 0\n", cap))).unwrap_err().to_string();
+            assert!(err.contains(&format!("'{}'", cap)), "{}", err);
+        }
+        assert!(Limits::load(&testing::temp_file("limits.toml", "[limits]\nnodos = 3\n")).is_err());
+        let limits = Limits::load(&testing::temp_file("limits.toml", "[limits]\ntombstones = 7\n")).unwrap();
+        assert_eq!((limits.tombstones, limits.nodes_per_pool), (7, Limits::default().nodes_per_pool));
+    }
+
+    /// Una pool llena sustituye al nodo caído visto hace más tiempo y, sin caídos, rechaza el nodo.
+    #[test]
+    fn full_pool_replaces_its_oldest_failed_node() {
+        let state = state_with_limits("nodes_per_pool = 2\n", &[]);
+        testing::announce(&state, "lmstudio", "box1", &url(1));
+        testing::announce(&state, "lmstudio", "box2", &url(2));
+
+        let register = |id: &str, i: usize| state.register_node(&LMSTUDIO, &testing::node_id(id), ServiceUrl::parse(url(i)).unwrap());
+        assert!(matches!(register("box3", 3), Err(RegisterError::PoolFull(2))));
+        // Un latido de un nodo que ya está no necesita sitio.
+        register("box1", 1).unwrap();
+
+        state.apply_health_check(&LMSTUDIO, &testing::node_id("box2"), false);
+        state.apply_health_check(&LMSTUDIO, &testing::node_id("box1"), false);
+        register("box3", 3).unwrap();
+        assert_eq!(testing::node_state(&state, "lmstudio", "box2"), "absent");
+        assert_eq!(testing::node_state(&state, "lmstudio", "box1"), "failed");
+        assert_eq!(usage(&state, "nodes.lmstudio"), (2, 2));
+        // La otra pool tiene su propio tope.
+        testing::announce(&state, "ollama", "box4", &url(4));
+        assert_eq!(usage(&state, "nodes.ollama"), (1, 2));
+    }
+
+    /// Cada estructura, llenada con el doble de su tope, se queda en el tope.
+    #[actix_web::test]
+    async fn every_store_evicts_instead_of_growing() {
+        let limits = [
+            "history_nodes", "error_log_nodes", "error_metric_series", "idempotency_entries", "fairness_dispatches", "revision_changes",
+            "pipeline_reservations", "sticky_sessions", "version_warnings", "request_samples", "tombstones", "recent_requests", "recent_events",
+            "client_in_flight",
+        ];
+        let state = state_with_limits(&limits.iter().map(|name| format!("{} = 3\n", name)).collect::<String>(), &["--pipeline-min-available", "0"]);
+        let ids: Vec<String> = (0..6).map(|i| format!("box{}", i)).collect();
+
+        for id in &ids {
+            state.node_history.write().unwrap().record(id, "lmstudio", "absent", "available", TransitionCause::Heartbeat);
+        }
+        assert_eq!(usage(&state, "node_history"), (3, 3));
+        assert!(state.node_history.read().unwrap().get("box0").is_none(), "se olvida el nodo con la transición más antigua");
+        assert!(state.node_history.read().unwrap().get("box5").is_some());
+
+        for id in &ids {
+            state.upstream_errors.write().unwrap().record(id, "lmstudio", ErrorCategory::ConnectRefused, "rechazada".to_string());
+            state.metrics.record_upstream_error(id, ErrorCategory::ConnectRefused.label());
+        }
+        assert_eq!(usage(&state, "upstream_errors"), (3, 3));
+        assert!(state.upstream_errors.read().unwrap().recent("box0").is_empty());
+        assert_eq!(usage(&state, "upstream_error_series"), (3, 3));
+
+        for id in &ids {
+            let Claim::Owner(owner) = state.idempotency.claim("key:sk", "lmstudio", id, b"{}") else { panic!("clave {} ya vista", id) };
+            owner.finish(HttpResponse::Ok().body("hecho")).await;
+        }
+        assert_eq!(usage(&state, "idempotency"), (3, 3));
+        assert!(matches!(state.idempotency.claim("key:sk", "lmstudio", "box0", b"{}"), Claim::Owner(_)), "la respuesta más antigua se olvidó");
+
+        // Las altas suben la revisión; las asignaciones pasan por la ventana de reparto.
+        for (i, id) in ids.iter().enumerate() {
+            testing::announce(&state, "lmstudio", id, &url(i));
+        }
+        assert_eq!(usage(&state, "revisions"), (3, 3));
+        for id in &ids {
+            let nodes = state.lm_studio_nodes.read().unwrap();
+            state.fairness.record("lmstudio", &nodes, id, false, &state.tiers, &state.domains);
+        }
+        assert_eq!(usage(&state, "fairness.lmstudio"), (3, 3));
+
+        // Cada petición con su token deja reservado su nodo hasta llenar las reservas.
+        for (i, id) in ids.iter().enumerate() {
+            state.update_node_state(&LMSTUDIO, &testing::node_id(id), NodeHealth::Busy, TransitionCause::Admin);
+            let req = TestRequest::default().insert_header((PIPELINE_HEADER, format!("t{}", i))).to_http_request();
+            let token = PipelineToken::from_request(&req, &LMSTUDIO, None).unwrap();
+            pipeline::release_node(&state, &LMSTUDIO, &testing::node_id(id), &ServiceUrl::parse(url(i)).unwrap(), Some(&token));
+        }
+        assert_eq!(usage(&state, "pipeline_reservations"), (3, 3));
+        assert_eq!(testing::node_state(&state, "lmstudio", "box5"), "available", "sin sitio, el nodo vuelve a la pool");
+
+        let sessions: Vec<Session> = (0..6)
+            .map(|i| Session::from_request(&TestRequest::default().insert_header((SESSION_HEADER, format!("s{}", i))).to_http_request(), None).unwrap())
+            .collect();
+        for (session, id) in sessions.iter().zip(&ids) {
+            state.sessions.pin(session, "lmstudio", &testing::node_id(id));
+        }
+        assert_eq!(usage(&state, "sticky_sessions"), (3, 3));
+        assert!(state.sessions.pinned(&sessions[0]).is_none(), "se olvida la sesión usada hace más tiempo");
+        assert!(state.sessions.pinned(&sessions[5]).is_some());
+
+        for (i, id) in ids.iter().enumerate() {
+            state.set_node_version(&LMSTUDIO, &testing::node_id(id), &format!("0.0.{}", i));
+        }
+        assert!(usage(&state, "version_warnings").0 <= 3);
+
+        for i in 0..6 {
+            state.request_window.record(i);
+            state.recent_requests.record(RecentRequest::start(&TestRequest::default().to_http_request(), &format!("req-{}", i), "lmstudio", b"{}"));
+            state.recent_events.record(RecentEvent::new(BalancerEvent::RulesReloaded { rules: i as usize }));
+        }
+        assert_eq!(usage(&state, "request_window"), (3, 3));
+        assert_eq!(usage(&state, "recent_requests"), (3, 3));
+        assert_eq!(state.recent_requests.list()[0].request_id, "req-5");
+        assert_eq!(usage(&state, "recent_events"), (3, 3));
+
+        // Los clientes con peticiones en curso no se olvidan: uno nuevo no entra hasta que otro termine.
+        let mut permits: Vec<_> = ids.iter().filter_map(|id| client_limit::try_acquire(state.clone(), id, 2)).collect();
+        assert_eq!(permits.len(), 3);
+        assert_eq!(usage(&state, "client_in_flight"), (3, 3));
+        assert!(client_limit::try_acquire(state.clone(), "box0", 2).is_some(), "un cliente que ya está no necesita sitio");
+        permits.remove(0);
+        assert!(client_limit::try_acquire(state.clone(), "box5", 2).is_some());
+
+        for id in &ids {
+            state.deregister_node(&LMSTUDIO, &testing::node_id(id));
+        }
+        assert_eq!(usage(&state, "tombstones"), (3, 3));
+        assert_eq!(state.tombstones.list().iter().filter(|tombstone| tombstone.node_id == "box0").count(), 0);
+    }
+
+    #[actix_web::test]
+    async fn memory_table_is_served_and_exported_as_gauges() {
+        let state = state_with_limits("nodes_per_pool = 5\ntombstones = 9\n", &["--admin-token", "secreto"]);
+        testing::announce(&state, "lmstudio", "box1", &url(1));
+        let app = init_service(balancer::app(state.clone())).await;
+
+        let resp = call_service(&app, TestRequest::get().uri("/debug/memory").to_request()).await;
+        assert_eq!(resp.status(), 401);
+
+        let req = TestRequest::get().uri("/debug/memory").insert_header(("Authorization", "Bearer secreto")).to_request();
+        let body: Value = read_body_json(call_service(&app, req).await).await;
+        let stores = body["stores"].as_array().unwrap();
+        let store = |name: &str| stores.iter().find(|store| store["name"] == name).unwrap_or_else(|| panic!("falta {}", name)).clone();
+        assert_eq!(store("nodes.lmstudio")["entries"], 1);
+        assert_eq!(store("nodes.lmstudio")["cap"], 5);
+        assert!(store("nodes.lmstudio")["bytes"].as_u64().unwrap() > 0);
+        assert_eq!(store("tombstones")["cap"], 9);
+        assert_eq!(body["total_bytes"], stores.iter().map(|store| store["bytes"].as_u64().unwrap()).sum::<u64>());
+        assert_eq!(body["limits"]["nodes_per_pool"], 5);
+
+        let metrics = read_body(call_service(&app, TestRequest::get().uri("/metrics").to_request()).await).await;
+        let metrics = String::from_utf8(metrics.to_vec()).unwrap();
+        for line in [
+            "lmserver_store_entries{store=\"nodes.lmstudio\"} 1",
+            "lmserver_store_capacity{store=\"nodes.lmstudio\"} 5",
+            "lmserver_store_capacity{store=\"tombstones\"} 9",
+        ] {
+            assert!(metrics.lines().any(|l| l == line), "falta '{}' en /metrics", line);
+        }
+        assert_eq!(metrics.lines().filter(|l| l.starts_with("lmserver_store_bytes{")).count(), stores.len());
+    }
+
+    /// Una estructura que pasa del 90% se avisa una vez y se vuelve a vigilar al bajar.
+    #[actix_web::test]
+    async fn stores_near_their_cap_are_flagged_until_they_drop() {
+        tokio::time::pause();
+        let state = state_with_limits("nodes_per_pool = 10\n", &[]);
+        let flagged = |state: &AppState| state.bounded.warned.lock().unwrap().contains("nodes.lmstudio");
+        tokio::spawn(watch(state.clone()));
+
+        for i in 0..8 {
+            testing::announce(&state, "lmstudio", &format!("box{}", i), &url(i));
+        }
+        tokio::time::sleep(CHECK_INTERVAL + Duration::from_secs(1)).await;
+        assert!(!flagged(&state), "al 80% no se avisa");
+
+        testing::announce(&state, "lmstudio", "box8", &url(8));
+        tokio::time::sleep(CHECK_INTERVAL).await;
+        assert!(flagged(&state), "al 90% se avisa");
+
+        state.deregister_node(&LMSTUDIO, &testing::node_id("box8"));
+        tokio::time::sleep(CHECK_INTERVAL).await;
+        assert!(!flagged(&state));
+    }
+}
@@ -0,0 +1,154 @@
+// src/listeners.rs
+//! Listeners UDP de descubrimiento, cada uno en su dirección y con sus pools permitidas.
+//!
+//! Con redes separadas (p.ej. una VLAN por pool) cada red anuncia en su propio puerto o
+//! interfaz y el listener sólo registra nodos en las pools que tiene permitidas; un anuncio
+//! para otra pool se descarta con un aviso. Todos los listeners alimentan el mismo registro.
+//! Los contadores de cada uno se publican en `/nodes` para diagnosticar anuncios mal dirigidos.
+use chrono::Local;
+use serde_json::json;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::ids::PoolName;
+
+pub const DEFAULT_UDP_ADDR: &str = "0.0.0.0:4000";
+
+#[derive(Debug)]
+pub struct ListenerConfigError(String);
+
+impl fmt::Display for ListenerConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ListenerConfigError {}
+
+/// Un listener de descubrimiento y sus contadores de paquetes.
+pub struct DiscoveryListener {
+    pub bind_addr: String,
+    /// Pools (o alias) en las que puede registrar nodos; `None` permite todas.
+    allowed_pools: Option<BTreeSet<PoolName>>,
+    packets: AtomicU64,
+    registered: AtomicU64,
+    rejected: AtomicU64,
+    malformed: AtomicU64,
+    /// Último anuncio rechazado por pool no permitida: (pool, origen, hora).
+    last_rejected: Mutex<Option<(String, SocketAddr, String)>>,
+}
+
+impl DiscoveryListener {
+    fn new(bind_addr: String, allowed_pools: Option<BTreeSet<PoolName>>) -> Self {
+        Self {
+            bind_addr,
+            allowed_pools,
+            packets: AtomicU64::new(0),
+            registered: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            malformed: AtomicU64::new(0),
+            last_rejected: Mutex::new(None),
+        }
+    }
+
+    /// Si el listener puede tocar la pool. Se acepta tanto el nombre anunciado como la pool a la
+    /// que resuelve, así que permitir `lmstudio` permite también sus alias.
+    pub fn allows(&self, announced: &str, canonical: &str) -> bool {
+        self.allowed_pools
+            .as_ref()
+            .is_none_or(|pools| pools.contains(announced) || pools.contains(canonical))
+    }
+
+    pub fn record_packet(&self) {
+        self.packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_registered(&self) {
+        self.registered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_malformed(&self) {
+        self.malformed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_rejected(&self, pool: &str, src_addr: SocketAddr) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+        *self.last_rejected.lock().unwrap() = Some((pool.to_string(), src_addr, Local::now().to_rfc3339()));
+    }
+
+    /// Pools permitidas, para anunciarlas por mDNS; `None` si admite todas.
+    #[cfg(feature = "mdns")]
+    pub fn allowed_pool_names(&self) -> Option<Vec<&str>> {
+        self.allowed_pools.as_ref().map(|pools| pools.iter().map(PoolName::as_str).collect())
+    }
+
+    pub fn allowed_pools_label(&self) -> String {
+        match &self.allowed_pools {
+            Some(pools) => pools.iter().map(PoolName::as_str).collect::<Vec<_>>().join(","),
+            None => "todas".to_string(),
+        }
+    }
+
+    pub fn summary(&self) -> serde_json::Value {
+        let last_rejected = self.last_rejected.lock().unwrap().as_ref().map(|(pool, from, at)| {
+            json!({ "pool": pool, "from": from.to_string(), "at": at })
+        });
+        json!({
+            "bind_addr": self.bind_addr,
+            "allowed_pools": self.allowed_pools,
+            "packets": self.packets.load(Ordering::Relaxed),
+            "registered": self.registered.load(Ordering::Relaxed),
+            "rejected_pool": self.rejected.load(Ordering::Relaxed),
+            "malformed": self.malformed.load(Ordering::Relaxed),
+            "last_rejected": last_rejected,
+        })
+    }
+}
+
+/// Construye los listeners a partir de `-u` y de las entradas `--udp-listener`
+/// (`<dirección>[=<pool>,<pool>...]`). Sin ninguno, escucha en `DEFAULT_UDP_ADDR` para todas las pools.
+pub fn build(
+    udp_addr: Option<&str>,
+    entries: &[String],
+    is_pool: impl Fn(&str) -> bool,
+) -> Result<Vec<Arc<DiscoveryListener>>, ListenerConfigError> {
+    let mut listeners = Vec::new();
+    if let Some(udp_addr) = udp_addr {
+        listeners.push(DiscoveryListener::new(udp_addr.to_string(), None));
+    }
+    for entry in entries {
+        let (bind_addr, pools) = match entry.split_once('=') {
+            Some((bind_addr, pools)) => (bind_addr.trim(), Some(pools)),
+            None => (entry.trim(), None),
+        };
+        if bind_addr.parse::<SocketAddr>().is_err() {
+            return Err(ListenerConfigError(format!("Listener UDP inválido '{}': se esperaba <ip:puerto>[=<pool>,...]", entry)));
+        }
+        let allowed_pools = match pools {
+            Some(pools) => {
+                let mut allowed = BTreeSet::new();
+                for pool in pools.split(',').map(str::trim) {
+                    let pool = PoolName::new(pool).map_err(|e| ListenerConfigError(format!("Listener UDP '{}': {}", entry, e)))?;
+                    if !is_pool(&pool) {
+                        return Err(ListenerConfigError(format!("Listener UDP '{}': pool desconocida '{}'", entry, pool)));
+                    }
+                    allowed.insert(pool);
+                }
+                Some(allowed)
+            }
+            None => None,
+        };
+        listeners.push(DiscoveryListener::new(bind_addr.to_string(), allowed_pools));
+    }
+    if listeners.is_empty() {
+        listeners.push(DiscoveryListener::new(DEFAULT_UDP_ADDR.to_string(), None));
+    }
+    let mut seen = BTreeSet::new();
+    if let Some(listener) = listeners.iter().find(|listener| !seen.insert(listener.bind_addr.clone())) {
+        return Err(ListenerConfigError(format!("Listener UDP en {} repetido", listener.bind_addr)));
+    }
+    Ok(listeners.into_iter().map(Arc::new).collect())
+}
@@ -0,0 +1,268 @@
+// src/loader.rs
+//! Carga coordinada de modelos para liberar VRAM (opcional, desactivada por defecto).
+//!
+//! Si llega una petición para un modelo de la lista permitida que no está residente en ningún
+//! nodo libre, se elige uno que lo tenga instalado, se descargan los modelos que tiene en
+//! memoria y se carga el pedido. Mientras tanto el nodo queda en estado `Loading` y la
+//! petición espera, como mucho, el timeout configurado. Cada decisión se publica en el bus
+//! de eventos (log y `GET /events`).
+//!
+//! Sólo Ollama permite hacerlo por API (`/api/ps`, y `/api/generate` con `keep_alive`). Los
+//! nodos no anuncian su VRAM, así que se liberan todos los modelos residentes del nodo elegido.
+use futures_util::future::join_all;
+use log::warn;
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+
+use crate::balancer::{AppState, NodeHealth};
+use crate::events::BalancerEvent;
+use crate::history::TransitionCause;
+use crate::ids::{NodeId, PoolName, ServiceUrl};
+
+/// Timeout por defecto para descargar y cargar modelos en un nodo.
+pub const DEFAULT_MODEL_LOAD_TIMEOUT_SECS: u64 = 120;
+
+const PS_TIMEOUT: Duration = Duration::from_secs(3);
+
+pub struct ModelLoader {
+    /// Modelos que se pueden cargar automáticamente. Vacía: coordinador desactivado.
+    allowed: BTreeSet<String>,
+    timeout: Duration,
+}
+
+impl ModelLoader {
+    pub fn new(allowed: &[String], timeout: Duration) -> Self {
+        Self { allowed: allowed.iter().cloned().collect(), timeout }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.allowed.is_empty()
+    }
+
+    /// Lo más que puede durar una carga; pasado, un nodo en Loading ya no la está haciendo.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+/// Modelos residentes en memoria de un nodo Ollama.
+async fn resident_models(client: &reqwest::Client, service_url: &ServiceUrl) -> Option<Vec<String>> {
+    let json: Value = client.get(service_url.endpoint("/api/ps")).timeout(PS_TIMEOUT).send().await.ok()?.json().await.ok()?;
+    let models = json.get("models")?.as_array()?;
+    Some(models.iter().filter_map(|m| m.get("name").and_then(Value::as_str)).map(str::to_string).collect())
+}
+
+/// `keep_alive: 0` descarga el modelo; cualquier otro valor lo carga y lo mantiene.
+async fn set_keep_alive(client: &reqwest::Client, service_url: &ServiceUrl, model: &str, keep_alive: Value, timeout: Duration) -> Result<(), String> {
+    let response = client
+        .post(service_url.endpoint("/api/generate"))
+        .timeout(timeout)
+        .json(&json!({ "model": model, "keep_alive": keep_alive }))
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("el nodo respondió {}", response.status()))
+    }
+}
+
+/// Mantiene un nodo en Loading mientras dura la carga. Si el futuro de la petición se suelta a
+/// mitad (el cliente cerró la conexión), el nodo vuelve a Available en vez de quedarse en Loading.
+struct LoadingGuard<'a> {
+    state: &'a AppState,
+    service: &'a PoolName,
+    unique_node_id: &'a NodeId,
+    model: &'a str,
+    started: Instant,
+    armed: bool,
+}
+
+impl LoadingGuard<'_> {
+    /// Termina la carga dejando el nodo en `new_health`.
+    fn release(mut self, new_health: NodeHealth) {
+        self.armed = false;
+        self.state.update_node_state(self.service, self.unique_node_id, new_health, TransitionCause::ModelLoad);
+    }
+}
+
+impl Drop for LoadingGuard<'_> {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+        warn!("Loader: Se canceló la carga de {} en el nodo ID {}; vuelve a Available.", self.model, self.unique_node_id);
+        self.state.events.publish(BalancerEvent::ModelLoadFailed {
+            service: self.service.to_string(),
+            node_id: self.unique_node_id.to_string(),
+            model: self.model.to_string(),
+            error: "petición cancelada".to_string(),
+            elapsed_ms: self.started.elapsed().as_millis() as u64,
+        });
+        let Some(nodes_lock) = self.state.pool(self.service) else {
+            return;
+        };
+        let mut nodes = nodes_lock.write().unwrap();
+        if nodes.get(self.unique_node_id.as_str()).is_some_and(|info| matches!(info.state, NodeHealth::Loading)) {
+            self.state.set_node_state(self.service, &mut nodes, self.unique_node_id, NodeHealth::Available, TransitionCause::ModelLoad);
+        }
+    }
+}
+
+/// Si hace falta, prepara un nodo con el modelo pedido y lo devuelve ya ocupado (`Busy`).
+/// `Ok(None)` significa que no interviene y la petición sigue el enrutado normal.
+pub async fn ensure_model(state: &AppState, service: &PoolName, model: Option<&str>) -> Result<Option<(NodeId, ServiceUrl)>, String> {
+    let loader = &state.model_loader;
+    let Some(model) = model.filter(|m| service == "ollama" && loader.allowed.contains(*m)) else {
+        return Ok(None);
+    };
+    let Some(nodes_lock) = state.pool(service) else {
+        return Ok(None);
+    };
+
+    // Candidatos: nodos libres que anuncian el modelo entre los instalados.
+    let candidates: Vec<(NodeId, ServiceUrl)> = nodes_lock
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|(_, info)| matches!(info.state, NodeHealth::Available) && info.models.iter().any(|m| m == model))
+        .map(|(id, info)| (id.clone(), info.service_url.clone()))
+        .collect();
+    if candidates.is_empty() {
+        return Ok(None);
+    }
+    let resident = join_all(candidates.iter().map(|(_, url)| resident_models(&state.client, url))).await;
+    if resident.iter().flatten().any(|models| models.iter().any(|m| m == model)) {
+        return Ok(None);
+    }
+    // Se prefiere el nodo con menos modelos que descargar.
+    let Some(((unique_node_id, service_url), to_unload)) = candidates
+        .into_iter()
+        .zip(resident)
+        .filter_map(|(node, models)| models.map(|models| (node, models)))
+        .min_by_key(|(_, models)| models.len())
+    else {
+        return Ok(None);
+    };
+
+    // El nodo pasa a Loading sólo si sigue libre; si otra petición lo ocupó, no se interviene.
+    {
+        let mut nodes = nodes_lock.write().unwrap();
+        if !nodes.get(unique_node_id.as_str()).is_some_and(|info| matches!(info.state, NodeHealth::Available)) {
+            return Ok(None);
+        }
+        state.set_node_state(service, &mut nodes, &unique_node_id, NodeHealth::Loading, TransitionCause::ModelLoad);
+    }
+    state.events.publish(BalancerEvent::ModelLoadDecision {
+        service: service.to_string(),
+        node_id: unique_node_id.to_string(),
+        model: model.to_string(),
+        unload: to_unload.clone(),
+        reason: "ningún nodo libre tiene el modelo residente".to_string(),
+    });
+
+    let started = Instant::now();
+    let guard = LoadingGuard { state, service, unique_node_id: &unique_node_id, model, started, armed: true };
+    let deadline = started + loader.timeout;
+    let result = async {
+        for resident in &to_unload {
+            set_keep_alive(&state.client, &service_url, resident, json!(0), deadline.saturating_duration_since(Instant::now()))
+                .await
+                .map_err(|e| format!("no se pudo descargar {}: {}", resident, e))?;
+            state.events.publish(BalancerEvent::ModelUnloaded {
+                service: service.to_string(),
+                node_id: unique_node_id.to_string(),
+                model: resident.clone(),
+            });
+        }
+        set_keep_alive(&state.client, &service_url, model, json!("5m"), deadline.saturating_duration_since(Instant::now()))
+            .await
+            .map_err(|e| format!("no se pudo cargar {}: {}", model, e))
+    }
+    .await;
+
+    let elapsed_ms = started.elapsed().as_millis() as u64;
+    match result {
+        Ok(()) => {
+            state.events.publish(BalancerEvent::ModelLoaded {
+                service: service.to_string(),
+                node_id: unique_node_id.to_string(),
+                model: model.to_string(),
+                elapsed_ms,
+            });
+            guard.release(NodeHealth::Busy);
+            Ok(Some((unique_node_id, service_url)))
+        }
+        Err(e) => {
+            warn!("Loader: Falló la carga de {} en el nodo ID {}: {}", model, unique_node_id, e);
+            state.events.publish(BalancerEvent::ModelLoadFailed {
+                service: service.to_string(),
+                node_id: unique_node_id.to_string(),
+                model: model.to_string(),
+                error: e.clone(),
+                elapsed_ms,
+            });
+            guard.release(NodeHealth::Available);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, HttpResponse};
+
+    use crate::balancer::{LMSTUDIO, OLLAMA};
+    use crate::testing;
+
+    #[actix_web::test]
+    async fn cancelled_load_leaves_the_node_available() {
+        let backend = testing::backend(|cfg| {
+            cfg.route("/api/ps", web::get().to(|| async { HttpResponse::Ok().json(json!({ "models": [{ "name": "small" }] })) }))
+                .route(
+                    "/api/generate",
+                    web::post().to(|| async {
+                        tokio::time::sleep(Duration::from_secs(10)).await;
+                        HttpResponse::Ok().json(json!({ "done": true }))
+                    }),
+                );
+        });
+        let state = testing::state(&["--auto-load-model", "big"]);
+        testing::announce(&state, "ollama", "box1", &backend);
+        state.set_node_models(&OLLAMA, &testing::node_id("box1"), vec!["big".to_string()]);
+
+        let load = ensure_model(&state, &OLLAMA, Some("big"));
+        let cancelled = tokio::time::timeout(Duration::from_millis(300), load).await;
+
+        assert!(cancelled.is_err());
+        assert_eq!(testing::node_state(&state, "ollama", "box1"), "available");
+    }
+
+    #[actix_web::test]
+    async fn failed_load_leaves_the_node_available() {
+        let backend = testing::backend(|cfg| {
+            cfg.route("/api/ps", web::get().to(|| async { HttpResponse::Ok().json(json!({ "models": [] })) }))
+                .route("/api/generate", web::post().to(HttpResponse::InternalServerError));
+        });
+        let state = testing::state(&["--auto-load-model", "big"]);
+        testing::announce(&state, "ollama", "box1", &backend);
+        state.set_node_models(&OLLAMA, &testing::node_id("box1"), vec!["big".to_string()]);
+
+        assert!(ensure_model(&state, &OLLAMA, Some("big")).await.is_err());
+        assert_eq!(testing::node_state(&state, "ollama", "box1"), "available");
+    }
+
+    #[actix_web::test]
+    async fn models_outside_the_allowlist_are_left_alone() {
+        let state = testing::state(&["--auto-load-model", "big"]);
+        testing::announce(&state, "ollama", "box1", &testing::unreachable_url());
+        state.set_node_models(&OLLAMA, &testing::node_id("box1"), vec!["other".to_string()]);
+
+        assert_eq!(ensure_model(&state, &OLLAMA, Some("other")).await, Ok(None));
+        assert_eq!(ensure_model(&state, &LMSTUDIO, Some("big")).await, Ok(None));
+        assert_eq!(testing::node_state(&state, "ollama", "box1"), "available");
+    }
+}
@@ -0,0 +1,117 @@
+// src/locality.rs
+//! Preferencia por los nodos de la red local (`--local-prefix 192.168.1.0/24`).
+//!
+//! Con nodos en la LAN y otros tras un túnel (WireGuard), cada ida y vuelta al remoto encarece
+//! mucho los streams. Un nodo es local si su IP está en alguno de los prefijos configurados: la
+//! IP de origen de su último anuncio (UDP o latido gRPC) o, si nunca se ha anunciado, la del host
+//! de su URL. Los locales se prueban antes que el resto; uno remoto sólo atiende cuando no queda
+//! ningún local libre que pueda hacerlo. La preferencia cede ante los niveles (`--node-tier`) y
+//! las preferencias de las reglas, que mandan sobre ella.
+//!
+//! `/nodes` y la UI marcan cada nodo con `local`. Sin prefijos no hay nodos locales ni remotos y
+//! el orden no cambia.
+use serde_json::{json, Value};
+use std::fmt;
+use std::net::IpAddr;
+use url::Host;
+
+use crate::balancer::NodeInfo;
+
+#[derive(Debug)]
+pub struct LocalityConfigError(String);
+
+impl fmt::Display for LocalityConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for LocalityConfigError {}
+
+/// Prefijo de red `dirección/longitud`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Subnet {
+    network: IpAddr,
+    len: u8,
+}
+
+impl Subnet {
+    /// Interpreta `192.168.1.0/24` o `fd00::/8`; una IP sin longitud es sólo esa dirección.
+    pub fn parse(raw: &str) -> Result<Self, LocalityConfigError> {
+        let invalid = |reason: &str| LocalityConfigError(format!("Prefijo inválido '{}': {}", raw, reason));
+        let (address, len) = match raw.trim().split_once('/') {
+            Some((address, len)) => (address, Some(len)),
+            None => (raw.trim(), None),
+        };
+        let network: IpAddr = address.parse().map_err(|_| invalid("no es una IP"))?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let len = match len {
+            Some(len) => len.parse::<u8>().ok().filter(|len| *len <= max).ok_or_else(|| invalid(&format!("la longitud debe ir de 0 a {}", max)))?,
+            None => max,
+        };
+        Ok(Self { network: mask(network, len), len })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        ip.is_ipv4() == self.network.is_ipv4() && mask(ip, self.len) == self.network
+    }
+}
+
+impl fmt::Display for Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.len)
+    }
+}
+
+fn mask(ip: IpAddr, len: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) => {
+            let bits = u32::from(v4) & u32::MAX.checked_shl(32 - u32::from(len)).unwrap_or(0);
+            IpAddr::V4(bits.into())
+        }
+        IpAddr::V6(v6) => {
+            let bits = u128::from(v6) & u128::MAX.checked_shl(128 - u32::from(len)).unwrap_or(0);
+            IpAddr::V6(bits.into())
+        }
+    }
+}
+
+/// Prefijos de la red local (`--local-prefix`).
+#[derive(Default)]
+pub struct Locality {
+    prefixes: Vec<Subnet>,
+}
+
+impl Locality {
+    pub fn new(entries: &[String]) -> Result<Self, LocalityConfigError> {
+        let prefixes = entries.iter().map(|entry| Subnet::parse(entry)).collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { prefixes })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.prefixes.is_empty()
+    }
+
+    /// IP por la que se juzga al nodo: la de origen de su último anuncio o la de su URL.
+    pub fn node_ip(info: &NodeInfo) -> Option<IpAddr> {
+        info.source_ip.or_else(|| match info.service_url.host()? {
+            Host::Ipv4(ip) => Some(IpAddr::V4(ip)),
+            Host::Ipv6(ip) => Some(IpAddr::V6(ip)),
+            Host::Domain(_) => None,
+        })
+    }
+
+    /// Si el nodo está en la red local. Sin prefijos, ninguno lo está.
+    pub fn is_local(&self, info: &NodeInfo) -> bool {
+        Self::node_ip(info).is_some_and(|ip| self.prefixes.iter().any(|prefix| prefix.contains(ip)))
+    }
+
+    /// Configuración para `/config`.
+    pub fn describe(&self) -> Value {
+        json!({
+            "enabled": self.is_enabled(),
+            "prefixes": self.prefixes.iter().map(Subnet::to_string).collect::<Vec<_>>(),
+        })
+    }
+}
@@ -1,14 +1,109 @@
 // main.rs
-use clap::Parser;
-use std::io; 
+use clap::{CommandFactory, Parser};
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use log::{info, LevelFilter}; 
 use fern::colors::{Color, ColoredLevelConfig};
+use url::Url;
 
+mod aliases;
+mod api_types;
+mod audit;
+mod auth;
 mod balancer;
+mod benchmark;
+mod build_info;
+mod canary;
+mod cancel;
+mod capacity;
+mod client_limit;
+mod clock;
+mod config;
+mod context;
+mod diagnose;
+mod direct;
+mod discovery;
+mod domains;
+mod drain;
+mod dispatch_rate;
+mod dns;
+mod errors;
+mod events;
+mod fairness;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod headers;
+mod health;
+mod history;
+mod ids;
+mod idempotency;
+mod index;
+mod interleave;
+mod keys;
+mod labels;
+mod latency;
+mod limits;
+mod locality;
+mod listeners;
+mod loader;
+mod metrics;
+mod models;
+#[cfg(feature = "mdns")]
+mod mdns;
 mod node;
+mod ollama;
+mod openapi;
+mod persistence;
+mod pins;
+mod pipeline;
+mod platform;
+mod postprocess;
+mod preview;
+mod profiles;
+mod queues;
+mod reconcile;
+mod reload;
+mod reprobe;
+mod retry;
+mod revisions;
+mod round_robin;
+mod rpm;
+mod rules;
+mod selection;
+mod sessions;
+mod slow_lane;
+mod spill;
+mod spool;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod stats;
+mod status;
+mod strictness;
+mod storage;
+mod streaming;
+mod support;
+mod tags;
+mod target;
+mod tasks;
+#[cfg(test)]
+mod testing;
+mod tiers;
+#[cfg(feature = "tls")]
+mod tls;
+mod tombstones;
+mod tools;
+mod ui;
+mod usage;
+mod user_affinity;
+mod validation;
+mod warm;
+mod warmup;
+mod workload;
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, long_version = build_info::LONG_VERSION, about, long_about = None)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
@@ -23,19 +118,143 @@ struct Cli {
 #[derive(clap::Subcommand, Debug)]
 enum Commands {
     #[command(about = "Inicia el balanceador de cargas.")]
-    Balancer {
-        #[arg(short, long, default_value = "0.0.0.0:8080", help = "Dirección IP y puerto donde escuchará el balanceador.")]
-        listen_addr: String,
-        #[arg(short, long, default_value = "0.0.0.0:4000", help = "Dirección IP y puerto para escuchar los anuncios UDP de los nodos.")]
-        udp_addr: String,
-    },
+    Balancer(Box<config::BalancerConfig>),
     #[command(about = "Inicia un nodo que anuncia sus servicios al balanceador.")]
-    Node {
-        #[arg(short = 'i', long, help = "Dirección IP del balanceador para enviar anuncios UDP.")]
-        balancer_ip: String,
-        #[arg(short = 'p', long, default_value_t = 4000, help = "Puerto UDP del balanceador.")]
-        balancer_port: u16,
+    Node(Box<NodeArgs>),
+    #[command(about = "Genera el script de autocompletado para la shell indicada.")]
+    Completions {
+        #[arg(value_enum, help = "Shell de destino.")]
+        shell: clap_complete::Shell,
+        #[arg(long, value_name = "DIR", help = "Directorio donde escribir el script en lugar de stdout.")]
+        out_dir: Option<PathBuf>,
+    },
+    #[command(about = "Escribe el documento OpenAPI de la API del balanceador tras comprobar que cubre todas las rutas.")]
+    Openapi {
+        #[arg(long, help = "Sólo comprueba el documento (rutas cubiertas y ejemplos válidos); sale con error si falla. Pensado para CI.")]
+        check: bool,
+        #[arg(long, value_name = "FILE", help = "Archivo donde escribir el documento en lugar de stdout.")]
+        out: Option<PathBuf>,
     },
+    #[command(about = "Genera las páginas man (roff) de todos los subcomandos.")]
+    Man {
+        #[arg(long, value_name = "DIR", help = "Directorio donde escribir una página por subcomando en lugar de stdout.")]
+        out_dir: Option<PathBuf>,
+    },
+}
+
+/// Argumentos de `load_balancer node`.
+#[derive(clap::Args, Debug)]
+struct NodeArgs {
+    #[arg(short = 'i', long, help = "Dirección IP del balanceador para enviar anuncios UDP. Con la feature mdns puede omitirse: entonces el nodo busca balanceadores por mDNS (_lmserver-lb._udp) y se anuncia a todos los que encuentra.")]
+    #[cfg_attr(not(feature = "mdns"), arg(required = true))]
+    balancer_ip: Option<String>,
+    #[arg(short = 'p', long, default_value_t = 4000, help = "Puerto UDP del balanceador.")]
+    balancer_port: u16,
+    #[arg(long, default_value_t = discovery::DEFAULT_MAX_DATAGRAM_BYTES, help = "Tamaño máximo en bytes de cada datagrama de anuncio. Las listas de modelos más largas se reparten en varios.")]
+    max_datagram_bytes: usize,
+    #[arg(long, default_value_t = node::DEFAULT_UNACKED_THRESHOLD, help = "Anuncios seguidos sin ACK tras los que se considera que el balanceador no responde y se espacian los anuncios.")]
+    unacked_threshold: u32,
+    #[arg(long, value_name = "SECONDS", default_value_t = node::DEFAULT_MAX_ANNOUNCE_INTERVAL_SECS, help = "Intervalo máximo entre anuncios mientras el balanceador no responde.")]
+    max_announce_interval: u64,
+    #[arg(long, value_name = "PATH", help = "Directorio de modelos del backend. Se anuncia al balanceador el espacio libre de su volumen y lo que ocupan los modelos guardados.")]
+    models_path: Option<PathBuf>,
+    #[arg(long, value_enum, default_value_t = tools::ToolCallingMode::Auto, help = "Si se anuncia la capacidad tool-calling: 'auto' la prueba contra el backend al arrancar con una petición mínima, 'on' y 'off' la fijan.")]
+    tool_calling: tools::ToolCallingMode,
+    #[arg(long, value_name = "N", help = "Peso del nodo frente a los demás de su pool, p.ej. 4 para una GPU grande y 1 para un portátil sin GPU. Sólo cuenta si el balanceador usa --node-selection weighted; sin él, el nodo pesa 1.")]
+    weight: Option<u32>,
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..), help = "Tope de peticiones por minuto que acepta el nodo, responda lo rápido que responda. Al alcanzarlo, el balanceador deja de darle peticiones hasta que la ventana del último minuto baje del tope; --node-max-rpm en el balanceador puede fijar uno menor.")]
+    max_rpm: Option<u32>,
+    #[arg(long, value_name = "DOMAIN", value_parser = parse_failure_domain, help = "Dominio de fallo del nodo: los nodos que caen juntos (mismo rack, PDU o switch) comparten dominio, p.ej. 'rack-a'. El balanceador repite las peticiones en nodos de otro dominio y avisa cuando una pool queda en uno solo.")]
+    failure_domain: Option<String>,
+    #[arg(long, value_name = "TOKENS", help = "Ventana de contexto del nodo en tokens, para los modelos cuyo backend no la informa (p.ej. limitada por la memoria de la máquina). El balanceador no le manda peticiones que no quepan en ella.")]
+    max_context: Option<u64>,
+    #[arg(long = "tag", value_name = "KEY=VALUE", value_parser = tags::parse_tag, help = "Etiqueta del nodo, p.ej. 'quantized=q8' o 'region=lab2' (repetible). Las peticiones con X-Require-Tags sólo van a nodos con todas las etiquetas que piden.")]
+    tags: Vec<(String, String)>,
+    #[arg(long, value_name = "SECONDS", default_value_t = node::DEFAULT_DRAIN_LEAD_SECS, value_parser = clap::value_parser!(u64).range(..=discovery::MAX_DRAIN_LEAD_SECS), help = "Antelación con la que se anuncia el apagado al recibir SIGUSR2 (como mucho un día). El balanceador deja de dar peticiones nuevas al nodo y lo saca al cumplirse el plazo.")]
+    drain_lead: u64,
+    #[arg(long, value_name = "ADDR", help = "Sirve un proxy de inferencia (/lmstudio, /ollama) en ADDR para clientes que usan el nodo como respaldo. Pasa las peticiones al balanceador y, mientras éste no responde, las atiende contra el backend local con X-LMServER-Degraded: local.")]
+    spool_listen: Option<SocketAddr>,
+    #[arg(long, value_name = "URL", requires = "spool_listen", help = "URL HTTP del balanceador para el proxy de --spool-listen. Por defecto, http://<balancer-ip>:8080/.")]
+    spool_balancer_url: Option<Url>,
+    #[arg(long, value_name = "N", requires = "spool_listen", default_value_t = spool::DEFAULT_SPOOL_MAX_CONCURRENT, help = "Máximo de peticiones que el proxy de --spool-listen atiende a la vez en local con el balanceador caído; el resto recibe un 503.")]
+    spool_max_concurrent: usize,
+}
+
+fn write_completions(shell: clap_complete::Shell, out_dir: Option<&Path>) -> io::Result<()> {
+    match out_dir {
+        Some(dir) => {
+            let mut cmd = Cli::command();
+            let bin_name = cmd.get_name().to_string();
+            let path = clap_complete::generate_to(shell, &mut cmd, bin_name, dir)?;
+            eprintln!("Script de autocompletado escrito en {}", path.display());
+        }
+        None => render_completions(shell, &mut io::stdout()),
+    }
+    Ok(())
+}
+
+fn render_completions(shell: clap_complete::Shell, out: &mut dyn io::Write) {
+    let mut cmd = Cli::command();
+    let bin_name = cmd.get_name().to_string();
+    clap_complete::generate(shell, &mut cmd, bin_name, out);
+}
+
+fn write_man_pages(out_dir: Option<&Path>) -> io::Result<()> {
+    match out_dir {
+        Some(dir) => {
+            clap_mangen::generate_to(Cli::command(), dir)?;
+            eprintln!("Páginas man escritas en {}", dir.display());
+            Ok(())
+        }
+        None => render_man_pages(&mut io::stdout().lock()),
+    }
+}
+
+/// Todas las páginas man seguidas, la del comando principal primero.
+fn render_man_pages(out: &mut dyn io::Write) -> io::Result<()> {
+    fn render(cmd: clap::Command, out: &mut dyn io::Write) -> io::Result<()> {
+        clap_mangen::Man::new(cmd.clone()).render(out)?;
+        for sub in cmd.get_subcommands().filter(|s| !s.is_hide_set()).cloned() {
+            render(sub, out)?;
+        }
+        Ok(())
+    }
+    let mut cmd = Cli::command().disable_help_subcommand(true);
+    cmd.build();
+    render(cmd, out)
+}
+
+fn write_openapi(check_only: bool, out: Option<&Path>) -> io::Result<()> {
+    openapi::check().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if check_only {
+        eprintln!("Documento OpenAPI correcto.");
+        return Ok(());
+    }
+    let document = serde_json::to_string_pretty(openapi::document()).map_err(io::Error::other)?;
+    match out {
+        Some(path) => {
+            std::fs::write(path, document + "\n")?;
+            eprintln!("Documento OpenAPI escrito en {}", path.display());
+        }
+        None => println!("{}", document),
+    }
+    Ok(())
+}
+
+/// URL HTTP del balanceador en su puerto por defecto, para `--spool-listen` sin `--spool-balancer-url`.
+fn default_balancer_url(balancer_ip: &str) -> io::Result<Url> {
+    let host = if balancer_ip.contains(':') { format!("[{}]", balancer_ip) } else { balancer_ip.to_string() };
+    Url::parse(&format!("http://{}:8080/", host))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("No se puede formar la URL del balanceador con '{}': {}. Indica --spool-balancer-url.", balancer_ip, e)))
+}
+
+/// Valor de `--failure-domain`: debe caber en un campo del datagrama `DOMAIN`.
+fn parse_failure_domain(value: &str) -> Result<String, String> {
+    if domains::is_valid_label(value) {
+        Ok(value.to_string())
+    } else {
+        Err("el dominio no puede estar vacío ni llevar comas o espacios".to_string())
+    }
 }
 
 fn setup_logging(level: LevelFilter, log_file: &str) -> Result<(), fern::InitError> {
@@ -46,7 +265,7 @@ fn setup_logging(level: LevelFilter, log_file: &str) -> Result<(), fern::InitErr
         .debug(Color::Blue)
         .trace(Color::BrightBlack);
 
-    let colors_level = colors_line.clone().info(Color::Green);
+    let colors_level = colors_line.info(Color::Green);
 
     let base_config = fern::Dispatch::new()
         .format(move |out, message, record| {
@@ -91,22 +310,148 @@ fn setup_logging(level: LevelFilter, log_file: &str) -> Result<(), fern::InitErr
 async fn main() -> io::Result<()> {
     let cli = Cli::parse();
 
+    // Estos subcomandos escriben en stdout: se resuelven antes de inicializar el logger.
+    match &cli.command {
+        Commands::Completions { shell, out_dir } => return write_completions(*shell, out_dir.as_deref()),
+        Commands::Man { out_dir } => return write_man_pages(out_dir.as_deref()),
+        Commands::Openapi { check, out } => return write_openapi(*check, out.as_deref()),
+        _ => {}
+    }
+
     if let Err(e) = setup_logging(cli.log_level, &cli.log_file) {
         eprintln!("Error inicializando el logger: {}", e);
     }
 
     info!("Logging inicializado. Nivel: {}, Archivo: {}", cli.log_level, cli.log_file);
+    info!(
+        "lmServer {} (commit {}, compilado {}, features: {})",
+        build_info::VERSION,
+        build_info::GIT_HASH,
+        build_info::BUILD_TIMESTAMP,
+        build_info::FEATURES
+    );
 
     match cli.command {
-        Commands::Balancer { listen_addr, udp_addr } => {
+        Commands::Balancer(config) => {
             info!("Iniciando en modo Balanceador...");
-            balancer::run_balancer(&listen_addr, &udp_addr).await?;
+            balancer::run_balancer(*config).await?;
         }
-        Commands::Node { balancer_ip, balancer_port } => {
+        Commands::Node(args) => {
+            let NodeArgs {
+                balancer_ip,
+                balancer_port,
+                max_datagram_bytes,
+                unacked_threshold,
+                max_announce_interval,
+                models_path,
+                tool_calling,
+                weight,
+                max_rpm,
+                failure_domain,
+                max_context,
+                tags,
+                drain_lead,
+                spool_listen,
+                spool_balancer_url,
+                spool_max_concurrent,
+            } = *args;
             info!("Iniciando en modo Nodo...");
-            node::run_node(&balancer_ip, balancer_port).await?;
+            let backoff = node::AnnounceBackoff {
+                unacked_threshold: unacked_threshold.max(1),
+                max_interval: Duration::from_secs(max_announce_interval).max(node::ANNOUNCE_INTERVAL),
+            };
+            let spool = match spool_listen {
+                Some(listen) => Some(spool::SpoolOptions {
+                    listen,
+                    balancer_url: match (spool_balancer_url, &balancer_ip) {
+                        (Some(url), _) => url,
+                        (None, Some(balancer_ip)) => default_balancer_url(balancer_ip)?,
+                        (None, None) => {
+                            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Sin -i, --spool-listen necesita --spool-balancer-url."));
+                        }
+                    },
+                    max_concurrent: spool_max_concurrent,
+                }),
+                None => None,
+            };
+            let options = node::NodeOptions {
+                max_datagram_bytes,
+                backoff,
+                models_path,
+                tool_calling,
+                weight: weight.map(|weight| weight.max(1)),
+                max_rpm,
+                failure_domain,
+                max_context: max_context.filter(|tokens| *tokens > 0),
+                tags: tags.into_iter().collect(),
+                drain_lead: Duration::from_secs(drain_lead),
+                spool,
+            };
+            let balancers = match balancer_ip {
+                Some(balancer_ip) => node::Balancers::Fixed(format!("{}:{}", balancer_ip, balancer_port)),
+                #[cfg(feature = "mdns")]
+                None => node::Balancers::Discovered(
+                    mdns::browse_balancers().ok_or_else(|| io::Error::other("No se pudo buscar balanceadores por mDNS; indica -i."))?,
+                ),
+                #[cfg(not(feature = "mdns"))]
+                None => unreachable!("sin la feature mdns, -i es obligatorio"),
+            };
+            node::run_node(balancers, options).await?;
         }
+        Commands::Completions { .. } | Commands::Man { .. } | Commands::Openapi { .. } => unreachable!(),
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::ValueEnum;
+
+    use crate::testing;
+
+    /// Opciones que tienen que salir en la salida generada: del balanceador, del nodo y globales.
+    const KEY_FLAGS: [&str; 5] = ["listen-addr", "udp-listener", "balancer-ip", "tool-calling", "log-level"];
+
+    #[test]
+    fn cli_is_consistent() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn completions_cover_every_shell_and_key_flags() {
+        for shell in clap_complete::Shell::value_variants() {
+            let mut out = Vec::new();
+            render_completions(*shell, &mut out);
+            let script = String::from_utf8(out).unwrap();
+            for flag in KEY_FLAGS.iter().chain(&["balancer", "node", "completions", "openapi", "man"]) {
+                assert!(script.contains(flag), "{} no aparece en el autocompletado de {}", flag, shell);
+            }
+        }
+    }
+
+    #[test]
+    fn man_pages_cover_every_subcommand() {
+        let mut out = Vec::new();
+        render_man_pages(&mut out).unwrap();
+        let pages = String::from_utf8(out).unwrap();
+
+        for title in ["load_balancer", "load_balancer-balancer", "load_balancer-node", "load_balancer-completions", "load_balancer-man"] {
+            assert!(pages.contains(&format!(".TH {} ", title)), "falta la página de {}", title);
+        }
+        for flag in KEY_FLAGS {
+            assert!(pages.contains(&flag.replace('-', "\\-")), "{} no aparece en las páginas man", flag);
+        }
+    }
+
+    #[test]
+    fn out_dir_gets_one_file_per_page() {
+        let dir = testing::temp_dir();
+        write_man_pages(Some(&dir)).unwrap();
+        write_completions(clap_complete::Shell::Bash, Some(&dir)).unwrap();
+
+        for page in ["load_balancer.1", "load_balancer-balancer.1", "load_balancer-node.1", "load_balancer.bash"] {
+            assert!(dir.join(page).is_file(), "falta {}", page);
+        }
+    }
+}
@@ -0,0 +1,381 @@
+// src/mdns.rs
+use actix_web::web;
+use log::{debug, error, info, warn};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::interval;
+use url::Url;
+
+use crate::balancer::{effective_service_url, AppState};
+use crate::ids::{NodeId, PoolName, ServiceUrl};
+use crate::build_info;
+use crate::listeners::DiscoveryListener;
+
+pub const SERVICE_TYPE: &str = "_lmserver._udp.local.";
+
+/// Tipo con el que el balanceador anuncia sus listeners UDP, para los nodos arrancados sin `-i`.
+/// `_lmserver._udp` ya lo usan los nodos para anunciarse a sí mismos.
+pub const BALANCER_SERVICE_TYPE: &str = "_lmserver-lb._udp.local.";
+
+/// Cada cuánto se refresca `last_seen` de los nodos que siguen vivos en la caché mDNS,
+/// igual que haría un anuncio UDP, para que la limpieza de inactivos no los elimine.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Mantiene vivos los registros mDNS de un nodo hasta que se llama a `shutdown`.
+pub struct MdnsAdvertiser {
+    daemon: ServiceDaemon,
+    fullnames: Vec<String>,
+    /// Registros de los servicios del nodo, para volver a publicarlos si cambian sus capacidades.
+    /// Vacío en el balanceador.
+    records: Mutex<HashMap<String, NodeRecord>>,
+}
+
+/// Lo necesario para rehacer el registro de un servicio del nodo: mdns-sd no permite cambiar
+/// los TXT de un registro ya anunciado, sólo volver a registrarlo con el mismo nombre.
+struct NodeRecord {
+    instance_name: String,
+    host_name: String,
+    unique_node_id: String,
+    url: String,
+    port: u16,
+    /// `None` mientras no se conozcan; entonces no se publica el TXT `capabilities`.
+    capabilities: Option<String>,
+}
+
+impl NodeRecord {
+    fn service_info(&self, service_type: &str) -> Result<ServiceInfo, mdns_sd::Error> {
+        let version = build_info::summary();
+        let mut properties = vec![
+            ("service", service_type),
+            ("id", self.unique_node_id.as_str()),
+            ("url", self.url.as_str()),
+            ("version", version.as_str()),
+        ];
+        properties.extend(self.capabilities.as_deref().map(|capabilities| ("capabilities", capabilities)));
+        ServiceInfo::new(SERVICE_TYPE, &self.instance_name, &self.host_name, "", self.port, &properties[..])
+            .map(ServiceInfo::enable_addr_auto)
+    }
+}
+
+/// Capacidades del TXT `capabilities`, separadas por comas como en `CAPS`.
+fn txt_capabilities(value: &str) -> Vec<&str> {
+    value.split(',').map(str::trim).filter(|capability| !capability.is_empty()).collect()
+}
+
+impl MdnsAdvertiser {
+    /// Anuncia cada `(servicio, url)` como una instancia `_lmserver._udp` con registros TXT.
+    /// Las capacidades se publican después, con `set_capabilities`, cuando se conocen.
+    pub fn start(unique_node_id: &str, services: &[(&str, &str)]) -> Option<Self> {
+        let daemon = match ServiceDaemon::new() {
+            Ok(daemon) => daemon,
+            Err(e) => {
+                warn!("mDNS: No se pudo iniciar el daemon ({}). Se continúa sólo con anuncios UDP.", e);
+                return None;
+            }
+        };
+
+        let host_name = format!("{}.local.", unique_node_id);
+        let mut fullnames = Vec::new();
+        let mut records = HashMap::new();
+        for (service_type, service_url) in services {
+            let record = NodeRecord {
+                instance_name: format!("{}-{}", service_type, unique_node_id),
+                host_name: host_name.clone(),
+                unique_node_id: unique_node_id.to_string(),
+                url: service_url.to_string(),
+                port: Url::parse(service_url)
+                    .ok()
+                    .and_then(|url| url.port_or_known_default())
+                    .unwrap_or(0),
+                capabilities: None,
+            };
+            let registration = record.service_info(service_type).and_then(|service_info| {
+                let fullname = service_info.get_fullname().to_string();
+                daemon.register(service_info).map(|_| fullname)
+            });
+            match registration {
+                Ok(fullname) => {
+                    info!("mDNS: Anunciando {} como {}", service_type, fullname);
+                    fullnames.push(fullname);
+                    records.insert(service_type.to_string(), record);
+                }
+                Err(e) => error!("mDNS: No se pudo registrar {} (ID: {}): {}", service_type, unique_node_id, e),
+            }
+        }
+
+        Some(Self { daemon, fullnames, records: Mutex::new(records) })
+    }
+
+    /// Publica las capacidades de un servicio del nodo en su TXT `capabilities`, volviendo a
+    /// registrarlo. No hace nada si no han cambiado desde la última vez.
+    pub fn set_capabilities(&self, service_type: &str, capabilities: &[&str]) {
+        let mut records = self.records.lock().unwrap();
+        let Some(record) = records.get_mut(service_type) else {
+            return;
+        };
+        let joined = capabilities.join(",");
+        if record.capabilities.as_deref() == Some(joined.as_str()) {
+            return;
+        }
+        record.capabilities = Some(joined);
+        match record.service_info(service_type).and_then(|service_info| self.daemon.register(service_info)) {
+            Ok(()) => debug!("mDNS: Capacidades de {} publicadas: [{}]", service_type, capabilities.join(", ")),
+            Err(e) => warn!("mDNS: No se pudieron publicar las capacidades de {}: {}", service_type, e),
+        }
+    }
+
+    /// Anuncia cada listener UDP del balanceador como una instancia `_lmserver-lb._udp`, con su
+    /// versión y, si las limita, sus pools en los registros TXT. Los listeners en loopback no se
+    /// anuncian: ningún nodo de la red podría alcanzarlos.
+    pub fn start_balancer(listeners: &[Arc<DiscoveryListener>]) -> Option<Self> {
+        let advertised: Vec<(SocketAddr, &DiscoveryListener)> = listeners
+            .iter()
+            .filter_map(|listener| Some((listener.bind_addr.parse::<SocketAddr>().ok()?, listener.as_ref())))
+            .filter(|(addr, listener)| {
+                let reachable = !addr.ip().is_loopback();
+                if !reachable {
+                    info!("mDNS: El listener UDP {} es de loopback; no se anuncia.", listener.bind_addr);
+                }
+                reachable
+            })
+            .collect();
+        if advertised.is_empty() {
+            return None;
+        }
+        let daemon = match ServiceDaemon::new() {
+            Ok(daemon) => daemon,
+            Err(e) => {
+                warn!("mDNS: No se pudo iniciar el daemon ({}). Los nodos tendrán que indicar -i.", e);
+                return None;
+            }
+        };
+
+        let hostname = hostname::get().ok().and_then(|h| h.into_string().ok()).unwrap_or_else(|| "lmserver".to_string());
+        let host_name = format!("{}.local.", hostname);
+        let version = build_info::summary();
+        let mut fullnames = Vec::new();
+        for (addr, listener) in advertised {
+            let instance_name = format!("balancer-{}-{}", hostname, addr.port());
+            let pools = listener.allowed_pool_names().map(|pools| pools.join(","));
+            let mut properties = vec![("version", version.as_str())];
+            properties.extend(pools.as_deref().map(|pools| ("pools", pools)));
+            // Un listener en 0.0.0.0 se anuncia con las direcciones de todas las interfaces.
+            let ip = if addr.ip().is_unspecified() { String::new() } else { addr.ip().to_string() };
+            let registration = ServiceInfo::new(BALANCER_SERVICE_TYPE, &instance_name, &host_name, ip.as_str(), addr.port(), &properties[..])
+                .map(|service_info| if addr.ip().is_unspecified() { service_info.enable_addr_auto() } else { service_info })
+                .and_then(|service_info| {
+                    let fullname = service_info.get_fullname().to_string();
+                    daemon.register(service_info).map(|_| fullname)
+                });
+            match registration {
+                Ok(fullname) => {
+                    info!("mDNS: Anunciando el listener UDP {} como {}", listener.bind_addr, fullname);
+                    fullnames.push(fullname);
+                }
+                Err(e) => error!("mDNS: No se pudo anunciar el listener UDP {}: {}", listener.bind_addr, e),
+            }
+        }
+
+        Some(Self { daemon, fullnames, records: Mutex::new(HashMap::new()) })
+    }
+
+    /// Retira los registros (el daemon envía los paquetes de despedida) y detiene el daemon.
+    pub fn shutdown(&self) {
+        for fullname in &self.fullnames {
+            if let Err(e) = self.daemon.unregister(fullname) {
+                warn!("mDNS: No se pudo retirar {}: {}", fullname, e);
+            }
+        }
+        let _ = self.daemon.shutdown();
+    }
+}
+
+/// Busca nodos anunciados por mDNS y los registra por la misma vía que el listener UDP.
+pub async fn browse_nodes(app_state: web::Data<AppState>) {
+    let daemon = match ServiceDaemon::new() {
+        Ok(daemon) => daemon,
+        Err(e) => {
+            error!("mDNS: No se pudo iniciar el daemon: {}. El descubrimiento mDNS se ha detenido.", e);
+            return;
+        }
+    };
+    let receiver = match daemon.browse(SERVICE_TYPE) {
+        Ok(receiver) => receiver,
+        Err(e) => {
+            error!("mDNS: No se pudo iniciar la búsqueda de {}: {}", SERVICE_TYPE, e);
+            return;
+        }
+    };
+    info!("mDNS: Buscando nodos {}", SERVICE_TYPE);
+
+    // fullname -> (servicio, ID de nodo, URL efectiva).
+    let mut known: HashMap<String, (PoolName, NodeId, ServiceUrl)> = HashMap::new();
+    let mut ticker = interval(REFRESH_INTERVAL);
+
+    loop {
+        let event = tokio::select! {
+            event = receiver.recv_async() => match event {
+                Ok(event) => event,
+                Err(_) => break,
+            },
+            _ = ticker.tick() => {
+                for (service_type, unique_node_id, service_url) in known.values() {
+                    if let Err(e) = app_state.register_node(service_type, unique_node_id, service_url.clone()) {
+                        debug!("mDNS: No se pudo refrescar el nodo ID {}: {}", unique_node_id, e);
+                    }
+                }
+                continue;
+            }
+        };
+
+        match event {
+            ServiceEvent::ServiceResolved(service_info) => {
+                let (Some(service_type), Some(unique_node_id), Some(announced_url)) = (
+                    service_info.get_property_val_str("service"),
+                    service_info.get_property_val_str("id"),
+                    service_info.get_property_val_str("url"),
+                ) else {
+                    warn!("mDNS: Registro {} sin campos TXT service/id/url. Ignorado.", service_info.get_fullname());
+                    continue;
+                };
+                let parsed = PoolName::new(app_state.canonical_service(service_type))
+                    .and_then(|pool| Ok((pool, NodeId::new(unique_node_id)?, ServiceUrl::parse(announced_url)?)));
+                let (service_type, unique_node_id, announced_url) = match parsed {
+                    Ok(parsed) => parsed,
+                    Err(e) => {
+                        warn!("mDNS: Registro {} ignorado: {}", service_info.get_fullname(), e);
+                        continue;
+                    }
+                };
+                let (service_type, unique_node_id) = (&service_type, &unique_node_id);
+
+                let service_url = match service_info.get_addresses_v4().into_iter().next() {
+                    Some(ip) => effective_service_url(announced_url, IpAddr::V4(*ip), unique_node_id),
+                    None => announced_url,
+                };
+                debug!("mDNS: Resuelto {} (ID {}) en {}", service_type, unique_node_id, service_url);
+
+                if let Err(e) = app_state.register_node(service_type, unique_node_id, service_url.clone()) {
+                    warn!("mDNS: Nodo ID {} de '{}' no registrado: {}", unique_node_id, service_type, e);
+                } else {
+                    if let Some(version) = service_info.get_property_val_str("version") {
+                        app_state.set_node_version(service_type, unique_node_id, version);
+                    }
+                    if let Some(capabilities) = service_info.get_property_val_str("capabilities") {
+                        app_state.set_node_capabilities(service_type, unique_node_id, &txt_capabilities(capabilities));
+                    }
+                    known.insert(
+                        service_info.get_fullname().to_string(),
+                        (service_type.clone(), unique_node_id.clone(), service_url),
+                    );
+                }
+            }
+            ServiceEvent::ServiceRemoved(_, fullname) => {
+                if let Some((service_type, unique_node_id, _)) = known.remove(&fullname) {
+                    app_state.deregister_node(&service_type, &unique_node_id);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    warn!("mDNS: El canal de eventos se cerró. El descubrimiento mDNS se ha detenido.");
+}
+
+/// Balanceador encontrado por mDNS.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FoundBalancer {
+    pub addr: SocketAddr,
+    /// Pools que admite su listener; `None` si admite todas.
+    pools: Option<Vec<String>>,
+}
+
+impl FoundBalancer {
+    /// Si el listener registra nodos de la pool.
+    pub fn accepts(&self, service: &str) -> bool {
+        self.pools.as_ref().is_none_or(|pools| pools.iter().any(|pool| pool == service))
+    }
+}
+
+/// Busca balanceadores `_lmserver-lb._udp` en segundo plano. El canal tiene en cada momento los
+/// que están anunciados; `None` si no se pudo iniciar el daemon.
+pub fn browse_balancers() -> Option<watch::Receiver<Vec<FoundBalancer>>> {
+    let daemon = match ServiceDaemon::new() {
+        Ok(daemon) => daemon,
+        Err(e) => {
+            error!("mDNS: No se pudo iniciar el daemon: {}", e);
+            return None;
+        }
+    };
+    let receiver = match daemon.browse(BALANCER_SERVICE_TYPE) {
+        Ok(receiver) => receiver,
+        Err(e) => {
+            error!("mDNS: No se pudo iniciar la búsqueda de {}: {}", BALANCER_SERVICE_TYPE, e);
+            return None;
+        }
+    };
+    info!("mDNS: Buscando balanceadores {}", BALANCER_SERVICE_TYPE);
+
+    let (sender, found) = watch::channel(Vec::new());
+    tokio::spawn(async move {
+        // El daemon vive mientras dure la búsqueda.
+        let _daemon = daemon;
+        let mut known: BTreeMap<String, FoundBalancer> = BTreeMap::new();
+        while let Ok(event) = receiver.recv_async().await {
+            match event {
+                ServiceEvent::ServiceResolved(service_info) => {
+                    // Una IPv6 de enlace local no sirve sin la interfaz; llega a veces antes que la IPv4.
+                    let addresses = service_info.get_addresses();
+                    let usable = |ip: &&IpAddr| !matches!(ip, IpAddr::V6(v6) if v6.is_unicast_link_local());
+                    let Some(ip) = addresses.iter().find(|ip| ip.is_ipv4()).or_else(|| addresses.iter().find(usable)) else {
+                        continue;
+                    };
+                    let balancer = FoundBalancer {
+                        addr: SocketAddr::new(*ip, service_info.get_port()),
+                        pools: service_info.get_property_val_str("pools").map(|pools| pools.split(',').map(str::to_string).collect()),
+                    };
+                    let fullname = service_info.get_fullname().to_string();
+                    if known.get(&fullname) == Some(&balancer) {
+                        continue;
+                    }
+                    info!(
+                        "mDNS: Balanceador {} en {} (versión {}).",
+                        fullname,
+                        balancer.addr,
+                        service_info.get_property_val_str("version").unwrap_or("desconocida")
+                    );
+                    known.insert(fullname, balancer);
+                }
+                ServiceEvent::ServiceRemoved(_, fullname) => {
+                    let Some(balancer) = known.remove(&fullname) else {
+                        continue;
+                    };
+                    info!("mDNS: El balanceador {} en {} se retira.", fullname, balancer.addr);
+                }
+                _ => continue,
+            }
+            let mut balancers: Vec<FoundBalancer> = known.values().cloned().collect();
+            balancers.sort_by_key(|balancer| balancer.addr);
+            balancers.dedup_by_key(|balancer| balancer.addr);
+            sender.send_replace(balancers);
+        }
+        warn!("mDNS: El canal de eventos se cerró. La búsqueda de balanceadores se ha detenido.");
+    });
+    Some(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn txt_capabilities_ignores_blanks() {
+        assert_eq!(txt_capabilities("tool-calling"), vec!["tool-calling"]);
+        assert_eq!(txt_capabilities(" tool-calling , ,vision"), vec!["tool-calling", "vision"]);
+        assert!(txt_capabilities("").is_empty());
+    }
+}
@@ -0,0 +1,462 @@
+// src/metrics.rs
+use chrono::{DateTime, Local};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::headers::DropReason;
+use crate::limits::StoreUsage;
+
+/// Contadores globales del balanceador, expuestos en formato de texto Prometheus en `GET /metrics`.
+#[derive(Default)]
+pub struct Metrics {
+    pub rejected_empty_bodies: AtomicU64,
+    pub rejected_invalid_requests: AtomicU64,
+    pub rejected_over_context: AtomicU64,
+    /// Respuestas repetidas desde la caché de idempotencia.
+    pub idempotent_replays: AtomicU64,
+    pub event_subscribers: AtomicU64,
+    pub event_lag_disconnects: AtomicU64,
+    pub streamed_responses: AtomicU64,
+    pub stream_node_held_ms: AtomicU64,
+    pub stream_client_drain_ms: AtomicU64,
+    pub sse_keepalives: AtomicU64,
+    /// Peticiones esperando nodo en la cola en este momento.
+    pub queued_requests: AtomicU64,
+    /// Saltos del reloj del sistema detectados.
+    pub clock_jumps: AtomicU64,
+    /// Cabeceras no reenviadas a los nodos por superar los límites, por motivo (`DropReason`).
+    pub dropped_headers: [AtomicU64; 3],
+    pub rejected_oversized_authorization: AtomicU64,
+    /// Peticiones con `tools` rechazadas con 501 por no haber nodos con la capacidad.
+    pub rejected_missing_capability: AtomicU64,
+    /// Peticiones rechazadas con 404 por pedir un modelo que ningún nodo anuncia.
+    pub rejected_unknown_model: AtomicU64,
+    /// Peticiones rechazadas con 503 porque ningún nodo tiene las etiquetas de `X-Require-Tags`.
+    pub rejected_unsatisfied_tags: AtomicU64,
+    /// Peticiones clasificadas en el carril lento (`slow_lane`).
+    pub slow_lane_requests: AtomicU64,
+    /// Peticiones reenviadas en modo directo, sin cola (`direct`).
+    pub direct_requests: AtomicU64,
+    /// Peticiones rechazadas por el tope de peticiones en curso de su cliente (`client_limit`).
+    pub rejected_client_in_flight: AtomicU64,
+    /// Peticiones despachadas a un nodo con su modelo caliente (`warm`).
+    pub warm_model_hits: AtomicU64,
+    /// Nodos Busy sin petición devueltos a Available (`reconcile`); distinto de cero es un fallo.
+    pub stale_busy_reconciliations: AtomicU64,
+    /// Nodos que superaron su fase canaria (`canary`).
+    pub canary_promotions: AtomicU64,
+    /// Nodos marcados Failed al terminar su fase canaria.
+    pub canary_failures: AtomicU64,
+    /// Peticiones con campos de primer nivel desconocidos en una pool con `strictness`.
+    pub strict_request_violations: AtomicU64,
+    /// Reintentos en otro nodo de respuestas con `tool_calls` mal formados.
+    pub tool_call_retries: AtomicU64,
+    /// Peticiones rechazadas con 503 por exceso de carga durante el calentamiento.
+    pub rejected_warmup: AtomicU64,
+    /// Peticiones abandonadas porque el cliente cerró la conexión.
+    pub cancelled_requests: AtomicU64,
+    /// Peticiones que los proxies de los nodos atendieron por su cuenta con el balanceador caído.
+    pub spooled_requests: AtomicU64,
+    /// Respuestas en streaming sin recuento del nodo cuyos tokens se estimaron (`usage`).
+    pub estimated_usage_streams: AtomicU64,
+    /// Peticiones sin respuesta reenviadas a otro nodo al vencer el drenaje del suyo (`drain`).
+    pub drain_redispatched_requests: AtomicU64,
+    /// Streams cortados con pista para reanudar al vencer el drenaje de su nodo (`drain`).
+    pub drain_terminated_streams: AtomicU64,
+    /// Reintentos y milisegundos de espera por punto de reintento (`retry`).
+    retries: Mutex<BTreeMap<&'static str, (u64, u64)>>,
+    /// Errores de reenvío por (nodo, categoría).
+    upstream_errors: Mutex<BTreeMap<(String, &'static str), u64>>,
+    /// Tope de series (nodo, categoría); lleno, una serie nueva sustituye a la de menor cuenta.
+    max_error_series: usize,
+    /// Número de puestas a cero de los contadores (`POST /stats/reset`) y cuándo fue la última.
+    epoch: AtomicU64,
+    last_reset: Mutex<Option<DateTime<Local>>>,
+}
+
+impl Metrics {
+    pub fn new(max_error_series: usize) -> Self {
+        Self { max_error_series, ..Default::default() }
+    }
+
+    pub fn record_upstream_error(&self, unique_node_id: &str, category: &'static str) {
+        let mut upstream_errors = self.upstream_errors.lock().unwrap();
+        let key = (unique_node_id.to_string(), category);
+        if !upstream_errors.contains_key(&key) && upstream_errors.len() >= self.max_error_series {
+            let smallest = upstream_errors.iter().min_by_key(|(_, count)| **count).map(|(key, _)| key.clone());
+            if let Some(smallest) = smallest {
+                upstream_errors.remove(&smallest);
+            }
+        }
+        *upstream_errors.entry(key).or_default() += 1;
+    }
+
+    pub fn upstream_error_usage(&self) -> StoreUsage {
+        let upstream_errors = self.upstream_errors.lock().unwrap();
+        let bytes = upstream_errors.keys().map(|(node, _)| std::mem::size_of::<((String, &str), u64)>() + node.len()).sum();
+        StoreUsage { entries: upstream_errors.len(), bytes }
+    }
+
+    pub fn record_retries(&self, site: &'static str, retries: u64, waited: Duration) {
+        let mut totals = self.retries.lock().unwrap();
+        let (count, waited_ms) = totals.entry(site).or_default();
+        *count += retries;
+        *waited_ms += waited.as_millis() as u64;
+    }
+
+    /// Totales por categoría de un nodo desde el arranque.
+    pub fn upstream_error_totals(&self, unique_node_id: &str) -> BTreeMap<&'static str, u64> {
+        self.upstream_errors
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((node, _), _)| node == unique_node_id)
+            .map(|((_, category), count)| (*category, *count))
+            .collect()
+    }
+
+    /// Totales por nodo y categoría desde la última puesta a cero.
+    pub fn upstream_error_snapshot(&self) -> BTreeMap<String, BTreeMap<&'static str, u64>> {
+        let mut snapshot: BTreeMap<String, BTreeMap<&'static str, u64>> = BTreeMap::new();
+        for ((node, category), count) in self.upstream_errors.lock().unwrap().iter() {
+            snapshot.entry(node.clone()).or_default().insert(category, *count);
+        }
+        snapshot
+    }
+
+    /// Pone a cero los contadores globales y los errores de todos los nodos. Los gauges no se tocan.
+    pub fn reset_counters(&self) {
+        for counter in [
+            &self.rejected_empty_bodies,
+            &self.rejected_invalid_requests,
+            &self.rejected_over_context,
+            &self.idempotent_replays,
+            &self.event_lag_disconnects,
+            &self.streamed_responses,
+            &self.stream_node_held_ms,
+            &self.stream_client_drain_ms,
+            &self.sse_keepalives,
+            &self.clock_jumps,
+            &self.rejected_oversized_authorization,
+            &self.rejected_missing_capability,
+            &self.rejected_unknown_model,
+            &self.rejected_unsatisfied_tags,
+            &self.slow_lane_requests,
+            &self.direct_requests,
+            &self.rejected_client_in_flight,
+            &self.warm_model_hits,
+            &self.stale_busy_reconciliations,
+            &self.canary_promotions,
+            &self.canary_failures,
+            &self.strict_request_violations,
+            &self.tool_call_retries,
+            &self.rejected_warmup,
+            &self.cancelled_requests,
+            &self.spooled_requests,
+            &self.estimated_usage_streams,
+            &self.drain_redispatched_requests,
+            &self.drain_terminated_streams,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+        for counter in &self.dropped_headers {
+            counter.store(0, Ordering::Relaxed);
+        }
+        self.retries.lock().unwrap().clear();
+        self.upstream_errors.lock().unwrap().clear();
+    }
+
+    /// Pone a cero los errores de los nodos indicados.
+    pub fn reset_node_errors(&self, unique_node_ids: &BTreeSet<String>) {
+        self.upstream_errors.lock().unwrap().retain(|(node, _), _| !unique_node_ids.contains(node));
+    }
+
+    /// Abre una época nueva de contadores y devuelve su número.
+    pub fn next_epoch(&self) -> u64 {
+        *self.last_reset.lock().unwrap() = Some(Local::now());
+        self.epoch.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch.load(Ordering::Relaxed)
+    }
+
+    pub fn last_reset(&self) -> Option<DateTime<Local>> {
+        *self.last_reset.lock().unwrap()
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        write_counter(
+            &mut out,
+            "lmserver_rejected_empty_bodies_total",
+            "Peticiones de inferencia rechazadas por llegar con el cuerpo vacío.",
+            self.rejected_empty_bodies.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "lmserver_rejected_invalid_requests_total",
+            "Peticiones de inferencia rechazadas por no superar la validación del esquema.",
+            self.rejected_invalid_requests.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "lmserver_rejected_over_context_total",
+            "Peticiones de inferencia rechazadas porque el prompt estimado no cabe en la ventana de contexto del modelo.",
+            self.rejected_over_context.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "lmserver_rejected_missing_capability_total",
+            "Peticiones con tools rechazadas porque ningún nodo tiene la capacidad tool-calling.",
+            self.rejected_missing_capability.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "lmserver_rejected_unknown_model_total",
+            "Peticiones rechazadas porque ningún nodo anuncia el modelo pedido, o no indicaban modelo.",
+            self.rejected_unknown_model.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "lmserver_rejected_unsatisfied_tags_total",
+            "Peticiones rechazadas porque ningún nodo tiene las etiquetas de X-Require-Tags.",
+            self.rejected_unsatisfied_tags.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "lmserver_slow_lane_requests_total",
+            "Peticiones que superaron un umbral del carril lento (--slow-lane-body-bytes, --slow-lane-prompt-tokens).",
+            self.slow_lane_requests.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "lmserver_direct_requests_total",
+            "Peticiones reenviadas sin cola al único nodo de una pool con --direct-pool.",
+            self.direct_requests.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "lmserver_rejected_client_in_flight_total",
+            "Peticiones rechazadas con 429 por el tope de peticiones en curso de su cliente (--max-client-in-flight).",
+            self.rejected_client_in_flight.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "lmserver_warm_model_hits_total",
+            "Peticiones despachadas a un nodo que atendió su mismo modelo dentro de --warm-model-window-secs.",
+            self.warm_model_hits.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "lmserver_stale_busy_reconciliations_total",
+            "Nodos Busy sin ninguna petición en curso devueltos a Available por la tarea de limpieza. Distinto de cero indica un fallo del balanceador.",
+            self.stale_busy_reconciliations.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "lmserver_canary_promotions_total",
+            "Nodos recién anunciados que superaron su fase canaria y pasaron a tráfico completo.",
+            self.canary_promotions.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "lmserver_canary_failures_total",
+            "Nodos recién anunciados marcados Failed por su tasa de errores al terminar la fase canaria.",
+            self.canary_failures.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "lmserver_strict_request_violations_total",
+            "Peticiones con campos de primer nivel desconocidos en una pool con --strictness warn o enforce (rechazadas con enforce).",
+            self.strict_request_violations.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "lmserver_tool_call_retries_total",
+            "Peticiones repetidas en otro nodo tras una respuesta con tool_calls mal formados.",
+            self.tool_call_retries.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "lmserver_rejected_warmup_total",
+            "Peticiones rechazadas con 503 y Retry-After por exceso de carga durante el calentamiento tras arrancar.",
+            self.rejected_warmup.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "lmserver_cancelled_requests_total",
+            "Peticiones abandonadas, liberando su nodo, porque el cliente cerró la conexión antes de terminar.",
+            self.cancelled_requests.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "lmserver_spooled_requests_total",
+            "Peticiones que los proxies de los nodos atendieron contra su backend mientras el balanceador no respondía.",
+            self.spooled_requests.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "lmserver_estimated_usage_streams_total",
+            "Respuestas en streaming auditadas sin usage del nodo, con los tokens estimados por el balanceador.",
+            self.estimated_usage_streams.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "lmserver_drain_redispatched_requests_total",
+            "Peticiones sin respuesta reenviadas a otro nodo al vencer el plazo de drenaje del suyo (--drain-policy migrate).",
+            self.drain_redispatched_requests.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "lmserver_drain_terminated_streams_total",
+            "Streams cortados con resume_hint al vencer el plazo de drenaje de su nodo (--drain-policy migrate).",
+            self.drain_terminated_streams.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "lmserver_idempotent_replays_total",
+            "Reintentos con Idempotency-Key servidos con la respuesta guardada de la petición original.",
+            self.idempotent_replays.load(Ordering::Relaxed),
+        );
+        write_gauge(
+            &mut out,
+            "lmserver_event_subscribers",
+            "Suscriptores conectados a GET /events.",
+            self.event_subscribers.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "lmserver_event_lag_disconnects_total",
+            "Suscriptores de eventos desconectados por no leer a tiempo.",
+            self.event_lag_disconnects.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "lmserver_streamed_responses_total",
+            "Respuestas reenviadas en streaming.",
+            self.streamed_responses.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "lmserver_stream_node_held_milliseconds_total",
+            "Tiempo que las respuestas en streaming mantuvieron ocupado a un nodo.",
+            self.stream_node_held_ms.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "lmserver_stream_client_drain_milliseconds_total",
+            "Tiempo que los clientes tardaron en leer el buffer después de liberarse el nodo.",
+            self.stream_client_drain_ms.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "lmserver_sse_keepalives_total",
+            "Comentarios de keep-alive enviados en respuestas SSE durante pausas del nodo.",
+            self.sse_keepalives.load(Ordering::Relaxed),
+        );
+        write_gauge(
+            &mut out,
+            "lmserver_queued_requests",
+            "Peticiones esperando a que quede libre un nodo.",
+            self.queued_requests.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "lmserver_clock_jumps_total",
+            "Saltos del reloj del sistema detectados (p.ej. correcciones de NTP).",
+            self.clock_jumps.load(Ordering::Relaxed),
+        );
+        write_labeled_metric(
+            &mut out,
+            "lmserver_forwarded_headers_dropped_total",
+            "Cabeceras del cliente no reenviadas a los nodos por superar los límites, por motivo.",
+            "counter",
+            DropReason::ALL
+                .iter()
+                .map(|reason| (format!("reason=\"{}\"", reason.label()), self.dropped_headers[reason.index()].load(Ordering::Relaxed))),
+        );
+        write_counter(
+            &mut out,
+            "lmserver_rejected_oversized_authorization_total",
+            "Peticiones rechazadas porque la cabecera Authorization a reenviar supera los límites.",
+            self.rejected_oversized_authorization.load(Ordering::Relaxed),
+        );
+        write_gauge(
+            &mut out,
+            "lmserver_stats_epoch",
+            "Época de los contadores; aumenta con cada POST /stats/reset.",
+            self.epoch(),
+        );
+        write_gauge(
+            &mut out,
+            "lmserver_stats_last_reset_timestamp_seconds",
+            "Momento (epoch Unix) de la última puesta a cero de los contadores; 0 si nunca.",
+            self.last_reset().map_or(0, |at| at.timestamp().max(0) as u64),
+        );
+        let retries = self.retries.lock().unwrap();
+        write_labeled_metric(
+            &mut out,
+            "lmserver_retries_total",
+            "Reintentos de llamadas salientes, por punto de reintento (--retry-policy).",
+            "counter",
+            retries.iter().map(|(site, (count, _))| (format!("site=\"{}\"", site), *count)),
+        );
+        write_labeled_metric(
+            &mut out,
+            "lmserver_retry_delay_milliseconds_total",
+            "Tiempo esperado entre reintentos de llamadas salientes, por punto de reintento.",
+            "counter",
+            retries.iter().map(|(site, (_, waited_ms))| (format!("site=\"{}\"", site), *waited_ms)),
+        );
+        drop(retries);
+        let upstream_errors = self.upstream_errors.lock().unwrap();
+        write_labeled_metric(
+            &mut out,
+            "lmserver_upstream_errors_total",
+            "Errores al reenviar peticiones a los nodos, por categoría.",
+            "counter",
+            upstream_errors
+                .iter()
+                .map(|((node, category), count)| (format!("node=\"{}\",category=\"{}\"", escape_label(node), category), *count)),
+        );
+        out
+    }
+}
+
+fn write_counter(out: &mut String, name: &str, help: &str, value: u64) {
+    write_metric(out, name, help, "counter", value);
+}
+
+fn write_gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    write_metric(out, name, help, "gauge", value);
+}
+
+fn write_metric(out: &mut String, name: &str, help: &str, kind: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Escribe una métrica con una muestra por conjunto de etiquetas (`clave="valor",...`).
+pub fn write_labeled_metric(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    kind: &str,
+    samples: impl IntoIterator<Item = (String, u64)>,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+    }
+}
+
+/// Escapa un valor de etiqueta según el formato de texto de Prometheus.
+pub fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
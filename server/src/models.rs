@@ -0,0 +1,41 @@
+// src/models.rs
+//! Enrutado por modelo: una petición sólo va a los nodos que anuncian el modelo de su campo
+//! `model`.
+//!
+//! Los nodos anuncian los modelos de su backend en datagramas `MODELS` (`discovery`). En una
+//! pool donde ningún nodo los anuncia (nodos antiguos) se enruta como siempre, a cualquier
+//! nodo libre. En cuanto uno los anuncia, un nodo sin el modelo, o sin lista, deja de ser
+//! candidato, y una petición sin `model` o con un modelo que nadie tiene se rechaza con un
+//! 404 que lista los modelos disponibles, en lugar de esperar en la cola hasta el 503.
+//!
+//! Ollama resuelve un nombre sin etiqueta como `:latest`: `llama3` casa con `llama3:latest`.
+use std::collections::{BTreeSet, HashMap};
+
+use crate::balancer::NodeInfo;
+use crate::ids::NodeId;
+
+/// Si la lista anunciada por un nodo incluye `model`.
+pub fn serves(models: &[String], model: &str) -> bool {
+    models.iter().any(|served| served == model || served.strip_suffix(":latest") == Some(model))
+}
+
+/// Si algún nodo de la pool anuncia su lista de modelos: sólo entonces se filtra por modelo.
+pub fn advertised(nodes: &HashMap<NodeId, NodeInfo>) -> bool {
+    nodes.values().any(|info| !info.models.is_empty())
+}
+
+/// Si un nodo puede atender una petición para `model` en una pool que anuncia (o no) modelos.
+pub fn eligible(advertised: bool, info: &NodeInfo, model: Option<&str>) -> bool {
+    !advertised || model.is_some_and(|model| serves(&info.models, model))
+}
+
+/// Si la pool puede atender la petición con algún nodo, libre o no.
+pub fn routable(nodes: &HashMap<NodeId, NodeInfo>, model: Option<&str>) -> bool {
+    let advertised = advertised(nodes);
+    !advertised || nodes.values().any(|info| eligible(advertised, info, model))
+}
+
+/// Modelos anunciados por los nodos de la pool, en cualquier estado.
+pub fn available(nodes: &HashMap<NodeId, NodeInfo>, into: &mut BTreeSet<String>) {
+    into.extend(nodes.values().flat_map(|info| info.models.iter().cloned()));
+}
@@ -1,12 +1,81 @@
 // node.rs
+use std::collections::BTreeMap;
 use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
 use std::time::Duration;
 use tokio::net::UdpSocket;
-use tokio::time::interval;
+use tokio::sync::watch;
+use tokio::time::{sleep_until, Instant};
 use uuid::Uuid;
-use log::{info, warn, error};
+use log::{debug, info, warn, error};
 
-fn prompt_for_url(service_name: &str) -> Option<String> {
+use crate::build_info;
+use crate::discovery;
+use crate::ids::ServiceUrl;
+use crate::platform::Platform;
+use crate::spool::{self, SpoolLedger, SpoolOptions};
+use crate::storage::{self, STORAGE_PROBE_INTERVAL};
+use crate::tags::NodeTags;
+use crate::tools::{self, ToolCallingMode};
+
+/// Intervalo normal entre anuncios.
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(10);
+
+pub const DEFAULT_UNACKED_THRESHOLD: u32 = 3;
+pub const DEFAULT_DRAIN_LEAD_SECS: u64 = 90;
+pub const DEFAULT_MAX_ANNOUNCE_INTERVAL_SECS: u64 = 300;
+
+/// Espaciado de los anuncios cuando el balanceador deja de confirmarlos.
+#[derive(Clone, Copy, Debug)]
+pub struct AnnounceBackoff {
+    /// Anuncios seguidos sin ACK tras los que se avisa y se empieza a espaciar.
+    pub unacked_threshold: u32,
+    pub max_interval: Duration,
+}
+
+/// Seguimiento de los ACK del balanceador. Un balanceador antiguo nunca responde: mientras no
+/// llegue el primer ACK se anuncia al ritmo normal y no se avisa de nada.
+struct AckTracker {
+    backoff: AnnounceBackoff,
+    supported: bool,
+    unacked: u32,
+    interval: Duration,
+}
+
+impl AckTracker {
+    fn new(backoff: AnnounceBackoff) -> Self {
+        Self { backoff, supported: false, unacked: 0, interval: ANNOUNCE_INTERVAL }
+    }
+
+    /// Registra el resultado de un anuncio y devuelve el intervalo hasta el siguiente.
+    fn record(&mut self, acked: bool, service_name: &str, balancer_target: &str) -> Duration {
+        if acked {
+            if !self.supported {
+                debug!("El balanceador {} confirma los anuncios de {}; backoff activado.", balancer_target, service_name);
+            } else if self.unacked >= self.backoff.unacked_threshold {
+                info!("El balanceador {} vuelve a responder a los anuncios de {}.", balancer_target, service_name);
+            }
+            self.supported = true;
+            self.unacked = 0;
+            self.interval = ANNOUNCE_INTERVAL;
+        } else if self.supported {
+            self.unacked += 1;
+            if self.unacked == self.backoff.unacked_threshold {
+                warn!(
+                    "El balanceador {} no responde: {} anuncios de {} sin confirmar. Se espaciarán los anuncios hasta {}s.",
+                    balancer_target, self.unacked, service_name, self.backoff.max_interval.as_secs()
+                );
+            }
+            if self.unacked >= self.backoff.unacked_threshold {
+                self.interval = (self.interval * 2).min(self.backoff.max_interval);
+            }
+        }
+        self.interval
+    }
+}
+
+fn prompt_for_url(service_name: &str) -> Option<ServiceUrl> {
     print!("Introduce la URL completa para {} (ej: http://localhost:1234/v1/api) o deja en blanco si no aplica: ", service_name);
     io::stdout().flush().unwrap();
     let mut url = String::new();
@@ -15,43 +84,414 @@ fn prompt_for_url(service_name: &str) -> Option<String> {
     if url.is_empty() {
         None
     } else {
-        if url.starts_with("http://") || url.starts_with("https://") {
-             Some(url.to_string())
-        } else {
-            warn!("URL inválida para {}. Debe empezar con http:// o https://. Ignorando.", service_name);
-            None
+        match ServiceUrl::parse(url) {
+            Ok(url) => Some(url),
+            Err(e) => {
+                warn!("{}. Se ignora la URL de {}.", e, service_name);
+                None
+            }
+        }
+    }
+}
+
+/// Consulta al backend local los modelos que tiene disponibles.
+async fn fetch_models(client: &reqwest::Client, service_name: &str, service_url: &ServiceUrl) -> Option<Vec<String>> {
+    let (path, list_key, name_key) = match service_name {
+        "ollama" => ("/api/tags", "models", "name"),
+        _ => ("/v1/models", "data", "id"),
+    };
+    let url = service_url.endpoint(path);
+
+    let json: serde_json::Value = match client.get(url.as_str()).send().await {
+        Ok(response) => response.json().await.ok()?,
+        Err(e) => {
+            debug!("No se pudo obtener la lista de modelos de {} en {}: {}", service_name, url, e);
+            return None;
+        }
+    };
+    let models = json.get(list_key)?.as_array()?
+        .iter()
+        .filter_map(|entry| entry.get(name_key).and_then(|name| name.as_str()).map(str::to_string))
+        .collect();
+    Some(models)
+}
+
+/// Ventanas de contexto que conoce el backend local. Sólo LM Studio las expone en un listado
+/// (`/api/v0/models`); Ollama exige consultar modelo a modelo y se configuran en el balanceador.
+async fn fetch_context_windows(client: &reqwest::Client, service_name: &str, service_url: &ServiceUrl) -> Option<BTreeMap<String, u64>> {
+    if service_name != "lmstudio" {
+        return None;
+    }
+    let url = service_url.endpoint("/api/v0/models");
+    let json: serde_json::Value = client.get(url.as_str()).send().await.ok()?.json().await.ok()?;
+    let windows = json.get("data")?.as_array()?
+        .iter()
+        .filter_map(|entry| {
+            let id = entry.get("id")?.as_str()?;
+            let tokens = entry.get("max_context_length")?.as_u64()?;
+            Some((id.to_string(), tokens))
+        })
+        .collect();
+    Some(windows)
+}
+
+/// Versión que informa el backend. Sólo Ollama la expone (`/api/version`).
+async fn fetch_backend_version(client: &reqwest::Client, service_name: &str, service_url: &ServiceUrl) -> Option<String> {
+    if service_name != "ollama" {
+        return None;
+    }
+    let url = service_url.endpoint("/api/version");
+    let json: serde_json::Value = client.get(url.as_str()).send().await.ok()?.json().await.ok()?;
+    json.get("version")?.as_str().map(str::to_string).filter(|version| !version.is_empty())
+}
+
+fn capabilities(tool_calling: bool) -> &'static [&'static str] {
+    if tool_calling { &[tools::TOOL_CALLING] } else { &[] }
+}
+
+fn capabilities_message(service_name: &str, unique_node_id: &str, tool_calling: bool) -> String {
+    discovery::capabilities_message(service_name, unique_node_id, capabilities(tool_calling))
+}
+
+async fn send_datagram(socket: &UdpSocket, datagram: &str, balancer_target: &str, service_name: &str, unique_node_id: &str) {
+    if let Err(e) = socket.send_to(datagram.as_bytes(), balancer_target).await {
+        error!(
+            "Error al enviar broadcast UDP para {} (ID: {}): {}",
+            service_name, unique_node_id, e
+        );
+    }
+}
+
+/// Balanceadores a los que se anuncia el nodo.
+#[derive(Clone)]
+pub enum Balancers {
+    /// El de `-i` y `-p`.
+    Fixed(String),
+    /// Los que encuentra mDNS en cada momento, sin `-i`.
+    #[cfg(feature = "mdns")]
+    Discovered(watch::Receiver<Vec<crate::mdns::FoundBalancer>>),
+}
+
+impl Balancers {
+    /// Destinos de los anuncios de `service_name`.
+    #[cfg_attr(not(feature = "mdns"), allow(unused_variables))]
+    fn targets(&self, service_name: &str) -> Vec<String> {
+        match self {
+            Balancers::Fixed(target) => vec![target.clone()],
+            #[cfg(feature = "mdns")]
+            Balancers::Discovered(found) => {
+                found.borrow().iter().filter(|balancer| balancer.accepts(service_name)).map(|balancer| balancer.addr.to_string()).collect()
+            }
+        }
+    }
+
+    /// Espera a que cambien los balanceadores encontrados; con `-i` no termina nunca.
+    async fn changed(&mut self) {
+        match self {
+            Balancers::Fixed(_) => std::future::pending().await,
+            #[cfg(feature = "mdns")]
+            Balancers::Discovered(found) => {
+                if found.changed().await.is_err() {
+                    std::future::pending().await
+                }
+            }
         }
     }
+
+    /// Para el log.
+    fn label(&self) -> String {
+        match self {
+            Balancers::Fixed(target) => target.clone(),
+            #[cfg(feature = "mdns")]
+            Balancers::Discovered(found) => {
+                let found: Vec<String> = found.borrow().iter().map(|balancer| balancer.addr.to_string()).collect();
+                format!("[{}] (mDNS)", found.join(", "))
+            }
+        }
+    }
+}
+
+/// Envía el datagrama a cada balanceador.
+async fn send_to_all(socket: &UdpSocket, datagram: &str, targets: &[String], service_name: &str, unique_node_id: &str) {
+    for target in targets {
+        send_datagram(socket, datagram, target, service_name, unique_node_id).await;
+    }
+}
+
+/// Opciones de anuncio comunes a todos los servicios del nodo.
+#[derive(Clone)]
+struct AnnounceOptions {
+    max_datagram_bytes: usize,
+    backoff: AnnounceBackoff,
+    models_path: Option<PathBuf>,
+    tool_calling: ToolCallingMode,
+    weight: Option<u32>,
+    max_rpm: Option<u32>,
+    failure_domain: Option<String>,
+    max_context: Option<u64>,
+    tags: NodeTags,
+    /// Se rellena en segundo plano al arrancar; hasta entonces no se anuncia `PLATFORM`.
+    platform: Arc<OnceLock<Platform>>,
+    /// Instante del apagado anunciado con SIGUSR2. Desde entonces sólo se envía `DRAINING`.
+    drain: watch::Receiver<Option<Instant>>,
+    /// Lo atendido por el proxy con el balanceador caído (`--spool-listen`), para `SPOOLED`.
+    spool: Option<Arc<SpoolLedger>>,
+    /// Registros mDNS del nodo, donde también se publican las capacidades que se anuncian en `CAPS`.
+    #[cfg(feature = "mdns")]
+    mdns: Option<Arc<crate::mdns::MdnsAdvertiser>>,
 }
 
 async fn udp_broadcast_service(
     service_name: &str,
     unique_node_id: &str,
-    service_url: &str,
-    balancer_target: String,
+    service_url: &ServiceUrl,
+    mut balancers: Balancers,
+    options: AnnounceOptions,
 ) -> io::Result<()> {
+    #[cfg(feature = "mdns")]
+    let mdns = options.mdns.clone();
+    let AnnounceOptions { max_datagram_bytes, backoff, models_path, tool_calling: tool_calling_mode, weight, max_rpm, failure_domain, max_context, tags, platform, mut drain, spool, .. } = options;
+    let publish_capabilities = |tool_calling: bool| {
+        #[cfg(feature = "mdns")]
+        if let Some(advertiser) = &mdns {
+            advertiser.set_capabilities(service_name, capabilities(tool_calling));
+        }
+        #[cfg(not(feature = "mdns"))]
+        let _ = tool_calling;
+    };
     let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .build()
+        .expect("No se pudo crear el cliente HTTP");
     info!(
         "Anunciando {} (ID: {}) en {} al balanceador {}",
-        service_name, unique_node_id, service_url, balancer_target
+        service_name, unique_node_id, service_url, balancers.label()
     );
 
-    let msg = format!("DISCOVER,{},{},{}", service_name, unique_node_id, service_url);
-    let mut ticker = interval(Duration::from_secs(10));
+    let msg = discovery::discover_message(service_name, unique_node_id, service_url.as_str());
+    let version_msg = discovery::version_message(service_name, unique_node_id, &build_info::summary());
+    let weight_msg = weight.map(|weight| discovery::weight_message(service_name, unique_node_id, weight));
+    let max_rpm_msg = max_rpm.map(|max_rpm| discovery::max_rpm_message(service_name, unique_node_id, max_rpm));
+    let domain_msg = failure_domain.map(|domain| discovery::domain_message(service_name, unique_node_id, &domain));
+    let max_context_msg = max_context.map(|tokens| discovery::max_context_message(service_name, unique_node_id, tokens));
+    let tags_msg = (!tags.is_empty()).then(|| discovery::tags_message(service_name, unique_node_id, &tags));
+    let mut acks = AckTracker::new(backoff);
+    let mut next_interval = ANNOUNCE_INTERVAL;
+    let mut round: u64 = 0;
+    let mut buf = [0u8; 512];
+    let mut storage_msg: Option<String> = None;
+    let mut next_storage_probe = Instant::now();
+    let mut storage_warned = false;
+    let mut backend_version: Option<String> = None;
+    // `None` mientras no se sepa si el backend llama bien a herramientas; entonces no se anuncia `CAPS`.
+    let mut tool_calling = match tool_calling_mode {
+        ToolCallingMode::Auto => None,
+        ToolCallingMode::On => Some(true),
+        ToolCallingMode::Off => Some(false),
+    };
 
     loop {
-        ticker.tick().await;
-        match socket.send_to(msg.as_bytes(), &balancer_target).await {
-            Ok(_) => {},
-            Err(e) => error!(
-                "Error al enviar broadcast UDP para {} (ID: {}): {}",
-                service_name, unique_node_id, e
-            ),
+        // Sin ningún balanceador encontrado por mDNS no hay a quién anunciarse: se espera a que
+        // aparezca uno (o a que empiece el drenaje).
+        let targets = balancers.targets(service_name);
+        if targets.is_empty() {
+            info!("Esperando a encontrar por mDNS un balanceador que admita {} (ID: {}).", service_name, unique_node_id);
+            tokio::select! {
+                _ = balancers.changed() => {}
+                Ok(()) = drain.changed() => {}
+            }
+            if drain.borrow().is_some() && balancers.targets(service_name).is_empty() {
+                info!("Apagado anunciado sin ningún balanceador encontrado; se dejan de enviar anuncios de {}.", service_name);
+                return Ok(());
+            }
+            continue;
+        }
+        // Drenándose, el nodo deja de anunciarse (un DISCOVER cancelaría el drenaje) y repite
+        // DRAINING con el tiempo que queda, por si se pierde algún datagrama.
+        let draining = *drain.borrow_and_update();
+        if let Some(until) = draining {
+            let remaining = until.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                info!("Plazo de apagado de {} (ID: {}) cumplido; se dejan de enviar anuncios.", service_name, unique_node_id);
+                return Ok(());
+            }
+            let lead_secs = remaining.as_millis().div_ceil(1000) as u64;
+            send_to_all(&socket, &discovery::draining_message(service_name, unique_node_id, lead_secs), &targets, service_name, unique_node_id).await;
+            sleep_until((Instant::now() + ANNOUNCE_INTERVAL).min(until)).await;
+            continue;
+        }
+        let sent_at = Instant::now();
+        if let Some(path) = models_path.as_ref().filter(|_| sent_at >= next_storage_probe) {
+            next_storage_probe = sent_at + STORAGE_PROBE_INTERVAL;
+            let target = path.clone();
+            let probed = tokio::task::spawn_blocking(move || storage::probe(&target)).await;
+            match probed {
+                Ok(Ok(report)) => {
+                    storage_warned = false;
+                    storage_msg = Some(discovery::storage_message(service_name, unique_node_id, &report));
+                }
+                Ok(Err(e)) => {
+                    // Una ruta mal configurada no debe impedir anunciar el servicio.
+                    if !storage_warned {
+                        warn!("No se pudo medir el espacio de modelos en {}: {}. No se anunciará.", path.display(), e);
+                        storage_warned = true;
+                    }
+                    storage_msg = None;
+                }
+                Err(e) => error!("La medición del espacio de modelos falló: {}", e),
+            }
+        }
+        let mut datagrams = vec![msg.clone(), version_msg.clone()];
+        datagrams.extend(weight_msg.clone());
+        datagrams.extend(max_rpm_msg.clone());
+        datagrams.extend(domain_msg.clone());
+        datagrams.extend(max_context_msg.clone());
+        datagrams.extend(tags_msg.clone());
+        datagrams.extend(platform.get().map(|platform| discovery::platform_message(service_name, unique_node_id, platform)));
+        let mut probe_model = None;
+        if let Some(models) = fetch_models(&client, service_name, service_url).await {
+            if tool_calling.is_none() {
+                probe_model = models.first().cloned();
+            }
+            let models_datagrams = discovery::models_messages(service_name, unique_node_id, round, &models, max_datagram_bytes);
+            if models_datagrams.len() > 1 {
+                debug!("Lista de {} modelos de {} repartida en {} datagramas.", models.len(), service_name, models_datagrams.len());
+            }
+            datagrams.extend(models_datagrams);
+            round += 1;
+        }
+        if let Some(version) = fetch_backend_version(&client, service_name, service_url).await {
+            if backend_version.as_ref().is_some_and(|previous| *previous != version) {
+                info!("{} se actualizó a la versión {}.", service_name, version);
+                // Con el backend nuevo la prueba de herramientas ya no vale: se repite en la siguiente ronda.
+                if tool_calling_mode == ToolCallingMode::Auto {
+                    tool_calling = None;
+                }
+            }
+            datagrams.push(discovery::backend_version_message(service_name, unique_node_id, &version));
+            backend_version = Some(version);
+        }
+        if let Some(windows) = fetch_context_windows(&client, service_name, service_url).await {
+            datagrams.extend(discovery::context_messages(service_name, unique_node_id, &windows, max_datagram_bytes));
+        }
+        datagrams.extend(storage_msg.clone());
+        datagrams.extend(tool_calling.map(|capable| capabilities_message(service_name, unique_node_id, capable)));
+        if let Some(capable) = tool_calling {
+            publish_capabilities(capable);
+        }
+        datagrams.extend(spool.as_ref().and_then(|ledger| ledger.report(service_name, unique_node_id)));
+
+        // La prueba de herramientas puede tardar (carga del modelo): se hace tras anunciar el nodo.
+        for datagram in &datagrams {
+            send_to_all(&socket, datagram, &targets, service_name, unique_node_id).await;
+        }
+        if let Some(model) = probe_model {
+            tool_calling = tools::probe(&client, service_url.as_str(), &model, tools::PROBE_TIMEOUT).await;
+            match tool_calling {
+                Some(true) => info!("{} llama bien a herramientas con {}; se anuncia {}.", service_name, model, tools::TOOL_CALLING),
+                Some(false) => warn!("{} no llama bien a herramientas con {}; no se anuncia {}.", service_name, model, tools::TOOL_CALLING),
+                None => debug!("No se pudo probar la llamada a herramientas de {}; se reintentará.", service_name),
+            }
+            if let Some(capable) = tool_calling {
+                send_to_all(&socket, &capabilities_message(service_name, unique_node_id, capable), &targets, service_name, unique_node_id).await;
+                publish_capabilities(capable);
+            }
+        }
+
+        // Se espera al siguiente anuncio escuchando los ACK. El primero devuelve el ritmo normal;
+        // con varios balanceadores basta el de uno. Un balanceador nuevo adelanta el anuncio.
+        let mut deadline = sent_at + next_interval;
+        let mut acked = false;
+        let mut rediscovered = false;
+        loop {
+            tokio::select! {
+                _ = sleep_until(deadline) => break,
+                Ok(()) = drain.changed() => break,
+                _ = balancers.changed() => {
+                    rediscovered = true;
+                    break;
+                }
+                received = socket.recv_from(&mut buf) => {
+                    let Ok((len, _)) = received else { continue };
+                    let msg = String::from_utf8_lossy(&buf[..len]);
+                    if discovery::parse_ack_message(msg.trim()) == Some(unique_node_id) {
+                        acked = true;
+                        deadline = deadline.min(sent_at + ANNOUNCE_INTERVAL);
+                    }
+                }
+            }
+        }
+        if !rediscovered {
+            next_interval = acks.record(acked, service_name, &balancers.label());
         }
     }
 }
 
-pub async fn run_node(balancer_ip: &str, balancer_port: u16) -> io::Result<()> {
+/// Con SIGUSR2 el nodo anuncia que se apagará dentro de `lead`: el balanceador deja de darle
+/// peticiones nuevas y deja terminar las que tiene. Pensado para scripts de actualización que
+/// saben con antelación que van a reiniciar la máquina.
+#[cfg(unix)]
+fn spawn_drain_signal(sender: watch::Sender<Option<Instant>>, lead: Duration) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = match signal(SignalKind::user_defined2()) {
+        Ok(signals) => signals,
+        Err(e) => {
+            warn!("No se pudo escuchar SIGUSR2; el nodo no podrá anunciar su apagado con antelación: {}", e);
+            return;
+        }
+    };
+    info!("Envía SIGUSR2 al proceso para anunciar el apagado del nodo con {}s de antelación.", lead.as_secs());
+    tokio::spawn(async move {
+        while signals.recv().await.is_some() {
+            if sender.borrow().is_some() {
+                info!("SIGUSR2 recibido, pero el apagado ya está anunciado.");
+                continue;
+            }
+            info!("SIGUSR2 recibido: se anuncia el apagado del nodo dentro de {}s.", lead.as_secs());
+            sender.send_replace(Some(Instant::now() + lead));
+        }
+    });
+}
+
+/// Avisa al balanceador de que el nodo se apaga ya, para que lo saque sin esperar a que caduque.
+async fn send_goodbye(services: &[&str], unique_node_id: &str, balancers: &Balancers) {
+    let socket = match UdpSocket::bind("0.0.0.0:0").await {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!("No se pudo enviar GOODBYE al balanceador: {}", e);
+            return;
+        }
+    };
+    for service_name in services {
+        send_to_all(&socket, &discovery::goodbye_message(service_name, unique_node_id), &balancers.targets(service_name), service_name, unique_node_id).await;
+    }
+}
+
+/// Opciones de `load_balancer node`.
+pub struct NodeOptions {
+    pub max_datagram_bytes: usize,
+    pub backoff: AnnounceBackoff,
+    pub models_path: Option<PathBuf>,
+    pub tool_calling: ToolCallingMode,
+    pub weight: Option<u32>,
+    /// Tope de peticiones por minuto que se anuncia con `MAX_RPM`.
+    pub max_rpm: Option<u32>,
+    /// Dominio de fallo que se anuncia con `DOMAIN`.
+    pub failure_domain: Option<String>,
+    /// Ventana de contexto de todo el nodo que se anuncia con `MAX_CONTEXT`.
+    pub max_context: Option<u64>,
+    /// Etiquetas `clave=valor` que se anuncian con `TAGS`.
+    pub tags: NodeTags,
+    /// Antelación con la que se anuncia el apagado al recibir SIGUSR2.
+    pub drain_lead: Duration,
+    /// Proxy con modo de reserva para cuando el balanceador no responde.
+    pub spool: Option<SpoolOptions>,
+}
+
+pub async fn run_node(balancers: Balancers, options: NodeOptions) -> io::Result<()> {
+    let NodeOptions { max_datagram_bytes, backoff, models_path, tool_calling, weight, max_rpm, failure_domain, max_context, tags, drain_lead, spool } = options;
     let hostname = hostname::get().ok()
         .and_then(|h| h.into_string().ok())
         .unwrap_or_else(|| "unknown-host".to_string());
@@ -67,30 +507,82 @@ pub async fn run_node(balancer_ip: &str, balancer_port: u16) -> io::Result<()> {
         return Ok(());
     }
 
-    let balancer_target = format!("{}:{}", balancer_ip, balancer_port);
+    let platform = Arc::new(OnceLock::new());
+    let detected = platform.clone();
+    tokio::task::spawn_blocking(move || {
+        let found = Platform::detect();
+        info!("Plataforma del nodo: {} {}, acelerador {}, memoria {}.", found.os, found.arch, found.accelerator, found.memory);
+        let _ = detected.set(found);
+    });
+    let (drain_sender, drain) = watch::channel(None);
+    let spool = match spool {
+        Some(spool_options) => {
+            let local = [("lmstudio", &lm_studio_url), ("ollama", &ollama_url)]
+                .into_iter()
+                .filter_map(|(service, url)| Some((service, url.as_ref()?.to_string())))
+                .collect();
+            let ledger = Arc::new(SpoolLedger::default());
+            spool::start(spool_options, local, ledger.clone())?;
+            Some(ledger)
+        }
+        None => None,
+    };
+
+    #[cfg(feature = "mdns")]
+    let mdns_advertiser = {
+        let mut services = Vec::new();
+        if let Some(url) = &lm_studio_url {
+            services.push(("lmstudio", url.as_str()));
+        }
+        if let Some(url) = &ollama_url {
+            services.push(("ollama", url.as_str()));
+        }
+        crate::mdns::MdnsAdvertiser::start(&unique_node_id, &services).map(Arc::new)
+    };
+
+    let options = AnnounceOptions {
+        max_datagram_bytes, backoff, models_path, tool_calling, weight, max_rpm, failure_domain, max_context, tags, platform, drain, spool,
+        #[cfg(feature = "mdns")]
+        mdns: mdns_advertiser.clone(),
+    };
     let mut tasks = vec![];
+    let services: Vec<&'static str> = [("lmstudio", &lm_studio_url), ("ollama", &ollama_url)]
+        .into_iter()
+        .filter_map(|(service, url)| url.is_some().then_some(service))
+        .collect();
 
     if let Some(url) = lm_studio_url {
-        let target = balancer_target.clone();
+        let target = balancers.clone();
         let id_clone = unique_node_id.clone();
+        let options = options.clone();
         tasks.push(tokio::spawn(async move {
-            udp_broadcast_service("lmstudio", &id_clone, &url, target).await
+            udp_broadcast_service("lmstudio", &id_clone, &url, target, options).await
         }));
     }
 
     if let Some(url) = ollama_url {
-        let target = balancer_target.clone();
+        let target = balancers.clone();
         let id_clone = unique_node_id.clone();
+        let options = options.clone();
         tasks.push(tokio::spawn(async move {
-            udp_broadcast_service("ollama", &id_clone, &url, target).await
+            udp_broadcast_service("ollama", &id_clone, &url, target, options).await
         }));
     }
 
     info!("Nodo anunciando servicios con ID {} cada 10 segundos. Presiona Ctrl+C para detener.", unique_node_id);
+    #[cfg(unix)]
+    spawn_drain_signal(drain_sender, drain_lead);
+    #[cfg(not(unix))]
+    let _ = (drain_sender, drain_lead);
 
     match tokio::signal::ctrl_c().await {
         Ok(()) => {
             info!("Cerrando nodo...");
+            send_goodbye(&services, &unique_node_id, &balancers).await;
+            #[cfg(feature = "mdns")]
+            if let Some(advertiser) = mdns_advertiser {
+                advertiser.shutdown();
+            }
         }
         Err(err) => {
             error!("Error al escuchar señal de interrupción: {}", err);
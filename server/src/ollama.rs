@@ -0,0 +1,165 @@
+// src/ollama.rs
+use actix_web::{get, web, HttpResponse, Responder};
+use futures_util::future::join_all;
+use log::{debug, warn};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use url::Url;
+
+use crate::balancer::{AppState, NodeHealth};
+
+/// Tiempo durante el que se reutiliza una respuesta agregada sin volver a consultar los nodos.
+const CACHE_TTL: Duration = Duration::from_secs(5);
+const NODE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Cabecera que marca una respuesta servida desde la caché porque ningún nodo respondió.
+pub const STALE_HEADER: &str = "X-LMServER-Stale";
+
+struct CachedResponse {
+    body: Value,
+    fetched_at: Instant,
+}
+
+/// Cachés de los endpoints nativos de Ollama que el balanceador agrega.
+#[derive(Default)]
+pub struct OllamaCache {
+    tags: Mutex<Option<CachedResponse>>,
+    version: Mutex<Option<CachedResponse>>,
+}
+
+/// Consulta `path` en todos los nodos Ollama no fallidos. Devuelve `(node_id, json)` de los que respondieron.
+async fn query_nodes(state: &AppState, path: &str) -> Vec<(String, Value)> {
+    let targets: Vec<(String, Url)> = state
+        .ollama_nodes
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|(_, info)| !matches!(info.state, NodeHealth::Failed(_)))
+        .map(|(id, info)| (id.to_string(), info.service_url.endpoint(path)))
+        .collect();
+
+    let requests = targets.into_iter().map(|(id, url)| async move {
+        let response = state.client.get(url).timeout(NODE_TIMEOUT).send().await;
+        match response {
+            Ok(response) if response.status().is_success() => response.json::<Value>().await.ok().map(|json| (id, json)),
+            Ok(response) => {
+                debug!("Ollama API: {} respondió {} a {}", id, response.status(), path);
+                None
+            }
+            Err(e) => {
+                debug!("Ollama API: {} no respondió a {}: {}", id, path, e);
+                None
+            }
+        }
+    });
+    join_all(requests).await.into_iter().flatten().collect()
+}
+
+/// Sirve desde la caché si es reciente; si no, agrega de nuevo y, si ningún nodo responde,
+/// recurre a la última respuesta conocida marcándola como obsoleta.
+fn serve_cached<F>(cache: &Mutex<Option<CachedResponse>>, responses: Vec<(String, Value)>, aggregate: F) -> HttpResponse
+where
+    F: FnOnce(Vec<(String, Value)>) -> Value,
+{
+    if responses.is_empty() {
+        return match cache.lock().unwrap().as_ref() {
+            Some(cached) => {
+                warn!("Ollama API: Ningún nodo respondió; sirviendo respuesta en caché de hace {}s.", cached.fetched_at.elapsed().as_secs());
+                HttpResponse::Ok()
+                    .insert_header((STALE_HEADER, cached.fetched_at.elapsed().as_secs().to_string()))
+                    .json(&cached.body)
+            }
+            None => HttpResponse::ServiceUnavailable().json(json!({
+                "error": "No hay nodos Ollama disponibles",
+            })),
+        };
+    }
+    let body = aggregate(responses);
+    *cache.lock().unwrap() = Some(CachedResponse { body: body.clone(), fetched_at: Instant::now() });
+    HttpResponse::Ok().json(body)
+}
+
+fn fresh(cache: &Mutex<Option<CachedResponse>>) -> Option<Value> {
+    cache
+        .lock()
+        .unwrap()
+        .as_ref()
+        .filter(|cached| cached.fetched_at.elapsed() < CACHE_TTL)
+        .map(|cached| cached.body.clone())
+}
+
+/// Une las listas de modelos, deduplicando por nombre+digest y quedándose con el `modified_at` más reciente.
+fn merge_tags(responses: Vec<(String, Value)>) -> Value {
+    let mut merged: HashMap<(String, String), Value> = HashMap::new();
+    for (_, body) in responses {
+        let Some(models) = body.get("models").and_then(Value::as_array) else {
+            continue;
+        };
+        for model in models {
+            let name = model.get("name").and_then(Value::as_str).unwrap_or_default().to_string();
+            let digest = model.get("digest").and_then(Value::as_str).unwrap_or_default().to_string();
+            let modified_at = model.get("modified_at").and_then(Value::as_str).unwrap_or_default();
+            let newer = merged
+                .get(&(name.clone(), digest.clone()))
+                .and_then(|existing| existing.get("modified_at").and_then(Value::as_str))
+                .is_none_or(|existing| modified_at > existing);
+            if newer {
+                merged.insert((name, digest), model.clone());
+            }
+        }
+    }
+    let mut models: Vec<Value> = merged.into_values().collect();
+    models.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+    json!({ "models": models })
+}
+
+fn parse_version(version: &str) -> Vec<u64> {
+    version
+        .trim_start_matches('v')
+        .split(|c: char| !c.is_ascii_digit())
+        .take(3)
+        .map(|part| part.parse().unwrap_or(0))
+        .collect()
+}
+
+/// Versión mínima de la pool (la que limita qué API se puede usar) más el detalle por nodo.
+fn merge_versions(responses: Vec<(String, Value)>) -> Value {
+    let mut nodes: Vec<(String, String)> = responses
+        .into_iter()
+        .filter_map(|(id, body)| body.get("version").and_then(Value::as_str).map(|v| (id, v.to_string())))
+        .collect();
+    nodes.sort();
+    let min_version = nodes
+        .iter()
+        .map(|(_, version)| version)
+        .min_by_key(|version| parse_version(version))
+        .cloned()
+        .unwrap_or_default();
+    json!({
+        "version": min_version,
+        "lmserver_nodes": nodes
+            .into_iter()
+            .map(|(node_id, version)| json!({ "node_id": node_id, "version": version }))
+            .collect::<Vec<_>>(),
+    })
+}
+
+#[get("/api/tags")]
+async fn tags_handler(state: web::Data<AppState>) -> impl Responder {
+    if let Some(body) = fresh(&state.ollama_cache.tags) {
+        return HttpResponse::Ok().json(body);
+    }
+    let responses = query_nodes(&state, "/api/tags").await;
+    serve_cached(&state.ollama_cache.tags, responses, merge_tags)
+}
+
+#[get("/api/version")]
+async fn version_handler(state: web::Data<AppState>) -> impl Responder {
+    if let Some(body) = fresh(&state.ollama_cache.version) {
+        return HttpResponse::Ok().json(body);
+    }
+    let responses = query_nodes(&state, "/api/version").await;
+    serve_cached(&state.ollama_cache.version, responses, merge_versions)
+}
@@ -0,0 +1,1663 @@
+// src/openapi.rs
+//! Documento OpenAPI 3.1 de la API del balanceador (`GET /openapi.json` y `load_balancer openapi`).
+//!
+//! Se mantiene a mano junto a las rutas: `OPERATIONS` tiene una entrada por ruta y método, la
+//! seguridad de cada operación sale de `auth::ROUTES` y las cabeceras se citan por las
+//! constantes de cada módulo, así que no pueden desviarse de lo que hace el código. Los
+//! esquemas llevan ejemplos.
+//!
+//! `check` falla si una ruta de `auth::ROUTES` (donde debe estar toda ruta registrada en la
+//! `App`) no está documentada, si se documenta una ruta que no existe, si un esquema citado no
+//! está definido o si un ejemplo no valida contra su esquema. `load_balancer openapi --check`
+//! lo ejecuta en CI; el balanceador lo avisa al arrancar.
+use actix_web::{get, HttpResponse, Responder};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::OnceLock;
+
+use crate::api_types;
+use crate::auth::{self, Access};
+use crate::build_info;
+use crate::cancel;
+use crate::capacity;
+use crate::headers;
+use crate::idempotency;
+use crate::keys;
+use crate::labels;
+use crate::ollama;
+use crate::pipeline;
+use crate::postprocess;
+use crate::sessions;
+use crate::spill;
+use crate::tags;
+use crate::target;
+use crate::workload;
+
+#[derive(Debug)]
+pub struct OpenApiError(String);
+
+impl fmt::Display for OpenApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for OpenApiError {}
+
+/// Cuerpo de una respuesta.
+#[derive(Clone, Copy)]
+enum Body {
+    /// JSON con el esquema de `components.schemas`.
+    Json(&'static str),
+    /// Texto con este tipo MIME, sin esquema.
+    Text(&'static str),
+    /// Texto alineado o JSON según `Accept`.
+    TextOrJson(&'static str),
+}
+
+struct Response {
+    status: u16,
+    description: &'static str,
+    body: Body,
+    /// Lleva `Retry-After`.
+    retry_after: bool,
+}
+
+const fn ok(description: &'static str, body: Body) -> Response {
+    Response { status: 200, description, body, retry_after: false }
+}
+
+const fn error(status: u16, description: &'static str) -> Response {
+    Response { status, description, body: Body::Json("Error"), retry_after: false }
+}
+
+struct Operation {
+    method: &'static str,
+    path: &'static str,
+    tag: &'static str,
+    summary: &'static str,
+    /// Parámetros de query: (nombre, tipo, descripción).
+    query: &'static [(&'static str, &'static str, &'static str)],
+    /// Esquema del cuerpo JSON y si es obligatorio.
+    body: Option<(&'static str, bool)>,
+    responses: &'static [Response],
+    /// Petición de inferencia reenviada a un nodo: lleva las cabeceras propias del reenvío.
+    proxied: bool,
+}
+
+/// Cabeceras que el balanceador lee en las peticiones de inferencia.
+const PROXY_REQUEST_HEADERS: &[(&str, &str)] = &[
+    (cancel::REQUEST_ID_HEADER, "Id de la petición (ASCII visible, hasta 128 caracteres); se reenvía al nodo. Sin él, el balanceador genera uno."),
+    (workload::WORKLOAD_CLASS_HEADER, "Clase de carga: 'interactive' o 'batch'. La de la API key o una regla manda sobre ella."),
+    (pipeline::PIPELINE_HEADER, "Token de pipeline: las peticiones seguidas con el mismo token reutilizan el nodo."),
+    (sessions::SESSION_HEADER, "ID de sesión: las peticiones de la sesión esperan al nodo que atendió la anterior mientras éste pueda atenderlas."),
+    (idempotency::IDEMPOTENCY_HEADER, "Clave de idempotencia por cliente y pool: un reintento con la misma clave y el mismo cuerpo recibe la respuesta guardada; con otro cuerpo, 422."),
+    (labels::LABELS_HEADER, "Etiquetas de analítica clave=valor separadas por comas; van a la auditoría y al uso diario y nunca se reenvían al nodo."),
+    (target::TARGET_NODE_HEADER, "ID de nodo: la petición espera sólo a ese nodo, sin pasar por el criterio de selección. Para depurar una máquina."),
+    (tags::REQUIRE_TAGS_HEADER, "Etiquetas clave=valor separadas por comas: la petición sólo va a nodos que las tengan todas."),
+];
+
+/// Cabeceras que el balanceador añade a las respuestas de inferencia.
+const PROXY_RESPONSE_HEADERS: &[(&str, &str, &str)] = &[
+    (sessions::NODE_ID_HEADER, "string", "ID del nodo que atendió la petición."),
+    (spill::SERVED_POOL_HEADER, "string", "Pool del nodo que atendió la petición; con --pool-cost o --pool-spillover puede no ser la de la ruta."),
+    (capacity::POOL_AVAILABLE_HEADER, "integer", "Nodos libres en la pool."),
+    (capacity::POOL_BUSY_HEADER, "integer", "Nodos ocupados en la pool."),
+    (capacity::QUEUE_DEPTH_HEADER, "integer", "Peticiones en la cola de la pool."),
+    (capacity::PRESSURE_HEADER, "string", "Presión de la pool: low, medium o high."),
+    (keys::OVERRIDE_HEADER, "string", "Campos que la política de la API key sobrescribió, separados por comas."),
+    (headers::DROPPED_HEADERS_HEADER, "string", "Cabeceras de la petición que no se reenviaron por los límites de tamaño."),
+    (postprocess::POSTPROCESS_HEADER, "string", "Post-procesados aplicados a la respuesta, separados por comas."),
+    (idempotency::REPLAYED_HEADER, "string", "'true' si la respuesta es la guardada para la clave de idempotencia."),
+];
+
+const INFERENCE_RESPONSES: &[Response] = &[
+    ok("Respuesta del nodo: JSON, SSE con stream=true o NDJSON en las rutas nativas de Ollama.", Body::Json("ChatCompletionResponse")),
+    Response { status: 400, description: "Cuerpo vacío, inválido, con campos de primer nivel desconocidos en una pool con --strictness enforce o que no cabe en la ventana de contexto configurada del modelo.", body: Body::Json("OpenAIError"), retry_after: false },
+    Response { status: 403, description: "Modelo no permitido para la API key o petición rechazada por una regla.", body: Body::Json("OpenAIError"), retry_after: false },
+    Response { status: 404, description: "La petición no indica modelo o ningún nodo anuncia el pedido; lista los disponibles. Con X-Target-Node, ninguna pool de la ruta tiene ese nodo; lista los conocidos.", body: Body::Json("OpenAIError"), retry_after: false },
+    Response { status: 422, description: "La petición no cabe en la ventana de contexto de ningún nodo que pueda atenderla.", body: Body::Json("OpenAIError"), retry_after: false },
+    Response { status: 429, description: "Alcanzado el tope de streams simultáneos o el de peticiones en curso del cliente (--max-client-in-flight), éste con Retry-After.", body: Body::Json("OpenAIError"), retry_after: true },
+    Response { status: 501, description: "Ningún nodo tiene la capacidad que exige la petición (tools).", body: Body::Json("OpenAIError"), retry_after: false },
+    Response { status: 502, description: "El nodo devolvió tool_calls mal formados o, en una pool con --strictness enforce, una respuesta sin el mensaje esperado (choices[0].message, o message en las rutas nativas de Ollama).", body: Body::Json("OpenAIError"), retry_after: false },
+    Response { status: 503, description: "Sin nodo libre dentro del tiempo de cola, calentamiento en curso o ningún nodo con las etiquetas de X-Require-Tags.", body: Body::Text("text/plain"), retry_after: true },
+];
+
+const NODE_ID: &str = "Nodo desconocido.";
+
+const OPERATIONS: &[Operation] = &[
+    Operation {
+        method: "get",
+        path: "/",
+        tag: "status",
+        summary: "Estado mínimo: recuentos de nodos por pool y estado, cola y streams.",
+        query: &[],
+        body: None,
+        responses: &[ok("Estado del balanceador.", Body::TextOrJson("IndexStatus"))],
+        proxied: false,
+    },
+    Operation {
+        method: "get",
+        path: "/version",
+        tag: "status",
+        summary: "Versión, commit y features del binario.",
+        query: &[],
+        body: None,
+        responses: &[ok("Versión del balanceador.", Body::Json("BuildInfo"))],
+        proxied: false,
+    },
+    Operation {
+        method: "get",
+        path: "/api/version",
+        tag: "ollama",
+        summary: "Versión de Ollama de los nodos, con la API nativa de Ollama.",
+        query: &[],
+        body: None,
+        responses: &[ok("Versión agregada de los nodos Ollama.", Body::Json("OllamaVersion"))],
+        proxied: false,
+    },
+    Operation {
+        method: "get",
+        path: "/readyz",
+        tag: "status",
+        summary: "Listo para recibir tráfico: 503 durante el calentamiento tras arrancar.",
+        query: &[],
+        body: None,
+        responses: &[
+            ok("Listo.", Body::Json("Readiness")),
+            Response { status: 503, description: "Calentamiento en curso.", body: Body::Json("Readiness"), retry_after: false },
+        ],
+        proxied: false,
+    },
+    Operation {
+        method: "get",
+        path: "/status/public",
+        tag: "status",
+        summary: "Estado agregado sin identificadores, para pantallas públicas.",
+        query: &[],
+        body: None,
+        responses: &[ok("Campos elegidos con --public-status-fields.", Body::TextOrJson("PublicStatus"))],
+        proxied: false,
+    },
+    Operation {
+        method: "get",
+        path: "/openapi.json",
+        tag: "status",
+        summary: "Este documento.",
+        query: &[],
+        body: None,
+        responses: &[ok("Documento OpenAPI 3.1.", Body::Json("OpenApiDocument"))],
+        proxied: false,
+    },
+    Operation {
+        method: "post",
+        path: "/lmstudio",
+        tag: "inference",
+        summary: "Chat completion en la pool de LM Studio.",
+        query: &[],
+        body: Some(("ChatCompletionRequest", true)),
+        responses: INFERENCE_RESPONSES,
+        proxied: true,
+    },
+    Operation {
+        method: "post",
+        path: "/ollama",
+        tag: "inference",
+        summary: "Chat completion en la pool de Ollama.",
+        query: &[],
+        body: Some(("ChatCompletionRequest", true)),
+        responses: INFERENCE_RESPONSES,
+        proxied: true,
+    },
+    Operation {
+        method: "post",
+        path: "/pool/{name}",
+        tag: "inference",
+        summary: "Chat completion en la pool o alias indicado.",
+        query: &[],
+        body: Some(("ChatCompletionRequest", true)),
+        responses: INFERENCE_RESPONSES,
+        proxied: true,
+    },
+    Operation {
+        method: "get",
+        path: "/api/tags",
+        tag: "ollama",
+        summary: "Modelos de todos los nodos Ollama, con la API nativa de Ollama.",
+        query: &[],
+        body: None,
+        responses: &[ok("Unión de los modelos de los nodos; puede venir de la caché (ver la cabecera de antigüedad).", Body::Json("OllamaTags"))],
+        proxied: false,
+    },
+    Operation {
+        method: "get",
+        path: "/nodes",
+        tag: "nodes",
+        summary: "Resumen de los nodos registrados.",
+        query: &[("include", "string", "Con removed, también las lápidas de los nodos que salieron del registro.")],
+        body: None,
+        responses: &[ok("Nodos y revisión del registro.", Body::Json("NodeList"))],
+        proxied: false,
+    },
+    Operation {
+        method: "get",
+        path: "/nodes/watch",
+        tag: "nodes",
+        summary: "Cambios del registro desde una revisión, con espera larga.",
+        query: &[
+            ("since", "integer", "Revisión de partida; 0 devuelve el registro completo."),
+            ("timeout_secs", "integer", "Espera máxima sin cambios."),
+        ],
+        body: None,
+        responses: &[ok("Nodos cambiados, o el registro completo con full=true.", Body::Json("NodeWatch"))],
+        proxied: false,
+    },
+    Operation {
+        method: "get",
+        path: "/nodes/{id}",
+        tag: "nodes",
+        summary: "Detalle de un nodo en cada pool donde está registrado.",
+        query: &[],
+        body: None,
+        responses: &[ok("Detalle del nodo.", Body::Json("NodeDetail")), error(404, NODE_ID)],
+        proxied: false,
+    },
+    Operation {
+        method: "get",
+        path: "/nodes/{id}/history",
+        tag: "nodes",
+        summary: "Transiciones recientes del ciclo de vida de un nodo.",
+        query: &[],
+        body: None,
+        responses: &[ok("Historial del nodo.", Body::Json("NodeHistory")), error(404, "Sin historial para el nodo.")],
+        proxied: false,
+    },
+    Operation {
+        method: "patch",
+        path: "/nodes/{id}/dispatch-rate",
+        tag: "nodes",
+        summary: "Fija el ritmo máximo de despacho de un nodo; null lo devuelve al de su pool.",
+        query: &[],
+        body: Some(("DispatchRate", true)),
+        responses: &[ok("Ritmo aplicado.", Body::Json("Object")), error(400, "Ritmo inválido."), error(404, NODE_ID)],
+        proxied: false,
+    },
+    Operation {
+        method: "post",
+        path: "/nodes/{id}/diagnose",
+        tag: "nodes",
+        summary: "Comprueba la conectividad con un nodo (TCP, HTTP y una completion mínima).",
+        query: &[],
+        body: Some(("DiagnoseRequest", false)),
+        responses: &[
+            ok("Informe del diagnóstico.", Body::Json("DiagnoseReport")),
+            error(404, NODE_ID),
+            Response { status: 429, description: "El nodo se diagnosticó hace poco.", body: Body::Json("Error"), retry_after: true },
+        ],
+        proxied: false,
+    },
+    Operation {
+        method: "post",
+        path: "/nodes/{id}/reprobe",
+        tag: "nodes",
+        summary: "Vuelve a probar las capacidades de un nodo libre, ocupándolo mientras dura la prueba.",
+        query: &[],
+        body: None,
+        responses: &[
+            ok("Resultado de la prueba.", Body::Json("ReprobeReport")),
+            error(404, NODE_ID),
+            error(409, "El nodo no está libre, no anuncia modelos o ya se está probando."),
+        ],
+        proxied: false,
+    },
+    Operation {
+        method: "get",
+        path: "/nodes/{id}/benchmarks",
+        tag: "nodes",
+        summary: "Resultados recientes de las pruebas de rendimiento de un nodo, del más antiguo al más reciente.",
+        query: &[],
+        body: None,
+        responses: &[ok("Serie de pruebas del nodo.", Body::Json("NodeBenchmarks")), error(404, NODE_ID)],
+        proxied: false,
+    },
+    Operation {
+        method: "patch",
+        path: "/nodes/{id}/benchmark",
+        tag: "nodes",
+        summary: "Salta un nodo en las pruebas de rendimiento diarias o lo vuelve a incluir.",
+        query: &[],
+        body: Some(("BenchmarkSettings", true)),
+        responses: &[ok("Ajuste aplicado.", Body::Json("BenchmarkSettings")), error(404, NODE_ID)],
+        proxied: false,
+    },
+    Operation {
+        method: "post",
+        path: "/nodes/{id}/repin",
+        tag: "nodes",
+        summary: "Vuelve a fijar un nodo a una IP o, sin ella, a la de su siguiente anuncio (--node-pinning).",
+        query: &[],
+        body: Some(("RepinRequest", false)),
+        responses: &[
+            ok("Pin aplicado.", Body::Json("RepinResult")),
+            error(400, "Cuerpo inválido."),
+            error(404, "El nodo no está fijado a ninguna IP."),
+            error(409, "La fijación de nodos está desactivada."),
+        ],
+        proxied: false,
+    },
+    Operation {
+        method: "post",
+        path: "/nodes/{id}/remove",
+        tag: "nodes",
+        summary: "Saca un nodo del registro en todas sus pools, dejando su lápida.",
+        query: &[],
+        body: None,
+        responses: &[
+            ok("Lápidas que deja el nodo.", Body::Json("NodeRemoval")),
+            error(404, NODE_ID),
+            error(409, "El nodo está atendiendo una petición."),
+        ],
+        proxied: false,
+    },
+    Operation {
+        method: "get",
+        path: "/events",
+        tag: "nodes",
+        summary: "Eventos del balanceador en tiempo real.",
+        query: &[],
+        body: None,
+        responses: &[ok("Un evento JSON por mensaje SSE.", Body::Text("text/event-stream")), error(503, "Máximo de suscriptores alcanzado.")],
+        proxied: false,
+    },
+    Operation {
+        method: "get",
+        path: "/metrics",
+        tag: "stats",
+        summary: "Métricas en formato de exposición de Prometheus.",
+        query: &[],
+        body: None,
+        responses: &[ok("Métricas.", Body::Text("text/plain; version=0.0.4"))],
+        proxied: false,
+    },
+    Operation {
+        method: "get",
+        path: "/stats/summary",
+        tag: "stats",
+        summary: "Contadores, errores de reenvío y desbordes desde la última puesta a cero.",
+        query: &[],
+        body: None,
+        responses: &[ok("Resumen de estadísticas.", Body::Json("Object"))],
+        proxied: false,
+    },
+    Operation {
+        method: "get",
+        path: "/stats/fairness",
+        tag: "stats",
+        summary: "Reparto de peticiones entre los nodos de cada pool.",
+        query: &[],
+        body: None,
+        responses: &[ok("Reparto por pool y nodo.", Body::Json("Object"))],
+        proxied: false,
+    },
+    Operation {
+        method: "post",
+        path: "/stats/reset",
+        tag: "stats",
+        summary: "Pone a cero los contadores; sin cuerpo, todos.",
+        query: &[],
+        body: Some(("ResetScope", false)),
+        responses: &[ok("Alcance aplicado y nueva época.", Body::Json("ResetResult")), error(400, "Alcance inválido."), error(404, "Pool desconocida.")],
+        proxied: false,
+    },
+    Operation {
+        method: "get",
+        path: "/config",
+        tag: "admin",
+        summary: "Configuración efectiva del balanceador.",
+        query: &[],
+        body: None,
+        responses: &[ok("Configuración.", Body::Json("Object"))],
+        proxied: false,
+    },
+    Operation {
+        method: "post",
+        path: "/admin/profile",
+        tag: "admin",
+        summary: "Fija un perfil o vuelve a los horarios con name=null.",
+        query: &[],
+        body: Some(("ProfileOverride", true)),
+        responses: &[ok("Perfil activo.", Body::Json("ProfileState")), error(404, "Perfil desconocido.")],
+        proxied: false,
+    },
+    Operation {
+        method: "post",
+        path: "/admin/reload",
+        tag: "admin",
+        summary: "Vuelve a leer --rules-file y --profiles-file y los aplica; devuelve el diff de lo que cambió.",
+        query: &[],
+        body: None,
+        responses: &[
+            ok("Revisión aplicada y claves cambiadas.", Body::Json("ConfigReloadResult")),
+            error(400, "Algún archivo es inválido; no se aplica nada."),
+            error(409, "El balanceador arrancó sin archivos que recargar."),
+        ],
+        proxied: false,
+    },
+    Operation {
+        method: "post",
+        path: "/admin/config/rollback",
+        tag: "admin",
+        summary: "Vuelve a aplicar la configuración anterior a la actual.",
+        query: &[],
+        body: None,
+        responses: &[ok("Revisión restaurada y claves cambiadas.", Body::Json("ConfigReloadResult")), error(409, "No queda ninguna configuración anterior.")],
+        proxied: false,
+    },
+    Operation {
+        method: "get",
+        path: "/admin/support-bundle",
+        tag: "admin",
+        summary: "Paquete de soporte: configuración enmascarada, registro, últimas peticiones y eventos, contadores y versión.",
+        query: &[("format", "string", "json (por defecto) o zip, un JSON por sección; zip necesita la feature archive.")],
+        body: None,
+        responses: &[
+            ok("Paquete de soporte como adjunto; con format=zip, application/zip.", Body::Json("SupportBundle")),
+            error(400, "Formato desconocido o zip sin la feature archive."),
+            error(500, "El paquete no cabe en su tope ni recortando."),
+            error(503, "El paquete no se generó a tiempo."),
+        ],
+        proxied: false,
+    },
+    Operation {
+        method: "post",
+        path: "/debug/preview",
+        tag: "debug",
+        summary: "Muestra cómo quedaría una petición tras las políticas, sin enviarla.",
+        query: &[],
+        body: Some(("DebugRequest", true)),
+        responses: &[ok("Petición resultante y cambios.", Body::Json("Object")), error(404, "Pool desconocida.")],
+        proxied: false,
+    },
+    Operation {
+        method: "post",
+        path: "/debug/route",
+        tag: "debug",
+        summary: "Qué regla de enrutado coincidiría con una petición y por qué no las anteriores.",
+        query: &[],
+        body: Some(("DebugRequest", true)),
+        responses: &[ok("Regla elegida y evaluación de cada una.", Body::Json("RouteDebugResult")), error(404, "Pool desconocida.")],
+        proxied: false,
+    },
+    Operation {
+        method: "get",
+        path: "/debug/memory",
+        tag: "debug",
+        summary: "Uso de los almacenes acotados por [limits].",
+        query: &[],
+        body: None,
+        responses: &[ok("Entradas y bytes por almacén.", Body::Json("Object"))],
+        proxied: false,
+    },
+    Operation {
+        method: "get",
+        path: "/audit/export",
+        tag: "usage",
+        summary: "Registro de auditoría en NDJSON, un registro por línea.",
+        query: &[
+            ("from", "string", "Inicio RFC 3339 (incluido)."),
+            ("to", "string", "Fin RFC 3339 (incluido)."),
+        ],
+        body: None,
+        responses: &[
+            ok("Registros de auditoría.", Body::Text("application/x-ndjson")),
+            error(400, "Fecha inválida."),
+            error(404, "Auditoría no habilitada."),
+        ],
+        proxied: false,
+    },
+    Operation {
+        method: "get",
+        path: "/usage/daily",
+        tag: "usage",
+        summary: "Uso diario sumado por API key, pool y modelo.",
+        query: &[
+            ("from", "string", "Primer día AAAA-MM-DD; por defecto, hace 30 días."),
+            ("to", "string", "Último día AAAA-MM-DD; por defecto, hoy."),
+            ("group_by", "string", "label:<clave> para partir cada fila por el valor de esa etiqueta de analítica."),
+        ],
+        body: None,
+        responses: &[ok("Uso por día.", Body::Json("UsageDaily")), error(400, "Fecha o group_by inválidos."), error(404, "Persistencia no habilitada.")],
+        proxied: false,
+    },
+];
+
+/// Esquemas de `components.schemas`.
+fn schemas() -> Map<String, Value> {
+    let string = json!({ "type": "string" });
+    let integer = json!({ "type": "integer", "minimum": 0 });
+    let schemas = json!({
+        "Object": { "type": "object", "description": "Objeto JSON; su forma puede crecer entre versiones." },
+        "Error": {
+            "type": "object",
+            "required": ["error"],
+            "properties": {
+                "error": string,
+                "missing_scope": { "type": "string", "description": "Scope que faltaba, en los 401/403 de autorización." },
+                "retry_after_secs": integer,
+            },
+        },
+        "OpenAIError": {
+            "type": "object",
+            "required": ["error"],
+            "properties": {
+                "error": {
+                    "type": "object",
+                    "required": ["message", "type"],
+                    "properties": {
+                        "message": string,
+                        "type": string,
+                        "param": { "type": ["string", "null"] },
+                        "available_models": { "type": "array", "items": string, "description": "Modelos anunciados por los nodos, en los 404 model_not_found." },
+                    },
+                },
+            },
+        },
+        "ChatMessage": {
+            "type": "object",
+            "required": ["role"],
+            "properties": {
+                "role": { "enum": ["system", "developer", "user", "assistant", "tool", "function"] },
+                "content": { "type": ["string", "array", "null"] },
+            },
+        },
+        "ChatCompletionRequest": {
+            "type": "object",
+            "description": "Petición OpenAI o nativa de Ollama. Los campos que el balanceador no conoce se reenvían tal cual.",
+            "required": ["messages"],
+            "properties": {
+                "model": string,
+                "messages": { "type": "array", "items": { "$ref": "#/components/schemas/ChatMessage" } },
+                "temperature": { "type": "number" },
+                "top_p": { "type": "number" },
+                "max_tokens": integer,
+                "n": integer,
+                "stream": { "type": "boolean" },
+                "presence_penalty": { "type": "number" },
+                "frequency_penalty": { "type": "number" },
+                "tools": { "type": "array" },
+            },
+        },
+        "ChatCompletionResponse": {
+            "type": "object",
+            "description": "Respuesta del nodo tal cual (OpenAI con `choices`/`usage` u Ollama nativa con `message`).",
+            "properties": {
+                "choices": { "type": "array" },
+                "usage": {
+                    "type": "object",
+                    "properties": { "prompt_tokens": integer, "completion_tokens": integer, "total_tokens": integer },
+                },
+                "message": { "type": "object" },
+            },
+        },
+        "BuildInfo": {
+            "type": "object",
+            "required": ["version", "git_commit", "build_timestamp", "features"],
+            "properties": {
+                "version": string,
+                "git_commit": string,
+                "build_timestamp": string,
+                "features": { "type": "array", "items": string },
+            },
+        },
+        "Readiness": {
+            "type": "object",
+            "required": ["ready"],
+            "properties": { "ready": { "type": "boolean" }, "warmup": { "type": "object" } },
+        },
+        "PoolCounts": {
+            "type": "object",
+            "description": "Nodos por estado en cada pool.",
+            "additionalProperties": { "type": "object", "additionalProperties": integer },
+        },
+        "IndexStatus": {
+            "type": "object",
+            "required": ["version", "uptime_secs", "queued_requests", "active_streams", "pools"],
+            "properties": {
+                "version": string,
+                "uptime_secs": integer,
+                "queued_requests": integer,
+                "active_streams": integer,
+                "pools": { "$ref": "#/components/schemas/PoolCounts" },
+                "workload": {
+                    "type": "object",
+                    "description": "Con --reserve-interactive: nodos que puede ocupar cada clase de carga.",
+                    "additionalProperties": {
+                        "type": "object",
+                        "required": ["reserved", "effective_reserved", "interactive", "batch", "batch_busy"],
+                        "properties": {
+                            "reserved": integer,
+                            "effective_reserved": integer,
+                            "interactive": integer,
+                            "batch": integer,
+                            "batch_busy": integer,
+                        },
+                    },
+                },
+                "interleave": {
+                    "type": "object",
+                    "description": "Con --short-dispatch-share: peticiones cortas y largas en espera y en nodos, y despachos que se deben a las cortas.",
+                    "additionalProperties": {
+                        "type": "object",
+                        "required": ["short_waiting", "long_waiting", "short_busy", "long_busy", "short_credit"],
+                        "properties": {
+                            "short_waiting": integer,
+                            "long_waiting": integer,
+                            "short_busy": integer,
+                            "long_busy": integer,
+                            "short_credit": { "type": "number" },
+                        },
+                    },
+                },
+            },
+        },
+        "PublicStatus": {
+            "type": "object",
+            "description": "Sólo trae los campos de --public-status-fields.",
+            "properties": {
+                "version": string,
+                "uptime_secs": integer,
+                "requests_per_minute": { "type": "number" },
+                "latency_p95_ms": { "type": ["integer", "null"] },
+                "queued_requests": integer,
+                "active_streams": integer,
+                "pools": { "$ref": "#/components/schemas/PoolCounts" },
+            },
+        },
+        "OpenApiDocument": {
+            "type": "object",
+            "required": ["openapi", "info", "paths"],
+            "properties": { "openapi": string, "info": { "type": "object" }, "paths": { "type": "object" } },
+        },
+        "OllamaTags": {
+            "type": "object",
+            "required": ["models"],
+            "properties": { "models": { "type": "array", "items": { "type": "object" } } },
+        },
+        "OllamaVersion": {
+            "type": "object",
+            "properties": { "version": string },
+        },
+        "ResetScope": {
+            "type": "object",
+            "required": ["scope"],
+            "properties": {
+                "scope": { "enum": ["all", "pool", "node"] },
+                "pool": { "type": "string", "description": "Con scope=pool." },
+                "node": { "type": "string", "description": "Con scope=node." },
+            },
+        },
+        "ResetResult": {
+            "type": "object",
+            "required": ["scope", "stats"],
+            "properties": {
+                "scope": string,
+                "nodes_reset": { "type": ["array", "null"], "items": string },
+                "stats": { "type": "object" },
+            },
+        },
+        "ProfileOverride": {
+            "type": "object",
+            "properties": { "name": { "type": ["string", "null"] } },
+        },
+        "ProfileState": {
+            "type": "object",
+            "required": ["active_profile", "pinned"],
+            "properties": { "active_profile": string, "pinned": { "type": "boolean" } },
+        },
+        "ConfigReloadResult": {
+            "type": "object",
+            "required": ["revision", "changes"],
+            "properties": {
+                "revision": { "type": "integer" },
+                "changes": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["key", "change"],
+                        "properties": {
+                            "key": string,
+                            "change": { "type": "string", "enum": ["added", "removed", "changed"] },
+                            "old": { "description": "Valor anterior; falta en las claves añadidas." },
+                            "new": { "description": "Valor nuevo; falta en las claves quitadas." },
+                        },
+                    },
+                },
+            },
+        },
+        "SupportBundle": {
+            "type": "object",
+            "required": ["schema_version", "generated_at", "uptime_secs", "build", "config", "registry", "recent_requests", "events", "stats", "discovery", "memory", "truncated"],
+            "properties": {
+                "schema_version": integer,
+                "generated_at": string,
+                "uptime_secs": integer,
+                "build": { "$ref": "#/components/schemas/BuildInfo" },
+                "config": { "type": "object", "description": "Como /config, con los secretos enmascarados." },
+                "registry": { "$ref": "#/components/schemas/NodeList" },
+                "recent_requests": { "type": "array", "items": { "$ref": "#/components/schemas/RecentRequest" } },
+                "events": { "type": "array", "items": { "type": "object", "required": ["at", "type"] }, "description": "Eventos de /events con el instante en at." },
+                "stats": { "type": "object", "description": "Como /stats/summary." },
+                "discovery": { "type": "array", "items": { "type": "object" } },
+                "memory": { "type": "array", "items": { "type": "object" } },
+                "truncated": {
+                    "type": "object",
+                    "additionalProperties": integer,
+                    "description": "Listas recortadas para caber en el tope (puntero JSON) y cuántas entradas quedan.",
+                },
+            },
+        },
+        "RecentRequest": {
+            "type": "object",
+            "required": ["at", "request_id", "pool", "stream", "body_bytes", "status", "duration_ms"],
+            "properties": {
+                "at": string,
+                "request_id": string,
+                "pool": string,
+                "api_key": { "type": ["string", "null"], "description": "Enmascarada." },
+                "model": { "type": ["string", "null"] },
+                "stream": { "type": "boolean" },
+                "body_bytes": integer,
+                "status": integer,
+                "node_id": { "type": ["string", "null"] },
+                "served_pool": { "type": ["string", "null"] },
+                "duration_ms": { "type": "integer", "minimum": 0, "description": "Hasta las cabeceras de la respuesta." },
+            },
+        },
+        "DebugRequest": {
+            "type": "object",
+            "required": ["pool", "request"],
+            "properties": {
+                "pool": string,
+                "api_key": { "type": ["string", "null"] },
+                "headers": { "type": "object", "additionalProperties": string },
+                "request": { "type": "object" },
+            },
+        },
+        "RouteDebugResult": {
+            "type": "object",
+            "required": ["pool", "final_pool", "evaluated"],
+            "properties": {
+                "pool": string,
+                "api_key_policy": { "type": ["string", "null"] },
+                "matched_rule": { "type": ["string", "null"] },
+                "action": {},
+                "final_pool": { "type": ["string", "null"] },
+                "evaluated": { "type": "array", "items": { "type": "object" } },
+                "slow_lane": {
+                    "type": "object",
+                    "description": "Clasificación del carril lento: slow o normal, el umbral superado, el carril y los umbrales en vigor.",
+                },
+            },
+        },
+        "DailyUsage": {
+            "type": "object",
+            "required": ["day", "service", "requests", "prompt_tokens", "completion_tokens", "total_tokens", "estimated"],
+            "properties": {
+                "day": string,
+                "api_key": { "type": ["string", "null"] },
+                "service": string,
+                "model": { "type": ["string", "null"] },
+                "requests": integer,
+                "prompt_tokens": integer,
+                "completion_tokens": integer,
+                "total_tokens": integer,
+                "estimated": { "type": "boolean", "description": "Tokens estimados por el balanceador: el nodo no mandó usage en su stream." },
+                "labels": { "type": "object", "additionalProperties": string, "description": "Con group_by=label:<clave>, el valor de esa etiqueta; falta en las filas sin ella." },
+            },
+        },
+        "UsageDaily": {
+            "type": "object",
+            "required": ["from", "to", "days"],
+            "properties": {
+                "from": string,
+                "to": string,
+                "group_by": string,
+                "days": { "type": "array", "items": { "$ref": "#/components/schemas/DailyUsage" } },
+            },
+        },
+    });
+    // El registro de nodos va aparte: en un solo `json!` se pasa del límite de recursión de macros.
+    let nodes = json!({
+        "NodeSummary": {
+            "type": "object",
+            "required": ["node_id", "service", "service_url", "state", "source"],
+            "properties": {
+                "node_id": string,
+                "service": string,
+                "service_url": string,
+                "state": { "enum": ["available", "busy", "loading", "failed", "draining"] },
+                "source": { "enum": ["announced", "local", "local-auto"] },
+                "version": { "type": ["string", "null"] },
+                "low_disk": { "type": "boolean" },
+                "dispatch_rate": {},
+                "rpm": {
+                    "type": ["object", "null"],
+                    "description": "Tope de peticiones por minuto (--max-rpm en el nodo o --node-max-rpm, el menor) y las despachadas en el último minuto; null si no tiene.",
+                    "required": ["max_rpm", "used"],
+                    "properties": { "max_rpm": { "type": "integer", "minimum": 1 }, "used": { "type": "integer", "minimum": 0 } },
+                },
+                "canary": {
+                    "type": ["object", "null"],
+                    "description": "Fase canaria en curso (--canary-secs, --canary-requests): recibe sólo --canary-percent de las peticiones que podría atender. null fuera de ella.",
+                    "required": ["elapsed_secs", "requests", "errors"],
+                    "properties": {
+                        "elapsed_secs": { "type": "integer", "minimum": 0 },
+                        "requests": { "type": "integer", "minimum": 0 },
+                        "errors": { "type": "integer", "minimum": 0 },
+                    },
+                },
+                "capabilities": { "type": "array", "items": string },
+                "backend_version": { "type": ["string", "null"] },
+                "in_flight": { "type": "integer", "minimum": 0 },
+                "capabilities_stale": { "type": "boolean" },
+                "weight": { "type": "integer", "minimum": 1 },
+                "domain": { "type": ["string", "null"], "description": "Dominio de fallo (rack, PDU, switch), anunciado con --failure-domain o fijado con --node-domain." },
+                "latency_ms": { "type": ["integer", "null"], "description": "Media móvil de lo que tardan sus respuestas; null hasta la primera." },
+                "drain_secs": { "type": ["integer", "null"], "description": "Segundos hasta que sale del registro, si anunció su apagado (draining)." },
+                "pinned_ip": { "type": ["string", "null"], "description": "IP a la que está fijado su ID con --node-pinning." },
+                "tags": { "type": "object", "additionalProperties": string, "description": "Etiquetas clave=valor anunciadas con --tag, para X-Require-Tags." },
+                "last_model": { "type": ["string", "null"], "description": "Último model que atendió, para --warm-model-window-secs." },
+                "local": { "type": "boolean", "description": "Su IP de origen está en un --local-prefix: se prueba antes que los remotos." },
+                "platform": {
+                    "type": "object",
+                    "description": "Plataforma anunciada por el nodo; unknown en lo que no pudo averiguar.",
+                    "required": ["os", "arch", "accelerator", "memory"],
+                    "properties": {
+                        "os": string,
+                        "arch": string,
+                        "accelerator": { "type": "string", "examples": ["metal", "cuda", "rocm", "unknown"] },
+                        "memory": { "type": "string", "examples": ["unified", "discrete", "unknown"] },
+                        "memory_bytes": { "type": ["integer", "null"] },
+                    },
+                },
+            },
+        },
+        "NodeList": {
+            "type": "object",
+            "required": ["revision", "nodes"],
+            "properties": {
+                "balancer_version": string,
+                "revision": integer,
+                "nodes": { "type": "array", "items": { "$ref": "#/components/schemas/NodeSummary" } },
+                "discovery_listeners": { "type": "array" },
+                "removed": { "type": "array", "items": { "$ref": "#/components/schemas/Tombstone" }, "description": "Con include=removed." },
+            },
+        },
+        "Tombstone": {
+            "type": "object",
+            "required": ["tombstone_id", "node_id", "service", "reason", "removed_at", "final_state", "stats"],
+            "properties": {
+                "tombstone_id": string,
+                "node_id": string,
+                "service": string,
+                "service_url": string,
+                "source": string,
+                "reason": { "enum": ["stale", "goodbye", "admin_delete", "evicted", "replaced", "drained"] },
+                "removed_at": string,
+                "final_state": { "enum": ["available", "busy", "loading", "failed", "draining"] },
+                "version": { "type": ["string", "null"] },
+                "backend_version": { "type": ["string", "null"] },
+                "models": { "type": "array", "items": string },
+                "last_error": { "type": ["string", "null"] },
+                "stats": {
+                    "type": "object",
+                    "required": ["registered_at", "dispatched", "failures", "spooled"],
+                    "properties": { "registered_at": string, "dispatched": integer, "failures": integer, "spooled": integer },
+                },
+            },
+        },
+        "NodeRemoval": {
+            "type": "object",
+            "required": ["node_id", "removed"],
+            "properties": {
+                "node_id": string,
+                "removed": { "type": "array", "items": { "$ref": "#/components/schemas/Tombstone" } },
+            },
+        },
+        "NodeWatch": {
+            "type": "object",
+            "required": ["revision", "full", "nodes"],
+            "properties": {
+                "revision": integer,
+                "full": { "type": "boolean" },
+                "nodes": { "type": "array", "items": { "type": "object" } },
+            },
+        },
+        "NodeDetail": {
+            "type": "object",
+            "required": ["node_id"],
+            "properties": { "node_id": string },
+        },
+        "NodeHistory": {
+            "type": "object",
+            "required": ["node_id", "transitions"],
+            "properties": { "node_id": string, "transitions": { "type": "array", "items": { "type": "object" } } },
+        },
+        "DispatchRate": {
+            "type": ["object", "null"],
+            "required": ["per_second"],
+            "properties": { "per_second": { "type": "number" }, "burst": integer },
+        },
+        "DiagnoseRequest": {
+            "type": "object",
+            "properties": { "model": { "type": "string", "description": "Por defecto, el primer modelo que anuncia el nodo." } },
+        },
+        "DiagnoseReport": {
+            "type": "object",
+            "required": ["node_id", "service", "state", "tcp", "http", "completion", "recent_errors"],
+            "properties": {
+                "node_id": string,
+                "service": string,
+                "service_url": string,
+                "state": string,
+                "timestamp": string,
+                "elapsed_ms": integer,
+                "tcp": { "type": "object" },
+                "http": { "type": "object" },
+                "completion": { "type": "object" },
+                "recent_errors": { "type": "array" },
+            },
+        },
+        "ReprobeReport": {
+            "type": "object",
+            "required": ["node_id", "service", "model", "capabilities", "changed", "stale"],
+            "properties": {
+                "node_id": string,
+                "service": string,
+                "backend_version": { "type": ["string", "null"] },
+                "model": string,
+                "elapsed_ms": integer,
+                "capabilities": { "type": "array", "items": string },
+                "changed": { "type": "boolean" },
+                "stale": { "type": "boolean", "description": "La versión del backend cambió y ninguna prueba ha tenido éxito desde entonces." },
+                "error": { "type": ["string", "null"] },
+            },
+        },
+        "BenchmarkRun": {
+            "type": "object",
+            "required": ["node_id", "service", "model", "started_at", "prompts", "latency_ms", "tokens_per_sec", "regressed"],
+            "properties": {
+                "node_id": string,
+                "service": string,
+                "model": string,
+                "started_at": string,
+                "prompts": integer,
+                "latency_ms": { "type": "integer", "description": "Mediana de la duración de cada prompt." },
+                "tokens_per_sec": { "type": ["number", "null"] },
+                "regressed": { "type": "boolean" },
+            },
+        },
+        "NodeBenchmarks": {
+            "type": "object",
+            "required": ["node_id", "skip", "running", "runs"],
+            "properties": {
+                "node_id": string,
+                "skip": { "type": "boolean" },
+                "running": { "type": "boolean" },
+                "runs": { "type": "array", "items": { "$ref": "#/components/schemas/BenchmarkRun" } },
+            },
+        },
+        "BenchmarkSettings": {
+            "type": "object",
+            "required": ["skip"],
+            "properties": { "skip": { "type": "boolean" } },
+        },
+        "RepinRequest": {
+            "type": "object",
+            "properties": { "ip": { "type": ["string", "null"], "description": "Sin ella, el siguiente anuncio del nodo fija su IP." } },
+        },
+        "RepinResult": {
+            "type": "object",
+            "required": ["node_id", "previous_ip", "ip"],
+            "properties": { "node_id": string, "previous_ip": { "type": ["string", "null"] }, "ip": { "type": ["string", "null"] } },
+        },
+    });
+    match (schemas, nodes) {
+        (Value::Object(mut schemas), Value::Object(nodes)) => {
+            schemas.extend(nodes);
+            schemas
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Ejemplos de cada esquema; `check` los valida y el documento los publica.
+fn examples() -> Vec<(&'static str, Value)> {
+    vec![
+        ("Error", json!({ "error": "Falta un token Bearer válido con el scope 'nodes:write'.", "missing_scope": "nodes:write" })),
+        ("OpenAIError", json!({ "error": { "message": "El cuerpo de la petición está vacío; se esperaba un JSON.", "type": "invalid_request_error", "param": null } })),
+        (
+            "ChatCompletionRequest",
+            json!({ "model": "llama3:8b", "messages": [{ "role": "user", "content": "Hola" }], "max_tokens": 64, "stream": false }),
+        ),
+        (
+            "ChatCompletionResponse",
+            json!({
+                "choices": [{ "index": 0, "message": { "role": "assistant", "content": "¡Hola!" }, "finish_reason": "stop" }],
+                "usage": { "prompt_tokens": 5, "completion_tokens": 2, "total_tokens": 7 },
+            }),
+        ),
+        ("BuildInfo", json!({ "version": "0.1.0", "git_commit": "3cc67e0bcd82", "build_timestamp": "2026-10-16T09:00:00Z", "features": ["sqlite"] })),
+        ("Readiness", json!({ "ready": true })),
+        (
+            "IndexStatus",
+            json!({
+                "version": "0.1.0 (3cc67e0bcd82)",
+                "uptime_secs": 3600,
+                "queued_requests": 2,
+                "active_streams": 1,
+                "pools": { "ollama": { "available": 1, "busy": 1, "loading": 0, "failed": 0, "draining": 0 } },
+                "workload": { "ollama": { "reserved": 1, "effective_reserved": 1, "interactive": 2, "batch": 1, "batch_busy": 1 } },
+                "interleave": { "ollama": { "short_waiting": 3, "long_waiting": 2, "short_busy": 0, "long_busy": 2, "short_credit": 0.5 } },
+            }),
+        ),
+        (
+            "PublicStatus",
+            json!({ "uptime_secs": 3600, "requests_per_minute": 12.5, "latency_p95_ms": 840, "pools": { "ollama": { "available": 2, "busy": 0 } } }),
+        ),
+        ("OllamaTags", json!({ "models": [{ "name": "llama3:8b" }] })),
+        ("OllamaVersion", json!({ "version": "0.3.12" })),
+        (
+            "NodeList",
+            json!({
+                "balancer_version": "0.1.0 (3cc67e0bcd82)",
+                "revision": 42,
+                "nodes": [{
+                    "node_id": "gpu-01",
+                    "service": "ollama",
+                    "service_url": "http://10.0.0.5:11434/api/chat",
+                    "state": "available",
+                    "source": "announced",
+                    "version": "0.1.0",
+                    "low_disk": false,
+                    "dispatch_rate": null,
+                    "capabilities": ["tool-calling"],
+                    "backend_version": "0.5.7",
+                    "in_flight": 0,
+                    "capabilities_stale": false,
+                    "weight": 1,
+                    "domain": "rack-a",
+                    "latency_ms": 840,
+                    "platform": { "os": "linux", "arch": "x86_64", "accelerator": "cuda", "memory": "discrete", "memory_bytes": 68719476736u64 },
+                }],
+                "discovery_listeners": [],
+                "removed": [{
+                    "tombstone_id": "6f1c2d3e-8a9b-4c5d-9e0f-1a2b3c4d5e6f",
+                    "node_id": "gpu-02",
+                    "service": "ollama",
+                    "service_url": "http://10.0.0.6:11434/api/chat",
+                    "source": "announced",
+                    "reason": "stale",
+                    "removed_at": "2026-10-16T09:30:00+02:00",
+                    "final_state": "failed",
+                    "version": "0.1.0",
+                    "backend_version": "0.5.7",
+                    "models": ["llama3:8b"],
+                    "last_error": "connection refused",
+                    "stats": { "registered_at": "2026-10-16T08:00:00+02:00", "dispatched": 118, "failures": 1, "spooled": 0 },
+                }],
+            }),
+        ),
+        ("NodeWatch", json!({ "revision": 43, "full": false, "nodes": [{ "node_id": "gpu-01", "service": "ollama", "removed": true }] })),
+        ("NodeHistory", json!({ "node_id": "gpu-01", "transitions": [] })),
+        (
+            "NodeRemoval",
+            json!({
+                "node_id": "gpu-03",
+                "removed": [{
+                    "tombstone_id": "0b7e4c1a-2f3d-4e5a-8b6c-7d8e9f0a1b2c",
+                    "node_id": "gpu-03",
+                    "service": "lmstudio",
+                    "reason": "admin_delete",
+                    "removed_at": "2026-10-16T10:00:00+02:00",
+                    "final_state": "available",
+                    "models": [],
+                    "stats": { "registered_at": "2026-10-15T18:00:00+02:00", "dispatched": 7, "failures": 0, "spooled": 0 },
+                }],
+            }),
+        ),
+        ("DispatchRate", json!({ "per_second": 2.0, "burst": 1 })),
+        ("DiagnoseRequest", json!({ "model": "llama3:8b" })),
+        (
+            "DiagnoseReport",
+            json!({
+                "node_id": "gpu-01",
+                "service": "ollama",
+                "service_url": "http://10.0.0.5:11434/api/chat",
+                "state": "available",
+                "elapsed_ms": 412,
+                "tcp": { "ok": true, "connect_ms": 1 },
+                "http": { "status": 200, "latency_ms": 8 },
+                "completion": { "status": 200, "ttfb_ms": 380 },
+                "recent_errors": [],
+            }),
+        ),
+        (
+            "ReprobeReport",
+            json!({
+                "node_id": "gpu-01",
+                "service": "ollama",
+                "backend_version": "0.5.7",
+                "model": "llama3.1:8b",
+                "elapsed_ms": 950,
+                "capabilities": ["tool-calling"],
+                "changed": true,
+                "stale": false,
+                "error": null,
+            }),
+        ),
+        (
+            "NodeBenchmarks",
+            json!({
+                "node_id": "gpu-01",
+                "skip": false,
+                "running": false,
+                "runs": [{
+                    "node_id": "gpu-01",
+                    "service": "ollama",
+                    "model": "llama3.1:8b",
+                    "started_at": "2026-10-16T03:00:00Z",
+                    "prompts": 3,
+                    "latency_ms": 1840,
+                    "tokens_per_sec": 34.8,
+                    "regressed": false,
+                }],
+            }),
+        ),
+        ("BenchmarkSettings", json!({ "skip": true })),
+        ("RepinRequest", json!({ "ip": "10.0.0.17" })),
+        ("RepinResult", json!({ "node_id": "gpu-01", "previous_ip": "10.0.0.5", "ip": "10.0.0.17" })),
+        ("ResetScope", json!({ "scope": "pool", "pool": "ollama" })),
+        ("ResetResult", json!({ "scope": "pool ollama", "nodes_reset": ["gpu-01"], "stats": { "epoch": 3 } })),
+        ("ProfileOverride", json!({ "name": "night" })),
+        ("ProfileState", json!({ "active_profile": "night", "pinned": true })),
+        (
+            "ConfigReloadResult",
+            json!({
+                "revision": 4,
+                "changes": [
+                    { "key": "profiles.night.settings.queue_timeout_secs", "change": "changed", "old": 600, "new": 900 },
+                    { "key": "rules.sql-a-ollama.action.route", "change": "added", "new": "ollama" },
+                ],
+            }),
+        ),
+        (
+            "SupportBundle",
+            json!({
+                "schema_version": 1,
+                "generated_at": "2026-10-16T09:30:00Z",
+                "uptime_secs": 5400,
+                "build": { "version": "0.1.0", "git_commit": "3cc67e0bcd82", "build_timestamp": "2026-10-16T09:00:00Z", "features": ["archive"] },
+                "config": { "active_profile": "default", "pinned": false },
+                "registry": { "balancer_version": "0.1.0 (3cc67e0bcd82)", "revision": 12, "nodes": [], "discovery_listeners": [], "removed": [] },
+                "recent_requests": [{
+                    "at": "2026-10-16T09:29:58Z",
+                    "request_id": "5f0c2f7e-1b7c-4f43-9d55-2f6d3e0a9b11",
+                    "pool": "ollama",
+                    "api_key": "sk-...a1b2",
+                    "model": "llama3:8b",
+                    "stream": false,
+                    "body_bytes": 118,
+                    "status": 200,
+                    "node_id": "gpu-01",
+                    "served_pool": "ollama",
+                    "duration_ms": 840,
+                }],
+                "events": [{ "at": "2026-10-16T09:29:00Z", "type": "pool_recovered", "service": "ollama" }],
+                "stats": {},
+                "discovery": [{ "bind_addr": "0.0.0.0:9999", "packets": 310, "registered": 3, "rejected_pool": 0, "malformed": 0 }],
+                "memory": [{ "name": "recent_requests", "cap": 200, "entries": 1, "bytes": 296 }],
+                "truncated": {},
+            }),
+        ),
+        (
+            "DebugRequest",
+            json!({ "pool": "ollama", "api_key": null, "headers": { "X-Job-Id": "42" }, "request": { "messages": [{ "role": "user", "content": "Hola" }] } }),
+        ),
+        (
+            "RouteDebugResult",
+            json!({
+                "pool": "ollama",
+                "api_key_policy": null,
+                "matched_rule": "resumenes-en-lote",
+                "action": { "classify": "batch" },
+                "final_pool": "ollama",
+                "evaluated": [{ "rule": "resumenes-en-lote", "matched": true }],
+                "slow_lane": { "enabled": false },
+            }),
+        ),
+        (
+            "UsageDaily",
+            json!({
+                "from": "2026-10-01",
+                "to": "2026-10-16",
+                "days": [{
+                    "day": "2026-10-16",
+                    "api_key": "sk-r…9f",
+                    "service": "ollama",
+                    "model": "llama3:8b",
+                    "requests": 3,
+                    "prompt_tokens": 15,
+                    "completion_tokens": 6,
+                    "total_tokens": 21,
+                    "estimated": false,
+                }],
+            }),
+        ),
+    ]
+}
+
+/// Parámetros `{nombre}` de la ruta.
+fn path_parameters(path: &str) -> Vec<Value> {
+    path.split('/')
+        .filter_map(|segment| segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')))
+        .map(|name| json!({ "name": name, "in": "path", "required": true, "schema": { "type": "string" } }))
+        .collect()
+}
+
+fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+/// Esquema JSON de una respuesta; bajo `/v1`, dentro del sobre de `api_types`.
+fn json_schema(schema: &str, enveloped: bool) -> Value {
+    if !enveloped {
+        return schema_ref(schema);
+    }
+    json!({
+        "type": "object",
+        "required": ["api_version", "data"],
+        "additionalProperties": false,
+        "properties": { "api_version": { "enum": [api_types::API_VERSION] }, "data": schema_ref(schema) },
+    })
+}
+
+fn content(body: Body, enveloped: bool) -> Value {
+    match body {
+        Body::Json(schema) => json!({ "application/json": { "schema": json_schema(schema, enveloped) } }),
+        Body::Text(mime) => json!({ mime: { "schema": { "type": "string" } } }),
+        Body::TextOrJson(schema) => json!({
+            "text/plain": { "schema": { "type": "string" } },
+            "application/json": { "schema": json_schema(schema, enveloped) },
+        }),
+    }
+}
+
+/// Operación en `path`: su ruta, o la misma con el prefijo `/v1`.
+fn operation(op: &Operation, path: &str) -> Value {
+    let enveloped = path != op.path;
+    let access = auth::route_access(op.path);
+    let (security, scope) = match access {
+        Some(Access::Public) | None => (json!([]), None),
+        Some(Access::Inference) => (json!([{}, { "bearer": [] }]), None),
+        Some(Access::Read(scope)) => (json!([{}, { "bearer": [] }]), Some(scope)),
+        Some(Access::Protected(scope)) => (json!([{ "bearer": [] }]), Some(scope)),
+    };
+
+    let mut parameters = path_parameters(path);
+    parameters.extend(op.query.iter().map(|(name, kind, description)| {
+        json!({ "name": name, "in": "query", "required": false, "description": description, "schema": { "type": kind } })
+    }));
+    if op.proxied {
+        parameters.extend(PROXY_REQUEST_HEADERS.iter().map(|(name, description)| {
+            json!({ "name": name, "in": "header", "required": false, "description": description, "schema": { "type": "string" } })
+        }));
+    }
+
+    let mut responses = Map::new();
+    for response in op.responses {
+        let mut entry = json!({ "description": response.description, "content": content(response.body, enveloped) });
+        let mut headers = Map::new();
+        if response.retry_after {
+            headers.insert("Retry-After".to_string(), json!({ "description": "Segundos antes de reintentar.", "schema": { "type": "integer" } }));
+        }
+        if op.proxied && response.status == 200 {
+            for (name, kind, description) in PROXY_RESPONSE_HEADERS {
+                headers.insert(name.to_string(), json!({ "description": description, "schema": { "type": kind } }));
+            }
+        }
+        if op.path == "/api/tags" && response.status == 200 {
+            headers.insert(
+                ollama::STALE_HEADER.to_string(),
+                json!({ "description": "Segundos desde que se obtuvo la respuesta en caché, si ningún nodo respondió.", "schema": { "type": "integer" } }),
+            );
+        }
+        if !headers.is_empty() {
+            entry["headers"] = Value::Object(headers);
+        }
+        responses.insert(response.status.to_string(), entry);
+    }
+    if let Some(scope) = scope {
+        let description = format!("Falta un token con el scope '{}'.", scope);
+        responses.entry("401").or_insert_with(|| json!({ "description": description, "content": content(Body::Json("Error"), enveloped) }));
+        responses.entry("403").or_insert_with(|| json!({ "description": description, "content": content(Body::Json("Error"), enveloped) }));
+    }
+
+    let mut operation = json!({
+        "operationId": operation_id(op.method, path),
+        "tags": [op.tag],
+        "summary": op.summary,
+        "security": security,
+        "responses": responses,
+    });
+    if !parameters.is_empty() {
+        operation["parameters"] = Value::Array(parameters);
+    }
+    if let Some((schema, required)) = op.body {
+        operation["requestBody"] = json!({ "required": required, "content": content(Body::Json(schema), false) });
+    }
+    let mut notes = Vec::new();
+    if let Some(scope) = scope {
+        operation["x-required-scope"] = json!(scope);
+        if matches!(access, Some(Access::Read(_))) {
+            notes.push("Abierta salvo con --protect-read-endpoints.".to_string());
+        }
+    }
+    if !enveloped && api_types::is_versioned(op.path) {
+        operation["deprecated"] = json!(true);
+        notes.push(format!("Obsoleta: usa {}{}, que responde dentro del sobre {{api_version, data}}.", api_types::PREFIX, op.path));
+    }
+    if !notes.is_empty() {
+        operation["description"] = json!(notes.join(" "));
+    }
+    operation
+}
+
+/// `get /nodes/{id}/history` → `get_nodes_id_history`.
+fn operation_id(method: &str, path: &str) -> String {
+    let path: Vec<&str> = path
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect();
+    if path.is_empty() {
+        format!("{}_index", method)
+    } else {
+        format!("{}_{}", method, path.join("_"))
+    }
+}
+
+fn build() -> Value {
+    let mut paths = Map::new();
+    for op in OPERATIONS {
+        let entry = paths.entry(op.path).or_insert_with(|| json!({}));
+        entry[op.method] = operation(op, op.path);
+        if api_types::is_versioned(op.path) {
+            let versioned = format!("{}{}", api_types::PREFIX, op.path);
+            let entry = paths.entry(versioned.clone()).or_insert_with(|| json!({}));
+            entry[op.method] = operation(op, &versioned);
+        }
+    }
+    let mut schemas = schemas();
+    for (name, example) in examples() {
+        if let Some(Value::Object(schema)) = schemas.get_mut(name) {
+            schema.insert("examples".to_string(), json!([example]));
+        }
+    }
+    let tags: BTreeSet<&str> = OPERATIONS.iter().map(|op| op.tag).collect();
+    json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": "lmServer",
+            "version": build_info::VERSION,
+            "description": "API del balanceador: inferencia repartida entre nodos LM Studio y Ollama, registro de nodos, estadísticas y administración.",
+        },
+        "tags": tags.iter().map(|tag| json!({ "name": tag })).collect::<Vec<_>>(),
+        "paths": paths,
+        "components": {
+            "schemas": schemas,
+            "securitySchemes": {
+                "bearer": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "description": "--admin-token, --audit-token o una API key de --api-keys-file. `x-required-scope` indica el scope que exige cada operación.",
+                },
+            },
+        },
+    })
+}
+
+/// Documento completo; se construye una vez.
+pub fn document() -> &'static Value {
+    static DOCUMENT: OnceLock<Value> = OnceLock::new();
+    DOCUMENT.get_or_init(build)
+}
+
+/// Valida `value` contra `schema` con el subconjunto de JSON Schema que usan los esquemas de
+/// arriba: `$ref`, `type`, `enum`, `required`, `properties`, `additionalProperties` e `items`.
+fn validate(value: &Value, schema: &Value, schemas: &Map<String, Value>, at: &str, problems: &mut Vec<String>) {
+    if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+        match reference.strip_prefix("#/components/schemas/").and_then(|name| schemas.get(name)) {
+            Some(target) => validate(value, target, schemas, at, problems),
+            None => problems.push(format!("{}: referencia desconocida {}", at, reference)),
+        }
+        return;
+    }
+    if let Some(kind) = schema.get("type") {
+        let kinds: Vec<&str> = match kind {
+            Value::String(kind) => vec![kind.as_str()],
+            Value::Array(kinds) => kinds.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        let matches = kinds.iter().any(|kind| match *kind {
+            "object" => value.is_object(),
+            "array" => value.is_array(),
+            "string" => value.is_string(),
+            "integer" => value.is_i64() || value.is_u64(),
+            "number" => value.is_number(),
+            "boolean" => value.is_boolean(),
+            "null" => value.is_null(),
+            _ => false,
+        });
+        if !matches {
+            problems.push(format!("{}: se esperaba {}, es {}", at, kind, value));
+            return;
+        }
+    }
+    if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+        if !allowed.contains(value) {
+            problems.push(format!("{}: {} no es uno de {}", at, value, Value::Array(allowed.clone())));
+        }
+    }
+    if let Some(minimum) = schema.get("minimum").and_then(Value::as_f64) {
+        if value.as_f64().is_some_and(|number| number < minimum) {
+            problems.push(format!("{}: {} es menor que {}", at, value, minimum));
+        }
+    }
+    if let Value::Object(object) = value {
+        for field in schema.get("required").and_then(Value::as_array).into_iter().flatten().filter_map(Value::as_str) {
+            if !object.contains_key(field) {
+                problems.push(format!("{}: falta el campo obligatorio '{}'", at, field));
+            }
+        }
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for (field, field_value) in object {
+            let field_at = format!("{}.{}", at, field);
+            match (properties.and_then(|properties| properties.get(field)), schema.get("additionalProperties")) {
+                (Some(field_schema), _) => validate(field_value, field_schema, schemas, &field_at, problems),
+                (None, Some(Value::Bool(false))) => problems.push(format!("{}: campo no permitido", field_at)),
+                (None, Some(extra)) if extra.is_object() => validate(field_value, extra, schemas, &field_at, problems),
+                (None, _) => {}
+            }
+        }
+    }
+    if let (Value::Array(items), Some(item_schema)) = (value, schema.get("items")) {
+        for (index, item) in items.iter().enumerate() {
+            validate(item, item_schema, schemas, &format!("{}[{}]", at, index), problems);
+        }
+    }
+}
+
+/// Rutas de la tabla sin documentar, operaciones sin ruta y operaciones repetidas.
+fn route_problems(routes: &[(&str, Access)], operations: &[Operation]) -> Vec<String> {
+    let mut problems = Vec::new();
+    for (route, _) in routes {
+        if !operations.iter().any(|op| op.path == *route) {
+            problems.push(format!("La ruta {} no está documentada en openapi::OPERATIONS.", route));
+        }
+    }
+    let mut seen = BTreeSet::new();
+    for op in operations {
+        if !routes.iter().any(|(route, _)| *route == op.path) {
+            problems.push(format!("{} {} está documentada pero no aparece en auth::ROUTES.", op.method, op.path));
+        }
+        if !seen.insert((op.method, op.path)) {
+            problems.push(format!("{} {} está documentada dos veces.", op.method, op.path));
+        }
+    }
+    problems
+}
+
+/// Comprueba que el documento cubre `auth::ROUTES` y que los ejemplos validan.
+pub fn check() -> Result<(), OpenApiError> {
+    let mut problems = route_problems(auth::ROUTES, OPERATIONS);
+
+    let schemas = schemas();
+    let cited = OPERATIONS.iter().flat_map(|op| {
+        op.body.map(|(schema, _)| schema).into_iter().chain(op.responses.iter().filter_map(|response| match response.body {
+            Body::Json(schema) | Body::TextOrJson(schema) => Some(schema),
+            Body::Text(_) => None,
+        }))
+    });
+    for schema in cited.collect::<BTreeSet<_>>() {
+        if !schemas.contains_key(schema) {
+            problems.push(format!("El esquema {} no está definido.", schema));
+        }
+    }
+
+    for (name, example) in examples() {
+        match schemas.get(name) {
+            Some(schema) => validate(&example, schema, &schemas, name, &mut problems),
+            None => problems.push(format!("Ejemplo de un esquema que no existe: {}.", name)),
+        }
+        // Contrato: el ejemplo debe leerse con el tipo compartido, sin campos de más.
+        if let Some(Err(e)) = api_types::check_example(name, &example) {
+            problems.push(format!("{}: el ejemplo no casa con api_types: {}", name, e));
+        }
+    }
+    // El propio documento es la respuesta de una operación.
+    validate(document(), &schema_ref("OpenApiDocument"), &schemas, "OpenApiDocument", &mut problems);
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(OpenApiError(problems.join("\n")))
+    }
+}
+
+#[get("/openapi.json")]
+async fn openapi_handler() -> impl Responder {
+    HttpResponse::Ok().json(document())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::dev::{ServiceRequest, ServiceResponse};
+    use actix_web::http::Method;
+    use actix_web::middleware::{from_fn, Next};
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+
+    use crate::balancer;
+    use crate::testing;
+
+    /// Lo mismo que `load_balancer openapi --check` en CI.
+    #[test]
+    fn document_covers_every_route_and_examples_validate() {
+        if let Err(e) = check() {
+            panic!("{}", e);
+        }
+    }
+
+    #[test]
+    fn undocumented_unrouted_and_repeated_operations_are_reported() {
+        let op = |method, path| Operation { method, path, tag: "t", summary: "", query: &[], body: None, responses: &[], proxied: false };
+        let operations = [op("get", "/a"), op("post", "/a"), op("get", "/c"), op("get", "/c")];
+        let problems = route_problems(&[("/a", Access::Public), ("/b", Access::Public), ("/c", Access::Public)], &operations);
+        assert_eq!(problems, [
+            "La ruta /b no está documentada en openapi::OPERATIONS.",
+            "get /c está documentada dos veces.",
+        ]);
+        let problems = route_problems(&[("/a", Access::Public)], &operations[..2]);
+        assert!(problems.is_empty(), "{:?}", problems);
+        let problems = route_problems(&[], &operations[..1]);
+        assert_eq!(problems, ["get /a está documentada pero no aparece en auth::ROUTES."]);
+    }
+
+    /// Responde con el patrón de la ruta que actix eligió, sin llegar a ningún handler.
+    async fn matched_pattern(req: ServiceRequest, _: Next<impl actix_web::body::MessageBody>) -> Result<ServiceResponse, actix_web::Error> {
+        let pattern = req.match_pattern().unwrap_or_default();
+        Ok(req.into_response(HttpResponse::Ok().body(pattern)))
+    }
+
+    /// Cada operación documentada llega a una ruta registrada con ese mismo patrón.
+    #[actix_web::test]
+    async fn every_documented_operation_is_registered_in_the_app() {
+        let app = init_service(balancer::app(testing::state(&[])).wrap(from_fn(matched_pattern))).await;
+        for op in OPERATIONS {
+            let path: Vec<&str> = op.path.split('/').map(|segment| if segment.starts_with('{') { "box1" } else { segment }).collect();
+            let req = TestRequest::default().method(Method::from_bytes(op.method.to_uppercase().as_bytes()).unwrap()).uri(&path.join("/"));
+            let resp = call_service(&app, req.to_request()).await;
+            let pattern = actix_web::test::read_body(resp).await;
+            assert_eq!(pattern, op.path.as_bytes(), "{} {} no está registrada en la App", op.method, op.path);
+        }
+    }
+
+    #[actix_web::test]
+    async fn document_is_served_with_every_versioned_alias() {
+        let app = init_service(balancer::app(testing::state(&[]))).await;
+        let served: Value = read_body_json(call_service(&app, TestRequest::get().uri("/openapi.json").to_request()).await).await;
+        assert_eq!(&served, document());
+        let paths = served["paths"].as_object().unwrap();
+        for op in OPERATIONS {
+            assert!(paths[op.path][op.method].is_object(), "{} {}", op.method, op.path);
+            let versioned = format!("{}{}", api_types::PREFIX, op.path);
+            assert_eq!(paths.contains_key(&versioned), api_types::is_versioned(op.path), "{}", versioned);
+        }
+        assert_eq!(served["components"]["securitySchemes"]["bearer"]["scheme"], "bearer");
+    }
+
+    #[test]
+    fn validator_reports_each_kind_of_mismatch() {
+        let schemas: Map<String, Value> = std::iter::once((
+            "Node".to_string(),
+            json!({
+                "type": "object",
+                "required": ["id", "state"],
+                "additionalProperties": false,
+                "properties": {
+                    "id": { "type": "string" },
+                    "state": { "enum": ["available", "busy"] },
+                    "weight": { "type": "integer", "minimum": 1 },
+                    "models": { "type": "array", "items": { "type": "string" } },
+                    "platform": { "$ref": "#/components/schemas/Nada" },
+                },
+            }),
+        ))
+        .collect();
+        let problems = |value: Value| {
+            let mut problems = Vec::new();
+            validate(&value, &schema_ref("Node"), &schemas, "Node", &mut problems);
+            problems
+        };
+        assert!(problems(json!({ "id": "box1", "state": "busy", "weight": 2, "models": ["m"] })).is_empty());
+        assert_eq!(problems(json!({ "id": 1, "state": "busy" })), ["Node.id: se esperaba \"string\", es 1"]);
+        assert_eq!(problems(json!({ "state": "busy" })), ["Node: falta el campo obligatorio 'id'"]);
+        assert_eq!(problems(json!({ "id": "a", "state": "off" })), ["Node.state: \"off\" no es uno de [\"available\",\"busy\"]"]);
+        assert_eq!(problems(json!({ "id": "a", "state": "busy", "weight": 0 })), ["Node.weight: 0 es menor que 1"]);
+        assert_eq!(problems(json!({ "id": "a", "state": "busy", "models": [1] })), ["Node.models[0]: se esperaba \"string\", es 1"]);
+        assert_eq!(problems(json!({ "id": "a", "state": "busy", "extra": true })), ["Node.extra: campo no permitido"]);
+        assert_eq!(problems(json!({ "id": "a", "state": "busy", "platform": {} })), ["Node.platform: referencia desconocida #/components/schemas/Nada"]);
+        assert_eq!(problems(json!([])), ["Node: se esperaba \"object\", es []"]);
+    }
+}
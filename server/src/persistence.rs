@@ -0,0 +1,843 @@
+// src/persistence.rs
+//! Persistencia del balanceador: el registro de auditoría de peticiones, el uso diario por
+//! API key, la última instantánea del registro de nodos y la serie de pruebas de rendimiento
+//! (`benchmark`).
+//!
+//! Todo pasa por el trait `Persistence`, así que un almacén nuevo (Postgres, p.ej.) no toca el
+//! resto del balanceador. Se elige con `--persistence`:
+//!
+//! - `json` (por defecto): ficheros en `--state-dir` — `audit.ndjson` (o `--audit-file`),
+//!   `usage.ndjson`, `registry.json` y `benchmarks.ndjson`. Sólo anexa líneas y reescribe la instantánea con un
+//!   rename, así que vale en un NFS. Con sólo `--audit-file` guarda únicamente la auditoría.
+//! - `sqlite` (feature `sqlite`): una base `lmserver.db` en `--state-dir`.
+//!
+//! `Store` envuelve el almacén elegido: sus errores se registran en el log y nunca cambian la
+//! respuesta de una petición.
+use actix_web::web::{self, Bytes};
+use actix_web::{get, HttpResponse, Responder};
+use chrono::{DateTime, Days, NaiveDate, Utc};
+use futures_util::stream::{self, Stream};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::time::sleep;
+
+use crate::audit::{self, AuditRecord};
+use crate::benchmark::BenchmarkRun;
+use crate::balancer::AppState;
+use crate::labels::RequestLabels;
+use crate::pins::NodePin;
+
+/// Cada cuánto se guarda la instantánea del registro, si ha cambiado.
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Días que devuelve `GET /usage/daily` sin `from`.
+const DEFAULT_USAGE_DAYS: u64 = 30;
+
+#[derive(Debug)]
+pub struct PersistenceError(pub(crate) String);
+
+impl fmt::Display for PersistenceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for PersistenceError {}
+
+impl From<io::Error> for PersistenceError {
+    fn from(e: io::Error) -> Self {
+        PersistenceError(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for PersistenceError {
+    fn from(e: serde_json::Error) -> Self {
+        PersistenceError(e.to_string())
+    }
+}
+
+/// Almacén de `--persistence`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum PersistenceBackend {
+    /// Ficheros NDJSON y JSON.
+    Json,
+    /// Base SQLite (exige compilar con la feature `sqlite`).
+    Sqlite,
+}
+
+/// Un nodo tal como estaba registrado al guardar la instantánea.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SnapshotNode {
+    pub service: String,
+    pub node_id: String,
+    pub service_url: String,
+    pub source: String,
+    pub models: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RegistrySnapshot {
+    pub saved_at: DateTime<Utc>,
+    pub nodes: Vec<SnapshotNode>,
+    /// IP fijada a cada ID de nodo (`pins`); las instantáneas anteriores no la traen.
+    #[serde(default)]
+    pub pins: BTreeMap<String, NodePin>,
+}
+
+/// Uso de un día por (API key, pool, modelo, etiquetas), con las peticiones de tokens
+/// estimados (`usage`) en una fila aparte. Cada petición anexa uno con `requests: 1`; los
+/// resúmenes diarios son su suma.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DailyUsage {
+    pub day: NaiveDate,
+    /// Forma enmascarada de la key, como en la auditoría.
+    pub api_key: Option<String>,
+    pub service: String,
+    pub model: Option<String>,
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+    /// Los tokens de la fila son estimaciones; los registros anteriores no lo traen y son exactos.
+    #[serde(default)]
+    pub estimated: bool,
+    /// Etiquetas de analítica (`labels`); en `/usage/daily`, sólo la de `group_by`.
+    #[serde(default, skip_serializing_if = "RequestLabels::is_empty")]
+    pub labels: RequestLabels,
+}
+
+type UsageKey = (NaiveDate, Option<String>, String, Option<String>, bool, RequestLabels);
+
+impl DailyUsage {
+    /// Uso que aporta una petición auditada; sin tokens en el registro cuenta la petición sin tokens.
+    pub fn from_record(record: &AuditRecord) -> Self {
+        Self {
+            day: record.timestamp.date_naive(),
+            api_key: record.api_key.clone(),
+            service: record.service.clone(),
+            model: record.model.clone(),
+            requests: 1,
+            prompt_tokens: record.prompt_tokens.unwrap_or(0),
+            completion_tokens: record.completion_tokens.unwrap_or(0),
+            total_tokens: record.total_tokens.unwrap_or(0),
+            estimated: record.estimated,
+            labels: record.labels.clone(),
+        }
+    }
+
+    fn key(&self) -> UsageKey {
+        (self.day, self.api_key.clone(), self.service.clone(), self.model.clone(), self.estimated, self.labels.clone())
+    }
+
+    fn add(&mut self, delta: &DailyUsage) {
+        self.requests += delta.requests;
+        self.prompt_tokens += delta.prompt_tokens;
+        self.completion_tokens += delta.completion_tokens;
+        self.total_tokens += delta.total_tokens;
+    }
+}
+
+/// Suma los incrementos de uso con día en `[from, to]`, ordenados por día, key, pool, modelo,
+/// exactos antes que estimados y etiquetas.
+pub fn sum_usage(deltas: impl IntoIterator<Item = DailyUsage>, from: NaiveDate, to: NaiveDate) -> Vec<DailyUsage> {
+    let mut days: BTreeMap<UsageKey, DailyUsage> = BTreeMap::new();
+    for delta in deltas.into_iter().filter(|delta| delta.day >= from && delta.day <= to) {
+        match days.get_mut(&delta.key()) {
+            Some(day) => day.add(&delta),
+            None => {
+                days.insert(delta.key(), delta);
+            }
+        }
+    }
+    days.into_values().collect()
+}
+
+/// Vuelve a sumar el uso quedándose sólo con la etiqueta `label` de cada fila, o con ninguna.
+/// Las filas sin esa etiqueta se suman aparte, sin `labels`.
+pub fn group_by_label(days: Vec<DailyUsage>, label: Option<&str>) -> Vec<DailyUsage> {
+    let regrouped = days.into_iter().map(|mut day| {
+        day.labels.retain(|key, _| Some(key.as_str()) == label);
+        day
+    });
+    sum_usage(regrouped, NaiveDate::MIN, NaiveDate::MAX)
+}
+
+/// Registros de auditoría exportados, una línea NDJSON por elemento.
+pub type RecordStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>;
+
+/// Operaciones que el balanceador necesita de un almacén. Las llamadas bloquean: los handlers
+/// que leen mucho (`export_requests`, `daily_summaries`) las hacen en `web::block`.
+pub trait Persistence: Send + Sync {
+    /// Tipo y ubicación del almacén, para `/config` y el log.
+    fn describe(&self) -> serde_json::Value;
+    /// Sustituye la instantánea guardada del registro.
+    fn save_registry(&self, snapshot: &RegistrySnapshot) -> Result<(), PersistenceError>;
+    /// Última instantánea guardada, si hay alguna.
+    fn load_registry(&self) -> Result<Option<RegistrySnapshot>, PersistenceError>;
+    /// Anexa un registro de auditoría. Los registros escritos no se modifican nunca.
+    fn append_request(&self, record: &AuditRecord) -> Result<(), PersistenceError>;
+    /// Suma `delta` al uso de su día.
+    fn append_usage(&self, delta: &DailyUsage) -> Result<(), PersistenceError>;
+    /// Uso por día, key, pool, modelo, estimación y etiquetas entre `from` y `to`, ambos incluidos.
+    fn daily_summaries(&self, from: NaiveDate, to: NaiveDate) -> Result<Vec<DailyUsage>, PersistenceError>;
+    /// Registros de auditoría con `timestamp` dentro de `[from, to]`, en orden de escritura.
+    fn export_requests(&self, from: Option<DateTime<Utc>>, to: Option<DateTime<Utc>>) -> Result<RecordStream, PersistenceError>;
+    /// Anexa el resultado de una prueba de rendimiento.
+    fn append_benchmark(&self, run: &BenchmarkRun) -> Result<(), PersistenceError>;
+    /// Las `limit` últimas pruebas del nodo, de la más antigua a la más reciente.
+    fn benchmarks(&self, unique_node_id: &str, limit: usize) -> Result<Vec<BenchmarkRun>, PersistenceError>;
+}
+
+/// El almacén configurado. Los errores de escritura sólo se registran en el log.
+pub struct Store {
+    backend: Box<dyn Persistence>,
+}
+
+impl Store {
+    /// Abre el almacén de `backend`. `None` si la configuración no pide persistir nada.
+    pub fn open(backend: PersistenceBackend, state_dir: Option<&Path>, audit_file: Option<&Path>) -> Result<Option<Self>, PersistenceError> {
+        let backend: Box<dyn Persistence> = match backend {
+            PersistenceBackend::Json => {
+                if state_dir.is_none() && audit_file.is_none() {
+                    return Ok(None);
+                }
+                Box::new(JsonFileStore::open(state_dir, audit_file)?)
+            }
+            PersistenceBackend::Sqlite => {
+                if audit_file.is_some() {
+                    return Err(PersistenceError(
+                        "--audit-file es del almacén json; con --persistence sqlite la auditoría va a la base de --state-dir".to_string(),
+                    ));
+                }
+                let Some(state_dir) = state_dir else {
+                    return Err(PersistenceError("--persistence sqlite exige --state-dir".to_string()));
+                };
+                open_sqlite(state_dir)?
+            }
+        };
+        Ok(Some(Self { backend }))
+    }
+
+    pub fn describe(&self) -> serde_json::Value {
+        self.backend.describe()
+    }
+
+    /// Anexa el registro de auditoría de una petición y suma su uso.
+    pub fn record(&self, record: &AuditRecord) {
+        if let Err(e) = self.backend.append_request(record) {
+            error!("Persistencia: No se pudo anexar el registro de auditoría: {}", e);
+        }
+        if let Err(e) = self.backend.append_usage(&DailyUsage::from_record(record)) {
+            error!("Persistencia: No se pudo anotar el uso: {}", e);
+        }
+    }
+
+    pub fn save_registry(&self, snapshot: &RegistrySnapshot) {
+        match self.backend.save_registry(snapshot) {
+            Ok(()) => debug!("Persistencia: Instantánea del registro guardada ({} nodos).", snapshot.nodes.len()),
+            Err(e) => error!("Persistencia: No se pudo guardar la instantánea del registro: {}", e),
+        }
+    }
+
+    pub fn load_registry(&self) -> Option<RegistrySnapshot> {
+        self.backend.load_registry().unwrap_or_else(|e| {
+            warn!("Persistencia: No se pudo leer la instantánea del registro: {}. Se ignora.", e);
+            None
+        })
+    }
+
+    pub fn daily_summaries(&self, from: NaiveDate, to: NaiveDate) -> Result<Vec<DailyUsage>, PersistenceError> {
+        self.backend.daily_summaries(from, to)
+    }
+
+    pub fn export_requests(&self, from: Option<DateTime<Utc>>, to: Option<DateTime<Utc>>) -> Result<RecordStream, PersistenceError> {
+        self.backend.export_requests(from, to)
+    }
+
+    pub fn record_benchmark(&self, run: &BenchmarkRun) {
+        if let Err(e) = self.backend.append_benchmark(run) {
+            error!("Persistencia: No se pudo guardar la prueba de rendimiento del nodo {}: {}", run.node_id, e);
+        }
+    }
+
+    /// Serie de pruebas del nodo; vacía si no se puede leer.
+    pub fn benchmarks(&self, unique_node_id: &str, limit: usize) -> Vec<BenchmarkRun> {
+        self.backend.benchmarks(unique_node_id, limit).unwrap_or_else(|e| {
+            warn!("Persistencia: No se pudieron leer las pruebas de rendimiento del nodo {}: {}", unique_node_id, e);
+            Vec::new()
+        })
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn open_sqlite(state_dir: &Path) -> Result<Box<dyn Persistence>, PersistenceError> {
+    fs::create_dir_all(state_dir)?;
+    Ok(Box::new(crate::sqlite::SqliteStore::open(&state_dir.join(crate::sqlite::DATABASE_FILE))?))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn open_sqlite(_state_dir: &Path) -> Result<Box<dyn Persistence>, PersistenceError> {
+    Err(PersistenceError("Este binario no incluye SQLite; compílalo con --features sqlite".to_string()))
+}
+
+/// Fichero de sólo anexado con una línea JSON por registro.
+struct NdjsonFile {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl NdjsonFile {
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { path: path.to_path_buf(), file: Mutex::new(file) })
+    }
+
+    fn append<T: Serialize>(&self, value: &T) -> Result<(), PersistenceError> {
+        let mut line = serde_json::to_vec(value)?;
+        line.push(b'\n');
+        self.file
+            .lock()
+            .unwrap()
+            .write_all(&line)
+            .map_err(|e| PersistenceError(format!("{}: {}", self.path.display(), e)))
+    }
+}
+
+/// Almacén `json`: la auditoría en NDJSON y, con `--state-dir`, el uso, la instantánea y las
+/// pruebas de rendimiento.
+pub struct JsonFileStore {
+    audit: NdjsonFile,
+    usage: Option<NdjsonFile>,
+    registry_path: Option<PathBuf>,
+    benchmarks: Option<NdjsonFile>,
+}
+
+impl JsonFileStore {
+    fn open(state_dir: Option<&Path>, audit_file: Option<&Path>) -> io::Result<Self> {
+        if let Some(state_dir) = state_dir {
+            fs::create_dir_all(state_dir)?;
+        }
+        let audit_path = match (audit_file, state_dir) {
+            (Some(path), _) => path.to_path_buf(),
+            (None, Some(state_dir)) => state_dir.join("audit.ndjson"),
+            (None, None) => unreachable!("Store::open no abre el almacén json sin ruta"),
+        };
+        Ok(Self {
+            audit: NdjsonFile::open(&audit_path)?,
+            usage: state_dir.map(|dir| NdjsonFile::open(&dir.join("usage.ndjson"))).transpose()?,
+            registry_path: state_dir.map(|dir| dir.join("registry.json")),
+            benchmarks: state_dir.map(|dir| NdjsonFile::open(&dir.join("benchmarks.ndjson"))).transpose()?,
+        })
+    }
+}
+
+impl Persistence for JsonFileStore {
+    fn describe(&self) -> serde_json::Value {
+        json!({
+            "backend": "json",
+            "audit_file": self.audit.path,
+            "usage_file": self.usage.as_ref().map(|usage| &usage.path),
+            "registry_file": self.registry_path,
+            "benchmarks_file": self.benchmarks.as_ref().map(|benchmarks| &benchmarks.path),
+        })
+    }
+
+    fn save_registry(&self, snapshot: &RegistrySnapshot) -> Result<(), PersistenceError> {
+        let Some(path) = &self.registry_path else {
+            return Ok(());
+        };
+        // Escribe aparte y renombra: quien lea nunca ve una instantánea a medias.
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(snapshot)?)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    fn load_registry(&self) -> Result<Option<RegistrySnapshot>, PersistenceError> {
+        let Some(path) = &self.registry_path else {
+            return Ok(None);
+        };
+        match fs::read(path) {
+            Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn append_request(&self, record: &AuditRecord) -> Result<(), PersistenceError> {
+        self.audit.append(record)
+    }
+
+    fn append_usage(&self, delta: &DailyUsage) -> Result<(), PersistenceError> {
+        match &self.usage {
+            Some(usage) => usage.append(delta),
+            None => Ok(()),
+        }
+    }
+
+    fn daily_summaries(&self, from: NaiveDate, to: NaiveDate) -> Result<Vec<DailyUsage>, PersistenceError> {
+        let Some(usage) = &self.usage else {
+            return Err(PersistenceError("El uso diario sólo se guarda con --state-dir.".to_string()));
+        };
+        let deltas = io::BufReader::new(File::open(&usage.path)?)
+            .lines()
+            .map_while(Result::ok)
+            .filter_map(|line| serde_json::from_str::<DailyUsage>(&line).ok());
+        Ok(sum_usage(deltas, from, to))
+    }
+
+    fn export_requests(&self, from: Option<DateTime<Utc>>, to: Option<DateTime<Utc>>) -> Result<RecordStream, PersistenceError> {
+        let file = tokio::fs::File::from_std(File::open(&self.audit.path)?);
+        let lines = BufReader::new(file).lines();
+        Ok(Box::pin(stream::unfold(lines, move |mut lines| async move {
+            loop {
+                match lines.next_line().await {
+                    Ok(Some(line)) => {
+                        if in_range(&line, from, to) {
+                            return Some((Ok(Bytes::from(line + "\n")), lines));
+                        }
+                    }
+                    Ok(None) => return None,
+                    Err(e) => return Some((Err(e), lines)),
+                }
+            }
+        })))
+    }
+
+    fn append_benchmark(&self, run: &BenchmarkRun) -> Result<(), PersistenceError> {
+        match &self.benchmarks {
+            Some(benchmarks) => benchmarks.append(run),
+            None => Ok(()),
+        }
+    }
+
+    fn benchmarks(&self, unique_node_id: &str, limit: usize) -> Result<Vec<BenchmarkRun>, PersistenceError> {
+        let Some(benchmarks) = &self.benchmarks else {
+            return Ok(Vec::new());
+        };
+        let mut runs: Vec<BenchmarkRun> = io::BufReader::new(File::open(&benchmarks.path)?)
+            .lines()
+            .map_while(Result::ok)
+            .filter_map(|line| serde_json::from_str::<BenchmarkRun>(&line).ok())
+            .filter(|run| run.node_id == unique_node_id)
+            .collect();
+        runs.drain(..runs.len().saturating_sub(limit));
+        Ok(runs)
+    }
+}
+
+fn in_range(line: &str, from: Option<DateTime<Utc>>, to: Option<DateTime<Utc>>) -> bool {
+    #[derive(Deserialize)]
+    struct Timestamped {
+        timestamp: DateTime<Utc>,
+    }
+    match serde_json::from_str::<Timestamped>(line) {
+        Ok(record) => from.is_none_or(|from| record.timestamp >= from) && to.is_none_or(|to| record.timestamp <= to),
+        Err(_) => false,
+    }
+}
+
+/// Copia el registro actual para guardarlo.
+pub fn registry_snapshot(state: &AppState) -> RegistrySnapshot {
+    let mut nodes = Vec::new();
+    for (_, service, lock) in state.pools() {
+        for (id, info) in lock.read().unwrap().iter() {
+            nodes.push(SnapshotNode {
+                service: service.to_string(),
+                node_id: id.to_string(),
+                service_url: info.service_url.to_string(),
+                source: info.source.label().to_string(),
+                models: info.models.clone(),
+            });
+        }
+    }
+    nodes.sort_by(|a, b| (&a.service, &a.node_id).cmp(&(&b.service, &b.node_id)));
+    RegistrySnapshot { saved_at: Utc::now(), nodes, pins: state.pins.snapshot() }
+}
+
+/// Guarda la instantánea si el registro cambió desde `last_revision`. Sin cambios desde el
+/// arranque no se toca: se conserva la de la ejecución anterior.
+pub fn save_if_changed(state: &AppState, last_revision: &mut u64) {
+    let Some(store) = &state.persistence else {
+        return;
+    };
+    let revision = state.revisions.current();
+    if revision == *last_revision {
+        return;
+    }
+    store.save_registry(&registry_snapshot(state));
+    *last_revision = revision;
+}
+
+/// Guarda la instantánea del registro periódicamente.
+pub async fn run(state: web::Data<AppState>) {
+    if state.persistence.is_none() {
+        return;
+    }
+    let mut last_revision = 0;
+    loop {
+        sleep(SNAPSHOT_INTERVAL).await;
+        save_if_changed(&state, &mut last_revision);
+    }
+}
+
+/// Instantánea anterior, para el calentamiento y los pines.
+pub fn previous_registry(store: Option<&Store>) -> Option<RegistrySnapshot> {
+    let snapshot = store?.load_registry()?;
+    info!(
+        "Persistencia: La instantánea del registro de {} tenía {} nodos.",
+        snapshot.saved_at.to_rfc3339(),
+        snapshot.nodes.len()
+    );
+    Some(snapshot)
+}
+
+/// Prefijo de `group_by` para sumar por una etiqueta de analítica.
+const GROUP_BY_LABEL: &str = "label:";
+
+#[derive(Deserialize)]
+struct UsageQuery {
+    from: Option<String>,
+    to: Option<String>,
+    group_by: Option<String>,
+}
+
+/// Uso diario sumado por API key, pool y modelo, con los tokens estimados en filas aparte
+/// (`estimated: true`). `from`/`to` son fechas AAAA-MM-DD; por defecto, los últimos 30 días.
+/// Con `group_by=label:<clave>` cada fila se parte además por el valor de esa etiqueta.
+#[get("/usage/daily")]
+async fn usage_daily_handler(state: web::Data<AppState>, query: web::Query<UsageQuery>) -> impl Responder {
+    if state.persistence.is_none() {
+        return HttpResponse::NotFound().json(json!({
+            "error": "La persistencia no está habilitada (--state-dir o --audit-file).",
+        }));
+    }
+    let mut bounds = [None, None];
+    for (bound, value) in bounds.iter_mut().zip([&query.from, &query.to]) {
+        if let Some(value) = value {
+            match audit::parse_bound(value) {
+                Some(parsed) => *bound = Some(parsed.date_naive()),
+                None => {
+                    return HttpResponse::BadRequest().json(json!({
+                        "error": format!("Fecha inválida '{}'. Usa AAAA-MM-DD.", value),
+                    }));
+                }
+            }
+        }
+    }
+    let group_label = match query.group_by.as_deref() {
+        None => None,
+        Some(group_by) => match group_by.strip_prefix(GROUP_BY_LABEL).filter(|key| !key.is_empty()) {
+            Some(key) => Some(key.to_string()),
+            None => {
+                return HttpResponse::BadRequest().json(json!({
+                    "error": format!("group_by inválido '{}'. Usa {}<clave>.", group_by, GROUP_BY_LABEL),
+                }));
+            }
+        },
+    };
+    let to = bounds[1].unwrap_or_else(|| Utc::now().date_naive());
+    let from = bounds[0].unwrap_or_else(|| to.checked_sub_days(Days::new(DEFAULT_USAGE_DAYS - 1)).unwrap_or(to));
+
+    let summaries = web::block(move || state.persistence.as_ref().map(|store| store.daily_summaries(from, to))).await;
+    match summaries {
+        Ok(Some(Ok(days))) => {
+            let days = group_by_label(days, group_label.as_deref());
+            match group_label {
+                Some(label) => HttpResponse::Ok().json(json!({ "from": from, "to": to, "group_by": format!("{}{}", GROUP_BY_LABEL, label), "days": days })),
+                None => HttpResponse::Ok().json(json!({ "from": from, "to": to, "days": days })),
+            }
+        }
+        Ok(Some(Err(e))) => {
+            error!("Persistencia: No se pudo leer el uso diario: {}", e);
+            HttpResponse::InternalServerError().json(json!({ "error": format!("No se pudo leer el uso diario: {}", e) }))
+        }
+        Ok(None) | Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use serde_json::Value;
+    use std::sync::atomic::Ordering;
+
+    use crate::balancer::{self, build_state, NodeHealth};
+    use crate::testing;
+
+    /// Estado arrancado sobre `dir`, como tras reiniciar el proceso.
+    fn started(dir: &Path, args: &[&str]) -> web::Data<AppState> {
+        let mut args = args.to_vec();
+        args.extend(["--state-dir", dir.to_str().unwrap()]);
+        let store = Store::open(PersistenceBackend::Json, Some(dir), None).unwrap();
+        web::Data::new(build_state(&testing::config(&args), store).unwrap())
+    }
+
+    /// Un proceso que muere a mitad de petición (kill -9, OOM) deja una instantánea con nodos
+    /// ocupados y cola; al volver a arrancar no hay nodos Busy heredados ni contadores sucios:
+    /// el registro empieza vacío y cada nodo vuelve Available al anunciarse.
+    #[actix_web::test]
+    async fn restart_after_a_hard_kill_mid_request_starts_clean() {
+        let dir = testing::temp_dir();
+        let crashed = started(&dir, &[]);
+        testing::announce(&crashed, "lmstudio", "box1", "http://127.0.0.1:1/");
+        testing::announce(&crashed, "lmstudio", "box2", "http://127.0.0.1:2/");
+        {
+            let mut nodes = crashed.pool("lmstudio").unwrap().write().unwrap();
+            let busy = nodes.get_mut("box1").unwrap();
+            busy.state = NodeHealth::Busy;
+            busy.in_flight.fetch_add(1, Ordering::Relaxed);
+        }
+        crashed.capacity.pool("lmstudio").unwrap().queued.fetch_add(3, Ordering::Relaxed);
+        save_if_changed(&crashed, &mut 0);
+        // Sin parada limpia: el proceso simplemente desaparece.
+        drop(crashed);
+
+        // La instantánea no guarda estados ni contadores que puedan quedarse colgados.
+        let saved: Value = serde_json::from_slice(&fs::read(dir.join("registry.json")).unwrap()).unwrap();
+        for node in saved["nodes"].as_array().unwrap() {
+            let mut fields: Vec<_> = node.as_object().unwrap().keys().map(String::as_str).collect();
+            fields.sort();
+            assert_eq!(fields, ["models", "node_id", "service", "service_url", "source"]);
+        }
+
+        let restarted = started(&dir, &["--warmup-secs", "60"]);
+        assert_eq!(testing::node_state(&restarted, "lmstudio", "box1"), "absent");
+        assert_eq!(testing::node_state(&restarted, "lmstudio", "box2"), "absent");
+        assert_eq!(restarted.capacity.pool("lmstudio").unwrap().queued.load(Ordering::Relaxed), 0);
+        assert_eq!(restarted.warmup.describe()["previous_nodes"], 2);
+
+        let url = testing::chat_node(Duration::ZERO);
+        testing::announce(&restarted, "lmstudio", "box1", &url);
+        assert_eq!(testing::node_state(&restarted, "lmstudio", "box1"), "available");
+        assert_eq!(restarted.pool("lmstudio").unwrap().read().unwrap()["box1"].in_flight(), 0);
+
+        let app = init_service(balancer::app(restarted.clone())).await;
+        let req = TestRequest::post().uri("/lmstudio").set_json(json!({ "model": "m", "messages": [{ "role": "user", "content": "hola" }] }));
+        assert_eq!(call_service(&app, req.to_request()).await.status(), 200);
+        assert_eq!(testing::node_state(&restarted, "lmstudio", "box1"), "available");
+    }
+
+    /// Una instantánea a medio escribir (o ilegible) se ignora: se arranca como sin ella.
+    #[actix_web::test]
+    async fn truncated_snapshot_is_ignored_on_startup() {
+        let dir = testing::temp_dir();
+        let crashed = started(&dir, &[]);
+        testing::announce(&crashed, "lmstudio", "box1", "http://127.0.0.1:1/");
+        save_if_changed(&crashed, &mut 0);
+        drop(crashed);
+        let path = dir.join("registry.json");
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() / 2]).unwrap();
+
+        let restarted = started(&dir, &["--warmup-secs", "60"]);
+        assert_eq!(testing::node_state(&restarted, "lmstudio", "box1"), "absent");
+        assert_eq!(restarted.warmup.describe()["previous_nodes"], Value::Null);
+        assert_eq!(restarted.pins.pinned_ip("box1"), None);
+    }
+
+    /// Un almacén de cada backend compilado, cada uno en su directorio vacío.
+    fn backends() -> Vec<(&'static str, Store)> {
+        let compiled = [
+            ("json", PersistenceBackend::Json),
+            #[cfg(feature = "sqlite")]
+            ("sqlite", PersistenceBackend::Sqlite),
+        ];
+        compiled.into_iter().map(|(name, backend)| (name, Store::open(backend, Some(&testing::temp_dir()), None).unwrap().unwrap())).collect()
+    }
+
+    fn at(rfc3339: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(rfc3339).unwrap().with_timezone(&Utc)
+    }
+
+    fn record(timestamp: &str, api_key: Option<&str>, model: &str, tokens: u64, estimated: bool) -> AuditRecord {
+        AuditRecord {
+            schema_version: audit::AUDIT_SCHEMA_VERSION,
+            timestamp: at(timestamp),
+            api_key: api_key.map(str::to_string),
+            service: "lmstudio".to_string(),
+            model: Some(model.to_string()),
+            prompt_tokens: Some(tokens),
+            completion_tokens: Some(2 * tokens),
+            total_tokens: Some(3 * tokens),
+            estimated,
+            node_id: "box1".to_string(),
+            postprocess: Vec::new(),
+            spilled_from: None,
+            terminated: None,
+            labels: RequestLabels::new(),
+        }
+    }
+
+    #[test]
+    fn registry_snapshot_round_trips_on_every_backend() {
+        for (backend, store) in backends() {
+            assert!(store.backend.load_registry().unwrap().is_none(), "{}", backend);
+            let snapshot = RegistrySnapshot {
+                saved_at: at("2025-04-01T10:00:00Z"),
+                nodes: vec![SnapshotNode {
+                    service: "ollama".to_string(),
+                    node_id: "box1".to_string(),
+                    service_url: "http://10.0.0.1:11434/".to_string(),
+                    source: "announced".to_string(),
+                    models: vec!["llama3".to_string()],
+                }],
+                pins: [("box1".to_string(), NodePin { ip: "10.0.0.1".parse().unwrap(), pinned_at: at("2025-04-01T09:00:00Z") })].into(),
+            };
+            store.backend.save_registry(&snapshot).unwrap();
+            let mut newer = snapshot.clone();
+            newer.saved_at = at("2025-04-01T10:00:30Z");
+            newer.nodes.clear();
+            store.backend.save_registry(&newer).unwrap();
+
+            let loaded = store.backend.load_registry().unwrap().unwrap();
+            assert_eq!(serde_json::to_value(&loaded).unwrap(), serde_json::to_value(&newer).unwrap(), "{}", backend);
+        }
+    }
+
+    #[test]
+    fn usage_is_summed_per_day_key_model_and_estimation_on_every_backend() {
+        for (backend, store) in backends() {
+            for (timestamp, key, model, tokens, estimated) in [
+                ("2025-04-01T08:00:00Z", Some("sk-...a"), "m1", 10, false),
+                ("2025-04-01T09:00:00Z", Some("sk-...a"), "m1", 5, false),
+                ("2025-04-01T09:30:00Z", Some("sk-...a"), "m1", 7, true),
+                ("2025-04-01T10:00:00Z", None, "m2", 1, false),
+                ("2025-04-02T00:00:00Z", Some("sk-...a"), "m1", 100, false),
+                ("2025-03-31T23:59:59Z", Some("sk-...a"), "m1", 1_000, false),
+            ] {
+                store.record(&record(timestamp, key, model, tokens, estimated));
+            }
+            let day = |d| NaiveDate::from_ymd_opt(2025, 4, d).unwrap();
+
+            let mut summary: Vec<_> = store
+                .daily_summaries(day(1), day(1))
+                .unwrap()
+                .into_iter()
+                .map(|usage| (usage.api_key, usage.model, usage.estimated, usage.requests, usage.prompt_tokens, usage.total_tokens))
+                .collect();
+            let mut expected = vec![
+                (Some("sk-...a".to_string()), Some("m1".to_string()), false, 2, 15, 45),
+                (Some("sk-...a".to_string()), Some("m1".to_string()), true, 1, 7, 21),
+                (None, Some("m2".to_string()), false, 1, 1, 3),
+            ];
+            summary.sort();
+            expected.sort();
+            assert_eq!(summary, expected, "{}", backend);
+
+            let days: Vec<_> = store.daily_summaries(day(1), day(2)).unwrap().iter().map(|usage| usage.day).collect();
+            assert!(days.contains(&day(2)) && !days.contains(&NaiveDate::from_ymd_opt(2025, 3, 31).unwrap()), "{}: {:?}", backend, days);
+        }
+    }
+
+    #[actix_web::test]
+    async fn requests_are_exported_in_write_order_within_bounds_on_every_backend() {
+        use futures_util::StreamExt;
+
+        for (backend, store) in backends() {
+            for (timestamp, model) in [("2025-04-01T10:00:00Z", "a"), ("2025-04-01T11:00:00Z", "b"), ("2025-04-01T12:00:00Z", "c")] {
+                store.record(&record(timestamp, None, model, 1, false));
+            }
+            let export = |from: Option<&str>, to: Option<&str>| {
+                let stream = store.export_requests(from.map(at), to.map(at)).unwrap();
+                async move {
+                    let bytes: Vec<u8> = stream.map(|chunk| chunk.unwrap().to_vec()).collect::<Vec<_>>().await.concat();
+                    String::from_utf8(bytes)
+                        .unwrap()
+                        .lines()
+                        .map(|line| serde_json::from_str::<Value>(line).unwrap()["model"].as_str().unwrap().to_string())
+                        .collect::<Vec<_>>()
+                }
+            };
+            assert_eq!(export(None, None).await, ["a", "b", "c"], "{}", backend);
+            assert_eq!(export(Some("2025-04-01T11:00:00Z"), None).await, ["b", "c"], "{}", backend);
+            assert_eq!(export(None, Some("2025-04-01T11:00:00Z")).await, ["a", "b"], "{}", backend);
+            assert!(export(Some("2025-04-02T00:00:00Z"), None).await.is_empty(), "{}", backend);
+        }
+    }
+
+    #[test]
+    fn benchmarks_keep_the_latest_runs_per_node_on_every_backend() {
+        for (backend, store) in backends() {
+            for (node_id, minute, latency_ms) in [("box1", 0, 100), ("box2", 1, 999), ("box1", 2, 110), ("box1", 3, 120)] {
+                store.record_benchmark(&BenchmarkRun {
+                    node_id: node_id.to_string(),
+                    service: "lmstudio".to_string(),
+                    model: "m".to_string(),
+                    started_at: at(&format!("2025-04-01T10:0{}:00Z", minute)),
+                    prompts: 3,
+                    latency_ms,
+                    tokens_per_sec: Some(20.0),
+                    regressed: false,
+                });
+            }
+            let latencies = |node_id, limit| store.benchmarks(node_id, limit).iter().map(|run| run.latency_ms).collect::<Vec<_>>();
+            assert_eq!(latencies("box1", 10), [100, 110, 120], "{}", backend);
+            assert_eq!(latencies("box1", 2), [110, 120], "{}", backend);
+            assert_eq!(latencies("box2", 10), [999], "{}", backend);
+            assert!(latencies("box9", 10).is_empty(), "{}", backend);
+        }
+    }
+
+    /// Un almacén que falla en todo.
+    struct Broken;
+
+    impl Persistence for Broken {
+        fn describe(&self) -> serde_json::Value {
+            json!({ "backend": "broken" })
+        }
+        fn save_registry(&self, _: &RegistrySnapshot) -> Result<(), PersistenceError> {
+            Err(PersistenceError("disco lleno".to_string()))
+        }
+        fn load_registry(&self) -> Result<Option<RegistrySnapshot>, PersistenceError> {
+            Err(PersistenceError("disco lleno".to_string()))
+        }
+        fn append_request(&self, _: &AuditRecord) -> Result<(), PersistenceError> {
+            Err(PersistenceError("disco lleno".to_string()))
+        }
+        fn append_usage(&self, _: &DailyUsage) -> Result<(), PersistenceError> {
+            Err(PersistenceError("disco lleno".to_string()))
+        }
+        fn daily_summaries(&self, _: NaiveDate, _: NaiveDate) -> Result<Vec<DailyUsage>, PersistenceError> {
+            Err(PersistenceError("disco lleno".to_string()))
+        }
+        fn export_requests(&self, _: Option<DateTime<Utc>>, _: Option<DateTime<Utc>>) -> Result<RecordStream, PersistenceError> {
+            Err(PersistenceError("disco lleno".to_string()))
+        }
+        fn append_benchmark(&self, _: &BenchmarkRun) -> Result<(), PersistenceError> {
+            Err(PersistenceError("disco lleno".to_string()))
+        }
+        fn benchmarks(&self, _: &str, _: usize) -> Result<Vec<BenchmarkRun>, PersistenceError> {
+            Err(PersistenceError("disco lleno".to_string()))
+        }
+    }
+
+    /// Los fallos del almacén sólo van al log: las peticiones se sirven igual.
+    #[actix_web::test]
+    async fn backend_errors_never_change_the_response() {
+        let state = web::Data::new(build_state(&testing::config(&["--audit-token", "audit"]), Some(Store { backend: Box::new(Broken) })).unwrap());
+        testing::announce(&state, "lmstudio", "box1", &testing::chat_node(Duration::ZERO));
+        let app = init_service(balancer::app(state.clone())).await;
+
+        for _ in 0..3 {
+            let req = TestRequest::post().uri("/lmstudio").set_json(json!({ "model": "m", "messages": [{ "role": "user", "content": "hola" }] }));
+            assert_eq!(call_service(&app, req.to_request()).await.status(), 200);
+        }
+        save_if_changed(&state, &mut 0);
+        assert!(state.persistence.as_ref().unwrap().benchmarks("box1", 5).is_empty());
+        // Las consultas que sólo leen del almacén sí lo dicen.
+        let resp = call_service(&app, TestRequest::get().uri("/usage/daily").insert_header(("Authorization", "Bearer audit")).to_request()).await;
+        assert_eq!(resp.status(), 500);
+    }
+}
@@ -0,0 +1,336 @@
+// src/pins.rs
+//! Identidad de los nodos fijada a su dirección de origen (TOFU, `--node-pinning`).
+//!
+//! Cualquiera que sepa anunciarse puede hacerlo con el ID de un nodo existente y llevarse su
+//! tráfico. Con `--node-pinning warn` o `reject`, la primera vez que se registra un ID (anuncio
+//! UDP o latido gRPC) se apunta la IP de origen. Un mensaje posterior con ese ID desde otra IP
+//! publica `node_identity_mismatch`, una vez por ID e IP; con `reject` el mensaje además se
+//! descarta, con `warn` se atiende.
+//!
+//! Un nodo que cambia de IP por las buenas (DHCP) queda fuera hasta que un administrador lo
+//! vuelve a fijar con `POST /nodes/{id}/repin`: sin cuerpo se olvida la IP y el siguiente
+//! anuncio fija la nueva; con `{"ip": "..."}` se fija esa. Los pines se guardan con la
+//! instantánea del registro (`persistence`), así que sobreviven a un reinicio, y `/nodes` da el
+//! de cada nodo en `pinned_ip`.
+use actix_web::{post, web, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use crate::balancer::AppState;
+use crate::events::{BalancerEvent, EventHub};
+use crate::persistence;
+
+/// Qué hacer con un ID que llega desde una IP distinta de la fijada (`--node-pinning`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum PinMode {
+    /// No se fija nada.
+    Disabled,
+    /// Se avisa y se atiende el mensaje.
+    Warn,
+    /// Se avisa y se descarta el mensaje.
+    Reject,
+}
+
+impl PinMode {
+    pub fn label(self) -> &'static str {
+        match self {
+            PinMode::Disabled => "disabled",
+            PinMode::Warn => "warn",
+            PinMode::Reject => "reject",
+        }
+    }
+}
+
+/// IP fijada a un ID de nodo.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NodePin {
+    pub ip: IpAddr,
+    pub pinned_at: DateTime<Utc>,
+}
+
+pub struct NodePins {
+    mode: PinMode,
+    pins: Mutex<BTreeMap<String, NodePin>>,
+    /// (ID, IP) ya avisados, para no repetir el evento en cada latido.
+    reported: Mutex<HashSet<(String, IpAddr)>>,
+}
+
+impl NodePins {
+    /// `previous` son los pines de la instantánea anterior.
+    pub fn new(mode: PinMode, previous: BTreeMap<String, NodePin>) -> Self {
+        let pins = if mode == PinMode::Disabled { BTreeMap::new() } else { previous };
+        if !pins.is_empty() {
+            info!("Pines: {} nodos fijados a su IP en la instantánea anterior.", pins.len());
+        }
+        Self { mode, pins: Mutex::new(pins), reported: Mutex::default() }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.mode != PinMode::Disabled
+    }
+
+    /// Si se atiende un mensaje del nodo llegado desde `source`. Un ID sin pin pasa siempre;
+    /// sólo `pin` lo fija.
+    pub fn permits(&self, events: &EventHub, unique_node_id: &str, source: IpAddr) -> bool {
+        if !self.is_enabled() {
+            return true;
+        }
+        let source = source.to_canonical();
+        let Some(pinned) = self.pins.lock().unwrap().get(unique_node_id).map(|pin| pin.ip) else {
+            return true;
+        };
+        if pinned == source {
+            return true;
+        }
+        let rejected = self.mode == PinMode::Reject;
+        if self.reported.lock().unwrap().insert((unique_node_id.to_string(), source)) {
+            events.publish(BalancerEvent::NodeIdentityMismatch {
+                node_id: unique_node_id.to_string(),
+                pinned_ip: pinned.to_string(),
+                source_ip: source.to_string(),
+                rejected,
+            });
+        }
+        !rejected
+    }
+
+    /// Fija el ID a `source` si aún no tiene pin (primer registro).
+    pub fn pin(&self, unique_node_id: &str, source: IpAddr) {
+        if !self.is_enabled() {
+            return;
+        }
+        let source = source.to_canonical();
+        self.pins.lock().unwrap().entry(unique_node_id.to_string()).or_insert_with(|| {
+            info!("Pines: Nodo {} fijado a {}.", unique_node_id, source);
+            NodePin { ip: source, pinned_at: Utc::now() }
+        });
+    }
+
+    /// Sustituye (o, sin `ip`, olvida) el pin del nodo. Devuelve el anterior.
+    fn repin(&self, unique_node_id: &str, ip: Option<IpAddr>) -> Option<NodePin> {
+        self.reported.lock().unwrap().retain(|(reported, _)| reported != unique_node_id);
+        let mut pins = self.pins.lock().unwrap();
+        match ip {
+            Some(ip) => pins.insert(unique_node_id.to_string(), NodePin { ip: ip.to_canonical(), pinned_at: Utc::now() }),
+            None => pins.remove(unique_node_id),
+        }
+    }
+
+    pub fn pinned_ip(&self, unique_node_id: &str) -> Option<IpAddr> {
+        self.pins.lock().unwrap().get(unique_node_id).map(|pin| pin.ip)
+    }
+
+    /// Todos los pines, para la instantánea del registro.
+    pub fn snapshot(&self) -> BTreeMap<String, NodePin> {
+        self.pins.lock().unwrap().clone()
+    }
+
+    /// Configuración para `/config`.
+    pub fn describe(&self) -> serde_json::Value {
+        json!({ "mode": self.mode.label(), "pinned": self.pins.lock().unwrap().len() })
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RepinRequest {
+    ip: Option<IpAddr>,
+}
+
+/// Vuelve a fijar la identidad de un nodo: a `ip` o, sin ella, a la del siguiente anuncio.
+#[post("/nodes/{id}/repin")]
+async fn repin_handler(state: web::Data<AppState>, path: web::Path<String>, body: web::Bytes) -> impl Responder {
+    let unique_node_id = path.into_inner();
+    if !state.pins.is_enabled() {
+        return HttpResponse::Conflict().json(json!({ "error": "La fijación de nodos está desactivada (--node-pinning)." }));
+    }
+    let request: RepinRequest = if body.iter().all(u8::is_ascii_whitespace) {
+        RepinRequest::default()
+    } else {
+        match serde_json::from_slice(&body) {
+            Ok(request) => request,
+            Err(e) => return HttpResponse::BadRequest().json(json!({ "error": format!("Cuerpo inválido: {}", e) })),
+        }
+    };
+    let ip = request.ip;
+    if ip.is_none() && state.pins.pinned_ip(&unique_node_id).is_none() {
+        return HttpResponse::NotFound().json(json!({ "error": format!("El nodo {} no está fijado a ninguna IP", unique_node_id) }));
+    }
+    let previous = state.pins.repin(&unique_node_id, ip);
+    match ip {
+        Some(ip) => info!("Pines: Nodo {} fijado a mano a {}.", unique_node_id, ip),
+        None => info!("Pines: Nodo {} sin pin; el siguiente anuncio lo fija.", unique_node_id),
+    }
+    if let Some(store) = &state.persistence {
+        store.save_registry(&persistence::registry_snapshot(&state));
+    }
+    HttpResponse::Ok().json(json!({
+        "node_id": unique_node_id,
+        "previous_ip": previous.map(|pin| pin.ip),
+        "ip": state.pins.pinned_ip(&unique_node_id),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use serde_json::Value;
+    use std::sync::Arc;
+    use tokio::net::UdpSocket;
+    use tokio::sync::broadcast::Receiver;
+
+    use crate::balancer::{self, build_state};
+    use crate::discovery::{discover_message, goodbye_message};
+    use crate::events;
+    use crate::persistence::{PersistenceBackend, Store};
+    use crate::testing;
+
+    const URL: &str = "http://127.0.0.1:1234/v1/chat/completions";
+    const OTHER_URL: &str = "http://127.0.0.2:1234/v1/chat/completions";
+
+    /// Un socket de 127.0.0.2 hacia el mismo listener: otra máquina que se anuncia.
+    async fn impostor(socket: &UdpSocket) -> UdpSocket {
+        let impostor = UdpSocket::bind("127.0.0.2:0").await.unwrap();
+        impostor.connect(socket.peer_addr().unwrap()).await.unwrap();
+        impostor
+    }
+
+    /// Manda el datagrama y espera a que el listener lo haya procesado.
+    async fn send(state: &AppState, socket: &UdpSocket, datagram: &str) {
+        let listener = &state.discovery_listeners[0];
+        let packets = listener.summary()["packets"].as_u64().unwrap();
+        socket.send(datagram.as_bytes()).await.unwrap();
+        testing::eventually(|| listener.summary()["packets"].as_u64().unwrap() > packets).await;
+    }
+
+    fn url_of(state: &AppState, id: &str) -> Option<String> {
+        state.lm_studio_nodes.read().unwrap().get(id).map(|info| info.service_url.to_string())
+    }
+
+    /// Eventos `node_identity_mismatch` recibidos hasta ahora, como (IP fijada, IP de origen, rechazado).
+    fn mismatches(events: &mut Receiver<Arc<BalancerEvent>>) -> Vec<(String, String, bool)> {
+        std::iter::from_fn(|| events.try_recv().ok())
+            .filter_map(|event| match &*event {
+                BalancerEvent::NodeIdentityMismatch { pinned_ip, source_ip, rejected, .. } => Some((pinned_ip.clone(), source_ip.clone(), *rejected)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn mismatch(rejected: bool) -> (String, String, bool) {
+        ("127.0.0.1".to_string(), "127.0.0.2".to_string(), rejected)
+    }
+
+    #[actix_web::test]
+    async fn impersonation_is_rejected_and_reported_once() {
+        let (state, socket) = testing::discovery(&["--node-pinning", "reject"]).await;
+        let (mut events, _subscriber) = events::admit(state.clone()).unwrap();
+        let impostor = impostor(&socket).await;
+
+        send(&state, &socket, &discover_message("lmstudio", "box1", URL)).await;
+        assert_eq!(state.pins.pinned_ip("box1"), Some("127.0.0.1".parse().unwrap()));
+
+        send(&state, &impostor, &discover_message("lmstudio", "box1", OTHER_URL)).await;
+        send(&state, &impostor, &goodbye_message("lmstudio", "box1")).await;
+        assert_eq!(url_of(&state, "box1").as_deref(), Some(URL));
+        assert_eq!(mismatches(&mut events), [mismatch(true)]);
+
+        // El nodo legítimo sigue atendido, y el impostor no se vuelve a avisar.
+        send(&state, &socket, &goodbye_message("lmstudio", "box1")).await;
+        assert_eq!(url_of(&state, "box1"), None);
+        send(&state, &impostor, &discover_message("lmstudio", "box1", OTHER_URL)).await;
+        assert_eq!(url_of(&state, "box1"), None);
+        assert!(mismatches(&mut events).is_empty());
+    }
+
+    #[actix_web::test]
+    async fn warn_mode_reports_and_accepts() {
+        let (state, socket) = testing::discovery(&["--node-pinning", "warn"]).await;
+        let (mut events, _subscriber) = events::admit(state.clone()).unwrap();
+        let impostor = impostor(&socket).await;
+
+        send(&state, &socket, &discover_message("lmstudio", "box1", URL)).await;
+        send(&state, &impostor, &discover_message("lmstudio", "box1", OTHER_URL)).await;
+        assert_eq!(url_of(&state, "box1").as_deref(), Some(OTHER_URL));
+        assert_eq!(mismatches(&mut events), [mismatch(false)]);
+        // El pin no se mueve solo.
+        assert_eq!(state.pins.pinned_ip("box1"), Some("127.0.0.1".parse().unwrap()));
+    }
+
+    fn repin(id: &str, body: &str) -> TestRequest {
+        TestRequest::post()
+            .uri(&format!("/nodes/{}/repin", id))
+            .insert_header(("Authorization", "Bearer secreto"))
+            .insert_header(("Content-Type", "application/json"))
+            .set_payload(body.to_string())
+    }
+
+    #[actix_web::test]
+    async fn a_node_that_changed_ip_is_repinned_by_an_admin() {
+        let (state, socket) = testing::discovery(&["--node-pinning", "reject", "--admin-token", "secreto"]).await;
+        let app = init_service(balancer::app(state.clone())).await;
+        let moved = impostor(&socket).await;
+        send(&state, &socket, &discover_message("lmstudio", "box1", URL)).await;
+
+        // Hasta que un administrador lo vuelve a fijar, la IP nueva (DHCP) no entra.
+        send(&state, &moved, &discover_message("lmstudio", "box1", OTHER_URL)).await;
+        assert_eq!(url_of(&state, "box1").as_deref(), Some(URL));
+        let res = call_service(&app, repin("box1", "").to_request()).await;
+        assert_eq!(res.status(), 200);
+        let body: Value = read_body_json(res).await;
+        assert_eq!(body, json!({ "node_id": "box1", "previous_ip": "127.0.0.1", "ip": null }));
+
+        send(&state, &moved, &discover_message("lmstudio", "box1", OTHER_URL)).await;
+        assert_eq!(url_of(&state, "box1").as_deref(), Some(OTHER_URL));
+        assert_eq!(state.pins.pinned_ip("box1"), Some("127.0.0.2".parse().unwrap()));
+        let nodes: Value = read_body_json(call_service(&app, TestRequest::get().uri("/nodes").to_request()).await).await;
+        let node = nodes["nodes"].as_array().unwrap().iter().find(|node| node["node_id"] == "box1").unwrap();
+        assert_eq!(node["pinned_ip"], "127.0.0.2");
+        // Ahora es la IP de antes la que no entra.
+        send(&state, &socket, &discover_message("lmstudio", "box1", URL)).await;
+        assert_eq!(url_of(&state, "box1").as_deref(), Some(OTHER_URL));
+
+        // Con una IP en el cuerpo se fija esa.
+        let res = call_service(&app, repin("box1", r#"{"ip": "127.0.0.1"}"#).to_request()).await;
+        assert_eq!(res.status(), 200);
+        send(&state, &socket, &discover_message("lmstudio", "box1", URL)).await;
+        assert_eq!(url_of(&state, "box1").as_deref(), Some(URL));
+
+        assert_eq!(call_service(&app, repin("box1", r#"{"ip": "nope"}"#).to_request()).await.status(), 400);
+        assert_eq!(call_service(&app, repin("box1", r#"{"addr": "127.0.0.1"}"#).to_request()).await.status(), 400);
+        assert_eq!(call_service(&app, repin("box9", "").to_request()).await.status(), 404);
+    }
+
+    #[actix_web::test]
+    async fn repin_conflicts_when_pinning_is_disabled() {
+        let state = testing::state(&["--admin-token", "secreto"]);
+        state.pins.pin("box1", "127.0.0.1".parse().unwrap());
+        assert_eq!(state.pins.pinned_ip("box1"), None);
+        let app = init_service(balancer::app(state)).await;
+        assert_eq!(call_service(&app, repin("box1", "").to_request()).await.status(), 409);
+    }
+
+    #[actix_web::test]
+    async fn pins_survive_a_restart() {
+        let dir = testing::temp_dir();
+        let args = ["--state-dir", dir.to_str().unwrap(), "--node-pinning", "reject", "--admin-token", "secreto"];
+        let open = || Store::open(PersistenceBackend::Json, Some(&dir), None).unwrap();
+        let state = web::Data::new(build_state(&testing::config(&args), open()).unwrap());
+        state.pins.pin("box1", "127.0.0.1".parse().unwrap());
+        let app = init_service(balancer::app(state)).await;
+        // Volver a fijarlo guarda la instantánea en el momento.
+        assert_eq!(call_service(&app, repin("box1", r#"{"ip": "127.0.0.3"}"#).to_request()).await.status(), 200);
+
+        let restarted = build_state(&testing::config(&args), open()).unwrap();
+        assert_eq!(restarted.pins.pinned_ip("box1"), Some("127.0.0.3".parse().unwrap()));
+        // Sin fijación, los pines guardados no cuentan.
+        let disabled = build_state(&testing::config(&["--state-dir", dir.to_str().unwrap()]), open()).unwrap();
+        assert_eq!(disabled.pins.pinned_ip("box1"), None);
+    }
+}
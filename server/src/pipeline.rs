@@ -0,0 +1,182 @@
+// src/pipeline.rs
+//! Reservas de nodo para ráfagas de peticiones dependientes (`X-Pipeline: <token>`).
+//!
+//! Al terminar una petición con token, el nodo no vuelve a la pool: queda Busy y reservado
+//! durante una ventana corta para la siguiente petición con el mismo token, que se salta la
+//! cola. Si nadie la reclama a tiempo, la reserva caduca y el nodo se libera.
+use actix_web::{web, HttpRequest};
+use log::{debug, info};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+use crate::balancer::{AppState, NodeHealth, NodeMap};
+use crate::history::TransitionCause;
+use crate::ids::{NodeId, PoolName, ServiceUrl};
+use crate::limits::StoreUsage;
+
+pub const PIPELINE_HEADER: &str = "x-pipeline";
+
+/// Ventana por defecto durante la que se guarda el nodo para la siguiente petición.
+pub const DEFAULT_PIPELINE_WINDOW_MS: u64 = 2000;
+
+/// Clave de una reserva: pool, API key y token. Incluir la key impide que otro cliente
+/// reclame una reserva adivinando el token.
+type ReservationKey = (PoolName, String, String);
+
+struct Reservation {
+    unique_node_id: NodeId,
+    service_url: ServiceUrl,
+    expires: Instant,
+    generation: u64,
+}
+
+pub struct PipelineReservations {
+    window: Duration,
+    min_available: usize,
+    /// Reservas simultáneas. Lleno, el nodo se libera sin reservar.
+    max_reservations: usize,
+    entries: Mutex<HashMap<ReservationKey, Reservation>>,
+    next_generation: AtomicU64,
+}
+
+/// Token de pipeline de la petición, con el ámbito (pool y API key) en el que es válido.
+pub struct PipelineToken {
+    key: ReservationKey,
+}
+
+impl PipelineToken {
+    pub fn from_request(req: &HttpRequest, service: &PoolName, api_key: Option<&str>) -> Option<Self> {
+        let token = req.headers().get(PIPELINE_HEADER)?.to_str().ok()?.trim();
+        if token.is_empty() {
+            return None;
+        }
+        Some(Self { key: (service.clone(), api_key.unwrap_or_default().to_string(), token.to_string()) })
+    }
+}
+
+impl PipelineReservations {
+    pub fn new(window: Duration, min_available: usize, max_reservations: usize) -> Self {
+        Self {
+            window,
+            min_available,
+            max_reservations,
+            entries: Mutex::new(HashMap::new()),
+            next_generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Reclama el nodo reservado para el token, si sigue vigente y el nodo sigue ocupado por la reserva.
+    pub fn claim(&self, token: &PipelineToken, nodes_lock: &NodeMap) -> Option<(NodeId, ServiceUrl)> {
+        let reservation = {
+            let mut entries = self.entries.lock().unwrap();
+            // Una reserva caducada se deja a su tarea de expiración, que es quien libera el nodo.
+            if entries.get(&token.key)?.expires <= Instant::now() {
+                return None;
+            }
+            entries.remove(&token.key)?
+        };
+        let still_held = nodes_lock
+            .read()
+            .unwrap()
+            .get(&reservation.unique_node_id)
+            .is_some_and(|info| matches!(info.state, NodeHealth::Busy));
+        still_held.then_some((reservation.unique_node_id, reservation.service_url))
+    }
+
+    /// Indica si el nodo está retenido por una reserva, para la UI.
+    pub fn is_reserved(&self, service: &str, unique_node_id: &str) -> bool {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|((pool, _, _), r)| pool == service && r.unique_node_id == *unique_node_id)
+    }
+
+    pub fn active(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn usage(&self) -> StoreUsage {
+        let entries = self.entries.lock().unwrap();
+        let bytes = entries
+            .iter()
+            .map(|((pool, api_key, token), r)| {
+                std::mem::size_of::<(ReservationKey, Reservation)>() + pool.len() + api_key.len() + token.len() + r.unique_node_id.len() + r.service_url.as_str().len()
+            })
+            .sum();
+        StoreUsage { entries: entries.len(), bytes }
+    }
+
+    fn is_full(&self, token: &PipelineToken) -> bool {
+        let entries = self.entries.lock().unwrap();
+        !entries.contains_key(&token.key) && entries.len() >= self.max_reservations
+    }
+
+    /// Crea la reserva. Devuelve su generación y el nodo de la reserva que sustituye, si había otra.
+    fn reserve(&self, token: &PipelineToken, unique_node_id: &NodeId, service_url: &ServiceUrl) -> (u64, Option<NodeId>) {
+        let generation = self.next_generation.fetch_add(1, Ordering::Relaxed);
+        let replaced = self.entries.lock().unwrap().insert(
+            token.key.clone(),
+            Reservation {
+                unique_node_id: unique_node_id.clone(),
+                service_url: service_url.clone(),
+                expires: Instant::now() + self.window,
+                generation,
+            },
+        );
+        (generation, replaced.map(|r| r.unique_node_id))
+    }
+
+    /// Retira la reserva si sigue siendo la misma; `false` si ya se reclamó o se sustituyó.
+    fn expire(&self, key: &ReservationKey, generation: u64) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.get(key).is_some_and(|r| r.generation == generation) {
+            entries.remove(key);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Libera un nodo tras una petición completada. Con token, y si la pool no está bajo presión,
+/// lo deja reservado para la siguiente petición de la ráfaga en lugar de devolverlo a la pool.
+pub fn release_node(
+    state: &web::Data<AppState>,
+    service: &PoolName,
+    unique_node_id: &NodeId,
+    service_url: &ServiceUrl,
+    token: Option<&PipelineToken>,
+) {
+    let pipeline = &state.pipeline;
+    let under_pressure = || {
+        state.pool(service).is_none_or(|lock| {
+            let available = lock.read().unwrap().values().filter(|info| matches!(info.state, NodeHealth::Available)).count();
+            available < pipeline.min_available
+        })
+    };
+    let Some(token) = token.filter(|token| !pipeline.window.is_zero() && !pipeline.is_full(token) && !under_pressure()) else {
+        state.update_node_state(service, unique_node_id, NodeHealth::Available, TransitionCause::RequestCompleted);
+        return;
+    };
+
+    let (generation, replaced) = pipeline.reserve(token, unique_node_id, service_url);
+    if let Some(replaced) = replaced.filter(|id| id != unique_node_id) {
+        // Dos peticiones concurrentes con el mismo token: sólo se guarda el último nodo.
+        state.update_node_state(service, &replaced, NodeHealth::Available, TransitionCause::RequestCompleted);
+    }
+    debug!("Pipeline: Nodo ID {} reservado {}ms para el token {}.", unique_node_id, pipeline.window.as_millis(), token.key.2);
+    let state = state.clone();
+    let key = token.key.clone();
+    let unique_node_id = unique_node_id.clone();
+    tokio::spawn(async move {
+        sleep(state.pipeline.window).await;
+        if state.pipeline.expire(&key, generation) {
+            info!("Pipeline: Reserva del nodo ID {} caducada sin reclamar. Se libera.", unique_node_id);
+            state.update_node_state(&key.0, &unique_node_id, NodeHealth::Available, TransitionCause::RequestCompleted);
+        }
+    });
+}
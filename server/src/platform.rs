@@ -0,0 +1,267 @@
+// src/platform.rs
+//! Plataforma de un nodo: sistema, arquitectura, acelerador y memoria.
+//!
+//! El nodo la averigua una vez al arrancar, en segundo plano para no retrasar el primer
+//! anuncio, y la anuncia en un datagrama `PLATFORM` (`discovery`) en cuanto la tiene. Todo es
+//! de mejor esfuerzo: lo que no se puede averiguar se anuncia como `unknown`, nunca se adivina.
+//! El acelerador se deduce así:
+//!
+//! - macOS en `aarch64` (Apple Silicon): `metal`, con memoria `unified`.
+//! - Linux con el driver de NVIDIA cargado (`/proc/driver/nvidia/version`): `cuda`, `discrete`.
+//! - Linux con `/dev/kfd` (AMD ROCm): `rocm`, `discrete`.
+//! - Cualquier otro caso: `unknown`. Un nodo sin GPU detectada no se anuncia como CPU.
+//!
+//! `memory_bytes` es la memoria total del sistema, no la VRAM de una GPU discreta.
+//!
+//! Las reglas de enrutado pueden preferir nodos por estos campos con `PlatformMatch`.
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// Valor de un campo que el nodo no pudo averiguar o que nunca anunció.
+pub const UNKNOWN: &str = "unknown";
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Platform {
+    pub os: String,
+    pub arch: String,
+    /// `metal`, `cuda`, `rocm` o `unknown`.
+    pub accelerator: String,
+    /// `unified`, `discrete` o `unknown`.
+    pub memory: String,
+    /// Memoria total del sistema.
+    pub memory_bytes: Option<u64>,
+}
+
+impl Default for Platform {
+    /// Todo desconocido: un nodo que no anuncia `PLATFORM`.
+    fn default() -> Self {
+        Self {
+            os: UNKNOWN.to_string(),
+            arch: UNKNOWN.to_string(),
+            accelerator: UNKNOWN.to_string(),
+            memory: UNKNOWN.to_string(),
+            memory_bytes: None,
+        }
+    }
+}
+
+impl Platform {
+    /// Plataforma de esta máquina. Bloquea mientras lee `/proc` o llama a `sysctl`.
+    pub fn detect() -> Self {
+        let os = std::env::consts::OS;
+        let arch = std::env::consts::ARCH;
+        let (accelerator, memory) = match (os, arch) {
+            ("macos", "aarch64") => ("metal", "unified"),
+            ("linux", _) if Path::new("/proc/driver/nvidia/version").exists() => ("cuda", "discrete"),
+            ("linux", _) if Path::new("/dev/kfd").exists() => ("rocm", "discrete"),
+            _ => (UNKNOWN, UNKNOWN),
+        };
+        Self {
+            os: os.to_string(),
+            arch: arch.to_string(),
+            accelerator: accelerator.to_string(),
+            memory: memory.to_string(),
+            memory_bytes: total_memory_bytes(os),
+        }
+    }
+
+    /// Resumen corto para la UI, p.ej. `metal 64G`.
+    pub fn label(&self) -> String {
+        match self.memory_bytes {
+            Some(bytes) => format!("{} {}G", self.accelerator, bytes / (1 << 30)),
+            None => self.accelerator.clone(),
+        }
+    }
+}
+
+fn total_memory_bytes(os: &str) -> Option<u64> {
+    match os {
+        "linux" => {
+            let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+            let line = meminfo.lines().find(|line| line.starts_with("MemTotal:"))?;
+            let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+            Some(kib * 1024)
+        }
+        "macos" => {
+            let output = Command::new("sysctl").args(["-n", "hw.memsize"]).output().ok()?;
+            String::from_utf8(output.stdout).ok()?.trim().parse().ok()
+        }
+        _ => None,
+    }
+}
+
+/// Condición sobre la plataforma de un nodo, para la acción `prefer` de las reglas. Todos los
+/// campos presentes deben cumplirse. Un campo desconocido del nodo sólo coincide con el valor
+/// `unknown`, y sin memoria conocida no se cumple `min_memory_gb`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PlatformMatch {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub os: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arch: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accelerator: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_memory_gb: Option<u64>,
+}
+
+impl PlatformMatch {
+    pub fn is_empty(&self) -> bool {
+        self.os.is_none() && self.arch.is_none() && self.accelerator.is_none() && self.memory.is_none() && self.min_memory_gb.is_none()
+    }
+
+    pub fn matches(&self, platform: &Platform) -> bool {
+        let field = |expected: &Option<String>, actual: &str| expected.as_ref().is_none_or(|expected| expected.eq_ignore_ascii_case(actual));
+        field(&self.os, &platform.os)
+            && field(&self.arch, &platform.arch)
+            && field(&self.accelerator, &platform.accelerator)
+            && field(&self.memory, &platform.memory)
+            && self.min_memory_gb.is_none_or(|min| platform.memory_bytes.is_some_and(|bytes| bytes >= min << 30))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use serde_json::{json, Value};
+    use std::time::{Duration, Instant};
+
+    use crate::balancer::{self, NodeHealth, LMSTUDIO};
+    use crate::discovery;
+    use crate::history::TransitionCause;
+    use crate::testing;
+
+    fn platform(os: &str, arch: &str, accelerator: &str, memory: &str, gb: Option<u64>) -> Platform {
+        Platform {
+            os: os.to_string(),
+            arch: arch.to_string(),
+            accelerator: accelerator.to_string(),
+            memory: memory.to_string(),
+            memory_bytes: gb.map(|gb| gb << 30),
+        }
+    }
+
+    fn condition(toml: &str) -> PlatformMatch {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn matcher_handles_present_absent_and_unknown_values() {
+        let mac = platform("macos", "aarch64", "metal", "unified", Some(64));
+        let cuda = platform("linux", "x86_64", "cuda", "discrete", Some(128));
+        let no_memory = platform("linux", "x86_64", UNKNOWN, UNKNOWN, None);
+        let silent = Platform::default();
+        let cases = [
+            // Campos presentes: todos deben coincidir, sin distinguir mayúsculas.
+            ("accelerator = \"metal\"", [true, false, false, false]),
+            ("accelerator = \"METAL\"\nmemory = \"unified\"", [true, false, false, false]),
+            ("os = \"linux\"\narch = \"x86_64\"", [false, true, true, false]),
+            ("os = \"linux\"\naccelerator = \"metal\"", [false, false, false, false]),
+            // Campo ausente de la condición: no se mira.
+            ("min_memory_gb = 64", [true, true, false, false]),
+            ("min_memory_gb = 65", [false, true, false, false]),
+            // Desconocido en el nodo: sólo coincide con un `unknown` explícito.
+            ("accelerator = \"unknown\"", [false, false, true, true]),
+            ("os = \"unknown\"", [false, false, false, true]),
+            ("min_memory_gb = 0", [true, true, false, false]),
+        ];
+        for (toml, expected) in cases {
+            let condition = condition(toml);
+            assert!(!condition.is_empty());
+            let actual = [&mac, &cuda, &no_memory, &silent].map(|platform| condition.matches(platform));
+            assert_eq!(actual, expected, "{}", toml);
+        }
+        assert!(PlatformMatch::default().is_empty());
+        assert!(toml::from_str::<PlatformMatch>("gpu = \"metal\"").is_err());
+    }
+
+    #[test]
+    fn detection_reports_this_machine_or_unknown() {
+        let detected = Platform::detect();
+        assert_eq!((detected.os.as_str(), detected.arch.as_str()), (std::env::consts::OS, std::env::consts::ARCH));
+        let expected_memory = match detected.accelerator.as_str() {
+            "metal" => "unified",
+            "cuda" | "rocm" => "discrete",
+            UNKNOWN => UNKNOWN,
+            other => panic!("acelerador inventado: {}", other),
+        };
+        assert_eq!(detected.memory, expected_memory);
+        assert!(detected.memory_bytes.is_none_or(|bytes| bytes > 0));
+    }
+
+    #[test]
+    fn labels_show_the_accelerator_and_known_memory() {
+        assert_eq!(platform("macos", "aarch64", "metal", "unified", Some(64)).label(), "metal 64G");
+        assert_eq!(Platform::default().label(), UNKNOWN);
+    }
+
+    #[test]
+    fn platform_datagrams_round_trip_and_fill_gaps_with_unknown() {
+        let mac = platform("macos", "aarch64", "metal", "unified", Some(64));
+        let msg = discovery::platform_message("lmstudio", "mac", &mac);
+        assert_eq!(discovery::parse_platform_message(&msg), Some(("lmstudio", "mac", mac)));
+        // Un valor con comas o `=` viaja como desconocido en vez de romper el datagrama.
+        let odd = platform("linux", "x86,64", "a=b", "", None);
+        let (_, _, parsed) = discovery::parse_platform_message(&discovery::platform_message("lmstudio", "box", &odd)).unwrap();
+        assert_eq!(parsed, platform("linux", UNKNOWN, UNKNOWN, UNKNOWN, None));
+        // Campos que faltan, vacíos o ilegibles, y campos que no conoce.
+        let (_, _, parsed) = discovery::parse_platform_message("PLATFORM,ollama,box,os=linux,memory_bytes=mucha,gpu=x,arch=").unwrap();
+        assert_eq!(parsed, platform("linux", UNKNOWN, UNKNOWN, UNKNOWN, None));
+        assert_eq!(discovery::parse_platform_message("DISCOVER,ollama,box"), None);
+    }
+
+    #[actix_web::test]
+    async fn long_prompts_prefer_metal_nodes_and_fall_back_to_the_rest() {
+        let rules = testing::temp_file(
+            "rules.toml",
+            "[[rules]]\nname = \"largos-a-metal\"\nmatch = { min_prompt_tokens = 200 }\naction = { prefer = { accelerator = \"metal\" } }\n",
+        );
+        let state = testing::state(&["--rules-file", rules.to_str().unwrap()]);
+        let node = testing::chat_node(Duration::ZERO);
+        for id in ["cuda", "mac", "silent"] {
+            testing::announce(&state, "lmstudio", id, &node);
+        }
+        state.set_node_platform(&LMSTUDIO, &testing::node_id("mac"), platform("macos", "aarch64", "metal", "unified", Some(64)));
+        state.set_node_platform(&LMSTUDIO, &testing::node_id("cuda"), platform("linux", "x86_64", "cuda", "discrete", Some(128)));
+        let app = init_service(balancer::app(state.clone())).await;
+        let served_by = |content: String| {
+            let app = &app;
+            async move {
+                let res = call_service(app, testing::chat_with(json!({ "messages": [{ "role": "user", "content": content }] })).to_request()).await;
+                assert_eq!(res.status(), 200);
+                res.headers().get("X-LMServer-Node-Id").unwrap().to_str().unwrap().to_string()
+            }
+        };
+        let long = "palabra ".repeat(400);
+
+        for _ in 0..4 {
+            assert_eq!(served_by(long.clone()).await, "mac");
+        }
+        let mut short = Vec::new();
+        for _ in 0..3 {
+            short.push(served_by("hola".to_string()).await);
+        }
+        // Los cortos no pasan por la regla: el nivel por defecto (`gpu=true`) los reparte entre
+        // los dos nodos con GPU.
+        short.sort();
+        short.dedup();
+        assert_eq!(short, ["cuda", "mac"]);
+
+        // Sin nodo metal libre, los largos van a cualquier otro.
+        state.update_node_state(&LMSTUDIO, &testing::node_id("mac"), NodeHealth::Failed(Instant::now()), TransitionCause::HealthCheck);
+        assert_ne!(served_by(long.clone()).await, "mac");
+
+        // `/nodes` enseña la plataforma, con `unknown` para el que nunca la anunció.
+        let body: Value = read_body_json(call_service(&app, TestRequest::get().uri("/nodes").to_request()).await).await;
+        let platform_of = |id: &str| body["nodes"].as_array().unwrap().iter().find(|node| node["node_id"] == id).unwrap()["platform"].clone();
+        assert_eq!(platform_of("mac"), json!({ "os": "macos", "arch": "aarch64", "accelerator": "metal", "memory": "unified", "memory_bytes": 64u64 << 30 }));
+        assert_eq!(platform_of("silent")["accelerator"], UNKNOWN);
+        assert_eq!(platform_of("silent")["memory_bytes"], Value::Null);
+    }
+}
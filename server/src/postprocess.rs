@@ -0,0 +1,369 @@
+// src/postprocess.rs
+//! Post-procesado opcional del texto generado, por pool (desactivado por defecto).
+//!
+//! Para backends que ignoran `stop` o devuelven basura alrededor del texto:
+//! - `strip-tokens`: quita tokens especiales que se escapan de algunas plantillas de chat
+//!   (`--strip-token <pool>=<token>`).
+//! - `stop`: corta en la primera secuencia `stop` que mandó el cliente y pone
+//!   `finish_reason: "stop"`.
+//! - `trim`: quita los espacios al principio y al final.
+//!
+//! Se aplican en ese orden sobre `choices[].message.content` (o `text`) de la respuesta
+//! completa, y sobre `choices[].delta.content` en streaming SSE. En streaming se retiene el
+//! final de cada fragmento que podría ser el principio de una secuencia de stop, de un token
+//! o (con `trim`) espacios finales, hasta ver cómo sigue. Los post-procesados que se aplican
+//! a una respuesta se indican en la cabecera `X-LMServer-Postprocess` y en la auditoría.
+use actix_web::web::Bytes;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+pub const POSTPROCESS_HEADER: &str = "X-LMServer-Postprocess";
+
+const STRIP_TOKENS: &str = "strip-tokens";
+const STOP: &str = "stop";
+const TRIM: &str = "trim";
+
+#[derive(Debug)]
+pub struct PostprocessConfigError(String);
+
+impl fmt::Display for PostprocessConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for PostprocessConfigError {}
+
+/// Post-procesados configurados en una pool.
+#[derive(Clone, Debug, Default)]
+pub struct PoolPostprocess {
+    stop: bool,
+    trim: bool,
+    strip_tokens: Vec<String>,
+}
+
+impl PoolPostprocess {
+    pub fn names(&self) -> Vec<&'static str> {
+        let mut names = Vec::new();
+        if !self.strip_tokens.is_empty() {
+            names.push(STRIP_TOKENS);
+        }
+        if self.stop {
+            names.push(STOP);
+        }
+        if self.trim {
+            names.push(TRIM);
+        }
+        names
+    }
+}
+
+#[derive(Default)]
+pub struct Postprocessors {
+    pools: HashMap<String, PoolPostprocess>,
+}
+
+/// Separa una entrada `pool=valor` de la línea de comandos.
+fn split_entry<'a>(entry: &'a str, flag: &str) -> Result<(&'a str, &'a str), PostprocessConfigError> {
+    match entry.split_once('=') {
+        Some((pool, value)) if !pool.trim().is_empty() && !value.is_empty() => Ok((pool.trim(), value)),
+        _ => Err(PostprocessConfigError(format!("Entrada inválida '{}' en {}: se esperaba <pool>=<valor>", entry, flag))),
+    }
+}
+
+impl Postprocessors {
+    /// Interpreta `--postprocess <pool>=stop|trim` y `--strip-token <pool>=<token>`.
+    pub fn new(pools: &[&str], hooks: &[String], strip_tokens: &[String]) -> Result<Self, PostprocessConfigError> {
+        let mut configured: HashMap<String, PoolPostprocess> = HashMap::new();
+        let known = |pool: &str, flag: &str| {
+            if pools.contains(&pool) {
+                Ok(())
+            } else {
+                Err(PostprocessConfigError(format!("Pool desconocida '{}' en {}", pool, flag)))
+            }
+        };
+        for entry in hooks {
+            let (pool, hook) = split_entry(entry, "--postprocess")?;
+            known(pool, "--postprocess")?;
+            let pool = configured.entry(pool.to_string()).or_default();
+            match hook.trim() {
+                STOP => pool.stop = true,
+                TRIM => pool.trim = true,
+                other => {
+                    return Err(PostprocessConfigError(format!(
+                        "Post-procesado desconocido '{}' en --postprocess: se admiten '{}' y '{}' ('{}' se activa con --strip-token)",
+                        other, STOP, TRIM, STRIP_TOKENS
+                    )));
+                }
+            }
+        }
+        for entry in strip_tokens {
+            let (pool, token) = split_entry(entry, "--strip-token")?;
+            known(pool, "--strip-token")?;
+            configured.entry(pool.to_string()).or_default().strip_tokens.push(token.to_string());
+        }
+        Ok(Self { pools: configured })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &PoolPostprocess)> {
+        self.pools.iter().map(|(pool, config)| (pool.as_str(), config))
+    }
+
+    /// Lo que hay que aplicar a la respuesta de esta petición, o `None` si nada.
+    /// `stop` sólo se aplica si el cliente mandó secuencias de stop.
+    pub fn plan(&self, service: &str, req_body: &[u8]) -> Option<ResponsePlan> {
+        let config = self.pools.get(service)?;
+        let stop = if config.stop { requested_stops(req_body) } else { Vec::new() };
+        let plan = ResponsePlan { strip_tokens: config.strip_tokens.clone(), stop, trim: config.trim };
+        (!plan.applied().is_empty()).then_some(plan)
+    }
+}
+
+/// `stop` de la petición: una cadena o una lista de cadenas.
+fn requested_stops(req_body: &[u8]) -> Vec<String> {
+    let Ok(body) = serde_json::from_slice::<Value>(req_body) else {
+        return Vec::new();
+    };
+    let stops = match body.get("stop") {
+        Some(Value::String(stop)) => vec![stop.clone()],
+        Some(Value::Array(stops)) => stops.iter().filter_map(Value::as_str).map(str::to_string).collect(),
+        _ => Vec::new(),
+    };
+    stops.into_iter().filter(|stop| !stop.is_empty()).collect()
+}
+
+/// Post-procesados que se aplican a una respuesta concreta.
+#[derive(Clone, Debug)]
+pub struct ResponsePlan {
+    strip_tokens: Vec<String>,
+    stop: Vec<String>,
+    trim: bool,
+}
+
+impl ResponsePlan {
+    /// Nombres de los post-procesados aplicados, en orden, para la cabecera y la auditoría.
+    pub fn applied(&self) -> Vec<String> {
+        let mut applied = Vec::new();
+        if !self.strip_tokens.is_empty() {
+            applied.push(STRIP_TOKENS.to_string());
+        }
+        if !self.stop.is_empty() {
+            applied.push(STOP.to_string());
+        }
+        if self.trim {
+            applied.push(TRIM.to_string());
+        }
+        applied
+    }
+
+    /// Aplica el plan a una respuesta completa. `None` si no es JSON con `choices` y se deja tal cual.
+    pub fn apply_body(&self, body: &[u8]) -> Option<Bytes> {
+        let mut json: Value = serde_json::from_slice(body).ok()?;
+        for choice in json.get_mut("choices")?.as_array_mut()? {
+            let field = if choice.pointer("/message/content").is_some_and(Value::is_string) { "/message/content" } else { "/text" };
+            let Some(Value::String(text)) = choice.pointer_mut(field) else {
+                continue;
+            };
+            let mut state = TextState::default();
+            let mut processed = state.push(self, text);
+            processed.push_str(&state.finish(self));
+            *text = processed;
+            if state.stopped {
+                choice["finish_reason"] = json!("stop");
+            }
+        }
+        serde_json::to_vec(&json).ok().map(Bytes::from)
+    }
+
+    /// Cuántos bytes del final de `text` hay que retener porque podrían empezar una secuencia de stop o un token.
+    fn held_suffix(&self, text: &str) -> usize {
+        self.stop
+            .iter()
+            .chain(&self.strip_tokens)
+            .flat_map(|pattern| (1..pattern.len()).filter(|&len| pattern.is_char_boundary(len)).map(move |len| &pattern[..len]))
+            .filter(|prefix| text.ends_with(prefix))
+            .map(str::len)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Texto de una `choice` pendiente de emitir.
+#[derive(Default)]
+struct TextState {
+    held: String,
+    /// Ya se emitió algo que no era espacio (para `trim` al principio).
+    started: bool,
+    /// Se encontró una secuencia de stop: no se emite nada más.
+    stopped: bool,
+    /// Ya se mandó el `finish_reason` de esta `choice`.
+    finished: bool,
+}
+
+impl TextState {
+    /// Añade un fragmento y devuelve lo que ya se puede emitir.
+    fn push(&mut self, plan: &ResponsePlan, piece: &str) -> String {
+        if self.stopped {
+            return String::new();
+        }
+        self.held.push_str(piece);
+        for token in &plan.strip_tokens {
+            if self.held.contains(token.as_str()) {
+                self.held = self.held.replace(token.as_str(), "");
+            }
+        }
+        if let Some(position) = plan.stop.iter().filter_map(|stop| self.held.find(stop.as_str())).min() {
+            self.held.truncate(position);
+            self.stopped = true;
+            return self.finish(plan);
+        }
+        let mut ready = self.held.len() - plan.held_suffix(&self.held);
+        if plan.trim {
+            ready = self.held[..ready].trim_end().len();
+        }
+        let emitted: String = self.held.drain(..ready).collect();
+        self.emit(plan, emitted)
+    }
+
+    /// Vacía lo retenido al terminar la `choice`.
+    fn finish(&mut self, plan: &ResponsePlan) -> String {
+        let mut rest = std::mem::take(&mut self.held);
+        if plan.trim {
+            rest.truncate(rest.trim_end().len());
+        }
+        self.emit(plan, rest)
+    }
+
+    fn emit(&mut self, plan: &ResponsePlan, text: String) -> String {
+        let text = if plan.trim && !self.started { text.trim_start().to_string() } else { text };
+        self.started |= !text.is_empty();
+        text
+    }
+}
+
+/// Reescribe al vuelo un stream SSE de chat completions según un plan.
+pub struct StreamRewriter {
+    plan: ResponsePlan,
+    /// Bytes de un evento aún incompleto.
+    pending: Vec<u8>,
+    choices: BTreeMap<u64, TextState>,
+    /// Último evento JSON visto, como plantilla (id, model, created) de los fragmentos sintéticos.
+    template: Option<Value>,
+    flushed: bool,
+}
+
+impl StreamRewriter {
+    pub fn new(plan: ResponsePlan) -> Self {
+        Self { plan, pending: Vec::new(), choices: BTreeMap::new(), template: None, flushed: false }
+    }
+
+    /// Procesa un fragmento del nodo y devuelve los eventos completos ya reescritos.
+    pub fn push(&mut self, chunk: &[u8]) -> Bytes {
+        self.pending.extend_from_slice(chunk);
+        let mut out = Vec::new();
+        while let Some(end) = event_end(&self.pending) {
+            let event: Vec<u8> = self.pending.drain(..end).collect();
+            out.extend(self.rewrite_event(&event));
+        }
+        Bytes::from(out)
+    }
+
+    /// Fin del stream: procesa el último evento sin cerrar y emite el texto aún retenido.
+    pub fn finish(&mut self) -> Bytes {
+        let mut out = Vec::new();
+        if !self.pending.is_empty() {
+            let event = std::mem::take(&mut self.pending);
+            out.extend(self.rewrite_event(&event));
+        }
+        out.extend(self.flush());
+        Bytes::from(out)
+    }
+
+    fn rewrite_event(&mut self, event: &[u8]) -> Vec<u8> {
+        let Ok(text) = std::str::from_utf8(event) else {
+            return event.to_vec();
+        };
+        let data: Vec<&str> = text.lines().filter_map(|line| line.strip_prefix("data:")).map(str::trim_start).collect();
+        if data.is_empty() {
+            return event.to_vec();
+        }
+        let data = data.join("\n");
+        if data.trim() == "[DONE]" {
+            let mut out = self.flush();
+            out.extend_from_slice(event);
+            return out;
+        }
+        let Ok(mut json) = serde_json::from_str::<Value>(&data) else {
+            return event.to_vec();
+        };
+        self.template = Some(json.clone());
+        let Some(choices) = json.get_mut("choices").and_then(Value::as_array_mut) else {
+            return event.to_vec();
+        };
+        let had_choices = !choices.is_empty();
+        let plan = &self.plan;
+        choices.retain_mut(|choice| {
+            let state = self.choices.entry(choice.get("index").and_then(Value::as_u64).unwrap_or(0)).or_default();
+            if state.finished {
+                return false;
+            }
+            let mut emitted = match choice.pointer("/delta/content").and_then(Value::as_str) {
+                Some(content) => state.push(plan, content),
+                None => String::new(),
+            };
+            if state.stopped {
+                choice["finish_reason"] = json!("stop");
+                state.finished = true;
+            } else if choice.get("finish_reason").is_some_and(|reason| !reason.is_null()) {
+                emitted.push_str(&state.finish(plan));
+                state.finished = true;
+            }
+            if let Some(content) = choice.pointer_mut("/delta/content") {
+                *content = json!(emitted);
+            } else if !emitted.is_empty() {
+                choice["delta"]["content"] = json!(emitted);
+            }
+            true
+        });
+        if had_choices && choices.is_empty() {
+            return Vec::new();
+        }
+        format!("data: {}\n\n", json).into_bytes()
+    }
+
+    /// Emite, en fragmentos sintéticos, el texto retenido de las `choices` que el nodo no cerró.
+    fn flush(&mut self) -> Vec<u8> {
+        if self.flushed {
+            return Vec::new();
+        }
+        self.flushed = true;
+        let mut out = Vec::new();
+        let Some(template) = &self.template else {
+            return out;
+        };
+        for (index, state) in &mut self.choices {
+            if state.finished {
+                continue;
+            }
+            let rest = state.finish(&self.plan);
+            if rest.is_empty() {
+                continue;
+            }
+            let mut chunk = template.clone();
+            chunk["choices"] = json!([{ "index": index, "delta": { "content": rest }, "finish_reason": null }]);
+            out.extend(format!("data: {}\n\n", chunk).into_bytes());
+        }
+        out
+    }
+}
+
+/// Fin (exclusivo) del primer evento SSE completo del buffer.
+fn event_end(buffer: &[u8]) -> Option<usize> {
+    let lf = buffer.windows(2).position(|w| w == b"\n\n").map(|at| at + 2);
+    let crlf = buffer.windows(4).position(|w| w == b"\r\n\r\n").map(|at| at + 4);
+    match (lf, crlf) {
+        (Some(lf), Some(crlf)) => Some(lf.min(crlf)),
+        (lf, crlf) => lf.or(crlf),
+    }
+}
@@ -0,0 +1,170 @@
+// src/preview.rs
+//! `POST /debug/preview`: muestra qué se enviaría a un nodo sin elegir nodo ni reenviar nada.
+//!
+//! Aplica las mismas transformaciones, en el mismo orden, que `handle_service_request`:
+//! validación (con `--strictness enforce`, también de los campos desconocidos), política de la
+//! API key, techo de `max_tokens` del perfil activo y límites de las cabeceras reenviadas.
+use actix_web::{post, web, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+use crate::balancer::{AppState};
+use crate::headers;
+use crate::keys;
+use crate::profiles;
+use crate::strictness::{self, Strictness};
+use crate::validation;
+
+#[derive(Deserialize)]
+pub struct PreviewRequest {
+    pool: String,
+    /// API key con la que simular la petición, para aplicar su política.
+    api_key: Option<String>,
+    /// Cabeceras que enviaría el cliente.
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    request: Value,
+}
+
+#[derive(Serialize)]
+struct FieldChange {
+    field: String,
+    before: Option<Value>,
+    after: Option<Value>,
+}
+
+#[derive(Serialize)]
+struct AppliedTransform {
+    name: String,
+    changes: Vec<FieldChange>,
+}
+
+/// Campos de primer nivel que cambian entre dos cuerpos JSON.
+fn diff_fields(before: &Value, after: &Value) -> Vec<FieldChange> {
+    let (Some(before), Some(after)) = (before.as_object(), after.as_object()) else {
+        return Vec::new();
+    };
+    let mut fields: Vec<&String> = before.keys().chain(after.keys()).collect();
+    fields.sort();
+    fields.dedup();
+    fields
+        .into_iter()
+        .filter(|field| before.get(*field) != after.get(*field))
+        .map(|field| FieldChange {
+            field: field.clone(),
+            before: before.get(field).cloned(),
+            after: after.get(field).cloned(),
+        })
+        .collect()
+}
+
+#[post("/debug/preview")]
+async fn preview_handler(state: web::Data<AppState>, preview: web::Json<PreviewRequest>) -> impl Responder {
+    let PreviewRequest { pool, api_key, headers: client_headers, request } = preview.into_inner();
+    if state.pool(&pool).is_none() {
+        return HttpResponse::NotFound().json(json!({ "error": format!("Pool desconocida '{}'.", pool) }));
+    }
+
+    let mut body = serde_json::to_vec(&request).unwrap_or_default();
+    let chat_request = match validation::validate_chat_request(&body) {
+        Ok(chat_request) => chat_request,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "error": e.to_string(), "param": e.param })),
+    };
+    let service = state.canonical_service(&pool);
+    if state.profiles.settings().strictness.of(service) == Strictness::Enforce {
+        let unknown = strictness::unknown_fields(service, &chat_request.extra);
+        if let Some(first) = unknown.first() {
+            return HttpResponse::Ok().json(json!({
+                "pool": pool,
+                "rejected": {
+                    "status": 400,
+                    "type": "invalid_request_error",
+                    "param": first,
+                    "message": format!("Campos de primer nivel desconocidos: {}.", unknown.join(", ")),
+                },
+            }));
+        }
+    }
+
+    let mut transforms = Vec::new();
+    let mut overridden: Vec<&'static str> = Vec::new();
+    let mut current = request;
+    let policy = api_key.as_deref().and_then(|key| state.key_policies.lookup(key));
+    if let Some((policy_name, policy)) = policy {
+        match policy.apply(&body) {
+            Ok(applied) => {
+                overridden = applied.overridden;
+                if let Some(rewritten) = applied.body {
+                    body = rewritten.to_vec();
+                }
+            }
+            Err(denied) => {
+                return HttpResponse::Ok().json(json!({
+                    "pool": pool,
+                    "api_key_policy": policy_name,
+                    "rejected": {
+                        "status": 403,
+                        "type": "model_not_allowed",
+                        "model": denied.model,
+                        "allowed_models": denied.allowed,
+                    },
+                }));
+            }
+        }
+        let after: Value = serde_json::from_slice(&body).unwrap_or_default();
+        transforms.push(AppliedTransform { name: format!("api_key_policy:{}", policy_name), changes: diff_fields(&current, &after) });
+        current = after;
+    }
+
+    let settings = state.profiles.settings();
+    if let Some(clamped) = settings.max_tokens.and_then(|ceiling| profiles::apply_max_tokens_ceiling(&body, ceiling)) {
+        body = clamped.to_vec();
+        let after: Value = serde_json::from_slice(&body).unwrap_or_default();
+        transforms.push(AppliedTransform {
+            name: format!("max_tokens_ceiling:{}", state.profiles.active_name()),
+            changes: diff_fields(&current, &after),
+        });
+        current = after;
+    }
+
+    let whitelist = state.header_whitelists.get(&pool);
+    let whitelisted = client_headers
+        .into_iter()
+        .map(|(name, value)| (name.to_ascii_lowercase(), value.into_bytes()))
+        .filter(|(name, _)| whitelist.is_some_and(|w| w.allows(name)))
+        .collect();
+    let limited = match state.header_limits.apply(whitelisted) {
+        Ok(limited) => limited,
+        Err(e) => {
+            return HttpResponse::Ok().json(json!({
+                "pool": pool,
+                "rejected": { "status": 400, "type": "invalid_request_error", "message": e.to_string() },
+            }));
+        }
+    };
+    let mut upstream_headers = BTreeMap::from([("content-type".to_string(), "application/json".to_string())]);
+    for (name, value) in limited.forwarded {
+        let value = String::from_utf8_lossy(&value).into_owned();
+        let value = if headers::is_sensitive(&name) { headers::mask_value(&value) } else { value };
+        upstream_headers.insert(name, value);
+    }
+    let mut response_headers: BTreeMap<&str, String> = BTreeMap::new();
+    if !overridden.is_empty() {
+        response_headers.insert(keys::OVERRIDE_HEADER, overridden.join(","));
+    }
+    if !limited.dropped.is_empty() {
+        let dropped: Vec<_> = limited.dropped.into_iter().map(|(name, _)| name).collect();
+        response_headers.insert(headers::DROPPED_HEADERS_HEADER, dropped.join(","));
+    }
+
+    HttpResponse::Ok().json(json!({
+        "pool": pool,
+        "api_key_policy": policy.map(|(name, _)| name),
+        "profile": state.profiles.active_name(),
+        "transforms": transforms,
+        "upstream_body": current,
+        "upstream_headers": upstream_headers,
+        "response_headers": response_headers,
+    }))
+}
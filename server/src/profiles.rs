@@ -0,0 +1,363 @@
+// src/profiles.rs
+//! Perfiles de configuración con horario.
+//!
+//! ```toml
+//! [profiles.day]
+//! active = "09:00-18:00 Mon-Fri"
+//! queue_timeout_secs = 10
+//!
+//! [profiles.night]
+//! active = "22:00-07:00"
+//! queue_timeout_secs = 600
+//! max_tokens = 8192
+//! strictness = { ollama = "enforce" }
+//! ```
+//!
+//! Fuera de cualquier horario rige el perfil `default` (los valores de la línea de comandos).
+use actix_web::web::{self, Bytes};
+use chrono::{DateTime, Datelike, Local, NaiveTime, TimeZone, Weekday};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::Duration;
+use tokio::time::interval;
+
+use crate::balancer::AppState;
+use crate::events::BalancerEvent;
+use crate::strictness::{PoolStrictness, StrictnessOverrides};
+
+/// Nombre del perfil que usa los valores base cuando ningún horario está activo.
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Cada cuánto se reevalúan los horarios.
+pub const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub struct ProfileError(String);
+
+impl fmt::Display for ProfileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ProfileError {}
+
+/// Ajustes que un perfil puede cambiar en caliente.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct RuntimeSettings {
+    pub queue_timeout_secs: u64,
+    /// Techo de `max_tokens` (`options.num_predict` en Ollama). `None` deja pasar lo que pida el cliente.
+    pub max_tokens: Option<u64>,
+    /// Validación de peticiones y respuestas de cada pool (`strictness`).
+    pub strictness: PoolStrictness,
+}
+
+impl RuntimeSettings {
+    pub fn queue_timeout(&self) -> Duration {
+        Duration::from_secs(self.queue_timeout_secs)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileDef {
+    active: String,
+    queue_timeout_secs: Option<u64>,
+    max_tokens: Option<u64>,
+    #[serde(default)]
+    strictness: StrictnessOverrides,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfilesFile {
+    #[serde(default)]
+    profiles: BTreeMap<String, ProfileDef>,
+}
+
+/// Franja horaria `HH:MM-HH:MM` con días opcionales (`Mon-Fri`, `Sat,Sun`). Si el fin es
+/// anterior al inicio, la franja cruza la medianoche y el día se refiere al de inicio.
+#[derive(Clone)]
+struct Schedule {
+    spec: String,
+    start: NaiveTime,
+    end: NaiveTime,
+    days: [bool; 7],
+}
+
+impl Schedule {
+    fn parse(spec: &str) -> Result<Self, String> {
+        let mut parts = spec.split_whitespace();
+        let range = parts.next().ok_or("horario vacío")?;
+        let (start, end) = range.split_once('-').ok_or("se esperaba HH:MM-HH:MM")?;
+        let parse_time = |t: &str| NaiveTime::parse_from_str(t, "%H:%M").map_err(|_| format!("hora inválida '{}'", t));
+        let (start, end) = (parse_time(start)?, parse_time(end)?);
+
+        let mut days = [true; 7];
+        if let Some(day_spec) = parts.next() {
+            days = [false; 7];
+            for item in day_spec.split(',') {
+                let parse_day = |d: &str| d.parse::<Weekday>().map_err(|_| format!("día inválido '{}'", d));
+                let (first, last) = match item.split_once('-') {
+                    Some((first, last)) => (parse_day(first)?, parse_day(last)?),
+                    None => (parse_day(item)?, parse_day(item)?),
+                };
+                let mut day = first;
+                loop {
+                    days[day.num_days_from_monday() as usize] = true;
+                    if day == last {
+                        break;
+                    }
+                    day = day.succ();
+                }
+            }
+        }
+        if parts.next().is_some() {
+            return Err("texto sobrante tras los días".to_string());
+        }
+        Ok(Self { spec: spec.to_string(), start, end, days })
+    }
+
+    fn contains<Tz: TimeZone>(&self, now: &DateTime<Tz>) -> bool {
+        let time = now.time();
+        let today = self.days[now.weekday().num_days_from_monday() as usize];
+        let yesterday = self.days[now.weekday().pred().num_days_from_monday() as usize];
+        if self.start < self.end {
+            today && time >= self.start && time < self.end
+        } else if self.start > self.end {
+            (today && time >= self.start) || (yesterday && time < self.end)
+        } else {
+            today
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Profile {
+    name: String,
+    schedule: Schedule,
+    queue_timeout_secs: Option<u64>,
+    max_tokens: Option<u64>,
+    strictness: StrictnessOverrides,
+}
+
+impl Profile {
+    fn apply(&self, base: RuntimeSettings) -> RuntimeSettings {
+        RuntimeSettings {
+            queue_timeout_secs: self.queue_timeout_secs.unwrap_or(base.queue_timeout_secs),
+            max_tokens: self.max_tokens.or(base.max_tokens),
+            strictness: base.strictness.with(&self.strictness),
+        }
+    }
+}
+
+struct ActiveProfile {
+    name: String,
+    pinned: bool,
+}
+
+/// Perfiles definidos en el archivo, en orden alfabético.
+#[derive(Clone, Default)]
+pub struct ProfileSet(Vec<Profile>);
+
+impl ProfileSet {
+    /// Lee los perfiles de un archivo TOML.
+    fn read(path: &Path) -> Result<Self, ProfileError> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| ProfileError(format!("No se pudo leer {}: {}", path.display(), e)))?;
+        let file: ProfilesFile = toml::from_str(&content)
+            .map_err(|e| ProfileError(format!("Archivo de perfiles inválido {}: {}", path.display(), e)))?;
+
+        let mut profiles = Vec::new();
+        for (name, def) in file.profiles {
+            if name == DEFAULT_PROFILE {
+                return Err(ProfileError(format!("El nombre de perfil '{}' está reservado.", DEFAULT_PROFILE)));
+            }
+            let schedule = Schedule::parse(&def.active)
+                .map_err(|e| ProfileError(format!("Horario inválido en el perfil '{}' ('{}'): {}", name, def.active, e)))?;
+            profiles.push(Profile {
+                name,
+                schedule,
+                queue_timeout_secs: def.queue_timeout_secs,
+                max_tokens: def.max_tokens,
+                strictness: def.strictness,
+            });
+        }
+        Ok(Self(profiles))
+    }
+
+    /// Perfiles con su horario y sus ajustes sobre `base`.
+    pub fn describe(&self, base: RuntimeSettings) -> serde_json::Value {
+        let mut profiles = serde_json::Map::new();
+        profiles.insert(DEFAULT_PROFILE.to_string(), serde_json::json!({ "settings": base }));
+        for profile in &self.0 {
+            profiles.insert(
+                profile.name.clone(),
+                serde_json::json!({ "active": profile.schedule.spec, "settings": profile.apply(base) }),
+            );
+        }
+        serde_json::Value::Object(profiles)
+    }
+}
+
+/// Perfiles cargados y el perfil activo (por horario o fijado manualmente).
+pub struct ProfileManager {
+    base: RuntimeSettings,
+    profiles: RwLock<ProfileSet>,
+    /// Archivo de perfiles, para recargarlo (`reload`).
+    source: Option<PathBuf>,
+    active: RwLock<ActiveProfile>,
+}
+
+impl ProfileManager {
+    /// Gestor sin perfiles: siempre rige `default`.
+    pub fn new(base: RuntimeSettings) -> Self {
+        Self {
+            base,
+            profiles: RwLock::default(),
+            source: None,
+            active: RwLock::new(ActiveProfile { name: DEFAULT_PROFILE.to_string(), pinned: false }),
+        }
+    }
+
+    /// Carga los perfiles de un archivo TOML. Si varios horarios coinciden gana el primero por orden alfabético.
+    pub fn load(path: &Path, base: RuntimeSettings) -> Result<Self, ProfileError> {
+        let profiles = ProfileSet::read(path)?;
+        Ok(Self { profiles: RwLock::new(profiles), source: Some(path.to_path_buf()), ..Self::new(base) })
+    }
+
+    pub fn base(&self) -> RuntimeSettings {
+        self.base
+    }
+
+    /// Vuelve a leer el archivo de perfiles, sin aplicarlo. `None` si no hay archivo.
+    pub fn read_source(&self) -> Option<Result<ProfileSet, ProfileError>> {
+        self.source.as_deref().map(ProfileSet::read)
+    }
+
+    pub fn current(&self) -> ProfileSet {
+        self.profiles.read().unwrap().clone()
+    }
+
+    /// Sustituye los perfiles. Si el activo desaparece se vuelve a los horarios. Devuelve
+    /// `(anterior, nuevo)` si el perfil activo cambió.
+    pub fn replace(&self, profiles: ProfileSet, now: &DateTime<Local>) -> Option<(String, String)> {
+        let previous = self.active_name();
+        *self.profiles.write().unwrap() = profiles;
+        {
+            let mut active = self.active.write().unwrap();
+            if active.name != DEFAULT_PROFILE && !self.profiles.read().unwrap().0.iter().any(|p| p.name == active.name) {
+                *active = ActiveProfile { name: DEFAULT_PROFILE.to_string(), pinned: false };
+            }
+        }
+        self.refresh(now);
+        let current = self.active_name();
+        (previous != current).then_some((previous, current))
+    }
+
+    pub fn active_name(&self) -> String {
+        self.active.read().unwrap().name.clone()
+    }
+
+    pub fn is_pinned(&self) -> bool {
+        self.active.read().unwrap().pinned
+    }
+
+    /// Ajustes efectivos del perfil activo.
+    pub fn settings(&self) -> RuntimeSettings {
+        let active = self.active.read().unwrap();
+        self.profiles
+            .read()
+            .unwrap()
+            .0
+            .iter()
+            .find(|p| p.name == active.name)
+            .map_or(self.base, |p| p.apply(self.base))
+    }
+
+    /// Perfiles definidos con su horario, para `GET /config`.
+    pub fn describe(&self) -> serde_json::Value {
+        self.profiles.read().unwrap().describe(self.base)
+    }
+
+    /// Reevalúa los horarios. Devuelve `(anterior, nuevo)` si el perfil activo cambió.
+    pub fn refresh(&self, now: &DateTime<Local>) -> Option<(String, String)> {
+        let profiles = self.profiles.read().unwrap();
+        let scheduled = profiles
+            .0
+            .iter()
+            .find(|p| p.schedule.contains(now))
+            .map_or(DEFAULT_PROFILE, |p| p.name.as_str());
+        let mut active = self.active.write().unwrap();
+        if active.pinned || active.name == scheduled {
+            return None;
+        }
+        let previous = std::mem::replace(&mut active.name, scheduled.to_string());
+        Some((previous, active.name.clone()))
+    }
+
+    /// Fija un perfil hasta que se llame a `unpin`, ignorando los horarios.
+    pub fn pin(&self, name: &str) -> Result<Option<(String, String)>, ProfileError> {
+        if name != DEFAULT_PROFILE && !self.profiles.read().unwrap().0.iter().any(|p| p.name == name) {
+            return Err(ProfileError(format!("Perfil desconocido '{}'.", name)));
+        }
+        let mut active = self.active.write().unwrap();
+        active.pinned = true;
+        if active.name == name {
+            return Ok(None);
+        }
+        let previous = std::mem::replace(&mut active.name, name.to_string());
+        Ok(Some((previous, active.name.clone())))
+    }
+
+    /// Quita la fijación manual y vuelve a aplicar los horarios.
+    pub fn unpin(&self, now: &DateTime<Local>) -> Option<(String, String)> {
+        self.active.write().unwrap().pinned = false;
+        self.refresh(now)
+    }
+}
+
+/// Recorta `max_tokens` (u `options.num_predict` de Ollama) al techo del perfil.
+/// Devuelve el cuerpo reescrito sólo si hubo que recortar algo.
+pub fn apply_max_tokens_ceiling(body: &[u8], ceiling: u64) -> Option<Bytes> {
+    let mut json: serde_json::Value = serde_json::from_slice(body).ok()?;
+    let mut clamped = false;
+    for pointer in ["/max_tokens", "/options/num_predict"] {
+        if let Some(value) = json.pointer_mut(pointer) {
+            if value.as_u64().is_some_and(|requested| requested > ceiling) {
+                *value = ceiling.into();
+                clamped = true;
+            }
+        }
+    }
+    if !clamped {
+        return None;
+    }
+    serde_json::to_vec(&json).ok().map(Bytes::from)
+}
+
+/// Registra un cambio de perfil en el log y lo publica en `GET /events`.
+pub fn announce_switch(state: &AppState, (from, to): (String, String), reason: &str) {
+    info!("Profiles: Perfil activo {} -> {} ({}).", from, to, reason);
+    state.events.publish(BalancerEvent::ProfileChanged {
+        from,
+        to,
+        reason: reason.to_string(),
+        settings: state.profiles.settings(),
+    });
+}
+
+/// Aplica los horarios de los perfiles periódicamente.
+pub async fn run_scheduler(state: web::Data<AppState>) {
+    let mut ticker = interval(SCHEDULE_CHECK_INTERVAL);
+    loop {
+        ticker.tick().await;
+        if let Some(switch) = state.profiles.refresh(&Local::now()) {
+            announce_switch(&state, switch, "horario");
+        }
+    }
+}
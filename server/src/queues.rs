@@ -0,0 +1,125 @@
+// src/queues.rs
+//! Colas de espera por modelo.
+//!
+//! Una petición que no encuentra nodo libre espera en la cola de su pool y de su `model`. Cada
+//! cola es una FIFO propia: una petición para un modelo cuyo único nodo está ocupado no retiene
+//! a las de otro modelo con nodo libre, y el nodo que se libera se lo lleva la primera petición
+//! de un modelo que puede atender.
+//!
+//! Dentro de una cola, una petición sólo espera a las de delante que piden lo mismo que ella
+//! (clase de carga, longitud y capacidad): una batch frenada por la reserva interactiva o una
+//! larga que cede el turno a las cortas no frena a las que podrían ir a otro nodo. Las sesiones
+//! fijadas esperan a su nodo fuera de la cola; entran si lo pierden.
+//!
+//! Las colas existen mientras tienen peticiones: un modelo que aparece con un nodo nuevo abre la
+//! suya con la primera petición, y uno que desaparece con su último nodo deja la suya esperando
+//! hasta el tiempo de espera, por si vuelve. La profundidad de cada cola está en
+//! `/stats/summary` (`model_queues`) y en `lmserver_model_queue_depth`.
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+use crate::interleave::RequestLength;
+use crate::metrics::{escape_label, write_labeled_metric};
+use crate::workload::WorkloadClass;
+
+/// Cola de las peticiones sin `model`.
+pub const NO_MODEL: &str = "-";
+
+/// Lo que pide una petición en espera, aparte del modelo.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WaitShape {
+    pub class: WorkloadClass,
+    pub length: RequestLength,
+    pub capability: Option<String>,
+}
+
+#[derive(Default)]
+struct ModelQueue {
+    /// Peticiones en espera, en orden de llegada.
+    waiters: VecDeque<(u64, WaitShape)>,
+    /// Avisa a la cola cuando sale una petición, para que la siguiente no espere al sondeo.
+    advanced: Arc<Notify>,
+}
+
+#[derive(Default)]
+pub struct ModelQueues {
+    next_ticket: AtomicU64,
+    /// Por (pool, modelo).
+    queues: Mutex<HashMap<(String, String), ModelQueue>>,
+}
+
+impl ModelQueues {
+    /// Pone la petición a la cola de su modelo hasta que se suelte el turno.
+    pub fn enter(&self, service: &str, model: Option<&str>, shape: WaitShape) -> QueueTurn<'_> {
+        let key = (service.to_string(), model.unwrap_or(NO_MODEL).to_string());
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        let mut queues = self.queues.lock().unwrap();
+        let queue = queues.entry(key.clone()).or_default();
+        queue.waiters.push_back((ticket, shape));
+        QueueTurn { queues: self, key, ticket, advanced: queue.advanced.clone() }
+    }
+
+    /// Peticiones en espera por pool y modelo.
+    pub fn depths(&self) -> BTreeMap<String, BTreeMap<String, usize>> {
+        let mut depths: BTreeMap<String, BTreeMap<String, usize>> = BTreeMap::new();
+        for ((service, model), queue) in self.queues.lock().unwrap().iter() {
+            depths.entry(service.clone()).or_default().insert(model.clone(), queue.waiters.len());
+        }
+        depths
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        let samples = self.depths().into_iter().flat_map(|(pool, models)| {
+            models
+                .into_iter()
+                .map(move |(model, depth)| (format!("pool=\"{}\",model=\"{}\"", escape_label(&pool), escape_label(&model)), depth as u64))
+        });
+        write_labeled_metric(&mut out, "lmserver_model_queue_depth", "Peticiones esperando nodo, por pool y modelo.", "gauge", samples);
+        out
+    }
+}
+
+/// Plaza en la cola de un modelo; se deja al destruirse, consiga nodo o no.
+pub struct QueueTurn<'a> {
+    queues: &'a ModelQueues,
+    key: (String, String),
+    ticket: u64,
+    advanced: Arc<Notify>,
+}
+
+impl QueueTurn<'_> {
+    /// Si le toca probar suerte: nadie delante en la cola pide lo mismo.
+    pub fn is_next(&self) -> bool {
+        let queues = self.queues.queues.lock().unwrap();
+        let Some(queue) = queues.get(&self.key) else {
+            return true;
+        };
+        let mut ahead = queue.waiters.iter().take_while(|(ticket, _)| *ticket != self.ticket);
+        match queue.waiters.iter().find(|(ticket, _)| *ticket == self.ticket) {
+            Some((_, shape)) => !ahead.any(|(_, other)| other == shape),
+            None => true,
+        }
+    }
+
+    /// Espera a que salga alguien de la cola, como mucho `poll`.
+    pub async fn wait(&self, poll: Duration) {
+        let _ = tokio::time::timeout(poll, self.advanced.notified()).await;
+    }
+}
+
+impl Drop for QueueTurn<'_> {
+    fn drop(&mut self) {
+        let mut queues = self.queues.queues.lock().unwrap();
+        if let Some(queue) = queues.get_mut(&self.key) {
+            queue.waiters.retain(|(ticket, _)| *ticket != self.ticket);
+            if queue.waiters.is_empty() {
+                queues.remove(&self.key);
+            }
+        }
+        self.advanced.notify_waiters();
+    }
+}
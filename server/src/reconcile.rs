@@ -0,0 +1,153 @@
+// src/reconcile.rs
+//! Reconciliación de nodos Busy sin petición (`--stale-busy-grace-secs`).
+//!
+//! Un nodo queda Busy para siempre si el camino que debía liberarlo no llega a correr (una tarea
+//! abortada al reiniciarse un worker de actix, un pánico al tratar la respuesta). El registro de
+//! peticiones activas es el contador `in_flight` de cada nodo, que baja al soltar su guarda
+//! (`InFlight`) pase lo que pase con la tarea. La tarea de limpieza cruza ambos: un nodo Busy
+//! desde hace más del margen, sin peticiones en curso, sin reserva de `X-Pipeline` y sin prueba
+//! de capacidades en marcha es una fuga de estado. Lo mismo un nodo en Loading más allá del
+//! timeout de carga de modelos más el margen (la petición que espera la carga aún no cuenta en
+//! `in_flight`). Se avisa con el último `X-Request-Id` que lo tocó, se cuenta en
+//! `lmserver_stale_busy_reconciliations_total` y vuelve a Available con la causa `reconcile`.
+//!
+//! Cualquier valor distinto de cero en el contador es un fallo del balanceador. El barrido lee
+//! cada pool una vez y toma el lock de escritura sólo para cada nodo sospechoso, volviendo a
+//! comprobarlo antes de tocarlo.
+use log::warn;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use crate::balancer::{AppState, NodeHealth, NodeInfo};
+use crate::history::TransitionCause;
+use crate::ids::NodeId;
+
+pub const DEFAULT_STALE_BUSY_GRACE_SECS: u64 = 30;
+
+/// Si el nodo está Busy (o Loading) sin nada que lo justifique desde hace más de su margen.
+fn is_stale(state: &AppState, service: &str, unique_node_id: &str, info: &NodeInfo, now: Instant, grace: Duration) -> bool {
+    let limit = match info.state {
+        NodeHealth::Busy => grace,
+        NodeHealth::Loading => state.model_loader.timeout() + grace,
+        _ => return false,
+    };
+    info.in_flight() == 0
+        && info.busy_since.is_some_and(|since| now.saturating_duration_since(since) >= limit)
+        && !state.pipeline.is_reserved(service, unique_node_id)
+        && !state.reprober.is_running(unique_node_id)
+}
+
+/// Devuelve a Available los nodos Busy o Loading que ninguna petición ocupa. Corre en la tarea
+/// de limpieza.
+pub fn sweep(state: &AppState) {
+    let Some(grace) = state.stale_busy_grace else {
+        return;
+    };
+    for (_, service, lock) in state.pools() {
+        let now = Instant::now();
+        let suspects: Vec<NodeId> = lock
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(unique_id, info)| is_stale(state, service, unique_id, info, now, grace))
+            .map(|(unique_id, _)| unique_id.clone())
+            .collect();
+        for unique_node_id in suspects {
+            let mut nodes = lock.write().unwrap();
+            let Some(info) = nodes.get(&unique_node_id).filter(|info| is_stale(state, service, &unique_node_id, info, Instant::now(), grace)) else {
+                continue;
+            };
+            let stuck_in = info.state.label();
+            let stuck_secs = info.busy_since.map_or(0, |since| since.elapsed().as_secs());
+            let last_request_id = info.last_request_id.clone();
+            state.set_node_state(service, &mut nodes, &unique_node_id, NodeHealth::Available, TransitionCause::Reconcile);
+            drop(nodes);
+            state.metrics.stale_busy_reconciliations.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                "Reconciliación: fuga de estado en el nodo ID {} ({}): en {} desde hace {}s sin ninguna petición en curso; última petición {}. Vuelve a Available.",
+                unique_node_id,
+                service,
+                stuck_in,
+                stuck_secs,
+                last_request_id.as_deref().unwrap_or("(ninguna)")
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing;
+
+    /// Deja el nodo en `health` desde hace `secs` segundos, como si nadie lo hubiera liberado.
+    fn stick(state: &AppState, unique_node_id: &str, health: NodeHealth, secs: u64) {
+        let mut nodes = state.lm_studio_nodes.write().unwrap();
+        let info = nodes.get_mut(unique_node_id).unwrap();
+        info.state = health;
+        info.busy_since = Some(Instant::now() - Duration::from_secs(secs));
+        info.last_request_id = Some("req-1".to_string());
+    }
+
+    #[actix_web::test]
+    async fn stale_busy_node_goes_back_through_update_node_state() {
+        let state = testing::state(&["--stale-busy-grace-secs", "5"]);
+        let _consumers = testing::consumers(&state);
+        testing::announce(&state, "lmstudio", "box1", "http://10.0.0.1:1234/");
+        testing::announce(&state, "lmstudio", "box2", "http://10.0.0.2:1234/");
+        stick(&state, "box1", NodeHealth::Busy, 60);
+        stick(&state, "box2", NodeHealth::Busy, 1);
+        let revision = state.revisions.current();
+
+        sweep(&state);
+
+        assert_eq!(testing::node_state(&state, "lmstudio", "box1"), "available");
+        assert_eq!(testing::node_state(&state, "lmstudio", "box2"), "busy");
+        assert!(state.revisions.current() > revision);
+        assert_eq!(state.metrics.stale_busy_reconciliations.load(Ordering::Relaxed), 1);
+        testing::eventually(|| {
+            state.node_history.read().unwrap().get("box1").and_then(|log| log.back()).map(|t| (t.from, t.to, t.cause))
+                == Some(("busy", "available", TransitionCause::Reconcile))
+        })
+        .await;
+    }
+
+    #[actix_web::test]
+    async fn busy_node_with_a_request_in_flight_is_left_alone() {
+        let state = testing::state(&["--stale-busy-grace-secs", "5"]);
+        testing::announce(&state, "lmstudio", "box1", "http://10.0.0.1:1234/");
+        stick(&state, "box1", NodeHealth::Busy, 60);
+        state.lm_studio_nodes.read().unwrap()["box1"].in_flight.fetch_add(1, Ordering::Relaxed);
+
+        sweep(&state);
+
+        assert_eq!(testing::node_state(&state, "lmstudio", "box1"), "busy");
+        assert_eq!(state.metrics.stale_busy_reconciliations.load(Ordering::Relaxed), 0);
+    }
+
+    #[actix_web::test]
+    async fn loading_node_waits_for_the_load_timeout() {
+        let state = testing::state(&["--stale-busy-grace-secs", "5", "--model-load-timeout", "30"]);
+        testing::announce(&state, "lmstudio", "box1", "http://10.0.0.1:1234/");
+        testing::announce(&state, "lmstudio", "box2", "http://10.0.0.2:1234/");
+        stick(&state, "box1", NodeHealth::Loading, 20);
+        stick(&state, "box2", NodeHealth::Loading, 40);
+
+        sweep(&state);
+
+        assert_eq!(testing::node_state(&state, "lmstudio", "box1"), "loading");
+        assert_eq!(testing::node_state(&state, "lmstudio", "box2"), "available");
+    }
+
+    #[actix_web::test]
+    async fn disabled_sweep_touches_nothing() {
+        let state = testing::state(&["--stale-busy-grace-secs", "0"]);
+        testing::announce(&state, "lmstudio", "box1", "http://10.0.0.1:1234/");
+        stick(&state, "box1", NodeHealth::Busy, 60);
+
+        sweep(&state);
+
+        assert_eq!(testing::node_state(&state, "lmstudio", "box1"), "busy");
+    }
+}
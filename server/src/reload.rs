@@ -0,0 +1,411 @@
+// src/reload.rs
+//! Recarga en caliente de la configuración, con diff y vuelta atrás.
+//!
+//! Lo que se puede cambiar sin reiniciar son las reglas de enrutado (`--rules-file`) y los
+//! perfiles (`--profiles-file`). `POST /admin/reload` vuelve a leer los dos archivos y los
+//! aplica juntos: si alguno es inválido no se aplica nada. La recarga automática de las reglas
+//! al cambiar su archivo pasa por el mismo camino.
+//!
+//! Cada configuración aplicada se guarda en memoria (las últimas `--config-history`) y
+//! `POST /admin/config/rollback` vuelve a aplicar la anterior. Las dos operaciones devuelven,
+//! y publican en `config_reloaded`, el diff de lo que cambió: claves añadidas, quitadas o
+//! cambiadas con el valor anterior y el nuevo. El diff se calcula sobre la configuración ya
+//! resuelta (`resolved`), no sobre el texto de los archivos, así que incluye los ajustes base de
+//! la línea de comandos; los valores de claves con `token`, `secret` o `password` se enmascaran.
+use actix_web::{post, web, HttpResponse, Responder};
+use chrono::{DateTime, Local, Utc};
+use log::info;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+
+use crate::balancer::AppState;
+use crate::events::BalancerEvent;
+use crate::profiles::{self, ProfileSet, RuntimeSettings};
+use crate::rules::RuleSet;
+
+pub const DEFAULT_CONFIG_HISTORY: usize = 5;
+
+/// Partes del nombre de una clave cuyo valor no se muestra en el diff.
+const SECRET_KEYS: &[&str] = &["token", "secret", "password"];
+const MASK: &str = "***";
+
+/// Una clave que cambió entre dos configuraciones. `old` falta en las añadidas y `new` en las
+/// quitadas.
+#[derive(Clone, Debug, Serialize)]
+pub struct ConfigChange {
+    pub key: String,
+    pub change: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new: Option<Value>,
+}
+
+struct AppliedConfig {
+    revision: u64,
+    applied_at: DateTime<Utc>,
+    operation: &'static str,
+    rules: RuleSet,
+    profiles: ProfileSet,
+}
+
+struct History {
+    applied: VecDeque<AppliedConfig>,
+    last_revision: u64,
+}
+
+pub struct ConfigHistory {
+    limit: usize,
+    /// Se mantiene tomado mientras se aplica una configuración, para que dos recargas no se crucen.
+    history: Mutex<History>,
+}
+
+impl ConfigHistory {
+    /// Historial con la configuración del arranque como revisión 1.
+    pub fn new(limit: usize, rules: &RuleSet, profiles: ProfileSet) -> Self {
+        let startup = AppliedConfig { revision: 1, applied_at: Utc::now(), operation: "startup", rules: rules.clone(), profiles };
+        Self { limit: limit.max(1), history: Mutex::new(History { applied: VecDeque::from([startup]), last_revision: 1 }) }
+    }
+
+    /// Configuración para `/config`.
+    pub fn describe(&self) -> Value {
+        let history = self.history.lock().unwrap();
+        json!({
+            "limit": self.limit,
+            "applied": history
+                .applied
+                .iter()
+                .map(|applied| json!({ "revision": applied.revision, "applied_at": applied.applied_at.to_rfc3339(), "operation": applied.operation }))
+                .collect::<Vec<_>>(),
+        })
+    }
+}
+
+/// Configuración recargable resuelta: cada regla por nombre, con su posición, y cada perfil con
+/// sus ajustes efectivos.
+fn resolved(rules: &RuleSet, profiles: &ProfileSet, base: RuntimeSettings) -> Value {
+    let mut by_name = Map::new();
+    for (order, rule) in rules.rules().iter().enumerate() {
+        let mut entry = serde_json::to_value(rule).unwrap_or_default();
+        if let Value::Object(fields) = &mut entry {
+            fields.remove("name");
+            fields.insert("order".to_string(), order.into());
+        }
+        let name = if by_name.contains_key(&rule.name) { format!("{}#{}", rule.name, order) } else { rule.name.clone() };
+        by_name.insert(name, entry);
+    }
+    json!({ "rules": by_name, "profiles": profiles.describe(base) })
+}
+
+/// Hojas de `value` por clave con puntos (`rules.mi-regla.action.route`). Los arrays son hojas.
+fn flatten(prefix: &str, value: &Value, out: &mut BTreeMap<String, Value>) {
+    match value {
+        Value::Object(fields) if !fields.is_empty() => {
+            for (name, field) in fields {
+                let key = if prefix.is_empty() { name.clone() } else { format!("{}.{}", prefix, name) };
+                flatten(&key, field, out);
+            }
+        }
+        _ => {
+            out.insert(prefix.to_string(), value.clone());
+        }
+    }
+}
+
+fn masked(key: &str, value: Value) -> Value {
+    let last = key.rsplit('.').next().unwrap_or(key).to_ascii_lowercase();
+    if SECRET_KEYS.iter().any(|secret| last.contains(secret)) {
+        MASK.into()
+    } else {
+        value
+    }
+}
+
+/// `value` con los valores de las claves secretas enmascarados a cualquier profundidad.
+pub fn mask_secrets(value: Value) -> Value {
+    match value {
+        Value::Object(fields) => Value::Object(
+            fields
+                .into_iter()
+                .map(|(name, field)| {
+                    let field = masked(&name, field);
+                    (name, mask_secrets(field))
+                })
+                .collect(),
+        ),
+        Value::Array(items) => Value::Array(items.into_iter().map(mask_secrets).collect()),
+        other => other,
+    }
+}
+
+/// Claves añadidas, quitadas y cambiadas de `old` a `new`.
+fn diff(old: &Value, new: &Value) -> Vec<ConfigChange> {
+    let (mut before, mut after) = (BTreeMap::new(), BTreeMap::new());
+    flatten("", old, &mut before);
+    flatten("", new, &mut after);
+    let mut changes = Vec::new();
+    for (key, old_value) in &before {
+        match after.remove(key) {
+            None => changes.push(ConfigChange { key: key.clone(), change: "removed", old: Some(masked(key, old_value.clone())), new: None }),
+            Some(new_value) if new_value != *old_value => changes.push(ConfigChange {
+                key: key.clone(),
+                change: "changed",
+                old: Some(masked(key, old_value.clone())),
+                new: Some(masked(key, new_value)),
+            }),
+            Some(_) => {}
+        }
+    }
+    for (key, new_value) in after {
+        changes.push(ConfigChange { new: Some(masked(&key, new_value)), key, change: "added", old: None });
+    }
+    changes.sort_by(|a, b| a.key.cmp(&b.key));
+    changes
+}
+
+/// Sustituye lo indicado y devuelve lo que cambió.
+fn swap(state: &AppState, rules: Option<RuleSet>, profiles: Option<ProfileSet>) -> Vec<ConfigChange> {
+    let base = state.profiles.base();
+    let before = resolved(&state.routing_rules.read().unwrap(), &state.profiles.current(), base);
+    if let Some(mut rules) = rules {
+        rules.mark_seen();
+        *state.routing_rules.write().unwrap() = rules;
+    }
+    let switch = profiles.and_then(|profiles| state.profiles.replace(profiles, &Local::now()));
+    let after = resolved(&state.routing_rules.read().unwrap(), &state.profiles.current(), base);
+    if let Some(switch) = switch {
+        profiles::announce_switch(state, switch, "configuración recargada");
+    }
+    diff(&before, &after)
+}
+
+/// Aplica una configuración nueva y la guarda en el historial. Devuelve su revisión y el diff.
+pub fn apply(state: &AppState, rules: Option<RuleSet>, profiles: Option<ProfileSet>, operation: &'static str) -> (u64, Vec<ConfigChange>) {
+    let mut history = state.config_history.history.lock().unwrap();
+    let changes = swap(state, rules, profiles);
+    history.last_revision += 1;
+    let revision = history.last_revision;
+    let applied = AppliedConfig {
+        revision,
+        applied_at: Utc::now(),
+        operation,
+        rules: state.routing_rules.read().unwrap().clone(),
+        profiles: state.profiles.current(),
+    };
+    history.applied.push_back(applied);
+    while history.applied.len() > state.config_history.limit {
+        history.applied.pop_front();
+    }
+    drop(history);
+    announce(state, operation, revision, &changes);
+    (revision, changes)
+}
+
+/// Descarta la última configuración aplicada y vuelve a aplicar la anterior. Devuelve la
+/// revisión restaurada y el diff, o `None` si no queda ninguna anterior.
+fn rollback(state: &AppState) -> Option<(u64, Vec<ConfigChange>)> {
+    let mut history = state.config_history.history.lock().unwrap();
+    if history.applied.len() < 2 {
+        return None;
+    }
+    history.applied.pop_back();
+    let previous = history.applied.back()?;
+    let (revision, rules, profiles) = (previous.revision, previous.rules.clone(), previous.profiles.clone());
+    let changes = swap(state, Some(rules), Some(profiles));
+    drop(history);
+    announce(state, "rollback", revision, &changes);
+    Some((revision, changes))
+}
+
+fn announce(state: &AppState, operation: &'static str, revision: u64, changes: &[ConfigChange]) {
+    info!("Config: Revisión {} aplicada ({}); {} claves cambiadas.", revision, operation, changes.len());
+    state.events.publish(BalancerEvent::ConfigReloaded { operation, revision, changes: changes.to_vec() });
+}
+
+/// Vuelve a leer `--rules-file` y `--profiles-file` y los aplica si los dos son válidos.
+#[post("/admin/reload")]
+async fn reload_handler(state: web::Data<AppState>) -> impl Responder {
+    let rules_path = state.routing_rules.read().unwrap().path().map(|path| path.to_path_buf());
+    let rules = match rules_path {
+        Some(path) => match RuleSet::load(&path, |pool| state.pool(state.canonical_service(pool)).is_some()) {
+            Ok(rules) => Some(rules),
+            Err(e) => return HttpResponse::BadRequest().json(json!({ "error": e.to_string() })),
+        },
+        None => None,
+    };
+    let profiles = match state.profiles.read_source() {
+        Some(Ok(profiles)) => Some(profiles),
+        Some(Err(e)) => return HttpResponse::BadRequest().json(json!({ "error": e.to_string() })),
+        None => None,
+    };
+    if rules.is_none() && profiles.is_none() {
+        return HttpResponse::Conflict().json(json!({ "error": "Nada que recargar: el balanceador arrancó sin --rules-file ni --profiles-file." }));
+    }
+    let (revision, changes) = apply(&state, rules, profiles, "reload");
+    HttpResponse::Ok().json(json!({ "revision": revision, "changes": changes }))
+}
+
+/// Vuelve a la configuración aplicada antes de la actual.
+#[post("/admin/config/rollback")]
+async fn rollback_handler(state: web::Data<AppState>) -> impl Responder {
+    match rollback(&state) {
+        Some((revision, changes)) => HttpResponse::Ok().json(json!({ "revision": revision, "changes": changes })),
+        None => HttpResponse::Conflict().json(json!({ "error": "No queda ninguna configuración anterior a la que volver." })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use std::path::Path;
+    use std::time::{Duration, Instant};
+
+    use crate::balancer::{self, NodeHealth, LMSTUDIO};
+    use crate::events;
+    use crate::history::TransitionCause;
+    use crate::testing;
+
+    fn change(change: &ConfigChange) -> (String, &'static str, Option<Value>, Option<Value>) {
+        (change.key.clone(), change.change, change.old.clone(), change.new.clone())
+    }
+
+    #[test]
+    fn diff_lists_added_removed_and_changed_leaves_with_secrets_masked() {
+        let old = json!({ "a": { "kept": 1, "changed": "x", "gone": [1, 2] }, "webhook": { "api_token": "t1" } });
+        let new = json!({ "a": { "kept": 1, "changed": "y", "new": { "deep": true } }, "webhook": { "api_token": "t2", "password": "p" } });
+        let changes: Vec<_> = diff(&old, &new).iter().map(change).collect();
+        assert_eq!(
+            changes,
+            [
+                ("a.changed".to_string(), "changed", Some(json!("x")), Some(json!("y"))),
+                ("a.gone".to_string(), "removed", Some(json!([1, 2])), None),
+                ("a.new.deep".to_string(), "added", None, Some(json!(true))),
+                ("webhook.api_token".to_string(), "changed", Some(json!(MASK)), Some(json!(MASK))),
+                ("webhook.password".to_string(), "added", None, Some(json!(MASK))),
+            ]
+        );
+        assert!(diff(&old, &old).is_empty());
+        assert_eq!(
+            mask_secrets(json!({ "hooks": [{ "url": "u", "Secret": "s" }], "token_ttl": 3 })),
+            json!({ "hooks": [{ "url": "u", "Secret": MASK }], "token_ttl": MASK })
+        );
+    }
+
+    /// Perfil `test`, activo siempre que se fije, con este tiempo de espera en cola.
+    fn write_profiles(path: &Path, queue_timeout_secs: u64) {
+        std::fs::write(path, format!("[profiles.test]\nactive = \"00:00-00:01\"\nqueue_timeout_secs = {}\n", queue_timeout_secs)).unwrap();
+    }
+
+    fn write_rules(path: &Path, message: &str) {
+        std::fs::write(path, format!("[[rules]]\nname = \"sin-sql\"\nmatch = {{ body = [{{ path = \"$.messages[0].content\", contains = \"SQL\" }}] }}\naction = {{ reject = \"{}\" }}\n", message)).unwrap();
+    }
+
+    fn admin(req: TestRequest) -> TestRequest {
+        req.insert_header(("Authorization", "Bearer secreto"))
+    }
+
+    #[actix_web::test]
+    async fn reload_reports_its_diff_and_rollback_restores_the_previous_behavior() {
+        let dir = testing::temp_dir();
+        let (profiles, rules) = (dir.join("profiles.toml"), dir.join("rules.toml"));
+        write_profiles(&profiles, 1);
+        write_rules(&rules, "Nada de SQL.");
+        let args = ["--profiles-file", profiles.to_str().unwrap(), "--rules-file", rules.to_str().unwrap(), "--admin-token", "secreto"];
+        let state = testing::state(&args);
+        state.profiles.pin("test").unwrap();
+        // Un nodo ocupado: cada petición espera en la cola hasta el tiempo del perfil.
+        testing::announce(&state, "lmstudio", "box1", "http://127.0.0.1:1/");
+        state.update_node_state(&LMSTUDIO, &testing::node_id("box1"), NodeHealth::Busy, TransitionCause::Admin);
+        let (mut events, _subscriber) = events::admit(state.clone()).unwrap();
+        let app = init_service(balancer::app(state.clone())).await;
+        let waited = || {
+            let app = &app;
+            async move {
+                let start = Instant::now();
+                assert_eq!(call_service(app, testing::chat().to_request()).await.status(), 503);
+                start.elapsed()
+            }
+        };
+        let rejection = || {
+            let app = &app;
+            async move {
+                let body: Value = read_body_json(call_service(app, testing::chat_with(json!({ "messages": [{ "role": "user", "content": "SQL" }] })).to_request()).await).await;
+                body["error"]["message"].as_str().unwrap_or_default().to_string()
+            }
+        };
+        assert!(waited().await < Duration::from_millis(1_800));
+        assert!(rejection().await.contains("Nada de SQL."));
+
+        write_profiles(&profiles, 2);
+        write_rules(&rules, "SQL no.");
+        let res = call_service(&app, admin(TestRequest::post().uri("/admin/reload")).to_request()).await;
+        assert_eq!(res.status(), 200);
+        let body: Value = read_body_json(res).await;
+        assert_eq!(body["revision"], 2);
+        let changes = body["changes"].as_array().unwrap();
+        assert!(changes.contains(&json!({ "key": "profiles.test.settings.queue_timeout_secs", "change": "changed", "old": 1, "new": 2 })), "{}", body);
+        assert!(changes.contains(&json!({ "key": "rules.sin-sql.action.reject", "change": "changed", "old": "Nada de SQL.", "new": "SQL no." })), "{}", body);
+        assert_eq!(changes.len(), 2, "{}", body);
+        assert!(waited().await >= Duration::from_millis(1_800));
+        assert!(rejection().await.contains("SQL no."));
+
+        let res = call_service(&app, admin(TestRequest::post().uri("/admin/config/rollback")).to_request()).await;
+        assert_eq!(res.status(), 200);
+        let rolled_back: Value = read_body_json(res).await;
+        assert_eq!(rolled_back["revision"], 1);
+        assert!(rolled_back["changes"].as_array().unwrap().contains(&json!({ "key": "profiles.test.settings.queue_timeout_secs", "change": "changed", "old": 2, "new": 1 })));
+        assert!(waited().await < Duration::from_millis(1_800));
+        assert!(rejection().await.contains("Nada de SQL."));
+
+        // Se publica cada operación con su diff.
+        let published: Vec<_> = std::iter::from_fn(|| events.try_recv().ok())
+            .filter_map(|event| match &*event {
+                BalancerEvent::ConfigReloaded { operation, revision, changes } => Some((*operation, *revision, changes.len())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(published, [("reload", 2, 2), ("rollback", 1, 2)]);
+
+        // No queda nada antes del arranque.
+        let res = call_service(&app, admin(TestRequest::post().uri("/admin/config/rollback")).to_request()).await;
+        assert_eq!(res.status(), 409);
+    }
+
+    #[actix_web::test]
+    async fn an_invalid_file_applies_nothing() {
+        let dir = testing::temp_dir();
+        let (profiles, rules) = (dir.join("profiles.toml"), dir.join("rules.toml"));
+        write_profiles(&profiles, 1);
+        write_rules(&rules, "Nada de SQL.");
+        let state = testing::state(&["--profiles-file", profiles.to_str().unwrap(), "--rules-file", rules.to_str().unwrap(), "--admin-token", "secreto"]);
+        let app = init_service(balancer::app(state.clone())).await;
+
+        write_rules(&rules, "SQL no.");
+        std::fs::write(&profiles, "[profiles.test]\nqueue_timeout_secs = \"mucho\"\n").unwrap();
+        let res = call_service(&app, admin(TestRequest::post().uri("/admin/reload")).to_request()).await;
+        assert_eq!(res.status(), 400);
+        let rules = serde_json::to_value(state.routing_rules.read().unwrap().rules()).unwrap();
+        assert_eq!(rules[0]["action"]["reject"], "Nada de SQL.");
+        assert_eq!(call_service(&app, admin(TestRequest::post().uri("/admin/config/rollback")).to_request()).await.status(), 409);
+
+        // Sin archivos no hay nada que recargar; y sin token no se puede.
+        let bare = init_service(balancer::app(testing::state(&["--admin-token", "secreto"]))).await;
+        assert_eq!(call_service(&bare, admin(TestRequest::post().uri("/admin/reload")).to_request()).await.status(), 409);
+        assert_eq!(call_service(&bare, TestRequest::post().uri("/admin/reload").to_request()).await.status(), 401);
+    }
+
+    #[test]
+    fn history_keeps_the_last_configurations() {
+        let state = testing::state(&["--config-history", "2"]);
+        for _ in 0..3 {
+            apply(&state, None, None, "reload");
+        }
+        let revisions: Vec<_> = state.config_history.describe()["applied"].as_array().unwrap().iter().map(|applied| applied["revision"].clone()).collect();
+        assert_eq!(revisions, [json!(3), json!(4)]);
+        assert!(rollback(&state).is_some_and(|(revision, changes)| revision == 3 && changes.is_empty()));
+        assert!(rollback(&state).is_none());
+    }
+}
@@ -0,0 +1,234 @@
+// src/reprobe.rs
+//! Nueva prueba de capacidades cuando cambia la versión del backend de un nodo.
+//!
+//! Los backends se actualizan en el sitio sin que cambie el ID del nodo, y sus capacidades
+//! cambian con ellos. El nodo informa la versión de su backend en cada anuncio (`BACKEND`). Si
+//! cambia en un nodo conocido, el balanceador marca sus capacidades como dudosas y las vuelve a
+//! probar él mismo: la misma prueba que hace el nodo al arrancar (`tools::probe`), con el
+//! cliente de timeouts cortos de los diagnósticos. `POST /nodes/{id}/reprobe` la lanza a mano.
+//!
+//! La prueba ocupa el nodo mientras dura: nunca se hace sobre un nodo ocupado y ninguna
+//! petición llega a mitad de prueba. La automática espera hasta `WAIT_FOR_IDLE` a que el nodo
+//! quede libre; la manual responde 409. Las capacidades siguen marcadas como dudosas
+//! (`capabilities_stale`) hasta que una prueba tenga éxito con la versión nueva. El resultado de
+//! la prueba manda sobre los `CAPS` del nodo mientras no vuelva a cambiar la versión.
+use actix_web::{post, web, HttpResponse, Responder};
+use chrono::{DateTime, Local};
+use log::{info, warn};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::{BTreeSet, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+use crate::balancer::{AppState, NodeHealth};
+use crate::diagnose;
+use crate::events::BalancerEvent;
+use crate::history::TransitionCause;
+use crate::ids::{NodeId, PoolName, ServiceUrl};
+use crate::tools;
+
+/// Tiempo máximo que la prueba automática espera a que un nodo ocupado quede libre.
+const WAIT_FOR_IDLE: Duration = Duration::from_secs(300);
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Estado de la prueba de capacidades de un nodo hecha por el balanceador.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct CapabilityProbe {
+    /// Versión del backend con la que tuvo éxito la última prueba.
+    pub probed_version: Option<String>,
+    pub probed_at: Option<DateTime<Local>>,
+    /// La versión del backend cambió y aún no hay una prueba con éxito.
+    pub stale: bool,
+    pub last_error: Option<String>,
+}
+
+impl CapabilityProbe {
+    /// Si el resultado de la prueba vale para `backend_version` y manda sobre los `CAPS`.
+    pub fn covers(&self, backend_version: Option<&str>) -> bool {
+        self.probed_version.is_some() && self.probed_version.as_deref() == backend_version
+    }
+}
+
+#[derive(Debug)]
+pub enum ReprobeError {
+    UnknownNode,
+    /// El nodo no está libre; lleva su estado.
+    NotAvailable(&'static str),
+    AlreadyRunning,
+    NoModels,
+}
+
+impl ReprobeError {
+    fn message(&self, unique_node_id: &str) -> String {
+        match self {
+            ReprobeError::UnknownNode => format!("Nodo {} desconocido", unique_node_id),
+            ReprobeError::NotAvailable(state) => format!("El nodo {} no está libre ({}); no se prueba.", unique_node_id, state),
+            ReprobeError::AlreadyRunning => format!("Ya hay una prueba en curso para el nodo {}.", unique_node_id),
+            ReprobeError::NoModels => format!("El nodo {} no anuncia modelos con los que probar.", unique_node_id),
+        }
+    }
+}
+
+/// Nodos con una prueba en curso.
+#[derive(Default)]
+pub struct Reprober {
+    running: Mutex<HashSet<String>>,
+}
+
+/// Mientras vive, el nodo cuenta como en prueba.
+struct Running<'a> {
+    reprober: &'a Reprober,
+    unique_node_id: String,
+}
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.reprober.running.lock().unwrap().remove(&self.unique_node_id);
+    }
+}
+
+impl Reprober {
+    /// Si hay una prueba en marcha en el nodo, que lo tiene ocupado.
+    pub fn is_running(&self, unique_node_id: &str) -> bool {
+        self.running.lock().unwrap().contains(unique_node_id)
+    }
+
+    fn start(&self, unique_node_id: &str) -> Option<Running<'_>> {
+        self.running
+            .lock()
+            .unwrap()
+            .insert(unique_node_id.to_string())
+            .then(|| Running { reprober: self, unique_node_id: unique_node_id.to_string() })
+    }
+}
+
+/// Nodo ocupado para la prueba.
+struct Claimed {
+    service: &'static PoolName,
+    service_url: ServiceUrl,
+    /// Primer modelo que anuncia: el de la prueba.
+    model: Option<String>,
+    backend_version: Option<String>,
+}
+
+/// Ocupa el nodo si está libre.
+fn claim(state: &AppState, unique_node_id: &NodeId) -> Result<Claimed, ReprobeError> {
+    for (_, service, lock) in state.pools() {
+        let mut nodes = lock.write().unwrap();
+        let Some(info) = nodes.get_mut(unique_node_id) else {
+            continue;
+        };
+        if !matches!(info.state, NodeHealth::Available) {
+            return Err(ReprobeError::NotAvailable(info.state.label()));
+        }
+        info.state = NodeHealth::Busy;
+        info.busy_since = Some(Instant::now());
+        let claimed = Claimed {
+            service,
+            service_url: info.service_url.clone(),
+            model: info.models.first().cloned(),
+            backend_version: info.backend_version.clone(),
+        };
+        state.capacity.record(service, &nodes);
+        state.revisions.bump(service, unique_node_id);
+        return Ok(claimed);
+    }
+    Err(ReprobeError::UnknownNode)
+}
+
+/// Prueba las capacidades del nodo y las actualiza. Devuelve el informe.
+pub async fn reprobe(state: &AppState, unique_node_id: &NodeId) -> Result<serde_json::Value, ReprobeError> {
+    let Some(_running) = state.reprober.start(unique_node_id) else {
+        return Err(ReprobeError::AlreadyRunning);
+    };
+    let Claimed { service, service_url, model, backend_version } = claim(state, unique_node_id)?;
+    let Some(model) = model else {
+        state.update_node_state(service, unique_node_id, NodeHealth::Available, TransitionCause::RequestCompleted);
+        return Err(ReprobeError::NoModels);
+    };
+
+    info!("Capacidades: Probando el nodo {} ({}) con {}.", unique_node_id, service, model);
+    let started = Instant::now();
+    let probed = tools::probe(state.diagnostics.client(), service_url.as_str(), &model, diagnose::STEP_TIMEOUT).await;
+    let elapsed_ms = started.elapsed().as_millis() as u64;
+    state.update_node_state(service, unique_node_id, NodeHealth::Available, TransitionCause::RequestCompleted);
+
+    let Some(lock) = state.pool(service) else {
+        return Err(ReprobeError::UnknownNode);
+    };
+    let mut nodes = lock.write().unwrap();
+    let Some(info) = nodes.get_mut(unique_node_id) else {
+        return Err(ReprobeError::UnknownNode);
+    };
+    info.capability_probe.probed_at = Some(Local::now());
+    let before = info.capabilities.clone();
+    match probed {
+        Some(capable) => {
+            let after: BTreeSet<String> = capable.then(|| tools::TOOL_CALLING.to_string()).into_iter().collect();
+            info.capabilities = after;
+            info.capability_probe.probed_version = backend_version.clone();
+            info.capability_probe.stale = false;
+            info.capability_probe.last_error = None;
+        }
+        None => {
+            warn!("Capacidades: La prueba del nodo {} no obtuvo respuesta válida del backend.", unique_node_id);
+            info.capability_probe.last_error = Some("Sin respuesta válida del backend".to_string());
+        }
+    }
+    let after = info.capabilities.clone();
+    let report = json!({
+        "node_id": unique_node_id,
+        "service": service,
+        "backend_version": backend_version,
+        "model": model,
+        "elapsed_ms": elapsed_ms,
+        "capabilities": after,
+        "changed": before != after,
+        "stale": info.capability_probe.stale,
+        "error": info.capability_probe.last_error,
+    });
+    state.revisions.bump(service, unique_node_id);
+    drop(nodes);
+
+    if before != after {
+        info!("Capacidades: El nodo {} pasa de {:?} a {:?}.", unique_node_id, before, after);
+        state.events.publish(BalancerEvent::NodeCapabilitiesChanged {
+            service: service.to_string(),
+            node_id: unique_node_id.to_string(),
+            backend_version,
+            before: before.into_iter().collect(),
+            after: after.into_iter().collect(),
+        });
+    }
+    Ok(report)
+}
+
+/// Lanza la prueba automática tras un cambio de versión, esperando a que el nodo quede libre.
+pub fn spawn(state: web::Data<AppState>, unique_node_id: NodeId) {
+    tokio::spawn(async move {
+        let deadline = Instant::now() + WAIT_FOR_IDLE;
+        loop {
+            match reprobe(&state, &unique_node_id).await {
+                Ok(_) => return,
+                Err(ReprobeError::NotAvailable(_)) if Instant::now() < deadline => sleep(IDLE_POLL_INTERVAL).await,
+                Err(e) => {
+                    warn!("Capacidades: No se volvió a probar el nodo {}: {}", unique_node_id, e.message(&unique_node_id));
+                    return;
+                }
+            }
+        }
+    });
+}
+
+/// Vuelve a probar las capacidades del nodo. 409 si está ocupado o ya se está probando.
+#[post("/nodes/{id}/reprobe")]
+async fn reprobe_handler(state: web::Data<AppState>, path: web::Path<NodeId>) -> impl Responder {
+    let unique_node_id = path.into_inner();
+    match reprobe(&state, &unique_node_id).await {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(e @ ReprobeError::UnknownNode) => HttpResponse::NotFound().json(json!({ "error": e.message(&unique_node_id) })),
+        Err(e) => HttpResponse::Conflict().json(json!({ "error": e.message(&unique_node_id) })),
+    }
+}
@@ -0,0 +1,343 @@
+// src/retry.rs
+//! Política de reintentos común a las llamadas salientes (`--retry-policy`).
+//!
+//! Cada punto que repite una llamada (`RetrySite`) tiene su `RetryPolicy`: intentos, espera
+//! base, multiplicador, tope de espera y jitter. `execute` envuelve la llamada, decide con el
+//! clasificador del que llama qué errores se repiten, no espera más allá del plazo que se le
+//! pasa y suma intentos y espera a las métricas con la etiqueta del punto.
+//!
+//! La espera antes del reintento `n` (1 para el primero) es `base * multiplicador^(n-1)`,
+//! limitada a `max_ms`, y se mueve al azar ±`jitter` (fracción) sin pasar del tope. Sin
+//! configurar, cada punto hace un solo intento.
+//!
+//! - `forward`: reenvío de la petición al nodo. Sólo se repite si no se pudo conectar, es
+//!   decir, si el nodo no llegó a recibirla; nunca una petición que pudo empezar a generar.
+//! - `health`: prueba de salud de los nodos estáticos, antes de darlos por caídos. No se
+//!   espera más allá de la siguiente ronda.
+use serde_json::json;
+use std::fmt;
+use std::future::Future;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+use crate::metrics::Metrics;
+use crate::selection::Xorshift;
+
+pub const DEFAULT_RETRY_BASE_MS: u64 = 200;
+pub const DEFAULT_RETRY_MULTIPLIER: f64 = 2.0;
+pub const DEFAULT_RETRY_MAX_MS: u64 = 5_000;
+pub const DEFAULT_RETRY_JITTER: f64 = 0.2;
+
+static JITTER: LazyLock<Xorshift> = LazyLock::new(Xorshift::default);
+
+#[derive(Debug)]
+pub struct RetryConfigError(String);
+
+impl fmt::Display for RetryConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for RetryConfigError {}
+
+/// Punto que repite llamadas salientes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RetrySite {
+    Forward,
+    Health,
+}
+
+impl RetrySite {
+    pub const ALL: [RetrySite; 2] = [RetrySite::Forward, RetrySite::Health];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            RetrySite::Forward => "forward",
+            RetrySite::Health => "health",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Intentos en total, contando el primero; 1 no repite.
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub multiplier: f64,
+    pub max_delay: Duration,
+    /// Fracción (0-1) en que se mueve al azar cada espera.
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            base_delay: Duration::from_millis(DEFAULT_RETRY_BASE_MS),
+            multiplier: DEFAULT_RETRY_MULTIPLIER,
+            max_delay: Duration::from_millis(DEFAULT_RETRY_MAX_MS),
+            jitter: DEFAULT_RETRY_JITTER,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Interpreta `attempts=3,base_ms=200,multiplier=2,max_ms=5000,jitter=0.2`. Lo que no se
+    /// indica toma el valor por defecto.
+    fn parse(spec: &str) -> Result<Self, String> {
+        let mut policy = Self::default();
+        for pair in spec.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+            let Some((key, value)) = pair.split_once('=').map(|(key, value)| (key.trim(), value.trim())) else {
+                return Err(format!("se esperaba clave=valor en '{}'", pair));
+            };
+            let invalid = || format!("valor inválido para {}: '{}'", key, value);
+            match key {
+                "attempts" => policy.max_attempts = value.parse().ok().filter(|attempts| *attempts > 0).ok_or_else(invalid)?,
+                "base_ms" => policy.base_delay = Duration::from_millis(value.parse().map_err(|_| invalid())?),
+                "multiplier" => policy.multiplier = value.parse().ok().filter(|multiplier: &f64| *multiplier >= 1.0).ok_or_else(invalid)?,
+                "max_ms" => policy.max_delay = Duration::from_millis(value.parse().map_err(|_| invalid())?),
+                "jitter" => policy.jitter = value.parse().ok().filter(|jitter| (0.0..=1.0).contains(jitter)).ok_or_else(invalid)?,
+                _ => return Err(format!("clave desconocida '{}' (attempts, base_ms, multiplier, max_ms, jitter)", key)),
+            }
+        }
+        Ok(policy)
+    }
+
+    /// Espera antes del reintento `retry` (1 para el primero), con `unit` uniforme en [0, 1)
+    /// para el jitter. Nunca pasa de `max_delay`.
+    pub fn delay(&self, retry: u32, unit: f64) -> Duration {
+        let exponent = retry.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let nominal = (self.base_delay.as_secs_f64() * self.multiplier.powi(exponent)).min(self.max_delay.as_secs_f64());
+        let factor = 1.0 - self.jitter + 2.0 * self.jitter * unit.clamp(0.0, 1.0);
+        Duration::from_secs_f64((nominal * factor).clamp(0.0, self.max_delay.as_secs_f64()))
+    }
+
+    fn describe(&self) -> serde_json::Value {
+        json!({
+            "attempts": self.max_attempts,
+            "base_ms": self.base_delay.as_millis() as u64,
+            "multiplier": self.multiplier,
+            "max_ms": self.max_delay.as_millis() as u64,
+            "jitter": self.jitter,
+        })
+    }
+}
+
+/// Política de cada punto de reintento.
+#[derive(Default)]
+pub struct RetryPolicies {
+    forward: RetryPolicy,
+    health: RetryPolicy,
+}
+
+impl RetryPolicies {
+    /// Interpreta entradas `punto:clave=valor,...` de la línea de comandos.
+    pub fn new(entries: &[String]) -> Result<Self, RetryConfigError> {
+        let mut policies = Self::default();
+        for entry in entries {
+            let Some((site, spec)) = entry.split_once(':') else {
+                return Err(RetryConfigError(format!("Entrada de --retry-policy inválida '{}': se esperaba <punto>:<clave>=<valor>,...", entry)));
+            };
+            let Some(site) = RetrySite::ALL.into_iter().find(|known| known.label() == site.trim()) else {
+                return Err(RetryConfigError(format!("Punto de reintento desconocido '{}' en --retry-policy (forward, health)", site.trim())));
+            };
+            let policy = RetryPolicy::parse(spec).map_err(|e| RetryConfigError(format!("--retry-policy '{}': {}", entry, e)))?;
+            match site {
+                RetrySite::Forward => policies.forward = policy,
+                RetrySite::Health => policies.health = policy,
+            }
+        }
+        Ok(policies)
+    }
+
+    pub fn get(&self, site: RetrySite) -> &RetryPolicy {
+        match site {
+            RetrySite::Forward => &self.forward,
+            RetrySite::Health => &self.health,
+        }
+    }
+
+    /// Configuración para `/config`.
+    pub fn describe(&self) -> serde_json::Value {
+        RetrySite::ALL.into_iter().map(|site| (site.label().to_string(), self.get(site).describe())).collect()
+    }
+}
+
+/// Ejecuta `op` con la política del punto `site`: repite los errores que `retryable` acepta
+/// mientras queden intentos y la espera no pase de `deadline`. Devuelve el último resultado.
+pub async fn execute<T, E, F, Fut>(
+    policies: &RetryPolicies,
+    site: RetrySite,
+    metrics: &Metrics,
+    deadline: Instant,
+    retryable: impl Fn(&E) -> bool,
+    mut op: F,
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let policy = policies.get(site);
+    let mut attempt = 1;
+    let mut waited = Duration::ZERO;
+    let result = loop {
+        let result = op().await;
+        match &result {
+            Err(e) if attempt < policy.max_attempts && retryable(e) => {
+                let delay = policy.delay(attempt, JITTER.next_f64());
+                if Instant::now() + delay >= deadline {
+                    break result;
+                }
+                sleep(delay).await;
+                waited += delay;
+                attempt += 1;
+            }
+            _ => break result,
+        }
+    };
+    if attempt > 1 {
+        metrics.record_retries(site.label(), u64::from(attempt - 1), waited);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    /// Margen para los redondeos de `Duration::from_secs_f64`.
+    const EPSILON: f64 = 1e-6;
+
+    /// Políticas de prueba: todas las combinaciones de unos valores que cubren los extremos.
+    fn policies() -> Vec<RetryPolicy> {
+        let mut policies = Vec::new();
+        for base_ms in [0, 1, 50, 200, 1_000] {
+            for multiplier in [1.0, 1.5, 2.0, 3.7] {
+                for max_ms in [0, 100, 5_000] {
+                    for jitter in [0.0, 0.2, 0.5, 1.0] {
+                        policies.push(RetryPolicy {
+                            max_attempts: 5,
+                            base_delay: Duration::from_millis(base_ms),
+                            multiplier,
+                            max_delay: Duration::from_millis(max_ms),
+                            jitter,
+                        });
+                    }
+                }
+            }
+        }
+        policies
+    }
+
+    /// Espera sin jitter antes del reintento `retry`, en segundos.
+    fn nominal(policy: &RetryPolicy, retry: u32) -> f64 {
+        (policy.base_delay.as_secs_f64() * policy.multiplier.powi(retry as i32 - 1)).min(policy.max_delay.as_secs_f64())
+    }
+
+    fn units() -> impl Iterator<Item = f64> {
+        (0..=20).map(|i| f64::from(i) / 20.0)
+    }
+
+    #[test]
+    fn delays_are_capped_and_jitter_stays_in_its_range() {
+        for policy in policies() {
+            let cap = policy.max_delay.as_secs_f64();
+            for retry in 1..=40 {
+                let nominal = nominal(&policy, retry);
+                let (low, high) = (nominal * (1.0 - policy.jitter), (nominal * (1.0 + policy.jitter)).min(cap));
+                for unit in units() {
+                    let delay = policy.delay(retry, unit).as_secs_f64();
+                    assert!(delay <= cap + EPSILON, "{:?} reintento {}: {} pasa del tope", policy, retry, delay);
+                    assert!(delay >= low - EPSILON && delay <= high + EPSILON, "{:?} reintento {} unit {}: {} fuera de [{}, {}]", policy, retry, unit, delay, low, high);
+                }
+                // Los extremos de `unit` dan los del rango.
+                assert!((policy.delay(retry, 0.0).as_secs_f64() - low).abs() < EPSILON);
+                assert!((policy.delay(retry, 1.0).as_secs_f64() - high).abs() < EPSILON);
+            }
+        }
+    }
+
+    #[test]
+    fn delays_grow_with_each_retry() {
+        for policy in policies() {
+            for unit in units() {
+                let delays: Vec<Duration> = (1..=40).map(|retry| policy.delay(retry, unit)).collect();
+                assert!(delays.windows(2).all(|pair| pair[0] <= pair[1]), "{:?} unit {}: {:?}", policy, unit, delays);
+            }
+        }
+    }
+
+    #[test]
+    fn without_jitter_delays_are_exact() {
+        let policy = RetryPolicy { max_attempts: 5, base_delay: Duration::from_millis(100), multiplier: 2.0, max_delay: Duration::from_millis(1_000), jitter: 0.0 };
+        let delays: Vec<u128> = (1..=6).map(|retry| policy.delay(retry, 0.7).as_millis()).collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1_000, 1_000]);
+        // Un `unit` fuera de [0, 1] no saca la espera de su rango, ni un reintento enorme la desborda.
+        let jittered = RetryPolicy { jitter: 0.5, ..policy };
+        assert_eq!(jittered.delay(1, -3.0), Duration::from_millis(50));
+        assert_eq!(jittered.delay(1, 3.0), Duration::from_millis(150));
+        assert_eq!(jittered.delay(u32::MAX, 1.0), Duration::from_millis(1_000));
+    }
+
+    #[test]
+    fn policies_are_parsed_per_site() {
+        let policies = RetryPolicies::new(&["forward:attempts=3,base_ms=100".to_string(), "health: jitter=0.5, max_ms=700".to_string()]).unwrap();
+        let forward = policies.get(RetrySite::Forward);
+        assert_eq!((forward.max_attempts, forward.base_delay, forward.multiplier), (3, Duration::from_millis(100), DEFAULT_RETRY_MULTIPLIER));
+        let health = policies.get(RetrySite::Health);
+        assert_eq!((health.max_attempts, health.max_delay, health.jitter), (1, Duration::from_millis(700), 0.5));
+        assert_eq!(RetryPolicies::default().get(RetrySite::Forward), &RetryPolicy::default());
+        for entry in ["forward", "webhook:attempts=2", "forward:attempts=0", "forward:multiplier=0.5", "forward:jitter=2", "forward:base_ms=-1", "forward:tries=2", "forward:attempts"] {
+            assert!(RetryPolicies::new(&[entry.to_string()]).is_err(), "{}", entry);
+        }
+    }
+
+    /// Valor de una métrica con etiqueta en la salida de Prometheus.
+    fn metric(metrics: &Metrics, line: &str) -> Option<u64> {
+        metrics.render().lines().find_map(|metric| metric.strip_prefix(line)?.trim().parse().ok())
+    }
+
+    fn fast(attempts: u32) -> RetryPolicies {
+        RetryPolicies::new(&[format!("forward:attempts={},base_ms=20,jitter=0", attempts)]).unwrap()
+    }
+
+    /// Ejecuta con `fast(attempts)` una operación que falla `failures` veces con `error`.
+    async fn run(attempts: u32, failures: u32, error: &'static str, deadline: Duration, metrics: &Metrics) -> (Result<u32, &'static str>, u32) {
+        let calls = Cell::new(0);
+        let result = execute(&fast(attempts), RetrySite::Forward, metrics, Instant::now() + deadline, |e: &&str| *e == "connect", || {
+            calls.set(calls.get() + 1);
+            let call = calls.get();
+            async move { if call <= failures { Err(error) } else { Ok(call) } }
+        })
+        .await;
+        (result, calls.get())
+    }
+
+    #[tokio::test]
+    async fn execute_repeats_only_retryable_errors_while_attempts_last() {
+        let metrics = Metrics::default();
+        let second = Duration::from_secs(1);
+        assert_eq!(run(3, 2, "connect", second, &metrics).await, (Ok(3), 3));
+        assert_eq!(metric(&metrics, "lmserver_retries_total{site=\"forward\"}"), Some(2));
+        assert_eq!(metric(&metrics, "lmserver_retry_delay_milliseconds_total{site=\"forward\"}"), Some(60));
+
+        assert_eq!(run(3, 5, "connect", second, &metrics).await, (Err("connect"), 3));
+        assert_eq!(run(3, 5, "status", second, &metrics).await, (Err("status"), 1));
+        assert_eq!(run(1, 5, "connect", second, &metrics).await, (Err("connect"), 1));
+        assert_eq!(metric(&metrics, "lmserver_retries_total{site=\"forward\"}"), Some(4));
+    }
+
+    #[tokio::test]
+    async fn execute_does_not_wait_past_the_deadline() {
+        let metrics = Metrics::default();
+        let start = Instant::now();
+        // La espera del segundo reintento (40 ms) ya no cabe en 50 ms.
+        assert_eq!(run(5, 5, "connect", Duration::from_millis(50), &metrics).await, (Err("connect"), 2));
+        assert!(start.elapsed() < Duration::from_millis(50));
+        assert_eq!(run(5, 5, "connect", Duration::ZERO, &metrics).await, (Err("connect"), 1));
+        assert_eq!(metric(&metrics, "lmserver_retries_total{site=\"forward\"}"), Some(1));
+    }
+}
@@ -0,0 +1,313 @@
+// src/revisions.rs
+//! Revisiones del registro de nodos para sincronizar planificadores externos.
+//!
+//! Cada cambio de un nodo (latido, cambio de estado, alta, baja, metadatos) incrementa una
+//! revisión global y apunta qué nodo cambió. `GET /nodes/watch?since=<rev>` devuelve sólo
+//! los nodos cambiados desde esa revisión, esperando (long-poll) si aún no hay ninguno. Se
+//! guardan los últimos cambios hasta el tope `revision_changes`; una revisión más antigua, o
+//! de otra ejecución del balanceador, recibe el registro completo.
+use actix_web::{get, web, HttpResponse, Responder};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{BTreeSet, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::timeout_at;
+
+use crate::balancer::{node_summary, AppState};
+use crate::limits::StoreUsage;
+
+const DEFAULT_WATCH_TIMEOUT_SECS: u64 = 30;
+const MAX_WATCH_TIMEOUT_SECS: u64 = 300;
+
+/// Nodos cambiados desde una revisión, o `None` si esa revisión ya no se puede servir.
+pub type ChangedSince = Option<BTreeSet<(String, String)>>;
+
+#[derive(Default)]
+struct Log {
+    revision: u64,
+    /// (revisión, pool, nodo), en orden creciente de revisión.
+    changes: VecDeque<(u64, String, String)>,
+}
+
+pub struct RegistryRevisions {
+    log: Mutex<Log>,
+    notify: watch::Sender<u64>,
+    /// Cambios que se conservan antes de compactar.
+    max_changes: usize,
+}
+
+impl RegistryRevisions {
+    pub fn new(max_changes: usize) -> Self {
+        Self { log: Mutex::default(), notify: watch::channel(0).0, max_changes }
+    }
+
+    pub fn usage(&self) -> StoreUsage {
+        let log = self.log.lock().unwrap();
+        let bytes = log.changes.iter().map(|(_, service, id)| std::mem::size_of::<(u64, String, String)>() + service.len() + id.len()).sum();
+        StoreUsage { entries: log.changes.len(), bytes }
+    }
+
+    /// Anota un cambio del nodo. Se llama con el lock de escritura de la pool aún tomado, para
+    /// que el orden de las revisiones sea el de los cambios.
+    pub fn bump(&self, service: &str, unique_node_id: &str) {
+        let mut log = self.log.lock().unwrap();
+        log.revision += 1;
+        let revision = log.revision;
+        log.changes.push_back((revision, service.to_string(), unique_node_id.to_string()));
+        if log.changes.len() > self.max_changes {
+            log.changes.pop_front();
+        }
+        drop(log);
+        self.notify.send_replace(revision);
+    }
+
+    pub fn current(&self) -> u64 {
+        self.log.lock().unwrap().revision
+    }
+
+    /// Revisión actual y nodos cambiados después de `since`. `since = 0` pide el registro completo.
+    pub fn changed_since(&self, since: u64) -> (u64, ChangedSince) {
+        let log = self.log.lock().unwrap();
+        // Los cambios guardados cubren (floor, revision]; antes de floor se compactaron.
+        let floor = log.changes.front().map_or(log.revision, |(first, _, _)| first - 1);
+        if since == 0 || since < floor || since > log.revision {
+            return (log.revision, None);
+        }
+        let changed = log
+            .changes
+            .iter()
+            .filter(|(revision, _, _)| *revision > since)
+            .map(|(_, service, id)| (service.clone(), id.clone()))
+            .collect();
+        (log.revision, Some(changed))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct WatchQuery {
+    #[serde(default)]
+    since: u64,
+    timeout_secs: Option<u64>,
+}
+
+/// Cambios del registro desde `since`. Sin cambios, espera hasta `timeout_secs` y devuelve una
+/// lista vacía con la misma revisión. `full: true` indica que `nodes` es el registro completo
+/// y el cliente debe sustituir su copia.
+#[get("/nodes/watch")]
+async fn watch_handler(state: web::Data<AppState>, query: web::Query<WatchQuery>) -> impl Responder {
+    let limit = Duration::from_secs(query.timeout_secs.unwrap_or(DEFAULT_WATCH_TIMEOUT_SECS).min(MAX_WATCH_TIMEOUT_SECS));
+    let deadline = tokio::time::Instant::now() + limit;
+    // Se suscribe antes de mirar el registro para no perder un cambio entre medias.
+    let mut notified = state.revisions.notify.subscribe();
+    loop {
+        let (revision, changed) = state.revisions.changed_since(query.since);
+        match changed {
+            None => {
+                let mut nodes = Vec::new();
+                for (_, service, lock) in state.pools() {
+                    let pool = lock.read().unwrap();
+                    let mut ids: Vec<_> = pool.keys().collect();
+                    ids.sort();
+                    nodes.extend(ids.into_iter().map(|id| node_summary(&state, service, id, &pool[id])));
+                }
+                return HttpResponse::Ok().json(json!({ "revision": revision, "full": true, "nodes": nodes }));
+            }
+            Some(changed) if !changed.is_empty() => {
+                let nodes: Vec<_> = changed
+                    .iter()
+                    .map(|(service, id)| {
+                        let pool = state.pool(service).map(|lock| lock.read().unwrap());
+                        match pool.as_ref().and_then(|pool| pool.get(id.as_str())) {
+                            Some(info) => json!(node_summary(&state, service, id, info)),
+                            None => json!({ "node_id": id, "service": service, "removed": true }),
+                        }
+                    })
+                    .collect();
+                return HttpResponse::Ok().json(json!({ "revision": revision, "full": false, "nodes": nodes }));
+            }
+            Some(_) => {
+                if timeout_at(deadline, notified.changed()).await.is_err() {
+                    return HttpResponse::Ok().json(json!({ "revision": revision, "full": false, "nodes": [] }));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use serde_json::Value;
+
+    use crate::balancer::{self, remove_stale_nodes, NodeHealth, LMSTUDIO, OLLAMA};
+    use crate::history::TransitionCause;
+    use crate::testing;
+
+    /// Nodos cambiados desde `since`, separados por comas, o `None` si toca el registro completo.
+    fn changed(revisions: &RegistryRevisions, since: u64) -> Option<String> {
+        revisions.changed_since(since).1.map(|nodes| nodes.into_iter().map(|(_, id)| id).collect::<Vec<_>>().join(","))
+    }
+
+    #[test]
+    fn old_revisions_are_compacted_away() {
+        let revisions = RegistryRevisions::new(3);
+        for id in ["a", "b", "a", "c", "d"] {
+            revisions.bump("lmstudio", id);
+        }
+        assert_eq!(revisions.current(), 5);
+
+        // Quedan las revisiones 3, 4 y 5: se sirve cualquier `since` desde 2.
+        assert_eq!(changed(&revisions, 2), Some("a,c,d".to_string()));
+        assert_eq!(changed(&revisions, 4), Some("d".to_string()));
+        assert_eq!(changed(&revisions, 5), Some("".to_string()));
+        assert_eq!(changed(&revisions, 1), None, "revisión compactada");
+        assert_eq!(changed(&revisions, 0), None, "registro completo");
+        assert_eq!(changed(&revisions, 6), None, "revisión de otra ejecución");
+    }
+
+    #[test]
+    fn a_node_changed_twice_is_listed_once() {
+        let revisions = RegistryRevisions::new(16);
+        revisions.bump("lmstudio", "a");
+        for _ in 0..3 {
+            revisions.bump("lmstudio", "b");
+        }
+        assert_eq!(changed(&revisions, 1), Some("b".to_string()));
+        assert_eq!(changed(&revisions, 0), None);
+        assert_eq!(revisions.usage().entries, 4);
+    }
+
+    /// Cada sitio que cambia el registro sube la revisión, y siempre hacia arriba.
+    #[actix_web::test]
+    async fn every_registry_mutation_bumps_the_revision() {
+        let state = testing::state(&["--admin-token", "secreto"]);
+        let app = init_service(balancer::app(state.clone())).await;
+        let mut last = state.revisions.current();
+        let mut bumped = |what: &str| {
+            let now = state.revisions.current();
+            assert!(now > last, "{} no subió la revisión ({} -> {})", what, last, now);
+            last = now;
+        };
+
+        testing::announce(&state, "lmstudio", "box1", "http://127.0.0.1:9001/");
+        bumped("el alta");
+        testing::announce(&state, "lmstudio", "box1", "http://127.0.0.1:9001/");
+        bumped("el latido");
+        state.apply_health_check(&LMSTUDIO, &testing::node_id("box1"), false);
+        bumped("el health check");
+        state.update_node_state(&LMSTUDIO, &testing::node_id("box1"), NodeHealth::Available, TransitionCause::Admin);
+        bumped("el cambio de estado");
+        state.set_node_models(&LMSTUDIO, &testing::node_id("box1"), vec!["llama3".to_string()]);
+        bumped("los modelos");
+
+        let req = TestRequest::post().uri("/nodes/box1/remove").insert_header(("Authorization", "Bearer secreto")).to_request();
+        assert!(call_service(&app, req).await.status().is_success());
+        bumped("el borrado por el admin");
+
+        testing::announce(&state, "lmstudio", "box2", "http://127.0.0.1:9002/");
+        bumped("el alta");
+        state.deregister_node(&LMSTUDIO, &testing::node_id("box2"));
+        bumped("la despedida");
+
+        testing::announce(&state, "ollama", "box3", "http://127.0.0.1:9003/");
+        bumped("el alta");
+        remove_stale_nodes(&mut state.ollama_nodes.write().unwrap(), &state, Duration::ZERO, "Ollama", &OLLAMA);
+        bumped("la limpieza");
+        assert_eq!(testing::node_state(&state, "ollama", "box3"), "absent");
+    }
+
+    fn watch_request(query: &str) -> TestRequest {
+        TestRequest::get().uri(&format!("/nodes/watch?{}", query))
+    }
+
+    async fn json_body<B: actix_web::body::MessageBody>(resp: actix_web::dev::ServiceResponse<B>) -> Value {
+        assert_eq!(resp.status(), 200);
+        read_body_json(resp).await
+    }
+
+    fn ids(body: &Value) -> Vec<&str> {
+        body["nodes"].as_array().unwrap().iter().map(|node| node["node_id"].as_str().unwrap()).collect()
+    }
+
+    #[actix_web::test]
+    async fn watch_returns_snapshot_then_deltas() {
+        let state = testing::state(&[]);
+        testing::announce(&state, "lmstudio", "box1", "http://127.0.0.1:9001/");
+        testing::announce(&state, "ollama", "box2", "http://127.0.0.1:9002/");
+        let app = init_service(balancer::app(state.clone())).await;
+
+        let snapshot = json_body(call_service(&app, watch_request("since=0").to_request()).await).await;
+        assert_eq!(snapshot["full"], true);
+        assert_eq!(ids(&snapshot), ["box1", "box2"]);
+        let revision = snapshot["revision"].as_u64().unwrap();
+        assert_eq!(revision, state.revisions.current());
+
+        testing::announce(&state, "lmstudio", "box1", "http://127.0.0.1:9001/");
+        let delta = json_body(call_service(&app, watch_request(&format!("since={}", revision)).to_request()).await).await;
+        assert_eq!(delta["full"], false);
+        assert_eq!(ids(&delta), ["box1"]);
+        assert_eq!(delta["nodes"][0]["state"], "available");
+        let revision = delta["revision"].as_u64().unwrap();
+
+        state.deregister_node(&OLLAMA, &testing::node_id("box2"));
+        let delta = json_body(call_service(&app, watch_request(&format!("since={}", revision)).to_request()).await).await;
+        assert_eq!(delta["nodes"], serde_json::json!([{ "node_id": "box2", "service": "ollama", "removed": true }]));
+
+        // Sin cambios y sin esperar: lista vacía con la misma revisión.
+        let revision = delta["revision"].as_u64().unwrap();
+        let idle = json_body(call_service(&app, watch_request(&format!("since={}&timeout_secs=0", revision)).to_request()).await).await;
+        assert_eq!(idle, serde_json::json!({ "revision": revision, "full": false, "nodes": [] }));
+    }
+
+    #[actix_web::test]
+    async fn long_poll_wakes_on_the_next_change() {
+        let state = testing::state(&[]);
+        testing::announce(&state, "lmstudio", "box0", "http://127.0.0.1:9000/");
+        let app = init_service(balancer::app(state.clone())).await;
+        // `since=0` pide el registro completo: se espera desde la revisión del primer alta.
+        let since = state.revisions.current();
+
+        let registering = state.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            testing::announce(&registering, "lmstudio", "box1", "http://127.0.0.1:9001/");
+        });
+        let start = std::time::Instant::now();
+        let delta = json_body(call_service(&app, watch_request(&format!("since={}&timeout_secs=10", since)).to_request()).await).await;
+        assert!(start.elapsed() < Duration::from_secs(5), "{:?}", start.elapsed());
+        assert_eq!(ids(&delta), ["box1"]);
+        assert!(delta["revision"].as_u64().unwrap() > since);
+    }
+
+    /// Un cliente que vuelve con una revisión compactada, o de antes de reiniciar el balanceador,
+    /// recibe el registro completo y puede seguir con deltas desde la revisión nueva.
+    #[actix_web::test]
+    async fn stale_revisions_get_a_full_snapshot() {
+        let limits = testing::temp_file("limits.toml", "[limits]\nrevision_changes = 4\n");
+        let state = testing::state(&["--limits-file", limits.to_str().unwrap()]);
+        testing::announce(&state, "lmstudio", "box1", "http://127.0.0.1:9001/");
+        testing::announce(&state, "lmstudio", "box2", "http://127.0.0.1:9002/");
+        let app = init_service(balancer::app(state.clone())).await;
+        let seen = json_body(call_service(&app, watch_request("since=0").to_request()).await).await["revision"].as_u64().unwrap();
+
+        for _ in 0..10 {
+            testing::announce(&state, "lmstudio", "box1", "http://127.0.0.1:9001/");
+        }
+        let reconnect = json_body(call_service(&app, watch_request(&format!("since={}", seen)).to_request()).await).await;
+        assert_eq!(reconnect["full"], true, "{}", reconnect);
+        assert_eq!(ids(&reconnect), ["box1", "box2"]);
+
+        let restarted = json_body(call_service(&app, watch_request(&format!("since={}", state.revisions.current() + 100)).to_request()).await).await;
+        assert_eq!(restarted["full"], true);
+        assert_eq!(restarted["revision"].as_u64().unwrap(), state.revisions.current());
+
+        let revision = reconnect["revision"].as_u64().unwrap();
+        testing::announce(&state, "lmstudio", "box2", "http://127.0.0.1:9002/");
+        let delta = json_body(call_service(&app, watch_request(&format!("since={}", revision)).to_request()).await).await;
+        assert_eq!(delta["full"], false);
+        assert_eq!(ids(&delta), ["box2"]);
+    }
+}
@@ -0,0 +1,149 @@
+// src/round_robin.rs
+//! Turno rotatorio entre los nodos de cada pool.
+//!
+//! Cada pool recuerda el último nodo que eligió. La siguiente búsqueda recorre los nodos en
+//! orden de ID empezando por el siguiente a ese y vuelve al principio, así que peticiones
+//! seguidas pasan por todos los nodos libres en vez de caer siempre en los primeros del
+//! `HashMap`. El cursor es un ID, no una posición: un nodo que entra ocupa su sitio en el
+//! anillo, uno que se va (aunque sea el del cursor) no descoloca a los demás, y los nodos
+//! ocupados o caídos se saltan sin mover el turno.
+//!
+//! El reparto ponderado (`--node-selection weighted`) usa el turno rotatorio suavizado de nginx:
+//! en cada elección los candidatos suman su peso a su crédito, se elige el de más crédito y
+//! este resta la suma de los pesos. Un nodo de peso 3 junto a uno de peso 1 recibe tres de cada
+//! cuatro peticiones, intercaladas (A A B A) en vez de en ráfagas. A igualdad de crédito manda
+//! el turno, así que con todos los pesos a 1 el resultado es el mismo que por turnos.
+//!
+//! Los criterios de `selection` que desempatan por turnos llevan cada uno su `RoundRobin`.
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::ids::NodeId;
+
+#[derive(Default)]
+pub struct RoundRobin {
+    /// Último nodo elegido, por pool. Una entrada por pool: no crece con los nodos.
+    last: Mutex<HashMap<String, NodeId>>,
+    /// Crédito de cada nodo en el reparto ponderado, por pool. Se olvida al salir el nodo.
+    credits: Mutex<HashMap<String, HashMap<NodeId, i64>>>,
+}
+
+impl RoundRobin {
+    /// IDs de la pool en el orden en que hay que probarlos: primero los posteriores al último
+    /// elegido y luego, dando la vuelta, el resto.
+    pub fn order<'a>(&self, service: &str, ids: impl Iterator<Item = &'a NodeId>) -> Vec<NodeId> {
+        let mut ids: Vec<NodeId> = ids.cloned().collect();
+        ids.sort_unstable();
+        if let Some(last) = self.last.lock().unwrap().get(service) {
+            let start = ids.partition_point(|id| id <= last);
+            ids.rotate_left(start);
+        }
+        ids
+    }
+
+    /// Avanza el turno de la pool hasta el nodo elegido.
+    pub fn advance(&self, service: &str, unique_node_id: &NodeId) {
+        self.last.lock().unwrap().insert(service.to_string(), unique_node_id.clone());
+    }
+
+    /// Candidatos `(ID, peso)` en el orden en que hay que probarlos en el reparto ponderado: de
+    /// más a menos crédito tras sumar cada uno su peso; a igualdad, por turnos.
+    pub fn weighted_order(&self, service: &str, candidates: &[(NodeId, u32)]) -> Vec<NodeId> {
+        let mut order = self.order(service, candidates.iter().map(|(id, _)| id));
+        let credits = self.credits.lock().unwrap();
+        let pool = credits.get(service);
+        let weights: HashMap<&NodeId, u32> = candidates.iter().map(|(id, weight)| (id, *weight)).collect();
+        // Orden estable: entre nodos con el mismo crédito sigue mandando el turno.
+        order.sort_by_key(|id| Reverse(pool.and_then(|pool| pool.get(id)).copied().unwrap_or(0) + i64::from(weights[id])));
+        order
+    }
+
+    /// Apunta la elección de `chosen` entre `candidates` en el reparto ponderado. Se olvidan los
+    /// créditos de los nodos para los que `in_pool` es falso.
+    pub fn charge(&self, service: &str, candidates: &[(NodeId, u32)], chosen: &NodeId, in_pool: impl Fn(&NodeId) -> bool) {
+        let mut credits = self.credits.lock().unwrap();
+        let pool = credits.entry(service.to_string()).or_default();
+        pool.retain(|id, _| in_pool(id));
+        let mut total = 0;
+        for (id, weight) in candidates {
+            *pool.entry(id.clone()).or_default() += i64::from(*weight);
+            total += i64::from(*weight);
+        }
+        *pool.entry(chosen.clone()).or_default() -= total;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service};
+    use std::time::{Duration, Instant};
+
+    use crate::balancer::{self, NodeHealth, LMSTUDIO};
+    use crate::history::TransitionCause;
+    use crate::testing;
+
+    fn ids(names: &[&str]) -> Vec<NodeId> {
+        names.iter().map(|name| testing::node_id(name)).collect()
+    }
+
+    #[test]
+    fn order_starts_after_the_last_choice_and_wraps() {
+        let rr = RoundRobin::default();
+        let pool = ids(&["c", "a", "b"]);
+        assert_eq!(rr.order("lmstudio", pool.iter()), ids(&["a", "b", "c"]));
+        rr.advance("lmstudio", &testing::node_id("a"));
+        assert_eq!(rr.order("lmstudio", pool.iter()), ids(&["b", "c", "a"]));
+        rr.advance("lmstudio", &testing::node_id("c"));
+        assert_eq!(rr.order("lmstudio", pool.iter()), ids(&["a", "b", "c"]));
+        // Cada pool lleva su turno.
+        assert_eq!(rr.order("ollama", pool.iter()), ids(&["a", "b", "c"]));
+    }
+
+    #[test]
+    fn joining_and_leaving_nodes_keep_the_turn() {
+        let rr = RoundRobin::default();
+        rr.advance("lmstudio", &testing::node_id("b"));
+        // Uno nuevo ocupa su sitio en el anillo.
+        assert_eq!(rr.order("lmstudio", ids(&["a", "b", "bb", "c"]).iter()), ids(&["bb", "c", "a", "b"]));
+        // Si se va el del cursor, sigue el siguiente.
+        assert_eq!(rr.order("lmstudio", ids(&["a", "c"]).iter()), ids(&["c", "a"]));
+        assert_eq!(rr.order("lmstudio", ids(&[]).iter()), ids(&[]));
+    }
+
+    #[actix_web::test]
+    async fn six_requests_occupy_each_of_three_nodes_twice() {
+        let state = testing::state(&[]);
+        let node = testing::chat_node(Duration::ZERO);
+        for id in ["box2", "box0", "box1"] {
+            testing::announce(&state, "lmstudio", id, &node);
+        }
+        let app = init_service(balancer::app(state.clone())).await;
+        // Nodos que reciben cada una de `count` peticiones seguidas.
+        let served_by = |count: usize| {
+            let app = &app;
+            async move {
+                let mut served = Vec::new();
+                for _ in 0..count {
+                    let res = call_service(app, testing::chat().to_request()).await;
+                    assert_eq!(res.status(), 200);
+                    served.push(res.headers().get("X-LMServer-Node-Id").unwrap().to_str().unwrap().to_string());
+                }
+                served
+            }
+        };
+
+        assert_eq!(served_by(6).await, ["box0", "box1", "box2", "box0", "box1", "box2"]);
+
+        // Un nodo caído se salta sin mover el turno; al volver recupera su sitio.
+        state.update_node_state(&LMSTUDIO, &testing::node_id("box1"), NodeHealth::Failed(Instant::now()), TransitionCause::HealthCheck);
+        assert_eq!(served_by(3).await, ["box0", "box2", "box0"]);
+        state.update_node_state(&LMSTUDIO, &testing::node_id("box1"), NodeHealth::Available, TransitionCause::HealthCheck);
+        assert_eq!(served_by(3).await, ["box1", "box2", "box0"]);
+
+        // Uno que entra por descubrimiento también recibe su parte.
+        testing::announce(&state, "lmstudio", "box3", &node);
+        assert_eq!(served_by(4).await, ["box1", "box2", "box3", "box0"]);
+    }
+}
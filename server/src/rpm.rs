@@ -0,0 +1,118 @@
+// src/rpm.rs
+//! Tope de peticiones por minuto de cada nodo (`max_rpm`).
+//!
+//! Algunos dueños de nodos quieren un techo fijo de tráfico para su máquina, responda lo rápido
+//! que responda. El nodo lo anuncia con `--max-rpm` (datagrama `MAX_RPM`) y `--node-max-rpm
+//! <nodo>=<n>` en el balanceador lo fija también; si hay los dos, manda el menor. Un nodo que ha
+//! recibido su tope en el último minuto no es elegible aunque esté Available: se prueba con otro
+//! y, si no hay ninguno, la petición sigue en la cola.
+//!
+//! La ventana es deslizante: cada nodo con tope guarda el instante de cada despacho y cada uno
+//! deja de contar 60 s después. No hay minutos de reloj, así que una cola que se vacía al cambiar
+//! de minuto no puede colar el doble del tope, y lo que cuenta es cuándo se despachó la petición,
+//! no cuándo entró en la cola. El tope es por pool, como el ritmo de despacho.
+//!
+//! Las reservas de `X-Pipeline` y los nodos que prepara el cargador también cuentan y, si el nodo
+//! ya está en su tope, vuelven a la pool. Un nodo con tope no entra en el modo directo.
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::api_types::RpmStatus;
+use crate::balancer::NodeInfo;
+
+/// Ventana sobre la que se cuenta el tope.
+pub const WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub struct RpmConfigError(String);
+
+impl fmt::Display for RpmConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for RpmConfigError {}
+
+/// Despachos del último minuto de un nodo. Vive en su `NodeInfo`, así que se consulta y anota
+/// con el lock de la pool; nunca guarda más que el tope.
+#[derive(Clone, Debug, Default)]
+pub struct RpmWindow {
+    dispatched: VecDeque<Instant>,
+}
+
+impl RpmWindow {
+    /// Despachos de la ventana que acaba en `now`.
+    pub fn count(&self, now: Instant) -> usize {
+        self.dispatched.iter().filter(|at| now.saturating_duration_since(**at) < WINDOW).count()
+    }
+
+    fn record(&mut self, now: Instant, cap: u32) {
+        while self.dispatched.front().is_some_and(|at| now.saturating_duration_since(*at) >= WINDOW) {
+            self.dispatched.pop_front();
+        }
+        self.dispatched.push_back(now);
+        while self.dispatched.len() > cap as usize {
+            self.dispatched.pop_front();
+        }
+    }
+}
+
+/// Topes fijados por configuración (`--node-max-rpm`).
+#[derive(Default)]
+pub struct RpmLimits {
+    overrides: HashMap<String, u32>,
+}
+
+impl RpmLimits {
+    /// Interpreta entradas `nodo=peticiones_por_minuto` de la línea de comandos.
+    pub fn new(entries: &[String]) -> Result<Self, RpmConfigError> {
+        let mut overrides = HashMap::new();
+        for entry in entries {
+            let Some((node, cap)) = entry.split_once('=').map(|(node, cap)| (node.trim(), cap.trim())) else {
+                return Err(RpmConfigError(format!("Tope inválido '{}': se esperaba <nodo>=<peticiones_por_minuto>", entry)));
+            };
+            let Some(cap) = cap.parse::<u32>().ok().filter(|cap| *cap > 0 && !node.is_empty()) else {
+                return Err(RpmConfigError(format!("Tope inválido '{}': el nodo no puede estar vacío y el tope debe ser mayor que 0", entry)));
+            };
+            if overrides.insert(node.to_string(), cap).is_some() {
+                return Err(RpmConfigError(format!("El nodo '{}' tiene más de un --node-max-rpm", node)));
+            }
+        }
+        Ok(Self { overrides })
+    }
+
+    /// Tope del nodo: el menor entre el configurado y el anunciado; `None` si no tiene.
+    pub fn cap_for(&self, unique_node_id: &str, info: &NodeInfo) -> Option<u32> {
+        match (self.overrides.get(unique_node_id).copied(), info.max_rpm) {
+            (Some(configured), Some(announced)) => Some(configured.min(announced)),
+            (configured, announced) => configured.or(announced),
+        }
+    }
+
+    /// Si el nodo puede recibir otra petición sin pasar de su tope.
+    pub fn admits(&self, unique_node_id: &str, info: &NodeInfo, now: Instant) -> bool {
+        self.cap_for(unique_node_id, info).is_none_or(|cap| info.rpm_window.count(now) < cap as usize)
+    }
+
+    /// Anota un despacho al nodo. Sin tope no se guarda nada.
+    pub fn record(&self, unique_node_id: &str, info: &mut NodeInfo, now: Instant) {
+        match self.cap_for(unique_node_id, info) {
+            Some(cap) => info.rpm_window.record(now, cap),
+            None => info.rpm_window = RpmWindow::default(),
+        }
+    }
+
+    /// Tope y uso del nodo para `/nodes` y la UI; `None` si no tiene tope.
+    pub fn status(&self, unique_node_id: &str, info: &NodeInfo) -> Option<RpmStatus> {
+        let max_rpm = self.cap_for(unique_node_id, info)?;
+        Some(RpmStatus { max_rpm, used: info.rpm_window.count(Instant::now()) as u32 })
+    }
+
+    /// Configuración para `/config`.
+    pub fn describe(&self) -> Value {
+        json!({ "window_secs": WINDOW.as_secs(), "nodes": self.overrides })
+    }
+}
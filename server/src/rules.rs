@@ -0,0 +1,696 @@
+// src/rules.rs
+//! Reglas declarativas de enrutado por contenido.
+//!
+//! ```toml
+//! [[rules]]
+//! name = "sql-a-ollama"
+//! match = { body = [{ path = "$.messages[0].content", contains = "SQL" }] }
+//! action = { route = "ollama" }
+//!
+//! [[rules]]
+//! name = "soporte-largo"
+//! match = { api_key = "support-bot", min_messages = 20 }
+//! action = { reject = "Conversación demasiado larga; empieza una nueva." }
+//!
+//! [[rules]]
+//! name = "contexto-largo-en-metal"
+//! match = { min_prompt_tokens = 16000 }
+//! action = { prefer = { accelerator = "metal" } }
+//!
+//! [[rules]]
+//! name = "resumenes-en-lote"
+//! match = { header = "X-Job-Id" }
+//! action = { classify = "batch" }
+//! ```
+//!
+//! Se evalúan en orden tras validar la petición y aplicar la política de la API key, antes de
+//! elegir nodo. Gana la primera que coincide; si ninguna coincide, la petición sigue en la
+//! pool de la ruta. Todas las condiciones de una regla deben cumplirse. El archivo se vuelve
+//! a leer cuando cambia; si la versión nueva es inválida se conservan las reglas anteriores.
+//!
+//! `prefer` no cambia de pool: entre los nodos libres prueba antes los de esa plataforma
+//! (`platform::PlatformMatch`) y, si no hay ninguno libre, usa cualquier otro.
+use actix_web::http::header::HeaderMap;
+use actix_web::{post, web, HttpResponse, Responder};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::time::interval;
+
+use crate::balancer::{AppState};
+use crate::context;
+use crate::events::BalancerEvent;
+use crate::ids::PoolName;
+use crate::platform::PlatformMatch;
+use crate::reload;
+use crate::workload::WorkloadClass;
+
+/// Cada cuánto se comprueba si el archivo de reglas cambió.
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub struct RulesConfigError(String);
+
+impl fmt::Display for RulesConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for RulesConfigError {}
+
+/// Condición sobre un valor del cuerpo, seleccionado con una ruta `$.campo[índice].campo`.
+/// `[*]` recorre todos los elementos de un array; basta con que uno cumpla.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BodyMatch {
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub equals: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub contains: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Matcher {
+    /// Nombre de la sección de la API key en `--api-keys-file`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+    /// Patrón del modelo con `*` y `?`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_messages: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_messages: Option<usize>,
+    /// Tamaño mínimo del prompt estimado (`context::estimate_prompt_tokens`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_prompt_tokens: Option<u64>,
+    /// Cabecera que debe venir en la petición, con cualquier valor.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub header: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub body: Vec<BodyMatch>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum RuleAction {
+    /// Atender la petición en otra pool (o alias).
+    Route(PoolName),
+    /// Rechazar la petición con este mensaje.
+    Reject(String),
+    /// Atenderla en su pool con esta clase de carga (`workload`).
+    Classify(WorkloadClass),
+    /// Atenderla en su pool, prefiriendo los nodos con esta plataforma.
+    Prefer(PlatformMatch),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub name: String,
+    #[serde(default, rename = "match")]
+    pub matcher: Matcher,
+    pub action: RuleAction,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    rules: Vec<Rule>,
+}
+
+/// Lo que las reglas pueden mirar de una petición.
+pub struct RouteRequest<'a> {
+    pub api_key_name: Option<&'a str>,
+    pub headers: &'a HeaderMap,
+    pub body: &'a Value,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PathSegment<'a> {
+    Key(&'a str),
+    Index(usize),
+    Wildcard,
+}
+
+/// Interpreta el subconjunto de JSONPath admitido: `$`, `.campo`, `[n]` y `[*]`.
+fn parse_path(path: &str) -> Option<Vec<PathSegment<'_>>> {
+    let mut rest = path.strip_prefix('$')?;
+    let mut segments = Vec::new();
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix('.') {
+            let end = after.find(['.', '[']).unwrap_or(after.len());
+            if end == 0 {
+                return None;
+            }
+            segments.push(PathSegment::Key(&after[..end]));
+            rest = &after[end..];
+        } else if let Some(after) = rest.strip_prefix('[') {
+            let (inner, after) = after.split_once(']')?;
+            segments.push(match inner {
+                "*" => PathSegment::Wildcard,
+                index => PathSegment::Index(index.parse().ok()?),
+            });
+            rest = after;
+        } else {
+            return None;
+        }
+    }
+    Some(segments)
+}
+
+fn select<'v>(value: &'v Value, segments: &[PathSegment<'_>], out: &mut Vec<&'v Value>) {
+    let Some((first, rest)) = segments.split_first() else {
+        out.push(value);
+        return;
+    };
+    match first {
+        PathSegment::Key(key) => {
+            if let Some(next) = value.get(*key) {
+                select(next, rest, out);
+            }
+        }
+        PathSegment::Index(index) => {
+            if let Some(next) = value.get(*index) {
+                select(next, rest, out);
+            }
+        }
+        PathSegment::Wildcard => {
+            for next in value.as_array().into_iter().flatten() {
+                select(next, rest, out);
+            }
+        }
+    }
+}
+
+/// Patrón con `*` (cualquier secuencia) y `?` (un carácter).
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let (pattern, text): (Vec<char>, Vec<char>) = (pattern.chars().collect(), text.chars().collect());
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+impl BodyMatch {
+    fn matches(&self, body: &Value) -> bool {
+        let Some(segments) = parse_path(&self.path) else {
+            return false;
+        };
+        let mut selected = Vec::new();
+        select(body, &segments, &mut selected);
+        selected.into_iter().any(|value| {
+            let equals = self.equals.as_ref().is_none_or(|expected| value == expected);
+            let contains = self.contains.as_ref().is_none_or(|needle| match value {
+                Value::String(text) => text.contains(needle.as_str()),
+                other => other.to_string().contains(needle.as_str()),
+            });
+            equals && contains
+        })
+    }
+}
+
+impl Matcher {
+    /// `Err` describe la primera condición que no se cumple.
+    fn check(&self, request: &RouteRequest<'_>) -> Result<(), String> {
+        if let Some(expected) = &self.api_key {
+            if request.api_key_name != Some(expected.as_str()) {
+                return Err(format!("la API key no es '{}'", expected));
+            }
+        }
+        if let Some(pattern) = &self.model {
+            let model = request.body.get("model").and_then(Value::as_str).unwrap_or_default();
+            if !glob_match(pattern, model) {
+                return Err(format!("el modelo '{}' no encaja con '{}'", model, pattern));
+            }
+        }
+        let messages = request.body.get("messages").and_then(Value::as_array).map_or(0, Vec::len);
+        if self.min_messages.is_some_and(|min| messages < min) || self.max_messages.is_some_and(|max| messages > max) {
+            return Err(format!("{} mensajes fuera del rango", messages));
+        }
+        if let Some(min) = self.min_prompt_tokens {
+            let tokens = context::estimate_prompt_tokens(request.body);
+            if tokens < min {
+                return Err(format!("prompt estimado de {} tokens, menos de {}", tokens, min));
+            }
+        }
+        if let Some(header) = &self.header {
+            if !request.headers.contains_key(header.as_str()) {
+                return Err(format!("falta la cabecera '{}'", header));
+            }
+        }
+        if let Some(failed) = self.body.iter().find(|condition| !condition.matches(request.body)) {
+            return Err(format!("el cuerpo no cumple la condición sobre '{}'", failed.path));
+        }
+        Ok(())
+    }
+}
+
+/// Reglas cargadas, en orden de evaluación.
+#[derive(Clone, Default)]
+pub struct RuleSet {
+    rules: Vec<Rule>,
+    /// Archivo de origen y su fecha de modificación al cargarlo, para la recarga.
+    source: Option<(PathBuf, Option<SystemTime>)>,
+}
+
+impl RuleSet {
+    /// Carga y valida el archivo. `is_pool` indica si un nombre es una pool o un alias válido.
+    pub fn load(path: &Path, is_pool: impl Fn(&str) -> bool) -> Result<Self, RulesConfigError> {
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| RulesConfigError(format!("No se pudo leer {}: {}", path.display(), e)))?;
+        let file: RulesFile = toml::from_str(&content)
+            .map_err(|e| RulesConfigError(format!("Archivo de reglas inválido {}: {}", path.display(), e)))?;
+        for rule in &file.rules {
+            if let RuleAction::Route(pool) = &rule.action {
+                if !is_pool(pool) {
+                    return Err(RulesConfigError(format!("La regla '{}' enruta a una pool desconocida '{}'", rule.name, pool)));
+                }
+            }
+            if matches!(&rule.action, RuleAction::Prefer(platform) if platform.is_empty()) {
+                return Err(RulesConfigError(format!("La regla '{}' no dice qué plataforma prefiere", rule.name)));
+            }
+            if let (Some(min), Some(max)) = (rule.matcher.min_messages, rule.matcher.max_messages) {
+                if min > max {
+                    return Err(RulesConfigError(format!("La regla '{}' tiene min_messages > max_messages", rule.name)));
+                }
+            }
+            for condition in &rule.matcher.body {
+                if parse_path(&condition.path).is_none() {
+                    return Err(RulesConfigError(format!(
+                        "Ruta inválida '{}' en la regla '{}': se admiten $, .campo, [n] y [*]",
+                        condition.path, rule.name
+                    )));
+                }
+            }
+        }
+        Ok(Self { rules: file.rules, source: Some((path.to_path_buf(), modified)) })
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// Archivo del que se cargaron, para recargarlo (`reload`).
+    pub fn path(&self) -> Option<&Path> {
+        self.source.as_ref().map(|(path, _)| path.as_path())
+    }
+
+    /// Toma como vista la fecha actual del archivo, para que `watch` no recargue encima de unas
+    /// reglas aplicadas a mano (p.ej. al volver a una configuración anterior).
+    pub fn mark_seen(&mut self) {
+        if let Some((path, modified)) = self.source.as_mut() {
+            *modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        }
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Primera regla que coincide con la petición.
+    pub fn evaluate(&self, request: &RouteRequest<'_>) -> Option<&Rule> {
+        self.rules.iter().find(|rule| rule.matcher.check(request).is_ok())
+    }
+}
+
+/// Vuelve a cargar el archivo de reglas cuando cambia su fecha de modificación.
+pub async fn watch(state: web::Data<AppState>) {
+    let mut ticker = interval(RELOAD_CHECK_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let Some((path, loaded_modified)) = state.routing_rules.read().unwrap().source.clone() else {
+            return;
+        };
+        let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+        if modified == loaded_modified {
+            continue;
+        }
+        match RuleSet::load(&path, |pool| state.pool(state.canonical_service(pool)).is_some()) {
+            Ok(rules) => {
+                info!("Rules: {} reglas recargadas de {}.", rules.len(), path.display());
+                let count = rules.len();
+                reload::apply(&state, Some(rules), None, "rules_file");
+                state.events.publish(BalancerEvent::RulesReloaded { rules: count });
+            }
+            Err(e) => {
+                error!("Rules: {}. Se mantienen las reglas anteriores.", e);
+                // No se reintenta hasta el siguiente cambio del archivo.
+                if let Some((_, loaded)) = state.routing_rules.write().unwrap().source.as_mut() {
+                    *loaded = modified;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RouteDebugRequest {
+    pool: PoolName,
+    /// API key con la que simular la petición.
+    api_key: Option<String>,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    request: Value,
+}
+
+/// `POST /debug/route`: qué regla coincidiría con una petición y por qué no coinciden las anteriores.
+#[post("/debug/route")]
+async fn route_debug_handler(state: web::Data<AppState>, debug: web::Json<RouteDebugRequest>) -> impl Responder {
+    let RouteDebugRequest { pool, api_key, headers, request } = debug.into_inner();
+    if state.pool(state.canonical_service(&pool)).is_none() {
+        return HttpResponse::NotFound().json(json!({ "error": format!("Pool desconocida '{}'.", pool) }));
+    }
+    let mut header_map = HeaderMap::new();
+    for (name, value) in &headers {
+        if let (Ok(name), Ok(value)) = (name.parse(), value.parse()) {
+            header_map.insert(name, value);
+        }
+    }
+    let key_policy = api_key.as_deref().and_then(|key| state.key_policies.lookup(key));
+    let api_key_name = key_policy.map(|(name, _)| name);
+    let route_request = RouteRequest { api_key_name, headers: &header_map, body: &request };
+
+    let rules = state.routing_rules.read().unwrap();
+    let mut evaluated = Vec::new();
+    let mut matched = None;
+    for rule in rules.rules() {
+        match rule.matcher.check(&route_request) {
+            Ok(()) => {
+                evaluated.push(json!({ "rule": rule.name, "matched": true }));
+                matched = Some(rule);
+                break;
+            }
+            Err(reason) => evaluated.push(json!({ "rule": rule.name, "matched": false, "reason": reason })),
+        }
+    }
+    // El tamaño del cuerpo se mide sobre `request` reserializado, sin los espacios del original.
+    let body = serde_json::to_vec(&request).unwrap_or_default();
+    let key_pool = key_policy.is_some_and(|(_, policy)| policy.pool.is_some());
+    let lane_pool = state.slow_lane.pool().filter(|_| !key_pool && state.slow_lane.classify(&body).is_some());
+    let final_pool = match matched.map(|rule| &rule.action) {
+        Some(RuleAction::Route(target)) => Some(state.canonical_service(target).to_string()),
+        Some(RuleAction::Reject(_)) => None,
+        Some(RuleAction::Classify(_) | RuleAction::Prefer(_)) | None => Some(state.canonical_service(lane_pool.unwrap_or(&pool)).to_string()),
+    };
+    HttpResponse::Ok().json(json!({
+        "pool": pool,
+        "api_key_policy": api_key_name,
+        "matched_rule": matched.map(|rule| &rule.name),
+        "action": matched.map(|rule| &rule.action),
+        "final_pool": final_pool,
+        "evaluated": evaluated,
+        "slow_lane": state.slow_lane.explain(&body),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use crate::balancer;
+    use crate::events;
+    use crate::testing;
+
+    fn rule_set(toml: &str) -> RuleSet {
+        RuleSet::load(&testing::temp_file("rules.toml", toml), |pool| ["lmstudio", "ollama"].contains(&pool)).unwrap()
+    }
+
+    fn messages(count: usize) -> Vec<Value> {
+        (0..count).map(|i| json!({ "role": "user", "content": format!("mensaje {}", i) })).collect()
+    }
+
+    #[test]
+    fn glob_matches_star_and_question_mark() {
+        let cases = [
+            ("llama3*", "llama3:8b", true),
+            ("llama3*", "llama2:7b", false),
+            ("*", "", true),
+            ("*:8b", "qwen2:8b", true),
+            ("*:8b", "qwen2:70b", false),
+            ("qwen?", "qwen2", true),
+            ("qwen?", "qwen", false),
+            ("a*b*c", "axxbyyc", true),
+            ("a*b*c", "axxbyy", false),
+            ("exacto", "exacto", true),
+            ("exacto", "exactos", false),
+        ];
+        for (pattern, text, expected) in cases {
+            assert_eq!(glob_match(pattern, text), expected, "'{}' contra '{}'", pattern, text);
+        }
+    }
+
+    #[test]
+    fn paths_parse_the_supported_subset() {
+        use PathSegment::*;
+        assert_eq!(parse_path("$"), Some(vec![]));
+        assert_eq!(parse_path("$.model"), Some(vec![Key("model")]));
+        assert_eq!(parse_path("$.messages[0].content"), Some(vec![Key("messages"), Index(0), Key("content")]));
+        assert_eq!(parse_path("$.messages[*].role"), Some(vec![Key("messages"), Wildcard, Key("role")]));
+        for invalid in ["", "model", "$.", "$..model", "$.messages[", "$.messages[-1]", "$.messages[x]", "$model"] {
+            assert_eq!(parse_path(invalid), None, "'{}'", invalid);
+        }
+    }
+
+    /// Cada condición del matcher, sola, contra una petición que la cumple y otra que no.
+    #[test]
+    fn each_matcher_checks_its_condition() {
+        let sql = json!({ "model": "llama3:8b", "messages": [
+            { "role": "system", "content": "Eres un experto en SQL." },
+            { "role": "user", "content": "hola" },
+        ], "temperature": 0 });
+        let plain = json!({ "model": "qwen2:7b", "messages": messages(1) });
+        let mut with_header = HeaderMap::new();
+        with_header.insert("x-job-id".parse().unwrap(), "42".parse().unwrap());
+        let no_headers = HeaderMap::new();
+
+        // Matcher, petición que lo cumple y petición que no: (API key, cabeceras, cuerpo).
+        type Request<'a> = (Option<&'a str>, &'a HeaderMap, &'a Value);
+        let cases: [(&str, Request, Request); 9] = [
+            ("api_key = \"support-bot\"", (Some("support-bot"), &no_headers, &plain), (Some("research"), &no_headers, &plain)),
+            ("model = \"llama3*\"", (None, &no_headers, &sql), (None, &no_headers, &plain)),
+            ("min_messages = 2", (None, &no_headers, &sql), (None, &no_headers, &plain)),
+            ("max_messages = 1", (None, &no_headers, &plain), (None, &no_headers, &sql)),
+            ("header = \"X-Job-Id\"", (None, &with_header, &plain), (None, &no_headers, &plain)),
+            ("body = [{ path = \"$.messages[0].content\", contains = \"SQL\" }]", (None, &no_headers, &sql), (None, &no_headers, &plain)),
+            ("body = [{ path = \"$.messages[*].content\", equals = \"hola\" }]", (None, &no_headers, &sql), (None, &no_headers, &plain)),
+            ("body = [{ path = \"$.temperature\", equals = 0 }]", (None, &no_headers, &sql), (None, &no_headers, &plain)),
+            ("body = [{ path = \"$.temperature\", contains = \"0\" }]", (None, &no_headers, &sql), (None, &no_headers, &plain)),
+        ];
+        for (matcher, (key, headers, body), (other_key, other_headers, other_body)) in cases {
+            let rules = rule_set(&format!("[[rules]]\nname = \"r\"\nmatch = {{ {} }}\naction = {{ route = \"ollama\" }}\n", matcher));
+            let request = RouteRequest { api_key_name: key, headers, body };
+            assert!(rules.evaluate(&request).is_some(), "{}: debería coincidir", matcher);
+            let request = RouteRequest { api_key_name: other_key, headers: other_headers, body: other_body };
+            assert!(rules.evaluate(&request).is_none(), "{}: no debería coincidir", matcher);
+        }
+    }
+
+    #[test]
+    fn all_conditions_of_a_rule_must_hold() {
+        let rules = rule_set("[[rules]]\nname = \"r\"\nmatch = { api_key = \"support-bot\", min_messages = 3 }\naction = { reject = \"no\" }\n");
+        let headers = HeaderMap::new();
+        let long = json!({ "model": "m", "messages": messages(3) });
+        let short = json!({ "model": "m", "messages": messages(2) });
+
+        assert!(rules.evaluate(&RouteRequest { api_key_name: Some("support-bot"), headers: &headers, body: &long }).is_some());
+        assert!(rules.evaluate(&RouteRequest { api_key_name: Some("support-bot"), headers: &headers, body: &short }).is_none());
+        assert!(rules.evaluate(&RouteRequest { api_key_name: None, headers: &headers, body: &long }).is_none());
+    }
+
+    #[test]
+    fn first_matching_rule_wins_and_unmatched_requests_fall_through() {
+        let rules = rule_set(concat!(
+            "[[rules]]\nname = \"largas\"\nmatch = { min_messages = 5 }\naction = { reject = \"demasiado larga\" }\n",
+            "[[rules]]\nname = \"llama\"\nmatch = { model = \"llama*\" }\naction = { route = \"ollama\" }\n",
+            "[[rules]]\nname = \"todas-las-llama\"\nmatch = { model = \"llama*\" }\naction = { route = \"lmstudio\" }\n",
+        ));
+        let headers = HeaderMap::new();
+        let name = |body: Value| rules.evaluate(&RouteRequest { api_key_name: None, headers: &headers, body: &body }).map(|rule| rule.name.clone());
+
+        assert_eq!(name(json!({ "model": "llama3", "messages": messages(6) })).as_deref(), Some("largas"));
+        assert_eq!(name(json!({ "model": "llama3", "messages": messages(1) })).as_deref(), Some("llama"));
+        assert_eq!(name(json!({ "model": "qwen2", "messages": messages(1) })), None);
+        assert_eq!(rule_set("").evaluate(&RouteRequest { api_key_name: None, headers: &headers, body: &json!({}) }).map(|rule| &rule.name), None);
+    }
+
+    #[test]
+    fn invalid_rules_files_are_rejected() {
+        let cases = [
+            ("[[rules]]\nname = \"r\"\naction = { route = \"vllm\" }\n", "pool desconocida 'vllm'"),
+            ("[[rules]]\nname = \"r\"\nmatch = { min_messages = 3, max_messages = 2 }\naction = { reject = \"no\" }\n", "min_messages > max_messages"),
+            ("[[rules]]\nname = \"r\"\nmatch = { body = [{ path = \"messages\", contains = \"x\" }] }\naction = { reject = \"no\" }\n", "Ruta inválida 'messages'"),
+            ("[[rules]]\nname = \"r\"\nmatch = { color = \"rojo\" }\naction = { reject = \"no\" }\n", "Archivo de reglas inválido"),
+            ("[[rules]]\nname = \"r\"\naction = { teleport = \"marte\" }\n", "Archivo de reglas inválido"),
+            ("[[rules]]\nname = \"r\"\naction = { prefer = {} }\n", "no dice qué plataforma"),
+        ];
+        for (toml, expected) in cases {
+            let err = RuleSet::load(&testing::temp_file("rules.toml", toml), |pool| pool == "ollama").map(|_| ()).unwrap_err().to_string();
+            assert!(err.contains(expected), "{:?}: {}", toml, err);
+        }
+        assert!(RuleSet::load(&testing::temp_dir().join("no-existe.toml"), |_| true).is_err());
+    }
+
+    const RULES: &str = concat!(
+        "[[rules]]\nname = \"sql-a-ollama\"\nmatch = { body = [{ path = \"$.messages[0].content\", contains = \"SQL\" }] }\naction = { route = \"ollama\" }\n",
+        "[[rules]]\nname = \"soporte-largo\"\nmatch = { api_key = \"support-bot\", min_messages = 3 }\naction = { reject = \"Conversación demasiado larga.\" }\n",
+    );
+
+    /// Estado con `RULES`, dos API keys y un nodo que cuenta peticiones en cada pool.
+    fn routed_state() -> (web::Data<AppState>, Arc<AtomicUsize>, Arc<AtomicUsize>) {
+        let rules = testing::temp_file("rules.toml", RULES);
+        let keys = testing::temp_file("keys.toml", "[keys.support-bot]\nkey = \"sk-support\"\n\n[keys.research]\nkey = \"sk-research\"\n");
+        let state = testing::state(&[
+            "--rules-file", rules.to_str().unwrap(),
+            "--api-keys-file", keys.to_str().unwrap(),
+            "--admin-token", "admin",
+        ]);
+        let (lmstudio, lmstudio_hits) = testing::counting_chat_node(Duration::ZERO);
+        let (ollama, ollama_hits) = testing::counting_chat_node(Duration::ZERO);
+        testing::announce(&state, "lmstudio", "box1", &lmstudio);
+        testing::announce(&state, "ollama", "box2", &ollama);
+        (state, lmstudio_hits, ollama_hits)
+    }
+
+    fn chat(key: &str, messages: Vec<Value>) -> TestRequest {
+        testing::chat_with(json!({ "messages": messages })).insert_header(("Authorization", format!("Bearer {}", key)))
+    }
+
+    #[actix_web::test]
+    async fn rules_route_reject_or_fall_through() {
+        let (state, lmstudio_hits, ollama_hits) = routed_state();
+        let app = init_service(balancer::app(state)).await;
+
+        let sql = vec![json!({ "role": "system", "content": "Escribe SQL." }), json!({ "role": "user", "content": "hola" })];
+        let resp = call_service(&app, chat("sk-research", sql).to_request()).await;
+        assert!(resp.status().is_success(), "{}", resp.status());
+        assert_eq!((lmstudio_hits.load(Ordering::SeqCst), ollama_hits.load(Ordering::SeqCst)), (0, 1));
+
+        let resp = call_service(&app, chat("sk-support", messages(3)).to_request()).await;
+        assert_eq!(resp.status(), 403);
+        let body: Value = read_body_json(resp).await;
+        assert_eq!(body["error"]["type"], "rejected_by_rule");
+        assert_eq!(body["error"]["message"], "Conversación demasiado larga.");
+        assert_eq!((lmstudio_hits.load(Ordering::SeqCst), ollama_hits.load(Ordering::SeqCst)), (0, 1));
+
+        // Ninguna regla coincide: la petición sigue en la pool de su ruta.
+        for key in ["sk-support", "sk-research"] {
+            let resp = call_service(&app, chat(key, messages(2)).to_request()).await;
+            assert!(resp.status().is_success(), "{}", resp.status());
+        }
+        let resp = call_service(&app, chat("sk-research", messages(3)).to_request()).await;
+        assert!(resp.status().is_success(), "{}", resp.status());
+        assert_eq!((lmstudio_hits.load(Ordering::SeqCst), ollama_hits.load(Ordering::SeqCst)), (3, 1));
+    }
+
+    #[actix_web::test]
+    async fn config_and_route_explainer_show_the_rules() {
+        let (state, lmstudio_hits, ollama_hits) = routed_state();
+        let app = init_service(balancer::app(state)).await;
+        let admin = ("Authorization", "Bearer admin");
+
+        let config: Value = read_body_json(call_service(&app, TestRequest::get().uri("/config").insert_header(admin).to_request()).await).await;
+        let rules = config["routing_rules"].as_array().unwrap();
+        assert_eq!(rules.iter().map(|rule| rule["name"].as_str().unwrap()).collect::<Vec<_>>(), ["sql-a-ollama", "soporte-largo"]);
+        assert_eq!(rules[0]["action"], json!({ "route": "ollama" }));
+        assert_eq!(rules[1]["match"], json!({ "api_key": "support-bot", "min_messages": 3 }));
+
+        let explain = |api_key: &str, messages: Vec<Value>| {
+            TestRequest::post()
+                .uri("/debug/route")
+                .insert_header(admin)
+                .set_json(json!({ "pool": "lmstudio", "api_key": api_key, "request": { "model": "m", "messages": messages } }))
+                .to_request()
+        };
+
+        let sql = vec![json!({ "role": "system", "content": "Escribe SQL." })];
+        let resp = call_service(&app, explain("sk-research", sql)).await;
+        assert_eq!(resp.status(), 200);
+        let body: Value = read_body_json(resp).await;
+        assert_eq!(body["matched_rule"], "sql-a-ollama");
+        assert_eq!(body["final_pool"], "ollama");
+        assert_eq!(body["api_key_policy"], "research");
+        assert_eq!(body["evaluated"], json!([{ "rule": "sql-a-ollama", "matched": true }]));
+
+        let body: Value = read_body_json(call_service(&app, explain("sk-support", messages(4))).await).await;
+        assert_eq!(body["matched_rule"], "soporte-largo");
+        assert_eq!(body["action"], json!({ "reject": "Conversación demasiado larga." }));
+        assert_eq!(body["final_pool"], Value::Null);
+        assert_eq!(body["evaluated"][0]["matched"], false);
+        assert!(body["evaluated"][0]["reason"].as_str().unwrap().contains("$.messages[0].content"), "{}", body);
+
+        let body: Value = read_body_json(call_service(&app, explain("sk-research", messages(4))).await).await;
+        assert_eq!(body["matched_rule"], Value::Null);
+        assert_eq!(body["final_pool"], "lmstudio");
+        assert_eq!(body["evaluated"][1]["reason"], "la API key no es 'support-bot'");
+
+        // El explicador no manda nada a los nodos.
+        assert_eq!((lmstudio_hits.load(Ordering::SeqCst), ollama_hits.load(Ordering::SeqCst)), (0, 0));
+    }
+
+    /// Escribe el archivo con una fecha de modificación posterior, para que `watch` vea el cambio
+    /// aunque caiga en el mismo instante que la versión anterior.
+    fn rewrite(path: &Path, contents: &str, seconds_later: u64) {
+        std::fs::write(path, contents).unwrap();
+        let file = std::fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(seconds_later)).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn edited_rules_are_reloaded_and_broken_edits_ignored() {
+        tokio::time::pause();
+        let path = testing::temp_file("rules.toml", RULES);
+        let state = testing::state(&["--rules-file", path.to_str().unwrap()]);
+        let (mut events, _guard) = events::admit(state.clone()).unwrap();
+        let names = |state: &AppState| state.routing_rules.read().unwrap().rules().iter().map(|rule| rule.name.clone()).collect::<Vec<_>>();
+        tokio::spawn(watch(state.clone()));
+        tokio::task::yield_now().await;
+
+        rewrite(&path, "[[rules]]\nname = \"nueva\"\nmatch = { model = \"llama*\" }\naction = { route = \"ollama\" }\n", 10);
+        tokio::time::sleep(RELOAD_CHECK_INTERVAL + Duration::from_secs(1)).await;
+        assert_eq!(names(&state), ["nueva"]);
+        let mut reloaded = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            if let BalancerEvent::RulesReloaded { rules } = *event {
+                reloaded.push(rules);
+            }
+        }
+        assert_eq!(reloaded, [1]);
+
+        // Una edición inválida se ignora y no se reintenta hasta el siguiente cambio.
+        rewrite(&path, "[[rules]]\nname = \"rota\"\naction = { route = \"vllm\" }\n", 20);
+        tokio::time::sleep(RELOAD_CHECK_INTERVAL * 3).await;
+        assert_eq!(names(&state), ["nueva"]);
+        assert!(std::iter::from_fn(|| events.try_recv().ok()).all(|event| !matches!(*event, BalancerEvent::RulesReloaded { .. })));
+
+        rewrite(&path, RULES, 30);
+        tokio::time::sleep(RELOAD_CHECK_INTERVAL + Duration::from_secs(1)).await;
+        assert_eq!(names(&state), ["sql-a-ollama", "soporte-largo"]);
+    }
+}
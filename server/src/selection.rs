@@ -0,0 +1,508 @@
+// src/selection.rs
+//! Criterios de elección de nodo (`--node-selection`), detrás del trait `NodeSelector`.
+//!
+//! `AppState::find_and_occupy_node` pide al criterio el orden en que probar los nodos de la pool
+//! y se queda con el primero que puede atender la petición: libre, con el modelo y la capacidad
+//! pedidos y con ficha de despacho. Esas comprobaciones (y la reserva interactiva, el
+//! intercalado, la preferencia de plataforma de las reglas o la sesión fijada) no dependen del
+//! criterio y siguen en el balanceador; un criterio sólo ordena. Ve los nodos de la pool con
+//! todo lo que se sabe de ellos (estado, peso, latencia, plataforma, peticiones en curso) en
+//! `SelectionContext`, así que uno nuevo se añade aquí sin tocar los handlers.
+//!
+//! Criterios incluidos:
+//!
+//! - `round-robin`: por turnos desde el último elegido (`round_robin`).
+//! - `least-connections`: el de menos peticiones en curso (`NodeInfo::in_flight`); a igualdad,
+//!   por turnos. Mientras cada nodo tenga una sola plaza (Busy/Available), todos los libres
+//!   tienen 0 y el resultado es el mismo que por turnos.
+//! - `weighted`: en proporción al peso que anuncia cada nodo (`WEIGHT`), con el turno
+//!   rotatorio suavizado de `round_robin`.
+//! - `latency`: de menor a mayor latencia reciente (`latency`).
+//! - `first-available`: siempre el primer nodo libre por orden de ID. Determinista: pensado
+//!   para pruebas y para pools donde un nodo debe llevarse todo lo que pueda.
+//! - `random`: un nodo libre al azar.
+//!
+//! Con `--node-anti-affinity`, cualquiera de ellos pasa el último nodo elegido en la pool al final
+//! del orden: con tráfico a ráfagas, el nodo que acaba de terminar vuelve a Available justo antes
+//! de la siguiente petición y, sin esto, se la lleva aunque haya otro libre. Si es el único
+//! libre, se elige igual.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::balancer::{NodeHealth, NodeInfo};
+use crate::ids::NodeId;
+use crate::latency::LatencyPolicy;
+use crate::round_robin::RoundRobin;
+
+/// Criterio para elegir entre los nodos libres de una pool (`--node-selection`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum NodeSelection {
+    /// Por turnos desde el último elegido.
+    RoundRobin,
+    /// El de menos peticiones en curso; a igualdad, por turnos.
+    LeastConnections,
+    /// Proporcional al peso que anuncia cada nodo; a igualdad, por turnos.
+    Weighted,
+    /// El de menor latencia reciente; a igualdad, y en una fracción de las elecciones, por turnos.
+    Latency,
+    /// El primer nodo libre por orden de ID.
+    FirstAvailable,
+    /// Un nodo libre al azar.
+    Random,
+}
+
+/// Lo que ve un criterio al elegir nodo.
+pub struct SelectionContext<'a> {
+    pub service: &'a str,
+    /// Todos los nodos de la pool, en cualquier estado.
+    pub nodes: &'a HashMap<NodeId, NodeInfo>,
+}
+
+pub trait NodeSelector: Send + Sync {
+    /// Nombre del criterio en `/config`.
+    fn label(&self) -> &'static str;
+
+    /// Nodos en el orden en que hay que probarlos; se elige el primero que puede atender la
+    /// petición. Puede dejar fuera los que nunca elegiría.
+    fn order(&self, ctx: &SelectionContext) -> Vec<NodeId>;
+
+    /// Aviso de la elección de `chosen`, con el lock de la pool aún tomado y antes de ocuparlo.
+    fn chosen(&self, _ctx: &SelectionContext, _chosen: &NodeId) {}
+
+    /// Si el reparto esperado es proporcional al peso de cada nodo, para medir el sesgo (`fairness`).
+    fn weighted(&self) -> bool {
+        false
+    }
+
+    /// Si el último nodo elegido en la pool se deja para el final (`--node-anti-affinity`).
+    fn avoids_last(&self) -> bool {
+        false
+    }
+}
+
+/// Generador xorshift64; no hace falta más para repartir al azar.
+pub struct Xorshift {
+    state: AtomicU64,
+}
+
+impl Default for Xorshift {
+    fn default() -> Self {
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).map_or(1, |elapsed| elapsed.as_nanos() as u64) | 1;
+        Self { state: AtomicU64::new(seed) }
+    }
+}
+
+impl Xorshift {
+    pub fn next_u64(&self) -> u64 {
+        let mut x = self.state.load(Ordering::Relaxed);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state.store(x, Ordering::Relaxed);
+        x
+    }
+
+    /// Número uniforme en [0, 1).
+    pub fn next_f64(&self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[derive(Default)]
+struct RoundRobinSelector {
+    turns: RoundRobin,
+}
+
+impl NodeSelector for RoundRobinSelector {
+    fn label(&self) -> &'static str {
+        "round_robin"
+    }
+
+    fn order(&self, ctx: &SelectionContext) -> Vec<NodeId> {
+        self.turns.order(ctx.service, ctx.nodes.keys())
+    }
+
+    fn chosen(&self, ctx: &SelectionContext, chosen: &NodeId) {
+        self.turns.advance(ctx.service, chosen);
+    }
+}
+
+#[derive(Default)]
+struct LeastConnectionsSelector {
+    turns: RoundRobin,
+}
+
+impl NodeSelector for LeastConnectionsSelector {
+    fn label(&self) -> &'static str {
+        "least_connections"
+    }
+
+    fn order(&self, ctx: &SelectionContext) -> Vec<NodeId> {
+        let mut order = self.turns.order(ctx.service, ctx.nodes.keys());
+        // Orden estable: entre nodos con las mismas peticiones en curso sigue mandando el turno.
+        order.sort_by_key(|unique_id| ctx.nodes.get(unique_id).map_or(0, NodeInfo::in_flight));
+        order
+    }
+
+    fn chosen(&self, ctx: &SelectionContext, chosen: &NodeId) {
+        self.turns.advance(ctx.service, chosen);
+    }
+}
+
+#[derive(Default)]
+struct WeightedSelector {
+    turns: RoundRobin,
+}
+
+impl WeightedSelector {
+    /// Candidatos `(ID, peso)`: los nodos libres.
+    fn candidates(ctx: &SelectionContext) -> Vec<(NodeId, u32)> {
+        ctx.nodes
+            .iter()
+            .filter(|(_, info)| matches!(info.state, NodeHealth::Available))
+            .map(|(unique_id, info)| (unique_id.clone(), info.weight))
+            .collect()
+    }
+}
+
+impl NodeSelector for WeightedSelector {
+    fn label(&self) -> &'static str {
+        "weighted"
+    }
+
+    fn order(&self, ctx: &SelectionContext) -> Vec<NodeId> {
+        self.turns.weighted_order(ctx.service, &Self::candidates(ctx))
+    }
+
+    fn chosen(&self, ctx: &SelectionContext, chosen: &NodeId) {
+        self.turns.advance(ctx.service, chosen);
+        self.turns.charge(ctx.service, &Self::candidates(ctx), chosen, |unique_id| ctx.nodes.contains_key(unique_id));
+    }
+
+    fn weighted(&self) -> bool {
+        true
+    }
+}
+
+struct LatencySelector {
+    turns: RoundRobin,
+    policy: Arc<LatencyPolicy>,
+}
+
+impl NodeSelector for LatencySelector {
+    fn label(&self) -> &'static str {
+        "latency"
+    }
+
+    fn order(&self, ctx: &SelectionContext) -> Vec<NodeId> {
+        let mut order = self.turns.order(ctx.service, ctx.nodes.keys());
+        if !self.policy.explores() {
+            // Orden estable: a igual media sigue mandando el turno. Sin media, primero.
+            let latency = |unique_id: &NodeId| ctx.nodes.get(unique_id).and_then(|info| info.latency.ms()).unwrap_or(0.0);
+            order.sort_by(|a, b| latency(a).total_cmp(&latency(b)));
+        }
+        order
+    }
+
+    fn chosen(&self, ctx: &SelectionContext, chosen: &NodeId) {
+        self.turns.advance(ctx.service, chosen);
+    }
+}
+
+struct FirstAvailableSelector;
+
+impl NodeSelector for FirstAvailableSelector {
+    fn label(&self) -> &'static str {
+        "first_available"
+    }
+
+    fn order(&self, ctx: &SelectionContext) -> Vec<NodeId> {
+        let mut order: Vec<NodeId> = ctx.nodes.keys().cloned().collect();
+        order.sort_unstable();
+        order
+    }
+}
+
+#[derive(Default)]
+struct RandomSelector {
+    rng: Xorshift,
+}
+
+impl NodeSelector for RandomSelector {
+    fn label(&self) -> &'static str {
+        "random"
+    }
+
+    fn order(&self, ctx: &SelectionContext) -> Vec<NodeId> {
+        // Se parte del orden por ID para que el resultado dependa sólo del generador.
+        let mut order: Vec<NodeId> = ctx.nodes.keys().cloned().collect();
+        order.sort_unstable();
+        for i in (1..order.len()).rev() {
+            order.swap(i, (self.rng.next_u64() % (i as u64 + 1)) as usize);
+        }
+        order
+    }
+}
+
+/// Capa sobre otro criterio que deja para el final el último nodo elegido en cada pool.
+struct AntiAffinitySelector {
+    inner: Box<dyn NodeSelector>,
+    /// Último nodo elegido, por pool.
+    last: Mutex<HashMap<String, NodeId>>,
+}
+
+impl NodeSelector for AntiAffinitySelector {
+    fn label(&self) -> &'static str {
+        self.inner.label()
+    }
+
+    fn order(&self, ctx: &SelectionContext) -> Vec<NodeId> {
+        let mut order = self.inner.order(ctx);
+        if let Some(last) = self.last.lock().unwrap().get(ctx.service) {
+            // Orden estable: el resto sigue en el orden del criterio.
+            order.sort_by_key(|unique_id| unique_id == last);
+        }
+        order
+    }
+
+    fn chosen(&self, ctx: &SelectionContext, chosen: &NodeId) {
+        self.inner.chosen(ctx, chosen);
+        self.last.lock().unwrap().insert(ctx.service.to_string(), chosen.clone());
+    }
+
+    fn weighted(&self) -> bool {
+        self.inner.weighted()
+    }
+
+    fn avoids_last(&self) -> bool {
+        true
+    }
+}
+
+/// Criterio de `--node-selection`. `latency` es la política de la que sale la media de cada nodo.
+pub fn build(selection: NodeSelection, latency: Arc<LatencyPolicy>, anti_affinity: bool) -> Box<dyn NodeSelector> {
+    let selector = select(selection, latency);
+    if anti_affinity {
+        Box::new(AntiAffinitySelector { inner: selector, last: Mutex::default() })
+    } else {
+        selector
+    }
+}
+
+fn select(selection: NodeSelection, latency: Arc<LatencyPolicy>) -> Box<dyn NodeSelector> {
+    match selection {
+        NodeSelection::RoundRobin => Box::<RoundRobinSelector>::default(),
+        NodeSelection::LeastConnections => Box::<LeastConnectionsSelector>::default(),
+        NodeSelection::Weighted => Box::<WeightedSelector>::default(),
+        NodeSelection::Latency => Box::new(LatencySelector { turns: RoundRobin::default(), policy: latency }),
+        NodeSelection::FirstAvailable => Box::new(FirstAvailableSelector),
+        NodeSelection::Random => Box::<RandomSelector>::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::web;
+    use serde_json::{json, Value};
+    use std::time::{Duration, Instant};
+
+    use crate::balancer::{self, build_state, AppState, LMSTUDIO};
+    use crate::history::TransitionCause;
+    use crate::testing;
+
+    fn seeded(seed: u64) -> Xorshift {
+        Xorshift { state: AtomicU64::new(seed) }
+    }
+
+    fn ids(names: &[&str]) -> Vec<NodeId> {
+        names.iter().map(|name| testing::node_id(name)).collect()
+    }
+
+    #[test]
+    fn xorshift_is_reproducible_from_its_seed() {
+        let (a, b) = (seeded(42), seeded(42));
+        let draws: Vec<u64> = (0..5).map(|_| a.next_u64()).collect();
+        assert_eq!(draws, (0..5).map(|_| b.next_u64()).collect::<Vec<_>>());
+        assert_ne!(draws, (0..5).map(|_| seeded(43).next_u64()).collect::<Vec<_>>());
+        assert!((0..1_000).map(|_| a.next_f64()).all(|x| (0.0..1.0).contains(&x)));
+    }
+
+    /// Registro con estos nodos, para armar un `SelectionContext` con los `NodeInfo` de verdad.
+    fn pool(names: &[&str]) -> web::Data<AppState> {
+        let state = testing::state(&[]);
+        for name in names {
+            testing::announce(&state, "lmstudio", name, "http://127.0.0.1:1/");
+        }
+        state
+    }
+
+    fn order(selector: &dyn NodeSelector, state: &AppState) -> Vec<NodeId> {
+        let nodes = state.pool("lmstudio").unwrap().read().unwrap();
+        let ctx = SelectionContext { service: "lmstudio", nodes: &nodes };
+        selector.order(&ctx)
+    }
+
+    fn choose(selector: &dyn NodeSelector, state: &AppState, chosen: &str) {
+        let nodes = state.pool("lmstudio").unwrap().read().unwrap();
+        let ctx = SelectionContext { service: "lmstudio", nodes: &nodes };
+        selector.chosen(&ctx, &testing::node_id(chosen));
+    }
+
+    #[test]
+    fn seeded_random_orders_are_reproducible_and_cover_every_node() {
+        let state = pool(&["c", "a", "b"]);
+        let (one, two) = (RandomSelector { rng: seeded(7) }, RandomSelector { rng: seeded(7) });
+        let mut firsts = HashMap::new();
+        for _ in 0..3_000 {
+            let order = order(&one, &state);
+            assert_eq!(order, self::order(&two, &state));
+            let mut sorted = order.clone();
+            sorted.sort();
+            assert_eq!(sorted, ids(&["a", "b", "c"]));
+            *firsts.entry(order[0].clone()).or_insert(0) += 1;
+        }
+        // Cada nodo sale primero una tercera parte de las veces, más o menos.
+        assert_eq!(firsts.len(), 3);
+        assert!(firsts.values().all(|count| (850..=1_150).contains(count)), "{:?}", firsts);
+    }
+
+    #[test]
+    fn first_available_and_anti_affinity_are_deterministic() {
+        let state = pool(&["c", "a", "b"]);
+        let first = build(NodeSelection::FirstAvailable, Arc::new(LatencyPolicy::new(0.3, std::time::Duration::ZERO, 0.0).unwrap()), false);
+        assert_eq!(first.label(), "first_available");
+        choose(first.as_ref(), &state, "a");
+        assert_eq!(order(first.as_ref(), &state), ids(&["a", "b", "c"]));
+
+        let spread = build(NodeSelection::FirstAvailable, Arc::new(LatencyPolicy::new(0.3, std::time::Duration::ZERO, 0.0).unwrap()), true);
+        assert!(spread.avoids_last());
+        choose(spread.as_ref(), &state, "a");
+        assert_eq!(order(spread.as_ref(), &state), ids(&["b", "c", "a"]));
+        choose(spread.as_ref(), &state, "b");
+        assert_eq!(order(spread.as_ref(), &state), ids(&["a", "c", "b"]));
+    }
+
+    /// Lo que ve el criterio en cada elección: nodos de la pool y elegido.
+    type Seen = Vec<(usize, NodeId)>;
+
+    /// Criterio de prueba: prueba los nodos en el orden fijado y apunta lo que ve.
+    struct Scripted {
+        order: Vec<NodeId>,
+        seen: Arc<Mutex<Seen>>,
+    }
+
+    impl NodeSelector for Scripted {
+        fn label(&self) -> &'static str {
+            "scripted"
+        }
+
+        fn order(&self, _ctx: &SelectionContext) -> Vec<NodeId> {
+            self.order.clone()
+        }
+
+        fn chosen(&self, ctx: &SelectionContext, chosen: &NodeId) {
+            self.seen.lock().unwrap().push((ctx.nodes.len(), chosen.clone()));
+        }
+    }
+
+    #[actix_web::test]
+    async fn requests_follow_an_injected_selector() {
+        let mut state = build_state(&testing::config(&[]), None).unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        state.selector = Box::new(Scripted { order: ids(&["box2", "box0", "box1"]), seen: seen.clone() });
+        let state = web::Data::new(state);
+        let url = testing::chat_node(Duration::ZERO);
+        for id in ["box0", "box1", "box2"] {
+            testing::announce(&state, "lmstudio", id, &url);
+        }
+        let app = init_service(balancer::app(state.clone())).await;
+        let served_by = || {
+            let app = &app;
+            async move {
+                let res = call_service(app, testing::chat().to_request()).await;
+                assert_eq!(res.status(), 200);
+                res.headers().get("X-LMServer-Node-Id").unwrap().to_str().unwrap().to_string()
+            }
+        };
+
+        assert_eq!(served_by().await, "box2");
+        assert_eq!(served_by().await, "box2");
+        // El balanceador sólo salta los que no pueden atender: caídos u ocupados.
+        state.update_node_state(&LMSTUDIO, &testing::node_id("box2"), NodeHealth::Failed(Instant::now()), TransitionCause::HealthCheck);
+        assert_eq!(served_by().await, "box0");
+        state.update_node_state(&LMSTUDIO, &testing::node_id("box0"), NodeHealth::Busy, TransitionCause::Admin);
+        assert_eq!(served_by().await, "box1");
+
+        let seen = seen.lock().unwrap().clone();
+        let expected: Seen = ["box2", "box2", "box0", "box1"].iter().map(|id| (3, testing::node_id(id))).collect();
+        assert_eq!(seen, expected);
+        let config: Value = read_body_json(call_service(&app, TestRequest::get().uri("/config").to_request()).await).await;
+        assert_eq!(config["node_selection"], "scripted", "{}", config);
+    }
+
+    #[actix_web::test]
+    async fn seeded_random_selection_is_reproducible_over_http() {
+        let url = testing::chat_node(Duration::ZERO);
+        let run = || async {
+            let mut state = build_state(&testing::config(&["--node-selection", "random"]), None).unwrap();
+            state.selector = Box::new(RandomSelector { rng: seeded(2024) });
+            let state = web::Data::new(state);
+            for id in ["box0", "box1", "box2", "box3"] {
+                testing::announce(&state, "lmstudio", id, &url);
+            }
+            let app = init_service(balancer::app(state)).await;
+            let mut served = Vec::new();
+            for _ in 0..12 {
+                let res = call_service(&app, testing::chat_with(json!({ "model": "m" })).to_request()).await;
+                served.push(res.headers().get("X-LMServer-Node-Id").unwrap().to_str().unwrap().to_string());
+            }
+            served
+        };
+        let served = run().await;
+        assert_eq!(served, run().await);
+        // Con la misma semilla, el primero de cada orden es el que sale.
+        let rng = RandomSelector { rng: seeded(2024) };
+        let state = pool(&["box0", "box1", "box2", "box3"]);
+        let expected: Vec<String> = (0..12).map(|_| order(&rng, &state)[0].to_string()).collect();
+        assert_eq!(served, expected);
+    }
+
+    /// Nodos que atienden `count` peticiones seguidas, cada una al terminar la anterior.
+    async fn served_in_a_burst(state: &web::Data<AppState>, count: usize) -> Vec<String> {
+        let app = init_service(balancer::app(state.clone())).await;
+        let mut served = Vec::new();
+        for _ in 0..count {
+            let res = call_service(&app, testing::chat_with(json!({ "model": "m" })).to_request()).await;
+            assert_eq!(res.status(), 200);
+            served.push(res.headers().get("X-LMServer-Node-Id").unwrap().to_str().unwrap().to_string());
+        }
+        served
+    }
+
+    #[actix_web::test]
+    async fn two_nodes_alternate_with_anti_affinity() {
+        let url = testing::chat_node(Duration::ZERO);
+        let two_nodes = |args: &[&str]| {
+            let state = testing::state(args);
+            for id in ["box0", "box1"] {
+                testing::announce(&state, "lmstudio", id, &url);
+            }
+            state
+        };
+
+        // Sin la capa, el nodo que acaba de quedar libre se lleva la siguiente.
+        assert_eq!(served_in_a_burst(&two_nodes(&["--node-selection", "first-available"]), 4).await, ["box0"; 4]);
+
+        let state = two_nodes(&["--node-selection", "first-available", "--node-anti-affinity"]);
+        assert_eq!(served_in_a_burst(&state, 6).await, ["box0", "box1", "box0", "box1", "box0", "box1"]);
+        // Si es el único libre, se repite.
+        state.update_node_state(&LMSTUDIO, &testing::node_id("box0"), NodeHealth::Failed(Instant::now()), TransitionCause::HealthCheck);
+        assert_eq!(served_in_a_burst(&state, 3).await, ["box1"; 3]);
+        state.update_node_state(&LMSTUDIO, &testing::node_id("box0"), NodeHealth::Available, TransitionCause::HealthCheck);
+        assert_eq!(served_in_a_burst(&state, 2).await, ["box0", "box1"]);
+    }
+}
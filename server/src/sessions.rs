@@ -0,0 +1,249 @@
+// src/sessions.rs
+//! Sesiones fijas (`X-Session-Id: <id>`): las peticiones seguidas de una conversación van al
+//! mismo nodo, que ya tiene su prefijo en la caché KV.
+//!
+//! La primera petición de una sesión se enruta como cualquier otra y deja la sesión fijada al
+//! nodo que la atendió. Las siguientes esperan a ese nodo en la cola, aunque haya otros libres,
+//! hasta el tiempo de espera de siempre. Si el nodo ya no puede atenderla (caído, drenándose,
+//! fuera del registro o sin el modelo o la capacidad que pide), la petición se enruta de forma
+//! normal y la sesión pasa al nodo nuevo. Una sesión sin peticiones durante `--session-ttl-secs`
+//! caduca; la tarea de limpieza la purga.
+//!
+//! Como en `pipeline`, la sesión se guarda junto a la API key: otro cliente con el mismo ID
+//! tiene su propia sesión. La respuesta lleva el nodo que la atendió en `X-LMServer-Node-Id`.
+use actix_web::HttpRequest;
+use log::debug;
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::ids::NodeId;
+use crate::limits::StoreUsage;
+
+pub const SESSION_HEADER: &str = "X-Session-Id";
+
+/// Nodo que atendió la petición, en toda respuesta reenviada desde un nodo.
+pub const NODE_ID_HEADER: &str = "X-LMServer-Node-Id";
+
+pub const DEFAULT_SESSION_TTL_SECS: u64 = 1800;
+
+/// Clave de una sesión: API key e ID de sesión.
+type SessionKey = (String, String);
+
+struct Pin {
+    service: String,
+    unique_node_id: NodeId,
+    last_used: Instant,
+}
+
+/// Sesión de la petición, en el ámbito de su API key.
+pub struct Session {
+    key: SessionKey,
+}
+
+impl Session {
+    pub fn from_request(req: &HttpRequest, api_key: Option<&str>) -> Option<Self> {
+        let id = req.headers().get(SESSION_HEADER)?.to_str().ok()?.trim();
+        if id.is_empty() {
+            return None;
+        }
+        Some(Self { key: (api_key.unwrap_or_default().to_string(), id.to_string()) })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.key.1
+    }
+}
+
+pub struct SessionAffinity {
+    ttl: Duration,
+    /// Sesiones fijadas. Lleno, una sesión nueva sustituye a la usada hace más tiempo.
+    max_sessions: usize,
+    entries: Mutex<HashMap<SessionKey, Pin>>,
+}
+
+impl SessionAffinity {
+    pub fn new(ttl: Duration, max_sessions: usize) -> Self {
+        Self { ttl, max_sessions, entries: Mutex::new(HashMap::new()) }
+    }
+
+    /// Con `--session-ttl-secs 0` se ignora `X-Session-Id`.
+    pub fn is_enabled(&self) -> bool {
+        !self.ttl.is_zero()
+    }
+
+    /// Pool y nodo al que está fijada la sesión, si no ha caducado.
+    pub fn pinned(&self, session: &Session) -> Option<(String, NodeId)> {
+        let entries = self.entries.lock().unwrap();
+        let pin = entries.get(&session.key).filter(|pin| pin.last_used.elapsed() <= self.ttl)?;
+        Some((pin.service.clone(), pin.unique_node_id.clone()))
+    }
+
+    /// Fija la sesión al nodo que atiende su petición y renueva su caducidad.
+    pub fn pin(&self, session: &Session, service: &str, unique_node_id: &NodeId) {
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.contains_key(&session.key) && entries.len() >= self.max_sessions {
+            let oldest = entries.iter().min_by_key(|(_, pin)| pin.last_used).map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        let pin = Pin { service: service.to_string(), unique_node_id: unique_node_id.clone(), last_used: Instant::now() };
+        entries.insert(session.key.clone(), pin);
+    }
+
+    /// Borra las sesiones caducadas.
+    pub fn purge(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        let before = entries.len();
+        entries.retain(|_, pin| pin.last_used.elapsed() <= self.ttl);
+        let purged = before - entries.len();
+        if purged > 0 {
+            debug!("Sesiones: {} sesiones caducadas tras {}s sin peticiones.", purged, self.ttl.as_secs());
+        }
+    }
+
+    pub fn active(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn usage(&self) -> StoreUsage {
+        let entries = self.entries.lock().unwrap();
+        let bytes = entries
+            .iter()
+            .map(|((api_key, id), pin)| size_of::<(SessionKey, Pin)>() + api_key.len() + id.len() + pin.service.len() + pin.unique_node_id.len())
+            .sum();
+        StoreUsage { entries: entries.len(), bytes }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use futures_util::future::join_all;
+
+    use crate::balancer::{self, NodeHealth, LMSTUDIO};
+    use crate::history::TransitionCause;
+    use crate::testing;
+
+    fn session(id: &str, api_key: Option<&str>) -> Option<Session> {
+        Session::from_request(&TestRequest::default().insert_header((SESSION_HEADER, id)).to_http_request(), api_key)
+    }
+
+    #[test]
+    fn sessions_are_trimmed_and_scoped_by_api_key() {
+        assert!(session("   ", None).is_none());
+        assert!(Session::from_request(&TestRequest::default().to_http_request(), None).is_none());
+        assert_eq!(session(" s1 ", None).unwrap().id(), "s1");
+
+        let affinity = SessionAffinity::new(Duration::from_secs(60), 10);
+        affinity.pin(&session("s1", Some("ana")).unwrap(), "lmstudio", &testing::node_id("box1"));
+        assert_eq!(affinity.pinned(&session("s1", Some("ana")).unwrap()), Some(("lmstudio".to_string(), testing::node_id("box1"))));
+        assert_eq!(affinity.pinned(&session("s1", Some("luis")).unwrap()), None);
+        assert_eq!(affinity.pinned(&session("s1", None).unwrap()), None);
+        // Fijarla otra vez la mueve de nodo.
+        affinity.pin(&session("s1", Some("ana")).unwrap(), "lmstudio", &testing::node_id("box2"));
+        assert_eq!(affinity.pinned(&session("s1", Some("ana")).unwrap()).unwrap().1, testing::node_id("box2"));
+        assert_eq!(affinity.active(), 1);
+    }
+
+    #[test]
+    fn idle_sessions_expire_and_are_purged() {
+        let affinity = SessionAffinity::new(Duration::from_millis(50), 10);
+        let (idle, busy) = (session("idle", None).unwrap(), session("busy", None).unwrap());
+        affinity.pin(&idle, "lmstudio", &testing::node_id("box1"));
+        affinity.pin(&busy, "lmstudio", &testing::node_id("box1"));
+        std::thread::sleep(Duration::from_millis(30));
+        // Cada petición renueva la caducidad.
+        affinity.pin(&busy, "lmstudio", &testing::node_id("box1"));
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(affinity.pinned(&idle).is_none());
+        assert!(affinity.pinned(&busy).is_some());
+        assert_eq!(affinity.active(), 2);
+        affinity.purge();
+        assert_eq!(affinity.active(), 1);
+        assert!(!SessionAffinity::new(Duration::ZERO, 10).is_enabled());
+    }
+
+    fn chat(session: Option<&str>) -> TestRequest {
+        match session {
+            Some(session) => testing::chat().insert_header((SESSION_HEADER, session)),
+            None => testing::chat(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn a_session_sticks_to_its_node_until_the_node_fails() {
+        let state = testing::state(&[]);
+        for id in ["box1", "box2"] {
+            testing::announce(&state, "lmstudio", id, &testing::chat_node(Duration::ZERO));
+        }
+        let app = init_service(balancer::app(state.clone())).await;
+        let served_by = |session: Option<&'static str>| {
+            let app = &app;
+            async move {
+                let res = call_service(app, chat(session).to_request()).await;
+                assert_eq!(res.status(), 200);
+                res.headers().get(NODE_ID_HEADER).unwrap().to_str().unwrap().to_string()
+            }
+        };
+
+        let first = served_by(Some("s1")).await;
+        let other = if first == "box1" { "box2" } else { "box1" };
+        for _ in 0..4 {
+            // Sin sesión van por turnos; con ella, siempre al mismo.
+            served_by(None).await;
+            assert_eq!(served_by(Some("s1")).await, first);
+        }
+        assert_eq!(served_by(Some("s2")).await, other, "una sesión nueva se enruta como cualquier otra");
+
+        state.update_node_state(&LMSTUDIO, &testing::node_id(&first), NodeHealth::Failed(Instant::now()), TransitionCause::HealthCheck);
+        assert_eq!(served_by(Some("s1")).await, other);
+        // Al volver el nodo la sesión se queda en el nuevo.
+        state.update_node_state(&LMSTUDIO, &testing::node_id(&first), NodeHealth::Available, TransitionCause::HealthCheck);
+        assert_eq!(served_by(Some("s1")).await, other);
+
+        state.deregister_node(&LMSTUDIO, &testing::node_id(other));
+        assert_eq!(served_by(Some("s1")).await, first);
+        assert_eq!(state.sessions.active(), 2);
+    }
+
+    #[actix_web::test]
+    async fn a_session_waits_for_its_busy_node_instead_of_a_free_one() {
+        let state = testing::state(&[]);
+        for id in ["box1", "box2"] {
+            testing::announce(&state, "lmstudio", id, &testing::chat_node(Duration::from_millis(300)));
+        }
+        let app = init_service(balancer::app(state.clone())).await;
+        let res = call_service(&app, chat(Some("s1")).to_request()).await;
+        let pinned = res.headers().get(NODE_ID_HEADER).unwrap().to_str().unwrap().to_string();
+
+        let start = Instant::now();
+        let served = join_all((0..2).map(|_| async {
+            let res = call_service(&app, chat(Some("s1")).to_request()).await;
+            (res.headers().get(NODE_ID_HEADER).unwrap().to_str().unwrap().to_string(), start.elapsed())
+        }))
+        .await;
+        assert!(served.iter().all(|(node, _)| *node == pinned), "{:?}", served);
+        // Una detrás de otra en el mismo nodo, aunque el otro estaba libre.
+        assert!(served.iter().any(|(_, at)| *at >= Duration::from_millis(600)), "{:?}", served);
+    }
+
+    #[actix_web::test]
+    async fn zero_ttl_ignores_the_header() {
+        let state = testing::state(&["--session-ttl-secs", "0"]);
+        for id in ["box1", "box2"] {
+            testing::announce(&state, "lmstudio", id, &testing::chat_node(Duration::ZERO));
+        }
+        let app = init_service(balancer::app(state.clone())).await;
+        let mut served = Vec::new();
+        for _ in 0..4 {
+            let res = call_service(&app, chat(Some("s1")).to_request()).await;
+            served.push(res.headers().get(NODE_ID_HEADER).unwrap().to_str().unwrap().to_string());
+        }
+        assert_eq!(served, ["box1", "box2", "box1", "box2"]);
+        assert_eq!(state.sessions.active(), 0);
+    }
+}
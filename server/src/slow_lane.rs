@@ -0,0 +1,193 @@
+// src/slow_lane.rs
+//! Carril lento para las peticiones enormes (`--slow-lane-body-bytes`,
+//! `--slow-lane-prompt-tokens`).
+//!
+//! Un prompt de RAG de cientos de miles de caracteres retiene su nodo mucho tiempo y dispara la
+//! latencia de todos los demás. Una petición cuyo cuerpo, o cuyo prompt estimado
+//! (`context::estimate_prompt_tokens`), pasa de alguno de los umbrales es lenta y va a su carril:
+//!
+//! - con `--slow-lane-pool`, a esa pool, sin desbordar a otras (salvo que la key o una regla ya
+//!   fijen la pool, como con el reparto por coste);
+//! - con `--slow-lane-tag clave=valor`, sólo a los nodos con esas etiquetas (`tags`);
+//! - sin ninguno de los dos, por el enrutado normal, pero ocupando a la vez como mucho una
+//!   fracción `--slow-lane-max-share` de los nodos utilizables de cada pool (al menos uno).
+//!
+//! Las lentas no guardan turno en la cola de su modelo, así que el tráfico normal nunca espera
+//! detrás de una que no puede despachar. Tienen su propio tiempo de espera en cola
+//! (`--slow-lane-queue-timeout-secs`) y de respuesta del nodo (`--slow-lane-request-timeout-secs`).
+//! La clasificación, el carril y el umbral aplicado salen en el log de cada petición y en
+//! `POST /debug/route`.
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+use crate::balancer::{NodeHealth, NodeInfo};
+use crate::context;
+use crate::ids::NodeId;
+use crate::tags::{NodeTags, TagRequirement};
+
+pub const DEFAULT_SLOW_LANE_MAX_SHARE: f64 = 0.5;
+
+#[derive(Debug)]
+pub struct SlowLaneConfigError(String);
+
+impl fmt::Display for SlowLaneConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for SlowLaneConfigError {}
+
+/// Umbral que superó una petición lenta.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlowReason {
+    BodyBytes { size: usize, threshold: usize },
+    PromptTokens { estimated: u64, threshold: u64 },
+}
+
+impl fmt::Display for SlowReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SlowReason::BodyBytes { size, threshold } => write!(f, "cuerpo de {} bytes > {}", size, threshold),
+            SlowReason::PromptTokens { estimated, threshold } => write!(f, "prompt de ~{} tokens > {}", estimated, threshold),
+        }
+    }
+}
+
+impl SlowReason {
+    fn describe(&self) -> Value {
+        match self {
+            SlowReason::BodyBytes { size, threshold } => json!({ "threshold": "body_bytes", "value": size, "limit": threshold }),
+            SlowReason::PromptTokens { estimated, threshold } => json!({ "threshold": "prompt_tokens", "value": estimated, "limit": threshold }),
+        }
+    }
+}
+
+pub struct SlowLane {
+    body_bytes: Option<usize>,
+    prompt_tokens: Option<u64>,
+    pool: Option<String>,
+    tags: Option<TagRequirement>,
+    max_share: f64,
+    queue_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+}
+
+impl SlowLane {
+    pub fn new(
+        body_bytes: Option<usize>,
+        prompt_tokens: Option<u64>,
+        pool: Option<String>,
+        tags: NodeTags,
+        max_share: f64,
+        queue_timeout_secs: Option<u64>,
+        request_timeout_secs: Option<u64>,
+    ) -> Result<Self, SlowLaneConfigError> {
+        if !(max_share > 0.0 && max_share <= 1.0) {
+            return Err(SlowLaneConfigError(format!("--slow-lane-max-share debe estar en (0, 1], no {}", max_share)));
+        }
+        if body_bytes == Some(0) || prompt_tokens == Some(0) {
+            return Err(SlowLaneConfigError("Los umbrales del carril lento deben ser mayores que 0.".to_string()));
+        }
+        let lane = Self {
+            body_bytes,
+            prompt_tokens,
+            pool,
+            tags: (!tags.is_empty()).then(|| TagRequirement::new(tags)),
+            max_share,
+            queue_timeout: queue_timeout_secs.map(Duration::from_secs),
+            request_timeout: request_timeout_secs.map(Duration::from_secs),
+        };
+        if !lane.is_enabled() && (lane.pool.is_some() || lane.tags.is_some() || lane.queue_timeout.is_some() || lane.request_timeout.is_some()) {
+            return Err(SlowLaneConfigError(
+                "El carril lento necesita un umbral: --slow-lane-body-bytes o --slow-lane-prompt-tokens.".to_string(),
+            ));
+        }
+        Ok(lane)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.body_bytes.is_some() || self.prompt_tokens.is_some()
+    }
+
+    /// Umbral que supera el cuerpo, si es una petición lenta. El tamaño se mira antes que el
+    /// prompt, que exige interpretar el JSON.
+    pub fn classify(&self, body: &[u8]) -> Option<SlowReason> {
+        if let Some(threshold) = self.body_bytes.filter(|threshold| body.len() > *threshold) {
+            return Some(SlowReason::BodyBytes { size: body.len(), threshold });
+        }
+        let threshold = self.prompt_tokens?;
+        let request = serde_json::from_slice::<Value>(body).ok()?;
+        let estimated = context::estimate_prompt_tokens(&request);
+        (estimated > threshold).then_some(SlowReason::PromptTokens { estimated, threshold })
+    }
+
+    /// Pool del carril, si tiene una.
+    pub fn pool(&self) -> Option<&str> {
+        self.pool.as_deref()
+    }
+
+    /// Carril de una petición lenta, para el log y `/debug/route`.
+    pub fn lane_label(&self) -> String {
+        match (&self.pool, &self.tags) {
+            (Some(pool), Some(tags)) => format!("pool:{} tags:{}", pool, tags.label()),
+            (Some(pool), None) => format!("pool:{}", pool),
+            (None, Some(tags)) => format!("tags:{}", tags.label()),
+            (None, None) => format!("max_share:{}", self.max_share),
+        }
+    }
+
+    /// Si el nodo puede atender una petición lenta: con `--slow-lane-tag`, sólo los del carril.
+    pub fn node_admits(&self, info: &NodeInfo) -> bool {
+        self.tags.as_ref().is_none_or(|tags| tags.matches(&info.tags))
+    }
+
+    /// Si una petición lenta puede ocupar otro nodo de la pool. Sólo limita sin carril propio.
+    /// Se llama con el lock del registro tomado.
+    pub fn admits(&self, nodes: &HashMap<NodeId, NodeInfo>) -> bool {
+        if self.pool.is_some() || self.tags.is_some() {
+            return true;
+        }
+        let usable = nodes.values().filter(|info| !matches!(info.state, NodeHealth::Failed(_) | NodeHealth::Draining(_))).count();
+        let allowed = ((usable as f64 * self.max_share).floor() as usize).max(1);
+        let busy = nodes.values().filter(|info| matches!(info.state, NodeHealth::Busy) && info.slow).count();
+        busy < allowed
+    }
+
+    pub fn queue_timeout(&self, normal: Duration) -> Duration {
+        self.queue_timeout.unwrap_or(normal)
+    }
+
+    pub fn request_timeout(&self) -> Option<Duration> {
+        self.request_timeout
+    }
+
+    /// Clasificación de una petición para `/debug/route`.
+    pub fn explain(&self, body: &[u8]) -> Value {
+        if !self.is_enabled() {
+            return json!({ "enabled": false });
+        }
+        let reason = self.classify(body);
+        json!({
+            "enabled": true,
+            "classification": if reason.is_some() { "slow" } else { "normal" },
+            "reason": reason.map(|reason| reason.describe()),
+            "lane": reason.map(|_| self.lane_label()),
+            "thresholds": { "body_bytes": self.body_bytes, "prompt_tokens": self.prompt_tokens },
+        })
+    }
+
+    /// Configuración para `/config`.
+    pub fn describe(&self) -> Value {
+        json!({
+            "enabled": self.is_enabled(),
+            "body_bytes": self.body_bytes,
+            "prompt_tokens": self.prompt_tokens,
+            "lane": self.is_enabled().then(|| self.lane_label()),
+            "queue_timeout_secs": self.queue_timeout.map(|timeout| timeout.as_secs()),
+            "request_timeout_secs": self.request_timeout.map(|timeout| timeout.as_secs()),
+        })
+    }
+}
@@ -0,0 +1,247 @@
+// src/spill.rs
+//! Reparto entre pools por coste: se prefiere la capacidad barata y sólo se desborda a una
+//! pool más cara bajo presión.
+//!
+//! Las pools con `--pool-cost` forman una cadena ordenada de menor a mayor coste. Una petición
+//! a cualquiera de ellas entra por la más barata y pasa a la siguiente si en `--spill-delay-ms`
+//! no encuentra nodo, o en cuanto la cola de la pool actual supera `--spill-queue-depth`. Las
+//! pools más baratas se siguen probando mientras espera, así que un nodo barato que se libere
+//! gana al caro. Una petición enrutada por una regla o por una key con `pool` no se desborda.
+//!
+//! Aparte de la cadena, `--pool-spillover <desde>=<hacia>` desborda en un solo sentido y sin
+//! coste: una petición a `desde` que en `--pool-spillover-delay-ms` no encuentra nodo (p.ej.
+//! porque la pool está vacía) prueba también `hacia`, sin dejar de probar `desde`. Pensado
+//! para nodos que sirven la API de OpenAI sea cual sea su backend: si el nodo de `hacia`
+//! anunció una ruta nativa de Ollama (`/api/...`), la petición va a `/v1/chat/completions` de
+//! ese mismo backend. Una pool con `--pool-cost` reparte por su cadena y no admite
+//! `--pool-spillover`. Está desactivado por defecto.
+//!
+//! Las comprobaciones previas al reparto (ventana de contexto, topes de streams) se hacen
+//! contra la pool de entrada; las cabeceras reenviadas siguen la whitelist de la pool final.
+//! La respuesta lleva la pool que la atendió en `X-LMServer-Pool`.
+use serde_json::json;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::ids::ServiceUrl;
+
+pub const DEFAULT_SPILL_DELAY_MS: u64 = 500;
+pub const DEFAULT_SPILL_QUEUE_DEPTH: u64 = 4;
+pub const DEFAULT_SPILLOVER_DELAY_MS: u64 = 2000;
+
+/// Pool que atendió la petición, en toda respuesta reenviada desde un nodo.
+pub const SERVED_POOL_HEADER: &str = "X-LMServer-Pool";
+
+/// Ruta compatible con OpenAI a la que va una petición desbordada con `--pool-spillover`.
+pub const OPENAI_CHAT_PATH: &str = "/v1/chat/completions";
+
+/// Ventana de la tasa de desbordes de `/stats/summary`, en minutos.
+const RATE_WINDOW_MINUTES: usize = 60;
+
+#[derive(Debug)]
+pub struct SpillConfigError(String);
+
+impl fmt::Display for SpillConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for SpillConfigError {}
+
+/// Por qué una petición pasó a una pool más cara.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpillReason {
+    /// La pool no tuvo nodo libre en `--spill-delay-ms`.
+    Delay,
+    /// La cola de la pool superó `--spill-queue-depth`.
+    QueueDepth,
+}
+
+impl SpillReason {
+    pub fn label(&self) -> &'static str {
+        match self {
+            SpillReason::Delay => "delay",
+            SpillReason::QueueDepth => "queue_depth",
+        }
+    }
+}
+
+/// URL a la que va una petición desbordada con `--pool-spillover`: la del nodo o, si anunció una
+/// ruta nativa de Ollama, `/v1/chat/completions` del mismo backend.
+pub fn spillover_url(service_url: &ServiceUrl) -> ServiceUrl {
+    if !service_url.path().starts_with("/api/") {
+        return service_url.clone();
+    }
+    ServiceUrl::parse(service_url.endpoint(OPENAI_CHAT_PATH).to_string()).unwrap_or_else(|_| service_url.clone())
+}
+
+/// Peticiones repartidas por la cadena y desbordadas, en un minuto.
+#[derive(Clone, Copy, Default)]
+struct MinuteCount {
+    minute: u64,
+    routed: u64,
+    spilled: u64,
+}
+
+pub struct SpillPolicy {
+    /// Pools con coste, de la más barata a la más cara.
+    chain: Vec<(String, f64)>,
+    delay: Duration,
+    queue_depth: u64,
+    started_at: Instant,
+    /// Cuentas de la última hora, un hueco por minuto.
+    minutes: Mutex<[MinuteCount; RATE_WINDOW_MINUTES]>,
+    /// Totales desde el arranque o la última puesta a cero, por (desde, hacia).
+    totals: Mutex<HashMap<(String, String), u64>>,
+    /// `--pool-spillover`: pool a la que desborda cada pool.
+    spillover: HashMap<String, String>,
+    spillover_delay: Duration,
+}
+
+impl SpillPolicy {
+    /// Interpreta entradas `pool=coste` y `desde=hacia` (`spillover`) de la línea de comandos.
+    pub fn new(
+        pools: &[&str],
+        entries: &[String],
+        delay: Duration,
+        queue_depth: u64,
+        spillover_entries: &[String],
+        spillover_delay: Duration,
+    ) -> Result<Self, SpillConfigError> {
+        let mut chain: Vec<(String, f64)> = Vec::new();
+        for entry in entries {
+            let parsed = entry
+                .split_once('=')
+                .and_then(|(pool, cost)| cost.trim().parse::<f64>().ok().map(|cost| (pool.trim(), cost)))
+                .filter(|(_, cost)| cost.is_finite() && *cost >= 0.0);
+            let Some((pool, cost)) = parsed else {
+                return Err(SpillConfigError(format!("Coste de pool inválido '{}': se esperaba <pool>=<coste>", entry)));
+            };
+            if !pools.contains(&pool) {
+                return Err(SpillConfigError(format!("Pool desconocida '{}' en --pool-cost", pool)));
+            }
+            if chain.iter().any(|(other, _)| other == pool) {
+                return Err(SpillConfigError(format!("Coste repetido para la pool '{}'", pool)));
+            }
+            chain.push((pool.to_string(), cost));
+        }
+        chain.sort_by(|a, b| a.1.total_cmp(&b.1));
+        let mut spillover = HashMap::new();
+        for entry in spillover_entries {
+            let Some((from, to)) = entry.split_once('=').map(|(from, to)| (from.trim(), to.trim())) else {
+                return Err(SpillConfigError(format!("Desborde inválido '{}': se esperaba <desde>=<hacia>", entry)));
+            };
+            if let Some(unknown) = [from, to].into_iter().find(|pool| !pools.contains(pool)) {
+                return Err(SpillConfigError(format!("Pool desconocida '{}' en --pool-spillover", unknown)));
+            }
+            if from == to {
+                return Err(SpillConfigError(format!("Desborde inválido '{}': una pool no desborda a sí misma", entry)));
+            }
+            if chain.len() > 1 && chain.iter().any(|(pool, _)| pool == from) {
+                return Err(SpillConfigError(format!("La pool '{}' ya reparte por coste (--pool-cost); no admite --pool-spillover", from)));
+            }
+            if spillover.insert(from.to_string(), to.to_string()).is_some() {
+                return Err(SpillConfigError(format!("Desborde repetido para la pool '{}'", from)));
+            }
+        }
+        Ok(Self {
+            chain,
+            delay,
+            queue_depth,
+            started_at: Instant::now(),
+            minutes: Mutex::new([MinuteCount::default(); RATE_WINDOW_MINUTES]),
+            totals: Mutex::new(HashMap::new()),
+            spillover,
+            spillover_delay,
+        })
+    }
+
+    /// Cadena de pools por la que se reparte una petición a `service`, de la más barata a la más
+    /// cara; `None` si la pool no tiene coste o no hay otra a la que desbordar.
+    pub fn chain_for(&self, service: &str) -> Option<impl Iterator<Item = &str>> {
+        (self.chain.len() > 1 && self.chain.iter().any(|(pool, _)| pool == service))
+            .then(|| self.chain.iter().map(|(pool, _)| pool.as_str()))
+    }
+
+    /// Pool a la que desborda `service` con `--pool-spillover`, si tiene.
+    pub fn spillover_for(&self, service: &str) -> Option<&str> {
+        self.spillover.get(service).map(String::as_str)
+    }
+
+    /// Si una petición que lleva `waited` sin nodo en su pool debe probar la de `--pool-spillover`.
+    pub fn should_spill_over(&self, waited: Duration) -> Option<SpillReason> {
+        (waited >= self.spillover_delay).then_some(SpillReason::Delay)
+    }
+
+    /// Si hay que pasar a la siguiente pool, según la cola de la actual y lo que lleva esperando en ella.
+    pub fn should_spill(&self, queued: u64, waited: Duration) -> Option<SpillReason> {
+        if queued > self.queue_depth {
+            Some(SpillReason::QueueDepth)
+        } else if waited >= self.delay {
+            Some(SpillReason::Delay)
+        } else {
+            None
+        }
+    }
+
+    /// Anota una petición repartida por la cadena; `spill` es (desde, hacia) si se desbordó.
+    pub fn record(&self, spill: Option<(&str, &str)>) {
+        let minute = self.started_at.elapsed().as_secs() / 60;
+        let mut minutes = self.minutes.lock().unwrap();
+        let slot = &mut minutes[minute as usize % RATE_WINDOW_MINUTES];
+        if slot.minute != minute {
+            *slot = MinuteCount { minute, ..Default::default() };
+        }
+        slot.routed += 1;
+        if let Some((from, to)) = spill {
+            slot.spilled += 1;
+            drop(minutes);
+            *self.totals.lock().unwrap().entry((from.to_string(), to.to_string())).or_default() += 1;
+        }
+    }
+
+    pub fn reset(&self) {
+        *self.minutes.lock().unwrap() = [MinuteCount::default(); RATE_WINDOW_MINUTES];
+        self.totals.lock().unwrap().clear();
+    }
+
+    /// Configuración de la cadena para `/config`.
+    pub fn describe(&self) -> serde_json::Value {
+        json!({
+            "pools": self.chain.iter().map(|(pool, cost)| json!({ "pool": pool, "cost": cost })).collect::<Vec<_>>(),
+            "delay_ms": self.delay.as_millis() as u64,
+            "queue_depth": self.queue_depth,
+            "spillover": self.spillover.iter().collect::<std::collections::BTreeMap<_, _>>(),
+            "spillover_delay_ms": self.spillover_delay.as_millis() as u64,
+        })
+    }
+
+    /// Desbordes de la última hora y totales por (desde, hacia) para `/stats/summary`.
+    pub fn summary(&self) -> serde_json::Value {
+        let current = self.started_at.elapsed().as_secs() / 60;
+        let (routed, spilled) = self
+            .minutes
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|slot| slot.routed > 0 && current - slot.minute < RATE_WINDOW_MINUTES as u64)
+            .fold((0, 0), |(routed, spilled), slot| (routed + slot.routed, spilled + slot.spilled));
+        let mut totals: Vec<_> = self
+            .totals
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((from, to), count)| json!({ "from": from, "to": to, "count": count }))
+            .collect();
+        totals.sort_by(|a, b| (a["from"].as_str(), a["to"].as_str()).cmp(&(b["from"].as_str(), b["to"].as_str())));
+        json!({
+            "routed_last_hour": routed,
+            "spilled_last_hour": spilled,
+            "spill_ratio_last_hour": if routed > 0 { spilled as f64 / routed as f64 } else { 0.0 },
+            "spills": totals,
+        })
+    }
+}
@@ -0,0 +1,310 @@
+// src/spool.rs
+//! Proxy del nodo con modo de reserva (`load_balancer node --spool-listen <addr>`).
+//!
+//! Los clientes que tienen el nodo como URL de respaldo le mandan sus peticiones de inferencia
+//! (`POST /lmstudio`, `POST /ollama`, las mismas rutas que el balanceador). Mientras el
+//! balanceador responde, el proxy se las pasa tal cual y el balanceador las reparte como
+//! siempre. El proxy comprueba cada pocos segundos `GET /version` del balanceador; tras varias
+//! comprobaciones fallidas seguidas lo da por caído y atiende él las peticiones contra el
+//! backend local, sin pasar por la cola del clúster, con `X-LMServER-Degraded: local` en la
+//! respuesta y hasta `--spool-max-concurrent` a la vez (el resto recibe un 503). En cuanto el
+//! balanceador vuelve a responder, el proxy vuelve a pasárselo todo.
+//!
+//! Lo atendido durante la caída se cuenta por servicio y viaja en los anuncios siguientes en un
+//! datagrama `SPOOLED` (`discovery`), para que las estadísticas del balanceador lo incluyan.
+//!
+//! El proxy sigue el contrato de `cancel`: reenvía el `X-Request-Id` en los dos casos y, si
+//! quien le llama cierra la conexión mientras espera, suelta su petición, con lo que el
+//! balanceador o el backend local ven el corte.
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use futures_util::stream;
+use log::{debug, info, warn};
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::interval;
+use url::Url;
+
+use crate::{cancel, discovery};
+
+pub const DEGRADED_HEADER: &str = "X-LMServER-Degraded";
+
+pub const DEFAULT_SPOOL_MAX_CONCURRENT: usize = 4;
+
+/// Cada cuánto se comprueba el balanceador.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Comprobaciones fallidas seguidas tras las que se da el balanceador por caído.
+const FAILED_CHECKS: u32 = 2;
+
+/// Anuncios tras la vuelta del balanceador que repiten el `SPOOLED` de la caída, por si se
+/// pierde alguno.
+const REPORT_ROUNDS: u32 = 3;
+
+/// Opciones de `--spool-listen`.
+pub struct SpoolOptions {
+    pub listen: SocketAddr,
+    /// URL HTTP del balanceador, a la que se pasan las peticiones mientras responde.
+    pub balancer_url: Url,
+    pub max_concurrent: usize,
+}
+
+/// Una caída del balanceador vista por el proxy.
+struct Outage {
+    /// Segundos Unix de su inicio; identifica la caída en `SPOOLED`.
+    id: u64,
+    /// Peticiones atendidas en local, por servicio.
+    served: BTreeMap<String, u64>,
+    /// Anuncios que aún repetirán el `SPOOLED` de cada servicio; sólo baja con el balanceador de vuelta.
+    rounds_left: BTreeMap<String, u32>,
+}
+
+/// Estado del balanceador según el proxy y lo atendido en local en la última caída. Lo
+/// comparten el proxy y los bucles de anuncio.
+#[derive(Default)]
+pub struct SpoolLedger {
+    balancer_down: AtomicBool,
+    outage: Mutex<Option<Outage>>,
+}
+
+impl SpoolLedger {
+    pub fn balancer_down(&self) -> bool {
+        self.balancer_down.load(Ordering::Relaxed)
+    }
+
+    fn set_balancer_down(&self, down: bool) {
+        if self.balancer_down.swap(down, Ordering::Relaxed) == down {
+            return;
+        }
+        let mut outage = self.outage.lock().unwrap();
+        if down {
+            let id = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+            *outage = Some(Outage { id, served: BTreeMap::new(), rounds_left: BTreeMap::new() });
+        } else if let Some(outage) = outage.as_mut() {
+            outage.rounds_left = outage.served.keys().map(|service| (service.clone(), REPORT_ROUNDS)).collect();
+        }
+    }
+
+    fn record_served(&self, service: &str) {
+        if let Some(outage) = self.outage.lock().unwrap().as_mut() {
+            *outage.served.entry(service.to_string()).or_default() += 1;
+        }
+    }
+
+    /// `SPOOLED` que debe acompañar al siguiente anuncio del servicio, si queda algo por contar.
+    pub fn report(&self, service: &str, unique_node_id: &str) -> Option<String> {
+        let down = self.balancer_down();
+        let mut outage = self.outage.lock().unwrap();
+        let outage = outage.as_mut()?;
+        let served = *outage.served.get(service)?;
+        if !down {
+            let rounds_left = outage.rounds_left.get_mut(service).filter(|rounds| **rounds > 0)?;
+            *rounds_left -= 1;
+        }
+        Some(discovery::spooled_message(service, unique_node_id, outage.id, served))
+    }
+}
+
+struct SpoolProxy {
+    client: reqwest::Client,
+    balancer_url: Url,
+    /// URL del backend local de cada servicio anunciado.
+    local: BTreeMap<&'static str, String>,
+    ledger: Arc<SpoolLedger>,
+    permits: Arc<Semaphore>,
+}
+
+/// Cabeceras de la petición del cliente que se pasan al balanceador; el id va aparte.
+fn forwarded_headers(req: &HttpRequest) -> Vec<(header::HeaderName, header::HeaderValue)> {
+    req.headers()
+        .iter()
+        .filter(|(name, _)| !matches!(*name, &header::HOST | &header::CONTENT_LENGTH | &header::CONNECTION | &header::TRANSFER_ENCODING))
+        .filter(|(name, _)| !name.as_str().eq_ignore_ascii_case(cancel::REQUEST_ID_HEADER))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect()
+}
+
+/// Respuesta al cliente con el cuerpo de `response` en streaming. El permiso, si lo hay, se
+/// suelta cuando termina el cuerpo.
+fn relay(response: reqwest::Response, permit: Option<OwnedSemaphorePermit>, degraded: bool) -> HttpResponse {
+    let status = StatusCode::from_u16(response.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+    let mut builder = HttpResponse::build(status);
+    for (name, value) in response.headers() {
+        if !matches!(*name, reqwest::header::CONTENT_LENGTH | reqwest::header::CONNECTION | reqwest::header::TRANSFER_ENCODING) {
+            builder.insert_header((name.as_str(), value.as_bytes()));
+        }
+    }
+    if degraded {
+        builder.insert_header((DEGRADED_HEADER, "local"));
+    }
+    let body = stream::unfold((response, permit), |(mut response, permit)| async move {
+        match response.chunk().await {
+            Ok(Some(chunk)) => Some((Ok(chunk), (response, permit))),
+            Ok(None) => None,
+            Err(e) => Some((Err(io::Error::other(e)), (response, permit))),
+        }
+    });
+    builder.streaming(body)
+}
+
+/// Quien llamó al proxy se fue: soltar la petición cierra la conexión con `target`.
+fn abandon(request_id: &str, service: &str, target: &str) -> HttpResponse {
+    info!("Cancelación: [{}] El cliente se fue; el proxy abandona la petición de {} a {}.", request_id, service, target);
+    cancel::client_closed()
+}
+
+async fn proxy(proxy: web::Data<SpoolProxy>, req: HttpRequest, body: web::Bytes, service: &'static str) -> HttpResponse {
+    let request_id = cancel::request_id(&req);
+    if !proxy.ledger.balancer_down() {
+        let Ok(target) = proxy.balancer_url.join(service) else {
+            return HttpResponse::InternalServerError().body("URL del balanceador inválida");
+        };
+        let mut request = proxy.client.post(target);
+        for (name, value) in forwarded_headers(&req) {
+            request = request.header(name.as_str(), value.as_bytes());
+        }
+        let sent = tokio::select! {
+            sent = request.header(cancel::REQUEST_ID_HEADER, request_id.as_str()).body(body).send() => sent,
+            _ = cancel::disconnected(&req) => return abandon(&request_id, service, "el balanceador"),
+        };
+        return match sent {
+            Ok(response) => relay(response, None, false),
+            Err(e) => {
+                warn!("Spool: No se pudo pasar la petición de {} al balanceador: {}", service, e);
+                HttpResponse::BadGateway().body(format!("El balanceador no responde: {}", e))
+            }
+        };
+    }
+
+    let Some(local_url) = proxy.local.get(service) else {
+        return HttpResponse::ServiceUnavailable().body(format!("El balanceador no responde y este nodo no sirve {}", service));
+    };
+    let Ok(permit) = proxy.permits.clone().try_acquire_owned() else {
+        debug!("Spool: Tope de peticiones locales alcanzado; se rechaza una petición de {}.", service);
+        return HttpResponse::ServiceUnavailable()
+            .insert_header((DEGRADED_HEADER, "local"))
+            .insert_header((header::RETRY_AFTER, CHECK_INTERVAL.as_secs().to_string()))
+            .body("El balanceador no responde y el nodo ya atiende todas las peticiones que puede");
+    };
+    let mut request = proxy.client.post(local_url.as_str()).header(reqwest::header::CONTENT_TYPE, "application/json");
+    if let Some(accept) = req.headers().get(header::ACCEPT) {
+        request = request.header(reqwest::header::ACCEPT, accept.as_bytes());
+    }
+    let sent = tokio::select! {
+        sent = request.header(cancel::REQUEST_ID_HEADER, request_id.as_str()).body(body).send() => sent,
+        _ = cancel::disconnected(&req) => return abandon(&request_id, service, "el backend local"),
+    };
+    match sent {
+        Ok(response) => {
+            proxy.ledger.record_served(service);
+            relay(response, Some(permit), true)
+        }
+        Err(e) => {
+            warn!("Spool: El backend local de {} no responde: {}", service, e);
+            HttpResponse::BadGateway().insert_header((DEGRADED_HEADER, "local")).body(format!("El backend local no responde: {}", e))
+        }
+    }
+}
+
+/// Comprueba el balanceador cada `CHECK_INTERVAL` y anota en el registro si está caído.
+async fn watch_balancer(client: reqwest::Client, balancer_url: Url, ledger: Arc<SpoolLedger>) {
+    let Ok(version_url) = balancer_url.join("version") else {
+        return;
+    };
+    let mut ticker = interval(CHECK_INTERVAL);
+    let mut failed = 0;
+    loop {
+        ticker.tick().await;
+        let healthy = client
+            .get(version_url.clone())
+            .timeout(CHECK_TIMEOUT)
+            .send()
+            .await
+            .is_ok_and(|response| response.status().is_success());
+        failed = if healthy { 0 } else { failed + 1 };
+        if healthy && ledger.balancer_down() {
+            info!("Spool: El balanceador {} vuelve a responder; se le pasan de nuevo las peticiones.", balancer_url);
+            ledger.set_balancer_down(false);
+        } else if failed == FAILED_CHECKS {
+            warn!("Spool: El balanceador {} no responde; el nodo atiende las peticiones contra su backend.", balancer_url);
+            ledger.set_balancer_down(true);
+        }
+    }
+}
+
+/// Arranca el proxy y la comprobación del balanceador. `local` es la URL del backend de cada
+/// servicio que anuncia el nodo.
+pub fn start(options: SpoolOptions, local: BTreeMap<&'static str, String>, ledger: Arc<SpoolLedger>) -> io::Result<()> {
+    let SpoolOptions { listen, balancer_url, max_concurrent } = options;
+    let client = reqwest::Client::new();
+    tokio::spawn(watch_balancer(client.clone(), balancer_url.clone(), ledger.clone()));
+    let proxy = web::Data::new(SpoolProxy { client, balancer_url: balancer_url.clone(), local, ledger, permits: Arc::new(Semaphore::new(max_concurrent.max(1))) });
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(proxy.clone())
+            .route("/lmstudio", web::post().to(|proxy, req, body| self::proxy(proxy, req, body, "lmstudio")))
+            .route("/ollama", web::post().to(|proxy, req, body| self::proxy(proxy, req, body, "ollama")))
+    })
+    .on_connect(cancel::on_connect)
+    .bind(listen)?
+    .run();
+    info!(
+        "Spool: Proxy del nodo en {}; pasa las peticiones a {} y, si deja de responder, las atiende en local (hasta {} a la vez).",
+        listen,
+        balancer_url,
+        max_concurrent.max(1)
+    );
+    tokio::spawn(server);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use serde_json::json;
+    use std::time::Instant;
+    use tokio::time::timeout;
+
+    /// Arranca el proxy en un puerto libre, ya en modo de reserva contra `backend`, y devuelve su URL.
+    async fn degraded_proxy(backend: &str) -> String {
+        let listen = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let ledger = Arc::new(SpoolLedger::default());
+        ledger.set_balancer_down(true);
+        let options = SpoolOptions { listen, balancer_url: Url::parse(&testing::unreachable_url()).unwrap(), max_concurrent: 1 };
+        start(options, BTreeMap::from([("lmstudio", backend.to_string())]), ledger).unwrap();
+        format!("http://{}/", listen)
+    }
+
+    #[actix_web::test]
+    async fn cancellation_reaches_the_backend_behind_the_node_proxy() {
+        let (backend, mut closures) = testing::silent_backend().await;
+        let proxy = degraded_proxy(&backend).await;
+        let state = testing::state(&[]);
+        testing::announce(&state, "lmstudio", "box0", &format!("{}lmstudio", proxy));
+        let balancer = testing::serve(state.clone());
+
+        // Cliente -> balanceador -> proxy del nodo -> backend; el cliente se va.
+        let started = Instant::now();
+        let sent = reqwest::Client::new()
+            .post(format!("{}lmstudio", balancer))
+            .header(cancel::REQUEST_ID_HEADER, "cadena-1")
+            .json(&json!({ "messages": [{ "role": "user", "content": "hola" }] }))
+            .timeout(Duration::from_millis(300))
+            .send()
+            .await;
+        assert!(sent.unwrap_err().is_timeout());
+
+        let received = timeout(Duration::from_secs(2), closures.recv()).await.expect("el backend no vio el corte").unwrap();
+        // Una comprobación en el balanceador y otra en el proxy, como mucho.
+        assert!(started.elapsed() < Duration::from_millis(300) + cancel::CHECK_INTERVAL * 4, "{:?}", started.elapsed());
+        assert!(received.to_ascii_lowercase().contains("x-request-id: cadena-1\r\n"), "{}", received);
+        testing::eventually(|| testing::node_state(&state, "lmstudio", "box0") == "available").await;
+    }
+}
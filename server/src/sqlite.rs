@@ -0,0 +1,273 @@
+// src/sqlite.rs
+//! Almacén SQLite de `persistence` (feature `sqlite`, `--persistence sqlite`).
+//!
+//! Una sola base con la auditoría (el registro JSON tal cual, indexado por `timestamp`), el
+//! uso diario ya sumado (un `UPSERT` por petición), la última instantánea del registro y las
+//! pruebas de rendimiento (el resultado JSON, indexado por nodo).
+//!
+//! Una base creada antes de que el uso separase los tokens estimados se migra al abrirla: sus
+//! filas pasan a la tabla nueva como exactas. Lo mismo con las etiquetas de analítica
+//! (`labels`): las filas anteriores quedan sin etiquetas.
+use actix_web::web::Bytes;
+use chrono::{DateTime, NaiveDate, SecondsFormat, Utc};
+use futures_util::stream;
+use log::info;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::audit::AuditRecord;
+use crate::benchmark::BenchmarkRun;
+use crate::persistence::{DailyUsage, Persistence, PersistenceError, RecordStream, RegistrySnapshot};
+
+pub const DATABASE_FILE: &str = "lmserver.db";
+
+/// Espera máxima si otro proceso tiene la base bloqueada.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// `api_key`, `model` y `labels` vacíos en lugar de NULL: NULL no casa en la clave primaria del
+// UPSERT. `labels` es el objeto JSON de las etiquetas, con las claves en orden.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS requests (
+    timestamp TEXT NOT NULL,
+    record TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS requests_timestamp ON requests (timestamp);
+CREATE TABLE IF NOT EXISTS daily_usage (
+    day TEXT NOT NULL,
+    api_key TEXT NOT NULL,
+    service TEXT NOT NULL,
+    model TEXT NOT NULL,
+    requests INTEGER NOT NULL,
+    prompt_tokens INTEGER NOT NULL,
+    completion_tokens INTEGER NOT NULL,
+    total_tokens INTEGER NOT NULL,
+    estimated INTEGER NOT NULL DEFAULT 0,
+    labels TEXT NOT NULL DEFAULT '',
+    PRIMARY KEY (day, api_key, service, model, estimated, labels)
+);
+CREATE TABLE IF NOT EXISTS registry (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    snapshot TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS benchmarks (
+    node_id TEXT NOT NULL,
+    run TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS benchmarks_node_id ON benchmarks (node_id);
+";
+
+/// `daily_usage` sin la columna `estimated`, que forma parte de la clave primaria: se rehace la tabla.
+const MIGRATE_ESTIMATED: &str = "
+BEGIN;
+ALTER TABLE daily_usage RENAME TO daily_usage_v1;
+CREATE TABLE daily_usage (
+    day TEXT NOT NULL,
+    api_key TEXT NOT NULL,
+    service TEXT NOT NULL,
+    model TEXT NOT NULL,
+    requests INTEGER NOT NULL,
+    prompt_tokens INTEGER NOT NULL,
+    completion_tokens INTEGER NOT NULL,
+    total_tokens INTEGER NOT NULL,
+    estimated INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (day, api_key, service, model, estimated)
+);
+INSERT INTO daily_usage (day, api_key, service, model, requests, prompt_tokens, completion_tokens, total_tokens)
+    SELECT day, api_key, service, model, requests, prompt_tokens, completion_tokens, total_tokens FROM daily_usage_v1;
+DROP TABLE daily_usage_v1;
+COMMIT;
+";
+
+/// `daily_usage` sin la columna `labels`, que también forma parte de la clave primaria.
+const MIGRATE_LABELS: &str = "
+BEGIN;
+ALTER TABLE daily_usage RENAME TO daily_usage_v2;
+CREATE TABLE daily_usage (
+    day TEXT NOT NULL,
+    api_key TEXT NOT NULL,
+    service TEXT NOT NULL,
+    model TEXT NOT NULL,
+    requests INTEGER NOT NULL,
+    prompt_tokens INTEGER NOT NULL,
+    completion_tokens INTEGER NOT NULL,
+    total_tokens INTEGER NOT NULL,
+    estimated INTEGER NOT NULL DEFAULT 0,
+    labels TEXT NOT NULL DEFAULT '',
+    PRIMARY KEY (day, api_key, service, model, estimated, labels)
+);
+INSERT INTO daily_usage (day, api_key, service, model, requests, prompt_tokens, completion_tokens, total_tokens, estimated)
+    SELECT day, api_key, service, model, requests, prompt_tokens, completion_tokens, total_tokens, estimated FROM daily_usage_v2;
+DROP TABLE daily_usage_v2;
+COMMIT;
+";
+
+impl From<rusqlite::Error> for PersistenceError {
+    fn from(e: rusqlite::Error) -> Self {
+        PersistenceError(format!("SQLite: {}", e))
+    }
+}
+
+/// Marca de tiempo con ancho fijo y en UTC, para que el orden de texto sea el cronológico.
+fn timestamp_key(timestamp: &DateTime<Utc>) -> String {
+    timestamp.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+pub struct SqliteStore {
+    path: PathBuf,
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> Result<Self, PersistenceError> {
+        let conn = Connection::open(path)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.execute_batch(SCHEMA)?;
+        let has_estimated: i64 =
+            conn.query_row("SELECT COUNT(*) FROM pragma_table_info('daily_usage') WHERE name = 'estimated'", [], |row| row.get(0))?;
+        if has_estimated == 0 {
+            info!("Persistencia: Migrando daily_usage de {} para separar los tokens estimados.", path.display());
+            conn.execute_batch(MIGRATE_ESTIMATED)?;
+        }
+        let has_labels: i64 =
+            conn.query_row("SELECT COUNT(*) FROM pragma_table_info('daily_usage') WHERE name = 'labels'", [], |row| row.get(0))?;
+        if has_labels == 0 {
+            info!("Persistencia: Migrando daily_usage de {} para guardar las etiquetas de analítica.", path.display());
+            conn.execute_batch(MIGRATE_LABELS)?;
+        }
+        Ok(Self { path: path.to_path_buf(), conn: Mutex::new(conn) })
+    }
+}
+
+impl Persistence for SqliteStore {
+    fn describe(&self) -> serde_json::Value {
+        json!({ "backend": "sqlite", "database": self.path })
+    }
+
+    fn save_registry(&self, snapshot: &RegistrySnapshot) -> Result<(), PersistenceError> {
+        let snapshot = serde_json::to_string(snapshot)?;
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO registry (id, snapshot) VALUES (1, ?1) ON CONFLICT (id) DO UPDATE SET snapshot = excluded.snapshot",
+            params![snapshot],
+        )?;
+        Ok(())
+    }
+
+    fn load_registry(&self) -> Result<Option<RegistrySnapshot>, PersistenceError> {
+        let snapshot: Option<String> = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row("SELECT snapshot FROM registry WHERE id = 1", [], |row| row.get(0))
+            .optional()?;
+        Ok(snapshot.map(|snapshot| serde_json::from_str(&snapshot)).transpose()?)
+    }
+
+    fn append_request(&self, record: &AuditRecord) -> Result<(), PersistenceError> {
+        let line = serde_json::to_string(record)?;
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO requests (timestamp, record) VALUES (?1, ?2)",
+            params![timestamp_key(&record.timestamp), line],
+        )?;
+        Ok(())
+    }
+
+    fn append_usage(&self, delta: &DailyUsage) -> Result<(), PersistenceError> {
+        let labels = if delta.labels.is_empty() { String::new() } else { serde_json::to_string(&delta.labels)? };
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO daily_usage (day, api_key, service, model, requests, prompt_tokens, completion_tokens, total_tokens, estimated, labels)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+             ON CONFLICT (day, api_key, service, model, estimated, labels) DO UPDATE SET
+                 requests = requests + excluded.requests,
+                 prompt_tokens = prompt_tokens + excluded.prompt_tokens,
+                 completion_tokens = completion_tokens + excluded.completion_tokens,
+                 total_tokens = total_tokens + excluded.total_tokens",
+            params![
+                delta.day.to_string(),
+                delta.api_key.as_deref().unwrap_or(""),
+                delta.service,
+                delta.model.as_deref().unwrap_or(""),
+                delta.requests as i64,
+                delta.prompt_tokens as i64,
+                delta.completion_tokens as i64,
+                delta.total_tokens as i64,
+                delta.estimated,
+                labels,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn daily_summaries(&self, from: NaiveDate, to: NaiveDate) -> Result<Vec<DailyUsage>, PersistenceError> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(
+            "SELECT day, api_key, service, model, requests, prompt_tokens, completion_tokens, total_tokens, estimated, labels
+             FROM daily_usage WHERE day >= ?1 AND day <= ?2 ORDER BY day, api_key, service, model, estimated, labels",
+        )?;
+        let rows = statement.query_map(params![from.to_string(), to.to_string()], |row| {
+            let day: String = row.get(0)?;
+            let api_key: String = row.get(1)?;
+            let model: String = row.get(3)?;
+            let labels: String = row.get(9)?;
+            Ok((
+                day,
+                labels,
+                DailyUsage {
+                    day: NaiveDate::MIN,
+                    api_key: (!api_key.is_empty()).then_some(api_key),
+                    service: row.get(2)?,
+                    model: (!model.is_empty()).then_some(model),
+                    requests: row.get::<_, i64>(4)? as u64,
+                    prompt_tokens: row.get::<_, i64>(5)? as u64,
+                    completion_tokens: row.get::<_, i64>(6)? as u64,
+                    total_tokens: row.get::<_, i64>(7)? as u64,
+                    estimated: row.get(8)?,
+                    labels: Default::default(),
+                },
+            ))
+        })?;
+        let mut days = Vec::new();
+        for row in rows {
+            let (day, labels, mut usage) = row?;
+            usage.day = day.parse().map_err(|e| PersistenceError(format!("Día inválido '{}' en daily_usage: {}", day, e)))?;
+            if !labels.is_empty() {
+                usage.labels = serde_json::from_str(&labels)?;
+            }
+            days.push(usage);
+        }
+        Ok(days)
+    }
+
+    /// Lee todos los registros del rango antes de empezar a emitirlos: la conexión no puede
+    /// quedar tomada mientras el cliente descarga.
+    fn export_requests(&self, from: Option<DateTime<Utc>>, to: Option<DateTime<Utc>>) -> Result<RecordStream, PersistenceError> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(
+            "SELECT record FROM requests WHERE (?1 IS NULL OR timestamp >= ?1) AND (?2 IS NULL OR timestamp <= ?2) ORDER BY rowid",
+        )?;
+        let records = statement
+            .query_map(params![from.as_ref().map(timestamp_key), to.as_ref().map(timestamp_key)], |row| row.get::<_, String>(0))?
+            .map(|record| record.map(|record| Ok(Bytes::from(record + "\n"))))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Box::pin(stream::iter(records)))
+    }
+
+    fn append_benchmark(&self, run: &BenchmarkRun) -> Result<(), PersistenceError> {
+        let line = serde_json::to_string(run)?;
+        self.conn.lock().unwrap().execute("INSERT INTO benchmarks (node_id, run) VALUES (?1, ?2)", params![run.node_id, line])?;
+        Ok(())
+    }
+
+    fn benchmarks(&self, unique_node_id: &str, limit: usize) -> Result<Vec<BenchmarkRun>, PersistenceError> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare("SELECT run FROM benchmarks WHERE node_id = ?1 ORDER BY rowid DESC LIMIT ?2")?;
+        let mut runs = statement
+            .query_map(params![unique_node_id, limit as i64], |row| row.get::<_, String>(0))?
+            .map(|run| Ok(serde_json::from_str(&run?)?))
+            .collect::<Result<Vec<BenchmarkRun>, PersistenceError>>()?;
+        runs.reverse();
+        Ok(runs)
+    }
+}
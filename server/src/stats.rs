@@ -0,0 +1,137 @@
+// src/stats.rs
+//! Resumen de contadores en memoria y su puesta a cero sin reiniciar el balanceador.
+//!
+//! Cada puesta a cero abre una época nueva (`epoch`), que acompaña a los resúmenes y a
+//! `GET /metrics` para que los scrapers detecten el salto y no calculen tasas negativas.
+//! Sólo se tocan contadores en memoria: el registro de auditoría no se trunca nunca.
+use actix_web::{get, post, web, HttpResponse, Responder};
+use log::info;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::Ordering;
+
+use crate::balancer::{AppState};
+use crate::events::BalancerEvent;
+
+/// Alcance de `POST /stats/reset`. Sin cuerpo equivale a `all`.
+#[derive(Deserialize)]
+#[serde(tag = "scope", rename_all = "lowercase")]
+enum ResetScope {
+    All,
+    Pool { pool: String },
+    Node { node: String },
+}
+
+/// Época actual y momento de la última puesta a cero, para adjuntar a las respuestas de estadísticas.
+pub fn epoch_json(state: &AppState) -> serde_json::Value {
+    json!({
+        "epoch": state.metrics.epoch(),
+        "last_reset": state.metrics.last_reset().map(|at| at.to_rfc3339()),
+    })
+}
+
+#[post("/stats/reset")]
+async fn reset_handler(state: web::Data<AppState>, body: web::Bytes) -> impl Responder {
+    let scope = if body.iter().all(u8::is_ascii_whitespace) {
+        ResetScope::All
+    } else {
+        match serde_json::from_slice(&body) {
+            Ok(scope) => scope,
+            Err(e) => return HttpResponse::BadRequest().json(json!({ "error": format!("Alcance inválido: {}", e) })),
+        }
+    };
+
+    let (label, nodes) = match &scope {
+        ResetScope::All => {
+            state.metrics.reset_counters();
+            state.stream_limiter.reset_rejections();
+            state.upstream_errors.write().unwrap().clear_all();
+            state.fairness.reset();
+            state.spill.reset();
+            ("all".to_string(), None)
+        }
+        ResetScope::Pool { pool } => {
+            let Some(lock) = state.pool(pool) else {
+                return HttpResponse::NotFound().json(json!({ "error": format!("Pool desconocida '{}'.", pool) }));
+            };
+            let mut nodes: BTreeSet<String> = lock.read().unwrap().keys().map(|id| id.to_string()).collect();
+            nodes.extend(state.upstream_errors.read().unwrap().nodes_in(pool));
+            (format!("pool {}", pool), Some(nodes))
+        }
+        ResetScope::Node { node } => (format!("nodo {}", node), Some(BTreeSet::from([node.clone()]))),
+    };
+    if let Some(nodes) = &nodes {
+        state.metrics.reset_node_errors(nodes);
+        let mut upstream_errors = state.upstream_errors.write().unwrap();
+        for node in nodes {
+            upstream_errors.clear(node);
+        }
+    }
+
+    let epoch = state.metrics.next_epoch();
+    info!("Stats: Contadores puestos a cero ({}). Época {}.", label, epoch);
+    state.events.publish(BalancerEvent::StatsReset { scope: label.clone(), epoch });
+    HttpResponse::Ok().json(json!({
+        "scope": label,
+        "nodes_reset": nodes.map(|nodes| nodes.into_iter().collect::<Vec<_>>()),
+        "stats": epoch_json(&state),
+    }))
+}
+
+/// Contadores, errores por nodo y colas para `/stats/summary` y el paquete de soporte.
+pub fn summary(state: &AppState) -> serde_json::Value {
+    let metrics = &state.metrics;
+    json!({
+        "stats": epoch_json(state),
+        "counters": {
+            "rejected_empty_bodies": metrics.rejected_empty_bodies.load(Ordering::Relaxed),
+            "rejected_invalid_requests": metrics.rejected_invalid_requests.load(Ordering::Relaxed),
+            "rejected_over_context": metrics.rejected_over_context.load(Ordering::Relaxed),
+            "rejected_missing_capability": metrics.rejected_missing_capability.load(Ordering::Relaxed),
+            "rejected_unknown_model": metrics.rejected_unknown_model.load(Ordering::Relaxed),
+            "rejected_unsatisfied_tags": metrics.rejected_unsatisfied_tags.load(Ordering::Relaxed),
+            "slow_lane_requests": metrics.slow_lane_requests.load(Ordering::Relaxed),
+            "direct_requests": metrics.direct_requests.load(Ordering::Relaxed),
+            "rejected_client_in_flight": metrics.rejected_client_in_flight.load(Ordering::Relaxed),
+            "warm_model_hits": metrics.warm_model_hits.load(Ordering::Relaxed),
+            "stale_busy_reconciliations": metrics.stale_busy_reconciliations.load(Ordering::Relaxed),
+            "canary_promotions": metrics.canary_promotions.load(Ordering::Relaxed),
+            "canary_failures": metrics.canary_failures.load(Ordering::Relaxed),
+            "strict_request_violations": metrics.strict_request_violations.load(Ordering::Relaxed),
+            "tool_call_retries": metrics.tool_call_retries.load(Ordering::Relaxed),
+            "rejected_warmup": metrics.rejected_warmup.load(Ordering::Relaxed),
+            "cancelled_requests": metrics.cancelled_requests.load(Ordering::Relaxed),
+            "spooled_requests": metrics.spooled_requests.load(Ordering::Relaxed),
+            "estimated_usage_streams": metrics.estimated_usage_streams.load(Ordering::Relaxed),
+            "drain_redispatched_requests": metrics.drain_redispatched_requests.load(Ordering::Relaxed),
+            "drain_terminated_streams": metrics.drain_terminated_streams.load(Ordering::Relaxed),
+            "idempotent_replays": metrics.idempotent_replays.load(Ordering::Relaxed),
+            "event_lag_disconnects": metrics.event_lag_disconnects.load(Ordering::Relaxed),
+            "streamed_responses": metrics.streamed_responses.load(Ordering::Relaxed),
+            "stream_node_held_ms": metrics.stream_node_held_ms.load(Ordering::Relaxed),
+            "stream_client_drain_ms": metrics.stream_client_drain_ms.load(Ordering::Relaxed),
+            "sse_keepalives": metrics.sse_keepalives.load(Ordering::Relaxed),
+            "stream_limit_rejections": state.stream_limiter.rejections(),
+        },
+        "upstream_errors": metrics.upstream_error_snapshot(),
+        "active_streams": state.stream_limiter.active().into_iter().collect::<BTreeMap<_, _>>(),
+        "model_queues": state.model_queues.depths(),
+        "spill": state.spill.summary(),
+    })
+}
+
+#[get("/stats/summary")]
+async fn summary_handler(state: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(summary(&state))
+}
+
+/// Reparto de peticiones por nodo en la ventana, frente al que tocaría entre los elegibles.
+#[get("/stats/fairness")]
+async fn fairness_handler(state: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(json!({
+        "window_secs": state.fairness.window().as_secs(),
+        "skew_threshold": state.fairness.skew_threshold,
+        "pools": state.fairness.report(),
+    }))
+}
@@ -0,0 +1,300 @@
+// src/status.rs
+//! Vista pública del estado (`GET /status/public` y la UI de terminal con `--ui-public`).
+//!
+//! Pensada para una pantalla a la vista de cualquiera: sólo datos agregados que no identifican
+//! a nadie. Se construye en el servidor a partir de recuentos, nunca recortando una vista
+//! completa, así que un ID, una URL, un host o una API key no pueden colarse aunque los nodos
+//! los tengan. Los campos que se publican se eligen con `--public-status-fields`; por defecto,
+//! nodos por pool y estado, peticiones por minuto, p95 de latencia y uptime.
+//!
+//! El ritmo y la latencia salen de una ventana móvil de `request_completed`, acotada por
+//! `request_samples` en `[limits]`.
+use actix_web::http::header;
+use actix_web::{get, web, HttpRequest, HttpResponse, Responder};
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::balancer::AppState;
+use crate::index;
+use crate::limits::StoreUsage;
+use crate::ui;
+
+/// Ventana de la que salen el ritmo y la latencia.
+pub const REQUEST_WINDOW: Duration = Duration::from_secs(300);
+
+/// Campos de la vista pública.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum PublicField {
+    /// Nodos por pool y estado.
+    Pools,
+    /// Peticiones por minuto en la ventana.
+    RequestRate,
+    /// Percentil 95 de la duración de las peticiones en la ventana.
+    LatencyP95,
+    Uptime,
+    /// Versión del balanceador (no va por defecto: dice qué fallos conocidos podría tener).
+    Version,
+    /// Peticiones en cola.
+    Queued,
+    /// Respuestas en streaming en curso.
+    Streams,
+}
+
+pub const DEFAULT_PUBLIC_FIELDS: &str = "pools,request-rate,latency-p95,uptime";
+
+/// Duraciones de las peticiones terminadas en los últimos `REQUEST_WINDOW`.
+pub struct RequestWindow {
+    samples: Mutex<VecDeque<(Instant, u64)>>,
+    /// Lleno, se descarta la muestra más antigua.
+    max_samples: usize,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct WindowSummary {
+    pub requests: usize,
+    pub per_minute: f64,
+    pub p95_ms: Option<u64>,
+}
+
+impl RequestWindow {
+    pub fn new(max_samples: usize) -> Self {
+        Self { samples: Mutex::new(VecDeque::new()), max_samples }
+    }
+
+    fn prune(samples: &mut VecDeque<(Instant, u64)>, now: Instant) {
+        while samples.front().is_some_and(|(at, _)| now.duration_since(*at) > REQUEST_WINDOW) {
+            samples.pop_front();
+        }
+    }
+
+    pub fn record(&self, duration_ms: u64) {
+        let mut samples = self.samples.lock().unwrap();
+        let now = Instant::now();
+        Self::prune(&mut samples, now);
+        if samples.len() >= self.max_samples {
+            samples.pop_front();
+        }
+        samples.push_back((now, duration_ms));
+    }
+
+    pub fn summary(&self, uptime: Duration) -> WindowSummary {
+        let mut samples = self.samples.lock().unwrap();
+        Self::prune(&mut samples, Instant::now());
+        let mut durations: Vec<u64> = samples.iter().map(|(_, ms)| *ms).collect();
+        drop(samples);
+        durations.sort_unstable();
+        // Recién arrancado, la ventana real es el uptime (al menos un minuto, para no disparar el
+        // ritmo con las primeras peticiones): no se diluye en minutos sin datos.
+        let minutes = uptime.min(REQUEST_WINDOW).as_secs_f64().max(60.0) / 60.0;
+        let p95_ms = (!durations.is_empty()).then(|| durations[(durations.len() * 95).div_ceil(100) - 1]);
+        WindowSummary { requests: durations.len(), per_minute: durations.len() as f64 / minutes, p95_ms }
+    }
+
+    pub fn usage(&self) -> StoreUsage {
+        let entries = self.samples.lock().unwrap().len();
+        StoreUsage { entries, bytes: entries * std::mem::size_of::<(Instant, u64)>() }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PublicStatus {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uptime_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requests_per_minute: Option<f64>,
+    /// `Some(None)`: se publica, pero no hubo peticiones en la ventana.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_p95_ms: Option<Option<u64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queued_requests: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub active_streams: Option<usize>,
+    /// Nodos por pool y estado.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pools: Option<BTreeMap<&'static str, BTreeMap<&'static str, usize>>>,
+}
+
+/// Construye la vista pública con los campos configurados; el resto ni se calcula.
+pub fn public(state: &AppState) -> PublicStatus {
+    let fields = &state.public_status_fields;
+    let has = |field| fields.contains(&field);
+    let ui = ui::snapshot(state);
+    let window = (has(PublicField::RequestRate) || has(PublicField::LatencyP95))
+        .then(|| state.request_window.summary(state.started_at.elapsed()));
+    PublicStatus {
+        version: has(PublicField::Version).then_some(ui.version.clone()),
+        uptime_secs: has(PublicField::Uptime).then_some(ui.uptime_secs),
+        requests_per_minute: window.filter(|_| has(PublicField::RequestRate)).map(|w| (w.per_minute * 10.0).round() / 10.0),
+        latency_p95_ms: window.filter(|_| has(PublicField::LatencyP95)).map(|w| w.p95_ms),
+        queued_requests: has(PublicField::Queued).then_some(ui.queued_requests),
+        active_streams: has(PublicField::Streams).then_some(ui.active_streams),
+        pools: has(PublicField::Pools).then(|| index::pool_counts(&ui)),
+    }
+}
+
+fn render_text(status: &PublicStatus) -> String {
+    let mut out = String::new();
+    match &status.version {
+        Some(version) => {
+            let _ = writeln!(out, "lmServer {}", version);
+        }
+        None => {
+            let _ = writeln!(out, "lmServer");
+        }
+    }
+    if let Some(uptime) = status.uptime_secs {
+        let _ = writeln!(out, "{:<10} {}", "uptime", index::format_uptime(uptime));
+    }
+    if let Some(rate) = status.requests_per_minute {
+        let _ = writeln!(out, "{:<10} {:.1}/min", "requests", rate);
+    }
+    if let Some(p95) = status.latency_p95_ms {
+        let p95 = p95.map_or_else(|| "-".to_string(), |ms| format!("{}ms", ms));
+        let _ = writeln!(out, "{:<10} {}", "p95", p95);
+    }
+    if let Some(queued) = status.queued_requests {
+        let _ = writeln!(out, "{:<10} {}", "queued", queued);
+    }
+    if let Some(streams) = status.active_streams {
+        let _ = writeln!(out, "{:<10} {}", "streams", streams);
+    }
+    if let Some(pools) = &status.pools {
+        let _ = writeln!(out);
+        out.push_str(&index::render_pools(pools));
+    }
+    out
+}
+
+/// Texto de la UI de terminal en modo público.
+pub fn render_public(state: &AppState) -> String {
+    render_text(&public(state))
+}
+
+/// Estado agregado sin identificadores, sin autenticación. Texto o JSON según `Accept`, como `/`.
+#[get("/status/public")]
+async fn public_status_handler(state: web::Data<AppState>, req: HttpRequest) -> impl Responder {
+    let status = public(&state);
+    if index::prefers_json(req.headers()) {
+        HttpResponse::Ok().insert_header((header::VARY, "Accept")).json(status)
+    } else {
+        HttpResponse::Ok()
+            .content_type("text/plain; charset=utf-8")
+            .insert_header((header::VARY, "Accept"))
+            .body(render_text(&status))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use serde_json::{json, Value};
+    use std::time::Instant;
+
+    use crate::balancer::{self, NodeHealth, LMSTUDIO, OLLAMA};
+    use crate::history::TransitionCause;
+    use crate::testing;
+
+    const ALL_FIELDS: &str = "pools,request-rate,latency-p95,uptime,version,queued,streams";
+    /// Identificadores que tienen los nodos y las keys del estado de prueba.
+    const SECRETS: [&str; 8] = ["gpu-alpha", "gpu-beta", "secret-host", "corp.example", "10.9.8.7", "sk-team-secret", "acme-team", "llama-private"];
+
+    fn public_request(accept: &str) -> TestRequest {
+        TestRequest::get().uri("/status/public").insert_header((header::ACCEPT, accept))
+    }
+
+    async fn text<B: actix_web::body::MessageBody>(resp: actix_web::dev::ServiceResponse<B>) -> (u16, String) {
+        let status = resp.status().as_u16();
+        (status, String::from_utf8(read_body(resp).await.to_vec()).unwrap())
+    }
+
+    #[actix_web::test]
+    async fn public_view_never_contains_identifiers() {
+        let keys = testing::temp_file("keys.toml", "[keys.acme-team]\nkey = \"sk-team-secret\"\n");
+        let state = testing::state(&[
+            "--public-status-fields", ALL_FIELDS,
+            "--api-keys-file", keys.to_str().unwrap(),
+            "--admin-token", "admin",
+            "--protect-read-endpoints",
+        ]);
+        testing::announce(&state, "lmstudio", "gpu-alpha.corp.example", &testing::chat_node(Duration::ZERO));
+        testing::announce(&state, "ollama", "gpu-beta", "http://secret-host.corp.example:11434/");
+        testing::announce(&state, "ollama", "gpu-gamma-10.9.8.7", "http://10.9.8.7:11434/");
+        state.set_node_models(&LMSTUDIO, &testing::node_id("gpu-alpha.corp.example"), vec!["llama-private".to_string()]);
+        state.update_node_state(&OLLAMA, &testing::node_id("gpu-beta"), NodeHealth::Failed(Instant::now()), TransitionCause::HealthCheck);
+        let _consumers = testing::consumers(&state);
+        let app = init_service(balancer::app(state.clone())).await;
+        let req = TestRequest::post()
+            .uri("/lmstudio")
+            .insert_header(("Authorization", "Bearer sk-team-secret"))
+            .set_json(json!({ "model": "llama-private", "messages": [{ "role": "user", "content": "hola" }] }));
+        assert_eq!(call_service(&app, req.to_request()).await.status(), 200);
+        testing::eventually(|| state.request_window.summary(state.started_at.elapsed()).requests == 1).await;
+
+        let (status, json_body) = text(call_service(&app, public_request("application/json").to_request()).await).await;
+        assert_eq!(status, 200, "es pública aunque las lecturas estén protegidas");
+        let (status, text_body) = text(call_service(&app, public_request("text/plain").to_request()).await).await;
+        assert_eq!(status, 200);
+        for body in [&json_body, &text_body, &render_public(&state)] {
+            for secret in SECRETS {
+                assert!(!body.contains(secret), "la vista pública contiene {}: {}", secret, body);
+            }
+            assert!(!body.contains("http"), "{}", body);
+        }
+
+        let body: Value = serde_json::from_str(&json_body).unwrap();
+        assert_eq!(body["pools"]["lmstudio"]["available"], 1);
+        assert_eq!(body["pools"]["ollama"]["available"], 1);
+        assert_eq!(body["pools"]["ollama"]["failed"], 1);
+        assert_eq!(body["requests_per_minute"], 1.0);
+        assert!(body["latency_p95_ms"].is_u64(), "{}", body);
+    }
+
+    fn fields(body: &Value) -> Vec<&str> {
+        body.as_object().unwrap().keys().map(String::as_str).collect()
+    }
+
+    #[actix_web::test]
+    async fn default_and_configured_fields() {
+        let app = init_service(balancer::app(testing::state(&[]))).await;
+        let (_, body) = text(call_service(&app, public_request("application/json").to_request()).await).await;
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(fields(&body), ["latency_p95_ms", "pools", "requests_per_minute", "uptime_secs"]);
+        assert_eq!(body["latency_p95_ms"], Value::Null, "sin peticiones no hay p95");
+
+        let app = init_service(balancer::app(testing::state(&["--public-status-fields", "queued,streams"]))).await;
+        let (_, body) = text(call_service(&app, public_request("application/json").to_request()).await).await;
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body, json!({ "queued_requests": 0, "active_streams": 0 }));
+        let (_, text) = text(call_service(&app, public_request("text/plain").to_request()).await).await;
+        assert_eq!(text, "lmServer\nqueued     0\nstreams    0\n");
+    }
+
+    #[test]
+    fn window_reports_rate_and_p95() {
+        let window = RequestWindow::new(100);
+        assert_eq!(window.summary(Duration::from_secs(600)).p95_ms, None);
+        for ms in 1..=20 {
+            window.record(ms * 10);
+        }
+        let summary = window.summary(Duration::from_secs(600));
+        assert_eq!((summary.requests, summary.p95_ms), (20, Some(190)));
+        // 20 peticiones en la ventana de 5 minutos.
+        assert_eq!(summary.per_minute, 4.0);
+        // Recién arrancado cuenta el uptime, pero nunca menos de un minuto.
+        assert_eq!(window.summary(Duration::from_secs(120)).per_minute, 10.0);
+        assert_eq!(window.summary(Duration::from_secs(5)).per_minute, 20.0);
+
+        let window = RequestWindow::new(3);
+        for ms in [1, 2, 3, 4] {
+            window.record(ms);
+        }
+        assert_eq!(window.usage().entries, 3);
+        assert_eq!(window.summary(Duration::from_secs(600)).p95_ms, Some(4));
+    }
+}
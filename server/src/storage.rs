@@ -0,0 +1,89 @@
+// src/storage.rs
+//! Espacio en disco del volumen de modelos de un nodo.
+//!
+//! El nodo lo mide cada cierto tiempo en la ruta de `--models-path` y lo anuncia en un
+//! datagrama `STORAGE`. El balanceador marca con un aviso los nodos por debajo del mínimo
+//! de espacio libre: siguen recibiendo peticiones, pero un disco lleno explica fallos que de
+//! otro modo parecerían errores genéricos.
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+/// Cada cuánto mide el nodo el espacio de su volumen de modelos.
+pub const STORAGE_PROBE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Espacio libre mínimo por defecto antes de marcar un nodo, en MB.
+pub const DEFAULT_MIN_FREE_DISK_MB: u64 = 10 * 1024;
+
+/// Extensiones de los ficheros de pesos de LM Studio y similares.
+const MODEL_EXTENSIONS: [&str; 3] = ["gguf", "safetensors", "bin"];
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct StorageReport {
+    pub free_bytes: u64,
+    pub total_bytes: u64,
+    /// Ficheros de pesos guardados y lo que ocupan.
+    pub model_files: u64,
+    pub model_bytes: u64,
+}
+
+impl StorageReport {
+    pub fn is_low(&self, min_free_bytes: u64) -> bool {
+        self.free_bytes < min_free_bytes
+    }
+}
+
+/// Los blobs de Ollama se llaman `sha256-<hash>`, sin extensión.
+fn is_model_file(path: &Path) -> bool {
+    let extension = path.extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase);
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+    extension.is_some_and(|e| MODEL_EXTENSIONS.contains(&e.as_str())) || name.starts_with("sha256-")
+}
+
+/// Suma recursivamente los ficheros de modelos bajo `dir`. Los directorios ilegibles se saltan.
+fn scan_models(dir: &Path, report: &mut StorageReport) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        let path = entry.path();
+        if metadata.is_dir() {
+            scan_models(&path, report);
+        } else if metadata.is_file() && is_model_file(&path) {
+            report.model_files += 1;
+            report.model_bytes += metadata.len();
+        }
+    }
+}
+
+/// Mide el volumen que contiene `path` y los modelos guardados bajo él. Bloqueante.
+pub fn probe(path: &Path) -> io::Result<StorageReport> {
+    if !fs::metadata(path)?.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "no es un directorio"));
+    }
+    let mut report = StorageReport {
+        free_bytes: fs4::available_space(path)?,
+        total_bytes: fs4::total_space(path)?,
+        model_files: 0,
+        model_bytes: 0,
+    };
+    scan_models(path, &mut report);
+    Ok(report)
+}
+
+/// Formatea bytes en la unidad binaria más cómoda de leer.
+pub fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
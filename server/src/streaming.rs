@@ -0,0 +1,639 @@
+// src/streaming.rs
+//! Reenvío de respuestas en streaming (SSE / NDJSON) desacoplando al nodo del cliente.
+//!
+//! Una tarea lee del nodo y deja los fragmentos en un buffer limitado en bytes. En cuanto el
+//! nodo termina, se libera aunque el cliente siga leyendo lo que queda en el buffer. Si el
+//! buffer se llena mientras el nodo aún genera, la lectura del nodo se detiene hasta que el
+//! cliente consuma (contrapresión).
+//!
+//! El formato sale del `Content-Type` del nodo y se respeta byte a byte:
+//!
+//! - SSE (`text/event-stream`, OpenAI): si el nodo pasa un rato sin enviar nada entre eventos,
+//!   se intercala un comentario (`: keep-alive`) para que los proxies con timeout de
+//!   inactividad no corten la conexión. Es el único formato que reescribe el post-procesado.
+//! - NDJSON (`application/x-ndjson`, endpoints nativos de Ollama): se reenvía por líneas
+//!   completas, sin prefijos `data:` ni keep-alive. NDJSON no tiene comentarios y un objeto
+//!   vacío lo leerían clientes como Open WebUI como un fragmento más de la respuesta.
+use actix_web::web::{self, Bytes};
+use futures_util::stream::{self, Stream};
+use log::{debug, error, info};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Instant;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+
+use crate::audit::AuditRecord;
+use crate::balancer::{AppState, InFlight, NodeHealth};
+use crate::client_limit::ClientPermit;
+use crate::drain;
+use crate::events::BalancerEvent;
+use crate::history::TransitionCause;
+use crate::ids::{NodeId, PoolName, ServiceUrl};
+use crate::metrics::{escape_label, write_labeled_metric};
+use crate::pipeline::{self, PipelineToken};
+use crate::postprocess::{ResponsePlan, StreamRewriter};
+use crate::strictness::{StreamCheck, Strictness};
+use crate::usage::StreamUsage;
+
+/// Tamaño por defecto del buffer por respuesta en streaming.
+pub const DEFAULT_STREAM_BUFFER_BYTES: usize = 1024 * 1024;
+
+/// Segundos de silencio del nodo tras los que se envía un keep-alive en SSE.
+pub const DEFAULT_SSE_KEEPALIVE_SECS: u64 = 15;
+
+const SSE_KEEPALIVE: &[u8] = b": keep-alive\n\n";
+
+/// Límite de streams concurrentes que rechazó una petición.
+#[derive(Clone, Copy, Debug)]
+pub enum StreamLimit {
+    Global,
+    Pool,
+    ApiKey,
+}
+
+impl StreamLimit {
+    pub fn label(&self) -> &'static str {
+        match self {
+            StreamLimit::Global => "global",
+            StreamLimit::Pool => "pool",
+            StreamLimit::ApiKey => "api_key",
+        }
+    }
+}
+
+#[derive(Default)]
+struct StreamCounts {
+    global: usize,
+    per_pool: HashMap<String, usize>,
+    per_key: HashMap<String, usize>,
+}
+
+/// Topes de respuestas en streaming simultáneas, independientes de la concurrencia total.
+/// `None` significa sin límite. El tope por API key sólo se aplica a peticiones con key.
+pub struct StreamLimiter {
+    max_global: Option<usize>,
+    max_per_pool: Option<usize>,
+    max_per_key: Option<usize>,
+    counts: Mutex<StreamCounts>,
+    rejections: [AtomicU64; 3],
+}
+
+impl StreamLimiter {
+    pub fn new(pools: &[&str], max_global: Option<usize>, max_per_pool: Option<usize>, max_per_key: Option<usize>) -> Self {
+        // Las pools conocidas se mantienen aunque estén a cero para que la métrica no desaparezca.
+        let counts = StreamCounts {
+            per_pool: pools.iter().map(|pool| (pool.to_string(), 0)).collect(),
+            ..Default::default()
+        };
+        Self {
+            max_global,
+            max_per_pool,
+            max_per_key,
+            counts: Mutex::new(counts),
+            rejections: Default::default(),
+        }
+    }
+
+    /// Streams activos por pool.
+    pub fn active(&self) -> Vec<(String, usize)> {
+        let counts = self.counts.lock().unwrap();
+        let mut active: Vec<_> = counts.per_pool.iter().map(|(pool, n)| (pool.clone(), *n)).collect();
+        active.sort();
+        active
+    }
+
+    pub fn active_total(&self) -> usize {
+        self.counts.lock().unwrap().global
+    }
+
+    pub fn reset_rejections(&self) {
+        for rejections in &self.rejections {
+            rejections.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Rechazos por tope desde la última puesta a cero.
+    pub fn rejections(&self) -> BTreeMap<&'static str, u64> {
+        [StreamLimit::Global, StreamLimit::Pool, StreamLimit::ApiKey]
+            .iter()
+            .map(|limit| (limit.label(), self.rejections[*limit as usize].load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        write_labeled_metric(
+            &mut out,
+            "lmserver_active_streams",
+            "Respuestas en streaming en curso, por pool.",
+            "gauge",
+            self.active().into_iter().map(|(pool, n)| (format!("pool=\"{}\"", escape_label(&pool)), n as u64)),
+        );
+        write_labeled_metric(
+            &mut out,
+            "lmserver_stream_limit_rejections_total",
+            "Peticiones en streaming rechazadas con 429 por tope de streams simultáneos.",
+            "counter",
+            [StreamLimit::Global, StreamLimit::Pool, StreamLimit::ApiKey]
+                .iter()
+                .map(|limit| (format!("limit=\"{}\"", limit.label()), self.rejections[*limit as usize].load(Ordering::Relaxed))),
+        );
+        out
+    }
+}
+
+/// Plaza de stream ocupada; se libera al destruirse, termine como termine la respuesta.
+pub struct StreamPermit {
+    state: web::Data<AppState>,
+    pool: String,
+    api_key: Option<String>,
+}
+
+impl Drop for StreamPermit {
+    fn drop(&mut self) {
+        let mut counts = self.state.stream_limiter.counts.lock().unwrap();
+        counts.global -= 1;
+        if let Some(count) = counts.per_pool.get_mut(&self.pool) {
+            *count -= 1;
+        }
+        if let Some(api_key) = &self.api_key {
+            if let Some(count) = counts.per_key.get_mut(api_key) {
+                *count -= 1;
+                if *count == 0 {
+                    counts.per_key.remove(api_key);
+                }
+            }
+        }
+    }
+}
+
+/// Reserva una plaza de stream comprobando los tres topes de forma atómica.
+pub fn try_acquire(state: web::Data<AppState>, pool: &str, api_key: Option<&str>) -> Result<StreamPermit, StreamLimit> {
+    let limiter = &state.stream_limiter;
+    {
+        let mut counts = limiter.counts.lock().unwrap();
+        let over = |current: usize, max: Option<usize>| max.is_some_and(|max| current >= max);
+        let rejected = if over(counts.global, limiter.max_global) {
+            Some(StreamLimit::Global)
+        } else if over(counts.per_pool.get(pool).copied().unwrap_or(0), limiter.max_per_pool) {
+            Some(StreamLimit::Pool)
+        } else if api_key.is_some_and(|key| over(counts.per_key.get(key).copied().unwrap_or(0), limiter.max_per_key)) {
+            Some(StreamLimit::ApiKey)
+        } else {
+            None
+        };
+        if let Some(limit) = rejected {
+            limiter.rejections[limit as usize].fetch_add(1, Ordering::Relaxed);
+            return Err(limit);
+        }
+        counts.global += 1;
+        *counts.per_pool.entry(pool.to_string()).or_default() += 1;
+        if let Some(api_key) = api_key {
+            *counts.per_key.entry(api_key.to_string()).or_default() += 1;
+        }
+    }
+    Ok(StreamPermit {
+        state,
+        pool: pool.to_string(),
+        api_key: api_key.map(str::to_string),
+    })
+}
+
+/// Formato de una respuesta en streaming.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamFraming {
+    /// `text/event-stream`: eventos separados por una línea en blanco.
+    Sse,
+    /// `application/x-ndjson`: un objeto JSON por línea.
+    Ndjson,
+}
+
+impl StreamFraming {
+    /// Formato de la respuesta del nodo; `None` si no es streaming y debe leerse entera.
+    pub fn of(response: &reqwest::Response) -> Option<Self> {
+        let content_type = response.headers().get(reqwest::header::CONTENT_TYPE)?.to_str().ok()?;
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        if media_type.eq_ignore_ascii_case("text/event-stream") {
+            Some(StreamFraming::Sse)
+        } else if media_type.eq_ignore_ascii_case("application/x-ndjson") {
+            Some(StreamFraming::Ndjson)
+        } else {
+            None
+        }
+    }
+}
+
+/// Reagrupa NDJSON en líneas completas: el cliente nunca recibe medio objeto JSON.
+#[derive(Default)]
+struct LineFramer {
+    pending: Vec<u8>,
+}
+
+impl LineFramer {
+    /// Devuelve las líneas completas hasta ahora. Una línea sin terminar que ya ocupa
+    /// `max_pending` bytes se entrega tal cual para no retenerla sin límite.
+    fn push(&mut self, chunk: &[u8], max_pending: usize) -> Bytes {
+        self.pending.extend_from_slice(chunk);
+        match self.pending.iter().rposition(|byte| *byte == b'\n') {
+            Some(end) => {
+                let rest = self.pending.split_off(end + 1);
+                Bytes::from(std::mem::replace(&mut self.pending, rest))
+            }
+            None if self.pending.len() >= max_pending => Bytes::from(std::mem::take(&mut self.pending)),
+            None => Bytes::new(),
+        }
+    }
+
+    /// Lo que quedaba tras la última línea cuando el nodo termina.
+    fn finish(&mut self) -> Bytes {
+        Bytes::from(std::mem::take(&mut self.pending))
+    }
+}
+
+/// Cabeceras para que los proxies intermedios (nginx, etc.) no almacenen ni retengan el stream.
+pub fn proxy_headers() -> [(&'static str, &'static str); 2] {
+    [("Cache-Control", "no-cache"), ("X-Accel-Buffering", "no")]
+}
+
+/// Indica si el fragmento cierra un evento SSE, es decir, si se puede intercalar un comentario tras él.
+fn ends_event(chunk: &[u8]) -> bool {
+    chunk.ends_with(b"\n\n") || chunk.ends_with(b"\r\n\r\n")
+}
+
+type Chunk = (io::Result<Bytes>, OwnedSemaphorePermit);
+
+/// Contabiliza el tiempo que el cliente tarda en vaciar el buffer tras terminar el nodo,
+/// tanto si lee hasta el final como si se desconecta antes.
+struct DrainGuard {
+    state: web::Data<AppState>,
+    upstream_done: Arc<OnceLock<Instant>>,
+}
+
+impl Drop for DrainGuard {
+    fn drop(&mut self) {
+        if let Some(done) = self.upstream_done.get() {
+            let drain_ms = done.elapsed().as_millis() as u64;
+            self.state.metrics.stream_client_drain_ms.fetch_add(drain_ms, Ordering::Relaxed);
+            debug!("Streaming: Cliente terminó de leer {}ms después de liberar el nodo.", drain_ms);
+        }
+    }
+}
+
+/// Nodo ocupado por una respuesta en streaming.
+pub struct NodeLease {
+    pub service: PoolName,
+    pub unique_node_id: NodeId,
+    pub service_url: ServiceUrl,
+    /// Cuándo se ocupó el nodo, para medir cuánto lo retuvo.
+    pub occupied_at: Instant,
+    pub pipeline_token: Option<PipelineToken>,
+    /// `X-Request-Id` de la petición, para los registros de cancelación.
+    pub request_id: String,
+    /// Cuenta la petición en el nodo hasta que éste termina de responder.
+    pub in_flight: InFlight,
+    /// Aviso de que venció el drenaje del nodo (`--drain-policy migrate`).
+    pub drain: Option<CancellationToken>,
+    /// Reenviada en modo directo (`direct`): el nodo no se ocupó y no se libera.
+    pub direct: bool,
+    /// Plaza del cliente (`client_limit`), que dura hasta el final del stream.
+    pub client_permit: Option<ClientPermit>,
+    /// Validación de los eventos del nodo (`strictness`) en su pool.
+    pub strictness: Strictness,
+}
+
+/// Reenvía `response` al cliente y libera el nodo de `lease` cuando el nodo termina. El registro
+/// de auditoría, si lo hay, se guarda al terminar con los tokens que cuente su `StreamUsage`.
+pub fn relay(
+    state: web::Data<AppState>,
+    lease: NodeLease,
+    mut response: reqwest::Response,
+    framing: StreamFraming,
+    audit: Option<(AuditRecord, StreamUsage)>,
+    permit: Option<StreamPermit>,
+    postprocess: Option<ResponsePlan>,
+) -> impl Stream<Item = io::Result<Bytes>> {
+    // NDJSON no admite comentarios: el keep-alive sólo se usa en SSE.
+    let keepalive = state.sse_keepalive.filter(|_| framing == StreamFraming::Sse);
+    let buffer_bytes = state.stream_buffer_bytes.clamp(1, Semaphore::MAX_PERMITS);
+    let permits = Arc::new(Semaphore::new(buffer_bytes));
+    let (tx, rx) = mpsc::unbounded_channel::<Chunk>();
+    let upstream_done = Arc::new(OnceLock::new());
+    // Sólo se reescribe SSE: el NDJSON nativo de Ollama pasa tal cual, línea a línea.
+    let mut rewriter = postprocess.filter(|_| framing == StreamFraming::Sse).map(StreamRewriter::new);
+    let mut lines = (framing == StreamFraming::Ndjson).then(LineFramer::default);
+    let (audit_record, mut usage) = audit.unzip();
+
+    let pump_state = state.clone();
+    let pump_done = upstream_done.clone();
+    tokio::spawn(async move {
+        let NodeLease { service, unique_node_id, service_url, occupied_at, pipeline_token, request_id, in_flight, drain, direct, client_permit, strictness } = lease;
+        let mut check = (strictness != Strictness::Off).then(|| StreamCheck::new(framing == StreamFraming::Ndjson));
+        // Fin del nodo y corte por drenaje: tras ellos aún se envía lo que quede al cliente.
+        let (mut finished, mut terminated) = (false, false);
+        let failed = loop {
+            // Con el nodo callado el cliente puede irse sin que falle ningún envío: se suelta ya.
+            let next = tokio::select! {
+                next = response.chunk() => next,
+                _ = tx.closed() => {
+                    pump_state.metrics.cancelled_requests.fetch_add(1, Ordering::Relaxed);
+                    info!("Cancelación: [{}] El cliente se desconectó; se abandona la respuesta del nodo ID {}.", request_id, unique_node_id);
+                    break false;
+                }
+                _ = drain::expired(drain.as_ref()) => {
+                    terminated = true;
+                    break false;
+                }
+            };
+            match next {
+                Ok(Some(chunk)) => {
+                    if let Some(usage) = &mut usage {
+                        usage.push(&chunk);
+                    }
+                    if let Some(check) = &mut check {
+                        check.push(&chunk);
+                    }
+                    let chunk = match (&mut rewriter, &mut lines) {
+                        (Some(rewriter), _) => rewriter.push(&chunk),
+                        (None, Some(lines)) => lines.push(&chunk, buffer_bytes),
+                        (None, None) => chunk,
+                    };
+                    if chunk.is_empty() {
+                        continue;
+                    }
+                    let size = chunk.len().clamp(1, buffer_bytes) as u32;
+                    // El semáforo nunca se cierra: `acquire` sólo espera a que el cliente consuma.
+                    let permit = permits.clone().acquire_many_owned(size).await.expect("semáforo cerrado");
+                    if tx.send((Ok(chunk), permit)).is_err() {
+                        pump_state.metrics.cancelled_requests.fetch_add(1, Ordering::Relaxed);
+                        info!("Cancelación: [{}] El cliente se desconectó; se abandona la respuesta del nodo ID {}.", request_id, unique_node_id);
+                        break false;
+                    }
+                }
+                Err(e) => {
+                    let category = pump_state.record_upstream_error(&service, &unique_node_id, &e);
+                    error!("Streaming: Error leyendo del nodo ID {} [{}]: {}", unique_node_id, category.label(), e);
+                    let permit = permits.clone().acquire_many_owned(0).await.expect("semáforo cerrado");
+                    let _ = tx.send((Err(io::Error::other(e)), permit));
+                    break true;
+                }
+                Ok(None) => {
+                    finished = true;
+                    break false;
+                }
+            }
+        };
+        if finished || terminated {
+            // Texto que el post-procesado aún retenía por si continuaba una secuencia de stop,
+            // o la última línea NDJSON si el nodo no la terminó con salto de línea. Tras un corte
+            // por drenaje, seguido del evento de error con la pista para reanudar.
+            let tail = match (&mut rewriter, &mut lines) {
+                (Some(rewriter), _) => Some(rewriter.finish()),
+                (None, Some(lines)) => Some(lines.finish()),
+                (None, None) => None,
+            };
+            let ending = terminated.then(|| drain::termination_event(&request_id, framing == StreamFraming::Sse));
+            for tail in tail.into_iter().chain(ending).filter(|tail| !tail.is_empty()) {
+                let size = tail.len().clamp(1, buffer_bytes) as u32;
+                let permit = permits.clone().acquire_many_owned(size).await.expect("semáforo cerrado");
+                let _ = tx.send((Ok(tail), permit));
+            }
+        }
+        if terminated {
+            pump_state.metrics.drain_terminated_streams.fetch_add(1, Ordering::Relaxed);
+            info!("Streaming: [{}] Venció el drenaje del nodo ID {}; stream cortado con pista para reanudar.", request_id, unique_node_id);
+            pump_state.events.publish(BalancerEvent::RequestTerminated {
+                service: service.to_string(),
+                node_id: unique_node_id.to_string(),
+                request_id: request_id.clone(),
+                reason: drain::DRAIN_TIMEOUT,
+                action: "terminated",
+                streamed: true,
+            });
+        }
+        drop(response);
+        let _ = pump_done.set(Instant::now());
+
+        let held_ms = occupied_at.elapsed().as_millis() as u64;
+        pump_state.metrics.stream_node_held_ms.fetch_add(held_ms, Ordering::Relaxed);
+        pump_state.metrics.streamed_responses.fetch_add(1, Ordering::Relaxed);
+        debug!("Streaming: Nodo ID {} liberado tras {}ms.", unique_node_id, held_ms);
+        let violation = check.and_then(|check| check.finish(finished));
+        if let Some(violation) = &violation {
+            pump_state.record_invalid_response(&service, &unique_node_id, strictness, violation);
+        }
+        // Con `--strictness enforce` el stream ya llegó al cliente, pero el nodo no vuelve a la pool.
+        if failed || (violation.is_some() && strictness == Strictness::Enforce) {
+            pump_state.update_node_state(&service, &unique_node_id, NodeHealth::Failed(Instant::now()), TransitionCause::RequestFailure);
+        } else if !terminated && !direct {
+            pipeline::release_node(&pump_state, &service, &unique_node_id, &service_url, pipeline_token.as_ref());
+        }
+        drop(in_flight);
+        drop(client_permit);
+        if let (Some(store), Some(mut record), Some(usage)) = (&pump_state.persistence, audit_record, usage) {
+            let counted = usage.finish();
+            (record.prompt_tokens, record.completion_tokens, record.total_tokens) = counted.tokens;
+            record.estimated = counted.estimated;
+            record.terminated = terminated.then_some(drain::DRAIN_TIMEOUT);
+            if counted.estimated {
+                pump_state.metrics.estimated_usage_streams.fetch_add(1, Ordering::Relaxed);
+                debug!("Streaming: El nodo ID {} no mandó usage; tokens estimados: {:?}.", unique_node_id, counted.tokens);
+            }
+            store.record(&record);
+        }
+        // Las cabeceras ya salieron con 200: un corte a mitad de stream no cambia el estado.
+        pump_state.events.publish(BalancerEvent::RequestCompleted {
+            service: service.into(),
+            node_id: unique_node_id.into(),
+            status: 200,
+            duration_ms: held_ms,
+            streamed: true,
+        });
+    });
+
+    // La plaza de stream viaja con el cuerpo: se libera cuando el cliente termina o se desconecta.
+    let guard = DrainGuard { state, upstream_done };
+    stream::unfold((rx, guard, permit, true), move |(mut rx, guard, permit, at_boundary)| async move {
+        // Sólo se intercala el keep-alive entre eventos, nunca en mitad de uno.
+        let next = match keepalive.filter(|_| at_boundary) {
+            Some(interval) => match timeout(interval, rx.recv()).await {
+                Ok(next) => next,
+                Err(_) => {
+                    guard.state.metrics.sse_keepalives.fetch_add(1, Ordering::Relaxed);
+                    return Some((Ok(Bytes::from_static(SSE_KEEPALIVE)), (rx, guard, permit, true)));
+                }
+            },
+            None => rx.recv().await,
+        };
+        // El permiso se devuelve al sacar el fragmento del buffer.
+        let (chunk, _permit) = next?;
+        let at_boundary = match &chunk {
+            Ok(bytes) if !bytes.is_empty() => ends_event(bytes),
+            _ => at_boundary,
+        };
+        Some((chunk, (rx, guard, permit, at_boundary)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::MessageBody;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::HttpResponse;
+    use futures_util::future::poll_fn;
+    use futures_util::StreamExt;
+    use std::time::Duration;
+    use serde_json::json;
+
+    use crate::balancer;
+    use crate::testing;
+
+    /// Nodo que manda `frames` con `gap` de silencio antes de cada uno salvo el primero.
+    fn slow_node(content_type: &'static str, frames: &'static [&'static str], gap: Duration) -> String {
+        testing::backend(move |cfg| {
+            cfg.default_service(web::to(move || async move {
+                let frames = stream::iter(frames.iter().enumerate()).then(move |(i, frame)| async move {
+                    if i > 0 {
+                        tokio::time::sleep(gap).await;
+                    }
+                    Ok::<_, io::Error>(Bytes::from_static(frame.as_bytes()))
+                });
+                HttpResponse::Ok().content_type(content_type).streaming(frames)
+            }));
+        })
+    }
+
+    /// Hace una petición en streaming a la pool y devuelve la respuesta y cada fragmento con su
+    /// instante de llegada, contado desde el primero.
+    async fn stream_through(state: web::Data<AppState>, pool: &str) -> (actix_web::http::header::HeaderMap, Vec<(Duration, String)>) {
+        let app = init_service(balancer::app(state)).await;
+        let req = TestRequest::post()
+            .uri(&format!("/{}", pool))
+            .set_json(json!({ "model": "m", "stream": true, "messages": [{ "role": "user", "content": "hola" }] }))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert!(resp.status().is_success(), "{}", resp.status());
+        let headers = resp.headers().clone();
+
+        let mut body = Box::pin(resp.into_body());
+        let mut chunks = Vec::new();
+        let mut start = None;
+        while let Some(chunk) = poll_fn(|cx| body.as_mut().poll_next(cx)).await {
+            let at = *start.get_or_insert_with(Instant::now);
+            let chunk = chunk.unwrap_or_else(|_| panic!("el stream falló"));
+            chunks.push((at.elapsed(), String::from_utf8(chunk.to_vec()).unwrap()));
+        }
+        (headers, chunks)
+    }
+
+    const SSE_FRAMES: &[&str] = &[
+        "data: {\"choices\":[{\"delta\":{\"content\":\"ho\"}}]}\n\n",
+        "data: {\"choices\":[{\"delta\":{\"content\":\"la\"}}]}\n\n",
+        "data: [DONE]\n\n",
+    ];
+
+    #[actix_web::test]
+    async fn sse_has_proxy_headers_and_keepalives_on_the_configured_cadence() {
+        let url = slow_node("text/event-stream", SSE_FRAMES, Duration::from_millis(2500));
+        let state = testing::state(&["--sse-keepalive-secs", "1"]);
+        testing::announce(&state, "lmstudio", "box1", &url);
+
+        let (headers, chunks) = stream_through(state.clone(), "lmstudio").await;
+
+        assert_eq!(headers.get("content-type").unwrap(), "text/event-stream");
+        assert_eq!(headers.get("cache-control").unwrap(), "no-cache");
+        assert_eq!(headers.get("x-accel-buffering").unwrap(), "no");
+
+        let events: String = chunks.iter().map(|(_, chunk)| chunk.as_str()).filter(|chunk| *chunk != ": keep-alive\n\n").collect();
+        assert_eq!(events, SSE_FRAMES.concat());
+        // Dos silencios de 2,5 s con keep-alive cada segundo: dos en cada uno, entre eventos.
+        let pattern: Vec<bool> = chunks.iter().map(|(_, chunk)| chunk == ": keep-alive\n\n").collect();
+        assert_eq!(pattern, [false, true, true, false, true, true, false], "{:?}", chunks);
+        let keepalives: Vec<Duration> = chunks.iter().filter(|(_, chunk)| chunk == ": keep-alive\n\n").map(|(at, _)| *at).collect();
+        for (at, expected) in keepalives.iter().zip([1000, 2000, 3500, 4500]) {
+            let expected = Duration::from_millis(expected);
+            assert!(*at >= expected && *at < expected + Duration::from_millis(400), "keep-alive a los {:?}, se esperaba a los {:?}", at, expected);
+        }
+        assert_eq!(state.metrics.sse_keepalives.load(Ordering::Relaxed), 4);
+    }
+
+    #[actix_web::test]
+    async fn ndjson_streams_get_no_keepalives() {
+        let url = slow_node("application/x-ndjson", &["{\"done\":false}\n", "{\"done\":true}\n"], Duration::from_millis(1500));
+        let state = testing::state(&["--sse-keepalive-secs", "1"]);
+        testing::announce(&state, "ollama", "box1", &url);
+
+        let (headers, chunks) = stream_through(state.clone(), "ollama").await;
+
+        assert_eq!(headers.get("x-accel-buffering").unwrap(), "no");
+        let body: String = chunks.iter().map(|(_, chunk)| chunk.as_str()).collect();
+        assert_eq!(body, "{\"done\":false}\n{\"done\":true}\n");
+        assert_eq!(state.metrics.sse_keepalives.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn line_framer_only_releases_complete_lines() {
+        let mut framer = LineFramer::default();
+        assert_eq!(framer.push(b"{\"a\":", 1024), "");
+        assert_eq!(framer.push(b"1}\n{\"b\"", 1024), "{\"a\":1}\n");
+        assert_eq!(framer.push(b":2}\n{\"c\":3}\n{\"d", 1024), "{\"b\":2}\n{\"c\":3}\n");
+        assert_eq!(framer.finish(), "{\"d");
+        assert_eq!(framer.finish(), "");
+    }
+
+    #[test]
+    fn line_framer_gives_up_on_an_endless_line() {
+        let mut framer = LineFramer::default();
+        assert_eq!(framer.push(b"0123", 8), "");
+        assert_eq!(framer.push(b"4567", 8), "01234567");
+        assert_eq!(framer.push(b"89\n", 8), "89\n");
+    }
+
+    /// Nodo que manda `frames` seguidos, con esta `Content-Type`.
+    fn node(content_type: &'static str, frames: &'static [&'static str]) -> String {
+        slow_node(content_type, frames, Duration::ZERO)
+    }
+
+    #[actix_web::test]
+    async fn ndjson_is_relayed_byte_for_byte_in_whole_lines() {
+        // Objetos partidos entre fragmentos y una última línea sin salto.
+        const FRAMES: &[&str] = &["{\"response\":\"ho", "la\",\"done\":false}\n{\"resp", "onse\":\"!\",\"done\":false}\n", "{\"done\":true}"];
+        for content_type in ["application/x-ndjson", "application/x-ndjson; charset=utf-8", "Application/X-NDJSON"] {
+            let state = testing::state(&["--sse-keepalive-secs", "1"]);
+            testing::announce(&state, "ollama", "box1", &node(content_type, FRAMES));
+
+            let (headers, chunks) = stream_through(state, "ollama").await;
+
+            assert_eq!(headers.get("content-type").unwrap(), content_type);
+            let body: String = chunks.iter().map(|(_, chunk)| chunk.as_str()).collect();
+            assert_eq!(body, FRAMES.concat(), "{}", content_type);
+            let (last, lines) = chunks.split_last().unwrap();
+            for (_, chunk) in lines {
+                assert!(chunk.ends_with('\n'), "medio objeto JSON: {:?}", chunk);
+                for line in chunk.lines() {
+                    serde_json::from_str::<serde_json::Value>(line).unwrap();
+                }
+            }
+            assert_eq!(last.1, "{\"done\":true}");
+            assert!(!body.contains("data:"));
+        }
+    }
+
+    #[actix_web::test]
+    async fn sse_is_relayed_byte_for_byte() {
+        for content_type in ["text/event-stream", "text/event-stream; charset=utf-8"] {
+            let state = testing::state(&["--sse-keepalive-secs", "0"]);
+            testing::announce(&state, "lmstudio", "box1", &node(content_type, SSE_FRAMES));
+
+            let (headers, chunks) = stream_through(state, "lmstudio").await;
+
+            assert_eq!(headers.get("content-type").unwrap(), content_type);
+            let body: String = chunks.iter().map(|(_, chunk)| chunk.as_str()).collect();
+            assert_eq!(body, SSE_FRAMES.concat(), "{}", content_type);
+        }
+    }
+}
@@ -0,0 +1,537 @@
+// src/strictness.rs
+//! Validación estricta de peticiones y respuestas por pool (`--strictness <pool>=<modo>`).
+//!
+//! Con `off` (por defecto) el balanceador sólo exige lo que necesita para repartir (`model` y
+//! `messages` bien formados) y reenvía el resto tal cual. Con `warn` o `enforce` comprueba además:
+//!
+//! - en la petición, que no traiga campos de primer nivel desconocidos: los de la API de chat de
+//!   OpenAI más los propios del backend de la pool (`/api/chat` en `ollama`);
+//! - en una respuesta con éxito sin streaming, que traiga el mensaje donde lo espera el cliente:
+//!   `choices[0].message` en la API de OpenAI y `message` en la nativa de Ollama (`/api/...`);
+//! - en streaming, los eventos según pasan, sin retenerlos: `choices` en SSE y `done` en NDJSON,
+//!   o un `error`. Es oportunista: en SSE sólo se miran las líneas `data:` (ni comentarios ni
+//!   `[DONE]`) y ninguna línea de más de 1 MiB; se avisa una vez por stream.
+//!
+//! `warn` avisa en el log y cuenta: las peticiones en `lmserver_strict_request_violations_total`
+//! y las respuestas por nodo, como error `invalid_response` de `lmserver_upstream_errors_total`.
+//! `enforce` además rechaza la petición con 400 y la respuesta con 502, y marca Failed al nodo que
+//! la dio. Un stream ya ha enviado sus cabeceras, así que llega entero al cliente; al terminar, el
+//! nodo se marca Failed en lugar de volver a la pool.
+//!
+//! El modo es un ajuste de los perfiles, así que cambia con su horario y con `POST /admin/reload`
+//! y aparece en `settings` de `/config`:
+//!
+//! ```toml
+//! [profiles.night]
+//! active = "22:00-07:00"
+//! strictness = { ollama = "enforce" }
+//! ```
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fmt;
+
+/// Campos de primer nivel de la API de chat de OpenAI.
+const OPENAI_FIELDS: &[&str] = &[
+    "model",
+    "messages",
+    "temperature",
+    "top_p",
+    "n",
+    "stream",
+    "stream_options",
+    "stop",
+    "max_tokens",
+    "max_completion_tokens",
+    "presence_penalty",
+    "frequency_penalty",
+    "logit_bias",
+    "logprobs",
+    "top_logprobs",
+    "user",
+    "tools",
+    "tool_choice",
+    "parallel_tool_calls",
+    "response_format",
+    "seed",
+    "service_tier",
+    "store",
+    "metadata",
+    "modalities",
+    "audio",
+    "prediction",
+    "reasoning_effort",
+    "web_search_options",
+    "functions",
+    "function_call",
+];
+
+/// Extensiones que LM Studio admite en su API compatible con OpenAI.
+const LMSTUDIO_FIELDS: &[&str] = &["ttl", "draft_model", "top_k", "min_p", "repeat_penalty"];
+
+/// Campos propios de `/api/chat` de Ollama; el resto coinciden con los de OpenAI.
+const OLLAMA_FIELDS: &[&str] = &["format", "options", "keep_alive", "think"];
+
+/// Línea más larga que se examina en un stream; una mayor no se comprueba.
+const MAX_LINE_BYTES: usize = 1024 * 1024;
+
+#[derive(Debug)]
+pub struct StrictnessConfigError(String);
+
+impl fmt::Display for StrictnessConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for StrictnessConfigError {}
+
+/// Qué se hace con una petición o una respuesta que no sigue el formato esperado.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Strictness {
+    #[default]
+    Off,
+    Warn,
+    Enforce,
+}
+
+impl Strictness {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "off" => Some(Strictness::Off),
+            "warn" => Some(Strictness::Warn),
+            "enforce" => Some(Strictness::Enforce),
+            _ => None,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Strictness::Off => "off",
+            Strictness::Warn => "warn",
+            Strictness::Enforce => "enforce",
+        }
+    }
+}
+
+/// Modo de cada pool. Es parte de `RuntimeSettings`.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct PoolStrictness {
+    pub lmstudio: Strictness,
+    pub ollama: Strictness,
+}
+
+/// Modos que cambia un perfil; la pool que no aparece se queda con el de la línea de comandos.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StrictnessOverrides {
+    lmstudio: Option<Strictness>,
+    ollama: Option<Strictness>,
+}
+
+impl PoolStrictness {
+    /// Interpreta entradas `pool=modo` de la línea de comandos; un `modo` solo vale para todas.
+    pub fn new(entries: &[String]) -> Result<Self, StrictnessConfigError> {
+        let mut strictness = Self::default();
+        for entry in entries {
+            let (pool, mode) = match entry.split_once('=') {
+                Some((pool, mode)) => (Some(pool.trim()), mode.trim()),
+                None => (None, entry.trim()),
+            };
+            let Some(mode) = Strictness::parse(mode) else {
+                return Err(StrictnessConfigError(format!("--strictness inválido '{}': el modo debe ser off, warn o enforce", entry)));
+            };
+            match pool {
+                None => strictness = Self { lmstudio: mode, ollama: mode },
+                Some("lmstudio") => strictness.lmstudio = mode,
+                Some("ollama") => strictness.ollama = mode,
+                Some(pool) => return Err(StrictnessConfigError(format!("--strictness inválido '{}': pool desconocida '{}'", entry, pool))),
+            }
+        }
+        Ok(strictness)
+    }
+
+    /// Modo de la pool; una pool desconocida no se comprueba.
+    pub fn of(&self, service: &str) -> Strictness {
+        match service {
+            "lmstudio" => self.lmstudio,
+            "ollama" => self.ollama,
+            _ => Strictness::Off,
+        }
+    }
+
+    /// Estos modos con los cambios de un perfil.
+    pub fn with(self, overrides: &StrictnessOverrides) -> Self {
+        Self { lmstudio: overrides.lmstudio.unwrap_or(self.lmstudio), ollama: overrides.ollama.unwrap_or(self.ollama) }
+    }
+}
+
+/// Campos de primer nivel de la petición que no conoce ninguna API de la pool.
+pub fn unknown_fields<'a>(service: &str, extra: &'a Map<String, Value>) -> Vec<&'a str> {
+    let backend = match service {
+        "lmstudio" => LMSTUDIO_FIELDS,
+        "ollama" => OLLAMA_FIELDS,
+        _ => &[],
+    };
+    extra.keys().map(String::as_str).filter(|field| !OPENAI_FIELDS.contains(field) && !backend.contains(field)).collect()
+}
+
+/// Comprueba una respuesta con éxito sin streaming; `native` si salió de una ruta nativa de Ollama.
+pub fn check_response(body: &[u8], native: bool) -> Result<(), String> {
+    let json: Value = serde_json::from_slice(body).map_err(|e| format!("el cuerpo no es JSON válido: {}", e))?;
+    let (pointer, field) = if native { ("/message", "message") } else { ("/choices/0/message", "choices[0].message") };
+    if !json.pointer(pointer).is_some_and(Value::is_object) {
+        return Err(format!("falta el objeto {}", field));
+    }
+    Ok(())
+}
+
+/// Comprueba un evento de un stream: SSE (`data: {...}`) o NDJSON (`{...}`) si `native`.
+fn check_event(line: &[u8], native: bool) -> Result<(), String> {
+    let line = line.trim_ascii();
+    let payload = if native {
+        line
+    } else {
+        match line.strip_prefix(b"data:") {
+            Some(payload) => payload.trim_ascii(),
+            None => return Ok(()),
+        }
+    };
+    if payload.is_empty() || payload == b"[DONE]" {
+        return Ok(());
+    }
+    let json: Value = serde_json::from_slice(payload).map_err(|e| format!("un evento no es JSON válido: {}", e))?;
+    if json.get("error").is_some() {
+        return Ok(());
+    }
+    let (valid, field) = if native {
+        (json.get("done").is_some_and(Value::is_boolean), "done")
+    } else {
+        (json.get("choices").is_some_and(Value::is_array), "choices")
+    };
+    if !valid {
+        return Err(format!("un evento no trae {}", field));
+    }
+    Ok(())
+}
+
+/// Comprobación de los eventos de un stream según pasan. Se queda con la primera infracción.
+pub struct StreamCheck {
+    native: bool,
+    /// Línea aún sin terminar.
+    line: Vec<u8>,
+    violation: Option<String>,
+}
+
+impl StreamCheck {
+    pub fn new(native: bool) -> Self {
+        Self { native, line: Vec::new(), violation: None }
+    }
+
+    /// Examina un fragmento tal como llega del nodo.
+    pub fn push(&mut self, chunk: &[u8]) {
+        if self.violation.is_some() {
+            return;
+        }
+        let mut rest = chunk;
+        while let Some(end) = rest.iter().position(|byte| *byte == b'\n') {
+            if self.line.len() + end <= MAX_LINE_BYTES {
+                self.line.extend_from_slice(&rest[..end]);
+                let line = std::mem::take(&mut self.line);
+                self.event(&line);
+            }
+            self.line.clear();
+            rest = &rest[end + 1..];
+        }
+        if self.line.len() + rest.len() <= MAX_LINE_BYTES {
+            self.line.extend_from_slice(rest);
+        }
+    }
+
+    fn event(&mut self, line: &[u8]) {
+        if self.violation.is_none() {
+            self.violation = check_event(line, self.native).err();
+        }
+    }
+
+    /// Primera infracción del stream. `complete` si el nodo lo terminó: entonces también se mira
+    /// la última línea, que puede llegar sin salto de línea.
+    pub fn finish(mut self, complete: bool) -> Option<String> {
+        if complete {
+            let line = std::mem::take(&mut self.line);
+            self.event(&line);
+        }
+        self.violation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body, read_body_json, TestRequest};
+    use actix_web::{web, HttpResponse};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use crate::balancer::{self, AppState, OLLAMA};
+    use crate::testing;
+
+    #[test]
+    fn modes_are_read_per_pool_or_for_all() {
+        let modes = |entries: &[&str]| {
+            let strictness = PoolStrictness::new(&entries.iter().map(|entry| entry.to_string()).collect::<Vec<_>>()).unwrap();
+            (strictness.lmstudio, strictness.ollama)
+        };
+        assert_eq!(modes(&[]), (Strictness::Off, Strictness::Off));
+        assert_eq!(modes(&["warn"]), (Strictness::Warn, Strictness::Warn));
+        assert_eq!(modes(&["enforce", "ollama = off"]), (Strictness::Enforce, Strictness::Off));
+        assert_eq!(modes(&["ollama=enforce", "warn"]), (Strictness::Warn, Strictness::Warn));
+        for bad in ["strict", "ollama=", "vllm=warn"] {
+            assert!(PoolStrictness::new(&[bad.to_string()]).is_err(), "{}", bad);
+        }
+
+        let strictness = PoolStrictness::new(&["lmstudio=warn".to_string()]).unwrap();
+        assert_eq!(strictness.of("lmstudio"), Strictness::Warn);
+        assert_eq!(strictness.of("vllm"), Strictness::Off);
+        let overrides: StrictnessOverrides = serde_json::from_value(json!({ "ollama": "enforce" })).unwrap();
+        let changed = strictness.with(&overrides);
+        assert_eq!((changed.lmstudio, changed.ollama), (Strictness::Warn, Strictness::Enforce));
+        assert!(serde_json::from_value::<StrictnessOverrides>(json!({ "vllm": "warn" })).is_err());
+    }
+
+    #[test]
+    fn unknown_fields_depend_on_the_pool() {
+        let extra: Map<String, Value> = serde_json::from_value(json!({ "temperature": 0.2, "ttl": 60, "keep_alive": "5m", "frobnicate": 1 })).unwrap();
+        let sorted = |service| {
+            let mut fields = unknown_fields(service, &extra);
+            fields.sort();
+            fields
+        };
+        assert_eq!(sorted("lmstudio"), ["frobnicate", "keep_alive"]);
+        assert_eq!(sorted("ollama"), ["frobnicate", "ttl"]);
+    }
+
+    #[test]
+    fn responses_must_carry_the_message_where_the_client_expects_it() {
+        assert!(check_response(br#"{"choices":[{"message":{"role":"assistant","content":"hola"}}]}"#, false).is_ok());
+        assert!(check_response(br#"{"choices":[{"text":"hola"}]}"#, false).unwrap_err().contains("choices[0].message"));
+        assert!(check_response(br#"{"choices":[]}"#, false).is_err());
+        assert!(check_response(b"hola", false).unwrap_err().contains("JSON"));
+        assert!(check_response(br#"{"message":{"role":"assistant","content":"hola"},"done":true}"#, true).is_ok());
+        assert!(check_response(br#"{"choices":[{"message":{}}]}"#, true).unwrap_err().contains("message"));
+    }
+
+    fn stream_violation(native: bool, chunks: &[&[u8]], complete: bool) -> Option<String> {
+        let mut check = StreamCheck::new(native);
+        for chunk in chunks {
+            check.push(chunk);
+        }
+        check.finish(complete)
+    }
+
+    #[test]
+    fn streams_are_checked_event_by_event() {
+        // SSE: sólo cuentan las líneas `data:`, aunque lleguen partidas.
+        let valid: &[&[u8]] = &[b": keepalive\n\ndata: {\"choi", b"ces\":[]}\n\nevent: x\ndata: {\"error\":{\"message\":\"no\"}}\n\n", b"data: [DONE]\n\n"];
+        assert_eq!(stream_violation(false, valid, true), None);
+        let invalid: &[&[u8]] = &[b"data: {\"choices\":[]}\n\n", b"data: {\"text\":\"hola\"}\n\n", b"data: no es json\n\n"];
+        assert!(stream_violation(false, invalid, true).unwrap().contains("choices"));
+        assert!(stream_violation(false, &[b"data: {nope}\n\n"], true).unwrap().contains("JSON"));
+
+        // NDJSON: la última línea sin salto sólo se mira si el nodo terminó el stream.
+        let native: &[&[u8]] = &[b"{\"message\":{},\"done\":false}\n", b"{\"message\":{}}"];
+        assert_eq!(stream_violation(true, native, false), None);
+        assert!(stream_violation(true, native, true).unwrap().contains("done"));
+        assert_eq!(stream_violation(true, &[b"{\"done\":false}\n{\"done\":true}"], true), None);
+
+        // Una línea de más de 1 MiB no se examina; la siguiente sí.
+        let long = format!("data: {{\"text\":\"{}\"}}\n", "x".repeat(MAX_LINE_BYTES));
+        assert_eq!(stream_violation(false, &[long.as_bytes()], true), None);
+        assert!(stream_violation(false, &[long.as_bytes(), b"data: {}\n"], true).is_some());
+    }
+
+    const COMPLIANT: &str = r#"{"choices":[{"message":{"role":"assistant","content":"hola"}}]}"#;
+    const NON_COMPLIANT: &str = r#"{"choices":[{"text":"hola"}]}"#;
+
+    /// Nodo que contesta siempre `body` con este tipo de contenido; cuenta las peticiones.
+    fn node(content_type: &'static str, body: &'static str) -> (String, Arc<AtomicUsize>) {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        let url = testing::backend(move |cfg| {
+            let counter = counter.clone();
+            cfg.default_service(web::to(move || {
+                counter.fetch_add(1, Ordering::Relaxed);
+                async move { HttpResponse::Ok().content_type(content_type).body(body) }
+            }));
+        });
+        (url, hits)
+    }
+
+    /// Valor de una serie de un contador en el texto de `/metrics`; 0 si no aparece.
+    fn counter(state: &AppState, series: &str) -> u64 {
+        state.metrics.render().lines().find_map(|line| line.strip_prefix(series)?.trim().parse().ok()).unwrap_or(0)
+    }
+
+    fn invalid_responses(state: &AppState, node: &str) -> u64 {
+        counter(state, &format!("lmserver_upstream_errors_total{{node=\"{}\",category=\"invalid_response\"}}", node))
+    }
+
+    fn chat(pool: &str, extra: Value) -> TestRequest {
+        testing::chat_with(extra).uri(&format!("/{}", pool))
+    }
+
+    #[actix_web::test]
+    async fn requests_with_unknown_fields_in_each_mode() {
+        for (mode, expected) in [("off", 200), ("warn", 200), ("enforce", 400)] {
+            let state = testing::state(&["--strictness", &format!("lmstudio={}", mode)]);
+            let (url, hits) = node("application/json", COMPLIANT);
+            testing::announce(&state, "lmstudio", "box1", &url);
+            let app = init_service(balancer::app(state.clone())).await;
+
+            // Las extensiones de LM Studio son campos conocidos en cualquier modo.
+            let res = call_service(&app, chat("lmstudio", json!({ "ttl": 60, "temperature": 0.2 })).to_request()).await;
+            assert_eq!(res.status(), 200, "{}", mode);
+
+            let res = call_service(&app, chat("lmstudio", json!({ "frobnicate": 1, "keep_alive": "5m" })).to_request()).await;
+            assert_eq!(res.status(), expected, "{}", mode);
+            let violations = counter(&state, "lmserver_strict_request_violations_total");
+            assert_eq!(violations, if mode == "off" { 0 } else { 1 }, "{}", mode);
+            if mode == "enforce" {
+                let body: Value = read_body_json(res).await;
+                assert_eq!(body["error"]["type"], "invalid_request_error");
+                assert_eq!(body["error"]["param"], "frobnicate");
+                assert!(body["error"]["message"].as_str().unwrap().contains("frobnicate, keep_alive"), "{}", body);
+                // La petición rechazada no llegó al nodo.
+                assert_eq!(hits.load(Ordering::Relaxed), 1);
+            } else {
+                assert_eq!(hits.load(Ordering::Relaxed), 2, "{}", mode);
+            }
+            assert_eq!(testing::node_state(&state, "lmstudio", "box1"), "available", "{}", mode);
+        }
+    }
+
+    #[actix_web::test]
+    async fn responses_in_each_mode_from_compliant_and_non_compliant_nodes() {
+        for mode in ["off", "warn", "enforce"] {
+            for (body, compliant) in [(COMPLIANT, true), (NON_COMPLIANT, false)] {
+                let state = testing::state(&["--strictness", &format!("lmstudio={}", mode)]);
+                let (url, _) = node("application/json", body);
+                testing::announce(&state, "lmstudio", "box1", &url);
+                let app = init_service(balancer::app(state.clone())).await;
+                let res = call_service(&app, chat("lmstudio", json!({})).to_request()).await;
+                let context = format!("{} {}", mode, if compliant { "compliant" } else { "non-compliant" });
+
+                let flagged = !compliant && mode != "off";
+                assert_eq!(invalid_responses(&state, "box1"), u64::from(flagged), "{}", context);
+                if flagged && mode == "enforce" {
+                    assert_eq!(res.status(), 502, "{}", context);
+                    let error: Value = read_body_json(res).await;
+                    assert_eq!(error["error"]["type"], "invalid_response");
+                    assert!(error["error"]["message"].as_str().unwrap().contains("choices[0].message"), "{}", error);
+                    assert_eq!(testing::node_state(&state, "lmstudio", "box1"), "failed");
+                } else {
+                    // Sin enforce la respuesta llega tal cual, y el nodo sigue en la pool.
+                    assert_eq!(res.status(), 200, "{}", context);
+                    assert_eq!(read_body(res).await, body.as_bytes(), "{}", context);
+                    assert_eq!(testing::node_state(&state, "lmstudio", "box1"), "available", "{}", context);
+                }
+                let last_error = state.pool("lmstudio").unwrap().read().unwrap().get("box1").unwrap().last_error.clone();
+                assert_eq!(last_error.is_some_and(|error| error.starts_with("invalid_response")), flagged, "{}", context);
+            }
+        }
+    }
+
+    const SSE_COMPLIANT: &str = "data: {\"choices\":[{\"delta\":{\"content\":\"hola\"}}]}\n\ndata: [DONE]\n\n";
+    const SSE_NON_COMPLIANT: &str = "data: {\"choices\":[{\"delta\":{\"content\":\"ho\"}}]}\n\ndata: {\"text\":\"la\"}\n\ndata: [DONE]\n\n";
+
+    #[actix_web::test]
+    async fn streams_in_each_mode_reach_the_client_whole() {
+        for mode in ["off", "warn", "enforce"] {
+            for (frames, compliant) in [(SSE_COMPLIANT, true), (SSE_NON_COMPLIANT, false)] {
+                let state = testing::state(&["--strictness", &format!("lmstudio={}", mode)]);
+                let (url, _) = node("text/event-stream", frames);
+                testing::announce(&state, "lmstudio", "box1", &url);
+                let app = init_service(balancer::app(state.clone())).await;
+                let res = call_service(&app, chat("lmstudio", json!({ "stream": true })).to_request()).await;
+                let context = format!("{} {}", mode, if compliant { "compliant" } else { "non-compliant" });
+                assert_eq!(res.status(), 200, "{}", context);
+                let body = read_body(res).await;
+                assert!(String::from_utf8_lossy(&body).contains("data: [DONE]"), "{}", context);
+
+                // El stream ya se envió entero; con enforce el nodo no vuelve a la pool al terminar.
+                let flagged = !compliant && mode != "off";
+                let expected = if flagged && mode == "enforce" { "failed" } else { "available" };
+                testing::eventually(|| testing::node_state(&state, "lmstudio", "box1") == expected).await;
+                assert_eq!(invalid_responses(&state, "box1"), u64::from(flagged), "{}", context);
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn native_ollama_responses_are_checked_in_their_own_format() {
+        let state = testing::state(&["--strictness", "ollama=enforce"]);
+        let (good, _) = node("application/json", r#"{"message":{"role":"assistant","content":"hola"},"done":true}"#);
+        let (bad, _) = node("application/json", COMPLIANT);
+        let (good_stream, _) = node("application/x-ndjson", "{\"message\":{\"content\":\"ho\"},\"done\":false}\n{\"message\":{\"content\":\"la\"},\"done\":true}\n");
+        let (bad_stream, _) = node("application/x-ndjson", "{\"message\":{\"content\":\"ho\"},\"done\":false}\n{\"message\":{\"content\":\"la\"}}\n");
+        let app = init_service(balancer::app(state.clone())).await;
+        for (id, url, stream, valid) in [("good", good, false, true), ("bad", bad, false, false), ("good-stream", good_stream, true, true), ("bad-stream", bad_stream, true, false)] {
+            testing::announce(&state, "ollama", id, &format!("{}api/chat", url));
+            // Los campos propios de `/api/chat` se admiten con enforce.
+            let res = call_service(&app, chat("ollama", json!({ "stream": stream, "keep_alive": "5m" })).to_request()).await;
+            assert_eq!(res.status(), if valid || stream { 200 } else { 502 }, "{}", id);
+            read_body(res).await;
+            let expected = if valid { "available" } else { "failed" };
+            testing::eventually(|| testing::node_state(&state, "ollama", id) == expected).await;
+            assert_eq!(invalid_responses(&state, id), u64::from(!valid), "{}", id);
+            state.deregister_node(&OLLAMA, &testing::node_id(id));
+        }
+    }
+
+    fn write_profiles(path: &std::path::Path, strictness: &str) {
+        std::fs::write(path, format!("[profiles.test]\nactive = \"00:00-00:01\"\n{}", strictness)).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn profiles_change_the_mode_on_reload() {
+        let profiles = testing::temp_file("profiles.toml", "");
+        write_profiles(&profiles, "");
+        let state = testing::state(&["--strictness", "lmstudio=warn", "--profiles-file", profiles.to_str().unwrap(), "--admin-token", "secreto"]);
+        state.profiles.pin("test").unwrap();
+        let (url, _) = node("application/json", COMPLIANT);
+        testing::announce(&state, "lmstudio", "box1", &url);
+        let app = init_service(balancer::app(state.clone())).await;
+        let modes = || {
+            let app = &app;
+            async move {
+                let config: Value = read_body_json(call_service(app, TestRequest::get().uri("/config").to_request()).await).await;
+                config["settings"]["strictness"].clone()
+            }
+        };
+        let unknown = || {
+            let app = &app;
+            async move { call_service(app, chat("lmstudio", json!({ "frobnicate": 1 })).to_request()).await.status() }
+        };
+        let admin = |uri: &str| TestRequest::post().uri(uri).insert_header(("Authorization", "Bearer secreto")).to_request();
+
+        // Sin `strictness` en el perfil manda la línea de comandos.
+        assert_eq!(modes().await, json!({ "lmstudio": "warn", "ollama": "off" }));
+        assert_eq!(unknown().await, 200);
+
+        write_profiles(&profiles, "strictness = { lmstudio = \"enforce\" }\n");
+        assert_eq!(call_service(&app, admin("/admin/reload")).await.status(), 200);
+        assert_eq!(modes().await, json!({ "lmstudio": "enforce", "ollama": "off" }));
+        assert_eq!(unknown().await, 400);
+
+        // Un modo desconocido no se aplica.
+        write_profiles(&profiles, "strictness = { lmstudio = \"strict\" }\n");
+        assert_eq!(call_service(&app, admin("/admin/reload")).await.status(), 400);
+        assert_eq!(modes().await, json!({ "lmstudio": "enforce", "ollama": "off" }));
+
+        assert_eq!(call_service(&app, admin("/admin/config/rollback")).await.status(), 200);
+        assert_eq!(modes().await, json!({ "lmstudio": "warn", "ollama": "off" }));
+        assert_eq!(unknown().await, 200);
+        assert_eq!(counter(&state, "lmserver_strict_request_violations_total"), 3);
+    }
+}
@@ -0,0 +1,297 @@
+// src/support.rs
+//! Paquete de soporte (`GET /admin/support-bundle`).
+//!
+//! Reúne en una sola descarga lo que suele pedirse para investigar un problema: la configuración
+//! resuelta con los secretos enmascarados, el registro con sus lápidas, las últimas peticiones de
+//! inferencia, los últimos eventos del bus, los contadores de los listeners de descubrimiento,
+//! `/stats`, `/debug/memory` y la versión. Lleva `schema_version` y `generated_at` arriba del
+//! todo. Con `?format=zip` (feature `archive`) es un zip con un JSON por sección y un
+//! `manifest.json`.
+//!
+//! De las peticiones sólo se guarda el resumen (pool, modelo, tamaño, estado, nodo y duración
+//! hasta las cabeceras) y la API key enmascarada; nunca el cuerpo. Las dos colas se acotan con
+//! `recent_requests` y `recent_events` en `[limits]`.
+//!
+//! Se genera fuera de los workers (`web::block`), con un plazo de `GENERATION_TIMEOUT`. Si el
+//! JSON pasa de `MAX_BUNDLE_BYTES` se recorta a la mitad la lista más larga (peticiones, eventos o
+//! lápidas, siempre las más antiguas) hasta que quepa; lo recortado aparece en `truncated`.
+use actix_web::http::header;
+use actix_web::{get, web, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::mem::size_of;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::audit::mask_api_key;
+use crate::balancer::{bearer_token, config_snapshot, node_list, AppState};
+use crate::events::BalancerEvent;
+use crate::limits::{self, StoreUsage};
+use crate::{build_info, reload, sessions, spill, stats};
+
+/// Versión del esquema del paquete. Sólo se incrementa ante cambios incompatibles.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Tamaño máximo del JSON del paquete, antes de comprimir.
+pub const MAX_BUNDLE_BYTES: usize = 8 * 1024 * 1024;
+
+/// Plazo para generarlo.
+pub const GENERATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Listas que se recortan, por orden de preferencia ante un empate.
+const TRIMMABLE: &[&str] = &["/recent_requests", "/events", "/registry/removed"];
+
+/// Secciones del paquete, en el orden en que se escriben en el zip.
+#[cfg(feature = "archive")]
+const SECTIONS: &[&str] = &["build", "config", "registry", "recent_requests", "events", "stats", "discovery", "memory"];
+
+/// Bytes aproximados de una entrada para `/debug/memory`.
+pub trait Footprint {
+    fn footprint(&self) -> usize;
+}
+
+/// Cola de las últimas `cap` entradas; llena, se olvida la más antigua.
+pub struct Ring<T> {
+    cap: usize,
+    entries: Mutex<VecDeque<T>>,
+}
+
+impl<T: Clone + Footprint> Ring<T> {
+    pub fn new(cap: usize) -> Self {
+        Self { cap, entries: Mutex::new(VecDeque::new()) }
+    }
+
+    pub fn record(&self, entry: T) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.cap {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Entradas, de la más reciente a la más antigua.
+    pub fn list(&self) -> Vec<T> {
+        self.entries.lock().unwrap().iter().rev().cloned().collect()
+    }
+
+    pub fn usage(&self) -> StoreUsage {
+        let entries = self.entries.lock().unwrap();
+        StoreUsage { entries: entries.len(), bytes: entries.iter().map(Footprint::footprint).sum() }
+    }
+}
+
+/// Lo poco que se mira del cuerpo de una petición.
+#[derive(Default, Deserialize)]
+struct Peek {
+    model: Option<String>,
+    stream: Option<bool>,
+}
+
+/// Resumen de una petición de inferencia, sin su contenido.
+#[derive(Clone, Debug, Serialize)]
+pub struct RecentRequest {
+    pub at: DateTime<Utc>,
+    pub request_id: String,
+    /// Pool o alias de la ruta.
+    pub pool: String,
+    pub api_key: Option<String>,
+    pub model: Option<String>,
+    pub stream: bool,
+    pub body_bytes: usize,
+    pub status: u16,
+    pub node_id: Option<String>,
+    pub served_pool: Option<String>,
+    /// Hasta las cabeceras de la respuesta; en streaming no incluye el cuerpo.
+    pub duration_ms: u64,
+    #[serde(skip)]
+    started: Instant,
+}
+
+impl RecentRequest {
+    pub fn start(req: &HttpRequest, request_id: &str, pool: &str, body: &[u8]) -> Self {
+        let peek: Peek = serde_json::from_slice(body).unwrap_or_default();
+        Self {
+            at: Utc::now(),
+            request_id: request_id.to_string(),
+            pool: pool.to_string(),
+            api_key: bearer_token(req).filter(|key| !key.is_empty()).map(mask_api_key),
+            model: peek.model,
+            stream: peek.stream.unwrap_or(false),
+            body_bytes: body.len(),
+            status: 0,
+            node_id: None,
+            served_pool: None,
+            duration_ms: 0,
+            started: Instant::now(),
+        }
+    }
+
+    /// Completa el resumen con las cabeceras de la respuesta.
+    pub fn finish(mut self, response: &HttpResponse) -> Self {
+        let header = |name: &str| response.headers().get(name).and_then(|value| value.to_str().ok()).map(str::to_string);
+        self.status = response.status().as_u16();
+        self.node_id = header(sessions::NODE_ID_HEADER);
+        self.served_pool = header(spill::SERVED_POOL_HEADER);
+        self.duration_ms = self.started.elapsed().as_millis() as u64;
+        self
+    }
+}
+
+impl Footprint for RecentRequest {
+    fn footprint(&self) -> usize {
+        size_of::<Self>()
+            + self.request_id.len()
+            + self.pool.len()
+            + [&self.api_key, &self.model, &self.node_id, &self.served_pool].iter().map(|field| field.as_ref().map_or(0, String::len)).sum::<usize>()
+    }
+}
+
+/// Evento del bus con el instante en que se publicó.
+#[derive(Clone, Debug, Serialize)]
+pub struct RecentEvent {
+    pub at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub event: BalancerEvent,
+}
+
+impl RecentEvent {
+    pub fn new(event: BalancerEvent) -> Self {
+        Self { at: Utc::now(), event }
+    }
+}
+
+impl Footprint for RecentEvent {
+    fn footprint(&self) -> usize {
+        size_of::<Self>() + serde_json::to_string(&self.event).map_or(0, |json| json.len())
+    }
+}
+
+/// Reúne las secciones y las recorta hasta que quepan en `MAX_BUNDLE_BYTES`. Devuelve el
+/// paquete y su JSON.
+fn assemble(state: &AppState) -> Result<(Value, Vec<u8>), String> {
+    let generated_at = Utc::now();
+    let registry = serde_json::to_value(node_list(state, true)).map_err(|e| e.to_string())?;
+    let mut value = json!({
+        "schema_version": SCHEMA_VERSION,
+        "generated_at": generated_at,
+        "uptime_secs": state.started_at.elapsed().as_secs(),
+        "build": build_info::to_json(),
+        "config": reload::mask_secrets(config_snapshot(state)),
+        "registry": registry,
+        "recent_requests": state.recent_requests.list(),
+        "events": state.recent_events.list(),
+        "stats": stats::summary(state),
+        "discovery": state.discovery_listeners.iter().map(|listener| listener.summary()).collect::<Vec<_>>(),
+        "memory": limits::report(state),
+        "truncated": {},
+    });
+    loop {
+        let json = serde_json::to_vec(&value).map_err(|e| e.to_string())?;
+        if json.len() <= MAX_BUNDLE_BYTES {
+            return Ok((value, json));
+        }
+        let longest = TRIMMABLE
+            .iter()
+            .rev()
+            .filter_map(|pointer| Some((*pointer, value.pointer(pointer)?.as_array()?.len())))
+            .filter(|(_, len)| *len > 0)
+            .max_by_key(|(_, len)| *len);
+        let Some((pointer, len)) = longest else {
+            return Err(format!("ocupa {} bytes y no queda nada que recortar (tope {})", json.len(), MAX_BUNDLE_BYTES));
+        };
+        if let Some(list) = value.pointer_mut(pointer).and_then(Value::as_array_mut) {
+            list.truncate(len / 2);
+        }
+        value["truncated"][pointer] = json!(len / 2);
+    }
+}
+
+/// Zip con un JSON por sección y `manifest.json`.
+#[cfg(feature = "archive")]
+fn archive(bundle: &Value) -> Result<Vec<u8>, String> {
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+
+    let options = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    let manifest = json!({
+        "schema_version": SCHEMA_VERSION,
+        "generated_at": bundle["generated_at"],
+        "uptime_secs": bundle["uptime_secs"],
+        "truncated": bundle["truncated"],
+        "sections": SECTIONS.iter().map(|section| format!("{}.json", section)).collect::<Vec<_>>(),
+    });
+    let files = std::iter::once(("manifest", &manifest)).chain(SECTIONS.iter().map(|section| (*section, &bundle[*section])));
+    for (name, content) in files {
+        let content = serde_json::to_vec_pretty(content).map_err(|e| e.to_string())?;
+        zip.start_file(format!("{}.json", name), options).map_err(|e| e.to_string())?;
+        zip.write_all(&content).map_err(|e| e.to_string())?;
+    }
+    Ok(zip.finish().map_err(|e| e.to_string())?.into_inner())
+}
+
+#[cfg(not(feature = "archive"))]
+fn archive(_bundle: &Value) -> Result<Vec<u8>, String> {
+    Err("compilado sin la feature archive".to_string())
+}
+
+#[derive(Deserialize)]
+pub struct SupportBundleQuery {
+    format: Option<String>,
+}
+
+/// `GET /admin/support-bundle[?format=json|zip]`.
+#[get("/admin/support-bundle")]
+async fn support_bundle_handler(state: web::Data<AppState>, query: web::Query<SupportBundleQuery>) -> impl Responder {
+    let zip = match query.format.as_deref() {
+        None | Some("json") => false,
+        Some("zip") if cfg!(feature = "archive") => true,
+        Some("zip") => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": "El formato zip necesita la feature archive; usa format=json.",
+            }));
+        }
+        Some(other) => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": format!("Formato desconocido '{}'. Usa json o zip.", other),
+            }));
+        }
+    };
+
+    let started = Instant::now();
+    let generated = tokio::time::timeout(
+        GENERATION_TIMEOUT,
+        web::block(move || {
+            let (bundle, json) = assemble(&state)?;
+            let body = if zip { archive(&bundle)? } else { json };
+            Ok::<_, String>((bundle, body))
+        }),
+    )
+    .await;
+    let (bundle, body) = match generated {
+        Ok(Ok(Ok(generated))) => generated,
+        Ok(Ok(Err(e))) => {
+            error!("Soporte: No se pudo generar el paquete de soporte: {}", e);
+            return HttpResponse::InternalServerError().json(json!({ "error": format!("No se pudo generar el paquete de soporte: {}", e) }));
+        }
+        Ok(Err(_)) => return HttpResponse::InternalServerError().finish(),
+        Err(_) => {
+            warn!("Soporte: El paquete de soporte no se generó en {}s.", GENERATION_TIMEOUT.as_secs());
+            return HttpResponse::ServiceUnavailable().json(json!({
+                "error": format!("El paquete de soporte no se generó en {}s; inténtalo de nuevo.", GENERATION_TIMEOUT.as_secs()),
+            }));
+        }
+    };
+
+    let (content_type, extension) = if zip { ("application/zip", "zip") } else { ("application/json", "json") };
+    let generated_at = bundle["generated_at"].as_str().and_then(|at| at.parse::<DateTime<Utc>>().ok()).unwrap_or_else(Utc::now);
+    let filename = format!("lmserver-support-{}.{}", generated_at.format("%Y%m%dT%H%M%SZ"), extension);
+    info!("Soporte: Paquete de soporte {} generado en {} ms ({} bytes).", filename, started.elapsed().as_millis(), body.len());
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header((header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename)))
+        .body(body)
+}
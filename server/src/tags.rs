@@ -0,0 +1,219 @@
+// src/tags.rs
+//! Etiquetas `clave=valor` de los nodos y la cabecera `X-Require-Tags`.
+//!
+//! Un nodo anuncia sus etiquetas con `--tag clave=valor` (repetible; datagrama `TAGS`), p.ej.
+//! `quantized=q8` o `region=lab2`. Una petición con `X-Require-Tags: quantized=q8,region=lab2`
+//! sólo va a nodos que tengan todas esas etiquetas con esos valores: el filtro se aplica antes
+//! del criterio de selección, como el del modelo o la capacidad. Si ningún nodo utilizable de
+//! las pools a las que puede ir la petición las tiene todas, libre u ocupado, se responde 503
+//! en el acto con las que no se pueden cumplir, en lugar de esperar en la cola hasta el timeout.
+use actix_web::http::header::HeaderMap;
+use std::collections::BTreeMap;
+
+use crate::domains;
+
+pub const REQUIRE_TAGS_HEADER: &str = "X-Require-Tags";
+
+/// Etiquetas de un nodo por clave.
+pub type NodeTags = BTreeMap<String, String>;
+
+/// Interpreta una etiqueta `clave=valor`. Ni la clave ni el valor pueden estar vacíos ni llevar
+/// comas, espacios o `=`, que romperían el datagrama y la cabecera.
+pub fn parse_tag(entry: &str) -> Result<(String, String), String> {
+    let valid = |part: &str| domains::is_valid_label(part) && !part.contains('=');
+    match entry.trim().split_once('=') {
+        Some((key, value)) if valid(key) && valid(value) => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("etiqueta inválida '{}': se esperaba clave=valor sin comas, espacios ni '=' de más", entry.trim())),
+    }
+}
+
+/// Etiquetas que exige una petición.
+#[derive(Clone, Debug)]
+pub struct TagRequirement(NodeTags);
+
+impl TagRequirement {
+    pub fn new(tags: NodeTags) -> Self {
+        Self(tags)
+    }
+
+    /// Lee `X-Require-Tags`. Sin cabecera, o vacía, no exige nada.
+    pub fn from_headers(headers: &HeaderMap) -> Result<Option<Self>, String> {
+        let Some(value) = headers.get(REQUIRE_TAGS_HEADER) else {
+            return Ok(None);
+        };
+        let value = value.to_str().map_err(|_| "la cabecera no es texto".to_string())?;
+        let mut required = NodeTags::new();
+        for entry in value.split(',').filter(|entry| !entry.trim().is_empty()) {
+            let (key, value) = parse_tag(entry)?;
+            if required.get(&key).is_some_and(|previous| *previous != value) {
+                return Err(format!("la clave '{}' aparece con dos valores", key));
+            }
+            required.insert(key, value);
+        }
+        Ok((!required.is_empty()).then_some(Self(required)))
+    }
+
+    pub fn matches(&self, tags: &NodeTags) -> bool {
+        self.0.iter().all(|(key, value)| tags.get(key) == Some(value))
+    }
+
+    /// Etiquetas exigidas que no tiene ninguno de `nodes`. Vacía si cada una la tiene alguno,
+    /// aunque ninguno las tenga todas a la vez.
+    pub fn unsatisfied<'a>(&self, nodes: impl Iterator<Item = &'a NodeTags> + Clone) -> Vec<String> {
+        self.0
+            .iter()
+            .filter(|(key, value)| !nodes.clone().any(|tags| tags.get(*key) == Some(value)))
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect()
+    }
+
+    pub fn label(&self) -> String {
+        self.0.iter().map(|(key, value)| format!("{}={}", key, value)).collect::<Vec<_>>().join(",")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::web;
+    use futures_util::future::join_all;
+    use serde_json::{json, Value};
+    use std::sync::atomic::Ordering;
+    use std::time::{Duration, Instant};
+
+    use crate::balancer::{self, AppState, NodeHealth, LMSTUDIO};
+    use crate::discovery::{parse_tags_message, tags_message};
+    use crate::history::TransitionCause;
+    use crate::sessions::NODE_ID_HEADER;
+    use crate::testing;
+
+    fn tags(entries: &[&str]) -> NodeTags {
+        entries.iter().map(|entry| parse_tag(entry).unwrap()).collect()
+    }
+
+    fn required(header: &str) -> Result<Option<TagRequirement>, String> {
+        TagRequirement::from_headers(TestRequest::default().insert_header((REQUIRE_TAGS_HEADER, header)).to_http_request().headers())
+    }
+
+    #[test]
+    fn tags_are_key_value_without_separators() {
+        assert_eq!(parse_tag(" region=lab2 "), Ok(("region".to_string(), "lab2".to_string())));
+        for entry in ["region", "=lab2", "region=", "region=lab 2", "region=a=b", "re,gion=lab2"] {
+            assert!(parse_tag(entry).is_err(), "{}", entry);
+        }
+        let announced = tags(&["quantized=q8", "region=lab2"]);
+        assert_eq!(parse_tags_message(&tags_message("lmstudio", "box1", &announced)), Some(("lmstudio", "box1", announced)));
+    }
+
+    #[test]
+    fn the_header_is_parsed_into_a_requirement() {
+        assert!(TagRequirement::from_headers(TestRequest::default().to_http_request().headers()).unwrap().is_none());
+        assert!(required(" , ").unwrap().is_none());
+        assert_eq!(required("region=lab2, quantized=q8,").unwrap().unwrap().label(), "quantized=q8,region=lab2");
+        assert!(required("region=lab2,region=lab2").unwrap().is_some());
+        assert!(required("region=lab2,region=lab1").is_err());
+        assert!(required("region").is_err());
+    }
+
+    #[test]
+    fn only_nodes_with_every_tag_match() {
+        let requirement = required("quantized=q8,region=lab2").unwrap().unwrap();
+        assert!(requirement.matches(&tags(&["quantized=q8", "region=lab2", "gpu=h100"])));
+        assert!(!requirement.matches(&tags(&["quantized=q8"])));
+        assert!(!requirement.matches(&tags(&["quantized=q4", "region=lab2"])));
+        assert!(!requirement.matches(&NodeTags::new()));
+
+        // Cada etiqueta la tiene algún nodo, aunque ninguno las tenga todas.
+        let (q8, lab2) = (tags(&["quantized=q8"]), tags(&["region=lab2"]));
+        assert!(requirement.unsatisfied([&q8, &lab2].into_iter()).is_empty());
+        assert_eq!(requirement.unsatisfied([&q8].into_iter()), ["region=lab2"]);
+        assert_eq!(requirement.unsatisfied(std::iter::empty()), ["quantized=q8", "region=lab2"]);
+    }
+
+    /// `q8` (quantized=q8, region=lab2), `lab1` (region=lab1) y `plain`, sin etiquetas; por ID,
+    /// `lab1` y `plain` van antes que `q8`.
+    fn tagged_pool(delay: Duration) -> web::Data<AppState> {
+        let state = testing::state(&["--node-selection", "first-available"]);
+        for (id, node_tags) in [("q8", &["quantized=q8", "region=lab2"][..]), ("lab1", &["region=lab1"]), ("plain", &[])] {
+            testing::announce(&state, "lmstudio", id, &testing::chat_node(delay));
+            state.set_node_tags(&LMSTUDIO, &testing::node_id(id), tags(node_tags));
+        }
+        state
+    }
+
+    #[actix_web::test]
+    async fn requests_go_only_to_nodes_with_the_required_tags() {
+        let state = tagged_pool(Duration::ZERO);
+        let app = init_service(balancer::app(state.clone())).await;
+        let served_by = |required: &'static str| {
+            let app = &app;
+            async move {
+                let res = call_service(app, testing::chat().insert_header((REQUIRE_TAGS_HEADER, required)).to_request()).await;
+                assert_eq!(res.status(), 200);
+                res.headers().get(NODE_ID_HEADER).unwrap().to_str().unwrap().to_string()
+            }
+        };
+        for _ in 0..3 {
+            assert_eq!(served_by("quantized=q8").await, "q8");
+            assert_eq!(served_by("region=lab2,quantized=q8").await, "q8");
+            assert_eq!(served_by("region=lab1").await, "lab1");
+        }
+        let res = call_service(&app, TestRequest::post().uri("/lmstudio").set_json(json!({ "messages": [{ "role": "user", "content": "hola" }] })).to_request()).await;
+        assert_eq!(res.headers().get(NODE_ID_HEADER).unwrap(), "lab1", "sin cabecera vale cualquiera");
+        let nodes: Value = read_body_json(call_service(&app, TestRequest::get().uri("/nodes").to_request()).await).await;
+        let q8 = nodes["nodes"].as_array().unwrap().iter().find(|node| node["node_id"] == "q8").unwrap();
+        assert_eq!(q8["tags"], json!({ "quantized": "q8", "region": "lab2" }));
+    }
+
+    #[actix_web::test]
+    async fn unsatisfiable_tags_are_rejected_at_once() {
+        let state = tagged_pool(Duration::ZERO);
+        let app = init_service(balancer::app(state.clone())).await;
+        let rejection = |required: &'static str| {
+            let app = &app;
+            async move {
+                let start = Instant::now();
+                let res = call_service(app, testing::chat().insert_header((REQUIRE_TAGS_HEADER, required)).to_request()).await;
+                assert_eq!(res.status(), 503, "{}", required);
+                assert!(start.elapsed() < Duration::from_millis(500), "{} esperó {:?}", required, start.elapsed());
+                let body: Value = read_body_json(res).await;
+                assert_eq!(body["error"]["type"], "tags_unavailable");
+                body["error"]["message"].as_str().unwrap().to_string()
+            }
+        };
+
+        // Etiqueta desconocida: se nombra.
+        assert!(rejection("gpu=h100").await.contains("gpu=h100"));
+        // Coincidencia parcial: sólo se nombra la que no tiene nadie.
+        let message = rejection("region=lab2,gpu=h100").await;
+        assert!(message.contains("gpu=h100") && !message.contains("region=lab2"), "{}", message);
+        // Cada una la tiene un nodo, pero ninguno las dos.
+        assert!(rejection("region=lab1,quantized=q8").await.contains("a la vez"));
+        // Un nodo caído no cuenta.
+        state.update_node_state(&LMSTUDIO, &testing::node_id("q8"), NodeHealth::Failed(Instant::now()), TransitionCause::HealthCheck);
+        assert!(rejection("quantized=q8").await.contains("quantized=q8"));
+        assert_eq!(state.metrics.rejected_unsatisfied_tags.load(Ordering::Relaxed), 4);
+
+        let res = call_service(&app, testing::chat().insert_header((REQUIRE_TAGS_HEADER, "region")).to_request()).await;
+        assert_eq!(res.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn a_busy_tagged_node_is_waited_for() {
+        let state = tagged_pool(Duration::from_millis(300));
+        let app = init_service(balancer::app(state.clone())).await;
+        let app = &app;
+        let start = Instant::now();
+        let served = join_all((0..2).map(|_| async move {
+            let res = call_service(app, testing::chat().insert_header((REQUIRE_TAGS_HEADER, "quantized=q8")).to_request()).await;
+            assert_eq!(res.status(), 200);
+            (res.headers().get(NODE_ID_HEADER).unwrap().to_str().unwrap().to_string(), start.elapsed())
+        }))
+        .await;
+        // El segundo espera al nodo ocupado en lugar de irse a uno libre sin las etiquetas.
+        assert!(served.iter().all(|(node, _)| node == "q8"), "{:?}", served);
+        assert!(served.iter().any(|(_, elapsed)| *elapsed >= Duration::from_millis(600)), "{:?}", served);
+        assert_eq!(state.metrics.rejected_unsatisfied_tags.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,27 @@
+// src/target.rs
+//! Peticiones dirigidas a un nodo concreto (`X-Target-Node: <id>`), para depurar una máquina.
+//!
+//! La petición espera sólo a ese nodo, en la pool de la ruta que lo tiene, hasta el tiempo de
+//! espera de siempre, sin pasar por el criterio de selección, las sesiones, la afinidad por
+//! usuario, el pipeline ni el cargador de modelos. Lo ocupa y lo libera como cualquier otra, así
+//! que el registro sigue cuadrando, y la respuesta lleva su ID en `X-LMServer-Node-Id`. Los
+//! filtros de la petición (modelo, capacidad, etiquetas, contexto) se siguen aplicando.
+//!
+//! Si ninguna pool de la ruta tiene el nodo se responde 404 con los IDs que sí tienen.
+use actix_web::http::header::HeaderMap;
+
+use crate::ids::NodeId;
+
+pub const TARGET_NODE_HEADER: &str = "X-Target-Node";
+
+/// Lee `X-Target-Node`. Sin cabecera, o vacía, la petición se enruta como siempre.
+pub fn from_headers(headers: &HeaderMap) -> Result<Option<NodeId>, String> {
+    let Some(value) = headers.get(TARGET_NODE_HEADER) else {
+        return Ok(None);
+    };
+    let value = value.to_str().map_err(|_| "la cabecera no es texto".to_string())?.trim();
+    if value.is_empty() {
+        return Ok(None);
+    }
+    NodeId::new(value).map(Some).map_err(|e| e.to_string())
+}
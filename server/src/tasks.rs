@@ -0,0 +1,52 @@
+// src/tasks.rs
+//! Tareas en segundo plano del balanceador y su parada ordenada.
+//!
+//! Cada tarea se cancela con el mismo token: su futuro se suelta en el siguiente punto de
+//! espera, lo que cierra también los recursos que posee (p.ej. el socket UDP). Al parar se
+//! espera a todas, con un límite, para que nada siga vivo cuando `run_balancer` retorna.
+use log::{debug, info, warn};
+use std::future::Future;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::{timeout_at, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// Tiempo máximo que se espera a que terminen las tareas al parar.
+pub const TASK_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+pub struct BackgroundTasks {
+    shutdown: CancellationToken,
+    handles: Vec<(&'static str, JoinHandle<()>)>,
+}
+
+impl BackgroundTasks {
+    /// Arranca `task` y la registra para pararla con el resto.
+    pub fn spawn<F>(&mut self, name: &'static str, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let shutdown = self.shutdown.clone();
+        let handle = tokio::spawn(async move {
+            tokio::select! {
+                _ = shutdown.cancelled() => debug!("Tasks: '{}' cancelada.", name),
+                _ = task => {}
+            }
+        });
+        self.handles.push((name, handle));
+    }
+
+    /// Cancela todas las tareas y espera a que terminen. Las que no lo hagan a tiempo se abortan.
+    pub async fn shutdown(self, limit: Duration) {
+        info!("Tasks: Deteniendo {} tareas en segundo plano...", self.handles.len());
+        self.shutdown.cancel();
+        let deadline = Instant::now() + limit;
+        for (name, mut handle) in self.handles {
+            if timeout_at(deadline, &mut handle).await.is_err() {
+                warn!("Tasks: '{}' no terminó en {:?}; se aborta.", name, limit);
+                handle.abort();
+            }
+        }
+        info!("Tasks: Tareas en segundo plano detenidas.");
+    }
+}
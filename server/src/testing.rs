@@ -0,0 +1,239 @@
+// src/testing.rs
+//! Utilidades de las pruebas: el estado del balanceador armado como lo haría
+//! `load_balancer balancer`, nodos anunciados y backends falsos en un puerto local.
+use actix_web::test::TestRequest;
+use actix_web::{web, App, HttpResponse, HttpServer};
+use clap::Parser;
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::mpsc;
+
+use crate::balancer::{app, build_state, udp_discovery_listener, AppState};
+use crate::cancel;
+use crate::config::BalancerConfig;
+use crate::events;
+use crate::ids::{NodeId, PoolName, ServiceUrl};
+use crate::tasks::BackgroundTasks;
+
+#[derive(Parser)]
+struct Args {
+    #[command(flatten)]
+    config: BalancerConfig,
+}
+
+/// Configuración de `load_balancer balancer` con estos argumentos.
+pub fn config(args: &[&str]) -> BalancerConfig {
+    Args::parse_from(std::iter::once("balancer").chain(args.iter().copied())).config
+}
+
+/// Estado del balanceador con estos argumentos, sin persistencia ni tareas en segundo plano.
+pub fn state(args: &[&str]) -> web::Data<AppState> {
+    web::Data::new(build_state(&config(args), None).expect("configuración de prueba inválida"))
+}
+
+/// Como `state`, pero las peticiones esperan un nodo libre como mucho `secs` segundos en vez
+/// de los 30 por defecto (fija un perfil que sólo cambia eso).
+pub fn state_with_queue_timeout(secs: u64, args: &[&str]) -> web::Data<AppState> {
+    let profiles = temp_file("profiles.toml", &format!("[profiles.test]\nactive = \"00:00-00:01\"\nqueue_timeout_secs = {}\n", secs));
+    let mut args = args.to_vec();
+    args.extend(["--profiles-file", profiles.to_str().unwrap()]);
+    let state = self::state(&args);
+    state.profiles.pin("test").unwrap();
+    state
+}
+
+/// Un directorio temporal propio de la prueba, vacío.
+pub fn temp_dir() -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!("lmserver-test-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed)));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Escribe `contents` en un archivo `name` de un directorio temporal propio y devuelve su ruta.
+pub fn temp_file(name: &str, contents: &str) -> PathBuf {
+    let path = temp_dir().join(name);
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+/// Estado con un listener UDP de descubrimiento en un puerto libre de 127.0.0.1 (`-u`), ya
+/// escuchando, y un socket conectado a él para mandarle anuncios como un nodo.
+pub async fn discovery(args: &[&str]) -> (web::Data<AppState>, UdpSocket) {
+    let addr = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+    let mut args = args.to_vec();
+    args.extend(["-u", addr.as_str()]);
+    let state = self::state(&args);
+    let listener = state.discovery_listeners[0].clone();
+    tokio::spawn(udp_discovery_listener(listener, state.clone(), !config(&args).disable_legacy_discovery));
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.connect(&addr).await.unwrap();
+    // El listener se enlaza en su propia tarea: se espera a que conteste a un anuncio.
+    for _ in 0..100 {
+        socket.send(b"PING").await.unwrap();
+        if packets(&state) > 0 {
+            // Los PING que aún estaban en camino llegan enseguida: se espera a que dejen de contar.
+            let mut seen = packets(&state);
+            loop {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                if packets(&state) == seen {
+                    return (state, socket);
+                }
+                seen = packets(&state);
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("el listener UDP de prueba no arrancó");
+}
+
+fn packets(state: &AppState) -> u64 {
+    state.discovery_listeners[0].summary()["packets"].as_u64().unwrap()
+}
+
+/// Arranca los consumidores del bus de eventos (historial, pools vacías...). Viven lo que el
+/// runtime de la prueba.
+pub fn consumers(state: &web::Data<AppState>) -> BackgroundTasks {
+    let mut tasks = BackgroundTasks::default();
+    events::spawn_consumers(&mut tasks, state);
+    tasks
+}
+
+pub fn node_id(id: &str) -> NodeId {
+    NodeId::new(id).unwrap()
+}
+
+pub fn pool_name(name: &str) -> PoolName {
+    PoolName::new(name).unwrap()
+}
+
+/// Registra (o vuelve a anunciar) un nodo, como al recibir su `DISCOVER`.
+pub fn announce(state: &AppState, service: &str, id: &str, url: &str) {
+    state.register_node(&pool_name(service), &node_id(id), ServiceUrl::parse(url).unwrap()).unwrap();
+}
+
+/// Estado del nodo en su pool (`label` de `NodeHealth`), o "absent" si no está registrado.
+pub fn node_state(state: &AppState, service: &str, id: &str) -> &'static str {
+    state.pool(service).unwrap().read().unwrap().get(id).map_or("absent", |info| info.state.label())
+}
+
+/// Espera, como mucho un segundo, a que se cumpla `done`; para lo que hacen los consumidores del bus.
+pub async fn eventually(mut done: impl FnMut() -> bool) {
+    for _ in 0..100 {
+        if done() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("la condición no se cumplió en un segundo");
+}
+
+/// Levanta un backend falso con estas rutas en un puerto libre de 127.0.0.1 y devuelve su URL.
+/// Vive lo que el runtime de la prueba.
+pub fn backend<F>(routes: F) -> String
+where
+    F: Fn(&mut web::ServiceConfig) + Clone + Send + 'static,
+{
+    let server = HttpServer::new(move || App::new().configure(routes.clone()))
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+    let addr = server.addrs()[0];
+    tokio::spawn(server.run());
+    format!("http://{}/", addr)
+}
+
+/// Petición de chat mínima a la pool `lmstudio`: un único mensaje de usuario.
+pub fn chat() -> TestRequest {
+    chat_with(json!({}))
+}
+
+/// Como `chat`, con estos campos añadidos al cuerpo (o en lugar de los que ya tiene).
+pub fn chat_with(fields: Value) -> TestRequest {
+    let mut body = json!({ "messages": [{ "role": "user", "content": "hola" }] });
+    body.as_object_mut().unwrap().extend(fields.as_object().expect("los campos van en un objeto").clone());
+    TestRequest::post().uri("/lmstudio").set_json(body)
+}
+
+/// La respuesta de chat que da `chat_node`, para los backends que además anotan lo que reciben.
+pub fn chat_reply() -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "choices": [{ "message": { "role": "assistant", "content": "hola" } }] }))
+}
+
+/// Un backend que contesta cualquier petición con `chat_reply` tras esperar `delay`.
+pub fn chat_node(delay: Duration) -> String {
+    backend(move |cfg| {
+        cfg.default_service(web::to(move || async move {
+            tokio::time::sleep(delay).await;
+            chat_reply()
+        }));
+    })
+}
+
+/// Como `chat_node`, y además cuenta las peticiones que recibe.
+pub fn counting_chat_node(delay: Duration) -> (String, Arc<AtomicUsize>) {
+    let hits = Arc::new(AtomicUsize::new(0));
+    let counted = hits.clone();
+    let url = backend(move |cfg| {
+        let hits = hits.clone();
+        cfg.default_service(web::to(move || {
+            hits.fetch_add(1, Ordering::SeqCst);
+            async move {
+                tokio::time::sleep(delay).await;
+                chat_reply()
+            }
+        }));
+    });
+    (url, counted)
+}
+
+/// Una URL en la que no escucha nadie: las peticiones fallan al conectar.
+pub fn unreachable_url() -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    format!("http://{}/", listener.local_addr().unwrap())
+}
+
+/// Levanta el balanceador con este estado en un puerto libre de 127.0.0.1, con la copia del
+/// socket de cada cliente como `load_balancer balancer`, y devuelve su URL.
+pub fn serve(state: web::Data<AppState>) -> String {
+    let server = HttpServer::new(move || app(state.clone()))
+        .on_connect(cancel::on_connect)
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+    let addr = server.addrs()[0];
+    tokio::spawn(server.run());
+    format!("http://{}/", addr)
+}
+
+/// Un backend que acepta peticiones y nunca contesta. Cuando quien llamó cierra la conexión,
+/// manda por el canal lo que recibió de ella (cabeceras y cuerpo).
+pub async fn silent_backend() -> (String, mpsc::UnboundedReceiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    let (closed, closures) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let Ok((mut socket, _)) = listener.accept().await else {
+                return;
+            };
+            let closed = closed.clone();
+            tokio::spawn(async move {
+                let mut received = Vec::new();
+                let mut buf = [0u8; 4096];
+                while let Ok(n @ 1..) = socket.read(&mut buf).await {
+                    received.extend_from_slice(&buf[..n]);
+                }
+                let _ = closed.send(String::from_utf8_lossy(&received).into_owned());
+            });
+        }
+    });
+    (url, closures)
+}
@@ -0,0 +1,125 @@
+// src/tiers.rs
+//! Niveles de nodos (`--node-tier`): los de un nivel se prueban antes que los del siguiente.
+//!
+//! Cada `--node-tier` define un nivel, en orden, con etiquetas `clave=valor` separadas por
+//! comas que el nodo debe cumplir todas. Un nodo está en el primer nivel cuyas etiquetas
+//! cumple; si no cumple ninguno, en uno más tras el último. Las etiquetas salen de la
+//! plataforma que anuncia el nodo (`platform`):
+//!
+//! - `gpu`: `true` si el nodo anuncia un acelerador (`metal`, `cuda`, `rocm`), `false` si no.
+//! - `os`, `arch`, `accelerator`, `memory`: el campo de la plataforma tal cual.
+//!
+//! Por defecto hay un solo nivel, `gpu=true`: los nodos con GPU se prueban primero y un nodo
+//! sin ella sólo recibe una petición cuando todos los que la tienen están ocupados, caídos o no
+//! pueden atenderla. Los nodos que no anuncian `PLATFORM` quedan juntos en el último nivel, así
+//! que en una pool sin esa información nada cambia. `--no-node-tiers` desactiva los niveles.
+//!
+//! Dentro de un nivel manda el criterio de `--node-selection`, y la preferencia de plataforma de
+//! una regla (`prefer`) manda sobre los niveles.
+use serde_json::{json, Value};
+use std::fmt;
+
+use crate::platform::{Platform, UNKNOWN};
+
+pub const DEFAULT_NODE_TIER: &str = "gpu=true";
+
+/// Claves de etiqueta que entiende `--node-tier`.
+const KNOWN_LABELS: &[&str] = &["gpu", "os", "arch", "accelerator", "memory"];
+
+#[derive(Debug)]
+pub struct TierConfigError(String);
+
+impl fmt::Display for TierConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for TierConfigError {}
+
+/// Valor de la etiqueta `key` para un nodo con esta plataforma.
+fn label_value<'a>(platform: &'a Platform, key: &str) -> &'a str {
+    match key {
+        "gpu" if platform.accelerator.eq_ignore_ascii_case(UNKNOWN) => "false",
+        "gpu" => "true",
+        "os" => &platform.os,
+        "arch" => &platform.arch,
+        "accelerator" => &platform.accelerator,
+        _ => &platform.memory,
+    }
+}
+
+#[derive(Default)]
+pub struct NodeTiers {
+    /// Etiquetas `(clave, valor)` de cada nivel, en orden.
+    tiers: Vec<Vec<(&'static str, String)>>,
+}
+
+impl NodeTiers {
+    /// Interpreta las entradas de `--node-tier`, una por nivel.
+    pub fn new(entries: &[String]) -> Result<Self, TierConfigError> {
+        let mut tiers = Vec::new();
+        for entry in entries {
+            let mut labels = Vec::new();
+            for label in entry.split(',').map(str::trim).filter(|label| !label.is_empty()) {
+                let Some((key, value)) = label.split_once('=').map(|(key, value)| (key.trim(), value.trim())) else {
+                    return Err(TierConfigError(format!("Nivel inválido '{}': se esperaba <clave>=<valor>[,<clave>=<valor>...]", entry)));
+                };
+                let Some(key) = KNOWN_LABELS.iter().copied().find(|known| known.eq_ignore_ascii_case(key)) else {
+                    return Err(TierConfigError(format!(
+                        "Etiqueta desconocida '{}' en el nivel '{}': las conocidas son {}",
+                        key,
+                        entry,
+                        KNOWN_LABELS.join(", ")
+                    )));
+                };
+                if value.is_empty() {
+                    return Err(TierConfigError(format!("Nivel inválido '{}': '{}' no tiene valor", entry, key)));
+                }
+                if key == "gpu" && !matches!(value, "true" | "false") {
+                    return Err(TierConfigError(format!("Nivel inválido '{}': gpu debe ser true o false", entry)));
+                }
+                labels.push((key, value.to_string()));
+            }
+            if labels.is_empty() {
+                return Err(TierConfigError("Nivel vacío en --node-tier".to_string()));
+            }
+            tiers.push(labels);
+        }
+        Ok(Self { tiers })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.tiers.is_empty()
+    }
+
+    /// Nivel de un nodo con esta plataforma: 0 es el primero en probarse.
+    pub fn tier_of(&self, platform: &Platform) -> usize {
+        self.tiers
+            .iter()
+            .position(|labels| labels.iter().all(|(key, value)| label_value(platform, key).eq_ignore_ascii_case(value)))
+            .unwrap_or(self.tiers.len())
+    }
+
+    /// Descripción de cada nivel, en orden, con el nivel de los que no cumplen ninguno al final.
+    pub fn legend(&self) -> Vec<String> {
+        if !self.is_enabled() {
+            return Vec::new();
+        }
+        let mut legend: Vec<String> = self
+            .tiers
+            .iter()
+            .map(|labels| labels.iter().map(|(key, value)| format!("{}={}", key, value)).collect::<Vec<_>>().join(","))
+            .collect();
+        legend.push("resto".to_string());
+        legend
+    }
+
+    /// Configuración para `/config`.
+    pub fn describe(&self) -> Value {
+        json!({
+            "enabled": self.is_enabled(),
+            "tiers": self.legend(),
+        })
+    }
+}
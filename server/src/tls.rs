@@ -0,0 +1,44 @@
+// src/tls.rs
+//! Listener HTTPS para los clientes. Con TLS se negocia HTTP/2 por ALPN, de modo que un
+//! cliente puede multiplexar muchas respuestas en streaming sobre una sola conexión.
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+#[derive(Debug)]
+pub struct TlsConfigError(String);
+
+impl fmt::Display for TlsConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for TlsConfigError {}
+
+fn open(path: &Path) -> Result<BufReader<File>, TlsConfigError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| TlsConfigError(format!("No se pudo leer {}: {}", path.display(), e)))
+}
+
+/// Carga la cadena de certificados y la clave privada (PEM). Los protocolos ALPN ("h2" y
+/// "http/1.1") los añade actix al enlazar el listener.
+pub fn load_server_config(cert_path: &Path, key_path: &Path) -> Result<ServerConfig, TlsConfigError> {
+    let certs: Vec<CertificateDer<'static>> = rustls_pemfile::certs(&mut open(cert_path)?)
+        .collect::<Result<_, _>>()
+        .map_err(|e| TlsConfigError(format!("Certificado inválido en {}: {}", cert_path.display(), e)))?;
+    if certs.is_empty() {
+        return Err(TlsConfigError(format!("{} no contiene ningún certificado.", cert_path.display())));
+    }
+    let key: PrivateKeyDer<'static> = rustls_pemfile::private_key(&mut open(key_path)?)
+        .map_err(|e| TlsConfigError(format!("Clave inválida en {}: {}", key_path.display(), e)))?
+        .ok_or_else(|| TlsConfigError(format!("{} no contiene ninguna clave privada.", key_path.display())))?;
+    ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| TlsConfigError(format!("El certificado y la clave no son válidos: {}", e)))
+}
@@ -0,0 +1,372 @@
+// src/tombstones.rs
+//! Lápidas de los nodos que salen del registro.
+//!
+//! Un nodo que se limpia desaparece de `/nodes` sin dejar rastro, y los paneles que lo
+//! consultan pierden el contexto: las gráficas se cortan sin saber por qué. Al salir, cada nodo
+//! deja una lápida con su estado final, sus acumulados (`NodeStats`) y el motivo de la salida,
+//! que `/nodes?include=removed` devuelve durante `--tombstone-retention-secs`. El evento
+//! `node_removed` lleva el `tombstone_id` para cruzarlo con la lápida.
+//!
+//! Las lápidas viven fuera de las pools: nunca se eligen para atender peticiones. Si el mismo ID
+//! vuelve a registrarse en la pool, su lápida se borra y, con `--restore-node-stats`, el nodo
+//! recupera los acumulados que tenía. Pasada la retención, la tarea de limpieza las purga.
+//!
+//! `POST /nodes/{id}/remove` saca a mano un nodo que no está atendiendo una petición. Un nodo
+//! anunciado que sigue vivo vuelve con su siguiente anuncio.
+use actix_web::{post, web, HttpResponse, Responder};
+use chrono::{DateTime, Local};
+use log::{debug, info};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::balancer::{AppState, NodeHealth, NodeInfo};
+use crate::history::TransitionCause;
+use crate::ids::NodeId;
+use crate::limits::StoreUsage;
+
+pub const DEFAULT_RETENTION_SECS: u64 = 3600;
+
+/// Por qué salió un nodo del registro.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RemovalReason {
+    /// Dejó de anunciarse.
+    Stale,
+    /// Anunció su salida.
+    Goodbye,
+    /// Lo sacó un administrador (`POST /nodes/{id}/remove`).
+    AdminDelete,
+    /// Caído y sacado para dejar sitio en una pool llena.
+    Evicted,
+    /// Sustituido por el mismo backend anunciado con el formato de ID nuevo.
+    Replaced,
+    /// Venció el plazo que anunció con `DRAINING`.
+    Drained,
+}
+
+impl RemovalReason {
+    /// Causa con la que la salida entra en el historial de transiciones.
+    pub fn cause(&self) -> TransitionCause {
+        match self {
+            RemovalReason::Goodbye => TransitionCause::Goodbye,
+            RemovalReason::Drained => TransitionCause::Drain,
+            RemovalReason::AdminDelete => TransitionCause::Admin,
+            RemovalReason::Stale | RemovalReason::Evicted | RemovalReason::Replaced => TransitionCause::Cleanup,
+        }
+    }
+}
+
+/// Acumulados de un nodo desde que se registró.
+#[derive(Clone, Debug, Serialize)]
+pub struct NodeStats {
+    pub registered_at: DateTime<Local>,
+    /// Peticiones que se le asignaron.
+    pub dispatched: u64,
+    /// Veces que pasó a `failed`.
+    pub failures: u64,
+    /// Peticiones que atendió su proxy por su cuenta mientras el balanceador no respondía (`SPOOLED`).
+    pub spooled: u64,
+}
+
+impl Default for NodeStats {
+    fn default() -> Self {
+        Self { registered_at: Local::now(), dispatched: 0, failures: 0, spooled: 0 }
+    }
+}
+
+/// Un nodo tal como estaba al salir del registro.
+#[derive(Clone, Debug, Serialize)]
+pub struct Tombstone {
+    pub tombstone_id: String,
+    pub node_id: String,
+    pub service: String,
+    pub service_url: String,
+    pub source: &'static str,
+    pub reason: RemovalReason,
+    pub removed_at: DateTime<Local>,
+    /// Estado del nodo al salir.
+    pub final_state: &'static str,
+    pub version: Option<String>,
+    pub backend_version: Option<String>,
+    pub models: Vec<String>,
+    pub last_error: Option<String>,
+    pub stats: NodeStats,
+    #[serde(skip)]
+    at: Instant,
+}
+
+pub struct Tombstones {
+    /// Cuánto se guarda cada lápida; cero no guarda ninguna.
+    retention: Duration,
+    restore_stats: bool,
+    /// Lleno, una lápida nueva sustituye a la más antigua.
+    cap: usize,
+    /// Por (pool, nodo): una salida nueva del mismo nodo sustituye a la anterior.
+    entries: Mutex<HashMap<(String, String), Tombstone>>,
+}
+
+impl Tombstones {
+    pub fn new(retention: Duration, restore_stats: bool, cap: usize) -> Self {
+        Self { retention, restore_stats, cap, entries: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.retention.is_zero()
+    }
+
+    /// Guarda la lápida de un nodo que sale del registro. Devuelve su ID.
+    pub fn bury(&self, service: &str, unique_node_id: &str, info: &NodeInfo, reason: RemovalReason) -> Option<String> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let tombstone = Tombstone {
+            tombstone_id: Uuid::new_v4().to_string(),
+            node_id: unique_node_id.to_string(),
+            service: service.to_string(),
+            service_url: info.service_url.to_string(),
+            source: info.source.label(),
+            reason,
+            removed_at: Local::now(),
+            final_state: info.state.label(),
+            version: info.version.clone(),
+            backend_version: info.backend_version.clone(),
+            models: info.models.clone(),
+            last_error: info.last_error.clone(),
+            stats: info.stats.clone(),
+            at: Instant::now(),
+        };
+        let tombstone_id = tombstone.tombstone_id.clone();
+        let key = (service.to_string(), unique_node_id.to_string());
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.contains_key(&key) && entries.len() >= self.cap {
+            let oldest = entries.iter().min_by_key(|(_, tombstone)| tombstone.at).map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(key, tombstone);
+        Some(tombstone_id)
+    }
+
+    /// Borra la lápida de un nodo que vuelve a registrarse en la pool. Devuelve los acumulados
+    /// que tenía si se restauran (`--restore-node-stats`).
+    pub fn revive(&self, service: &str, unique_node_id: &str) -> Option<NodeStats> {
+        let tombstone = self.entries.lock().unwrap().remove(&(service.to_string(), unique_node_id.to_string()))?;
+        let restored = if self.restore_stats { " con sus acumulados" } else { "" };
+        info!("Lápidas: El nodo {} vuelve a {}{}; se borra su lápida {}.", unique_node_id, service, restored, tombstone.tombstone_id);
+        self.restore_stats.then_some(tombstone.stats)
+    }
+
+    /// Borra las lápidas que pasaron la retención.
+    pub fn purge(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        let before = entries.len();
+        entries.retain(|_, tombstone| tombstone.at.elapsed() <= self.retention);
+        let purged = before - entries.len();
+        if purged > 0 {
+            debug!("Lápidas: {} lápidas purgadas tras {}s.", purged, self.retention.as_secs());
+        }
+    }
+
+    /// Lápidas vigentes, de la salida más reciente a la más antigua.
+    pub fn list(&self) -> Vec<Tombstone> {
+        let mut tombstones: Vec<Tombstone> = self
+            .entries
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|tombstone| tombstone.at.elapsed() <= self.retention)
+            .cloned()
+            .collect();
+        tombstones.sort_by_key(|tombstone| std::cmp::Reverse(tombstone.at));
+        tombstones
+    }
+
+    pub fn usage(&self) -> StoreUsage {
+        let entries = self.entries.lock().unwrap();
+        let bytes = entries
+            .values()
+            .map(|tombstone| {
+                size_of::<((String, String), Tombstone)>()
+                    + 2 * (tombstone.node_id.len() + tombstone.service.len())
+                    + tombstone.tombstone_id.len()
+                    + tombstone.service_url.len()
+                    + tombstone.models.iter().map(|model| size_of::<String>() + model.len()).sum::<usize>()
+                    + tombstone.last_error.as_ref().map_or(0, String::len)
+            })
+            .sum();
+        StoreUsage { entries: entries.len(), bytes }
+    }
+
+    /// Configuración para `/config`.
+    pub fn describe(&self) -> serde_json::Value {
+        json!({
+            "retention_secs": self.retention.as_secs(),
+            "restore_stats": self.restore_stats,
+        })
+    }
+}
+
+/// Saca un nodo del registro dejando su lápida. 409 si está ocupado.
+#[post("/nodes/{id}/remove")]
+async fn remove_handler(state: web::Data<AppState>, path: web::Path<NodeId>) -> impl Responder {
+    let unique_node_id = path.into_inner();
+    let mut removed = Vec::new();
+    for (_, service, lock) in state.pools() {
+        let mut nodes = lock.write().unwrap();
+        let Some(info) = nodes.get(unique_node_id.as_str()) else {
+            continue;
+        };
+        if matches!(info.state, NodeHealth::Busy | NodeHealth::Loading) {
+            return HttpResponse::Conflict().json(json!({
+                "error": format!("El nodo {} está ocupado ({}); no se saca.", unique_node_id, info.state.label()),
+            }));
+        }
+        let Some(info) = nodes.remove(unique_node_id.as_str()) else {
+            continue;
+        };
+        state.capacity.record(service, &nodes);
+        state.revisions.bump(service, &unique_node_id);
+        drop(nodes);
+        removed.push((service, info));
+    }
+    if removed.is_empty() {
+        return HttpResponse::NotFound().json(json!({ "error": format!("Nodo {} desconocido", unique_node_id) }));
+    }
+    let mut tombstones = Vec::new();
+    for (service, info) in removed {
+        info!("Lápidas: Nodo ID {} ({}) sacado del registro por un administrador.", unique_node_id, service);
+        state.record_removal(service, &unique_node_id, &info, RemovalReason::AdminDelete);
+        tombstones.extend(state.tombstones.list().into_iter().filter(|tombstone| tombstone.service == service.as_str() && tombstone.node_id == unique_node_id.as_str()));
+    }
+    HttpResponse::Ok().json(json!({ "node_id": unique_node_id, "removed": tombstones }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use serde_json::Value;
+
+    use crate::balancer::{self, LMSTUDIO};
+    use crate::events::{self, BalancerEvent};
+    use crate::testing;
+
+    /// Un nodo recién anunciado, como lo guarda el registro.
+    fn node() -> NodeInfo {
+        let state = testing::state(&[]);
+        testing::announce(&state, "lmstudio", "box1", "http://10.0.0.1:1234/");
+        let info = state.pool("lmstudio").unwrap().read().unwrap()["box1"].clone();
+        info
+    }
+
+    #[test]
+    fn zero_retention_keeps_nothing() {
+        let tombstones = Tombstones::new(Duration::ZERO, true, 10);
+        assert_eq!(tombstones.bury("lmstudio", "box1", &node(), RemovalReason::Stale), None);
+        assert!(tombstones.list().is_empty());
+    }
+
+    #[test]
+    fn full_store_replaces_the_oldest_and_a_node_keeps_one_tombstone() {
+        let tombstones = Tombstones::new(Duration::from_secs(60), false, 2);
+        let list = |tombstones: &Tombstones| tombstones.list().into_iter().map(|t| (t.service, t.node_id, t.reason)).collect::<Vec<_>>();
+        let entry = |service: &str, id: &str, reason| (service.to_string(), id.to_string(), reason);
+        tombstones.bury("lmstudio", "box1", &node(), RemovalReason::Stale);
+        std::thread::sleep(Duration::from_millis(2));
+        tombstones.bury("lmstudio", "box2", &node(), RemovalReason::Goodbye);
+        std::thread::sleep(Duration::from_millis(2));
+        // La segunda salida de box2 sustituye a la primera sin echar a nadie.
+        tombstones.bury("lmstudio", "box2", &node(), RemovalReason::Evicted);
+        assert_eq!(list(&tombstones), [entry("lmstudio", "box2", RemovalReason::Evicted), entry("lmstudio", "box1", RemovalReason::Stale)]);
+        std::thread::sleep(Duration::from_millis(2));
+        tombstones.bury("ollama", "box1", &node(), RemovalReason::AdminDelete);
+        assert_eq!(list(&tombstones), [entry("ollama", "box1", RemovalReason::AdminDelete), entry("lmstudio", "box2", RemovalReason::Evicted)]);
+        assert_eq!(tombstones.usage().entries, 2);
+        // Sin --restore-node-stats la lápida se borra igual, pero no devuelve los acumulados.
+        assert!(tombstones.revive("ollama", "box1").is_none());
+        assert_eq!(list(&tombstones), [entry("lmstudio", "box2", RemovalReason::Evicted)]);
+    }
+
+    fn stats(state: &AppState) -> (u64, u64) {
+        let nodes = state.pool("lmstudio").unwrap().read().unwrap();
+        (nodes["box1"].stats.dispatched, nodes["box1"].stats.failures)
+    }
+
+    #[actix_web::test]
+    async fn tombstone_lifecycle_from_registration_to_purge() {
+        let state = testing::state(&["--admin-token", "secreto", "--tombstone-retention-secs", "1", "--restore-node-stats"]);
+        let (mut events, _subscriber) = events::admit(state.clone()).unwrap();
+        let url = testing::chat_node(Duration::ZERO);
+        let app = init_service(balancer::app(state.clone())).await;
+        let removed = || async {
+            let body: Value = read_body_json(call_service(&app, TestRequest::get().uri("/nodes?include=removed").to_request()).await).await;
+            body["removed"].as_array().unwrap().clone()
+        };
+        let remove = || TestRequest::post().uri("/nodes/box1/remove").insert_header(("Authorization", "Bearer secreto")).to_request();
+
+        // Registro, dos peticiones y un fallo.
+        testing::announce(&state, "lmstudio", "box1", &url);
+        for _ in 0..2 {
+            assert_eq!(call_service(&app, testing::chat().to_request()).await.status(), 200);
+        }
+        state.update_node_state(&LMSTUDIO, &testing::node_id("box1"), NodeHealth::Failed(Instant::now()), TransitionCause::HealthCheck);
+        assert_eq!(stats(&state), (2, 1));
+
+        // Ocupado no se saca; caído, sí.
+        state.update_node_state(&LMSTUDIO, &testing::node_id("box1"), NodeHealth::Busy, TransitionCause::Admin);
+        assert_eq!(call_service(&app, remove()).await.status(), 409);
+        state.update_node_state(&LMSTUDIO, &testing::node_id("box1"), NodeHealth::Failed(Instant::now()), TransitionCause::HealthCheck);
+        assert_eq!(call_service(&app, remove()).await.status(), 200);
+        assert_eq!(testing::node_state(&state, "lmstudio", "box1"), "absent");
+
+        // La lápida tiene el estado final, los acumulados y el motivo; el evento, su ID.
+        let tombstones = removed().await;
+        assert_eq!(tombstones.len(), 1);
+        let tombstone = &tombstones[0];
+        assert_eq!((tombstone["node_id"].as_str(), tombstone["service"].as_str()), (Some("box1"), Some("lmstudio")));
+        assert_eq!((tombstone["reason"].as_str(), tombstone["final_state"].as_str()), (Some("admin_delete"), Some("failed")));
+        assert_eq!((tombstone["stats"]["dispatched"].as_u64(), tombstone["stats"]["failures"].as_u64()), (Some(2), Some(2)));
+        let removal = std::iter::from_fn(|| events.try_recv().ok())
+            .find_map(|event| match &*event {
+                BalancerEvent::NodeRemoved { tombstone_id, .. } => Some(tombstone_id.clone()),
+                _ => None,
+            })
+            .expect("no se publicó node_removed");
+        assert_eq!(removal.as_deref(), tombstone["tombstone_id"].as_str());
+        // Sin include=removed no aparecen.
+        let body: Value = read_body_json(call_service(&app, TestRequest::get().uri("/nodes").to_request()).await).await;
+        assert!(body.get("removed").is_none(), "{}", body);
+
+        // Al volver recupera sus acumulados y la lápida desaparece.
+        testing::announce(&state, "lmstudio", "box1", &url);
+        assert!(removed().await.is_empty());
+        assert_eq!(stats(&state), (2, 2));
+        assert_eq!(call_service(&app, testing::chat().to_request()).await.status(), 200);
+        assert_eq!(stats(&state), (3, 2));
+
+        // Sacado otra vez, se purga pasada la retención.
+        assert_eq!(call_service(&app, remove()).await.status(), 200);
+        assert_eq!(removed().await.len(), 1);
+        tokio::time::sleep(Duration::from_millis(1_100)).await;
+        assert!(removed().await.is_empty());
+        state.tombstones.purge();
+        assert_eq!(state.tombstones.usage().entries, 0);
+    }
+
+    #[actix_web::test]
+    async fn without_restore_a_returning_node_starts_from_zero() {
+        let state = testing::state(&[]);
+        testing::announce(&state, "lmstudio", "box1", "http://10.0.0.1:1234/");
+        state.update_node_state(&LMSTUDIO, &testing::node_id("box1"), NodeHealth::Failed(Instant::now()), TransitionCause::HealthCheck);
+        state.deregister_node(&LMSTUDIO, &testing::node_id("box1"));
+        assert_eq!(state.tombstones.list()[0].stats.failures, 1);
+        testing::announce(&state, "lmstudio", "box1", "http://10.0.0.1:1234/");
+        assert!(state.tombstones.list().is_empty());
+        assert_eq!(stats(&state), (0, 0));
+    }
+}
@@ -0,0 +1,223 @@
+// src/tools.rs
+//! Llamadas a herramientas (`tools`/`tool_choice` de OpenAI) y la capacidad `tool-calling`.
+//!
+//! No todos los backends saben usarlas: unos devuelven `tool_calls` mal formados y otros
+//! ignoran el campo. El nodo prueba su backend al arrancar con una petición mínima que obliga
+//! a llamar a una función (o se fija con `--tool-calling`) y lo anuncia con `CAPS`; el
+//! balanceador puede corregirlo por nodo con `--node-capability`. Una petición con `tools`
+//! sólo va a nodos con la capacidad, y una respuesta que termina en `tool_calls` tiene que
+//! traerlos bien formados: si no, cuenta como error del nodo y no llega al cliente.
+//!
+//! Sólo se validan las respuestas sin streaming; un stream se reenvía según llega.
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::time::Duration;
+
+/// Capacidad de los nodos que saben atender peticiones con `tools`.
+pub const TOOL_CALLING: &str = "tool-calling";
+
+/// Capacidades que entienden el balanceador y los nodos.
+pub const KNOWN_CAPABILITIES: &[&str] = &[TOOL_CALLING];
+
+/// Función que la petición de prueba obliga a llamar.
+const CANARY_FUNCTION: &str = "lmserver_canary";
+
+/// Tiempo máximo de la petición de prueba; puede tener que cargar el modelo.
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub struct CapabilityConfigError(String);
+
+impl fmt::Display for CapabilityConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for CapabilityConfigError {}
+
+/// Cómo decide el nodo si anuncia `tool-calling`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ToolCallingMode {
+    /// Lo prueba contra el backend al arrancar.
+    Auto,
+    /// Lo anuncia sin probar.
+    On,
+    /// No lo anuncia.
+    Off,
+}
+
+/// Capacidades fijadas en el balanceador para nodos concretos; mandan sobre lo que anuncien.
+#[derive(Default)]
+pub struct CapabilityOverrides {
+    /// Por nodo, cada capacidad forzada a sí (`true`) o a no (`false`).
+    per_node: HashMap<String, HashMap<String, bool>>,
+}
+
+impl CapabilityOverrides {
+    /// Interpreta entradas `nodo=capacidad` (o `nodo=-capacidad` para quitarla) de la línea de comandos.
+    pub fn new(entries: &[String]) -> Result<Self, CapabilityConfigError> {
+        let mut per_node: HashMap<String, HashMap<String, bool>> = HashMap::new();
+        for entry in entries {
+            let Some((node, capability)) = entry.split_once('=').map(|(node, capability)| (node.trim(), capability.trim())) else {
+                return Err(CapabilityConfigError(format!("Capacidad inválida '{}': se esperaba <nodo>=[-]<capacidad>", entry)));
+            };
+            let (capability, enabled) = match capability.strip_prefix('-') {
+                Some(capability) => (capability, false),
+                None => (capability, true),
+            };
+            if node.is_empty() || !KNOWN_CAPABILITIES.contains(&capability) {
+                return Err(CapabilityConfigError(format!(
+                    "Capacidad inválida '{}': las conocidas son {}",
+                    entry,
+                    KNOWN_CAPABILITIES.join(", ")
+                )));
+            }
+            per_node.entry(node.to_string()).or_default().insert(capability.to_string(), enabled);
+        }
+        Ok(Self { per_node })
+    }
+
+    /// Si el nodo tiene la capacidad, dadas las que anuncia.
+    pub fn has(&self, unique_node_id: &str, advertised: &BTreeSet<String>, capability: &str) -> bool {
+        match self.per_node.get(unique_node_id).and_then(|node| node.get(capability)) {
+            Some(enabled) => *enabled,
+            None => advertised.contains(capability),
+        }
+    }
+
+    /// Capacidades efectivas del nodo, para `/nodes`.
+    pub fn effective(&self, unique_node_id: &str, advertised: &BTreeSet<String>) -> Vec<&'static str> {
+        KNOWN_CAPABILITIES
+            .iter()
+            .copied()
+            .filter(|capability| self.has(unique_node_id, advertised, capability))
+            .collect()
+    }
+
+    /// Entradas configuradas, para `/config`.
+    pub fn describe(&self) -> Value {
+        let mut entries: Vec<String> = self
+            .per_node
+            .iter()
+            .flat_map(|(node, capabilities)| {
+                capabilities
+                    .iter()
+                    .map(move |(capability, enabled)| format!("{}={}{}", node, if *enabled { "" } else { "-" }, capability))
+            })
+            .collect();
+        entries.sort();
+        json!(entries)
+    }
+}
+
+/// Si la petición ofrece herramientas al modelo (`tools` con alguna entrada).
+pub fn requests_tools(extra: &Map<String, Value>) -> bool {
+    extra.get("tools").and_then(Value::as_array).is_some_and(|tools| !tools.is_empty())
+}
+
+/// Respuesta que dice llamar a herramientas sin traer `tool_calls` válidos.
+#[derive(Debug)]
+pub struct InvalidToolCalls(String);
+
+impl fmt::Display for InvalidToolCalls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for InvalidToolCalls {}
+
+fn validate_tool_call(index: usize, call: &Value) -> Result<(), String> {
+    if call.get("id").and_then(Value::as_str).is_none_or(str::is_empty) {
+        return Err(format!("tool_calls[{}] no tiene 'id'", index));
+    }
+    if call.get("type").and_then(Value::as_str) != Some("function") {
+        return Err(format!("tool_calls[{}].type no es 'function'", index));
+    }
+    let function = call.get("function").ok_or_else(|| format!("tool_calls[{}] no tiene 'function'", index))?;
+    if function.get("name").and_then(Value::as_str).is_none_or(str::is_empty) {
+        return Err(format!("tool_calls[{}].function no tiene 'name'", index));
+    }
+    let arguments = function
+        .get("arguments")
+        .and_then(Value::as_str)
+        .ok_or_else(|| format!("tool_calls[{}].function.arguments no es un string", index))?;
+    if serde_json::from_str::<Value>(arguments).is_err() {
+        return Err(format!("tool_calls[{}].function.arguments no es JSON válido", index));
+    }
+    Ok(())
+}
+
+/// Comprueba que las opciones con `finish_reason: "tool_calls"` traen un array `tool_calls`
+/// no vacío con `id`, `type: "function"`, `function.name` y `function.arguments` en JSON.
+/// Una respuesta que no es JSON o no termina en `tool_calls` no se toca.
+pub fn validate_tool_calls(body: &[u8]) -> Result<(), InvalidToolCalls> {
+    let Ok(response) = serde_json::from_slice::<Value>(body) else {
+        return Ok(());
+    };
+    let choices = response.get("choices").and_then(Value::as_array).map(Vec::as_slice).unwrap_or_default();
+    for (index, choice) in choices.iter().enumerate() {
+        if choice.get("finish_reason").and_then(Value::as_str) != Some("tool_calls") {
+            continue;
+        }
+        let calls = choice
+            .pointer("/message/tool_calls")
+            .and_then(Value::as_array)
+            .filter(|calls| !calls.is_empty())
+            .ok_or_else(|| InvalidToolCalls(format!("choices[{}] termina en tool_calls pero no trae ninguno", index)))?;
+        for (call_index, call) in calls.iter().enumerate() {
+            validate_tool_call(call_index, call).map_err(|e| InvalidToolCalls(format!("choices[{}]: {}", index, e)))?;
+        }
+    }
+    Ok(())
+}
+
+/// Petición de prueba: obliga al modelo a llamar a una función sin argumentos.
+fn canary_request(model: &str) -> Value {
+    json!({
+        "model": model,
+        "messages": [{ "role": "user", "content": format!("Call the {} function.", CANARY_FUNCTION) }],
+        "tools": [{
+            "type": "function",
+            "function": {
+                "name": CANARY_FUNCTION,
+                "description": "Health check. Takes no arguments.",
+                "parameters": { "type": "object", "properties": {} },
+            },
+        }],
+        "tool_choice": { "type": "function", "function": { "name": CANARY_FUNCTION } },
+        "max_tokens": 64,
+        "stream": false,
+    })
+}
+
+/// Prueba si el backend en `service_url` sabe llamar a herramientas con `model`. `None` si no
+/// se pudo saber (backend caído, error HTTP, timeout): hay que volver a probar más tarde.
+pub async fn probe(client: &reqwest::Client, service_url: &str, model: &str, timeout: Duration) -> Option<bool> {
+    let response = client
+        .post(service_url)
+        .timeout(timeout)
+        .json(&canary_request(model))
+        .send()
+        .await
+        .ok()?;
+    if !response.status().is_success() {
+        return None;
+    }
+    let response: Value = response.json().await.ok()?;
+    // Aquí se exige más que a las respuestas normales: la llamada tiene que estar bien formada
+    // aunque el backend no use `finish_reason: "tool_calls"`.
+    let calls: Vec<&Value> = response
+        .get("choices")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|choice| choice.pointer("/message/tool_calls").and_then(Value::as_array))
+        .flatten()
+        .collect();
+    let valid = calls.iter().enumerate().all(|(index, call)| validate_tool_call(index, call).is_ok());
+    let called = calls.iter().any(|call| call.pointer("/function/name").and_then(Value::as_str) == Some(CANARY_FUNCTION));
+    Some(valid && called)
+}
@@ -0,0 +1,505 @@
+// src/ui.rs
+//! UI de terminal del balanceador.
+//!
+//! Cada refresco toma primero una instantánea inmutable (`UiSnapshot`) de los registros y
+//! contadores, pool a pool con su lock tomado una sola vez, y después la pinta con `render`,
+//! que no lee nada más: una fila nunca mezcla datos de antes y después de un cambio. `GET /`
+//! resume la misma instantánea, así que ambas vistas cuentan lo mismo.
+//!
+//! Con `--ui-public` pinta en su lugar la vista pública de `status` (sólo recuentos), para
+//! dejarla en una pantalla a la vista de todos.
+use actix_web::web;
+use log::info;
+use serde::Serialize;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+use crate::api_types::{CanaryStatus, RpmStatus};
+use crate::balancer::{AppState, NodeHealth};
+use crate::build_info;
+use crate::platform::Platform;
+use crate::status;
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(2);
+const FLAP_WINDOW: Duration = Duration::from_secs(3600);
+
+/// Anchos de las columnas de ID, URL y plataforma; los valores más largos se recortan.
+const NODE_ID_WIDTH: usize = 45;
+const SERVICE_URL_WIDTH: usize = 60;
+const PLATFORM_WIDTH: usize = 12;
+
+/// Un nodo tal como estaba al tomar la instantánea.
+#[derive(Clone, Debug, Serialize)]
+pub struct NodeRow {
+    pub node_id: String,
+    pub service_url: String,
+    pub state: &'static str,
+    /// Ocupado por una reserva de `X-Pipeline` y no por una petición.
+    pub reserved: bool,
+    /// Segundos desde que se marcó como caído, si lo está.
+    pub failed_secs: Option<u64>,
+    /// Segundos hasta que sale del registro, si se está drenando.
+    pub drain_secs: Option<u64>,
+    pub low_disk: bool,
+    pub last_seen_secs: u64,
+    /// Cambios de estado en la última hora.
+    pub flaps: usize,
+    pub models: usize,
+    pub source: &'static str,
+    /// Peticiones despachadas al nodo que aún no han terminado.
+    pub in_flight: u32,
+    /// Peso anunciado para el reparto ponderado.
+    pub weight: u32,
+    /// Tope de peticiones por minuto y su uso en el último minuto, si tiene tope.
+    pub rpm: Option<RpmStatus>,
+    /// Fase canaria en curso, si el nodo está en ella.
+    pub canary: Option<CanaryStatus>,
+    pub platform: Platform,
+    /// Media móvil de latencia, en ms.
+    pub latency_ms: Option<u64>,
+    /// Nivel de `--node-tier`, si hay niveles.
+    pub tier: Option<usize>,
+    /// Si está en la red local (`--local-prefix`), si hay prefijos.
+    pub local: Option<bool>,
+    /// Tokens por segundo de la última prueba de rendimiento (`benchmark`).
+    pub benchmark_tps: Option<f64>,
+    /// La última prueba empeoró frente a la anterior.
+    pub benchmark_regressed: bool,
+    /// Se salta en las pruebas de rendimiento.
+    pub benchmark_skipped: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PoolView {
+    pub name: &'static str,
+    pub service: &'static str,
+    /// Ordenados por nivel y, dentro de cada uno, por ID.
+    pub nodes: Vec<NodeRow>,
+}
+
+/// Reparto de una pool: (nodo, índice de sesgo, marcado por sesgo sostenido).
+#[derive(Clone, Debug, Serialize)]
+pub struct FairnessLine {
+    pub pool: String,
+    pub dispatches: usize,
+    pub significant: bool,
+    pub nodes: Vec<(String, f64, bool)>,
+    /// Cuota de cada dominio de fallo frente a la esperada.
+    pub domains: Vec<(String, f64, f64)>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct UiSnapshot {
+    pub version: String,
+    pub listen_addr: String,
+    pub uptime_secs: u64,
+    pub profile: String,
+    pub profile_pinned: bool,
+    pub queue_timeout_secs: u64,
+    pub queued_requests: u64,
+    /// Pools sin nodos disponibles según el consumidor de alertas.
+    pub empty_pools: Vec<String>,
+    pub pools: Vec<PoolView>,
+    pub fairness: Vec<FairnessLine>,
+    pub active_streams: usize,
+    pub streams_per_pool: Vec<(String, usize)>,
+    pub pipeline_reservations: usize,
+    pub sticky_sessions: usize,
+    /// Etiquetas de cada nivel de `--node-tier`, en orden; vacío sin niveles.
+    pub node_tiers: Vec<String>,
+}
+
+/// Copia el estado que muestran la UI y `GET /`.
+pub fn snapshot(state: &AppState) -> UiSnapshot {
+    let pools = state
+        .pools()
+        .into_iter()
+        .map(|(name, service, lock)| {
+            let nodes = lock.read().unwrap();
+            let history = state.node_history.read().unwrap();
+            let now = Instant::now();
+            let mut rows: Vec<NodeRow> = nodes
+                .iter()
+                .map(|(id, info)| {
+                    let benchmark = state.benchmarks.latest(id).filter(|run| run.service == service.as_str());
+                    NodeRow {
+                        node_id: id.to_string(),
+                        service_url: info.service_url.to_string(),
+                        state: info.state.label(),
+                        reserved: matches!(info.state, NodeHealth::Busy) && state.pipeline.is_reserved(service, id),
+                        failed_secs: match info.state {
+                            NodeHealth::Failed(failed_at) => Some(now.duration_since(failed_at).as_secs()),
+                            _ => None,
+                        },
+                        drain_secs: match info.state {
+                            NodeHealth::Draining(deadline) => Some(deadline.saturating_duration_since(now).as_secs()),
+                            _ => None,
+                        },
+                        low_disk: state.is_low_on_disk(info),
+                        last_seen_secs: now.duration_since(info.last_seen).as_secs(),
+                        flaps: history.flap_count(id, service, FLAP_WINDOW),
+                        models: info.models.len(),
+                        source: info.source.label(),
+                        in_flight: info.in_flight(),
+                        weight: info.weight,
+                        rpm: state.rpm_limits.status(id, info),
+                        canary: info.canary.as_ref().map(|canary| state.canary.status(canary)),
+                        platform: info.platform.clone(),
+                        latency_ms: info.latency.ms().map(|ms| ms.round() as u64),
+                        tier: state.tiers.is_enabled().then(|| state.tiers.tier_of(&info.platform)),
+                        local: state.locality.is_enabled().then(|| state.locality.is_local(info)),
+                        benchmark_tps: benchmark.as_ref().and_then(|run| run.tokens_per_sec),
+                        benchmark_regressed: benchmark.is_some_and(|run| run.regressed),
+                        benchmark_skipped: state.benchmarks.is_skipped(id),
+                    }
+                })
+                .collect();
+            rows.sort_by(|a, b| a.tier.cmp(&b.tier).then_with(|| a.node_id.cmp(&b.node_id)));
+            PoolView { name, service, nodes: rows }
+        })
+        .collect();
+    let fairness = state
+        .fairness
+        .report()
+        .into_iter()
+        .map(|(pool, report)| FairnessLine {
+            nodes: report
+                .nodes
+                .iter()
+                .filter_map(|(id, node)| node.skew.map(|skew| (id.clone(), skew, state.fairness.is_flagged(&pool, id))))
+                .collect(),
+            domains: report.domains.iter().map(|(domain, fairness)| (domain.clone(), fairness.share, fairness.expected_share)).collect(),
+            pool,
+            dispatches: report.dispatches,
+            significant: report.significant,
+        })
+        .collect();
+    UiSnapshot {
+        version: build_info::summary(),
+        listen_addr: state.listen_addr.clone(),
+        uptime_secs: state.started_at.elapsed().as_secs(),
+        profile: state.profiles.active_name(),
+        profile_pinned: state.profiles.is_pinned(),
+        queue_timeout_secs: state.profiles.settings().queue_timeout_secs,
+        queued_requests: state.metrics.queued_requests.load(Ordering::Relaxed),
+        empty_pools: state.empty_pools.lock().unwrap().iter().cloned().collect(),
+        pools,
+        fairness,
+        active_streams: state.stream_limiter.active_total(),
+        streams_per_pool: state.stream_limiter.active(),
+        pipeline_reservations: state.pipeline.active(),
+        sticky_sessions: state.sessions.active(),
+        node_tiers: state.tiers.legend(),
+    }
+}
+
+/// Recorta `value` a `width` caracteres, marcando el corte con `…`.
+fn truncate(value: &str, width: usize) -> String {
+    if value.chars().count() <= width {
+        return value.to_string();
+    }
+    let mut truncated: String = value.chars().take(width - 1).collect();
+    truncated.push('…');
+    truncated
+}
+
+fn state_cell(row: &NodeRow) -> String {
+    let state = match (row.state, row.failed_secs) {
+        (_, Some(secs)) => format!("Failed ({}s)", secs),
+        ("draining", _) => format!("Draining ({}s)", row.drain_secs.unwrap_or(0)),
+        ("busy", _) if row.reserved => "Reserved".to_string(),
+        ("available", _) => "Available".to_string(),
+        ("busy", _) => "Busy".to_string(),
+        ("loading", _) => "Loading".to_string(),
+        (other, _) => other.to_string(),
+    };
+    if row.low_disk {
+        format!("{} [disco]", state)
+    } else {
+        state
+    }
+}
+
+fn benchmark_cell(row: &NodeRow) -> String {
+    match row.benchmark_tps {
+        _ if row.benchmark_skipped => "skip".to_string(),
+        Some(tps) if row.benchmark_regressed => format!("{:.1}t/s!", tps),
+        Some(tps) => format!("{:.1}t/s", tps),
+        None => "-".to_string(),
+    }
+}
+
+/// Pinta la instantánea. No consulta relojes ni estado: el mismo `snapshot` da el mismo texto.
+pub fn render(snapshot: &UiSnapshot) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "== Estado del Balanceador de Cargas ==");
+    let _ = writeln!(out, "Versión: lmServer {}", snapshot.version);
+    let _ = writeln!(out, "API Global escuchando en: http://{}", snapshot.listen_addr);
+    let pinned = if snapshot.profile_pinned { " (fijado)" } else { "" };
+    let _ = writeln!(out, "Perfil activo: {}{}", snapshot.profile, pinned);
+    let _ = writeln!(out, "Timeout cola peticiones: {}s", snapshot.queue_timeout_secs);
+    if !snapshot.node_tiers.is_empty() {
+        let tiers: Vec<String> = snapshot.node_tiers.iter().enumerate().map(|(tier, labels)| format!("{} ({})", tier, labels)).collect();
+        let _ = writeln!(out, "Niveles de nodos: {}", tiers.join(" > "));
+    }
+    for pool in &snapshot.empty_pools {
+        let _ = writeln!(out, "¡ALERTA! Pool {} sin nodos disponibles.", pool);
+    }
+
+    for pool in &snapshot.pools {
+        let _ = writeln!(out, "\n-- {} Nodes --", pool.name);
+        let _ = writeln!(
+            out,
+            "{:<45} {:<60} {:<15} {:<10} {:<10} {:<6} {:<9} {:<6} {:<9} {:<12} {:<12} {:<4} {:<6} {:<9} {:<9} {:<10}",
+            "Node ID", "Service URL", "State", "Last Seen", "Flaps (1h)", "Models", "In-flight", "Weight", "RPM", "Canary", "Platform", "Tier", "Net", "Latency", "Bench", "Source"
+        );
+        let _ = writeln!(out, "{}", "-".repeat(249));
+        if pool.nodes.is_empty() {
+            let _ = writeln!(out, "(No nodes registered)");
+        }
+        for row in &pool.nodes {
+            let _ = writeln!(
+                out,
+                "{:<45} {:<60} {:<15} {:<10} {:<10} {:<6} {:<9} {:<6} {:<9} {:<12} {:<12} {:<4} {:<6} {:<9} {:<9} {:<10}",
+                truncate(&row.node_id, NODE_ID_WIDTH),
+                truncate(&row.service_url, SERVICE_URL_WIDTH),
+                state_cell(row),
+                format!("{}s ago", row.last_seen_secs),
+                row.flaps,
+                row.models,
+                row.in_flight,
+                row.weight,
+                row.rpm.as_ref().map_or_else(|| "-".to_string(), |rpm| format!("{}/{}", rpm.used, rpm.max_rpm)),
+                row.canary.as_ref().map_or_else(|| "-".to_string(), |canary| format!("{}s {}r/{}e", canary.elapsed_secs, canary.requests, canary.errors)),
+                truncate(&row.platform.label(), PLATFORM_WIDTH),
+                row.tier.map_or_else(|| "-".to_string(), |tier| tier.to_string()),
+                match row.local {
+                    Some(true) => "local",
+                    Some(false) => "remote",
+                    None => "-",
+                },
+                row.latency_ms.map_or_else(|| "-".to_string(), |ms| format!("{}ms", ms)),
+                benchmark_cell(row),
+                row.source
+            );
+        }
+    }
+
+    for fairness in &snapshot.fairness {
+        let nodes: Vec<String> = fairness
+            .nodes
+            .iter()
+            .map(|(id, skew, flagged)| format!("{} {:.2}x{}", id, skew, if *flagged { " [sesgo]" } else { "" }))
+            .collect();
+        let note = if fairness.significant { "" } else { ", pocas muestras" };
+        let _ = writeln!(out, "Reparto {} ({} peticiones{}): {}", fairness.pool, fairness.dispatches, note, nodes.join(", "));
+        if !fairness.domains.is_empty() {
+            let domains: Vec<String> = fairness
+                .domains
+                .iter()
+                .map(|(domain, share, expected)| format!("{} {:.0}% (esperado {:.0}%)", domain, share * 100.0, expected * 100.0))
+                .collect();
+            let _ = writeln!(out, "  por dominio: {}", domains.join(", "));
+        }
+    }
+
+    let streams: Vec<String> = snapshot.streams_per_pool.iter().map(|(pool, n)| format!("{}: {}", pool, n)).collect();
+    let _ = writeln!(out, "\nStreams activos: {} [{}]", snapshot.active_streams, streams.join(", "));
+    let _ = writeln!(out, "Reservas X-Pipeline activas: {}", snapshot.pipeline_reservations);
+    let _ = writeln!(out, "Sesiones X-Session-Id fijadas: {}", snapshot.sticky_sessions);
+    let _ = writeln!(out, "Ctrl+C para detener.");
+    out
+}
+
+pub async fn run(state: web::Data<AppState>) {
+    loop {
+        print!("\x1B[2J\x1B[1;1H");
+        let _ = io::stdout().flush();
+        let text = if state.ui_public { status::render_public(&state) } else { render(&snapshot(&state)) };
+        for line in text.lines() {
+            info!("{}", line);
+        }
+        sleep(REFRESH_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    use crate::balancer::OLLAMA;
+    use crate::history::TransitionCause;
+    use crate::testing;
+
+    /// Compara con `tests/golden/<name>`; con `UPDATE_GOLDEN=1` lo reescribe en su lugar.
+    fn assert_golden(name: &str, actual: &str) {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden").join(name);
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            std::fs::write(&path, actual).unwrap();
+            return;
+        }
+        let expected = std::fs::read_to_string(&path).unwrap_or_else(|e| panic!("{}: {} (UPDATE_GOLDEN=1 lo crea)", path.display(), e));
+        assert!(actual == expected, "{} no coincide (UPDATE_GOLDEN=1 lo actualiza):\n{}", path.display(), actual);
+    }
+
+    fn base() -> UiSnapshot {
+        UiSnapshot {
+            version: "1.2.3 (abcdef012345)".to_string(),
+            listen_addr: "0.0.0.0:9000".to_string(),
+            uptime_secs: 3_725,
+            profile: "default".to_string(),
+            profile_pinned: false,
+            queue_timeout_secs: 30,
+            queued_requests: 0,
+            empty_pools: Vec::new(),
+            pools: vec![
+                PoolView { name: "LM Studio", service: "lmstudio", nodes: Vec::new() },
+                PoolView { name: "Ollama", service: "ollama", nodes: Vec::new() },
+            ],
+            fairness: Vec::new(),
+            active_streams: 0,
+            streams_per_pool: Vec::new(),
+            pipeline_reservations: 0,
+            sticky_sessions: 0,
+            node_tiers: Vec::new(),
+        }
+    }
+
+    fn row(node_id: &str, state: &'static str) -> NodeRow {
+        NodeRow {
+            node_id: node_id.to_string(),
+            service_url: format!("http://10.0.0.1:1234/{}", node_id),
+            state,
+            reserved: false,
+            failed_secs: None,
+            drain_secs: None,
+            low_disk: false,
+            last_seen_secs: 3,
+            flaps: 0,
+            models: 2,
+            source: "announced",
+            in_flight: 0,
+            weight: 1,
+            rpm: None,
+            canary: None,
+            platform: Platform::default(),
+            latency_ms: None,
+            tier: None,
+            local: None,
+            benchmark_tps: None,
+            benchmark_regressed: false,
+            benchmark_skipped: false,
+        }
+    }
+
+    #[test]
+    fn empty_pools() {
+        let mut snapshot = base();
+        snapshot.empty_pools = vec!["lmstudio".to_string(), "ollama".to_string()];
+        assert_golden("ui_empty_pools.txt", &render(&snapshot));
+    }
+
+    #[test]
+    fn long_ids_urls_and_platforms_are_truncated() {
+        let mut snapshot = base();
+        let mut long = row(&"nodo-con-un-identificador-larguísimo-".repeat(3), "available");
+        long.service_url = format!("http://{}.example.internal:1234/v1/chat/completions", "host".repeat(20));
+        long.platform = Platform {
+            os: "linux".to_string(),
+            arch: "x86_64".to_string(),
+            accelerator: "cuda-experimental".to_string(),
+            memory: "discrete".to_string(),
+            memory_bytes: Some(96 << 30),
+        };
+        let exact = row(&"x".repeat(NODE_ID_WIDTH), "available");
+        snapshot.pools[0].nodes = vec![long, exact];
+        let text = render(&snapshot);
+        // Todas las filas de la tabla tienen el mismo ancho, recortadas o no.
+        let widths: Vec<usize> = text.lines().filter(|line| line.starts_with("nodo-") || line.starts_with("xxx")).map(|line| line.chars().count()).collect();
+        assert_eq!(widths.len(), 2);
+        assert_eq!(widths[0], widths[1]);
+        assert!(text.contains(&format!("{} ", "x".repeat(NODE_ID_WIDTH))), "un ID del ancho exacto no se recorta");
+        assert_golden("ui_truncation.txt", &text);
+    }
+
+    #[test]
+    fn every_state_cell() {
+        let mut snapshot = base();
+        snapshot.profile = "noche".to_string();
+        snapshot.profile_pinned = true;
+        snapshot.node_tiers = vec!["cuda".to_string(), "metal, rocm".to_string()];
+        let mut failed = row("box-failed", "failed");
+        failed.failed_secs = Some(42);
+        failed.flaps = 5;
+        failed.tier = Some(1);
+        failed.local = Some(false);
+        let mut draining = row("box-draining", "draining");
+        draining.drain_secs = Some(17);
+        draining.in_flight = 2;
+        let mut reserved = row("box-reserved", "busy");
+        reserved.reserved = true;
+        reserved.rpm = Some(RpmStatus { max_rpm: 60, used: 12 });
+        let mut busy = row("box-busy", "busy");
+        busy.low_disk = true;
+        busy.canary = Some(CanaryStatus { elapsed_secs: 30, requests: 4, errors: 1 });
+        busy.latency_ms = Some(850);
+        let mut loading = row("box-loading", "loading");
+        loading.benchmark_tps = Some(41.25);
+        loading.benchmark_regressed = true;
+        loading.source = "static";
+        let mut available = row("box-available", "available");
+        available.benchmark_tps = Some(55.0);
+        available.weight = 3;
+        available.tier = Some(0);
+        available.local = Some(true);
+        let mut skipped = row("box-skipped", "available");
+        skipped.benchmark_tps = Some(10.0);
+        skipped.benchmark_skipped = true;
+        snapshot.pools[0].nodes = vec![available, busy, draining, failed];
+        snapshot.pools[1].nodes = vec![loading, reserved, skipped];
+        assert_golden("ui_states.txt", &render(&snapshot));
+    }
+
+    #[test]
+    fn stats_footer() {
+        let mut snapshot = base();
+        snapshot.pools[0].nodes = vec![row("box1", "available"), row("box2", "busy")];
+        snapshot.fairness = vec![
+            FairnessLine {
+                pool: "lmstudio".to_string(),
+                dispatches: 200,
+                significant: true,
+                nodes: vec![("box1".to_string(), 1.5, true), ("box2".to_string(), 0.5, false)],
+                domains: vec![("rack-a".to_string(), 0.75, 0.5), ("rack-b".to_string(), 0.25, 0.5)],
+            },
+            FairnessLine { pool: "ollama".to_string(), dispatches: 3, significant: false, nodes: Vec::new(), domains: Vec::new() },
+        ];
+        snapshot.active_streams = 3;
+        snapshot.streams_per_pool = vec![("lmstudio".to_string(), 2), ("ollama".to_string(), 1)];
+        snapshot.pipeline_reservations = 1;
+        snapshot.sticky_sessions = 4;
+        assert_golden("ui_footer.txt", &render(&snapshot));
+    }
+
+    /// La instantánea de un estado vivo calcula los tiempos con un solo `now` por pool y la
+    /// pinta igual cada vez.
+    #[test]
+    fn live_snapshot_renders_failed_elapsed_and_is_stable() {
+        let state = testing::state(&[]);
+        testing::announce(&state, "lmstudio", "box1", "http://10.0.0.1:1234/");
+        testing::announce(&state, "ollama", "box2", "http://10.0.0.2:11434/");
+        let failed_at = Instant::now() - Duration::from_secs(42);
+        state.update_node_state(&OLLAMA, &testing::node_id("box2"), NodeHealth::Failed(failed_at), TransitionCause::HealthCheck);
+
+        let snapshot = snapshot(&state);
+        let ollama = snapshot.pools.iter().find(|pool| pool.service == "ollama").unwrap();
+        assert_eq!((ollama.nodes[0].state, ollama.nodes[0].failed_secs), ("failed", Some(42)));
+        let text = render(&snapshot);
+        assert!(text.contains("Failed (42s)"), "{}", text);
+        assert_eq!(render(&snapshot), text);
+    }
+}
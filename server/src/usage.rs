@@ -0,0 +1,347 @@
+// src/usage.rs
+//! Tokens de las respuestas en streaming para la auditoría y el uso diario.
+//!
+//! Una respuesta normal trae su `usage` (u `prompt_eval_count`/`eval_count` en Ollama nativo) en
+//! el cuerpo. En streaming muchos backends no lo mandan nunca, o sólo si el cliente pide
+//! `stream_options.include_usage`, y eran justo las peticiones más largas las que quedaban sin
+//! tokens. `StreamUsage` lee los eventos del nodo según pasan (SSE o NDJSON, antes del
+//! post-procesado) y se queda con el último recuento que traiga alguno. Si no llega ninguno,
+//! estima:
+//!
+//! - la salida, con el texto emitido (`content`, razonamiento y argumentos de `tool_calls`)
+//!   dividido entre `--usage-chars-per-token`; con la feature `tiktoken`, con el tokenizador;
+//! - el prompt, como la comprobación de contexto (`context::estimate_prompt_tokens`).
+//!
+//! Un recuento estimado se guarda con `estimated: true` en su registro de auditoría y va a su
+//! propia fila del uso diario: los totales exactos nunca incluyen estimaciones.
+use serde_json::{json, Value};
+use std::fmt;
+
+/// Tokens `(prompt, completion, total)`; `None` si la respuesta no los trae.
+pub type TokenCounts = (Option<u64>, Option<u64>, Option<u64>);
+
+pub const DEFAULT_CHARS_PER_TOKEN: f64 = 4.0;
+
+/// Línea más larga que se examina; una mayor no se cuenta.
+const MAX_LINE_BYTES: usize = 1024 * 1024;
+
+#[derive(Debug)]
+pub struct UsageConfigError(String);
+
+impl fmt::Display for UsageConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for UsageConfigError {}
+
+/// Tokens de un objeto de respuesta o de un evento: `usage` de OpenAI o los contadores de Ollama nativo.
+pub fn json_usage(json: &Value) -> TokenCounts {
+    if let Some(usage) = json.get("usage") {
+        return (
+            usage.get("prompt_tokens").and_then(Value::as_u64),
+            usage.get("completion_tokens").and_then(Value::as_u64),
+            usage.get("total_tokens").and_then(Value::as_u64),
+        );
+    }
+    let prompt = json.get("prompt_eval_count").and_then(Value::as_u64);
+    let completion = json.get("eval_count").and_then(Value::as_u64);
+    let total = prompt.zip(completion).map(|(p, c)| p + c);
+    (prompt, completion, total)
+}
+
+/// Texto generado que trae un evento: deltas de OpenAI (chat y completions) o fragmentos de Ollama.
+fn emitted_text(json: &Value) -> Vec<&str> {
+    let mut texts = Vec::new();
+    for choice in json.get("choices").and_then(Value::as_array).map(Vec::as_slice).unwrap_or_default() {
+        texts.extend(choice.get("text").and_then(Value::as_str));
+        if let Some(delta) = choice.get("delta") {
+            texts.extend(["content", "reasoning_content"].iter().filter_map(|field| delta.get(field)?.as_str()));
+            for call in delta.get("tool_calls").and_then(Value::as_array).map(Vec::as_slice).unwrap_or_default() {
+                texts.extend(call.get("function").and_then(|function| function.get("arguments")?.as_str()));
+            }
+        }
+    }
+    if let Some(message) = json.get("message") {
+        texts.extend(["content", "thinking"].iter().filter_map(|field| message.get(field)?.as_str()));
+    }
+    texts.extend(json.get("response").and_then(Value::as_str));
+    texts
+}
+
+/// Cómo se estiman los tokens de salida de un stream sin recuento (`--usage-chars-per-token`).
+#[derive(Clone, Copy, Debug)]
+pub struct UsageEstimator {
+    chars_per_token: f64,
+}
+
+impl UsageEstimator {
+    pub fn new(chars_per_token: f64) -> Result<Self, UsageConfigError> {
+        if !(chars_per_token.is_finite() && chars_per_token > 0.0) {
+            return Err(UsageConfigError(format!("--usage-chars-per-token debe ser mayor que 0, no {}", chars_per_token)));
+        }
+        Ok(Self { chars_per_token })
+    }
+
+    /// Contador para un stream cuyo prompt se estima en `prompt_tokens`.
+    pub fn stream(&self, prompt_tokens: u64) -> StreamUsage {
+        StreamUsage { estimator: *self, prompt_tokens, line: Vec::new(), exact: None, emitted: 0 }
+    }
+
+    /// Medida de un fragmento de texto emitido: caracteres o, con `tiktoken`, tokens.
+    #[cfg(feature = "tiktoken")]
+    fn measure(text: &str) -> u64 {
+        crate::context::text_tokens(text)
+    }
+
+    #[cfg(not(feature = "tiktoken"))]
+    fn measure(text: &str) -> u64 {
+        text.chars().count() as u64
+    }
+
+    /// Tokens de salida de un stream que emitió `emitted` (ver `measure`).
+    #[cfg(feature = "tiktoken")]
+    fn completion_tokens(&self, emitted: u64) -> u64 {
+        emitted
+    }
+
+    #[cfg(not(feature = "tiktoken"))]
+    fn completion_tokens(&self, emitted: u64) -> u64 {
+        (emitted as f64 / self.chars_per_token).ceil() as u64
+    }
+
+    /// Configuración para `/config`.
+    pub fn describe(&self) -> Value {
+        json!({
+            "chars_per_token": self.chars_per_token,
+            "tokenizer": cfg!(feature = "tiktoken"),
+        })
+    }
+}
+
+/// Recuento de un stream, exacto o estimado.
+#[derive(Clone, Copy, Debug)]
+pub struct StreamedUsage {
+    pub tokens: TokenCounts,
+    pub estimated: bool,
+}
+
+/// Lectura de los eventos de un stream para contar sus tokens.
+pub struct StreamUsage {
+    estimator: UsageEstimator,
+    prompt_tokens: u64,
+    /// Línea aún sin terminar.
+    line: Vec<u8>,
+    /// Último recuento que trajo el nodo.
+    exact: Option<TokenCounts>,
+    /// Texto emitido, según `UsageEstimator::measure`.
+    emitted: u64,
+}
+
+impl StreamUsage {
+    /// Examina un fragmento tal como llega del nodo.
+    pub fn push(&mut self, chunk: &[u8]) {
+        let mut rest = chunk;
+        while let Some(end) = rest.iter().position(|byte| *byte == b'\n') {
+            if self.line.len() + end <= MAX_LINE_BYTES {
+                self.line.extend_from_slice(&rest[..end]);
+                let line = std::mem::take(&mut self.line);
+                self.event(&line);
+            }
+            self.line.clear();
+            rest = &rest[end + 1..];
+        }
+        if self.line.len() + rest.len() <= MAX_LINE_BYTES {
+            self.line.extend_from_slice(rest);
+        }
+    }
+
+    /// Una línea SSE (`data: {...}`) o NDJSON (`{...}`).
+    fn event(&mut self, line: &[u8]) {
+        let line = line.trim_ascii();
+        let line = line.strip_prefix(b"data:").map_or(line, <[u8]>::trim_ascii);
+        if !line.starts_with(b"{") {
+            return;
+        }
+        let Ok(json) = serde_json::from_slice::<Value>(line) else {
+            return;
+        };
+        let counts = json_usage(&json);
+        if counts != (None, None, None) {
+            self.exact = Some(counts);
+        }
+        self.emitted += emitted_text(&json).into_iter().map(UsageEstimator::measure).sum::<u64>();
+    }
+
+    /// Recuento del stream: el del nodo si mandó alguno y, si no, la estimación.
+    pub fn finish(mut self) -> StreamedUsage {
+        // La última línea puede llegar sin salto de línea.
+        let line = std::mem::take(&mut self.line);
+        self.event(&line);
+        if let Some(tokens) = self.exact {
+            return StreamedUsage { tokens, estimated: false };
+        }
+        let completion = self.estimator.completion_tokens(self.emitted);
+        StreamedUsage {
+            tokens: (Some(self.prompt_tokens), Some(completion), Some(self.prompt_tokens + completion)),
+            estimated: true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body, read_body_json, TestRequest};
+    use actix_web::{web, HttpResponse};
+    use std::sync::atomic::Ordering;
+
+    use crate::balancer::{self, build_state};
+    use crate::persistence::{PersistenceBackend, Store};
+    use crate::testing;
+
+    /// Frase de 50 caracteres; 40 de ellas son 2000, unos 500 tokens a 4 caracteres por token.
+    const PHRASE: &str = "La respuesta sigue con unas cuantas palabras más. ";
+
+    fn sse(events: &[Value]) -> String {
+        events.iter().map(|event| format!("data: {}\n\n", event)).chain(["data: [DONE]\n\n".to_string()]).collect()
+    }
+
+    fn delta(content: &str) -> Value {
+        json!({ "choices": [{ "delta": { "content": content } }] })
+    }
+
+    /// Tokens de salida que debe estimar un stream que emite estos trozos.
+    fn expected_completion(chunks: &[&str]) -> u64 {
+        if cfg!(feature = "tiktoken") {
+            chunks.iter().map(|chunk| UsageEstimator::measure(chunk)).sum()
+        } else {
+            (chunks.iter().map(|chunk| chunk.chars().count()).sum::<usize>() as f64 / DEFAULT_CHARS_PER_TOKEN).ceil() as u64
+        }
+    }
+
+    #[test]
+    fn ratio_must_be_positive() {
+        for ratio in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(UsageEstimator::new(ratio).is_err(), "{}", ratio);
+        }
+        assert_eq!(UsageEstimator::new(3.5).unwrap().describe()["chars_per_token"], 3.5);
+    }
+
+    #[test]
+    fn stream_without_usage_is_estimated_and_flagged() {
+        let chunks = vec![PHRASE; 40];
+        let body = sse(&chunks.iter().map(|chunk| delta(chunk)).collect::<Vec<_>>());
+        let mut usage = UsageEstimator::new(DEFAULT_CHARS_PER_TOKEN).unwrap().stream(12);
+        // Los fragmentos de red cortan las líneas por cualquier sitio.
+        for piece in body.as_bytes().chunks(37) {
+            usage.push(piece);
+        }
+        let counted = usage.finish();
+        assert!(counted.estimated);
+        let completion = counted.tokens.1.unwrap();
+        assert_eq!(completion, expected_completion(&chunks));
+        // Dentro de un 20% de los 2000 caracteres a 4 por token.
+        assert!((400..=600).contains(&completion), "{}", completion);
+        assert_eq!(counted.tokens, (Some(12), Some(completion), Some(12 + completion)));
+    }
+
+    #[test]
+    fn exact_counts_win_over_the_estimate() {
+        let body = sse(&[delta("hola"), json!({ "choices": [], "usage": { "prompt_tokens": 11, "completion_tokens": 222, "total_tokens": 233 } })]);
+        let mut usage = UsageEstimator::new(DEFAULT_CHARS_PER_TOKEN).unwrap().stream(999);
+        usage.push(body.as_bytes());
+        let counted = usage.finish();
+        assert!(!counted.estimated);
+        assert_eq!(counted.tokens, (Some(11), Some(222), Some(233)));
+
+        // Ollama nativo en NDJSON, con la última línea sin salto.
+        let mut usage = UsageEstimator::new(DEFAULT_CHARS_PER_TOKEN).unwrap().stream(999);
+        usage.push(b"{\"message\":{\"content\":\"ho\"}}\n{\"message\":{\"content\":\"la\"},\"done\":true,\"prompt_eval_count\":5,\"eval_count\":2}");
+        let counted = usage.finish();
+        assert_eq!((counted.tokens, counted.estimated), ((Some(5), Some(2), Some(7)), false));
+    }
+
+    #[test]
+    fn every_kind_of_emitted_text_counts() {
+        let events = [
+            json!({ "choices": [{ "delta": { "reasoning_content": "abcd" } }] }),
+            json!({ "choices": [{ "delta": { "tool_calls": [{ "function": { "arguments": "{\"a\":1}" } }] } }] }),
+            json!({ "choices": [{ "text": "efgh" }] }),
+            json!({ "message": { "thinking": "ijkl", "content": "mnop" } }),
+            json!({ "response": "qrst" }),
+        ];
+        let texts: Vec<&str> = events.iter().flat_map(emitted_text).collect();
+        assert_eq!(texts, ["abcd", "{\"a\":1}", "efgh", "mnop", "ijkl", "qrst"]);
+        // Un evento con recuento pero sin texto no suma nada.
+        assert!(emitted_text(&json!({ "usage": { "completion_tokens": 3 } })).is_empty());
+    }
+
+    fn node(body: String) -> String {
+        testing::backend(move |cfg| {
+            let body = body.clone();
+            cfg.default_service(web::to(move || {
+                let body = body.clone();
+                async move { HttpResponse::Ok().content_type("text/event-stream").body(body) }
+            }));
+        })
+    }
+
+    #[actix_web::test]
+    async fn streamed_usage_lands_in_its_own_flagged_rows() {
+        let dir = testing::temp_dir();
+        let store = Store::open(PersistenceBackend::Json, Some(&dir), None).unwrap();
+        let args = ["--state-dir", dir.to_str().unwrap(), "--audit-token", "audit", "--sse-keepalive-secs", "0"];
+        let state = web::Data::new(build_state(&testing::config(&args), store).unwrap());
+        let chunks = vec![PHRASE; 40];
+        let silent = node(sse(&chunks.iter().map(|chunk| delta(chunk)).collect::<Vec<_>>()));
+        let counted = node(sse(&[delta("hola"), json!({ "choices": [], "usage": { "prompt_tokens": 11, "completion_tokens": 222, "total_tokens": 233 } })]));
+        testing::announce(&state, "lmstudio", "silent", &silent);
+        testing::announce(&state, "ollama", "counted", &counted);
+        let app = init_service(balancer::app(state.clone())).await;
+
+        for pool in ["lmstudio", "ollama"] {
+            let req = TestRequest::post()
+                .uri(&format!("/{}", pool))
+                .set_json(json!({ "model": "m", "stream": true, "messages": [{ "role": "user", "content": "Cuéntame algo largo." }] }))
+                .to_request();
+            let res = call_service(&app, req).await;
+            assert_eq!(res.status(), 200);
+            read_body(res).await;
+        }
+        // El registro se guarda al terminar el bombeo del stream.
+        testing::eventually(|| state.metrics.estimated_usage_streams.load(Ordering::Relaxed) == 1).await;
+
+        let export = || TestRequest::get().uri("/audit/export").insert_header(("Authorization", "Bearer audit")).to_request();
+        let mut records = Vec::new();
+        for _ in 0..100 {
+            let body = read_body(call_service(&app, export()).await).await;
+            records = String::from_utf8(body.to_vec()).unwrap().lines().map(|line| serde_json::from_str::<Value>(line).unwrap()).collect();
+            if records.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let record = |service: &str| records.iter().find(|record| record["service"] == service).cloned().unwrap();
+        let estimated = record("lmstudio");
+        assert_eq!(estimated["estimated"], true, "{}", estimated);
+        assert_eq!(estimated["completion_tokens"].as_u64(), Some(expected_completion(&chunks)));
+        assert!(estimated["prompt_tokens"].as_u64().unwrap() > 0);
+        let exact = record("ollama");
+        assert!(exact.get("estimated").is_none(), "{}", exact);
+        assert_eq!((exact["prompt_tokens"].as_u64(), exact["completion_tokens"].as_u64()), (Some(11), Some(222)));
+
+        // Cada fila del uso diario es toda exacta o toda estimada.
+        let req = TestRequest::get().uri("/usage/daily").insert_header(("Authorization", "Bearer audit")).to_request();
+        let daily: Value = read_body_json(call_service(&app, req).await).await;
+        let rows = daily["days"].as_array().unwrap();
+        let flagged: Vec<(String, bool, u64)> = rows
+            .iter()
+            .map(|row| (row["service"].as_str().unwrap().to_string(), row["estimated"] == true, row["completion_tokens"].as_u64().unwrap()))
+            .collect();
+        assert_eq!(flagged.len(), 2, "{}", daily);
+        assert!(flagged.contains(&("lmstudio".to_string(), true, expected_completion(&chunks))), "{:?}", flagged);
+        assert!(flagged.contains(&("ollama".to_string(), false, 222)), "{:?}", flagged);
+    }
+}
@@ -0,0 +1,212 @@
+// src/user_affinity.rs
+//! Afinidad por usuario (`--user-affinity`): todas las peticiones de un mismo usuario final van
+//! al mismo nodo, que tiene su caché y cuyo reparto se puede medir por usuario.
+//!
+//! El usuario es el campo `user` de la petición de OpenAI o, con `--user-affinity-header`, esa
+//! cabecera (y `user` si no viene). Una petición sin usuario se enruta como siempre.
+//!
+//! El nodo sale de un hash consistente (rendezvous: gana el nodo con mayor `hash(usuario, nodo)`)
+//! sobre los nodos de la pool que pueden atender la petición: ni caídos ni drenándose, con su
+//! modelo y capacidad. Cuando un nodo entra o sale, sólo cambian de nodo los usuarios que
+//! ganarían o ganaban con él; el resto sigue donde estaba. El hash no depende del proceso, así
+//! que tampoco cambia al reiniciar el balanceador.
+//!
+//! Si el nodo del usuario está ocupado, la petición lo espera en la cola hasta el tiempo de
+//! espera, como una sesión fijada (`sessions`), que manda sobre la afinidad si la petición trae
+//! `X-Session-Id`. Con `--user-affinity-fallback` va en su lugar a otro nodo libre.
+use actix_web::HttpRequest;
+use serde_json::{json, Value};
+
+use crate::ids::NodeId;
+
+pub struct UserAffinity {
+    enabled: bool,
+    header: Option<String>,
+    pub fallback: bool,
+}
+
+impl UserAffinity {
+    pub fn new(enabled: bool, header: Option<String>, fallback: bool) -> Self {
+        Self { enabled, header, fallback }
+    }
+
+    /// Usuario de la petición: la cabecera configurada o, si no viene, el campo `user`.
+    pub fn user_of(&self, req: &HttpRequest, user_field: Option<&str>) -> Option<String> {
+        if !self.enabled {
+            return None;
+        }
+        let from_header = self.header.as_deref().and_then(|name| req.headers().get(name)).and_then(|value| value.to_str().ok());
+        from_header.or(user_field).map(str::trim).filter(|user| !user.is_empty()).map(str::to_string)
+    }
+
+    /// Nodo del usuario entre `nodes`, los que pueden atender su petición.
+    pub fn node_for<'a>(&self, user: &str, nodes: impl IntoIterator<Item = &'a NodeId>) -> Option<NodeId> {
+        nodes.into_iter().max_by_key(|unique_id| (score(user, unique_id), *unique_id)).cloned()
+    }
+
+    /// Configuración para `/config`.
+    pub fn describe(&self) -> Value {
+        json!({ "enabled": self.enabled, "header": self.header, "fallback": self.fallback })
+    }
+}
+
+/// Peso del par (usuario, nodo): FNV-1a de ambos, mezclado con el final de splitmix64 para que
+/// los pesos de un mismo usuario no se parezcan entre nodos de IDs parecidos.
+fn score(user: &str, unique_node_id: &str) -> u64 {
+    const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+    let mut hash = FNV_OFFSET;
+    // El separador evita que ("ab", "c") y ("a", "bc") pesen lo mismo.
+    for byte in user.bytes().chain([0xff]).chain(unique_node_id.bytes()) {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash ^= hash >> 30;
+    hash = hash.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use futures_util::future::join_all;
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    use crate::balancer::{self, LMSTUDIO};
+    use crate::sessions::NODE_ID_HEADER;
+    use crate::testing;
+
+    fn nodes(ids: &[&str]) -> Vec<NodeId> {
+        ids.iter().map(|id| testing::node_id(id)).collect()
+    }
+
+    /// Nodo de cada uno de 1000 usuarios.
+    fn assign(affinity: &UserAffinity, nodes: &[NodeId]) -> HashMap<String, NodeId> {
+        (0..1000).map(|i| format!("user-{}", i)).map(|user| (user.clone(), affinity.node_for(&user, nodes).unwrap())).collect()
+    }
+
+    #[test]
+    fn user_comes_from_the_header_then_the_field() {
+        let req = TestRequest::default().insert_header(("X-End-User", " ana ")).to_http_request();
+        let bare = TestRequest::default().to_http_request();
+        let by_header = UserAffinity::new(true, Some("X-End-User".to_string()), false);
+        assert_eq!(by_header.user_of(&req, Some("luis")).as_deref(), Some("ana"));
+        assert_eq!(by_header.user_of(&bare, Some("luis")).as_deref(), Some("luis"));
+        assert_eq!(by_header.user_of(&bare, Some("  ")), None);
+        assert_eq!(UserAffinity::new(true, None, false).user_of(&req, None), None);
+        assert_eq!(UserAffinity::new(false, Some("X-End-User".to_string()), false).user_of(&req, Some("luis")), None);
+    }
+
+    #[test]
+    fn assignment_is_stable_and_spread() {
+        let affinity = UserAffinity::new(true, None, false);
+        let pool = nodes(&["n0", "n1", "n2", "n3", "n4"]);
+        let first = assign(&affinity, &pool);
+        // El orden de los nodos no cuenta, y el hash no depende del proceso.
+        let mut reversed = pool.clone();
+        reversed.reverse();
+        assert_eq!(assign(&affinity, &reversed), first);
+        assert_eq!(score("ana", "n1"), score("ana", "n1"));
+        assert_ne!(score("ab", "c"), score("a", "bc"));
+        for node in &pool {
+            let users = first.values().filter(|assigned| *assigned == node).count();
+            assert!((150..=250).contains(&users), "{} tiene {} de 1000 usuarios", node, users);
+        }
+        assert_eq!(affinity.node_for("ana", &[]), None);
+    }
+
+    #[test]
+    fn removing_a_node_only_moves_its_users() {
+        let affinity = UserAffinity::new(true, None, false);
+        let before = assign(&affinity, &nodes(&["n0", "n1", "n2", "n3", "n4"]));
+        let after = assign(&affinity, &nodes(&["n0", "n1", "n3", "n4"]));
+        let moved: Vec<_> = before.keys().filter(|user| before[*user] != after[*user]).collect();
+        assert!(moved.iter().all(|user| before[*user].as_str() == "n2"));
+        assert_eq!(moved.len(), before.values().filter(|node| node.as_str() == "n2").count());
+        assert!((150..=250).contains(&moved.len()), "{} de 1000 usuarios cambian de nodo", moved.len());
+    }
+
+    #[test]
+    fn adding_a_node_only_takes_about_its_share() {
+        let affinity = UserAffinity::new(true, None, false);
+        let before = assign(&affinity, &nodes(&["n0", "n1", "n2", "n3", "n4"]));
+        let after = assign(&affinity, &nodes(&["n0", "n1", "n2", "n3", "n4", "n5"]));
+        let moved: Vec<_> = before.keys().filter(|user| before[*user] != after[*user]).collect();
+        assert!(moved.iter().all(|user| after[*user].as_str() == "n5"));
+        // Una sexta parte son unos 167.
+        assert!((120..=220).contains(&moved.len()), "{} de 1000 usuarios cambian de nodo", moved.len());
+    }
+
+    #[actix_web::test]
+    async fn each_user_keeps_its_node_until_the_node_leaves() {
+        let state = testing::state(&["--user-affinity"]);
+        for id in ["n1", "n2", "n3", "n4"] {
+            testing::announce(&state, "lmstudio", id, &testing::chat_node(Duration::ZERO));
+        }
+        let app = init_service(balancer::app(state.clone())).await;
+        let served_by = |user: String| {
+            let app = &app;
+            async move {
+                let res = call_service(app, testing::chat_with(serde_json::json!({ "user": &user })).to_request()).await;
+                assert_eq!(res.status(), 200);
+                (user, res.headers().get(NODE_ID_HEADER).unwrap().to_str().unwrap().to_string())
+            }
+        };
+        let users = || (0..24).map(|i| format!("user-{}", i));
+        let mut first = HashMap::new();
+        for user in users() {
+            let (user, node) = served_by(user).await;
+            first.insert(user, node);
+        }
+        for user in users() {
+            let (user, node) = served_by(user).await;
+            assert_eq!(first[&user], node, "{} cambió de nodo", user);
+        }
+        assert!(first.values().collect::<std::collections::HashSet<_>>().len() > 1, "todos los usuarios en un nodo: {:?}", first);
+
+        state.deregister_node(&LMSTUDIO, &testing::node_id("n3"));
+        for user in users() {
+            let (user, node) = served_by(user).await;
+            if first[&user] == "n3" {
+                assert_ne!(node, "n3");
+            } else {
+                assert_eq!(first[&user], node, "{} cambió de nodo sin que saliera el suyo", user);
+            }
+        }
+    }
+
+    /// Dos peticiones a la vez del mismo usuario con dos nodos de 300 ms: (nodo, tiempo) de cada una.
+    async fn two_at_once(args: &[&str]) -> Vec<(String, Duration)> {
+        let state = testing::state(args);
+        for id in ["n1", "n2"] {
+            testing::announce(&state, "lmstudio", id, &testing::chat_node(Duration::from_millis(300)));
+        }
+        let app = init_service(balancer::app(state.clone())).await;
+        let app = &app;
+        let start = Instant::now();
+        join_all((0..2).map(|_| async move {
+            let res = call_service(app, testing::chat_with(serde_json::json!({ "user": "ana" })).to_request()).await;
+            assert_eq!(res.status(), 200);
+            (res.headers().get(NODE_ID_HEADER).unwrap().to_str().unwrap().to_string(), start.elapsed())
+        }))
+        .await
+    }
+
+    #[actix_web::test]
+    async fn a_busy_user_node_is_waited_for_unless_fallback() {
+        let served = two_at_once(&["--user-affinity"]).await;
+        let mine = UserAffinity::new(true, None, false).node_for("ana", &nodes(&["n1", "n2"])).unwrap();
+        assert!(served.iter().all(|(node, _)| node.as_str() == mine.as_str()), "{:?}", served);
+        assert!(served.iter().any(|(_, elapsed)| *elapsed >= Duration::from_millis(600)), "{:?}", served);
+
+        let served = two_at_once(&["--user-affinity", "--user-affinity-fallback"]).await;
+        let mut nodes: Vec<_> = served.iter().map(|(node, _)| node.as_str()).collect();
+        nodes.sort();
+        assert_eq!(nodes, ["n1", "n2"]);
+        assert!(served.iter().all(|(_, elapsed)| *elapsed < Duration::from_millis(600)), "{:?}", served);
+    }
+}
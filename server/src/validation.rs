@@ -0,0 +1,258 @@
+// src/validation.rs
+//! Validación de peticiones de chat completions antes de ocupar un nodo.
+//!
+//! Sólo se comprueban los campos que el balanceador entiende; el resto pasa tal cual
+//! (`extra`), y al nodo se le reenvía siempre el cuerpo original. Por eso muchos campos se
+//! validan al deserializar pero el balanceador no llega a leerlos.
+#![allow(dead_code)]
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+use serde::Deserialize;
+use std::fmt;
+
+#[derive(Debug, Deserialize)]
+pub struct ChatCompletionRequest {
+    pub model: Option<String>,
+    pub messages: Vec<ChatMessage>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub max_tokens: Option<u64>,
+    pub n: Option<u32>,
+    pub stream: Option<bool>,
+    pub presence_penalty: Option<f64>,
+    pub frequency_penalty: Option<f64>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatMessage {
+    pub role: Role,
+    #[serde(default)]
+    pub content: MessageContent,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug)]
+pub enum Role {
+    System,
+    Developer,
+    User,
+    Assistant,
+    Tool,
+    Function,
+}
+
+const ROLES: &[&str] = &["system", "developer", "user", "assistant", "tool", "function"];
+
+/// Se lee como texto: con el derive, serde_json toma un `role` que no es cadena (`"role": 7`)
+/// por un error de sintaxis y se respondería que el cuerpo no es JSON válido.
+impl<'de> Deserialize<'de> for Role {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let role = String::deserialize(deserializer)?;
+        match role.as_str() {
+            "system" => Ok(Role::System),
+            "developer" => Ok(Role::Developer),
+            "user" => Ok(Role::User),
+            "assistant" => Ok(Role::Assistant),
+            "tool" => Ok(Role::Tool),
+            "function" => Ok(Role::Function),
+            other => Err(de::Error::unknown_variant(other, ROLES)),
+        }
+    }
+}
+
+/// `content` admite texto, la forma multimodal (array de partes) o `null` (p.ej. con `tool_calls`).
+#[derive(Debug, Default)]
+pub enum MessageContent {
+    #[default]
+    Empty,
+    Text(String),
+    Parts(Vec<serde_json::Value>),
+}
+
+impl<'de> Deserialize<'de> for MessageContent {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ContentVisitor;
+
+        impl<'de> Visitor<'de> for ContentVisitor {
+            type Value = MessageContent;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a string or an array of content parts")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+                Ok(MessageContent::Text(value.to_string()))
+            }
+
+            fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+                Ok(MessageContent::Empty)
+            }
+
+            fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+                Ok(MessageContent::Empty)
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut parts = Vec::new();
+                while let Some(part) = seq.next_element()? {
+                    parts.push(part);
+                }
+                Ok(MessageContent::Parts(parts))
+            }
+        }
+
+        deserializer.deserialize_any(ContentVisitor)
+    }
+}
+
+/// Error de validación con el campo afectado (`messages[0].role`) para el `param` de OpenAI.
+#[derive(Debug)]
+pub struct ValidationError {
+    pub param: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.param {
+            Some(param) => write!(f, "Campo '{}' inválido: {}", param, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Valida el cuerpo de una petición de chat completions.
+pub fn validate_chat_request(body: &[u8]) -> Result<ChatCompletionRequest, ValidationError> {
+    let deserializer = &mut serde_json::Deserializer::from_slice(body);
+    serde_path_to_error::deserialize(deserializer).map_err(|e| {
+        let path = e.path().to_string();
+        let inner = e.into_inner();
+        if inner.is_syntax() || inner.is_eof() {
+            return ValidationError { param: None, message: format!("El cuerpo no es JSON válido: {}", inner) };
+        }
+        let message = strip_position(&inner.to_string());
+        // En un campo ausente la ruta apunta al objeto que lo contiene: se añade el nombre del campo.
+        let missing = message
+            .strip_prefix("missing field `")
+            .and_then(|rest| rest.strip_suffix('`'));
+        let param = match (path.as_str(), missing) {
+            (".", Some(field)) => Some(field.to_string()),
+            (_, Some(field)) => Some(format!("{}.{}", path, field)),
+            (".", None) => None,
+            _ => Some(path),
+        };
+        ValidationError { param, message }
+    })
+}
+
+/// Quita el sufijo " at line X column Y" de los errores de serde_json, que no aporta nada al cliente.
+fn strip_position(message: &str) -> String {
+    match message.rfind(" at line ") {
+        Some(index) => message[..index].to_string(),
+        None => message.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::{web, HttpResponse};
+    use serde_json::{json, Value};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use crate::balancer;
+    use crate::testing;
+
+    /// Cuerpo mal formado, campo que debe señalar el 400 y fragmento del mensaje.
+    const MALFORMED: &[(&str, Option<&str>, &str)] = &[
+        (r#"{"model":"m","messages":"hola"}"#, Some("messages"), "expected a sequence"),
+        (r#"{"model":"m"}"#, Some("messages"), "missing field `messages`"),
+        (r#"{"model":"m","messages":null}"#, Some("messages"), "expected a sequence"),
+        (r#"{"model":"m","messages":[{"role":"usr","content":"hola"}]}"#, Some("messages[0].role"), "unknown variant `usr`"),
+        (r#"{"model":"m","messages":[{"content":"hola"}]}"#, Some("messages[0].role"), "missing field `role`"),
+        (r#"{"model":"m","messages":[{"role":"user","content":"hola"},{"role":7,"content":"x"}]}"#, Some("messages[1].role"), "invalid type: integer `7`, expected a string"),
+        (r#"{"model":"m","messages":[{"role":"user","content":42}]}"#, Some("messages[0].content"), "a string or an array of content parts"),
+        (r#"{"model":"m","messages":["hola"]}"#, Some("messages[0]"), "expected struct ChatMessage"),
+        (r#"{"model":"m","messages":[],"temperature":"0.7"}"#, Some("temperature"), "expected f64"),
+        (r#"{"model":"m","messages":[],"max_tokens":-1}"#, Some("max_tokens"), "expected u64"),
+        (r#"{"model":"m","messages":[],"stream":"yes"}"#, Some("stream"), "expected a boolean"),
+        (r#"{"model":7,"messages":[]}"#, Some("model"), "expected a string"),
+        (r#"{"model":"m","messages":[],"n":1.5}"#, Some("n"), "expected u32"),
+        (r#"{"model":"m","messages":["#, None, "no es JSON válido"),
+        (r#"["model","messages"]"#, None, "expected struct ChatCompletionRequest"),
+    ];
+
+    #[test]
+    fn malformed_payloads_name_the_offending_field() {
+        for (body, param, message) in MALFORMED {
+            let err = validate_chat_request(body.as_bytes()).expect_err(body);
+            assert_eq!(err.param.as_deref(), *param, "{}", body);
+            assert!(err.message.contains(message), "{}: {}", body, err.message);
+            // La posición sólo se deja en los errores de sintaxis, donde ayuda.
+            assert!(param.is_none() || !err.message.contains(" at line "), "{}", err.message);
+        }
+    }
+
+    #[test]
+    fn lenient_about_extra_fields_and_content_forms() {
+        let body = json!({
+            "model": "m",
+            "messages": [
+                { "role": "system", "content": "breve" },
+                { "role": "user", "content": [{ "type": "text", "text": "¿qué es?" }, { "type": "image_url", "image_url": { "url": "data:," } }] },
+                { "role": "assistant", "content": null, "tool_calls": [] },
+                { "role": "tool", "tool_call_id": "1", "content": "ok" },
+                { "role": "user" },
+            ],
+            "temperature": 1,
+            "response_format": { "type": "json_object" },
+            "seed": 7,
+        });
+
+        let request = validate_chat_request(body.to_string().as_bytes()).unwrap();
+
+        assert!(matches!(request.messages[1].content, MessageContent::Parts(ref parts) if parts.len() == 2));
+        assert!(matches!(request.messages[2].content, MessageContent::Empty));
+        assert!(matches!(request.messages[4].content, MessageContent::Empty));
+        assert!(request.messages[2].extra.contains_key("tool_calls"));
+        assert_eq!(request.extra.keys().collect::<Vec<_>>(), ["response_format", "seed"]);
+        assert_eq!(request.temperature, Some(1.0));
+    }
+
+    #[actix_web::test]
+    async fn malformed_payloads_never_reach_a_node() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        let url = testing::backend(move |cfg| {
+            let hits = hits.clone();
+            cfg.default_service(web::to(move || {
+                hits.fetch_add(1, Ordering::Relaxed);
+                async { HttpResponse::Ok().json(json!({ "choices": [] })) }
+            }));
+        });
+        let state = testing::state(&[]);
+        testing::announce(&state, "lmstudio", "box1", &url);
+        let app = init_service(balancer::app(state.clone())).await;
+
+        for (body, param, _) in MALFORMED {
+            let req = TestRequest::post().uri("/lmstudio").insert_header(("Content-Type", "application/json")).set_payload(*body).to_request();
+            let resp = call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", body);
+            let error: Value = read_body_json(resp).await;
+            assert_eq!(error["error"]["type"], "invalid_request_error", "{}", body);
+            assert_eq!(error["error"]["param"].as_str(), *param, "{}", body);
+        }
+        assert_eq!(counted.load(Ordering::Relaxed), 0);
+        assert_eq!(state.metrics.rejected_invalid_requests.load(Ordering::Relaxed), MALFORMED.len() as u64);
+        assert_eq!(testing::node_state(&state, "lmstudio", "box1"), "available");
+
+        let req = TestRequest::post().uri("/lmstudio").set_json(json!({ "model": "m", "messages": [] })).to_request();
+        assert!(call_service(&app, req).await.status().is_success());
+        assert_eq!(counted.load(Ordering::Relaxed), 1);
+    }
+}
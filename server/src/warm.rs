@@ -0,0 +1,46 @@
+// src/warm.rs
+//! Afinidad por el modelo caliente (`--warm-model-window-secs`).
+//!
+//! Ollama descarga un modelo tras un rato sin uso y lo vuelve a cargar cuando le llega una
+//! petición, lo que cuesta segundos. Cada nodo apunta el último `model` que atendió y cuándo
+//! (`NodeInfo::last_model`); entre los nodos que pueden atender la petición, los que atendieron
+//! su mismo modelo dentro de la ventana se prueban antes que el resto. Se compone con el
+//! enrutado por modelo, que ya deja fuera a los nodos que no lo anuncian, y cede ante los niveles
+//! (`--node-tier`) y las preferencias de las reglas; dentro de cada grupo sigue mandando
+//! `--node-selection`.
+//!
+//! Es una entrada por nodo que sale del registro con él, así que no crece con el tráfico ni
+//! sobrevive a un nodo que se va. Una petición sin `model` no tiene afinidad.
+use serde_json::{json, Value};
+use std::time::{Duration, Instant};
+
+use crate::balancer::NodeInfo;
+
+/// Ventana de la afinidad por el modelo caliente.
+pub struct WarmAffinity {
+    window: Option<Duration>,
+}
+
+impl WarmAffinity {
+    /// `window_secs` 0 la desactiva.
+    pub fn new(window_secs: u64) -> Self {
+        Self { window: (window_secs > 0).then(|| Duration::from_secs(window_secs)) }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.window.is_some()
+    }
+
+    /// Si el nodo atendió `model` hace menos de la ventana.
+    pub fn is_warm(&self, info: &NodeInfo, model: Option<&str>, now: Instant) -> bool {
+        let (Some(window), Some(model), Some((last, served_at))) = (self.window, model, &info.last_model) else {
+            return false;
+        };
+        last == model && now.saturating_duration_since(*served_at) < window
+    }
+
+    /// Configuración para `/config`.
+    pub fn describe(&self) -> Value {
+        json!({ "window_secs": self.window.map(|window| window.as_secs()) })
+    }
+}
@@ -0,0 +1,166 @@
+// src/warmup.rs
+//! Calentamiento tras arrancar el balanceador (`--warmup-secs`).
+//!
+//! Al reiniciar en horas de trabajo, todos los clientes que esperaban reintentan a la vez, antes
+//! de que la mayoría de nodos se haya vuelto a anunciar, y la avalancha cae entera sobre el
+//! primer nodo registrado. Durante el calentamiento:
+//!
+//! - `GET /readyz` responde 503, para que un balanceador externo no envíe tráfico todavía;
+//! - cada nodo despacha como mucho a `--warmup-dispatch-rate`, si es más lento que su ritmo;
+//! - una pool con `--warmup-max-queued` peticiones ya en cola responde 503 con un `Retry-After`
+//!   corto en lugar de encolar más.
+//!
+//! Termina al cumplirse la ventana o en cuanto hay `--warmup-min-nodes` nodos disponibles y,
+//! si hay una instantánea del registro guardada (`--state-dir`), además la fracción
+//! `--warmup-capacity-fraction` de los nodos que tenía.
+use actix_web::{get, web, HttpResponse, Responder};
+use log::info;
+use serde_json::json;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+use crate::balancer::{AppState, NodeHealth};
+use crate::dispatch_rate::DispatchRate;
+
+pub const DEFAULT_WARMUP_MIN_NODES: usize = 1;
+pub const DEFAULT_WARMUP_MAX_QUEUED: u64 = 4;
+pub const DEFAULT_WARMUP_RETRY_AFTER_SECS: u64 = 2;
+pub const DEFAULT_WARMUP_CAPACITY_FRACTION: f64 = 0.8;
+
+/// Cada cuánto se comprueba si el calentamiento ha terminado.
+const CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+pub struct WarmUp {
+    started_at: Instant,
+    window: Duration,
+    min_nodes: usize,
+    /// Nodos de la instantánea anterior y fracción de ellos que da el calentamiento por terminado.
+    previous_capacity: Option<(usize, f64)>,
+    pub dispatch_rate: Option<DispatchRate>,
+    pub max_queued: u64,
+    pub retry_after: Duration,
+    active: AtomicBool,
+    /// Motivo y momento del final, para `/config`.
+    ended: Mutex<Option<(&'static str, Duration)>>,
+}
+
+impl WarmUp {
+    /// Sin ventana (`window` cero) el balanceador arranca ya listo.
+    pub fn new(
+        window: Duration,
+        min_nodes: usize,
+        previous_capacity: Option<(usize, f64)>,
+        dispatch_rate: Option<DispatchRate>,
+        max_queued: u64,
+        retry_after: Duration,
+    ) -> Self {
+        Self {
+            started_at: Instant::now(),
+            window,
+            min_nodes,
+            previous_capacity,
+            dispatch_rate,
+            max_queued,
+            retry_after,
+            active: AtomicBool::new(!window.is_zero()),
+            ended: Mutex::new(None),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Si una petición más para una pool con `queued` en cola debe rechazarse.
+    pub fn rejects(&self, queued: u64) -> bool {
+        self.is_active() && queued >= self.max_queued
+    }
+
+    /// Nodos disponibles con los que termina, y qué umbral manda.
+    fn target(&self) -> (usize, &'static str) {
+        let capacity = self.previous_capacity.map_or(0, |(nodes, fraction)| (nodes as f64 * fraction).ceil() as usize);
+        if capacity > self.min_nodes {
+            (capacity, "capacity")
+        } else {
+            (self.min_nodes, "min_nodes")
+        }
+    }
+
+    fn end(&self, reason: &'static str) {
+        let elapsed = self.started_at.elapsed();
+        self.active.store(false, Ordering::Relaxed);
+        *self.ended.lock().unwrap() = Some((reason, elapsed));
+    }
+
+    /// Estado y configuración para `/config` y `/readyz`.
+    pub fn describe(&self) -> serde_json::Value {
+        let ended = *self.ended.lock().unwrap();
+        json!({
+            "active": self.is_active(),
+            "window_secs": self.window.as_secs(),
+            "remaining_secs": self.is_active().then(|| self.window.saturating_sub(self.started_at.elapsed()).as_secs()),
+            "min_nodes": self.min_nodes,
+            "previous_nodes": self.previous_capacity.map(|(nodes, _)| nodes),
+            "capacity_fraction": self.previous_capacity.map(|(_, fraction)| fraction),
+            "target_nodes": self.target().0,
+            "dispatch_rate": self.dispatch_rate,
+            "max_queued": self.max_queued,
+            "retry_after_secs": self.retry_after.as_secs(),
+            "ended_reason": ended.map(|(reason, _)| reason),
+            "ended_after_ms": ended.map(|(_, elapsed)| elapsed.as_millis() as u64),
+        })
+    }
+}
+
+fn available_nodes(state: &AppState) -> usize {
+    state
+        .pools()
+        .into_iter()
+        .map(|(_, _, lock)| lock.read().unwrap().values().filter(|info| matches!(info.state, NodeHealth::Available)).count())
+        .sum()
+}
+
+/// Vigila el calentamiento y lo da por terminado; después retira el ritmo de despacho reducido.
+pub async fn run(state: web::Data<AppState>) {
+    let warmup = &state.warmup;
+    if !warmup.is_active() {
+        return;
+    }
+    let (target, target_reason) = warmup.target();
+    info!(
+        "Arranque: Calentamiento de {}s o hasta {} nodos disponibles (máx. {} en cola por pool).",
+        warmup.window.as_secs(),
+        target,
+        warmup.max_queued
+    );
+    state.dispatch_limits.set_warmup(warmup.dispatch_rate);
+    let reason = loop {
+        if available_nodes(&state) >= target {
+            break target_reason;
+        }
+        if warmup.started_at.elapsed() >= warmup.window {
+            break "window";
+        }
+        sleep(CHECK_INTERVAL).await;
+    };
+    warmup.end(reason);
+    state.dispatch_limits.set_warmup(None);
+    info!(
+        "Arranque: Fin del calentamiento tras {}ms ({}); {} nodos disponibles.",
+        warmup.started_at.elapsed().as_millis(),
+        reason,
+        available_nodes(&state)
+    );
+}
+
+/// Listo para recibir tráfico: 503 mientras dura el calentamiento.
+#[get("/readyz")]
+async fn readyz_handler(state: web::Data<AppState>) -> impl Responder {
+    if state.warmup.is_active() {
+        HttpResponse::ServiceUnavailable().json(json!({ "ready": false, "warmup": state.warmup.describe() }))
+    } else {
+        HttpResponse::Ok().json(json!({ "ready": true }))
+    }
+}
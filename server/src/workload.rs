@@ -0,0 +1,278 @@
+// src/workload.rs
+//! Clases de carga: nodos reservados para el tráfico interactivo.
+//!
+//! Cada petición es `interactive` o `batch`. Lo decide, por este orden: una regla de enrutado
+//! con `action = { classify = "batch" }`, el `class` de la política de la API key, la cabecera
+//! `X-Workload-Class` y, si nada lo dice, `--default-workload-class`.
+//!
+//! `--reserve-interactive POOL=N` aparta N nodos de la pool para el tráfico interactivo. Las
+//! peticiones batch ven una pool más pequeña: sólo ocupan a la vez `sanos - N` nodos y, por
+//! encima, esperan en la cola; las interactivas usan cualquier nodo, así que adelantan a una
+//! cola batch llena. Cada nodo atiende una petición a la vez: reservar nodos es reservar
+//! huecos. La reserva se recorta cuando caen nodos y nunca deja a batch sin nodo mientras
+//! quede alguno sano.
+use actix_web::http::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use crate::balancer::{NodeHealth, NodeInfo};
+use crate::ids::NodeId;
+
+/// Cabecera con la que el cliente declara la clase de su petición.
+pub const WORKLOAD_CLASS_HEADER: &str = "X-Workload-Class";
+
+#[derive(Debug)]
+pub struct WorkloadConfigError(String);
+
+impl fmt::Display for WorkloadConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for WorkloadConfigError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum WorkloadClass {
+    /// Un usuario esperando la respuesta; puede usar los nodos reservados.
+    Interactive,
+    /// Trabajos en lote; nunca ocupan los nodos reservados.
+    Batch,
+}
+
+impl WorkloadClass {
+    pub fn label(&self) -> &'static str {
+        match self {
+            WorkloadClass::Interactive => "interactive",
+            WorkloadClass::Batch => "batch",
+        }
+    }
+
+    /// Clase pedida en `X-Workload-Class`. `Err` con el valor si no es una clase conocida.
+    pub fn from_headers(headers: &HeaderMap) -> Result<Option<Self>, String> {
+        let Some(value) = headers.get(WORKLOAD_CLASS_HEADER) else {
+            return Ok(None);
+        };
+        let value = value.to_str().unwrap_or_default().trim();
+        match value.to_ascii_lowercase().as_str() {
+            "interactive" => Ok(Some(WorkloadClass::Interactive)),
+            "batch" => Ok(Some(WorkloadClass::Batch)),
+            _ => Err(value.to_string()),
+        }
+    }
+}
+
+/// Capacidad efectiva de una pool para cada clase, con los nodos sanos de ahora.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct ClassCapacity {
+    /// Nodos reservados según `--reserve-interactive`.
+    pub reserved: usize,
+    /// Reserva aplicada tras descontar los nodos caídos.
+    pub effective_reserved: usize,
+    /// Nodos que puede ocupar una petición interactiva (todos los sanos).
+    pub interactive: usize,
+    /// Nodos que pueden ocupar a la vez las peticiones batch.
+    pub batch: usize,
+    /// Nodos ocupados ahora por peticiones batch.
+    pub batch_busy: usize,
+}
+
+pub struct WorkloadPolicy {
+    default_class: WorkloadClass,
+    /// Nodos reservados para interactivo, por pool.
+    reserved: HashMap<String, usize>,
+}
+
+impl WorkloadPolicy {
+    /// Interpreta entradas `pool=N` de la línea de comandos.
+    pub fn new(pools: &[&str], default_class: WorkloadClass, entries: &[String]) -> Result<Self, WorkloadConfigError> {
+        let mut reserved = HashMap::new();
+        for entry in entries {
+            let parsed = entry
+                .split_once('=')
+                .and_then(|(pool, nodes)| nodes.trim().parse::<usize>().ok().map(|nodes| (pool.trim(), nodes)));
+            let Some((pool, nodes)) = parsed else {
+                return Err(WorkloadConfigError(format!("Reserva inválida '{}': se esperaba <pool>=<nodos>", entry)));
+            };
+            if !pools.contains(&pool) {
+                return Err(WorkloadConfigError(format!("Pool desconocida '{}' en --reserve-interactive", pool)));
+            }
+            if reserved.insert(pool.to_string(), nodes).is_some() {
+                return Err(WorkloadConfigError(format!("Reserva repetida para la pool '{}'", pool)));
+            }
+        }
+        reserved.retain(|_, nodes| *nodes > 0);
+        Ok(Self { default_class, reserved })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.reserved.is_empty()
+    }
+
+    pub fn pools(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.reserved.iter().map(|(pool, nodes)| (pool.as_str(), *nodes))
+    }
+
+    /// Clase de una petición: regla, luego API key, luego cabecera y luego la de por defecto.
+    pub fn classify(&self, rule: Option<WorkloadClass>, key: Option<WorkloadClass>, header: Option<WorkloadClass>) -> WorkloadClass {
+        rule.or(key).or(header).unwrap_or(self.default_class)
+    }
+
+    /// Capacidad de cada clase en la pool. Se llama con el lock del registro tomado.
+    pub fn capacity(&self, service: &str, nodes: &HashMap<NodeId, NodeInfo>) -> ClassCapacity {
+        let reserved = self.reserved.get(service).copied().unwrap_or(0);
+        let healthy = nodes.values().filter(|info| !matches!(info.state, NodeHealth::Failed(_))).count();
+        let batch_busy = nodes
+            .values()
+            .filter(|info| matches!(info.state, NodeHealth::Busy) && info.workload == Some(WorkloadClass::Batch))
+            .count();
+        // Con nodos caídos la reserva cede antes que batch: siempre le queda uno si hay alguno sano.
+        let effective_reserved = reserved.min(healthy.saturating_sub(1));
+        ClassCapacity { reserved, effective_reserved, interactive: healthy, batch: healthy - effective_reserved, batch_busy }
+    }
+
+    /// Si una petición de `class` puede ocupar otro nodo de la pool ahora mismo.
+    pub fn admits(&self, class: WorkloadClass, service: &str, nodes: &HashMap<NodeId, NodeInfo>) -> bool {
+        if class == WorkloadClass::Interactive || !self.reserved.contains_key(service) {
+            return true;
+        }
+        let capacity = self.capacity(service, nodes);
+        capacity.batch_busy < capacity.batch
+    }
+
+    /// Configuración para `/config`.
+    pub fn describe(&self) -> serde_json::Value {
+        let reserved: BTreeMap<&str, usize> = self.pools().collect();
+        json!({
+            "default_class": self.default_class.label(),
+            "header": WORKLOAD_CLASS_HEADER,
+            "reserve_interactive": reserved,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use futures_util::future::join_all;
+    use serde_json::Value;
+    use std::time::{Duration, Instant};
+
+    use crate::balancer::{self, LMSTUDIO};
+    use crate::history::TransitionCause;
+    use crate::testing;
+
+    const POOLS: [&str; 2] = ["lmstudio", "ollama"];
+
+    fn policy(entries: &[&str]) -> Result<WorkloadPolicy, WorkloadConfigError> {
+        WorkloadPolicy::new(&POOLS, WorkloadClass::Interactive, &entries.iter().map(|e| e.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn reservations_parse_and_reject_bad_entries() {
+        assert!(!policy(&[]).unwrap().is_enabled());
+        assert!(!policy(&["lmstudio=0"]).unwrap().is_enabled());
+        let parsed = policy(&["lmstudio = 2", "ollama=1"]).unwrap();
+        let mut pools: Vec<_> = parsed.pools().collect();
+        pools.sort();
+        assert_eq!(pools, [("lmstudio", 2), ("ollama", 1)]);
+        for entries in [&["lmstudio"][..], &["lmstudio=-1"], &["lmstudio=x"], &["vllm=1"], &["lmstudio=1", "lmstudio=2"]] {
+            assert!(policy(entries).is_err(), "{:?}", entries);
+        }
+    }
+
+    #[test]
+    fn rule_beats_key_beats_header_beats_default() {
+        use WorkloadClass::{Batch, Interactive};
+        let batch_default = WorkloadPolicy::new(&POOLS, Batch, &[]).unwrap();
+        let cases = [
+            (None, None, None, Batch),
+            (None, None, Some(Interactive), Interactive),
+            (None, Some(Batch), Some(Interactive), Batch),
+            (Some(Interactive), Some(Batch), Some(Batch), Interactive),
+            (Some(Batch), None, None, Batch),
+        ];
+        for (rule, key, header, expected) in cases {
+            assert_eq!(batch_default.classify(rule, key, header), expected, "{:?} {:?} {:?}", rule, key, header);
+        }
+    }
+
+    #[test]
+    fn header_values_are_case_insensitive_and_checked() {
+        let headers = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(HeaderName::from_static("x-workload-class"), HeaderValue::from_str(value).unwrap());
+            headers
+        };
+        assert_eq!(WorkloadClass::from_headers(&HeaderMap::new()), Ok(None));
+        assert_eq!(WorkloadClass::from_headers(&headers(" Batch ")), Ok(Some(WorkloadClass::Batch)));
+        assert_eq!(WorkloadClass::from_headers(&headers("INTERACTIVE")), Ok(Some(WorkloadClass::Interactive)));
+        assert_eq!(WorkloadClass::from_headers(&headers("urgente")), Err("urgente".to_string()));
+    }
+
+    /// Con nodos caídos cede la reserva, nunca el último nodo de batch.
+    #[test]
+    fn reservation_shrinks_as_nodes_fail() {
+        let state = testing::state(&["--reserve-interactive", "lmstudio=2"]);
+        for id in ["box1", "box2", "box3", "box4"] {
+            testing::announce(&state, "lmstudio", id, "http://127.0.0.1:1/");
+        }
+        let capacity = |state: &balancer::AppState| {
+            let capacity = state.workload.capacity("lmstudio", &state.pool("lmstudio").unwrap().read().unwrap());
+            (capacity.effective_reserved, capacity.interactive, capacity.batch)
+        };
+        assert_eq!(capacity(&state), (2, 4, 2));
+        let mut expected = vec![(2, 3, 1), (1, 2, 1), (0, 1, 1), (0, 0, 0)].into_iter();
+        for id in ["box1", "box2", "box3", "box4"] {
+            state.update_node_state(&LMSTUDIO, &testing::node_id(id), NodeHealth::Failed(Instant::now()), TransitionCause::HealthCheck);
+            assert_eq!(capacity(&state), expected.next().unwrap(), "tras caer {}", id);
+        }
+        // Sin reserva en la pool, batch la usa entera.
+        assert!(state.workload.admits(WorkloadClass::Batch, "ollama", &state.pool("ollama").unwrap().read().unwrap()));
+    }
+
+    #[actix_web::test]
+    async fn interactive_request_overtakes_a_full_batch_queue() {
+        let state = testing::state(&["--reserve-interactive", "lmstudio=1"]);
+        testing::announce(&state, "lmstudio", "box1", &testing::chat_node(Duration::from_millis(400)));
+        testing::announce(&state, "lmstudio", "box2", &testing::chat_node(Duration::from_millis(400)));
+        let app = init_service(balancer::app(state.clone())).await;
+
+        let start = Instant::now();
+        let batch = join_all((0..3).map(|_| async {
+            let status = call_service(&app, testing::chat().insert_header((WORKLOAD_CLASS_HEADER, WorkloadClass::Batch.label())).to_request()).await.status();
+            (status, start.elapsed())
+        }));
+        let interactive = async {
+            // Llega cuando las tres batch ya están dentro: una en un nodo y dos en cola.
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let sent = Instant::now();
+            let status = call_service(&app, testing::chat().insert_header((WORKLOAD_CLASS_HEADER, WorkloadClass::Interactive.label())).to_request()).await.status();
+            (status, sent.elapsed(), start.elapsed())
+        };
+        let capacity = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let req = TestRequest::get().uri("/").insert_header(("Accept", "application/json")).to_request();
+            let body: Value = read_body_json(call_service(&app, req).await).await;
+            body["workload"]["lmstudio"].clone()
+        };
+        let (batch, (status, waited, interactive_done), capacity) = tokio::join!(batch, interactive, capacity);
+
+        assert_eq!(status, 200);
+        assert!(waited < Duration::from_millis(700), "la interactiva esperó {:?}", waited);
+        assert!(batch.iter().all(|(status, _)| *status == 200), "{:?}", batch);
+        let mut batch_done: Vec<Duration> = batch.iter().map(|(_, at)| *at).collect();
+        batch_done.sort();
+        // Batch sólo ocupa un nodo: van de una en una y la interactiva acaba antes que la segunda.
+        assert!(interactive_done < batch_done[1], "interactiva {:?}, batch {:?}", interactive_done, batch_done);
+        assert!(batch_done[2] >= Duration::from_millis(1_200), "batch {:?}", batch_done);
+        assert_eq!(capacity["reserved"], 1);
+        assert_eq!(capacity["interactive"], 2);
+        assert_eq!((capacity["batch"].as_u64(), capacity["batch_busy"].as_u64()), (Some(1), Some(1)), "{}", capacity);
+    }
+}